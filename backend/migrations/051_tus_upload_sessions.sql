@@ -0,0 +1,23 @@
+-- Migration 051: Resumable Upload Sessions (tus 1.0)
+-- Tracks in-progress uploads so clients can query the received offset and resume
+
+CREATE TABLE IF NOT EXISTS upload_sessions (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    target_path TEXT NOT NULL,  -- Destination path relative to the data dir
+    upload_length INTEGER NOT NULL,  -- Total size announced via Upload-Length
+    upload_offset INTEGER NOT NULL DEFAULT 0,  -- Bytes received so far
+    upload_metadata TEXT,  -- Raw Upload-Metadata header, echoed back on HEAD
+    temp_path TEXT NOT NULL,  -- Partial file under ./data/temp_uploads/tus
+    status TEXT NOT NULL DEFAULT 'uploading',  -- uploading, completed
+    expires_at TEXT NOT NULL,  -- Extended on every PATCH
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    completed_at TEXT,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    CHECK (status IN ('uploading', 'completed')),
+    CHECK (upload_offset >= 0 AND upload_offset <= upload_length)
+);
+
+CREATE INDEX IF NOT EXISTS idx_upload_sessions_user ON upload_sessions(user_id, status);
+CREATE INDEX IF NOT EXISTS idx_upload_sessions_expires ON upload_sessions(status, expires_at);
@@ -0,0 +1,47 @@
+-- Migration 052: Content-Addressed Block Store
+-- Optional deduplicated storage (SYNCSPACE_STORAGE_MODE=dedup). Content is split
+-- into content-defined chunks stored once under ./data/.blocks by SHA-256.
+
+-- One row per physical block on disk
+CREATE TABLE IF NOT EXISTS storage_blocks (
+    hash TEXT PRIMARY KEY NOT NULL,  -- SHA-256 of the block content (hex)
+    size_bytes INTEGER NOT NULL,
+    ref_count INTEGER NOT NULL DEFAULT 0,  -- Number of manifest entries referencing this block
+    created_at TEXT NOT NULL,
+    CHECK (ref_count >= 0)
+);
+
+-- A manifest describes one complete piece of content; its id is the SHA-256 of
+-- the whole content, so identical versions and backups share it
+CREATE TABLE IF NOT EXISTS block_manifests (
+    id TEXT PRIMARY KEY NOT NULL,
+    total_size INTEGER NOT NULL,
+    block_count INTEGER NOT NULL,
+    ref_count INTEGER NOT NULL DEFAULT 0,  -- Versions and backups holding this manifest
+    created_at TEXT NOT NULL,
+    last_ref_at TEXT NOT NULL,  -- Last time a reference was taken (GC grace period)
+    CHECK (ref_count >= 0)
+);
+
+CREATE TABLE IF NOT EXISTS block_manifest_entries (
+    manifest_id TEXT NOT NULL,
+    seq INTEGER NOT NULL,  -- Position of the block within the content
+    block_hash TEXT NOT NULL,
+    block_offset INTEGER NOT NULL,  -- Byte offset of the block within the content
+    PRIMARY KEY (manifest_id, seq),
+    FOREIGN KEY (manifest_id) REFERENCES block_manifests(id) ON DELETE CASCADE,
+    FOREIGN KEY (block_hash) REFERENCES storage_blocks(hash)
+);
+
+-- Manifests held by a backup (released when the backup is deleted)
+CREATE TABLE IF NOT EXISTS backup_block_refs (
+    backup_id TEXT NOT NULL,
+    file_path TEXT NOT NULL,
+    manifest_id TEXT NOT NULL,
+    PRIMARY KEY (backup_id, file_path),
+    FOREIGN KEY (manifest_id) REFERENCES block_manifests(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_block_manifest_entries_block ON block_manifest_entries(block_hash);
+CREATE INDEX IF NOT EXISTS idx_storage_blocks_unreferenced ON storage_blocks(ref_count, created_at);
+CREATE INDEX IF NOT EXISTS idx_backup_block_refs_manifest ON backup_block_refs(manifest_id);
@@ -0,0 +1,9 @@
+-- Migration 053: Storage Backend Objects
+-- Resumable storage migrations and object lookups for pluggable storage backends
+
+-- Last file processed by a migration job; a restarted job continues after it
+ALTER TABLE storage_migration_jobs ADD COLUMN cursor_file_id TEXT;
+ALTER TABLE storage_migration_jobs ADD COLUMN updated_at TEXT;
+
+-- Objects on remote backends are content addressed, so several files can share one key
+CREATE INDEX IF NOT EXISTS idx_file_storage_locations_object ON file_storage_locations(backend_id, storage_path);
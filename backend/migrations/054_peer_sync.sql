@@ -0,0 +1,76 @@
+-- Migration 054: Peer-to-Peer Folder Sync
+-- Pairing between SyncSpace instances, per-path version vectors and sync conflicts
+
+-- Identity of this instance as seen by its peers (single row)
+CREATE TABLE IF NOT EXISTS sync_instance (
+    instance_id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+-- Paired peers know each other's instance id and share an HMAC secret
+ALTER TABLE peers ADD COLUMN instance_id TEXT;
+ALTER TABLE peers ADD COLUMN shared_secret TEXT;
+ALTER TABLE peers ADD COLUMN paired_at TEXT;
+ALTER TABLE peers ADD COLUMN last_error TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_peers_instance_id ON peers(instance_id);
+
+-- One-time pairing codes, stored as SHA-256 hashes
+CREATE TABLE IF NOT EXISTS peer_pairing_codes (
+    id TEXT PRIMARY KEY,
+    code_hash TEXT NOT NULL UNIQUE,
+    created_by TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    used_at TEXT,
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Folders synchronized with a peer; remote_cursor is the last change sequence pulled
+CREATE TABLE IF NOT EXISTS peer_sync_folders (
+    id TEXT PRIMARY KEY,
+    peer_id TEXT NOT NULL,
+    folder_path TEXT NOT NULL,
+    remote_cursor INTEGER NOT NULL DEFAULT 0,
+    last_synced_at TEXT,
+    created_at TEXT NOT NULL,
+    UNIQUE(peer_id, folder_path),
+    FOREIGN KEY (peer_id) REFERENCES peers(id) ON DELETE CASCADE
+);
+
+-- Latest synchronized state of every path. seq orders the local change log:
+-- each local or applied remote change moves the path to a new, higher seq.
+CREATE TABLE IF NOT EXISTS sync_file_versions (
+    path TEXT PRIMARY KEY,
+    version_vector TEXT NOT NULL DEFAULT '{}',
+    checksum_sha256 TEXT,
+    size_bytes INTEGER NOT NULL DEFAULT 0,
+    is_deleted INTEGER NOT NULL DEFAULT 0,
+    modified_at TEXT NOT NULL,
+    seq INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_sync_file_versions_seq ON sync_file_versions(seq);
+
+-- Conflicts surfaced through /collaboration/conflicts
+CREATE TABLE IF NOT EXISTS file_conflicts (
+    id TEXT PRIMARY KEY,
+    file_id TEXT,
+    file_path TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    peer_id TEXT,
+    conflict_type TEXT NOT NULL, -- concurrent_edit, edit_delete
+    local_version INTEGER NOT NULL DEFAULT 0,
+    remote_version INTEGER NOT NULL DEFAULT 0,
+    conflict_copy_path TEXT,
+    details TEXT,
+    created_at TEXT NOT NULL,
+    resolved_at TEXT,
+    resolution_strategy TEXT,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (peer_id) REFERENCES peers(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_conflicts_user ON file_conflicts(user_id, resolved_at);
+CREATE INDEX IF NOT EXISTS idx_file_conflicts_path ON file_conflicts(file_path);
@@ -0,0 +1,27 @@
+-- Migration 055: Change Journal
+-- Ordered log of file mutations for incremental (delta) sync clients
+
+-- AUTOINCREMENT keeps seq strictly increasing, even after old rows are pruned
+CREATE TABLE IF NOT EXISTS change_journal (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    change_type TEXT NOT NULL, -- create, modify, delete, rename, move, copy, restore
+    path TEXT NOT NULL,
+    old_path TEXT,
+    file_id TEXT,
+    is_directory INTEGER NOT NULL DEFAULT 0,
+    size_bytes INTEGER,
+    checksum_sha256 TEXT,
+    user_id TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_change_journal_created ON change_journal(created_at);
+CREATE INDEX IF NOT EXISTS idx_change_journal_path ON change_journal(path);
+
+-- Highest seq removed by retention; older cursors must do a full resync
+CREATE TABLE IF NOT EXISTS change_journal_state (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    pruned_through INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT OR IGNORE INTO change_journal_state (id, pruned_through) VALUES (1, 0);
@@ -0,0 +1,4 @@
+-- Migration 056: Streaming version storage
+-- The version service stores a free-text comment with every version
+
+ALTER TABLE file_versions ADD COLUMN comment TEXT;
@@ -0,0 +1,18 @@
+-- WebDAV class 2 support
+--
+-- WebDAV locks share collaborative_locks with the collaboration API.
+-- lock_type holds the lock scope ('exclusive' or 'shared'). Locks may cover
+-- folders and unmapped URLs, so file_id holds the locked path.
+ALTER TABLE collaborative_locks ADD COLUMN depth TEXT NOT NULL DEFAULT '0';
+ALTER TABLE collaborative_locks ADD COLUMN owner_xml TEXT;
+ALTER TABLE collaborative_locks ADD COLUMN timeout_seconds INTEGER NOT NULL DEFAULT 1800;
+
+-- Dead properties set with PROPPATCH, value is serialized XML
+CREATE TABLE IF NOT EXISTS webdav_properties (
+    path TEXT NOT NULL,
+    namespace TEXT NOT NULL,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL,
+    updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (path, namespace, name)
+);
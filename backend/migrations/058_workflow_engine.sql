@@ -0,0 +1,66 @@
+-- Migration 058: Workflow engine
+-- Rules are evaluated on file events and executed through the job queue
+
+-- Per-rule execution limits and schedule bookkeeping
+ALTER TABLE workflow_rules ADD COLUMN max_concurrency INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE workflow_rules ADD COLUMN max_retries INTEGER NOT NULL DEFAULT 3;
+ALTER TABLE workflow_rules ADD COLUMN last_triggered_at TEXT;
+
+-- Executions are now queued and run asynchronously, which needs more
+-- statuses than the CHECK constraint allows, so the table is rebuilt
+CREATE TABLE IF NOT EXISTS workflow_executions_new (
+    id TEXT PRIMARY KEY DEFAULT (lower(hex(randomblob(16)))),
+    rule_id TEXT NOT NULL,
+    triggered_by TEXT,
+    trigger_event TEXT NOT NULL,
+    condition_met BOOLEAN NOT NULL,
+    condition_result TEXT,
+    action_executed BOOLEAN NOT NULL,
+    action_result TEXT,
+    status TEXT NOT NULL CHECK (status IN (
+        'queued', 'running', 'retrying', 'success', 'failed', 'skipped', 'cancelled'
+    )),
+    error_message TEXT,
+    execution_time_ms INTEGER,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    file_path TEXT,
+    job_id TEXT,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    started_at TEXT,
+    completed_at TEXT,
+    FOREIGN KEY (rule_id) REFERENCES workflow_rules(id) ON DELETE CASCADE,
+    FOREIGN KEY (triggered_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+INSERT INTO workflow_executions_new (
+    id, rule_id, triggered_by, trigger_event, condition_met, condition_result,
+    action_executed, action_result, status, error_message, execution_time_ms, created_at
+)
+SELECT
+    id, rule_id, triggered_by, trigger_event, condition_met, condition_result,
+    action_executed, action_result, status, error_message, execution_time_ms, created_at
+FROM workflow_executions;
+
+DROP TABLE workflow_executions;
+-- A rename checks every view, and version_storage_stats (migration 042)
+-- names columns files does not have; legacy mode skips that check
+PRAGMA legacy_alter_table = ON;
+ALTER TABLE workflow_executions_new RENAME TO workflow_executions;
+PRAGMA legacy_alter_table = OFF;
+
+CREATE INDEX IF NOT EXISTS idx_workflow_executions_rule ON workflow_executions(rule_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_workflow_executions_status ON workflow_executions(status, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_workflow_executions_triggered_by ON workflow_executions(triggered_by, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_workflow_executions_running ON workflow_executions(rule_id, status);
+
+-- Step-by-step log of each execution, one line per event
+CREATE TABLE IF NOT EXISTS workflow_execution_logs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    execution_id TEXT NOT NULL,
+    level TEXT NOT NULL, -- info, warn, error
+    message TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (execution_id) REFERENCES workflow_executions(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_workflow_execution_logs_execution ON workflow_execution_logs(execution_id, id);
@@ -0,0 +1,19 @@
+-- Migration 059: Workflow plugins
+-- WebAssembly modules run by run_script workflow actions, registered by admins
+
+CREATE TABLE IF NOT EXISTS workflow_plugins (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    description TEXT,
+    module BLOB NOT NULL,
+    sha256 TEXT NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    fuel_limit INTEGER NOT NULL,
+    memory_limit_mb INTEGER NOT NULL,
+    timeout_ms INTEGER NOT NULL,
+    is_active BOOLEAN NOT NULL DEFAULT 1,
+    created_by TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    FOREIGN KEY (created_by) REFERENCES users(id)
+);
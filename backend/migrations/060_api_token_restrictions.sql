@@ -0,0 +1,17 @@
+-- Migration 060: Personal access token restrictions
+-- Columns the token handlers use that 033 did not create, plus limits to
+-- folders and client addresses
+
+ALTER TABLE api_tokens ADD COLUMN token_prefix TEXT NOT NULL DEFAULT '';
+ALTER TABLE api_tokens ADD COLUMN usage_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE api_tokens ADD COLUMN max_uses INTEGER; -- NULL = unlimited
+ALTER TABLE api_tokens ADD COLUMN is_active BOOLEAN NOT NULL DEFAULT 1;
+ALTER TABLE api_tokens ADD COLUMN description TEXT;
+
+-- Comma-separated addresses or CIDR ranges, NULL = any
+ALTER TABLE api_tokens ADD COLUMN ip_whitelist TEXT;
+
+-- Comma-separated folders the token may touch, NULL = all of the user's files
+ALTER TABLE api_tokens ADD COLUMN path_prefixes TEXT;
+
+UPDATE api_tokens SET is_active = 0 WHERE is_revoked = 1;
@@ -0,0 +1,13 @@
+-- Migration 061: Live rate limits and bandwidth shaping
+-- Rate limit rules can also target an API token or a client address and
+-- choose what their counters are kept per; bandwidth quotas gain transfer
+-- rates
+
+ALTER TABLE api_rate_limits ADD COLUMN token_id TEXT;
+ALTER TABLE api_rate_limits ADD COLUMN ip_address TEXT; -- Address or CIDR range
+ALTER TABLE api_rate_limits ADD COLUMN limit_by TEXT NOT NULL DEFAULT 'user'; -- 'user', 'token' or 'ip'
+
+ALTER TABLE user_bandwidth_quotas ADD COLUMN upload_bytes_per_second INTEGER DEFAULT NULL; -- NULL = unthrottled
+ALTER TABLE user_bandwidth_quotas ADD COLUMN download_bytes_per_second INTEGER DEFAULT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_quota_alerts_type ON quota_alerts(user_id, alert_type, created_at);
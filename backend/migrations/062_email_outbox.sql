@@ -0,0 +1,25 @@
+-- Migration 062: Email outbox
+-- Rendered messages waiting for delivery and the outcome of each delivery;
+-- see src/mail for the statuses
+
+CREATE TABLE IF NOT EXISTS email_logs (
+    id TEXT PRIMARY KEY,
+    user_id TEXT REFERENCES users(id) ON DELETE SET NULL,
+    recipient TEXT NOT NULL,
+    template TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    text_body TEXT NOT NULL,
+    html_body TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'queued', -- queued, sending, sent, logged, retrying, failed, bounced
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TEXT NOT NULL DEFAULT (datetime('now')),
+    message_id TEXT,
+    smtp_response TEXT,
+    last_error TEXT,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+    sent_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_email_logs_due ON email_logs(status, next_attempt_at);
+CREATE INDEX IF NOT EXISTS idx_email_logs_user ON email_logs(user_id, created_at);
@@ -0,0 +1,42 @@
+-- Migration 063: Notification dispatch
+-- Channels per user and event type, quiet hours and digest timing per user,
+-- and email notifications waiting for their digest
+
+CREATE TABLE IF NOT EXISTS notification_preferences (
+    user_id TEXT NOT NULL,
+    event_type TEXT NOT NULL, -- share_received, comment_mention, quota_alert, job_failed
+    in_app BOOLEAN NOT NULL DEFAULT 1,
+    websocket BOOLEAN NOT NULL DEFAULT 1,
+    email BOOLEAN NOT NULL DEFAULT 1,
+    webhook BOOLEAN NOT NULL DEFAULT 1,
+    email_digest TEXT NOT NULL DEFAULT 'off', -- off, hourly, daily
+    updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+    PRIMARY KEY (user_id, event_type),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS notification_settings (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    quiet_hours_start TEXT, -- HH:MM in the user's time
+    quiet_hours_end TEXT,
+    utc_offset_minutes INTEGER NOT NULL DEFAULT 0,
+    digest_hour INTEGER NOT NULL DEFAULT 8, -- Hour of the user's day for daily digests
+    updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS notification_digest_items (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    title TEXT NOT NULL,
+    message TEXT NOT NULL,
+    mail_template TEXT NOT NULL,
+    mail_context TEXT NOT NULL, -- JSON
+    deliver_after TEXT NOT NULL, -- UTC, YYYY-MM-DD HH:MM:SS
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_notification_digest_due ON notification_digest_items(deliver_after);
+CREATE INDEX IF NOT EXISTS idx_notification_digest_user ON notification_digest_items(user_id);
@@ -0,0 +1,21 @@
+-- Migration 064: Inbound workflow webhooks
+-- Signed endpoints of rules with the webhook trigger, and the signatures
+-- accepted recently so that requests cannot be replayed
+
+CREATE TABLE IF NOT EXISTS workflow_webhook_endpoints (
+    id TEXT PRIMARY KEY, -- random, part of the endpoint URL
+    rule_id TEXT NOT NULL UNIQUE REFERENCES workflow_rules(id) ON DELETE CASCADE,
+    secret TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    last_received_at TEXT
+);
+
+CREATE TABLE IF NOT EXISTS workflow_webhook_deliveries (
+    endpoint_id TEXT NOT NULL REFERENCES workflow_webhook_endpoints(id) ON DELETE CASCADE,
+    signature TEXT NOT NULL, -- hex HMAC of the accepted request
+    received_at TEXT NOT NULL,
+    PRIMARY KEY (endpoint_id, signature)
+);
+
+CREATE INDEX IF NOT EXISTS idx_workflow_webhook_deliveries_received
+    ON workflow_webhook_deliveries(received_at);
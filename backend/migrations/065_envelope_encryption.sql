@@ -0,0 +1,12 @@
+-- Migration 065: Envelope encryption
+-- Every encrypted file gets its own data key, stored wrapped by the
+-- key-encryption key in encryption_keys, and the size of its plaintext
+
+ALTER TABLE encrypted_files ADD COLUMN wrapped_key TEXT;
+ALTER TABLE encrypted_files ADD COLUMN plaintext_size INTEGER;
+
+-- Records written before only marked files as encrypted without touching
+-- their content
+UPDATE files SET is_encrypted = 0
+WHERE id IN (SELECT file_id FROM encrypted_files WHERE wrapped_key IS NULL);
+DELETE FROM encrypted_files WHERE wrapped_key IS NULL;
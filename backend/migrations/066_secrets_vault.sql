@@ -0,0 +1,13 @@
+-- Migration 066: Secrets vault
+-- Credentials are sealed under the master key; every read of one is recorded
+CREATE TABLE IF NOT EXISTS secret_reads (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    secret TEXT NOT NULL,           -- "<table>.<column>"
+    row_id TEXT NOT NULL,
+    key_id TEXT,                    -- master key the value was sealed with, NULL for legacy values
+    purpose TEXT NOT NULL,
+    read_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_secret_reads_secret ON secret_reads(secret, row_id);
+CREATE INDEX IF NOT EXISTS idx_secret_reads_read_at ON secret_reads(read_at);
@@ -0,0 +1,35 @@
+-- Incremental backup chains and grandfather-father-son retention
+-- Migration 067
+
+-- Incremental and differential backups point at the backup they were taken
+-- against and at the full backup their chain starts with
+ALTER TABLE backups ADD COLUMN parent_backup_id TEXT;
+ALTER TABLE backups ADD COLUMN base_backup_id TEXT;
+CREATE INDEX IF NOT EXISTS idx_backups_parent ON backups(parent_backup_id);
+CREATE INDEX IF NOT EXISTS idx_backups_base ON backups(base_backup_id);
+
+-- The schedule endpoints in api/backup.rs (list, create, get, update and the
+-- next_run_at lookup of the status endpoint) were written against the schema
+-- of migration 016: cron_expression, enabled, destination_type and friends.
+-- Migration 044 dropped and recreated backup_schedules with a frequency /
+-- is_active layout for a scheduler that was never wired up, so since then every
+-- one of those queries fails with "no such column" and no schedule can be
+-- created. The retention and full_every columns below hang off the same rows,
+-- so the 016 columns come back here rather than rewriting the API against 044.
+-- Existing rows keep their 044 values and get the defaults for the new ones.
+ALTER TABLE backup_schedules ADD COLUMN cron_expression TEXT;
+ALTER TABLE backup_schedules ADD COLUMN enabled INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE backup_schedules ADD COLUMN destination_type TEXT NOT NULL DEFAULT 'local';
+ALTER TABLE backup_schedules ADD COLUMN destination_id TEXT;
+ALTER TABLE backup_schedules ADD COLUMN encryption_enabled INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE backup_schedules ADD COLUMN include_versions INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE backup_schedules ADD COLUMN include_database INTEGER NOT NULL DEFAULT 1;
+
+-- Newest backup of each of the last N days, ISO weeks, months and years,
+-- NULL everywhere falls back to retention_days
+ALTER TABLE backup_schedules ADD COLUMN keep_daily INTEGER;
+ALTER TABLE backup_schedules ADD COLUMN keep_weekly INTEGER;
+ALTER TABLE backup_schedules ADD COLUMN keep_monthly INTEGER;
+ALTER TABLE backup_schedules ADD COLUMN keep_yearly INTEGER;
+-- Incremental or differential backups after a full one before the next
+ALTER TABLE backup_schedules ADD COLUMN full_every INTEGER;
@@ -0,0 +1,23 @@
+-- Uploading backups to their destinations
+-- Migration 068
+
+-- Where a backup is uploaded to and how far that got
+ALTER TABLE backups ADD COLUMN destination_id TEXT;
+ALTER TABLE backups ADD COLUMN remote_path TEXT;
+ALTER TABLE backups ADD COLUMN upload_status TEXT; -- 'uploading', 'uploaded', 'failed'
+ALTER TABLE backups ADD COLUMN upload_error TEXT;
+ALTER TABLE backups ADD COLUMN uploaded_at TEXT;
+CREATE INDEX IF NOT EXISTS idx_backups_destination ON backups(destination_id);
+
+-- Credentials of a destination, sealed by the secrets vault and kept out of
+-- the config column
+ALTER TABLE backup_destinations ADD COLUMN secrets TEXT;
+
+-- Unfinished uploads, so a retried job continues where the last one stopped
+CREATE TABLE IF NOT EXISTS backup_uploads (
+    backup_id TEXT PRIMARY KEY,
+    destination_id TEXT NOT NULL,
+    upload_id TEXT, -- S3 multipart upload id
+    started_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
@@ -0,0 +1,48 @@
+-- Backup manifests in backup_files
+-- Migration 069
+
+-- backup_files rows belong to a backup (the backups table), not to one of the
+-- backup_jobs of migration 044. Rebuilt with a backup_id column referencing
+-- backups and an optional backup_job_id, because SQLite cannot drop the NOT
+-- NULL constraint or the foreign key of an existing column.
+CREATE TABLE backup_files_new (
+    id TEXT PRIMARY KEY DEFAULT (lower(hex(randomblob(16)))),
+    backup_id TEXT,
+    backup_job_id TEXT,
+    file_path TEXT NOT NULL,
+    file_hash TEXT,
+    file_size BIGINT NOT NULL,
+    compressed_size BIGINT,
+    mime_type TEXT,
+    modified_at TIMESTAMP,
+    is_directory BOOLEAN DEFAULT 0,
+    action TEXT CHECK(action IN ('added', 'modified', 'deleted', 'unchanged')),
+    is_encrypted BOOLEAN DEFAULT 0,
+    is_deduplicated BOOLEAN DEFAULT 0,
+    block_refs TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (backup_id) REFERENCES backups(id) ON DELETE CASCADE,
+    FOREIGN KEY (backup_job_id) REFERENCES backup_jobs(id) ON DELETE CASCADE
+);
+
+-- Manifests written before this migration kept the backup id in backup_job_id
+INSERT INTO backup_files_new (id, backup_id, backup_job_id, file_path, file_hash, file_size,
+    compressed_size, mime_type, modified_at, is_directory, action, is_encrypted,
+    is_deduplicated, block_refs, created_at)
+SELECT id,
+    CASE WHEN backup_job_id IN (SELECT id FROM backups) THEN backup_job_id END,
+    CASE WHEN backup_job_id IN (SELECT id FROM backups) THEN NULL ELSE backup_job_id END,
+    file_path, file_hash, file_size, compressed_size, mime_type, modified_at, is_directory,
+    action, is_encrypted, is_deduplicated, block_refs, created_at
+FROM backup_files;
+
+DROP TABLE backup_files;
+-- Legacy mode skips the check of the version_storage_stats view (see 058)
+PRAGMA legacy_alter_table = ON;
+ALTER TABLE backup_files_new RENAME TO backup_files;
+PRAGMA legacy_alter_table = OFF;
+
+CREATE INDEX IF NOT EXISTS idx_backup_files_backup ON backup_files(backup_id);
+CREATE INDEX IF NOT EXISTS idx_backup_files_job ON backup_files(backup_job_id);
+CREATE INDEX IF NOT EXISTS idx_backup_files_hash ON backup_files(file_hash);
+CREATE INDEX IF NOT EXISTS idx_backup_files_path ON backup_files(file_path);
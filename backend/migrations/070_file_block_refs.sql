@@ -0,0 +1,12 @@
+-- Migration 070: Block store references of live files
+-- With SYNCSPACE_STORAGE_MODE=dedup, uploaded and copied files keep their
+-- content in the block store too, sharing blocks with each other, versions
+-- and backups. Released when the file is purged or its content replaced.
+CREATE TABLE IF NOT EXISTS file_block_refs (
+    file_id TEXT PRIMARY KEY NOT NULL,
+    manifest_id TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (manifest_id) REFERENCES block_manifests(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_block_refs_manifest ON file_block_refs(manifest_id);
@@ -209,8 +209,8 @@ async fn create_user(
     // Assign role if provided
     if let Some(role) = &req.role {
         let role_id = match role.as_str() {
-            "admin" => "admin",
-            _ => "user",
+            "admin" => "role-admin",
+            _ => "role-user",
         };
 
         // Try to assign role, ignore if table doesn't exist
@@ -294,8 +294,8 @@ async fn update_user(
 
         // Add new role
         let role_id = match role.as_str() {
-            "admin" => "admin",
-            _ => "user",
+            "admin" => "role-admin",
+            _ => "role-user",
         };
         let _ = sqlx::query(
             "INSERT OR IGNORE INTO user_roles (user_id, role_id, granted_at, granted_by) VALUES (?, ?, datetime('now'), ?)",
@@ -327,7 +327,7 @@ async fn delete_user(
     let admin_count = sqlx::query_scalar::<_, i32>(
         "SELECT COUNT(*) FROM users u 
          LEFT JOIN user_roles ur ON u.id = ur.user_id 
-         WHERE ur.role_id = 'admin' OR u.username = 'admin'",
+         WHERE ur.role_id = 'role-admin' OR u.username = 'admin'",
     )
     .fetch_one(&state.db_pool)
     .await
@@ -336,7 +336,7 @@ async fn delete_user(
     let is_target_admin = sqlx::query_scalar::<_, i32>(
         "SELECT COUNT(*) FROM users u 
          LEFT JOIN user_roles ur ON u.id = ur.user_id 
-         WHERE u.id = ? AND (ur.role_id = 'admin' OR u.username = 'admin')",
+         WHERE u.id = ? AND (ur.role_id = 'role-admin' OR u.username = 'admin')",
     )
     .bind(&user_id)
     .fetch_one(&state.db_pool)
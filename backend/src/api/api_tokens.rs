@@ -1,7 +1,8 @@
 //! Personal Access Token (API Key) Management
 //!
 //! Allows users to create API tokens for programmatic access to the API.
-//! Tokens can have scopes, expiration dates, and usage limits.
+//! Tokens can have scopes, expiration dates, and usage limits, and can be
+//! limited to folders and client addresses (enforced by `crate::rbac`).
 
 use axum::{
     extract::{Path, Query, State},
@@ -14,8 +15,10 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use sqlx::FromRow;
+use std::net::IpAddr;
 use uuid::Uuid;
 
+use crate::rbac::tokens::{self, TokenGrant};
 use crate::{auth::UserInfo, AppState};
 
 // ============================================================================
@@ -29,7 +32,7 @@ pub struct ApiToken {
     pub name: String,
     pub token_prefix: String, // First 8 chars for display (e.g., "ssk_abc1...")
     pub token_hash: String,   // SHA-256 hash of full token
-    pub scopes: String,       // Comma-separated: "read,write,admin"
+    pub scopes: String,       // Comma-separated: "files:read,files:write"
     pub expires_at: Option<String>, // NULL = never expires
     pub last_used_at: Option<String>,
     pub usage_count: i64,
@@ -38,6 +41,7 @@ pub struct ApiToken {
     pub is_active: bool,
     pub created_at: String,
     pub description: Option<String>,
+    pub path_prefixes: Option<String>, // Comma-separated folders, NULL = all
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -51,6 +55,7 @@ pub struct ApiTokenResponse {
     pub usage_count: i64,
     pub max_uses: Option<i64>,
     pub ip_whitelist: Option<Vec<String>>,
+    pub path_prefixes: Option<Vec<String>>,
     pub is_active: bool,
     pub created_at: String,
     pub description: Option<String>,
@@ -73,6 +78,8 @@ pub struct CreateTokenRequest {
     #[serde(default)]
     pub ip_whitelist: Option<Vec<String>>,
     #[serde(default)]
+    pub path_prefixes: Option<Vec<String>>,
+    #[serde(default)]
     pub description: Option<String>,
 }
 
@@ -82,6 +89,7 @@ pub struct UpdateTokenRequest {
     pub scopes: Option<Vec<String>>,
     pub is_active: Option<bool>,
     pub ip_whitelist: Option<Vec<String>>,
+    pub path_prefixes: Option<Vec<String>>,
     pub description: Option<String>,
 }
 
@@ -166,58 +174,14 @@ async fn list_tokens(
 
 /// Get available scopes
 async fn list_scopes() -> Json<Vec<AvailableScope>> {
-    let scopes = vec![
-        AvailableScope {
-            scope: "read".to_string(),
-            description: "Read access to files and folders".to_string(),
-            category: "Files".to_string(),
-        },
-        AvailableScope {
-            scope: "write".to_string(),
-            description: "Create, update, and delete files and folders".to_string(),
-            category: "Files".to_string(),
-        },
-        AvailableScope {
-            scope: "share".to_string(),
-            description: "Create and manage file shares".to_string(),
-            category: "Sharing".to_string(),
-        },
-        AvailableScope {
-            scope: "upload".to_string(),
-            description: "Upload files".to_string(),
-            category: "Files".to_string(),
-        },
-        AvailableScope {
-            scope: "download".to_string(),
-            description: "Download files".to_string(),
-            category: "Files".to_string(),
-        },
-        AvailableScope {
-            scope: "search".to_string(),
-            description: "Search files and content".to_string(),
-            category: "Search".to_string(),
-        },
-        AvailableScope {
-            scope: "profile".to_string(),
-            description: "Read and update user profile".to_string(),
-            category: "User".to_string(),
-        },
-        AvailableScope {
-            scope: "notifications".to_string(),
-            description: "Access notifications".to_string(),
-            category: "User".to_string(),
-        },
-        AvailableScope {
-            scope: "webhooks".to_string(),
-            description: "Manage webhooks".to_string(),
-            category: "Integration".to_string(),
-        },
-        AvailableScope {
-            scope: "admin".to_string(),
-            description: "Administrative access (requires admin role)".to_string(),
-            category: "Admin".to_string(),
-        },
-    ];
+    let scopes = tokens::SCOPES
+        .iter()
+        .map(|(scope, description, category)| AvailableScope {
+            scope: scope.to_string(),
+            description: description.to_string(),
+            category: category.to_string(),
+        })
+        .collect();
 
     Json(scopes)
 }
@@ -228,24 +192,17 @@ async fn create_token(
     user: UserInfo,
     Json(req): Json<CreateTokenRequest>,
 ) -> Result<Json<CreateTokenResponse>, (StatusCode, String)> {
-    // Validate scopes
-    let valid_scopes = [
-        "read",
-        "write",
-        "share",
-        "upload",
-        "download",
-        "search",
-        "profile",
-        "notifications",
-        "webhooks",
-        "admin",
-    ];
-    for scope in &req.scopes {
-        if !valid_scopes.contains(&scope.as_str()) {
-            return Err((StatusCode::BAD_REQUEST, format!("Invalid scope: {}", scope)));
-        }
-    }
+    let scopes_str = normalize_scopes(&req.scopes)?;
+    let ip_whitelist_str = req
+        .ip_whitelist
+        .as_deref()
+        .map(normalize_ip_whitelist)
+        .transpose()?;
+    let path_prefixes_str = req
+        .path_prefixes
+        .as_deref()
+        .map(normalize_path_prefixes)
+        .transpose()?;
 
     // Generate secure token
     let token_id = Uuid::new_v4().to_string();
@@ -258,13 +215,10 @@ async fn create_token(
         .expires_in_days
         .map(|days| (Utc::now() + Duration::days(days)).to_rfc3339());
 
-    let scopes_str = req.scopes.join(",");
-    let ip_whitelist_str = req.ip_whitelist.as_ref().map(|ips| ips.join(","));
-
     sqlx::query(
         "INSERT INTO api_tokens 
-         (id, user_id, name, token_prefix, token_hash, scopes, expires_at, max_uses, ip_whitelist, is_active, created_at, description)
-         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 1, datetime('now'), ?)"
+         (id, user_id, name, token_prefix, token_hash, scopes, expires_at, max_uses, ip_whitelist, path_prefixes, is_active, created_at, description)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 1, datetime('now'), ?)"
     )
     .bind(&token_id)
     .bind(user.user_id())
@@ -275,6 +229,7 @@ async fn create_token(
     .bind(&expires_at)
     .bind(req.max_uses)
     .bind(&ip_whitelist_str)
+    .bind(&path_prefixes_str)
     .bind(&req.description)
     .execute(&state.db_pool)
     .await
@@ -317,7 +272,7 @@ async fn update_token(
     user: UserInfo,
     Path(id): Path<String>,
     Json(req): Json<UpdateTokenRequest>,
-) -> Result<Json<ApiTokenResponse>, StatusCode> {
+) -> Result<Json<ApiTokenResponse>, (StatusCode, String)> {
     // Verify ownership
     let existing: Option<ApiToken> =
         sqlx::query_as("SELECT * FROM api_tokens WHERE id = ? AND user_id = ?")
@@ -325,10 +280,10 @@ async fn update_token(
             .bind(user.user_id())
             .fetch_optional(&state.db_pool)
             .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     if existing.is_none() {
-        return Err(StatusCode::NOT_FOUND);
+        return Err((StatusCode::NOT_FOUND, "Token not found".to_string()));
     }
 
     // Build update query dynamically
@@ -341,7 +296,7 @@ async fn update_token(
     }
     if let Some(scopes) = &req.scopes {
         updates.push("scopes = ?");
-        values.push(scopes.join(","));
+        values.push(normalize_scopes(scopes)?);
     }
     if let Some(is_active) = req.is_active {
         updates.push("is_active = ?");
@@ -353,7 +308,11 @@ async fn update_token(
     }
     if let Some(ips) = &req.ip_whitelist {
         updates.push("ip_whitelist = ?");
-        values.push(ips.join(","));
+        values.push(normalize_ip_whitelist(ips)?);
+    }
+    if let Some(prefixes) = &req.path_prefixes {
+        updates.push("path_prefixes = ?");
+        values.push(normalize_path_prefixes(prefixes)?);
     }
     if let Some(desc) = &req.description {
         updates.push("description = ?");
@@ -375,11 +334,13 @@ async fn update_token(
         query
             .execute(&state.db_pool)
             .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
     }
 
     // Return updated token
-    get_token(State(state), user, Path(id)).await
+    get_token(State(state), user, Path(id))
+        .await
+        .map_err(|status| (status, "Failed to load token".to_string()))
 }
 
 /// Delete a token
@@ -398,6 +359,7 @@ async fn delete_token(
     if result.rows_affected() == 0 {
         return Err(StatusCode::NOT_FOUND);
     }
+    end_session(&id);
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -432,6 +394,7 @@ async fn regenerate_token(
     .execute(&state.db_pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    end_session(&id);
 
     // Fetch updated token
     let token: ApiToken = sqlx::query_as("SELECT * FROM api_tokens WHERE id = ?")
@@ -462,10 +425,18 @@ async fn revoke_token(
     if result.rows_affected() == 0 {
         return Err(StatusCode::NOT_FOUND);
     }
+    end_session(&id);
 
     Ok(StatusCode::OK)
 }
 
+/// Encryption keys unlocked with a token are locked again once the token
+/// can no longer be used
+fn end_session(token_id: &str) {
+    crate::encryption::keyring::keyring()
+        .end_session(&crate::auth::Session::for_api_token(token_id).id);
+}
+
 /// Validate a token (for testing)
 #[derive(Debug, Deserialize)]
 struct ValidateRequest {
@@ -540,7 +511,7 @@ async fn validate_token(
             Json(ValidateResponse {
                 valid: true,
                 token_name: Some(t.name),
-                scopes: Some(t.scopes.split(',').map(String::from).collect()),
+                scopes: Some(TokenGrant::new(&t.id, &t.scopes, None, None).scopes),
                 expires_at: t.expires_at,
                 message: "Token is valid".to_string(),
             })
@@ -631,6 +602,121 @@ fn hash_token(token: &str) -> String {
     format!("{:x}", hasher.finalize())
 }
 
+/// Validate requested scopes and store them under their current names
+fn normalize_scopes(scopes: &[String]) -> Result<String, (StatusCode, String)> {
+    let mut normalized = Vec::new();
+    for scope in scopes {
+        let scope = tokens::normalize_scope(scope)
+            .ok_or((StatusCode::BAD_REQUEST, format!("Invalid scope: {}", scope)))?;
+        if !normalized.contains(&scope) {
+            normalized.push(scope);
+        }
+    }
+    Ok(normalized.join(","))
+}
+
+fn normalize_ip_whitelist(ips: &[String]) -> Result<String, (StatusCode, String)> {
+    let mut rules = Vec::new();
+    for ip in ips.iter().map(|ip| ip.trim()).filter(|ip| !ip.is_empty()) {
+        if !tokens::is_valid_ip_rule(ip) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid IP address or range: {}", ip),
+            ));
+        }
+        rules.push(ip);
+    }
+    Ok(rules.join(","))
+}
+
+fn normalize_path_prefixes(prefixes: &[String]) -> Result<String, (StatusCode, String)> {
+    let mut normalized = Vec::new();
+    for prefix in prefixes {
+        let prefix = prefix.trim().trim_matches('/');
+        if prefix.is_empty() {
+            continue;
+        }
+        if prefix.contains(',') || prefix.split('/').any(|s| s == ".." || s == ".") {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid path prefix: {}", prefix),
+            ));
+        }
+        normalized.push(prefix);
+    }
+    Ok(normalized.join(","))
+}
+
+/// Authenticate a request made with a token secret (`ssk_...`) and record
+/// the use. Unknown, revoked, expired and used-up tokens are refused with
+/// 401; clients outside the token's IP allowlist with 403.
+pub(crate) async fn authenticate(
+    pool: &sqlx::SqlitePool,
+    secret: &str,
+    client_ip: Option<IpAddr>,
+) -> Result<(UserInfo, TokenGrant), StatusCode> {
+    let raw_token = secret
+        .strip_prefix("ssk_")
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let token: ApiToken =
+        sqlx::query_as("SELECT * FROM api_tokens WHERE token_hash = ? AND is_active = 1")
+            .bind(hash_token(raw_token))
+            .fetch_optional(pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let expired = token
+        .expires_at
+        .as_deref()
+        .and_then(|exp| exp.parse::<DateTime<Utc>>().ok())
+        .is_some_and(|exp| exp < Utc::now());
+    let used_up = token.max_uses.is_some_and(|max| token.usage_count >= max);
+    if expired || used_up {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let user = crate::auth::get_user_by_id(pool, &token.user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let user = UserInfo {
+        id: user.id,
+        username: user.username,
+        totp_enabled: user.totp_enabled,
+        role: user.role,
+        is_admin: user.is_admin,
+    };
+
+    let grant = TokenGrant::new(
+        &token.id,
+        &token.scopes,
+        token.path_prefixes.as_deref(),
+        token.ip_whitelist.as_deref(),
+    );
+    if !grant.allows_ip(client_ip) {
+        let address = client_ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+        crate::rbac::log_denial(
+            pool,
+            &user,
+            "AUTH",
+            &format!("api-token:{}", token.id),
+            &format!("address {}", address),
+        )
+        .await;
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    sqlx::query(
+        "UPDATE api_tokens SET usage_count = usage_count + 1, last_used_at = datetime('now') WHERE id = ?",
+    )
+    .bind(&token.id)
+    .execute(pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((user, grant))
+}
+
 fn token_to_response(token: ApiToken) -> ApiTokenResponse {
     ApiTokenResponse {
         id: token.id,
@@ -648,6 +734,11 @@ fn token_to_response(token: ApiToken) -> ApiTokenResponse {
         max_uses: token.max_uses,
         ip_whitelist: token
             .ip_whitelist
+            .filter(|s| !s.is_empty())
+            .map(|s| s.split(',').map(String::from).collect()),
+        path_prefixes: token
+            .path_prefixes
+            .filter(|s| !s.is_empty())
             .map(|s| s.split(',').map(String::from).collect()),
         is_active: token.is_active,
         created_at: token.created_at,
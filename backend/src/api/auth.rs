@@ -9,7 +9,7 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::auth::{User, UserInfo};
+use crate::auth::{Session, User, UserInfo};
 use crate::services;
 use crate::AppState;
 
@@ -280,11 +280,17 @@ async fn disable_2fa_handler(
 async fn refresh_token_handler(
     State(state): State<AppState>,
     user: UserInfo,
+    session: Session,
 ) -> Result<Json<AuthResponse>, StatusCode> {
-    services::refresh_token(&state, &user)
+    let response = services::refresh_token(&state, &user)
         .await
-        .map(Json)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Unlocked encryption keys carry over to the new access token
+    if let Ok(claims) = crate::auth::verify_token(&response.token) {
+        crate::encryption::keyring::keyring()
+            .rebind(&session.id, &Session::for_access_token(&response.token, &claims));
+    }
+    Ok(Json(response))
 }
 
 /// Logout user (revoke all refresh tokens)
@@ -292,6 +298,7 @@ async fn refresh_token_handler(
 async fn logout_handler(
     State(state): State<AppState>,
     user: UserInfo,
+    session: Session,
 ) -> Result<StatusCode, StatusCode> {
     tracing::info!("Logout attempt");
 
@@ -300,6 +307,8 @@ async fn logout_handler(
         .await
         .map(|_| {
             tracing::info!("Logout successful");
+            // Unlocked encryption keys end with the session
+            crate::encryption::keyring::keyring().end_session(&session.id);
             // Log logout
             let state_clone = state.clone();
             tokio::spawn(async move {
@@ -1,6 +1,9 @@
 //! Enhanced Backup & Restore API endpoints
 
 use crate::auth::UserInfo;
+use crate::backup::BackupOptions;
+use crate::jobs::queue::JobQueue;
+use crate::jobs::types::{Job, JobType};
 use crate::AppState;
 use axum::{
     extract::{Path, Query, State},
@@ -11,11 +14,12 @@ use axum::{
 use chrono::{Duration, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use std::sync::Arc;
 use uuid::Uuid;
 
 #[derive(Debug, Deserialize)]
 pub struct CreateBackupRequest {
-    pub backup_type: String, // 'full', 'incremental', 'database', 'files'
+    pub backup_type: String, // 'full', 'incremental', 'differential', 'database', 'files'
     pub include_versions: Option<bool>,
     pub include_database: Option<bool>,
     pub description: Option<String>,
@@ -41,6 +45,14 @@ pub struct Backup {
     pub description: Option<String>,
     pub checksum: Option<String>,
     pub destination_type: Option<String>,
+    pub destination_id: Option<String>,
+    /// Where the archive was uploaded to
+    pub remote_path: Option<String>,
+    /// 'uploading', 'uploaded' or 'failed'; `None` without a destination
+    pub upload_status: Option<String>,
+    pub upload_error: Option<String>,
+    pub parent_backup_id: Option<String>,
+    pub base_backup_id: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, FromRow)]
@@ -62,6 +74,11 @@ pub struct BackupSchedule {
     pub include_versions: bool,
     pub include_database: bool,
     pub max_backups: Option<i32>,
+    pub keep_daily: Option<i32>,
+    pub keep_weekly: Option<i32>,
+    pub keep_monthly: Option<i32>,
+    pub keep_yearly: Option<i32>,
+    pub full_every: Option<i32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, FromRow)]
@@ -130,6 +147,13 @@ pub struct CreateScheduleRequest {
     pub include_versions: Option<bool>,
     pub include_database: Option<bool>,
     pub max_backups: Option<i32>,
+    /// Grandfather-father-son retention; replaces retention_days when set
+    pub keep_daily: Option<i32>,
+    pub keep_weekly: Option<i32>,
+    pub keep_monthly: Option<i32>,
+    pub keep_yearly: Option<i32>,
+    /// Incremental or differential backups between two full ones
+    pub full_every: Option<i32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -169,6 +193,7 @@ pub fn router() -> Router<AppState> {
         .route("/backups/{backup_id}/verify", post(verify_backup))
         .route("/backups/{backup_id}/restore", post(restore_backup))
         .route("/backups/{backup_id}/download", get(download_backup))
+        .route("/backups/{backup_id}/chain", get(get_backup_chain))
         .route(
             "/backups/{backup_id}/verifications",
             get(list_verifications),
@@ -223,7 +248,9 @@ async fn list_backups(
     let mut sql = String::from(
         "SELECT id, backup_type, size_bytes, file_count, storage_path, created_by, created_at, 
                 status, error_message, COALESCE(is_encrypted, 0) as is_encrypted, 
-                compressed_size_bytes, description, checksum, destination_type
+                compressed_size_bytes, description, checksum, destination_type,
+                destination_id, remote_path, upload_status, upload_error,
+                parent_backup_id, base_backup_id
          FROM backups WHERE 1=1",
     );
 
@@ -321,16 +348,26 @@ async fn create_backup(
     user: UserInfo,
     Json(req): Json<CreateBackupRequest>,
 ) -> Result<Json<Backup>, StatusCode> {
+    let options = backup_options(
+        &req.backup_type,
+        req.include_database.unwrap_or(true),
+        req.include_versions.unwrap_or(true),
+    )?;
     let backup_id = Uuid::new_v4().to_string();
     let now = Utc::now().to_rfc3339();
-    let storage_path = format!("./data/backups/{}.zip", backup_id);
+    let storage_path = crate::backup::archive_path(&backup_id)
+        .to_string_lossy()
+        .into_owned();
+    let (destination_id, destination_type) =
+        pick_destination(&state.db_pool, req.destination_id.as_deref()).await?;
 
     // Insert backup record
     sqlx::query(
         r#"
         INSERT INTO backups (id, backup_type, size_bytes, file_count, storage_path, created_by, 
-                           created_at, status, is_encrypted, description, destination_type)
-        VALUES (?, ?, 0, 0, ?, ?, ?, 'in_progress', ?, ?, 'local')
+                           created_at, status, is_encrypted, description, destination_type,
+                           destination_id, compression_type)
+        VALUES (?, ?, 0, 0, ?, ?, ?, 'in_progress', ?, ?, ?, ?, 'tar.zst')
         "#,
     )
     .bind(&backup_id)
@@ -340,6 +377,8 @@ async fn create_backup(
     .bind(&now)
     .bind(req.encrypt.unwrap_or(false))
     .bind(&req.description)
+    .bind(&destination_type)
+    .bind(&destination_id)
     .execute(&state.db_pool)
     .await
     .map_err(|e| {
@@ -348,24 +387,7 @@ async fn create_backup(
     })?;
 
     // Queue background job for actual backup creation
-    let job_id = Uuid::new_v4().to_string();
-    let job_params = serde_json::json!({
-        "backup_id": backup_id,
-        "backup_type": req.backup_type,
-        "include_versions": req.include_versions.unwrap_or(true),
-        "include_database": req.include_database.unwrap_or(true),
-        "encrypt": req.encrypt.unwrap_or(false),
-        "destination_id": req.destination_id
-    });
-
-    let _ = sqlx::query(
-        "INSERT INTO jobs (id, job_type, status, parameters, created_at, priority) 
-         VALUES (?, 'backup_creation', 'pending', ?, datetime('now'), 'high')",
-    )
-    .bind(&job_id)
-    .bind(job_params.to_string())
-    .execute(&state.db_pool)
-    .await;
+    enqueue_backup_creation(&state.db_pool, &backup_id, options, &user.id).await?;
 
     let backup = Backup {
         id: backup_id,
@@ -381,12 +403,96 @@ async fn create_backup(
         compressed_size_bytes: None,
         description: req.description,
         checksum: None,
-        destination_type: Some("local".to_string()),
+        destination_type: Some(destination_type),
+        destination_id,
+        remote_path: None,
+        upload_status: None,
+        upload_error: None,
+        parent_backup_id: None,
+        base_backup_id: None,
     };
 
     Ok(Json(backup))
 }
 
+/// What a backup of `backup_type` contains
+fn backup_options(
+    backup_type: &str,
+    include_database: bool,
+    include_versions: bool,
+) -> Result<BackupOptions, StatusCode> {
+    match backup_type {
+        "database" => Ok(BackupOptions {
+            database: true,
+            files: false,
+            versions: false,
+        }),
+        "files" => Ok(BackupOptions {
+            database: false,
+            files: true,
+            versions: include_versions,
+        }),
+        // Incremental and differential backups only differ in what they
+        // are taken against, see crate::backup::chain
+        "full" | "incremental" | "differential" => Ok(BackupOptions {
+            database: include_database,
+            files: true,
+            versions: include_versions,
+        }),
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// The destination a new backup goes to: the requested one, else the
+/// default one. Backups without a destination stay in the local backup
+/// directory only.
+async fn pick_destination(
+    pool: &sqlx::SqlitePool,
+    requested: Option<&str>,
+) -> Result<(Option<String>, String), StatusCode> {
+    let destination_id = match requested {
+        Some(id) => Some(id.to_string()),
+        None => crate::backup::destination::default_id(pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    };
+    let Some(destination_id) = destination_id else {
+        return Ok((None, "local".to_string()));
+    };
+    let destination_type: Option<String> =
+        sqlx::query_scalar("SELECT destination_type FROM backup_destinations WHERE id = ?")
+            .bind(&destination_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let destination_type = destination_type.ok_or(StatusCode::BAD_REQUEST)?;
+    Ok((Some(destination_id), destination_type))
+}
+
+async fn enqueue_backup_creation(
+    pool: &sqlx::SqlitePool,
+    backup_id: &str,
+    options: BackupOptions,
+    created_by: &str,
+) -> Result<(), StatusCode> {
+    let job_type = JobType::BackupCreation {
+        backup_id: backup_id.to_string(),
+        include_files: options.files,
+        include_database: options.database,
+        include_versions: options.versions,
+    };
+    let job = Job::new(job_type, Some(created_by.to_string()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    JobQueue::new(Arc::new(pool.clone()))
+        .enqueue(job)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to queue backup {}: {}", backup_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(())
+}
+
 async fn get_backup(
     State(state): State<AppState>,
     _user: UserInfo,
@@ -396,7 +502,9 @@ async fn get_backup(
         r#"
         SELECT id, backup_type, size_bytes, file_count, storage_path, created_by, created_at, 
                status, error_message, COALESCE(is_encrypted, 0) as is_encrypted, 
-               compressed_size_bytes, description, checksum, destination_type
+               compressed_size_bytes, description, checksum, destination_type,
+                destination_id, remote_path, upload_status, upload_error,
+               parent_backup_id, base_backup_id
         FROM backups WHERE id = ?
         "#,
     )
@@ -414,27 +522,60 @@ async fn delete_backup(
     _user: UserInfo,
     Path(backup_id): Path<String>,
 ) -> Result<StatusCode, StatusCode> {
-    // Get storage path to delete file
-    let backup: Option<(String,)> = sqlx::query_as("SELECT storage_path FROM backups WHERE id = ?")
+    let exists: Option<String> = sqlx::query_scalar("SELECT id FROM backups WHERE id = ?")
         .bind(&backup_id)
         .fetch_optional(&state.db_pool)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if exists.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
 
-    if let Some((storage_path,)) = backup {
-        // Delete file
-        let _ = tokio::fs::remove_file(&storage_path).await;
+    // Later links of the chain need this backup to be restored
+    let children = crate::backup::chain::children(&state.db_pool, &backup_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !children.is_empty() {
+        return Err(StatusCode::CONFLICT);
+    }
 
-        // Delete database record
-        sqlx::query("DELETE FROM backups WHERE id = ?")
-            .bind(&backup_id)
-            .execute(&state.db_pool)
+    crate::backup::chain::delete(&state.db_pool, &backup_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to delete backup {}: {}", backup_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    release_backup_blocks(&state.db_pool, &backup_id).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Drop the block store references held by a deduplicated backup
+async fn release_backup_blocks(pool: &sqlx::SqlitePool, backup_id: &str) {
+    let manifests: Vec<String> =
+        sqlx::query_scalar("SELECT manifest_id FROM backup_block_refs WHERE backup_id = ?")
+            .bind(backup_id)
+            .fetch_all(pool)
             .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            .unwrap_or_default();
 
-        Ok(StatusCode::NO_CONTENT)
-    } else {
-        Err(StatusCode::NOT_FOUND)
+    if manifests.is_empty() {
+        return;
+    }
+
+    if let Err(e) = sqlx::query("DELETE FROM backup_block_refs WHERE backup_id = ?")
+        .bind(backup_id)
+        .execute(pool)
+        .await
+    {
+        tracing::warn!("Failed to delete block refs for backup {}: {}", backup_id, e);
+        return;
+    }
+
+    for manifest_id in manifests {
+        if let Err(e) = crate::block_store::release_manifest(pool, &manifest_id).await {
+            tracing::warn!("Failed to release manifest {}: {}", manifest_id, e);
+        }
     }
 }
 
@@ -444,30 +585,84 @@ async fn verify_backup(
     Path(backup_id): Path<String>,
 ) -> Result<Json<BackupVerification>, StatusCode> {
     // Verify backup exists
-    let backup: Option<(String, String)> =
+    let backup: Option<(String, Option<String>)> =
         sqlx::query_as("SELECT storage_path, checksum FROM backups WHERE id = ?")
             .bind(&backup_id)
             .fetch_optional(&state.db_pool)
             .await
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let (storage_path, _checksum) = backup.ok_or(StatusCode::NOT_FOUND)?;
+    let (storage_path, checksum) = backup.ok_or(StatusCode::NOT_FOUND)?;
 
-    // Check if file exists
-    let file_exists = tokio::fs::metadata(&storage_path).await.is_ok();
+    // Read every archive of the chain and check every entry against its
+    // manifest, and every archive against the checksum recorded for it
+    let verified = match crate::backup::destination::fetch_chain(&state.db_pool, &backup_id).await
+    {
+        Ok(_) => crate::backup::verify(std::path::Path::new(&storage_path)).await,
+        Err(e) => Err(e),
+    };
+    let details = match &verified {
+        Ok(links) => {
+            let mut mismatched = Vec::new();
+            for link in links {
+                let id = &link.manifest.backup_id;
+                let recorded = if *id == backup_id {
+                    checksum.clone()
+                } else {
+                    sqlx::query_scalar("SELECT checksum FROM backups WHERE id = ?")
+                        .bind(id)
+                        .fetch_optional(&state.db_pool)
+                        .await
+                        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                        .flatten()
+                };
+                if recorded.is_some_and(|recorded| recorded != link.sha256) {
+                    mismatched.push(id.clone());
+                }
+            }
+            let chain: Vec<_> = links
+                .iter()
+                .map(|link| {
+                    serde_json::json!({
+                        "backup_id": link.manifest.backup_id,
+                        "entries": link.manifest.entries.len(),
+                        "stored_bytes": link.manifest.stored_size(),
+                        "archive_sha256": link.sha256,
+                    })
+                })
+                .collect();
+            let manifest = &links[0].manifest;
+            let mut details = serde_json::json!({
+                "entries": manifest.entries.len(),
+                "size_bytes": manifest.total_size(),
+                "archive_sha256": links[0].sha256,
+                "chain": chain,
+            });
+            if !mismatched.is_empty() {
+                details["error"] = serde_json::json!(format!(
+                    "archive checksum of backup {} does not match the one recorded at backup time",
+                    mismatched.join(", ")
+                ));
+            }
+            details
+        }
+        Err(e) => serde_json::json!({ "error": format!("{:#}", e) }),
+    };
+    let passed = verified.is_ok() && details.get("error").is_none();
 
     let verification_id = Uuid::new_v4().to_string();
     let now = Utc::now().to_rfc3339();
-    let status = if file_exists { "passed" } else { "failed" };
-    let details = serde_json::json!({
-        "file_exists": file_exists,
-        "verified_by": user.id,
-        "verification_method": "file_check"
-    });
+    let status = if passed { "passed" } else { "failed" };
+    tracing::info!(
+        "Backup {} verification by {}: {}",
+        backup_id,
+        user.username,
+        status
+    );
 
     sqlx::query(
         "INSERT INTO backup_verifications (id, backup_id, verification_type, status, details, verified_at)
-         VALUES (?, ?, 'file_check', ?, ?, ?)"
+         VALUES (?, ?, 'checksum', ?, ?, ?)"
     )
     .bind(&verification_id)
     .bind(&backup_id)
@@ -481,7 +676,7 @@ async fn verify_backup(
     Ok(Json(BackupVerification {
         id: verification_id,
         backup_id,
-        verification_type: "file_check".to_string(),
+        verification_type: "checksum".to_string(),
         status: status.to_string(),
         details: Some(details.to_string()),
         verified_at: now,
@@ -495,16 +690,23 @@ async fn restore_backup(
     Json(req): Json<RestoreRequest>,
 ) -> Result<Json<BackupRestore>, StatusCode> {
     // Verify backup exists and is completed
-    let backup: Option<(String, String)> =
-        sqlx::query_as("SELECT storage_path, status FROM backups WHERE id = ?")
-            .bind(&backup_id)
-            .fetch_optional(&state.db_pool)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let (storage_path, status) = backup.ok_or(StatusCode::NOT_FOUND)?;
+    let status: Option<String> = sqlx::query_scalar("SELECT status FROM backups WHERE id = ?")
+        .bind(&backup_id)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    if status != "completed" {
+    if status.ok_or(StatusCode::NOT_FOUND)? != "completed" {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // A full restore brings back the database too; partial and selective
+    // ones only restore the listed files, optionally below restore_path
+    let include_database = match req.restore_type.as_str() {
+        "full" => true,
+        "partial" | "selective" => false,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    if !include_database && req.file_paths.as_ref().is_none_or(|paths| paths.is_empty()) {
         return Err(StatusCode::BAD_REQUEST);
     }
 
@@ -526,25 +728,21 @@ async fn restore_backup(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    // Queue restore job
-    let job_id = Uuid::new_v4().to_string();
-    let job_params = serde_json::json!({
-        "restore_id": restore_id,
-        "backup_id": backup_id,
-        "storage_path": storage_path,
-        "restore_type": req.restore_type,
-        "restore_path": req.restore_path,
-        "file_paths": req.file_paths
-    });
-
-    let _ = sqlx::query(
-        "INSERT INTO jobs (id, job_type, status, parameters, created_at, priority) 
-         VALUES (?, 'backup_restore', 'pending', ?, datetime('now'), 'high')",
-    )
-    .bind(&job_id)
-    .bind(job_params.to_string())
-    .execute(&state.db_pool)
-    .await;
+    // Queue restore job; a damaged archive does not get better by retrying
+    let job_type = JobType::BackupRestore {
+        restore_id: restore_id.clone(),
+        backup_id: backup_id.clone(),
+        include_database,
+        file_paths: req.file_paths.clone().filter(|_| !include_database),
+        restore_path: req.restore_path.clone(),
+    };
+    let mut job = Job::new(job_type, Some(user.id.clone()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    job.max_attempts = 1;
+    JobQueue::new(Arc::new(state.db_pool.clone()))
+        .enqueue(job)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     Ok(Json(BackupRestore {
         id: restore_id,
@@ -577,6 +775,13 @@ async fn download_backup(
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let (storage_path, backup_type) = backup.ok_or(StatusCode::NOT_FOUND)?;
+    // Archives only kept at their destination are downloaded first
+    crate::backup::destination::fetch(&state.db_pool, &backup_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch backup {}: {:#}", backup_id, e);
+            StatusCode::BAD_GATEWAY
+        })?;
 
     let file = tokio::fs::File::open(&storage_path)
         .await
@@ -585,11 +790,17 @@ async fn download_backup(
     let stream = ReaderStream::new(file);
     let body = Body::from_stream(stream);
 
-    let filename = format!("backup_{}_{}.zip", backup_type, backup_id);
+    // Backups written before archives were tar+zstd are zip files
+    let (extension, content_type) = if storage_path.ends_with(crate::backup::ARCHIVE_EXTENSION) {
+        (crate::backup::ARCHIVE_EXTENSION, "application/zstd")
+    } else {
+        ("zip", "application/zip")
+    };
+    let filename = format!("backup_{}_{}.{}", backup_type, backup_id, extension);
 
     Ok(axum::response::Response::builder()
         .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/zip")
+        .header(header::CONTENT_TYPE, content_type)
         .header(
             header::CONTENT_DISPOSITION,
             format!("attachment; filename=\"{}\"", filename),
@@ -598,6 +809,43 @@ async fn download_backup(
         .unwrap())
 }
 
+/// The backup and the links before it, back to the full backup a restore of
+/// it needs
+async fn get_backup_chain(
+    State(state): State<AppState>,
+    _user: UserInfo,
+    Path(backup_id): Path<String>,
+) -> Result<Json<Vec<Backup>>, StatusCode> {
+    let ids = crate::backup::chain::links(&state.db_pool, &backup_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut chain = Vec::new();
+    for id in ids {
+        let backup = sqlx::query_as::<_, Backup>(
+            r#"
+            SELECT id, backup_type, size_bytes, file_count, storage_path, created_by, created_at,
+                   status, error_message, COALESCE(is_encrypted, 0) as is_encrypted,
+                   compressed_size_bytes, description, checksum, destination_type,
+                destination_id, remote_path, upload_status, upload_error,
+                   parent_backup_id, base_backup_id
+            FROM backups WHERE id = ?
+            "#,
+        )
+        .bind(&id)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        match backup {
+            Some(backup) => chain.push(backup),
+            // The first id is the requested backup itself
+            None if chain.is_empty() => return Err(StatusCode::NOT_FOUND),
+            None => break,
+        }
+    }
+
+    Ok(Json(chain))
+}
+
 async fn list_verifications(
     State(state): State<AppState>,
     _user: UserInfo,
@@ -619,23 +867,29 @@ async fn cleanup_backups(
     State(state): State<AppState>,
     _user: UserInfo,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    // Delete backups older than retention period based on schedule settings
-    let deleted = sqlx::query(
-        r#"
-        DELETE FROM backups 
-        WHERE id IN (
-            SELECT b.id FROM backups b
-            JOIN backup_schedules s ON b.schedule_id = s.id
-            WHERE datetime(b.created_at) < datetime('now', '-' || COALESCE(s.retention_days, 30) || ' days')
-        )
-        "#
-    )
-    .execute(&state.db_pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Apply the retention of every schedule; manual backups are kept
+    let schedules: Vec<String> = sqlx::query_scalar("SELECT id FROM backup_schedules")
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut deleted = Vec::new();
+    for schedule_id in schedules {
+        let pruned = crate::backup::retention::apply(&state.db_pool, &schedule_id, Utc::now())
+            .await
+            .map_err(|e| {
+                tracing::error!("Retention of schedule {} failed: {}", schedule_id, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        for backup_id in &pruned {
+            release_backup_blocks(&state.db_pool, backup_id).await;
+        }
+        deleted.extend(pruned);
+    }
 
     Ok(Json(serde_json::json!({
-        "deleted_count": deleted.rows_affected(),
+        "deleted_count": deleted.len(),
+        "deleted": deleted,
         "message": "Cleanup completed"
     })))
 }
@@ -664,11 +918,13 @@ async fn list_schedules(
 ) -> Result<Json<Vec<BackupSchedule>>, StatusCode> {
     let schedules = sqlx::query_as::<_, BackupSchedule>(
         r#"
-        SELECT id, name, description, cron_expression, backup_type, enabled, 
-               destination_type, destination_id, retention_days, created_by, created_at,
-               last_run_at, next_run_at, COALESCE(encryption_enabled, 0) as encryption_enabled,
+        SELECT id, name, description, COALESCE(cron_expression, '') as cron_expression,
+               backup_type, enabled, destination_type, destination_id, retention_days,
+               created_by, created_at, last_run_at, next_run_at,
+               COALESCE(encryption_enabled, 0) as encryption_enabled,
                COALESCE(include_versions, 1) as include_versions, 
-               COALESCE(include_database, 1) as include_database, max_backups
+               COALESCE(include_database, 1) as include_database, max_backups,
+               keep_daily, keep_weekly, keep_monthly, keep_yearly, full_every
         FROM backup_schedules ORDER BY created_at DESC
         "#,
     )
@@ -698,8 +954,9 @@ async fn create_schedule(
         INSERT INTO backup_schedules (id, name, description, cron_expression, backup_type, enabled,
                                      destination_type, destination_id, retention_days, created_by, 
                                      created_at, next_run_at, encryption_enabled, include_versions, 
-                                     include_database, max_backups)
-        VALUES (?, ?, ?, ?, ?, 1, 'local', ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                                     include_database, max_backups, keep_daily, keep_weekly,
+                                     keep_monthly, keep_yearly, full_every)
+        VALUES (?, ?, ?, ?, ?, 1, 'local', ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         "#,
     )
     .bind(&schedule_id)
@@ -716,6 +973,11 @@ async fn create_schedule(
     .bind(req.include_versions.unwrap_or(true))
     .bind(req.include_database.unwrap_or(true))
     .bind(req.max_backups.unwrap_or(10))
+    .bind(req.keep_daily)
+    .bind(req.keep_weekly)
+    .bind(req.keep_monthly)
+    .bind(req.keep_yearly)
+    .bind(req.full_every)
     .execute(&state.db_pool)
     .await
     .map_err(|e| {
@@ -741,6 +1003,11 @@ async fn create_schedule(
         include_versions: req.include_versions.unwrap_or(true),
         include_database: req.include_database.unwrap_or(true),
         max_backups: req.max_backups,
+        keep_daily: req.keep_daily,
+        keep_weekly: req.keep_weekly,
+        keep_monthly: req.keep_monthly,
+        keep_yearly: req.keep_yearly,
+        full_every: req.full_every,
     }))
 }
 
@@ -751,11 +1018,13 @@ async fn get_schedule(
 ) -> Result<Json<BackupSchedule>, StatusCode> {
     let schedule = sqlx::query_as::<_, BackupSchedule>(
         r#"
-        SELECT id, name, description, cron_expression, backup_type, enabled, 
-               destination_type, destination_id, retention_days, created_by, created_at,
-               last_run_at, next_run_at, COALESCE(encryption_enabled, 0) as encryption_enabled,
+        SELECT id, name, description, COALESCE(cron_expression, '') as cron_expression,
+               backup_type, enabled, destination_type, destination_id, retention_days,
+               created_by, created_at, last_run_at, next_run_at,
+               COALESCE(encryption_enabled, 0) as encryption_enabled,
                COALESCE(include_versions, 1) as include_versions, 
-               COALESCE(include_database, 1) as include_database, max_backups
+               COALESCE(include_database, 1) as include_database, max_backups,
+               keep_daily, keep_weekly, keep_monthly, keep_yearly, full_every
         FROM backup_schedules WHERE id = ?
         "#,
     )
@@ -778,7 +1047,8 @@ async fn update_schedule(
         UPDATE backup_schedules 
         SET name = ?, description = ?, cron_expression = ?, backup_type = ?,
             destination_id = ?, retention_days = ?, encryption_enabled = ?,
-            include_versions = ?, include_database = ?, max_backups = ?
+            include_versions = ?, include_database = ?, max_backups = ?,
+            keep_daily = ?, keep_weekly = ?, keep_monthly = ?, keep_yearly = ?, full_every = ?
         WHERE id = ?
         "#,
     )
@@ -792,6 +1062,11 @@ async fn update_schedule(
     .bind(req.include_versions.unwrap_or(true))
     .bind(req.include_database.unwrap_or(true))
     .bind(req.max_backups.unwrap_or(10))
+    .bind(req.keep_daily)
+    .bind(req.keep_weekly)
+    .bind(req.keep_monthly)
+    .bind(req.keep_yearly)
+    .bind(req.full_every)
     .bind(&schedule_id)
     .execute(&state.db_pool)
     .await
@@ -860,8 +1135,8 @@ async fn trigger_schedule(
     Path(schedule_id): Path<String>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
     // Get schedule details
-    let schedule: Option<(String, bool, bool, bool)> = sqlx::query_as(
-        "SELECT backup_type, encryption_enabled, include_versions, include_database 
+    let schedule: Option<(String, bool, bool, bool, Option<String>)> = sqlx::query_as(
+        "SELECT backup_type, encryption_enabled, include_versions, include_database, destination_id
          FROM backup_schedules WHERE id = ?",
     )
     .bind(&schedule_id)
@@ -869,19 +1144,25 @@ async fn trigger_schedule(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let (backup_type, encrypt, include_versions, include_database) =
+    let (backup_type, encrypt, include_versions, include_database, destination_id) =
         schedule.ok_or(StatusCode::NOT_FOUND)?;
+    let options = backup_options(&backup_type, include_database, include_versions)?;
 
     // Create backup
     let backup_id = Uuid::new_v4().to_string();
     let now = Utc::now().to_rfc3339();
-    let storage_path = format!("./data/backups/{}.zip", backup_id);
+    let storage_path = crate::backup::archive_path(&backup_id)
+        .to_string_lossy()
+        .into_owned();
+    let (destination_id, destination_type) =
+        pick_destination(&state.db_pool, destination_id.as_deref()).await?;
 
     sqlx::query(
         r#"
         INSERT INTO backups (id, backup_type, size_bytes, file_count, storage_path, created_by, 
-                           created_at, status, is_encrypted, schedule_id, destination_type)
-        VALUES (?, ?, 0, 0, ?, ?, ?, 'in_progress', ?, ?, 'local')
+                           created_at, status, is_encrypted, schedule_id, destination_type,
+                           destination_id, compression_type)
+        VALUES (?, ?, 0, 0, ?, ?, ?, 'in_progress', ?, ?, ?, ?, 'tar.zst')
         "#,
     )
     .bind(&backup_id)
@@ -891,6 +1172,8 @@ async fn trigger_schedule(
     .bind(&now)
     .bind(encrypt)
     .bind(&schedule_id)
+    .bind(&destination_type)
+    .bind(&destination_id)
     .execute(&state.db_pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -904,24 +1187,7 @@ async fn trigger_schedule(
         .ok();
 
     // Queue job
-    let job_id = Uuid::new_v4().to_string();
-    let job_params = serde_json::json!({
-        "backup_id": backup_id,
-        "backup_type": backup_type,
-        "include_versions": include_versions,
-        "include_database": include_database,
-        "encrypt": encrypt,
-        "schedule_id": schedule_id
-    });
-
-    let _ = sqlx::query(
-        "INSERT INTO jobs (id, job_type, status, parameters, created_at, priority) 
-         VALUES (?, 'backup_creation', 'pending', ?, datetime('now'), 'high')",
-    )
-    .bind(&job_id)
-    .bind(job_params.to_string())
-    .execute(&state.db_pool)
-    .await;
+    enqueue_backup_creation(&state.db_pool, &backup_id, options, &user.id).await?;
 
     Ok(Json(serde_json::json!({
         "message": "Backup triggered successfully",
@@ -954,6 +1220,14 @@ async fn create_destination(
 ) -> Result<Json<BackupDestination>, StatusCode> {
     let dest_id = Uuid::new_v4().to_string();
     let now = Utc::now().to_rfc3339();
+    let (config, sealed) = seal_destination(
+        &state.db_pool,
+        &dest_id,
+        &req.destination_type,
+        req.config,
+        false,
+    )
+    .await?;
 
     // If setting as default, unset other defaults
     if req.is_default.unwrap_or(false) {
@@ -963,13 +1237,14 @@ async fn create_destination(
     }
 
     sqlx::query(
-        "INSERT INTO backup_destinations (id, name, destination_type, config, is_default, created_by, created_at, status)
-         VALUES (?, ?, ?, ?, ?, ?, ?, 'active')"
+        "INSERT INTO backup_destinations (id, name, destination_type, config, secrets, is_default, created_by, created_at, status)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, 'active')"
     )
     .bind(&dest_id)
     .bind(&req.name)
     .bind(&req.destination_type)
-    .bind(req.config.to_string())
+    .bind(&config)
+    .bind(&sealed)
     .bind(req.is_default.unwrap_or(false))
     .bind(&user.id)
     .bind(&now)
@@ -1012,6 +1287,16 @@ async fn update_destination(
     Path(destination_id): Path<String>,
     Json(req): Json<CreateDestinationRequest>,
 ) -> Result<Json<BackupDestination>, StatusCode> {
+    // Credentials left out of the config are kept
+    let (config, sealed) = seal_destination(
+        &state.db_pool,
+        &destination_id,
+        &req.destination_type,
+        req.config,
+        true,
+    )
+    .await?;
+
     if req.is_default.unwrap_or(false) {
         let _ = sqlx::query("UPDATE backup_destinations SET is_default = 0")
             .execute(&state.db_pool)
@@ -1019,11 +1304,12 @@ async fn update_destination(
     }
 
     sqlx::query(
-        "UPDATE backup_destinations SET name = ?, destination_type = ?, config = ?, is_default = ? WHERE id = ?"
+        "UPDATE backup_destinations SET name = ?, destination_type = ?, config = ?, secrets = ?, is_default = ? WHERE id = ?"
     )
     .bind(&req.name)
     .bind(&req.destination_type)
-    .bind(req.config.to_string())
+    .bind(&config)
+    .bind(&sealed)
     .bind(req.is_default.unwrap_or(false))
     .bind(&destination_id)
     .execute(&state.db_pool)
@@ -1074,33 +1360,102 @@ async fn delete_destination(
     }
 }
 
+/// Check a destination config and move its credentials into the sealed
+/// `secrets` column; returns the config and sealed credentials to store.
+/// With `keep_existing`, credentials already stored for `id` are kept
+/// unless the new config replaces them.
+async fn seal_destination(
+    pool: &sqlx::SqlitePool,
+    id: &str,
+    destination_type: &str,
+    mut config: serde_json::Value,
+    keep_existing: bool,
+) -> Result<(String, Option<String>), StatusCode> {
+    let mut secrets = serde_json::Map::new();
+    if keep_existing {
+        let stored: Option<String> =
+            sqlx::query_scalar("SELECT secrets FROM backup_destinations WHERE id = ?")
+                .bind(id)
+                .fetch_optional(pool)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .flatten();
+        if let Some(stored) = stored.filter(|stored| !stored.is_empty()) {
+            let revealed = crate::secrets::reveal(
+                pool,
+                &crate::secrets::BACKUP_DESTINATION_SECRETS,
+                id,
+                &stored,
+                "destination update",
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to read credentials of destination {}: {}", id, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            if let Ok(serde_json::Value::Object(revealed)) = serde_json::from_str(&revealed) {
+                secrets.extend(revealed);
+            }
+        }
+    }
+    if let Some(serde_json::Value::Object(new)) =
+        crate::backup::destination::split_secrets(&mut config)
+    {
+        secrets.extend(new);
+    }
+
+    let mut full = config.clone();
+    if let Some(full) = full.as_object_mut() {
+        full.extend(secrets.clone());
+    }
+    if let Err(e) = crate::backup::destination::build(id, destination_type, &full) {
+        tracing::warn!("Invalid backup destination config: {:#}", e);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let sealed = if secrets.is_empty() {
+        None
+    } else {
+        let plaintext = serde_json::Value::Object(secrets).to_string();
+        let sealed = crate::secrets::seal(&crate::secrets::BACKUP_DESTINATION_SECRETS, id, &plaintext)
+            .map_err(|e| {
+                tracing::error!("Failed to seal destination credentials: {}", e);
+                match e {
+                    crate::secrets::SecretError::NotConfigured => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                }
+            })?;
+        Some(sealed)
+    };
+    Ok((config.to_string(), sealed))
+}
+
 async fn test_destination(
     State(state): State<AppState>,
     _user: UserInfo,
     Path(destination_id): Path<String>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    let destination: Option<(String, String)> =
-        sqlx::query_as("SELECT destination_type, config FROM backup_destinations WHERE id = ?")
+    let exists: Option<String> =
+        sqlx::query_scalar("SELECT id FROM backup_destinations WHERE id = ?")
             .bind(&destination_id)
             .fetch_optional(&state.db_pool)
             .await
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let (dest_type, config) = destination.ok_or(StatusCode::NOT_FOUND)?;
+    exists.ok_or(StatusCode::NOT_FOUND)?;
     let now = Utc::now().to_rfc3339();
 
-    // Test connection based on type
-    let success = match dest_type.as_str() {
-        "local" => {
-            let config: serde_json::Value = serde_json::from_str(&config).unwrap_or_default();
-            let path = config
-                .get("path")
-                .and_then(|p| p.as_str())
-                .unwrap_or("./data/backups");
-            tokio::fs::metadata(path).await.is_ok() || tokio::fs::create_dir_all(path).await.is_ok()
-        }
-        _ => true, // For now, assume other types pass
+    // Connect, log in and write and delete a probe file
+    let probed = match crate::backup::destination::open(
+        &state.db_pool,
+        &destination_id,
+        "destination test",
+    )
+    .await
+    {
+        Ok(destination) => destination.probe().await,
+        Err(e) => Err(e),
     };
+    let success = probed.is_ok();
 
     // Update last verified
     let status = if success { "active" } else { "error" };
@@ -1114,7 +1469,10 @@ async fn test_destination(
 
     Ok(Json(serde_json::json!({
         "success": success,
-        "message": if success { "Connection successful" } else { "Connection failed" },
+        "message": match &probed {
+            Ok(()) => "Connection successful".to_string(),
+            Err(e) => format!("Connection failed: {:#}", e),
+        },
         "tested_at": now
     })))
 }
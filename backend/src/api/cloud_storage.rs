@@ -55,7 +55,7 @@ pub struct StorageBackend {
 #[derive(Debug, Deserialize)]
 pub struct CreateBackendRequest {
     pub name: String,
-    pub backend_type: String, // 's3', 'minio', 'gcs', 'azure_blob'
+    pub backend_type: String, // 'local', 's3', 'minio', 'gcs'
     pub config: serde_json::Value,
     pub is_active: Option<bool>,
     pub priority: Option<i64>,
@@ -88,6 +88,8 @@ pub struct StorageMigrationJob {
     pub completed_at: Option<String>,
     pub created_by: String,
     pub created_at: String,
+    pub cursor_file_id: Option<String>,
+    pub updated_at: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -130,18 +132,16 @@ async fn create_backend(
         return Err(StatusCode::FORBIDDEN);
     }
 
-    // Validate backend type
-    if !matches!(
-        req.backend_type.as_str(),
-        "s3" | "minio" | "gcs" | "azure_blob" | "local"
-    ) {
-        return Err(StatusCode::BAD_REQUEST);
-    }
-
     let backend_id = uuid::Uuid::new_v4().to_string();
     let now = chrono::Utc::now().to_rfc3339();
     let config_json = serde_json::to_string(&req.config).unwrap_or_default();
 
+    // Validate backend type and configuration
+    if let Err(e) = crate::storage::build_backend(&backend_id, &req.backend_type, &config_json) {
+        eprintln!("Invalid storage backend configuration: {}", e);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
     sqlx::query(
         "INSERT INTO storage_backends (id, name, backend_type, config, is_active, is_default, priority, created_at, updated_at)
          VALUES (?, ?, ?, ?, ?, 0, ?, ?, ?)"
@@ -161,6 +161,8 @@ async fn create_backend(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    reload_backends(&state).await;
+
     Ok(Json(serde_json::json!({
         "id": backend_id,
         "message": "Storage backend created successfully"
@@ -231,6 +233,17 @@ async fn update_backend(
     }
     if let Some(config) = req.config {
         let config_json = serde_json::to_string(&config).unwrap_or_default();
+        let backend_type: String =
+            sqlx::query_scalar("SELECT backend_type FROM storage_backends WHERE id = ?")
+                .bind(&id)
+                .fetch_optional(&state.db_pool)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .ok_or(StatusCode::NOT_FOUND)?;
+        if let Err(e) = crate::storage::build_backend(&id, &backend_type, &config_json) {
+            eprintln!("Invalid storage backend configuration: {}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
         sqlx::query("UPDATE storage_backends SET config = ?, updated_at = ? WHERE id = ?")
             .bind(&config_json)
             .bind(&now)
@@ -258,6 +271,8 @@ async fn update_backend(
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     }
 
+    reload_backends(&state).await;
+
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -287,32 +302,75 @@ async fn delete_backend(
         return Err(StatusCode::CONFLICT); // Cannot delete backend with files
     }
 
+    // The default backend receives new uploads and cannot be removed
+    if crate::storage::default_backend().id() == id {
+        return Err(StatusCode::CONFLICT);
+    }
+
     sqlx::query("DELETE FROM storage_backends WHERE id = ?")
         .bind(&id)
         .execute(&state.db_pool)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    reload_backends(&state).await;
+
     Ok(StatusCode::NO_CONTENT)
 }
 
-// Check backend health
+// Check backend health: write, read back and delete a probe object
 async fn check_backend_health(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
     UserInfo { .. }: UserInfo,
-    Path(_id): Path<String>,
+    Path(id): Path<String>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // TODO: Implement actual health check logic for each backend type
-    // For now, return a placeholder response
+    let backend = crate::storage::backend_by_id(&state.db_pool, &id)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let results = crate::storage::probe(backend.as_ref()).await;
+    let now = chrono::Utc::now().to_rfc3339();
+    let passed = results.iter().filter(|r| r.passed).count();
+    let status = if passed == 3 {
+        "healthy"
+    } else if passed > 0 {
+        "degraded"
+    } else {
+        "unhealthy"
+    };
+
+    for result in &results {
+        let _ = sqlx::query(
+            "INSERT INTO storage_backend_health (id, backend_id, check_type, status, response_time_ms, error_message, checked_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(&id)
+        .bind(result.check_type)
+        .bind(if result.passed { "healthy" } else { "unhealthy" })
+        .bind(result.response_time_ms)
+        .bind(&result.error)
+        .bind(&now)
+        .execute(&state.db_pool)
+        .await;
+    }
+
+    let checks: serde_json::Map<String, serde_json::Value> = results
+        .iter()
+        .map(|r| {
+            (
+                r.check_type.to_string(),
+                serde_json::json!(if r.passed { "pass" } else { "fail" }),
+            )
+        })
+        .collect();
+
     Ok(Json(serde_json::json!({
-        "backend_id": _id,
-        "status": "healthy",
-        "checks": {
-            "connectivity": "pass",
-            "read": "pass",
-            "write": "pass"
-        },
-        "last_check": chrono::Utc::now().to_rfc3339()
+        "backend_id": id,
+        "status": status,
+        "checks": checks,
+        "results": results,
+        "last_check": now
     })))
 }
 
@@ -320,7 +378,7 @@ async fn check_backend_health(
 async fn test_backend_connection(
     State(state): State<AppState>,
     UserInfo { id: user_id, .. }: UserInfo,
-    Path(_id): Path<String>,
+    Path(id): Path<String>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let is_admin = check_admin_permission(&state.db_pool, &user_id)
         .await
@@ -330,11 +388,27 @@ async fn test_backend_connection(
         return Err(StatusCode::FORBIDDEN);
     }
 
-    // TODO: Implement actual connection test
+    let backend = crate::storage::backend_by_id(&state.db_pool, &id)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let results = crate::storage::probe(backend.as_ref()).await;
+    let success = results.len() == 3 && results.iter().all(|r| r.passed);
+    let latency_ms: i64 = results.iter().map(|r| r.response_time_ms).sum();
+    let message = match results.iter().find(|r| !r.passed) {
+        Some(failed) => format!(
+            "{} check failed: {}",
+            failed.check_type,
+            failed.error.as_deref().unwrap_or("unknown error")
+        ),
+        None => "Connection test successful".to_string(),
+    };
+
     Ok(Json(serde_json::json!({
-        "success": true,
-        "message": "Connection test successful",
-        "latency_ms": 45
+        "success": success,
+        "message": message,
+        "latency_ms": latency_ms,
+        "checks": results
     })))
 }
 
@@ -369,6 +443,8 @@ async fn set_default_backend(
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    reload_backends(&state).await;
+
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -386,6 +462,23 @@ async fn create_migration_job(
         return Err(StatusCode::FORBIDDEN);
     }
 
+    if req.source_backend_id == req.target_backend_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    for backend_id in [&req.source_backend_id, &req.target_backend_id] {
+        crate::storage::backend_by_id(&state.db_pool, backend_id)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+    }
+
+    // Reject filters the migration runner would not understand
+    if let Some(filter) = &req.file_filter
+        && !filter.is_null()
+        && serde_json::from_value::<crate::storage::migration::FileFilter>(filter.clone()).is_err()
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
     let job_id = uuid::Uuid::new_v4().to_string();
     let now = chrono::Utc::now().to_rfc3339();
     let filter_json = req
@@ -410,6 +503,8 @@ async fn create_migration_job(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    crate::storage::migration::spawn_job(state.db_pool.clone(), job_id.clone());
+
     Ok(Json(serde_json::json!({
         "id": job_id,
         "message": "Migration job created successfully"
@@ -466,7 +561,9 @@ async fn cancel_migration_job(
         return Err(StatusCode::FORBIDDEN);
     }
 
-    sqlx::query("UPDATE storage_migration_jobs SET status = 'cancelled' WHERE id = ? AND status = 'pending'")
+    // A running job notices the status change before its next file
+    sqlx::query("UPDATE storage_migration_jobs SET status = 'cancelled', updated_at = ? WHERE id = ? AND status IN ('pending', 'running')")
+        .bind(chrono::Utc::now().to_rfc3339())
         .bind(&id)
         .execute(&state.db_pool)
         .await
@@ -521,6 +618,13 @@ async fn get_storage_stats(
     }))
 }
 
+// Helper: Refresh the in-memory backend registry after configuration changes
+async fn reload_backends(state: &AppState) {
+    if let Err(e) = crate::storage::reload(&state.db_pool).await {
+        eprintln!("Failed to reload storage backends: {}", e);
+    }
+}
+
 // Helper: Check if user has admin permission
 async fn check_admin_permission(
     pool: &sqlx::SqlitePool,
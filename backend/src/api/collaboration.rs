@@ -68,13 +68,15 @@ async fn acquire_lock(
     user: UserInfo,
     Json(req): Json<AcquireLockRequest>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    // Use lock_type and duration_seconds from request
-    let _lock_type = &req.lock_type; // For future lock type differentiation
-    let _duration = req.duration_seconds; // For custom lock duration
-
-    let lock = services::collaboration::acquire_lock(&state, &user, &req.file_path)
-        .await
-        .map_err(|_| StatusCode::CONFLICT)?;
+    let lock = services::collaboration::acquire_lock(
+        &state,
+        &user,
+        &req.file_path,
+        &req.lock_type,
+        req.duration_seconds,
+    )
+    .await
+    .map_err(|_| StatusCode::CONFLICT)?;
     serde_json::to_value(lock)
         .map(Json)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
@@ -27,6 +27,56 @@ fn extract_mentions(text: &str) -> Vec<String> {
         .collect()
 }
 
+/// Notify the users mentioned in a comment on `file_path`, except its author
+fn notify_mentions(state: &AppState, author: &UserInfo, file_path: &str, text: &str) {
+    let mut mentions = extract_mentions(text);
+    mentions.sort();
+    mentions.dedup();
+    mentions.retain(|username| *username != author.username);
+    if mentions.is_empty() {
+        return;
+    }
+
+    let pool = state.db_pool.clone();
+    let author = author.clone();
+    let file_path = file_path.to_string();
+    let text = text.to_string();
+    tokio::spawn(async move {
+        let file_name = file_path.rsplit('/').next().unwrap_or(&file_path).to_string();
+        let folder = file_path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+        let action_url = format!("/#/files/{}", folder.trim_start_matches('/'));
+        let title = format!("{} mentioned you on {}", author.username, file_name);
+        for username in mentions {
+            let user_id: Option<String> =
+                match sqlx::query_scalar("SELECT id FROM users WHERE username = ?")
+                    .bind(&username)
+                    .fetch_optional(&pool)
+                    .await
+                {
+                    Ok(user_id) => user_id,
+                    Err(e) => {
+                        tracing::warn!("Failed to look up mentioned user {}: {}", username, e);
+                        continue;
+                    }
+                };
+            let Some(user_id) = user_id else {
+                continue;
+            };
+            crate::notify::send(
+                crate::notify::Notification::new(
+                    &user_id,
+                    crate::notify::EventType::CommentMention,
+                    &title,
+                    &text,
+                )
+                .with_action_url(&action_url)
+                .from_user(&author.id)
+                .with_context(serde_json::json!({"file_path": file_path})),
+            );
+        }
+    });
+}
+
 #[derive(Debug, Deserialize)]
 pub struct FilePathQuery {
     pub path: String,
@@ -107,6 +157,8 @@ async fn create_comment(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    notify_mentions(&state, &user_info, &req.file_path, &sanitized_content);
+
     // Log activity
     let file_name = req.file_path.split('/').last().unwrap_or(&req.file_path).to_string();
     let state_clone = state.clone();
@@ -381,8 +433,7 @@ async fn create_file_comment(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    // TODO: Send notifications to mentioned users via WebSocket
-    // For each username in mentions, look up user_id and send notification
+    notify_mentions(&state, &user_info, &query.path, &sanitized_content);
 
     Ok((
         StatusCode::CREATED,
@@ -0,0 +1,379 @@
+//! Delta Upload API Routes
+//!
+//! Upload a modified file by sending only the changed bytes:
+//!
+//! 1. `GET /delta/signature/{path}` returns block signatures of the current
+//!    server copy together with its checksum.
+//! 2. The client encodes its new content against the signature (see
+//!    `crate::delta` for the wire format) and sends it with
+//!    `POST /delta/upload/{path}?base_checksum=..&checksum=..`.
+//!
+//! The server rebuilds the file next to the other temp uploads, checks the
+//! result against `checksum`, and finalizes it through the same quota check
+//! as chunked uploads before recording a new version.
+
+use axum::{
+    Router,
+    body::Body,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Json},
+    routing::{get, post},
+};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio_util::io::StreamReader;
+
+use crate::AppState;
+use crate::auth::UserInfo;
+use crate::delta::{self, DeltaError};
+use crate::services::version_storage_service;
+
+const DATA_DIR: &str = "./data";
+
+#[derive(Debug, Deserialize)]
+pub struct SignatureQuery {
+    pub block_size: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeltaUploadQuery {
+    /// Checksum of the signature the delta was computed against
+    pub base_checksum: String,
+    /// SHA-256 of the complete new content
+    pub checksum: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeltaUploadResponse {
+    pub success: bool,
+    pub file_path: String,
+    pub size: u64,
+    pub checksum: String,
+    /// Bytes reused from the server copy
+    pub copied_bytes: u64,
+    /// Bytes sent by the client
+    pub literal_bytes: u64,
+    pub version_number: Option<i32>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/delta/signature/{*path}", get(get_signature))
+        .route("/delta/upload/{*path}", post(upload_delta))
+}
+
+/// Resolve an existing regular file under the data directory
+async fn existing_file(path: &str) -> Result<(String, PathBuf), StatusCode> {
+    let safe_path = crate::security::validate_file_path(path)?;
+    let absolute = std::path::Path::new(DATA_DIR).join(&safe_path);
+    let meta = fs::metadata(&absolute)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    if !meta.is_file() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok((safe_path, absolute))
+}
+
+/// Block signatures of the current server copy
+async fn get_signature(
+    Path(path): Path<String>,
+    _user: UserInfo,
+    Query(query): Query<SignatureQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let (_, absolute) = existing_file(&path).await?;
+    let size = fs::metadata(&absolute)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?
+        .len();
+    let block_size = query
+        .block_size
+        .unwrap_or_else(|| delta::default_block_size(size));
+
+    let signature = delta::signature(&absolute, block_size)
+        .await
+        .map_err(|e| match e {
+            DeltaError::Invalid(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+    Ok(Json(signature))
+}
+
+/// Rebuild a file from the server copy and an uploaded delta
+async fn upload_delta(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    user: UserInfo,
+    Query(query): Query<DeltaUploadQuery>,
+    body: Body,
+) -> Result<Json<DeltaUploadResponse>, StatusCode> {
+    let (safe_path, absolute) = existing_file(&path).await?;
+
+    let (base_id, base_checksum) = base_of(&state.db_pool, &safe_path, &absolute).await?;
+    // The file changed since the client fetched its signature
+    if !base_checksum.eq_ignore_ascii_case(&query.base_checksum) {
+        return Err(StatusCode::PRECONDITION_FAILED);
+    }
+    let max_size = max_result_size(&state.db_pool, &user.id, &safe_path).await?;
+
+    let upload_dir = PathBuf::from("./data/temp_uploads").join(uuid::Uuid::new_v4().to_string());
+    fs::create_dir_all(&upload_dir)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let result = rebuild_and_finalize(
+        &state,
+        &user,
+        &safe_path,
+        &absolute,
+        base_id.as_deref(),
+        &upload_dir,
+        &query,
+        max_size,
+        body,
+    )
+    .await;
+
+    // Clean up the temp upload (also on failure)
+    if let Err(e) = fs::remove_dir_all(&upload_dir).await {
+        tracing::warn!("Failed to cleanup delta upload directory: {}", e);
+    }
+
+    result.map(Json)
+}
+
+/// Id in `files` of the file a delta is applied to and the checksum the
+/// client's signature has to match
+async fn base_of(
+    pool: &SqlitePool,
+    safe_path: &str,
+    absolute: &std::path::Path,
+) -> Result<(Option<String>, String), StatusCode> {
+    let base: Option<(String, Option<String>)> = sqlx::query_as(
+        "SELECT id, checksum_sha256 FROM files WHERE path = ? AND is_deleted = 0
+         ORDER BY updated_at DESC LIMIT 1",
+    )
+    .bind(safe_path)
+    .fetch_optional(pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    match base {
+        Some((id, Some(checksum))) => Ok((Some(id), checksum)),
+        other => {
+            let (_, checksum) = crate::storage::sha256_file(absolute)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            Ok((other.map(|(id, _)| id), checksum))
+        }
+    }
+}
+
+/// The result can never be larger than what the quota check would accept,
+/// which is the free space plus the size of the file being replaced
+async fn max_result_size(
+    pool: &SqlitePool,
+    user_id: &str,
+    safe_path: &str,
+) -> Result<u64, StatusCode> {
+    let available = crate::api::quota::available_bytes(pool, user_id).await?;
+    let replaced = crate::api::quota::replaced_bytes(pool, user_id, safe_path).await?;
+    Ok((available + replaced) as u64)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn rebuild_and_finalize(
+    state: &AppState,
+    user: &UserInfo,
+    safe_path: &str,
+    absolute: &std::path::Path,
+    base_id: Option<&str>,
+    upload_dir: &std::path::Path,
+    query: &DeltaUploadQuery,
+    max_size: u64,
+    body: Body,
+) -> Result<DeltaUploadResponse, StatusCode> {
+    let assembled_path = upload_dir.join("assembled");
+    let reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
+
+    let mut assembled = fs::File::create(&assembled_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let rebuilt = delta::apply(absolute, reader, &mut assembled, max_size)
+        .await
+        .map_err(|e| {
+            tracing::warn!("Delta upload to '{}' rejected: {}", safe_path, e);
+            match e {
+                DeltaError::Invalid(_) => StatusCode::BAD_REQUEST,
+                DeltaError::TooLarge(_) => StatusCode::INSUFFICIENT_STORAGE,
+                DeltaError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        })?;
+
+    assembled
+        .sync_data()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    drop(assembled);
+
+    if !rebuilt.checksum.eq_ignore_ascii_case(&query.checksum) {
+        tracing::warn!(
+            "Delta upload to '{}' produced {} instead of {}",
+            safe_path,
+            rebuilt.checksum,
+            query.checksum
+        );
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    // Keep the content being replaced if the file has no history yet
+    if let Some(base_id) = base_id {
+        let versions: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM file_versions WHERE file_id = ?")
+                .bind(base_id)
+                .fetch_one(&state.db_pool)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if versions == 0
+            && let Err(e) = version_storage_service::create_version(
+                &state.db_pool,
+                base_id,
+                absolute,
+                &user.id,
+                Some("Before delta upload"),
+            )
+            .await
+        {
+            tracing::warn!("Failed to keep previous version of {}: {}", safe_path, e);
+        }
+    }
+
+    let file = crate::api::upload_chunk::finalize_assembled_upload(
+        state,
+        user,
+        &assembled_path,
+        safe_path,
+    )
+    .await?;
+
+    let file_id = base_id
+        .map(str::to_string)
+        .unwrap_or_else(|| file.id.to_string());
+    let version_number = match version_storage_service::create_version(
+        &state.db_pool,
+        &file_id,
+        absolute,
+        &user.id,
+        Some("Delta upload"),
+    )
+    .await
+    {
+        Ok(version) => Some(version.version_number),
+        Err(e) => {
+            tracing::warn!("Failed to create version for {}: {}", safe_path, e);
+            None
+        }
+    };
+
+    tracing::info!(
+        "Delta upload finalized: {} ({} bytes, {} reused, {} sent)",
+        file.path,
+        rebuilt.size,
+        rebuilt.copied_bytes,
+        rebuilt.literal_bytes
+    );
+
+    Ok(DeltaUploadResponse {
+        success: true,
+        file_path: file.path,
+        size: rebuilt.size,
+        checksum: rebuilt.checksum,
+        copied_bytes: rebuilt.copied_bytes,
+        literal_bytes: rebuilt.literal_bytes,
+        version_number,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn quota_pool() -> SqlitePool {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::raw_sql(
+            "CREATE TABLE users (
+                id TEXT PRIMARY KEY,
+                storage_used_bytes INTEGER NOT NULL,
+                storage_quota_bytes INTEGER NOT NULL
+            );
+            CREATE TABLE files (
+                id TEXT PRIMARY KEY,
+                path TEXT NOT NULL,
+                owner_id TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL,
+                checksum_sha256 TEXT,
+                is_deleted INTEGER NOT NULL DEFAULT 0,
+                updated_at TEXT NOT NULL
+            );
+            INSERT INTO users VALUES ('alice', 900, 1000), ('bob', 100, 1000);
+            INSERT INTO files VALUES
+                ('f1', 'docs/plan.txt', 'alice', 300, 'ABC123', 0, '2026-01-02'),
+                ('f0', 'docs/plan.txt', 'alice', 50, 'old', 1, '2026-01-03'),
+                ('f2', 'docs/notes.txt', 'alice', 40, NULL, 0, '2026-01-01');",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn test_base_checksum() {
+        let pool = quota_pool().await;
+        let dir = tempfile::TempDir::new().unwrap();
+        let absolute = dir.path().join("notes.txt");
+        fs::write(&absolute, b"hello").await.unwrap();
+        // sha256("hello")
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        // The recorded checksum of the live row is trusted
+        let (id, checksum) = base_of(&pool, "docs/plan.txt", &absolute).await.unwrap();
+        assert_eq!((id.as_deref(), checksum.as_str()), (Some("f1"), "ABC123"));
+        // Without one, or without a row, the file on disk is hashed
+        let (id, checksum) = base_of(&pool, "docs/notes.txt", &absolute).await.unwrap();
+        assert_eq!((id.as_deref(), checksum.as_str()), (Some("f2"), hello));
+        let (id, checksum) = base_of(&pool, "docs/new.txt", &absolute).await.unwrap();
+        assert_eq!((id, checksum.as_str()), (None, hello));
+    }
+
+    #[tokio::test]
+    async fn test_max_result_size_counts_the_replaced_file() {
+        let pool = quota_pool().await;
+        // 100 bytes free plus the 300 of the live plan.txt being replaced
+        assert_eq!(
+            max_result_size(&pool, "alice", "docs/plan.txt").await,
+            Ok(400)
+        );
+        assert_eq!(
+            max_result_size(&pool, "alice", "docs/new.txt").await,
+            Ok(100)
+        );
+        // Files of others free nothing for the uploader
+        assert_eq!(
+            max_result_size(&pool, "bob", "docs/plan.txt").await,
+            Ok(900)
+        );
+        assert_eq!(
+            max_result_size(&pool, "carol", "docs/plan.txt").await,
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+}
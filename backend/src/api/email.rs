@@ -115,7 +115,7 @@ async fn test_connection(
         .await
         .map_err(|_| StatusCode::NOT_FOUND)?;
 
-    match email_integration::test_email_connection(&account).await {
+    match email_integration::test_email_connection(&state.db_pool, &account).await {
         Ok(true) => Ok(Json(serde_json::json!({
             "success": true,
             "message": "Connection test passed"
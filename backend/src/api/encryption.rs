@@ -1,20 +1,26 @@
 //! File Encryption API Endpoints
 //! Provides REST API for file encryption at rest with AES-256-GCM
+//!
+//! Encrypting, decrypting and downloading encrypted files needs the key
+//! unlocked: either once per session via `/encryption/keys/{key_id}/unlock`
+//! or by passing its password along with the request.
+//!
+//! Encrypting a file deletes its versions, search index entry, thumbnails
+//! and previews, which would otherwise keep its plaintext.
 
 use axum::{
     extract::{Path, State},
     http::StatusCode,
-    routing::{delete, get, post, put},
+    routing::{delete, get, post},
     Json, Router,
 };
-use base64::Engine;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    auth::UserInfo,
+    auth::{Session, UserInfo},
     encryption::{
-        create_encryption_key, deactivate_encryption_key, is_file_encrypted,
-        list_encryption_keys, EncryptionKey,
+        create_encryption_key, deactivate_encryption_key, get_encryption_key, is_file_encrypted,
+        keyring::keyring, list_encryption_keys, unlock_key, EncryptionKey, SecretKey,
     },
     AppState,
 };
@@ -26,6 +32,8 @@ pub fn router() -> Router<AppState> {
         .route("/encryption/keys", get(list_keys).post(create_key))
         .route("/encryption/keys/{key_id}", delete(delete_key))
         .route("/encryption/keys/{key_id}/rotate", post(rotate_key))
+        .route("/encryption/keys/{key_id}/unlock", post(unlock_key_handler))
+        .route("/encryption/keys/{key_id}/lock", post(lock_key))
         // File encryption operations
         .route("/encryption/files/{file_id}/status", get(get_file_encryption_status))
         .route("/encryption/files/{file_id}/encrypt", post(encrypt_file_handler))
@@ -48,11 +56,20 @@ pub struct CreateKeyRequest {
 #[derive(Debug, Deserialize)]
 pub struct EncryptFileRequest {
     pub key_id: String,
-    pub password: String,
+    /// Not needed while the key is unlocked
+    #[serde(default)]
+    pub password: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DecryptFileRequest {
+    /// Not needed while the key is unlocked
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockKeyRequest {
     pub password: String,
 }
 
@@ -95,6 +112,7 @@ pub struct KeyResponse {
     pub created_at: String,
     pub last_used_at: Option<String>,
     pub files_count: i64,
+    pub is_unlocked: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -111,6 +129,7 @@ pub struct OperationResponse {
 async fn list_keys(
     State(state): State<AppState>,
     user: UserInfo,
+    session: Session,
 ) -> Result<Json<Vec<KeyResponse>>, StatusCode> {
     let keys = list_encryption_keys(&state.db_pool, user.user_id())
         .await
@@ -128,6 +147,7 @@ async fn list_keys(
         .unwrap_or((0,));
         
         responses.push(KeyResponse {
+            is_unlocked: keyring().is_unlocked(&session.id, user.user_id(), &key.id),
             id: key.id,
             name: key.name,
             is_active: key.is_active,
@@ -172,6 +192,7 @@ async fn create_key(
         created_at: key.created_at,
         last_used_at: key.last_used_at,
         files_count: 0,
+        is_unlocked: false,
     }))
 }
 
@@ -207,10 +228,12 @@ async fn delete_key(
     }))
 }
 
-/// Rotate an encryption key (change password)
+/// Rotate an encryption key: replace it by a new key protected by the new
+/// password and rewrap the data keys of its files. Content is not re-encrypted.
 async fn rotate_key(
     State(state): State<AppState>,
     user: UserInfo,
+    session: Session,
     Path(key_id): Path<String>,
     Json(req): Json<RotateKeyRequest>,
 ) -> Result<Json<OperationResponse>, StatusCode> {
@@ -221,100 +244,110 @@ async fn rotate_key(
             message: "New password must be at least 8 characters".to_string(),
         }));
     }
-    
-    // Get the existing key
-    let key: Option<EncryptionKey> = sqlx::query_as(
-        "SELECT * FROM encryption_keys WHERE id = ? AND user_id = ? AND is_active = 1"
-    )
-    .bind(&key_id)
-    .bind(user.user_id())
-    .fetch_optional(&state.db_pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    let key = match key {
-        Some(k) => k,
-        None => {
-            return Ok(Json(OperationResponse {
-                success: false,
-                message: "Encryption key not found".to_string(),
-            }));
-        }
+
+    let key = get_encryption_key(&state.db_pool, &key_id, user.user_id())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(key) = key else {
+        return Ok(Json(OperationResponse {
+            success: false,
+            message: "Encryption key not found".to_string(),
+        }));
     };
-    
-    // Verify old password by attempting to decrypt master key
-    use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce, Key, aead::OsRng};
-    use aes_gcm::aead::AeadCore;
-    use argon2::{Argon2, Algorithm, Params, Version};
-    
-    // Derive key from old password
-    let salt_bytes = key.salt.as_bytes();
-    let argon2 = Argon2::new(
-        Algorithm::Argon2id,
-        Version::V0x13,
-        Params::new(15000, 2, 1, Some(32)).unwrap()
-    );
-    
-    let mut old_derived = [0u8; 32];
-    if argon2.hash_password_into(req.old_password.as_bytes(), salt_bytes, &mut old_derived).is_err() {
+
+    let Ok(old_key) = unlock_key(&key, &req.old_password) else {
         return Ok(Json(OperationResponse {
             success: false,
             message: "Invalid old password".to_string(),
         }));
-    }
-    
-    let stored_key = base64::engine::general_purpose::STANDARD.decode(&key.key_hash)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    if stored_key.len() < 12 {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    }
-    
-    let (nonce_bytes, encrypted_master_key) = stored_key.split_at(12);
-    let nonce = Nonce::from_slice(nonce_bytes);
-    
-    let old_cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&old_derived));
-    let master_key_bytes = match old_cipher.decrypt(nonce, encrypted_master_key) {
-        Ok(mk) => mk,
-        Err(_) => {
-            return Ok(Json(OperationResponse {
-                success: false,
-                message: "Invalid old password".to_string(),
-            }));
-        }
     };
-    
-    // Re-encrypt master key with new password
-    let mut new_derived = [0u8; 32];
-    argon2.hash_password_into(req.new_password.as_bytes(), salt_bytes, &mut new_derived)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    let new_cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&new_derived));
-    let new_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    
-    let new_encrypted_master = new_cipher.encrypt(&new_nonce, master_key_bytes.as_slice())
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    let mut new_stored_key = new_nonce.to_vec();
-    new_stored_key.extend_from_slice(&new_encrypted_master);
-    let new_key_hash = base64::engine::general_purpose::STANDARD.encode(&new_stored_key);
-    
-    // Update the key in database
-    sqlx::query(
-        "UPDATE encryption_keys SET key_hash = ?, last_used_at = datetime('now') WHERE id = ?"
-    )
-    .bind(&new_key_hash)
-    .bind(&key_id)
-    .execute(&state.db_pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
+
+    let (new_key, files) =
+        crate::encryption::rotate_key(&state.db_pool, &key, &old_key, &req.new_password)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to rotate encryption key {}: {}", key_id, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+    // Copies unlocked under the old password are no longer valid
+    keyring().lock_key(&key_id);
+    keyring().unlock(&session, user.user_id(), &key_id, new_key);
+
     Ok(Json(OperationResponse {
         success: true,
-        message: "Encryption key password rotated successfully".to_string(),
+        message: format!("Encryption key rotated, {} file keys rewrapped", files),
     }))
 }
 
+/// Unlock a key for this session so encrypted files can be downloaded
+async fn unlock_key_handler(
+    State(state): State<AppState>,
+    user: UserInfo,
+    session: Session,
+    Path(key_id): Path<String>,
+    Json(req): Json<UnlockKeyRequest>,
+) -> Result<Json<OperationResponse>, StatusCode> {
+    let key = get_encryption_key(&state.db_pool, &key_id, user.user_id())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(key) = key else {
+        return Ok(Json(OperationResponse {
+            success: false,
+            message: "Encryption key not found or not active".to_string(),
+        }));
+    };
+
+    match key_encryption_key(&session, user.user_id(), &key, Some(&req.password)) {
+        Ok(_) => Ok(Json(OperationResponse {
+            success: true,
+            message: format!(
+                "Encryption key unlocked for {} minutes of inactivity",
+                crate::encryption::keyring::IDLE_TIMEOUT.as_secs() / 60
+            ),
+        })),
+        Err(message) => Ok(Json(OperationResponse {
+            success: false,
+            message: message.to_string(),
+        })),
+    }
+}
+
+/// Lock a key again before its idle timeout
+async fn lock_key(
+    session: Session,
+    Path(key_id): Path<String>,
+) -> Json<OperationResponse> {
+    let was_unlocked = keyring().lock(&session.id, &key_id);
+    Json(OperationResponse {
+        success: true,
+        message: if was_unlocked {
+            "Encryption key locked".to_string()
+        } else {
+            "Encryption key was not unlocked".to_string()
+        },
+    })
+}
+
+/// The key-encryption key of `key`, unlocking it for `session` with
+/// `password` if given
+fn key_encryption_key(
+    session: &Session,
+    user_id: &str,
+    key: &EncryptionKey,
+    password: Option<&str>,
+) -> Result<SecretKey, &'static str> {
+    match password {
+        Some(password) => {
+            let unlocked = unlock_key(key, password).map_err(|_| "Invalid password")?;
+            keyring().unlock(session, user_id, &key.id, unlocked.clone());
+            Ok(unlocked)
+        }
+        None => keyring()
+            .get(&session.id, user_id, &key.id)
+            .ok_or("Encryption key is locked, unlock it or provide its password"),
+    }
+}
+
 // ============================================================================
 // File Encryption Handlers
 // ============================================================================
@@ -373,110 +406,122 @@ async fn get_file_encryption_status(
     }))
 }
 
-/// Encrypt a file
+/// Encrypt a file in place under its own data key
 async fn encrypt_file_handler(
     State(state): State<AppState>,
     user: UserInfo,
+    session: Session,
     Path(file_id): Path<String>,
     Json(req): Json<EncryptFileRequest>,
 ) -> Result<Json<OperationResponse>, StatusCode> {
-    // Check if file exists
+    // Only the owner can encrypt a file
     let file_exists: Option<(String,)> = sqlx::query_as(
-        "SELECT id FROM files WHERE id = ?"
+        "SELECT id FROM files WHERE id = ? AND owner_id = ? AND is_deleted = 0"
     )
     .bind(&file_id)
+    .bind(user.user_id())
     .fetch_optional(&state.db_pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
+
     if file_exists.is_none() {
         return Ok(Json(OperationResponse {
             success: false,
             message: "File not found".to_string(),
         }));
     }
-    
+
     // Check if already encrypted
     if is_file_encrypted(&state.db_pool, &file_id)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? 
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
     {
         return Ok(Json(OperationResponse {
             success: false,
             message: "File is already encrypted".to_string(),
         }));
     }
-    
+
     // Verify key exists and belongs to user
-    let key: Option<EncryptionKey> = sqlx::query_as(
-        "SELECT * FROM encryption_keys WHERE id = ? AND user_id = ? AND is_active = 1"
-    )
-    .bind(&req.key_id)
-    .bind(user.user_id())
-    .fetch_optional(&state.db_pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    if key.is_none() {
+    let key = get_encryption_key(&state.db_pool, &req.key_id, user.user_id())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(key) = key else {
         return Ok(Json(OperationResponse {
             success: false,
             message: "Encryption key not found or not active".to_string(),
         }));
-    }
-    
-    // In a real implementation, we would:
-    // 1. Read the file content
-    // 2. Encrypt it with the master key (after decrypting master key with password)
-    // 3. Write the encrypted content back
-    // 4. Store the encryption record
-    
-    // For now, we'll create a placeholder encryption record
-    let encryption_id = uuid::Uuid::new_v4().to_string();
-    let nonce_placeholder = base64::engine::general_purpose::STANDARD.encode(vec![0u8; 12]);
-    
-    sqlx::query(
-        "INSERT INTO encrypted_files (id, file_id, key_id, nonce, created_at)
-         VALUES (?, ?, ?, ?, datetime('now'))"
+    };
+
+    let key_encryption_key =
+        match key_encryption_key(&session, user.user_id(), &key, req.password.as_deref()) {
+            Ok(unlocked) => unlocked,
+            Err(message) => {
+                return Ok(Json(OperationResponse {
+                    success: false,
+                    message: message.to_string(),
+                }));
+            }
+        };
+
+    crate::encryption::encrypt_file(
+        &state.db_pool,
+        &state.search_index,
+        &file_id,
+        &key.id,
+        user.user_id(),
+        &key_encryption_key,
     )
-    .bind(&encryption_id)
-    .bind(&file_id)
-    .bind(&req.key_id)
-    .bind(&nonce_placeholder)
-    .execute(&state.db_pool)
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    // Update key last_used_at
-    sqlx::query("UPDATE encryption_keys SET last_used_at = datetime('now') WHERE id = ?")
-        .bind(&req.key_id)
-        .execute(&state.db_pool)
-        .await
-        .ok();
-    
+    .map_err(|e| {
+        tracing::error!("Failed to encrypt file {}: {}", file_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    forget_renderings(&state.db_pool, &file_id).await.map_err(|e| {
+        tracing::error!("Failed to delete thumbnails of encrypted file {}: {}", file_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
     Ok(Json(OperationResponse {
         success: true,
         message: "File encrypted successfully".to_string(),
     }))
 }
 
-/// Decrypt a file
+/// Delete the thumbnails and previews rendered from the plaintext of a file
+/// just encrypted
+async fn forget_renderings(
+    pool: &sqlx::SqlitePool,
+    file_id: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    crate::thumbnails::delete_thumbnails(file_id).await?;
+    sqlx::query("DELETE FROM file_thumbnails WHERE file_id = ?")
+        .bind(file_id)
+        .execute(pool)
+        .await?;
+    crate::file_preview::delete_previews(pool, file_id).await?;
+    Ok(())
+}
+
+/// Decrypt a file in place and drop its data key
 async fn decrypt_file_handler(
     State(state): State<AppState>,
     user: UserInfo,
+    session: Session,
     Path(file_id): Path<String>,
-    Json(_req): Json<DecryptFileRequest>,
+    Json(req): Json<DecryptFileRequest>,
 ) -> Result<Json<OperationResponse>, StatusCode> {
     // Check if file is encrypted
     if !is_file_encrypted(&state.db_pool, &file_id)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? 
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
     {
         return Ok(Json(OperationResponse {
             success: false,
             message: "File is not encrypted".to_string(),
         }));
     }
-    
+
     // Verify user owns the encryption key
     let enc_record: Option<(String,)> = sqlx::query_as(
         "SELECT ef.key_id FROM encrypted_files ef
@@ -488,28 +533,38 @@ async fn decrypt_file_handler(
     .fetch_optional(&state.db_pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    if enc_record.is_none() {
+
+    let key = match enc_record {
+        Some((key_id,)) => get_encryption_key(&state.db_pool, &key_id, user.user_id())
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        None => None,
+    };
+    let Some(key) = key else {
         return Ok(Json(OperationResponse {
             success: false,
             message: "You don't have permission to decrypt this file".to_string(),
         }));
-    }
-    
-    // In a real implementation, we would:
-    // 1. Decrypt the master key using the password
-    // 2. Read the encrypted file content
-    // 3. Decrypt it
-    // 4. Write the decrypted content back
-    // 5. Remove the encryption record
-    
-    // For now, just remove the encryption record
-    sqlx::query("DELETE FROM encrypted_files WHERE file_id = ?")
-        .bind(&file_id)
-        .execute(&state.db_pool)
+    };
+
+    let key_encryption_key =
+        match key_encryption_key(&session, user.user_id(), &key, req.password.as_deref()) {
+            Ok(unlocked) => unlocked,
+            Err(message) => {
+                return Ok(Json(OperationResponse {
+                    success: false,
+                    message: message.to_string(),
+                }));
+            }
+        };
+
+    crate::encryption::decrypt_file(&state.db_pool, &file_id, user.user_id(), &key_encryption_key)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
+        .map_err(|e| {
+            tracing::error!("Failed to decrypt file {}: {}", file_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
     Ok(Json(OperationResponse {
         success: true,
         message: "File decrypted successfully".to_string(),
@@ -558,10 +613,10 @@ async fn get_encryption_settings(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     
-    if let Some((json,)) = settings_json {
-        if let Ok(settings) = serde_json::from_str(&json) {
-            return Ok(Json(settings));
-        }
+    if let Some((json,)) = settings_json
+        && let Ok(settings) = serde_json::from_str(&json)
+    {
+        return Ok(Json(settings));
     }
     
     // Return default settings
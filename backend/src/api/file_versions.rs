@@ -8,7 +8,6 @@ use axum::{
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use uuid::Uuid;
-use sha2::Digest;
 
 use crate::auth::UserInfo;
 use crate::AppState;
@@ -370,9 +369,9 @@ async fn download_version_content(
     Query(query): Query<FilePathQuery>,
     _user: UserInfo,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Get version file path from database
+    // Get version id from database
     let version: Option<(String, String)> = sqlx::query_as(
-        "SELECT storage_path, file_path FROM file_versions WHERE file_path = ? AND version_number = ?"
+        "SELECT id, file_path FROM file_versions WHERE file_path = ? AND version_number = ?"
     )
     .bind(&query.path)
     .bind(version_num)
@@ -380,12 +379,16 @@ async fn download_version_content(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let (storage_path, file_path) = version.ok_or(StatusCode::NOT_FOUND)?;
+    let (version_id, file_path) = version.ok_or(StatusCode::NOT_FOUND)?;
 
-    let metadata = std::fs::metadata(&storage_path)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let file_size = metadata.len();
+    // Stream the restored content; deltas and block store versions are
+    // rebuilt on the fly without loading them into memory
+    let (metadata, content) =
+        crate::services::version_storage_service::open_version(&state.db_pool, &version_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+    let file_size = metadata.original_size;
 
     // Get file extension for MIME type
     let mime_type = std::path::Path::new(&file_path)
@@ -395,12 +398,7 @@ async fn download_version_content(
         .map(|m| m.to_string())
         .unwrap_or_else(|| "application/octet-stream".to_string());
 
-    // Create response with proper headers
-    let body = axum::body::Body::from_stream(
-        tokio_util::io::ReaderStream::new(tokio::fs::File::open(&storage_path)
-            .await
-            .map_err(|_| StatusCode::NOT_FOUND)?)
-    );
+    let body = axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(content));
 
     Ok((
         [(
@@ -431,12 +429,20 @@ async fn delete_version_by_number(
     Query(query): Query<FilePathQuery>,
     user: UserInfo,
 ) -> Result<StatusCode, StatusCode> {
-    sqlx::query("DELETE FROM file_versions WHERE file_path = ? AND version_number = ?")
-        .bind(&query.path)
-        .bind(version_num)
-        .execute(&state.db_pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let version_id: Option<String> = sqlx::query_scalar(
+        "SELECT id FROM file_versions WHERE file_path = ? AND version_number = ?"
+    )
+    .bind(&query.path)
+    .bind(version_num)
+    .fetch_optional(&state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if let Some(version_id) = version_id {
+        crate::services::version_storage_service::delete_version(&state.db_pool, &version_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
 
     // Log activity
     let file_name = query.path.split('/').last().unwrap_or(&query.path).to_string();
@@ -469,9 +475,12 @@ async fn restore_version_by_number(
     user_info: UserInfo,
     Json(_req): Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    // Get the version details
-    let version: Option<(String, String, i64)> = sqlx::query_as(
-        "SELECT storage_path, file_path, size_bytes FROM file_versions WHERE file_path = ? AND version_number = ?"
+    // Versions are recorded against the file id, the live file by its path
+    let version: Option<(String, String)> = sqlx::query_as(
+        "SELECT fv.id, f.id FROM file_versions fv
+         JOIN files f ON f.id = fv.file_id
+         WHERE f.path = ? AND f.is_deleted = 0 AND fv.version_number = ?
+         ORDER BY f.updated_at DESC LIMIT 1",
     )
     .bind(&query.path)
     .bind(version_num)
@@ -479,59 +488,26 @@ async fn restore_version_by_number(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let (version_storage_path, file_path, version_size) = version.ok_or(StatusCode::NOT_FOUND)?;
-
-    // Get the current file
-    let current_file: Option<(String, String, String)> = sqlx::query_as(
-        "SELECT id, path, checksum_sha256 FROM files WHERE path = ?"
-    )
-    .bind(&file_path)
-    .fetch_optional(&state.db_pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let (file_id, current_path, _current_checksum) = current_file.ok_or(StatusCode::NOT_FOUND)?;
-
-    // Read the version content
-    let version_content = std::fs::read(&version_storage_path)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    // Calculate checksum of version content
-    let checksum = format!("{:x}", sha2::Sha256::digest(&version_content));
-
-    // Create a new version from the current content (backup before restore)
-    let current_version: (Option<i32>,) = sqlx::query_as(
-        "SELECT MAX(version_number) FROM file_versions WHERE file_path = ?"
+    let (version_id, file_id) = version.ok_or(StatusCode::NOT_FOUND)?;
+    let file_path = query.path.clone();
+    let safe_path = crate::security::validate_file_path(&file_path)?;
+    let current_path = std::path::Path::new("./data").join(&safe_path);
+
+    // Streamed and verified into place; the replaced content becomes a version
+    let (restored, replaced) = crate::services::version_storage_service::restore_version_over(
+        &state.db_pool,
+        &version_id,
+        &current_path,
+        &user_info.id,
     )
-    .bind(&file_path)
-    .fetch_one(&state.db_pool)
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let current_version_num = current_version.0.unwrap_or(0);
-    let new_version_num = current_version_num + 1;
-
-    // Backup current file to version storage if it exists
-    if let Ok(current_content) = std::fs::read(&current_path) {
-        let current_version_path = format!("{}/{}.v{}", 
-            std::path::Path::new(&current_path)
-                .parent()
-                .and_then(|p| p.to_str())
-                .unwrap_or("./data/versions"),
-            std::path::Path::new(&current_path)
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("file"),
-            current_version_num
-        );
-
-        let _ = std::fs::create_dir_all(std::path::Path::new(&current_version_path).parent().unwrap_or(std::path::Path::new(".")));
-        let _ = std::fs::write(&current_version_path, &current_content);
-    }
-
-    // Write the version content to the current path
-    std::fs::write(&current_path, &version_content)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|e| {
+        tracing::error!("Failed to restore version {} of {}: {}", version_num, file_path, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let version_size = restored.original_size;
+    let checksum = restored.checksum;
+    let new_version_num = replaced.as_ref().map(|v| v.version_number);
 
     // Update the file record
     let now = chrono::Utc::now().to_rfc3339();
@@ -572,7 +548,10 @@ async fn restore_version_by_number(
         "file_id": file_id,
         "size_bytes": version_size,
         "checksum_sha256": checksum,
-        "message": format!("Version {} restored successfully. Current version backed up as v{}", version_num, current_version_num)
+        "message": match new_version_num {
+            Some(backup) => format!("Version {} restored successfully. Current version backed up as v{}", version_num, backup),
+            None => format!("Version {} restored successfully", version_num),
+        }
     })))
 }
 
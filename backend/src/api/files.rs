@@ -2,17 +2,15 @@
 //! Handles upload, download, delete, rename, move, copy
 
 use axum::{
-    body::Body,
     extract::{Multipart, Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post, put},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
-use tokio_util::io::ReaderStream;
 
-use crate::auth::UserInfo;
+use crate::auth::{Session, UserInfo};
 use crate::models::FileInfo;
 use crate::services;
 use crate::AppState;
@@ -113,21 +111,27 @@ async fn list_files_handler(
         })
 }
 
-/// Download a file
-#[tracing::instrument(skip(state, user), fields(user_id = %user.id, file_path = %path))]
+/// Download a file (supports Range, If-Range and conditional requests)
+#[tracing::instrument(skip(state, user, headers), fields(user_id = %user.id, file_path = %path))]
 async fn download_file_handler(
     State(state): State<AppState>,
     user: UserInfo,
+    session: Session,
     Path(path): Path<String>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let file_handle = services::download_file(&state, &user, &path)
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let file = services::download_file(&state, &user, &session, &path)
         .await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
-
-    let stream = ReaderStream::new(file_handle);
-    let body = Body::from_stream(stream);
+        .map_err(|e| {
+            // Encrypted with a key the user has to unlock first
+            if e.is::<crate::encryption::Locked>() {
+                StatusCode::LOCKED
+            } else {
+                StatusCode::NOT_FOUND
+            }
+        })?;
 
-    Ok(body)
+    crate::http_range::file_response(&headers, &file).await
 }
 
 /// Upload a file (raw body)
@@ -103,7 +103,7 @@ async fn test_connection(
         .await
         .map_err(|_| StatusCode::NOT_FOUND)?;
 
-    match ftp_sync::test_ftp_connection(&connection).await {
+    match ftp_sync::test_ftp_connection(&state.db_pool, &connection).await {
         Ok(true) => Ok(Json(serde_json::json!({
             "success": true,
             "message": "Connection successful"
@@ -129,7 +129,7 @@ async fn list_remote_files(
         .await
         .map_err(|_| StatusCode::NOT_FOUND)?;
 
-    ftp_sync::list_remote_files(&connection, None)
+    ftp_sync::list_remote_files(&state.db_pool, &connection, None)
         .await
         .map(Json)
         .map_err(|e| {
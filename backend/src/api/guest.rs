@@ -830,9 +830,37 @@ async fn create_invitation(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     
+    send_invitation(&state, &user, &invitation).await?;
+    
     Ok(Json(InvitationResponse { invitation }))
 }
 
+/// Queue the invitation email for `invitation`
+async fn send_invitation(
+    state: &AppState,
+    user: &UserInfo,
+    invitation: &GuestInvitation,
+) -> Result<(), StatusCode> {
+    crate::mail::queue(
+        &state.db_pool,
+        &invitation.email,
+        None,
+        crate::mail::Kind::GuestInvitation,
+        &serde_json::json!({
+            "inviter": user.username,
+            "message": invitation.message,
+            "link": crate::mail::link(&format!("/#/guest-invite/{}", invitation.token)),
+            "expires_at": invitation.expires_at,
+        }),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| {
+        tracing::error!("Failed to queue invitation email: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
 /// Delete invitation
 async fn delete_invitation(
     State(state): State<AppState>,
@@ -860,14 +888,26 @@ async fn delete_invitation(
 
 /// Resend invitation email
 async fn resend_invitation(
-    State(_state): State<AppState>,
-    _user: UserInfo,
-    Path(_invitation_id): Path<String>,
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(invitation_id): Path<String>,
 ) -> Result<Json<OperationResponse>, StatusCode> {
-    // TODO: Implement email sending
+    let invitation: GuestInvitation = sqlx::query_as(
+        "SELECT * FROM guest_invitations WHERE id = ? AND invited_by = ?
+         AND is_accepted = 0 AND expires_at > datetime('now')"
+    )
+    .bind(&invitation_id)
+    .bind(user.user_id())
+    .fetch_optional(&state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    
+    send_invitation(&state, &user, &invitation).await?;
+    
     Ok(Json(OperationResponse {
-        success: false,
-        message: "Email sending not yet implemented".to_string(),
+        success: true,
+        message: "Invitation email queued".to_string(),
     }))
 }
 
@@ -263,7 +263,7 @@ async fn test_connection(
         }
     };
     
-    let result = ldap_integration::test_connection(&config).await;
+    let result = ldap_integration::test_connection(&state.db_pool, &config).await;
     
     if result.success {
         (StatusCode::OK, Json(result)).into_response()
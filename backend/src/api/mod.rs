@@ -19,6 +19,7 @@ pub mod conversion;
 pub mod cron;
 pub mod dashboard;
 pub mod database_health;
+pub mod delta;
 pub mod db_health;
 pub mod directories;
 pub mod duplicates;
@@ -42,19 +43,23 @@ pub mod rate_limiting;
 pub mod rbac;
 pub mod recent;
 pub mod search;
+pub mod secrets;
 pub mod setup;
 pub mod sharing;
 pub mod smart_folders;
 pub mod storage_analytics;
+pub mod sync;
 pub mod system;
 pub mod system_health;
 pub mod tags;
 pub mod themes;
 pub mod trash;
+pub mod tus;
 pub mod upload_chunk;
 pub mod users;
 pub mod versions;
 pub mod webhooks;
+pub mod websocket;
 pub mod workflow;
 
 // New API modules from POST_ALPHA_ROADMAP
@@ -70,15 +75,45 @@ pub mod compression;
 pub mod thumbnails;
 pub mod preview;
 pub mod virus_scan;
-// WebDAV (optional - needs configuration)
-// pub mod webdav;
+// WebDAV (network drive mounting, Basic or Bearer auth)
+pub mod webdav;
 
 use axum::{middleware, Router};
 
 use crate::AppState;
 
+/// Require the permissions of `policy` on every route of `router`
+fn guarded(
+    router: Router<AppState>,
+    state: &AppState,
+    policy: &'static crate::rbac::Policy,
+) -> Router<AppState> {
+    router.route_layer(middleware::from_fn_with_state(
+        crate::rbac::Guard::new(state.db_pool.clone(), policy),
+        crate::rbac::enforce,
+    ))
+}
+
+/// Shape transfers on every route of `router` and count them against the
+/// caller's bandwidth quota (see crate::throttle::bandwidth)
+fn metered(router: Router<AppState>, throttle: &crate::throttle::Throttle) -> Router<AppState> {
+    router.layer(middleware::from_fn_with_state(
+        throttle.clone(),
+        crate::throttle::bandwidth::meter,
+    ))
+}
+
 /// Build the complete API router
 pub fn build_api_router(state: AppState) -> Router<AppState> {
+    use crate::rbac::{
+        ACCOUNT, AUDIT, BACKUPS, ENCRYPTION, FILES, FILE_LISTS, GROUPS, NOTIFICATIONS, PROFILE,
+        QUOTAS, ROLES, SCANS, SERVER, SETTINGS, SHARES, SYSTEM, USERS, WEBDAV, WEBHOOKS,
+    };
+    let s = &state;
+    // Stored rate limit rules, applied once the caller is known
+    let throttle = crate::throttle::Throttle::new(state.db_pool.clone());
+    let limit = || middleware::from_fn_with_state(throttle.clone(), crate::throttle::limit);
+
     Router::new()
         // Public auth routes (login, register)
         .merge(auth::public_router())
@@ -90,80 +125,100 @@ pub fn build_api_router(state: AppState) -> Router<AppState> {
         .merge(sharing::public_router())
         // Public guest access routes (NO AUTH - token-based access)
         .merge(guest::public_router())
-        // Protected routes
+        // Peer-to-peer sync endpoints (signed by the paired instance)
+        .merge(peers::public_router())
+        // Inbound workflow webhooks (signed with the endpoint's secret)
+        .merge(workflow::public_router())
+        // Public routes are limited per address
+        .layer(limit())
+        // WebDAV accepts Basic credentials, so it has its own auth layer
+        .merge(
+            guarded(metered(webdav::router(), &throttle), s, &WEBDAV)
+                .layer(limit())
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    webdav::auth_middleware,
+                )),
+        )
+        // Protected routes; each router needs the permissions of its policy
+        // (see crate::rbac), and access tokens the matching scope
         .merge(
             Router::new()
-                .merge(auth::protected_router()) // Protected auth routes (2FA via TOTP, change-password, etc.)
-                .merge(auth_security::router()) // Auth security (sessions, login attempts, password policy)
-                .merge(users::router())
-                .merge(groups::router())
-                .merge(quota::router())
+                .merge(guarded(auth::protected_router(), s, &ACCOUNT)) // Protected auth routes (2FA via TOTP, change-password, etc.)
+                .merge(guarded(auth_security::router(), s, &ACCOUNT)) // Auth security (sessions, login attempts, password policy)
+                .merge(guarded(users::router(), s, &PROFILE))
+                .merge(guarded(groups::router(), s, &GROUPS))
+                .merge(guarded(quota::router(), s, &QUOTAS))
                 // === FILE-SCOPED ROUTES (MUST come before generic catch-all routes) ===
-                .merge(file_versions::file_versions_router()) // /files/{path}/versions/*
-                .merge(tags::file_tags_router()) // /files/{path}/tags/*
-                .merge(comments::file_comments_router()) // /files/{path}/comments/*
+                .merge(guarded(file_versions::file_versions_router(), s, &FILES)) // /files/{path}/versions/*
+                .merge(guarded(tags::file_tags_router(), s, &FILES)) // /files/{path}/tags/*
+                .merge(guarded(comments::file_comments_router(), s, &FILES)) // /files/{path}/comments/*
                 // === GENERIC ROUTES (MORE SPECIFIC FIRST) ===
-                .merge(file_versions::router())
+                .merge(guarded(file_versions::router(), s, &FILES))
                 // 2FA functionality now integrated into auth::protected_router()
-                .merge(versions::router()) // MUST come before files::router() (more specific routes first)
-                .merge(file_comparison::router()) // /files/{path}/compare - must come before files::router()
-                .merge(files::router()) // Has catch-all /files/{*path}, must be last for /files/*
-                .merge(directories::router())
-                .merge(search::router())
-                .merge(sharing::router())
-                .merge(activity::router())
-                .merge(tags::router())
-                .merge(favorites::router())
-                .merge(backup::router())
-                .merge(collaboration::router())
-                .merge(system::router())
-                .merge(performance::router())
-                .merge(notifications::router())
-                .merge(themes::router())
-                .merge(comments::router())
-                .merge(trash::router())
-                .merge(batch::router())
-                .merge(bulk_operations::router())
-                .merge(config::router())
-                .merge(conversion::router()) // File format conversion
-                .merge(peers::router())
-                .merge(recent::router())
-                .merge(duplicates::router())
-                .merge(folder_colors::router())
-                .merge(file_templates::router())
-                .merge(rbac::router())
-                .merge(workflow::router())
-                .merge(cloud_storage::router())
-                .merge(metadata::router()) // File metadata extraction (EXIF, ID3, PDF)
-                .merge(audit_compliance::router()) // Audit logs & compliance reports
-                .merge(dashboard::router()) // Admin dashboard & analytics
-                .merge(errors::router()) // Error reporting endpoint
-                .merge(jobs::router()) // Background jobs management
-                .merge(cron::router()) // Cron scheduler management
-                .merge(db_health::router()) // Database health and monitoring
-                .merge(database_health::router()) // Advanced database health check
-                .merge(upload_chunk::router()) // Chunked upload support
-                .merge(webhooks::router()) // Webhook management
-                .merge(system_health::router()) // System health monitoring
-                .merge(api_tokens::router()) // Personal Access Token management
-                .merge(cleanup::router()) // Auto-cleanup of deleted files
-                .merge(smart_folders::router()) // Smart folders with dynamic rules
-                .merge(storage_analytics::router()) // Storage analytics and statistics
-                .merge(admin::router()) // Admin user management
-                .merge(encryption::router()) // File encryption at rest
-                .merge(guest::router()) // Guest/external user access
-                .merge(rate_limiting::router()) // Rate limiting & quotas management
+                .merge(guarded(versions::router(), s, &FILES)) // MUST come before files::router() (more specific routes first)
+                .merge(guarded(file_comparison::router(), s, &FILES)) // /files/{path}/compare - must come before files::router()
+                .merge(guarded(metered(files::router(), &throttle), s, &FILES)) // Has catch-all /files/{*path}, must be last for /files/*
+                .merge(guarded(directories::router(), s, &FILES))
+                .merge(guarded(search::router(), s, &FILES))
+                .merge(guarded(sharing::router(), s, &SHARES))
+                .merge(guarded(activity::router(), s, &FILE_LISTS))
+                .merge(guarded(tags::router(), s, &FILES))
+                .merge(guarded(favorites::router(), s, &FILE_LISTS))
+                .merge(guarded(backup::router(), s, &BACKUPS))
+                .merge(guarded(collaboration::router(), s, &FILES))
+                .merge(guarded(system::router(), s, &SERVER))
+                .merge(guarded(performance::router(), s, &SETTINGS))
+                .merge(guarded(notifications::router(), s, &NOTIFICATIONS))
+                .merge(guarded(themes::router(), s, &PROFILE))
+                .merge(guarded(comments::router(), s, &FILES))
+                .merge(guarded(trash::router(), s, &FILES))
+                .merge(guarded(batch::router(), s, &FILES))
+                .merge(guarded(bulk_operations::router(), s, &FILES))
+                .merge(guarded(config::router(), s, &SERVER))
+                .merge(guarded(conversion::router(), s, &FILES)) // File format conversion
+                .merge(guarded(peers::router(), s, &SYSTEM))
+                .merge(guarded(sync::router(), s, &FILES))
+                .merge(guarded(recent::router(), s, &FILE_LISTS))
+                .merge(guarded(duplicates::router(), s, &FILES))
+                .merge(guarded(folder_colors::router(), s, &FILE_LISTS))
+                .merge(guarded(file_templates::router(), s, &FILES))
+                .merge(guarded(rbac::router(), s, &ROLES))
+                .merge(guarded(workflow::router(), s, &FILES))
+                .merge(guarded(cloud_storage::router(), s, &SYSTEM))
+                .merge(guarded(metadata::router(), s, &FILES)) // File metadata extraction (EXIF, ID3, PDF)
+                .merge(guarded(audit_compliance::router(), s, &AUDIT)) // Audit logs & compliance reports
+                .merge(guarded(dashboard::router(), s, &SETTINGS)) // Admin dashboard & analytics
+                .merge(guarded(errors::router(), s, &ACCOUNT)) // Error reporting endpoint
+                .merge(guarded(jobs::router(), s, &SERVER)) // Background jobs management
+                .merge(guarded(cron::router(), s, &SETTINGS)) // Cron scheduler management
+                .merge(guarded(db_health::router(), s, &SETTINGS)) // Database health and monitoring
+                .merge(guarded(database_health::router(), s, &SETTINGS)) // Advanced database health check
+                .merge(guarded(metered(upload_chunk::router(), &throttle), s, &FILES)) // Chunked upload support
+                .merge(guarded(metered(tus::router(), &throttle), s, &FILES)) // Resumable uploads (tus 1.0)
+                .merge(guarded(metered(delta::router(), &throttle), s, &FILES)) // Delta (rsync-style) uploads
+                .merge(guarded(webhooks::router(), s, &WEBHOOKS)) // Webhook management
+                .merge(guarded(system_health::router(), s, &SETTINGS)) // System health monitoring
+                .merge(guarded(api_tokens::router(), s, &ACCOUNT)) // Personal Access Token management
+                .merge(guarded(cleanup::router(), s, &SETTINGS)) // Auto-cleanup of deleted files
+                .merge(guarded(smart_folders::router(), s, &FILES)) // Smart folders with dynamic rules
+                .merge(guarded(storage_analytics::router(), s, &SETTINGS)) // Storage analytics and statistics
+                .merge(guarded(admin::router(), s, &USERS)) // Admin user management
+                .merge(guarded(encryption::router(), s, &ENCRYPTION)) // File encryption at rest
+                .merge(guarded(guest::router(), s, &SHARES)) // Guest/external user access
+                .merge(guarded(rate_limiting::router(), s, &SERVER)) // Rate limiting & quotas management
                 // New routes from POST_ALPHA_ROADMAP
-                .merge(thumbnails::router()) // Thumbnail generation
-                .merge(preview::router()) // File preview generation
-                .merge(virus_scan::router()) // Virus scanning (ClamAV)
-                .merge(oauth::protected_router()) // OAuth account linking
-                .merge(ldap::router()) // LDAP configuration (admin)
-                .merge(ftp::router()) // FTP sync connections
-                .merge(email::router()) // Email integration
-                .merge(archives::router()) // Archive management (zip, tar.gz)
-                .merge(compression::router()) // File compression/decompression
-                // .merge(webdav::router()) // WebDAV - needs configuration
+                .merge(guarded(thumbnails::router(), s, &FILES)) // Thumbnail generation
+                .merge(guarded(preview::router(), s, &FILES)) // File preview generation
+                .merge(guarded(virus_scan::router(), s, &SCANS)) // Virus scanning (ClamAV)
+                .merge(guarded(oauth::protected_router(), s, &ACCOUNT)) // OAuth account linking
+                .merge(guarded(ldap::router(), s, &SYSTEM)) // LDAP configuration (admin)
+                .merge(guarded(secrets::router(), s, &SYSTEM)) // Master keys and secret reads (admin)
+                .merge(guarded(ftp::router(), s, &FILES)) // FTP sync connections
+                .merge(guarded(email::router(), s, &FILES)) // Email integration
+                .merge(guarded(archives::router(), s, &FILES)) // Archive management (zip, tar.gz)
+                .merge(guarded(compression::router(), s, &FILES)) // File compression/decompression
+                .layer(limit())
                 .layer(middleware::from_fn_with_state(
                     state.clone(),
                     crate::middleware::auth::auth_middleware,
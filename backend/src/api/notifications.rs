@@ -7,7 +7,9 @@ use axum::{
     routing::{delete, get, post},
     Json, Router,
 };
+use serde::Deserialize;
 use serde_json::json;
+use crate::notify::{self, preferences::{self, EventPreference, Settings}};
 use crate::{auth::UserInfo, AppState};
 
 pub fn router() -> Router<AppState> {
@@ -16,6 +18,10 @@ pub fn router() -> Router<AppState> {
         .route("/notifications", delete(delete_all_notifications))
         .route("/notifications/{id}/read", post(mark_notification_read))
         .route("/notifications/read-all", post(mark_all_notifications_read))
+        .route(
+            "/notifications/preferences",
+            get(get_preferences).put(update_preferences),
+        )
         .route("/notifications/{id}", delete(delete_notification))
 }
 
@@ -105,3 +111,62 @@ async fn delete_notification(user_info: UserInfo, State(state): State<AppState>,
         }
     }
 }
+
+#[derive(Debug, Deserialize)]
+struct UpdatePreferencesRequest {
+    settings: Option<Settings>,
+    #[serde(default)]
+    events: Vec<EventPreference>,
+}
+
+async fn preferences_of(state: &AppState, user_id: &str) -> Result<serde_json::Value, sqlx::Error> {
+    let settings = preferences::settings(&state.db_pool, user_id).await?;
+    let mut events = Vec::new();
+    for event in notify::EventType::ALL {
+        events.push(preferences::event_preference(&state.db_pool, user_id, event).await?);
+    }
+    Ok(json!({ "settings": settings, "events": events }))
+}
+
+/// GET /api/notifications/preferences - Channels per event type, quiet hours and digest timing
+async fn get_preferences(user_info: UserInfo, State(state): State<AppState>) -> Result<Json<serde_json::Value>, StatusCode> {
+    preferences_of(&state, &user_info.id).await.map(Json).map_err(|e| {
+        tracing::error!("Failed to load notification preferences: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// PUT /api/notifications/preferences - Update the settings and the given event types
+async fn update_preferences(
+    user_info: UserInfo,
+    State(state): State<AppState>,
+    Json(req): Json<UpdatePreferencesRequest>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    if req.settings.as_ref().is_some_and(|settings| !settings.is_valid()) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let valid = |event: &EventPreference| {
+        notify::EventType::parse(&event.event_type).is_some()
+            && preferences::Digest::parse(&event.email_digest).is_some()
+    };
+    if !req.events.iter().all(valid) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let saved: Result<(), sqlx::Error> = async {
+        if let Some(settings) = &req.settings {
+            preferences::save_settings(&state.db_pool, &user_info.id, settings).await?;
+        }
+        for event in &req.events {
+            preferences::save_event_preference(&state.db_pool, &user_info.id, event).await?;
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = saved {
+        tracing::error!("Failed to save notification preferences: {}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    get_preferences(user_info, State(state)).await
+}
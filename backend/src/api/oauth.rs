@@ -203,7 +203,7 @@ async fn callback(
     };
     
     // Exchange code for token
-    let token_response = match oauth::exchange_code(&provider_config, &params.code).await {
+    let token_response = match oauth::exchange_code(&state.db_pool, &provider_config, &params.code).await {
         Ok(t) => t,
         Err(e) => {
             tracing::error!("OAuth token exchange failed: {}", e);
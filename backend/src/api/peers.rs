@@ -1,16 +1,21 @@
 //! P2P Peers API Routes
+//!
+//! Pairing and folder configuration for peer-to-peer sync, plus the signed
+//! `/p2p/*` endpoints other SyncSpace instances pull changes and blocks from.
 
 use axum::{
-    extract::State,
-    http::StatusCode,
+    body::Bytes,
+    extract::{OriginalUri, Path, Query, State},
+    http::{header, HeaderMap, Method, StatusCode},
     response::{IntoResponse, Json},
-    routing::get,
+    routing::{delete, get, post},
     Router,
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::auth::UserInfo;
+use crate::services::peer_sync::{self, BlockRequest, PairRequest, PeerRecord, SyncContext};
 use crate::AppState;
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
@@ -18,9 +23,12 @@ pub struct Peer {
     pub id: String,
     pub name: String,
     pub address: String,
-    pub status: String, // online, offline, syncing
+    pub status: String, // online, offline, syncing, error
     pub last_seen: String,
     pub sync_enabled: bool,
+    pub instance_id: Option<String>,
+    pub paired_at: Option<String>,
+    pub last_error: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -29,6 +37,48 @@ pub struct AddPeerRequest {
     pub address: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PairPeerRequest {
+    /// Base URL of the other instance, e.g. `https://nas.example.com`
+    pub address: String,
+    /// Pairing code generated on the other instance
+    pub code: String,
+    /// Base URL of this instance as reachable from the other one
+    /// (defaults to `SYNCSPACE_PUBLIC_URL`)
+    pub public_address: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PeerSyncFolder {
+    pub id: String,
+    pub peer_id: String,
+    pub folder_path: String,
+    pub remote_cursor: i64,
+    pub last_synced_at: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddSyncFolderRequest {
+    pub folder_path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    pub folder: String,
+    #[serde(default)]
+    pub since: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ManifestQuery {
+    pub path: String,
+}
+
+fn is_admin(user: &UserInfo) -> bool {
+    user.is_admin || user.role.as_deref() == Some("admin")
+}
+
 /// List all peers
 async fn list_peers(
     State(state): State<AppState>,
@@ -39,9 +89,10 @@ async fn list_peers(
 
     // Query peers table from database
     let peers: Vec<Peer> = sqlx::query_as(
-        "SELECT id, name, address, status, last_seen, sync_enabled 
-         FROM peers 
-         WHERE user_id = ? 
+        "SELECT id, name, address, status, COALESCE(last_seen, created_at) AS last_seen, sync_enabled,
+                instance_id, paired_at, last_error
+         FROM peers
+         WHERE user_id = ?
          ORDER BY created_at DESC"
     )
     .bind(&user_info.id)
@@ -102,7 +153,382 @@ async fn add_peer(
     ))
 }
 
+/// Identity of this instance as seen by its peers
+async fn get_identity(
+    State(state): State<AppState>,
+    _user: UserInfo,
+) -> Result<impl IntoResponse, StatusCode> {
+    let identity = peer_sync::local_identity(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(identity))
+}
+
+/// Create a one-time code another instance can pair with
+async fn create_pairing_code(
+    State(state): State<AppState>,
+    user: UserInfo,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !is_admin(&user) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let identity = peer_sync::local_identity(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (code, expires_at) = peer_sync::create_pairing_code(&state.db_pool, &user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(serde_json::json!({
+            "code": code,
+            "expires_at": expires_at.to_rfc3339(),
+            "instance_id": identity.instance_id,
+            "name": identity.name,
+        })),
+    ))
+}
+
+/// Pair with another instance using a code generated there
+async fn pair_peer(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Json(req): Json<PairPeerRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !is_admin(&user) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let public_address = req
+        .public_address
+        .or_else(|| std::env::var("SYNCSPACE_PUBLIC_URL").ok())
+        .filter(|a| a.starts_with("http://") || a.starts_with("https://"))
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    if !(req.address.starts_with("http://") || req.address.starts_with("https://")) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let peer = peer_sync::pair_with(
+        &state.db_pool,
+        &user.id,
+        &req.address,
+        &req.code,
+        &public_address,
+    )
+    .await
+    .map_err(|e| {
+        tracing::warn!("Pairing with {} failed: {}", req.address, e);
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(serde_json::json!({
+            "id": peer.id,
+            "name": peer.name,
+            "address": peer.address,
+            "instance_id": peer.instance_id,
+            "status": "online",
+        })),
+    ))
+}
+
+/// Load a peer owned by an admin
+async fn owned_peer(
+    state: &AppState,
+    user: &UserInfo,
+    peer_id: &str,
+) -> Result<PeerRecord, StatusCode> {
+    if !is_admin(user) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    peer_sync::load_peer(&state.db_pool, peer_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|peer| peer.user_id == user.id)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Remove a peer and stop syncing with it
+async fn delete_peer(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(peer_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let peer = owned_peer(&state, &user, &peer_id).await?;
+
+    sqlx::query("DELETE FROM peer_sync_folders WHERE peer_id = ?")
+        .bind(&peer.id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sqlx::query("DELETE FROM peers WHERE id = ?")
+        .bind(&peer.id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List folders synchronized with a peer
+async fn list_sync_folders(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(peer_id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let peer = owned_peer(&state, &user, &peer_id).await?;
+
+    let folders: Vec<PeerSyncFolder> = sqlx::query_as(
+        "SELECT id, peer_id, folder_path, remote_cursor, last_synced_at, created_at
+         FROM peer_sync_folders WHERE peer_id = ? ORDER BY folder_path",
+    )
+    .bind(&peer.id)
+    .fetch_all(&state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(folders))
+}
+
+/// Share a folder with a peer. The peer has to add the same folder on its
+/// side; an empty path shares the whole data directory.
+async fn add_sync_folder(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(peer_id): Path<String>,
+    Json(req): Json<AddSyncFolderRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let peer = owned_peer(&state, &user, &peer_id).await?;
+    let folder_path =
+        peer_sync::normalize_folder(&req.folder_path).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let folder = PeerSyncFolder {
+        id: Uuid::new_v4().to_string(),
+        peer_id: peer.id,
+        folder_path,
+        remote_cursor: 0,
+        last_synced_at: None,
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+    sqlx::query(
+        "INSERT INTO peer_sync_folders (id, peer_id, folder_path, remote_cursor, created_at) VALUES (?, ?, ?, 0, ?)",
+    )
+    .bind(&folder.id)
+    .bind(&folder.peer_id)
+    .bind(&folder.folder_path)
+    .bind(&folder.created_at)
+    .execute(&state.db_pool)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(db) if db.is_unique_violation() => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
+
+    Ok((StatusCode::CREATED, Json(folder)))
+}
+
+/// Stop syncing a folder with a peer
+async fn remove_sync_folder(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path((peer_id, folder_id)): Path<(String, String)>,
+) -> Result<StatusCode, StatusCode> {
+    let peer = owned_peer(&state, &user, &peer_id).await?;
+
+    let result = sqlx::query("DELETE FROM peer_sync_folders WHERE id = ? AND peer_id = ?")
+        .bind(&folder_id)
+        .bind(&peer.id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Pull changes from a peer now instead of waiting for the next interval
+async fn sync_now(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(peer_id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let peer = owned_peer(&state, &user, &peer_id).await?;
+    if peer.shared_secret.is_none() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let ctx = SyncContext::new(
+        state.db_pool.clone(),
+        state.search_index.clone(),
+        state.fs_tx.clone(),
+    );
+    let report = peer_sync::sync_peer(&ctx, &peer.id).await.map_err(|e| {
+        tracing::warn!("Sync with peer {} failed: {:#}", peer.name, e);
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    Ok(Json(report))
+}
+
+// ==================== PEER ENDPOINTS ====================
+
+/// Verify the HMAC signature of a request from a paired instance
+async fn authenticate_peer(
+    state: &AppState,
+    method: &Method,
+    uri: &OriginalUri,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<PeerRecord, StatusCode> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(StatusCode::UNAUTHORIZED)
+    };
+    let path_and_query = uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or_else(|| uri.path());
+
+    peer_sync::authenticate_peer(
+        &state.db_pool,
+        method.as_str(),
+        path_and_query,
+        header(peer_sync::PEER_ID_HEADER)?,
+        header(peer_sync::PEER_TIMESTAMP_HEADER)?,
+        header(peer_sync::PEER_SIGNATURE_HEADER)?,
+        body,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::UNAUTHORIZED)
+}
+
+/// Validate a requested path and check it is shared with the peer
+async fn shared_path(state: &AppState, peer: &PeerRecord, path: &str) -> Result<String, StatusCode> {
+    let path = crate::security::validate_file_path(path)?;
+    let shared = peer_sync::is_shared_with(&state.db_pool, &peer.id, &path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !shared || crate::services::fs_watcher::is_internal(&path) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(path)
+}
+
+/// Complete a pairing started with a code from this instance
+async fn accept_pairing(
+    State(state): State<AppState>,
+    Json(req): Json<PairRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !(req.address.starts_with("http://") || req.address.starts_with("https://")) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let response = peer_sync::accept_pairing(&state.db_pool, &req)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    tracing::info!("Paired with instance {} ({})", req.name, req.address);
+    Ok(Json(response))
+}
+
+/// Change log of a shared folder
+async fn peer_changes(
+    State(state): State<AppState>,
+    method: Method,
+    uri: OriginalUri,
+    headers: HeaderMap,
+    Query(query): Query<ChangesQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let peer = authenticate_peer(&state, &method, &uri, &headers, &[]).await?;
+    let folder = peer_sync::normalize_folder(&query.folder).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let shared: bool = sqlx::query_scalar(
+        "SELECT COUNT(*) > 0 FROM peer_sync_folders WHERE peer_id = ? AND folder_path = ?",
+    )
+    .bind(&peer.id)
+    .bind(&folder)
+    .fetch_one(&state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !shared {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let page = peer_sync::changes_since(&state.db_pool, &folder, query.since.max(0))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(page))
+}
+
+/// Block list of a shared file
+async fn peer_manifest(
+    State(state): State<AppState>,
+    method: Method,
+    uri: OriginalUri,
+    headers: HeaderMap,
+    Query(query): Query<ManifestQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let peer = authenticate_peer(&state, &method, &uri, &headers, &[]).await?;
+    let path = shared_path(&state, &peer, &query.path).await?;
+
+    let manifest = peer_sync::file_manifest(&state.db_pool, &path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(manifest))
+}
+
+/// Raw content of the requested blocks, concatenated in request order
+async fn peer_blocks(
+    State(state): State<AppState>,
+    method: Method,
+    uri: OriginalUri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, StatusCode> {
+    let peer = authenticate_peer(&state, &method, &uri, &headers, &body).await?;
+    let req: BlockRequest = serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let path = shared_path(&state, &peer, &req.path).await?;
+
+    let content = peer_sync::read_blocks(&path, &req.blocks)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+        // The file changed since the manifest was taken
+        .ok_or(StatusCode::CONFLICT)?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], content))
+}
+
 /// Build peers router
 pub fn router() -> Router<AppState> {
-    Router::new().route("/peers", get(list_peers).post(add_peer))
+    Router::new()
+        .route("/peers", get(list_peers).post(add_peer))
+        .route("/peers/identity", get(get_identity))
+        .route("/peers/pairing-codes", post(create_pairing_code))
+        .route("/peers/pair", post(pair_peer))
+        .route("/peers/{id}", delete(delete_peer))
+        .route(
+            "/peers/{id}/folders",
+            get(list_sync_folders).post(add_sync_folder),
+        )
+        .route("/peers/{id}/folders/{folder_id}", delete(remove_sync_folder))
+        .route("/peers/{id}/sync", post(sync_now))
+}
+
+/// Endpoints called by paired instances; authenticated by request
+/// signature instead of a user session
+pub fn public_router() -> Router<AppState> {
+    Router::new()
+        .route("/p2p/pair", post(accept_pairing))
+        .route("/p2p/changes", get(peer_changes))
+        .route("/p2p/manifest", get(peer_manifest))
+        .route("/p2p/blocks", post(peer_blocks))
 }
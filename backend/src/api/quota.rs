@@ -164,6 +164,40 @@ pub async fn check_quota_available(
     Ok(used + required_bytes <= quota)
 }
 
+/// Bytes a user can still store before hitting the quota
+pub async fn available_bytes(pool: &sqlx::SqlitePool, user_id: &str) -> Result<i64, StatusCode> {
+    let quota_info: Option<(i64, i64)> =
+        sqlx::query_as("SELECT storage_used_bytes, storage_quota_bytes FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let (used, quota) = quota_info.ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok((quota - used).max(0))
+}
+
+/// Size of the live file at `path` that an upload by `user_id` replaces.
+/// Only the user's own files count, their size is what the overwrite frees.
+pub async fn replaced_bytes(
+    pool: &sqlx::SqlitePool,
+    user_id: &str,
+    path: &str,
+) -> Result<i64, StatusCode> {
+    let size: Option<i64> = sqlx::query_scalar(
+        "SELECT size_bytes FROM files WHERE path = ? AND owner_id = ? AND is_deleted = 0
+         ORDER BY updated_at DESC LIMIT 1",
+    )
+    .bind(path)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(size.unwrap_or(0))
+}
+
 /// Get all users' quota info (admin only)
 async fn list_all_quotas(
     State(state): State<AppState>,
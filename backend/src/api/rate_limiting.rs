@@ -1,5 +1,7 @@
 //! Rate Limiting & Quotas API
-//! User storage quotas, bandwidth limits, and API rate limiting management
+//! User storage quotas, bandwidth limits, and API rate limiting management.
+//! The rules and bandwidth quotas stored here are enforced by
+//! crate::throttle; changes take effect within its cache lifetime.
 
 use axum::{
     extract::{Path, Query, State},
@@ -63,6 +65,8 @@ pub struct BandwidthQuota {
     pub daily_download_limit_bytes: Option<i64>,
     pub monthly_upload_limit_bytes: Option<i64>,
     pub monthly_download_limit_bytes: Option<i64>,
+    pub upload_bytes_per_second: Option<i64>,
+    pub download_bytes_per_second: Option<i64>,
     pub is_unlimited: bool,
     pub created_at: String,
     pub updated_at: String,
@@ -84,6 +88,10 @@ pub struct ApiRateLimit {
     pub id: String,
     pub user_id: Option<String>,
     pub role_name: Option<String>,
+    pub token_id: Option<String>,
+    pub ip_address: Option<String>,
+    /// What the counters are kept per: "user", "token" or "ip"
+    pub limit_by: String,
     pub endpoint_pattern: String,
     pub requests_per_minute: i32,
     pub requests_per_hour: i32,
@@ -120,6 +128,8 @@ pub struct UpdateBandwidthQuotaRequest {
     pub daily_download_limit_bytes: Option<i64>,
     pub monthly_upload_limit_bytes: Option<i64>,
     pub monthly_download_limit_bytes: Option<i64>,
+    pub upload_bytes_per_second: Option<i64>,
+    pub download_bytes_per_second: Option<i64>,
     pub is_unlimited: Option<bool>,
 }
 
@@ -127,6 +137,9 @@ pub struct UpdateBandwidthQuotaRequest {
 pub struct CreateRateLimitRequest {
     pub user_id: Option<String>,
     pub role_name: Option<String>,
+    pub token_id: Option<String>,
+    pub ip_address: Option<String>,
+    pub limit_by: Option<String>,
     pub endpoint_pattern: String,
     pub requests_per_minute: i32,
     pub requests_per_hour: i32,
@@ -141,6 +154,7 @@ pub struct UpdateRateLimitRequest {
     pub requests_per_hour: Option<i32>,
     pub requests_per_day: Option<i32>,
     pub burst_limit: Option<i32>,
+    pub limit_by: Option<String>,
     pub is_enabled: Option<bool>,
 }
 
@@ -412,6 +426,7 @@ async fn list_bandwidth_quotas(
         r#"
         SELECT id, user_id, daily_upload_limit_bytes, daily_download_limit_bytes,
                monthly_upload_limit_bytes, monthly_download_limit_bytes,
+               upload_bytes_per_second, download_bytes_per_second,
                is_unlimited, created_at, updated_at
         FROM user_bandwidth_quotas
         ORDER BY created_at DESC
@@ -460,6 +475,7 @@ async fn get_user_bandwidth_quota(
         r#"
         SELECT id, user_id, daily_upload_limit_bytes, daily_download_limit_bytes,
                monthly_upload_limit_bytes, monthly_download_limit_bytes,
+               upload_bytes_per_second, download_bytes_per_second,
                is_unlimited, created_at, updated_at
         FROM user_bandwidth_quotas
         WHERE user_id = ?
@@ -498,6 +514,8 @@ async fn update_bandwidth_quota(
             daily_download_limit_bytes = COALESCE(?, daily_download_limit_bytes),
             monthly_upload_limit_bytes = COALESCE(?, monthly_upload_limit_bytes),
             monthly_download_limit_bytes = COALESCE(?, monthly_download_limit_bytes),
+            upload_bytes_per_second = COALESCE(?, upload_bytes_per_second),
+            download_bytes_per_second = COALESCE(?, download_bytes_per_second),
             is_unlimited = COALESCE(?, is_unlimited),
             updated_at = datetime('now')
         WHERE user_id = ?
@@ -507,6 +525,8 @@ async fn update_bandwidth_quota(
     .bind(req.daily_download_limit_bytes)
     .bind(req.monthly_upload_limit_bytes)
     .bind(req.monthly_download_limit_bytes)
+    .bind(req.upload_bytes_per_second)
+    .bind(req.download_bytes_per_second)
     .bind(req.is_unlimited)
     .bind(&user_id)
     .execute(&state.db_pool)
@@ -586,6 +606,21 @@ async fn get_bandwidth_usage(
 
 // ==================== Rate Limit Handlers ====================
 
+/// Reject subjects the throttle cannot count by and malformed addresses
+fn validate_rule(limit_by: Option<&str>, ip_address: Option<&str>) -> Result<(), StatusCode> {
+    if let Some(limit_by) = limit_by
+        && !matches!(limit_by, "user" | "token" | "ip")
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if let Some(ip_address) = ip_address
+        && !crate::rbac::tokens::is_valid_ip_rule(ip_address)
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(())
+}
+
 async fn list_rate_limits(
     _user_info: UserInfo,
     State(state): State<AppState>,
@@ -596,8 +631,8 @@ async fn list_rate_limits(
 
     let limits = sqlx::query_as::<_, ApiRateLimit>(
         r#"
-        SELECT id, user_id, role_name, endpoint_pattern, requests_per_minute,
-               requests_per_hour, requests_per_day, burst_limit, is_enabled,
+        SELECT id, user_id, role_name, token_id, ip_address, limit_by,
+               endpoint_pattern, requests_per_minute, requests_per_hour, requests_per_day, burst_limit, is_enabled,
                created_at, updated_at
         FROM api_rate_limits
         ORDER BY created_at DESC
@@ -631,19 +666,24 @@ async fn create_rate_limit(
     State(state): State<AppState>,
     Json(req): Json<CreateRateLimitRequest>,
 ) -> Result<Json<ApiRateLimit>, StatusCode> {
+    validate_rule(req.limit_by.as_deref(), req.ip_address.as_deref())?;
     let id = uuid::Uuid::new_v4().to_string();
 
     sqlx::query(
         r#"
-        INSERT INTO api_rate_limits (id, user_id, role_name, endpoint_pattern, 
+        INSERT INTO api_rate_limits (id, user_id, role_name, token_id, ip_address,
+                                     limit_by, endpoint_pattern,
                                      requests_per_minute, requests_per_hour, 
                                      requests_per_day, burst_limit)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         "#
     )
     .bind(&id)
     .bind(&req.user_id)
     .bind(&req.role_name)
+    .bind(&req.token_id)
+    .bind(&req.ip_address)
+    .bind(req.limit_by.as_deref().unwrap_or("user"))
     .bind(&req.endpoint_pattern)
     .bind(req.requests_per_minute)
     .bind(req.requests_per_hour)
@@ -666,8 +706,8 @@ async fn get_rate_limit(
 ) -> Result<Json<ApiRateLimit>, StatusCode> {
     let limit = sqlx::query_as::<_, ApiRateLimit>(
         r#"
-        SELECT id, user_id, role_name, endpoint_pattern, requests_per_minute,
-               requests_per_hour, requests_per_day, burst_limit, is_enabled,
+        SELECT id, user_id, role_name, token_id, ip_address, limit_by,
+               endpoint_pattern, requests_per_minute, requests_per_hour, requests_per_day, burst_limit, is_enabled,
                created_at, updated_at
         FROM api_rate_limits
         WHERE id = ?
@@ -687,6 +727,7 @@ async fn update_rate_limit(
     Path(id): Path<String>,
     Json(req): Json<UpdateRateLimitRequest>,
 ) -> Result<Json<ApiRateLimit>, StatusCode> {
+    validate_rule(req.limit_by.as_deref(), None)?;
     sqlx::query(
         r#"
         UPDATE api_rate_limits 
@@ -695,6 +736,7 @@ async fn update_rate_limit(
             requests_per_hour = COALESCE(?, requests_per_hour),
             requests_per_day = COALESCE(?, requests_per_day),
             burst_limit = COALESCE(?, burst_limit),
+            limit_by = COALESCE(?, limit_by),
             is_enabled = COALESCE(?, is_enabled),
             updated_at = datetime('now')
         WHERE id = ?
@@ -705,6 +747,7 @@ async fn update_rate_limit(
     .bind(req.requests_per_hour)
     .bind(req.requests_per_day)
     .bind(req.burst_limit)
+    .bind(&req.limit_by)
     .bind(req.is_enabled)
     .bind(&id)
     .execute(&state.db_pool)
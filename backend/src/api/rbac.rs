@@ -1,5 +1,5 @@
 use axum::{
-    extract::{Path, Query, State},
+    extract::{OriginalUri, Path, Query, State},
     http::StatusCode,
     response::{IntoResponse, Json},
     routing::{delete, get},
@@ -161,11 +161,6 @@ async fn create_role(
     UserInfo { id: user_id, .. }: UserInfo,
     Json(req): Json<CreateRoleRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Check if user has role.create permission
-    if !has_permission(&state, &user_id, "role.create").await {
-        return Err(StatusCode::FORBIDDEN);
-    }
-
     let role_id = Uuid::new_v4().to_string();
     let now = chrono::Utc::now().to_rfc3339();
     let permissions_json = serde_json::to_string(&req.permissions).unwrap_or_default();
@@ -215,11 +210,6 @@ async fn update_role(
     Path(role_id): Path<String>,
     Json(req): Json<UpdateRoleRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Check permission
-    if !has_permission(&state, &user_id, "role.manage").await {
-        return Err(StatusCode::FORBIDDEN);
-    }
-
     // Get existing role
     let role: Role = sqlx::query_as("SELECT * FROM roles WHERE id = ?")
         .bind(&role_id)
@@ -276,11 +266,6 @@ async fn delete_role(
     UserInfo { id: user_id, .. }: UserInfo,
     Path(role_id): Path<String>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Check permission
-    if !has_permission(&state, &user_id, "role.delete").await {
-        return Err(StatusCode::FORBIDDEN);
-    }
-
     // Get role
     let role: Role = sqlx::query_as("SELECT * FROM roles WHERE id = ?")
         .bind(&role_id)
@@ -337,17 +322,12 @@ async fn get_role_permissions(
 // Get user's roles
 async fn get_user_roles(
     State(state): State<AppState>,
-    UserInfo {
-        id: requesting_user,
-        ..
-    }: UserInfo,
+    user: UserInfo,
+    OriginalUri(uri): OriginalUri,
     Path(user_id): Path<String>,
 ) -> Result<impl IntoResponse, StatusCode> {
     // Users can view their own roles, admins can view any
-    if user_id != requesting_user && !has_permission(&state, &requesting_user, "user.manage").await
-    {
-        return Err(StatusCode::FORBIDDEN);
-    }
+    require_self_or_manager(&state, &user, &user_id, uri.path()).await?;
 
     let user_roles: Vec<UserRoleWithDetails> = sqlx::query_as(
         "SELECT ur.user_id, ur.role_id, r.name as role_name, r.display_name as role_display_name,
@@ -372,11 +352,6 @@ async fn assign_user_role(
     Path(target_user_id): Path<String>,
     Json(req): Json<AssignRoleRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Check permission
-    if !has_permission(&state, &user_id, "role.assign").await {
-        return Err(StatusCode::FORBIDDEN);
-    }
-
     let now = chrono::Utc::now().to_rfc3339();
 
     sqlx::query(
@@ -423,11 +398,6 @@ async fn revoke_user_role(
     UserInfo { id: user_id, .. }: UserInfo,
     Path((target_user_id, role_id)): Path<(String, String)>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Check permission
-    if !has_permission(&state, &user_id, "role.assign").await {
-        return Err(StatusCode::FORBIDDEN);
-    }
-
     // Get role for audit
     let role: Option<Role> = sqlx::query_as("SELECT * FROM roles WHERE id = ?")
         .bind(&role_id)
@@ -464,19 +434,16 @@ async fn revoke_user_role(
 // Get user's effective permissions
 async fn get_user_permissions(
     State(state): State<AppState>,
-    UserInfo {
-        id: requesting_user,
-        ..
-    }: UserInfo,
+    user: UserInfo,
+    OriginalUri(uri): OriginalUri,
     Path(user_id): Path<String>,
 ) -> Result<impl IntoResponse, StatusCode> {
     // Users can view their own permissions, admins can view any
-    if user_id != requesting_user && !has_permission(&state, &requesting_user, "user.manage").await
-    {
-        return Err(StatusCode::FORBIDDEN);
-    }
+    require_self_or_manager(&state, &user, &user_id, uri.path()).await?;
 
-    let permissions = get_user_all_permissions(&state, &user_id).await;
+    let permissions = crate::rbac::permissions(&state.db_pool, &user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(Json(permissions))
 }
 
@@ -485,55 +452,15 @@ async fn list_available_permissions(
     State(_state): State<AppState>,
     UserInfo { id: _user_id, .. }: UserInfo,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let permissions = vec![
-        // System permissions
-        "system.admin",
-        "system.config",
-        // User management
-        "user.manage",
-        "user.create",
-        "user.delete",
-        "user.view",
-        // Role management
-        "role.manage",
-        "role.create",
-        "role.delete",
-        "role.assign",
-        // File operations
-        "file.read",
-        "file.write",
-        "file.delete",
-        "file.share",
-        "file.download",
-        // Share management
-        "share.manage",
-        "share.create",
-        "share.delete",
-        // Backup operations
-        "backup.manage",
-        "backup.create",
-        "backup.restore",
-        "backup.view",
-        // Audit and settings
-        "audit.view",
-        "settings.manage",
-        "settings.view",
-    ];
-
-    Ok(Json(permissions))
+    Ok(Json(crate::rbac::PERMISSIONS))
 }
 
 // Get permission audit log
 async fn get_permission_audit(
     State(state): State<AppState>,
-    UserInfo { id: user_id, .. }: UserInfo,
+    UserInfo { id: _user_id, .. }: UserInfo,
     Query(query): Query<AuditQuery>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Check permission
-    if !has_permission(&state, &user_id, "audit.view").await {
-        return Err(StatusCode::FORBIDDEN);
-    }
-
     let limit = query.limit.unwrap_or(100);
 
     let mut sql = String::from("SELECT * FROM permission_audit WHERE 1=1");
@@ -556,37 +483,20 @@ async fn get_permission_audit(
     Ok(Json(audit_logs))
 }
 
-// Helper: Check if user has permission
-async fn has_permission(state: &AppState, user_id: &str, permission: &str) -> bool {
-    let permissions = get_user_all_permissions(state, user_id).await;
-    permissions.contains(&permission.to_string())
-}
-
-// Helper: Get all user permissions from all roles
-async fn get_user_all_permissions(state: &AppState, user_id: &str) -> Vec<String> {
-    let user_roles: Vec<UserRoleWithDetails> = sqlx::query_as(
-        "SELECT ur.user_id, ur.role_id, r.name as role_name, r.display_name as role_display_name,
-         r.permissions, ur.granted_by, ur.granted_at, ur.expires_at
-         FROM user_roles ur
-         JOIN roles r ON ur.role_id = r.id
-         WHERE ur.user_id = ?",
-    )
-    .bind(user_id)
-    .fetch_all(&state.db_pool)
-    .await
-    .unwrap_or_default();
-
-    let mut all_permissions = std::collections::HashSet::new();
-
-    for user_role in user_roles {
-        if let Ok(perms) = serde_json::from_str::<Vec<String>>(&user_role.permissions) {
-            for perm in perms {
-                all_permissions.insert(perm);
-            }
-        }
+// Helper: Users may see their own roles and permissions, user managers anyone's
+async fn require_self_or_manager(
+    state: &AppState,
+    user: &UserInfo,
+    user_id: &str,
+    path: &str,
+) -> Result<(), StatusCode> {
+    if user_id == user.id
+        || crate::rbac::has_permission(&state.db_pool, &user.id, "user.manage").await
+    {
+        return Ok(());
     }
-
-    all_permissions.into_iter().collect()
+    crate::rbac::log_denial(&state.db_pool, user, "GET", path, "user.manage").await;
+    Err(StatusCode::FORBIDDEN)
 }
 
 // Helper: Log permission audit
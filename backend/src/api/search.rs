@@ -4,6 +4,7 @@
 
 use crate::auth::UserInfo;
 
+use crate::search::Viewer;
 use crate::{services, AppState};
 use axum::{
     extract::{Query, State},
@@ -12,7 +13,6 @@ use axum::{
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
-use sqlx::Row;
 
 #[derive(Debug, Deserialize)]
 pub struct SearchQuery {
@@ -76,59 +76,12 @@ async fn reindex_handler(
 ) -> Result<Json<ReindexResponse>, StatusCode> {
     eprintln!("🔄 Starting search index rebuild...");
 
-    // Get all non-deleted files from database
-    let files = sqlx::query(
-        "SELECT id, name, path, size_bytes, updated_at FROM files WHERE is_deleted = 0",
-    )
-    .fetch_all(&state.db_pool)
-    .await
-    .map_err(|e| {
-        eprintln!("Failed to fetch files for reindex: {:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-
-    let mut indexed_count = 0;
-
-    for row in files {
-        let file_id: String = row.try_get("id").unwrap_or_default();
-        let filename: String = row.try_get("name").unwrap_or_default();
-        let path: String = row.try_get("path").unwrap_or_default();
-        let size_bytes: i64 = row.try_get("size_bytes").unwrap_or(0);
-        let updated_at: String = row
-            .try_get("updated_at")
-            .unwrap_or_else(|_| chrono::Utc::now().to_rfc3339());
-
-        // Parse datetime
-        let modified = chrono::DateTime::parse_from_rfc3339(&updated_at)
-            .unwrap_or_else(|_| chrono::Utc::now().into())
-            .with_timezone(&chrono::Utc);
-
-        // Extract content from file (if possible)
-        let file_path = std::path::Path::new("./data").join(&path);
-        let content = if file_path.exists() {
-            crate::search::extract_content(&file_path).await
-        } else {
-            None
-        };
-
-        // Index the file
-        if let Err(e) = state
-            .search_index
-            .index_file(
-                &file_id,
-                &filename,
-                &path,
-                content,
-                modified,
-                size_bytes as u64,
-            )
-            .await
-        {
-            eprintln!("Failed to index file {}: {:?}", filename, e);
-        } else {
-            indexed_count += 1;
-        }
-    }
+    let indexed_count = crate::search::reindex_tree(&state.search_index, &state.db_pool, "")
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to reindex files: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
     eprintln!("✅ Reindex complete: {} files indexed", indexed_count);
 
@@ -139,72 +92,24 @@ async fn reindex_handler(
 }
 
 /// GET /api/search/suggest - Get search suggestions for autocomplete
-/// Uses database LIKE query for reliable prefix matching
+/// Only suggests files the user can read
 async fn suggest_handler(
     State(state): State<AppState>,
-    _user: UserInfo,
+    user: UserInfo,
     Query(query): Query<SearchQuery>,
 ) -> Result<Json<Vec<crate::search::SearchSuggestion>>, StatusCode> {
-    // Use database LIKE query for reliable autocomplete
-    let search_pattern = format!("%{}%", query.q.to_lowercase());
-    let prefix_pattern = format!("{}%", query.q.to_lowercase());
-    
-    // Simpler query without UNION - just search files and use mime_type to detect folders
-    let rows = sqlx::query(
-        r#"
-        SELECT DISTINCT name, 
-               COALESCE(
-                   CASE 
-                       WHEN mime_type = 'inode/directory' THEN 'folder'
-                       WHEN mime_type IS NULL OR mime_type = '' THEN 
-                           CASE 
-                               WHEN INSTR(name, '.') > 0 THEN LOWER(SUBSTR(name, INSTR(name, '.') + 1))
-                               ELSE 'file'
-                           END
-                       ELSE SUBSTR(mime_type, INSTR(mime_type, '/') + 1)
-                   END,
-                   'file'
-               ) as file_type,
-               path,
-               size_bytes,
-               CASE WHEN LOWER(name) LIKE ?2 THEN 0 ELSE 1 END as priority
-        FROM files 
-        WHERE is_deleted = 0 
-          AND (LOWER(name) LIKE ?1 OR LOWER(path) LIKE ?1)
-        ORDER BY priority, name
-        LIMIT ?3
-        "#,
-    )
-    .bind(&search_pattern)
-    .bind(&prefix_pattern)
-    .bind(query.limit as i64)
-    .fetch_all(&state.db_pool)
-    .await
-    .map_err(|e| {
-        eprintln!("Failed to get suggestions from DB: {:?}", e);
+    let viewer = Viewer::load(&state.db_pool, &user.id).await.map_err(|e| {
+        eprintln!("Failed to load search permissions: {:?}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    let suggestions: Vec<crate::search::SearchSuggestion> = rows
-        .iter()
-        .map(|row| {
-            use sqlx::Row;
-            let path: String = row.try_get("path").unwrap_or_default();
-            // Extract folder path (parent directory)
-            let folder_path = if path.contains('/') {
-                path.rsplit_once('/').map(|(parent, _)| parent.to_string())
-            } else {
-                None
-            };
-            crate::search::SearchSuggestion {
-                text: row.try_get("name").unwrap_or_default(),
-                file_type: row.try_get("file_type").ok(),
-                score: 1.0,
-                path: folder_path,
-                size_bytes: row.try_get("size_bytes").ok(),
-            }
-        })
-        .collect();
+    let suggestions = state
+        .search_index
+        .suggest(&query.q, query.limit, &viewer)
+        .map_err(|e| {
+            eprintln!("Failed to get suggestions: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
     Ok(Json(suggestions))
 }
@@ -212,7 +117,7 @@ async fn suggest_handler(
 /// GET /api/search/facets - Get search facets (aggregations)
 async fn facets_handler(
     State(state): State<AppState>,
-    _user: UserInfo,
+    user: UserInfo,
     Query(query): Query<SearchQuery>,
 ) -> Result<Json<crate::search::SearchFacets>, StatusCode> {
     let query_str = if query.q.is_empty() {
@@ -221,10 +126,18 @@ async fn facets_handler(
         Some(query.q.as_str())
     };
 
-    let facets = state.search_index.facets(query_str).map_err(|e| {
-        eprintln!("Failed to get facets: {:?}", e);
+    let viewer = Viewer::load(&state.db_pool, &user.id).await.map_err(|e| {
+        eprintln!("Failed to load search permissions: {:?}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    let facets = state
+        .search_index
+        .facets(query_str, &viewer)
+        .map_err(|e| {
+            eprintln!("Failed to get facets: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
     Ok(Json(facets))
 }
@@ -0,0 +1,77 @@
+//! Secrets vault administration
+//!
+//! Status of the master keys, rotation and the audit trail of secret reads
+//! (see crate::secrets). Secrets themselves are never returned.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+
+use crate::{auth::UserInfo, secrets, AppState};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/secrets/status", get(status))
+        .route("/secrets/rotate", post(rotate))
+        .route("/secrets/reads", get(reads))
+}
+
+fn error_status(e: &secrets::SecretError) -> StatusCode {
+    match e {
+        secrets::SecretError::NotConfigured => StatusCode::SERVICE_UNAVAILABLE,
+        // Keys from the environment cannot be rotated from here
+        secrets::SecretError::Key(_) => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Master keys and the number of secrets sealed with each of them
+async fn status(State(state): State<AppState>) -> Result<impl IntoResponse, StatusCode> {
+    secrets::status(&state.db_pool).await.map(Json).map_err(|e| {
+        tracing::error!("Failed to read secrets status: {}", e);
+        error_status(&e)
+    })
+}
+
+/// Activate a new master key and rewrap all secrets with it
+async fn rotate(
+    State(state): State<AppState>,
+    user: UserInfo,
+) -> Result<impl IntoResponse, StatusCode> {
+    let report = secrets::rotate(&state.db_pool).await.map_err(|e| {
+        tracing::error!("Master key rotation failed: {}", e);
+        error_status(&e)
+    })?;
+    tracing::info!(
+        "Master key rotated by {}: {} secrets rewrapped with {}, {} failed",
+        user.username,
+        report.rewrapped,
+        report.active_key_id,
+        report.failed
+    );
+    Ok(Json(report))
+}
+
+#[derive(Debug, Deserialize)]
+struct ReadsQuery {
+    /// `<table>.<column>`, for example `ftp_connections.password_encrypted`
+    secret: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Latest reads of secrets and what they were read for
+async fn reads(
+    State(state): State<AppState>,
+    Query(query): Query<ReadsQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    secrets::reads(&state.db_pool, query.secret.as_deref(), limit)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
@@ -5,15 +5,14 @@ use crate::auth::UserInfo;
 use crate::{services, AppState};
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use axum::{
-    body::Body,
     extract::{Path, Query, State},
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
     routing::{delete, get, post, put},
     Json, Router,
 };
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use tokio_util::io::ReaderStream;
 
 #[derive(Debug, Deserialize)]
 pub struct CreateShareRequest {
@@ -310,11 +309,14 @@ async fn get_public_share(
 }
 
 /// GET /sharing/public/{share_token}/download - Download via public share (NO AUTH)
+///
+/// Uses the same Range / conditional request handling as `/file/{*path}`.
 async fn download_public_share(
     State(state): State<AppState>,
     Path(share_token): Path<String>,
     Query(params): Query<PublicShareQuery>,
-) -> Result<impl axum::response::IntoResponse, StatusCode> {
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
     // Get share by token
     let share: crate::database::SharedLink = sqlx::query_as(
         "SELECT * FROM shared_links WHERE id = ? AND is_public = 1 AND allow_download = 1",
@@ -351,51 +353,53 @@ async fn download_public_share(
             .map_err(|_| StatusCode::FORBIDDEN)?;
     }
 
-    // Increment download counter
-    sqlx::query("UPDATE shared_links SET download_count = download_count + 1 WHERE id = ?")
-        .bind(&share_token)
-        .execute(&state.db_pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    // Log access
-    let _ = services::sharing::log_access(&state, &share.id, None, "download", None).await;
-
-    // Stream file from storage
-    let file_path = std::path::Path::new("./data").join(&share.item_id);
-    
-    if !file_path.exists() {
-        return Err(StatusCode::NOT_FOUND);
-    }
-
-    let file = tokio::fs::File::open(&file_path)
+    // Resolve file from storage
+    let safe_path = crate::security::validate_file_path(&share.item_id)
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    // Encrypted content can only be read by its owner
+    let mut file = services::open_stored_file(&state, &safe_path, None)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
+        .map_err(|e| {
+            if e.is::<crate::encryption::Locked>() {
+                StatusCode::LOCKED
+            } else {
+                StatusCode::NOT_FOUND
+            }
+        })?;
 
     // Get filename for content-disposition
-    let filename = file_path
+    let filename = file
+        .path
         .file_name()
         .and_then(|n| n.to_str())
-        .unwrap_or("download");
-
-    // Detect mime type
-    let mime_type = mime_guess::from_path(&file_path)
-        .first_or_octet_stream()
+        .unwrap_or("download")
         .to_string();
+    file.content_disposition = Some(format!("attachment; filename=\"{}\"", filename));
+
+    let response = crate::http_range::file_response(&headers, &file).await?;
+
+    // Only count downloads that deliver the file from its start, so resumed
+    // transfers, seeking and 304 revalidations don't consume max_downloads
+    let from_start = response.status() == StatusCode::OK
+        || (response.status() == StatusCode::PARTIAL_CONTENT
+            && headers
+                .get(header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.trim().starts_with("bytes=0-")));
+
+    if from_start {
+        // Increment download counter
+        sqlx::query("UPDATE shared_links SET download_count = download_count + 1 WHERE id = ?")
+            .bind(&share_token)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok((
-        [
-            ("content-type", mime_type),
-            (
-                "content-disposition",
-                format!("attachment; filename=\"{}\"", filename),
-            ),
-        ],
-        body,
-    ))
+        // Log access
+        let _ = services::sharing::log_access(&state, &share.id, None, "download", None).await;
+    }
+
+    Ok(response)
 }
 
 // ============================================================================
@@ -18,6 +18,7 @@ pub fn router() -> Router<AppState> {
         .route("/storage/analytics/top-files", get(get_top_files))
         .route("/storage/analytics/growth", get(get_storage_growth))
         .route("/storage/analytics/duplicates", get(get_duplicate_waste))
+        .route("/storage/analytics/dedup", get(get_dedup_savings))
 }
 
 #[derive(Serialize)]
@@ -402,6 +403,33 @@ async fn get_duplicate_waste(
     }))
 }
 
+#[derive(Serialize)]
+struct DedupSavings {
+    #[serde(flatten)]
+    stats: crate::block_store::BlockStoreStats,
+    physical_formatted: String,
+    logical_formatted: String,
+    savings_formatted: String,
+}
+
+/// Actual space saved by the block store (files, copies, versions and backups)
+async fn get_dedup_savings(
+    State(state): State<AppState>,
+    _user: UserInfo,
+) -> Result<impl IntoResponse, StatusCode> {
+    let stats = crate::block_store::stats(&state.db_pool).await.map_err(|e| {
+        eprintln!("Failed to fetch block store stats: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(DedupSavings {
+        physical_formatted: format_bytes(stats.physical_bytes),
+        logical_formatted: format_bytes(stats.logical_bytes),
+        savings_formatted: format_bytes(stats.savings_bytes),
+        stats,
+    }))
+}
+
 // Helper function to format bytes into human-readable format
 fn format_bytes(bytes: i64) -> String {
     const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
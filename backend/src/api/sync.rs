@@ -0,0 +1,184 @@
+//! Delta Sync API Routes
+//!
+//! Incremental sync on top of the server-side change journal: a client
+//! fetches the current cursor, does one full listing, and from then on only
+//! asks for the changes after its cursor (optionally long-polling).
+
+use axum::{
+    Router,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Json},
+    routing::get,
+};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::time::Duration;
+
+use crate::AppState;
+use crate::auth::UserInfo;
+use crate::services::change_journal;
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    /// Last cursor seen by the client; omitted to only get the current cursor
+    pub cursor: Option<i64>,
+    pub limit: Option<i64>,
+    /// Seconds to wait for changes if there are none yet (max 60)
+    pub timeout: Option<u64>,
+    /// Only report changes at or below this path
+    pub path: Option<String>,
+}
+
+/// Current cursor, to be taken before a full listing
+async fn get_cursor(
+    State(state): State<AppState>,
+    _user: UserInfo,
+) -> Result<impl IntoResponse, StatusCode> {
+    let cursor = change_journal::latest_cursor(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(serde_json::json!({ "cursor": cursor })))
+}
+
+/// Ordered changes after a cursor, limited to what the user can read.
+///
+/// Returns 410 Gone if the cursor is no longer covered by the journal; the
+/// client has to start over with a full listing.
+async fn get_changes(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Query(query): Query<ChangesQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    Ok(Json(changes_page(&state.db_pool, &user, &query).await?))
+}
+
+async fn changes_page(
+    pool: &SqlitePool,
+    user: &UserInfo,
+    query: &ChangesQuery,
+) -> Result<change_journal::ChangesPage, StatusCode> {
+    let prefix = match query.path.as_deref().map(|p| p.trim_matches('/')) {
+        Some(path) if !path.is_empty() => Some(crate::security::validate_file_path(path)?),
+        _ => None,
+    };
+    let limit = query.limit.unwrap_or(change_journal::DEFAULT_PAGE_SIZE);
+
+    let Some(cursor) = query.cursor else {
+        let cursor = change_journal::latest_cursor(pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(change_journal::ChangesPage {
+            changes: Vec::new(),
+            cursor,
+            has_more: false,
+        });
+    };
+
+    let wait = Duration::from_secs(query.timeout.unwrap_or(0)).min(change_journal::MAX_WAIT);
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut cursor = cursor;
+    loop {
+        let wait = deadline.saturating_duration_since(tokio::time::Instant::now());
+        let mut page = if wait.is_zero() {
+            change_journal::changes_since(pool, cursor, prefix.as_deref(), limit).await
+        } else {
+            change_journal::wait_for_changes(pool, cursor, prefix.as_deref(), limit, wait).await
+        }
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::GONE)?;
+        page.changes = change_journal::visible_to(pool, user, page.changes)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        // A long-poll keeps waiting when everything new was hidden
+        if !page.changes.is_empty() || page.has_more || wait.is_zero() {
+            return Ok(page);
+        }
+        cursor = page.cursor;
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/sync/cursor", get(get_cursor))
+        .route("/sync/changes", get(get_changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use change_journal::Change;
+
+    fn admin() -> UserInfo {
+        UserInfo {
+            id: "admin".to_string(),
+            username: "admin".to_string(),
+            totp_enabled: false,
+            role: Some("admin".to_string()),
+            is_admin: true,
+        }
+    }
+
+    fn query(cursor: Option<i64>, path: Option<&str>) -> ChangesQuery {
+        ChangesQuery {
+            cursor,
+            limit: Some(1),
+            timeout: None,
+            path: path.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_changes_page() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::raw_sql(include_str!("../../migrations/055_change_journal.sql"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        for path in ["a/x.txt", "b/y.txt", "a/z.txt"] {
+            change_journal::record(&pool, Change::new("create", path)).await;
+        }
+
+        // Without a cursor only the current one comes back
+        let page = changes_page(&pool, &admin(), &query(None, None))
+            .await
+            .unwrap();
+        assert_eq!((page.cursor, page.changes.len()), (3, 0));
+
+        let page = changes_page(&pool, &admin(), &query(Some(0), None))
+            .await
+            .unwrap();
+        assert_eq!(page.changes[0].path, "a/x.txt");
+        assert!(page.has_more);
+        let page = changes_page(&pool, &admin(), &query(Some(1), Some("/a/")))
+            .await
+            .unwrap();
+        assert_eq!(page.changes[0].path, "a/z.txt");
+        assert_eq!(page.cursor, 3);
+        assert!(!page.has_more);
+
+        // A long-poll returns at once when changes are waiting
+        let waiting = ChangesQuery {
+            timeout: Some(30),
+            ..query(Some(2), None)
+        };
+        let page = changes_page(&pool, &admin(), &waiting).await.unwrap();
+        assert_eq!(page.changes[0].path, "a/z.txt");
+
+        let climbing = changes_page(&pool, &admin(), &query(Some(0), Some("a/../.."))).await;
+        assert_eq!(climbing.err(), Some(StatusCode::BAD_REQUEST));
+        let ahead = changes_page(&pool, &admin(), &query(Some(9), None)).await;
+        assert_eq!(ahead.err(), Some(StatusCode::GONE));
+        sqlx::query("UPDATE change_journal_state SET pruned_through = 2")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let pruned = changes_page(&pool, &admin(), &query(Some(1), None)).await;
+        assert_eq!(pruned.err(), Some(StatusCode::GONE));
+    }
+}
@@ -82,23 +82,17 @@ async fn restore_from_trash(
     user_info: UserInfo,
     Json(req): Json<RestoreRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Restore file by setting is_deleted = 0
-    let now = chrono::Utc::now().to_rfc3339();
-
-    // Use destination_path if provided, otherwise restore to original location
-    let restore_path = req.destination_path.unwrap_or_else(|| path.clone());
-
-    let result = sqlx::query(
-        "UPDATE files SET is_deleted = 0, path = ?, updated_at = ? WHERE path = ? AND is_deleted = 1",
+    // Restore to destination_path if provided, otherwise to the original location
+    let restored = crate::services::restore_file(
+        &state,
+        &user_info,
+        &path,
+        req.destination_path.as_deref(),
     )
-    .bind(&restore_path)
-    .bind(&now)
-    .bind(&path)
-    .execute(&state.db_pool)
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    if result.rows_affected() == 0 {
+    if !restored {
         return Err(StatusCode::NOT_FOUND);
     }
 
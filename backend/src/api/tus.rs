@@ -0,0 +1,672 @@
+//! Resumable uploads via the tus 1.0 protocol
+//!
+//! Supported extensions: creation, creation-with-upload, expiration,
+//! termination and checksum (sha256/sha512). Sessions live in
+//! `upload_sessions`, partial data in `./data/temp_uploads/tus/<id>.part`.
+//! Completed uploads go through the same quota check as the chunked upload
+//! finalize endpoint before they are moved into place.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::Response,
+    routing::{head, post},
+    Router,
+};
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::fs;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::{auth::UserInfo, AppState};
+
+pub const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,creation-with-upload,expiration,termination,checksum";
+const TUS_CHECKSUM_ALGORITHMS: &str = "sha256,sha512";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Sessions expire this long after their last PATCH
+pub const UPLOAD_EXPIRY_HOURS: i64 = 24;
+pub const TUS_TEMP_DIR: &str = "./data/temp_uploads/tus";
+
+/// Non-standard status defined by the checksum extension
+const CHECKSUM_MISMATCH: u16 = 460;
+
+static TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+static TUS_VERSION_HEADER: HeaderName = HeaderName::from_static("tus-version");
+static TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+static TUS_CHECKSUM_ALGORITHM: HeaderName = HeaderName::from_static("tus-checksum-algorithm");
+static UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+static UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+static UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+static UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+static UPLOAD_CHECKSUM: HeaderName = HeaderName::from_static("upload-checksum");
+static UPLOAD_DEFER_LENGTH: HeaderName = HeaderName::from_static("upload-defer-length");
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UploadSession {
+    pub id: String,
+    pub user_id: String,
+    pub target_path: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    pub upload_metadata: Option<String>,
+    pub temp_path: String,
+    pub status: String,
+    pub expires_at: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub completed_at: Option<String>,
+}
+
+impl UploadSession {
+    fn is_expired(&self) -> bool {
+        DateTime::parse_from_rfc3339(&self.expires_at)
+            .map(|t| t.with_timezone(&Utc) < Utc::now())
+            .unwrap_or(true)
+    }
+
+    fn expires_header(&self) -> String {
+        DateTime::parse_from_rfc3339(&self.expires_at)
+            .map(|t| httpdate::fmt_http_date(t.into()))
+            .unwrap_or_default()
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/uploads/tus", post(create_upload).options(tus_options))
+        .route(
+            "/uploads/tus/{upload_id}",
+            head(get_upload_offset)
+                .patch(append_upload)
+                .delete(terminate_upload)
+                .options(tus_options),
+        )
+}
+
+// ==================== HANDLERS ====================
+
+/// OPTIONS - advertise protocol version and extensions
+async fn tus_options() -> Response {
+    tus_response(StatusCode::NO_CONTENT)
+        .header(&TUS_VERSION_HEADER, TUS_VERSION)
+        .header(&TUS_EXTENSION, TUS_EXTENSIONS)
+        .header(&TUS_CHECKSUM_ALGORITHM, TUS_CHECKSUM_ALGORITHMS)
+        .body(Body::empty())
+        .unwrap_or_default()
+}
+
+/// POST /uploads/tus - create a new upload session (creation extension)
+///
+/// Metadata keys: `filename` (required) and `path` (target directory).
+async fn create_upload(
+    State(state): State<AppState>,
+    user: UserInfo,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    check_tus_resumable(&headers)?;
+
+    if headers.contains_key(&UPLOAD_DEFER_LENGTH) {
+        // creation-defer-length is not advertised
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let upload_length: i64 = header_value(&headers, &UPLOAD_LENGTH)
+        .and_then(|v| v.parse().ok())
+        .filter(|len: &i64| *len >= 0)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let raw_metadata = header_value(&headers, &UPLOAD_METADATA).map(str::to_string);
+    let metadata = raw_metadata
+        .as_deref()
+        .map(parse_upload_metadata)
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+        .unwrap_or_default();
+
+    let target_path = target_path_from_metadata(&metadata).ok_or(StatusCode::BAD_REQUEST)?;
+
+    // Reject early when the announced size can never fit; the quota is checked
+    // again on completion because usage may change while the upload runs
+    let has_quota =
+        crate::api::quota::check_quota_available(&state.db_pool, &user.id, upload_length)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !has_quota {
+        tracing::warn!(
+            "User {} cannot start tus upload of {} bytes: quota exceeded",
+            user.id,
+            upload_length
+        );
+        return Err(StatusCode::INSUFFICIENT_STORAGE);
+    }
+
+    fs::create_dir_all(TUS_TEMP_DIR)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let upload_id = Uuid::new_v4().simple().to_string();
+    let temp_path = PathBuf::from(TUS_TEMP_DIR).join(format!("{}.part", upload_id));
+    fs::File::create(&temp_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let now = Utc::now();
+    let expires_at = (now + Duration::hours(UPLOAD_EXPIRY_HOURS)).to_rfc3339();
+
+    sqlx::query(
+        "INSERT INTO upload_sessions
+         (id, user_id, target_path, upload_length, upload_offset, upload_metadata, temp_path, status, expires_at, created_at, updated_at)
+         VALUES (?, ?, ?, ?, 0, ?, ?, 'uploading', ?, ?, ?)",
+    )
+    .bind(&upload_id)
+    .bind(&user.id)
+    .bind(&target_path)
+    .bind(upload_length)
+    .bind(&raw_metadata)
+    .bind(temp_path.to_string_lossy().to_string())
+    .bind(&expires_at)
+    .bind(now.to_rfc3339())
+    .bind(now.to_rfc3339())
+    .execute(&state.db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to create upload session: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    tracing::info!(
+        "tus upload {} created for '{}' ({} bytes)",
+        upload_id,
+        target_path,
+        upload_length
+    );
+
+    let mut session = fetch_session(&state, &user, &upload_id).await?;
+
+    // creation-with-upload: the POST body may already carry the first bytes
+    let has_payload = header_value(&headers, &header::CONTENT_TYPE) == Some(OFFSET_CONTENT_TYPE);
+    if has_payload {
+        session = write_chunk(&state, &user, session, &headers, body).await?;
+    } else if upload_length == 0 {
+        session = complete_upload(&state, &user, session).await?;
+    }
+
+    tus_response(StatusCode::CREATED)
+        .header(header::LOCATION, format!("/api/uploads/tus/{}", upload_id))
+        .header(&UPLOAD_OFFSET, session.upload_offset)
+        .header(&UPLOAD_EXPIRES, session.expires_header())
+        .body(Body::empty())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// HEAD /uploads/tus/{upload_id} - report how many bytes were received
+async fn get_upload_offset(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(upload_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    check_tus_resumable(&headers)?;
+
+    let session = fetch_session(&state, &user, &upload_id).await?;
+    if session.status == "uploading" && session.is_expired() {
+        return Err(StatusCode::GONE);
+    }
+
+    let mut response = tus_response(StatusCode::OK)
+        .header(&UPLOAD_OFFSET, session.upload_offset)
+        .header(&UPLOAD_LENGTH, session.upload_length)
+        .header(header::CACHE_CONTROL, "no-store")
+        .header(&UPLOAD_EXPIRES, session.expires_header());
+    if let Some(metadata) = &session.upload_metadata {
+        response = response.header(&UPLOAD_METADATA, metadata);
+    }
+
+    response
+        .body(Body::empty())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// PATCH /uploads/tus/{upload_id} - append bytes at the current offset
+async fn append_upload(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(upload_id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    check_tus_resumable(&headers)?;
+
+    if header_value(&headers, &header::CONTENT_TYPE) != Some(OFFSET_CONTENT_TYPE) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    let session = fetch_session(&state, &user, &upload_id).await?;
+    let session = write_chunk(&state, &user, session, &headers, body).await?;
+
+    tus_response(StatusCode::NO_CONTENT)
+        .header(&UPLOAD_OFFSET, session.upload_offset)
+        .header(&UPLOAD_EXPIRES, session.expires_header())
+        .body(Body::empty())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// DELETE /uploads/tus/{upload_id} - abort an upload (termination extension)
+async fn terminate_upload(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(upload_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    check_tus_resumable(&headers)?;
+
+    let session = fetch_session(&state, &user, &upload_id).await?;
+    let _guard = UploadGuard::acquire(&session.id).ok_or(StatusCode::LOCKED)?;
+
+    let _ = fs::remove_file(&session.temp_path).await;
+    sqlx::query("DELETE FROM upload_sessions WHERE id = ?")
+        .bind(&session.id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tracing::info!("tus upload {} terminated by {}", session.id, user.id);
+
+    tus_response(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+// ==================== UPLOAD LOGIC ====================
+
+/// Stream the request body into the partial file at the session offset.
+///
+/// Bytes received before a client disconnect are kept so the client can
+/// resume from the reported offset. With `Upload-Checksum` the chunk is only
+/// committed when the digest matches.
+async fn write_chunk(
+    state: &AppState,
+    user: &UserInfo,
+    session: UploadSession,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<UploadSession, StatusCode> {
+    let _guard = UploadGuard::acquire(&session.id).ok_or(StatusCode::LOCKED)?;
+
+    if session.status != "uploading" {
+        return Err(StatusCode::CONFLICT);
+    }
+    if session.is_expired() {
+        return Err(StatusCode::GONE);
+    }
+
+    // POST with creation-with-upload implies offset 0
+    let client_offset: i64 = match header_value(headers, &UPLOAD_OFFSET) {
+        Some(value) => value.parse().map_err(|_| StatusCode::BAD_REQUEST)?,
+        None if header_value(headers, &UPLOAD_LENGTH).is_some() => 0,
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+    if client_offset != session.upload_offset {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let mut checksum = header_value(headers, &UPLOAD_CHECKSUM)
+        .map(ChecksumVerifier::parse)
+        .transpose()?;
+
+    let start = session.upload_offset as u64;
+    let remaining = (session.upload_length - session.upload_offset) as u64;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(&session.temp_path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    // Drop anything beyond the committed offset (e.g. a rejected chunk)
+    file.set_len(start)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    file.seek(std::io::SeekFrom::Start(start))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut written: u64 = 0;
+    let mut interrupted = false;
+    let mut stream = body.into_data_stream();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                tracing::warn!("tus upload {} interrupted: {}", session.id, e);
+                interrupted = true;
+                break;
+            }
+        };
+
+        if written + chunk.len() as u64 > remaining {
+            let _ = file.set_len(start).await;
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
+        file.write_all(&chunk)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if let Some(verifier) = checksum.as_mut() {
+            verifier.update(&chunk);
+        }
+        written += chunk.len() as u64;
+    }
+
+    file.flush()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if let Some(verifier) = checksum {
+        // A partial chunk can't be verified, so it is discarded as well
+        if interrupted || !verifier.matches() {
+            let _ = file.set_len(start).await;
+            return Err(if interrupted {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::from_u16(CHECKSUM_MISMATCH).unwrap_or(StatusCode::BAD_REQUEST)
+            });
+        }
+    }
+
+    file.sync_data()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    drop(file);
+
+    let now = Utc::now();
+    let new_offset = session.upload_offset + written as i64;
+    let expires_at = (now + Duration::hours(UPLOAD_EXPIRY_HOURS)).to_rfc3339();
+
+    sqlx::query(
+        "UPDATE upload_sessions SET upload_offset = ?, expires_at = ?, updated_at = ?
+         WHERE id = ? AND upload_offset = ?",
+    )
+    .bind(new_offset)
+    .bind(&expires_at)
+    .bind(now.to_rfc3339())
+    .bind(&session.id)
+    .bind(session.upload_offset)
+    .execute(&state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let session = UploadSession {
+        upload_offset: new_offset,
+        expires_at,
+        updated_at: now.to_rfc3339(),
+        ..session
+    };
+
+    if interrupted {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if session.upload_offset == session.upload_length {
+        return complete_upload(state, user, session).await;
+    }
+
+    Ok(session)
+}
+
+/// Run the shared finalize step (quota check + import) once all bytes are in
+async fn complete_upload(
+    state: &AppState,
+    user: &UserInfo,
+    session: UploadSession,
+) -> Result<UploadSession, StatusCode> {
+    let temp_path = PathBuf::from(&session.temp_path);
+
+    if let Err(status) = crate::api::upload_chunk::finalize_assembled_upload(
+        state,
+        user,
+        &temp_path,
+        &session.target_path,
+    )
+    .await
+    {
+        if status == StatusCode::INSUFFICIENT_STORAGE {
+            // Same behaviour as chunked uploads: drop the data on quota failure
+            let _ = fs::remove_file(&temp_path).await;
+            let _ = sqlx::query("DELETE FROM upload_sessions WHERE id = ?")
+                .bind(&session.id)
+                .execute(&state.db_pool)
+                .await;
+        }
+        return Err(status);
+    }
+
+    let now = Utc::now().to_rfc3339();
+    sqlx::query(
+        "UPDATE upload_sessions SET status = 'completed', completed_at = ?, updated_at = ? WHERE id = ?",
+    )
+    .bind(&now)
+    .bind(&now)
+    .bind(&session.id)
+    .execute(&state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tracing::info!(
+        "tus upload {} completed: '{}' ({} bytes)",
+        session.id,
+        session.target_path,
+        session.upload_length
+    );
+
+    Ok(UploadSession {
+        status: "completed".to_string(),
+        completed_at: Some(now.clone()),
+        updated_at: now,
+        ..session
+    })
+}
+
+async fn fetch_session(
+    state: &AppState,
+    user: &UserInfo,
+    upload_id: &str,
+) -> Result<UploadSession, StatusCode> {
+    sqlx::query_as::<_, UploadSession>(
+        "SELECT * FROM upload_sessions WHERE id = ? AND user_id = ?",
+    )
+    .bind(upload_id)
+    .bind(&user.id)
+    .fetch_optional(&state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+// ==================== HELPERS ====================
+
+/// Rejects concurrent PATCH/DELETE requests on the same upload
+struct UploadGuard {
+    upload_id: String,
+}
+
+impl UploadGuard {
+    fn active() -> &'static Mutex<HashSet<String>> {
+        static ACTIVE: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+        ACTIVE.get_or_init(|| Mutex::new(HashSet::new()))
+    }
+
+    fn acquire(upload_id: &str) -> Option<Self> {
+        let mut active = Self::active().lock().ok()?;
+        if !active.insert(upload_id.to_string()) {
+            return None;
+        }
+        Some(Self {
+            upload_id: upload_id.to_string(),
+        })
+    }
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        if let Ok(mut active) = Self::active().lock() {
+            active.remove(&self.upload_id);
+        }
+    }
+}
+
+enum ChecksumVerifier {
+    Sha256(Sha256, Vec<u8>),
+    Sha512(Sha512, Vec<u8>),
+}
+
+impl ChecksumVerifier {
+    /// Parse `Upload-Checksum: <algorithm> <base64 digest>`
+    fn parse(value: &str) -> Result<Self, StatusCode> {
+        let (algorithm, encoded) = value
+            .trim()
+            .split_once(' ')
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        let expected = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        match algorithm {
+            "sha256" => Ok(Self::Sha256(Sha256::new(), expected)),
+            "sha512" => Ok(Self::Sha512(Sha512::new(), expected)),
+            _ => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher, _) => hasher.update(data),
+            Self::Sha512(hasher, _) => hasher.update(data),
+        }
+    }
+
+    fn matches(self) -> bool {
+        match self {
+            Self::Sha256(hasher, expected) => hasher.finalize().as_slice() == expected.as_slice(),
+            Self::Sha512(hasher, expected) => hasher.finalize().as_slice() == expected.as_slice(),
+        }
+    }
+}
+
+fn tus_response(status: StatusCode) -> axum::http::response::Builder {
+    Response::builder()
+        .status(status)
+        .header(&TUS_RESUMABLE, TUS_VERSION)
+}
+
+fn check_tus_resumable(headers: &HeaderMap) -> Result<(), StatusCode> {
+    match header_value(headers, &TUS_RESUMABLE) {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(StatusCode::PRECONDITION_FAILED),
+    }
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|v: &HeaderValue| v.to_str().ok())
+        .map(str::trim)
+}
+
+/// Parse `Upload-Metadata`: comma-separated `key base64value` pairs
+pub fn parse_upload_metadata(value: &str) -> Result<HashMap<String, String>, String> {
+    let mut metadata = HashMap::new();
+
+    for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, encoded) = match pair.split_once(' ') {
+            Some((key, encoded)) => (key, encoded.trim()),
+            None => (pair, ""),
+        };
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| format!("Invalid metadata value for '{}': {}", key, e))?;
+        let decoded = String::from_utf8(decoded)
+            .map_err(|_| format!("Metadata value for '{}' is not UTF-8", key))?;
+        metadata.insert(key.to_string(), decoded);
+    }
+
+    Ok(metadata)
+}
+
+/// Build the validated destination path from `path` + `filename` metadata
+fn target_path_from_metadata(metadata: &HashMap<String, String>) -> Option<String> {
+    let filename = metadata
+        .get("filename")
+        .or_else(|| metadata.get("name"))?;
+    crate::security::validate_filename(filename).ok()?;
+
+    let directory = metadata
+        .get("path")
+        .map(|p| p.trim_matches('/'))
+        .unwrap_or("");
+    let target = if directory.is_empty() {
+        filename.clone()
+    } else {
+        format!("{}/{}", directory, filename)
+    };
+
+    crate::security::validate_file_path(&target).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_upload_metadata() {
+        let metadata =
+            parse_upload_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential")
+                .unwrap();
+        assert_eq!(metadata["filename"], "world_domination_plan.pdf");
+        assert_eq!(metadata["is_confidential"], "");
+        assert!(parse_upload_metadata("filename !!!").is_err());
+    }
+
+    #[test]
+    fn test_target_path_from_metadata() {
+        let mut metadata = HashMap::new();
+        metadata.insert("filename".to_string(), "report.pdf".to_string());
+        assert_eq!(target_path_from_metadata(&metadata).as_deref(), Some("report.pdf"));
+
+        metadata.insert("path".to_string(), "/projects/q3/".to_string());
+        assert_eq!(
+            target_path_from_metadata(&metadata).as_deref(),
+            Some("projects/q3/report.pdf")
+        );
+
+        metadata.insert("path".to_string(), "../etc".to_string());
+        assert_eq!(target_path_from_metadata(&metadata), None);
+    }
+
+    #[test]
+    fn test_checksum_verifier() {
+        // sha256("hello")
+        let header = "sha256 LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+        let mut verifier = ChecksumVerifier::parse(header).unwrap();
+        verifier.update(b"hel");
+        verifier.update(b"lo");
+        assert!(verifier.matches());
+
+        let mut verifier = ChecksumVerifier::parse(header).unwrap();
+        verifier.update(b"world");
+        assert!(!verifier.matches());
+
+        assert!(ChecksumVerifier::parse("crc32 AAAA").is_err());
+    }
+}
@@ -6,11 +6,11 @@ use axum::{
     Router,
 };
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 
-use crate::{auth::UserInfo, AppState};
+use crate::{auth::UserInfo, models::FileInfo, AppState};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChunkFinalizeRequest {
@@ -152,7 +152,8 @@ async fn finalize_chunked_upload(
     let temp_dir = PathBuf::from("./data/temp_uploads");
     let upload_dir = temp_dir.join(&req.upload_id);
 
-    // Calculate total size by summing all chunks
+    // Every chunk has to be there before anything is assembled, their sizes
+    // add up to the size of the upload
     let mut total_size: i64 = 0;
     for i in 0..req.total_chunks {
         let chunk_path = upload_dir.join(format!("chunk_{:06}", i));
@@ -166,84 +167,120 @@ async fn finalize_chunked_upload(
         total_size += metadata.len() as i64;
     }
 
-    // SECURITY: Check quota before finalizing upload
-    let has_quota = crate::api::quota::check_quota_available(&state.db_pool, &user.id, total_size)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    if !has_quota {
-        tracing::warn!(
-            "User {} exceeded quota. Required: {} bytes",
-            user.id,
-            total_size
-        );
-        // Clean up temp chunks on quota failure
-        let _ = fs::remove_dir_all(&upload_dir).await;
-        return Err(StatusCode::INSUFFICIENT_STORAGE);
-    }
-
-    // Verify all chunks exist
-    for i in 0..req.total_chunks {
-        let chunk_path = upload_dir.join(format!("chunk_{:06}", i));
-        if !chunk_path.exists() {
-            tracing::error!("Missing chunk {} for upload {}", i, req.upload_id);
-            return Err(StatusCode::BAD_REQUEST);
-        }
-    }
-
-    // Determine final file path
-    let base_dir = PathBuf::from("./data");
-    let clean_path = req.path.trim_start_matches('/');
-    let final_path = if clean_path.is_empty() {
-        base_dir.join(&req.file_name)
+    // Determine final file path (relative to the data dir)
+    let clean_path = req.path.trim_start_matches('/').trim_end_matches('/');
+    let target_path = if clean_path.is_empty() {
+        req.file_name.clone()
     } else {
-        base_dir.join(clean_path).join(&req.file_name)
+        format!("{}/{}", clean_path, req.file_name)
     };
 
-    // Create parent directories
-    if let Some(parent) = final_path.parent() {
-        fs::create_dir_all(parent)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // SECURITY: Check quota before assembling, so a too large upload never
+    // takes up its size a second time
+    if let Err(status) = ensure_quota(&state, &user, &target_path, total_size).await {
+        // Clean up temp chunks on quota failure
+        let _ = fs::remove_dir_all(&upload_dir).await;
+        return Err(status);
     }
 
-    // Merge chunks into final file
-    let mut final_file = fs::File::create(&final_path)
+    // Merge chunks into a single assembled file inside the upload dir
+    let assembled_path = upload_dir.join("assembled");
+    let mut assembled = fs::File::create(&assembled_path)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     for i in 0..req.total_chunks {
         let chunk_path = upload_dir.join(format!("chunk_{:06}", i));
-        let chunk_data = fs::read(&chunk_path)
+        let mut chunk = fs::File::open(&chunk_path)
             .await
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-        final_file
-            .write_all(&chunk_data)
+        tokio::io::copy(&mut chunk, &mut assembled)
             .await
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     }
 
-    final_file
+    assembled
         .flush()
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    drop(assembled);
+
+    let result = finalize_assembled_upload(&state, &user, &assembled_path, &target_path).await;
 
-    // Clean up temp chunks
-    fs::remove_dir_all(&upload_dir).await.map_err(|e| {
+    // Clean up temp chunks (also on quota failure)
+    if let Err(e) = fs::remove_dir_all(&upload_dir).await {
         tracing::warn!("Failed to cleanup temp upload directory: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    }
+
+    let file = result?;
 
     tracing::info!(
-        "Chunked upload finalized: {} ({} chunks merged)",
-        final_path.display(),
-        req.total_chunks
+        "Chunked upload finalized: {} ({} chunks merged, {} bytes)",
+        file.path,
+        req.total_chunks,
+        total_size
     );
 
     Ok(Json(ChunkFinalizeResponse {
         success: true,
         message: "Chunked upload complete".to_string(),
-        file_path: final_path.to_string_lossy().to_string(),
+        file_path: file.path,
     }))
 }
+
+/// Final step shared by chunked and tus uploads: quota check, then move the
+/// assembled file into place and register it like a regular upload.
+pub async fn finalize_assembled_upload(
+    state: &AppState,
+    user: &UserInfo,
+    assembled_path: &Path,
+    target_path: &str,
+) -> Result<FileInfo, StatusCode> {
+    let total_size = fs::metadata(assembled_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .len() as i64;
+
+    // SECURITY: Check quota before finalizing upload
+    ensure_quota(state, user, target_path, total_size).await?;
+
+    crate::services::import_uploaded_file(state, user, target_path, assembled_path)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to finalize upload to '{}': {}", target_path, e);
+            StatusCode::BAD_REQUEST
+        })
+}
+
+/// 507 Insufficient Storage unless an upload of `size` bytes to `target_path`
+/// fits into the user's quota. An overwrite only needs what it adds to the
+/// file it replaces.
+async fn ensure_quota(
+    state: &AppState,
+    user: &UserInfo,
+    target_path: &str,
+    size: i64,
+) -> Result<(), StatusCode> {
+    let replaced = match crate::security::validate_file_path(target_path) {
+        Ok(safe_path) => {
+            crate::api::quota::replaced_bytes(&state.db_pool, &user.id, &safe_path).await?
+        }
+        // Rejected by the import anyway
+        Err(_) => 0,
+    };
+    let required = size - replaced;
+    let has_quota = crate::api::quota::check_quota_available(&state.db_pool, &user.id, required)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if !has_quota {
+        tracing::warn!(
+            "User {} exceeded quota. Required: {} bytes",
+            user.id,
+            required
+        );
+        return Err(StatusCode::INSUFFICIENT_STORAGE);
+    }
+    Ok(())
+}
@@ -1,7 +1,7 @@
 //! File Versioning API Routes with Differential Storage, Compression, and Tags
 
 use axum::{
-    body::Bytes,
+    body::Body,
     extract::{Path, Query, State},
     http::{header, StatusCode},
     response::{IntoResponse, Json, Response},
@@ -11,6 +11,7 @@ use axum::{
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use std::path::PathBuf;
+use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
 use crate::auth::UserInfo;
@@ -253,53 +254,45 @@ async fn delete_version(
     Path((file_id, version_id)): Path<(String, String)>,
     _user: UserInfo,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let version: Option<(String,)> =
-        sqlx::query_as("SELECT storage_path FROM file_versions WHERE id = ? AND file_id = ?")
+    let exists: Option<(String,)> =
+        sqlx::query_as("SELECT id FROM file_versions WHERE id = ? AND file_id = ?")
             .bind(&version_id)
             .bind(&file_id)
             .fetch_optional(&state.db_pool)
             .await
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if exists.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
 
-    if let Some((storage_path,)) = version {
-        let _ = tokio::fs::remove_file(&storage_path).await;
-
-        let result = sqlx::query("DELETE FROM file_versions WHERE id = ?")
-            .bind(&version_id)
-            .execute(&state.db_pool)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        if result.rows_affected() > 0 {
-            Ok(StatusCode::NO_CONTENT)
-        } else {
-            Err(StatusCode::NOT_FOUND)
+    match version_storage_service::delete_version(&state.db_pool, &version_id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("Error deleting version: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
-    } else {
-        Err(StatusCode::NOT_FOUND)
     }
 }
 
-/// Restore a version (returns file content)
+/// Restore a version (streams file content)
 async fn restore_version(
     State(state): State<AppState>,
     Path((_file_id, version_id)): Path<(String, String)>,
     _user: UserInfo,
 ) -> Result<Response, StatusCode> {
-    match version_storage_service::restore_version(&state.db_pool, &version_id).await {
-        Ok(content) => {
-            let body = Bytes::from(content);
-
-            Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header(header::CONTENT_TYPE, "application/octet-stream")
-                .header(
-                    header::CONTENT_DISPOSITION,
-                    format!("attachment; filename=\"restored_v{}.dat\"", version_id),
-                )
-                .body(body.into())
-                .unwrap())
-        }
+    match version_storage_service::open_version(&state.db_pool, &version_id).await {
+        Ok(Some((version, content))) => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, version.original_size)
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"restored_v{}.dat\"", version_id),
+            )
+            .body(Body::from_stream(ReaderStream::new(content)))
+            .unwrap()),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
         Err(e) => {
             eprintln!("Error restoring version: {}", e);
             Err(StatusCode::INTERNAL_SERVER_ERROR)
@@ -590,18 +583,16 @@ async fn get_version_diff(
         })));
     }
 
-    // Get version contents
-    let from_content = version_storage_service::restore_version(&state.db_pool, &from_version_id)
-        .await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
-    let to_content = version_storage_service::restore_version(&state.db_pool, &to_version_id)
-        .await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
-
-    // Check if binary
-    let is_text = |content: &[u8]| -> bool { content.iter().take(8000).all(|&b| b != 0) };
+    // Only small text versions are loaded; anything else is compared by checksum
+    let diff = version_storage_service::get_version_diff(
+        &state.db_pool,
+        &from_version_id,
+        &to_version_id,
+    )
+    .await
+    .map_err(|_| StatusCode::NOT_FOUND)?;
 
-    if !is_text(&from_content) || !is_text(&to_content) {
+    let Some(text_diff) = diff.text_diff else {
         return Ok(Json(serde_json::json!({
             "diff_type": "binary",
             "diff_content": null,
@@ -611,44 +602,23 @@ async fn get_version_diff(
             "to_version_id": to_version_id,
             "message": "Binary files cannot be compared"
         })));
-    }
-
-    let from_text = String::from_utf8_lossy(&from_content);
-    let to_text = String::from_utf8_lossy(&to_content);
-
-    // Simple line-by-line diff
-    let from_lines: Vec<&str> = from_text.lines().collect();
-    let to_lines: Vec<&str> = to_text.lines().collect();
+    };
 
     let mut diff_lines = Vec::new();
     let mut added = 0;
     let mut removed = 0;
 
-    // Simple diff algorithm (for now)
-    let max_lines = std::cmp::max(from_lines.len(), to_lines.len());
-    for i in 0..max_lines {
-        let from_line = from_lines.get(i).copied();
-        let to_line = to_lines.get(i).copied();
-
-        match (from_line, to_line) {
-            (Some(f), Some(t)) if f == t => {
-                diff_lines.push(format!("  {}", f));
-            }
-            (Some(f), Some(t)) => {
-                diff_lines.push(format!("- {}", f));
-                diff_lines.push(format!("+ {}", t));
-                removed += 1;
+    for line in text_diff {
+        match line.change_type {
+            version_storage_service::ChangeType::Added => {
+                diff_lines.push(format!("+ {}", line.content));
                 added += 1;
             }
-            (Some(f), None) => {
-                diff_lines.push(format!("- {}", f));
+            version_storage_service::ChangeType::Deleted => {
+                diff_lines.push(format!("- {}", line.content));
                 removed += 1;
             }
-            (None, Some(t)) => {
-                diff_lines.push(format!("+ {}", t));
-                added += 1;
-            }
-            (None, None) => {}
+            _ => diff_lines.push(format!("  {}", line.content)),
         }
     }
 
@@ -1,168 +1,403 @@
 //! WebDAV API endpoints
-//! Provides WebDAV protocol support for mounting SyncSpace as network drive
+//!
+//! Mounts the data directory at `/api/webdav` so SyncSpace can be used as a
+//! network drive from file managers. The protocol is implemented by
+//! `crate::webdav`; this module adds authentication and keeps the database,
+//! search index and quotas in sync with changes made through WebDAV.
+//!
+//! File managers cannot obtain JWTs, so besides `Bearer` tokens the routes
+//! accept HTTP Basic credentials: the account password for users without
+//! two-factor authentication, or a personal API token (`ssk_...`) as the
+//! password. Tokens need `files:read` for reading methods and `files:write`
+//! for the others, and path-limited tokens only reach their folders.
+//!
+//! Encrypted files are read and written through the keyring: they need their
+//! key unlocked in the session of the token used, so password logins (which
+//! have no session) cannot reach them.
 
 use axum::{
-    body::Body,
-    extract::{Path, State},
-    http::{header, HeaderMap, Method, StatusCode},
-    response::Response,
-    routing::any,
     Router,
+    extract::{ConnectInfo, OriginalUri, Request, State},
+    http::{HeaderMap, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::any,
 };
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-use crate::{auth::UserInfo, webdav, AppState};
+use crate::AppState;
+use crate::auth::{Session, User, UserInfo};
+use crate::encryption::Locked;
+use crate::rbac::tokens::{self, TokenGrant};
+use crate::services::fs_watcher;
+use crate::webdav::{DavChange, DavContent, DavHooks, DavServer, DavUser};
 
-/// Main WebDAV handler
-async fn webdav_handler(
-    state: State<AppState>,
-    headers: HeaderMap,
-    method: Method,
-    path: Path<String>,
-    user: UserInfo,
-    body: Body,
-) -> Result<Response<Body>, StatusCode> {
-    match method.as_str() {
-        "OPTIONS" => Ok(webdav::handle_options(headers).await),
-        "PROPFIND" => webdav::handle_propfind(state, headers, path, user).await,
-        "PROPPATCH" => webdav::handle_proppatch(state, path, user).await,
-        "MKCOL" => webdav::handle_mkcol(state, path, user)
-            .await
-            .map(|s| Response::builder().status(s).body(Body::empty()).unwrap()),
-        "COPY" => webdav::handle_copy(state, headers, path, user)
-            .await
-            .map(|s| Response::builder().status(s).body(Body::empty()).unwrap()),
-        "MOVE" => webdav::handle_move(state, headers, path, user)
-            .await
-            .map(|s| Response::builder().status(s).body(Body::empty()).unwrap()),
-        "LOCK" => webdav::handle_lock(state, path, user).await,
-        "UNLOCK" => Ok(Response::builder()
-            .status(webdav::handle_unlock(state, path, user).await)
-            .body(Body::empty())
-            .unwrap()),
-        "GET" | "HEAD" => handle_get(state, path, user, method == Method::HEAD).await,
-        "PUT" => handle_put(state, path, user, body).await,
-        "DELETE" => handle_delete(state, path, user)
-            .await
-            .map(|s| Response::builder().status(s).body(Body::empty()).unwrap()),
-        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
-    }
+const DATA_DIR: &str = "./data";
+/// URL path of the WebDAV root as seen by clients
+const MOUNT_PATH: &str = "/api/webdav";
+
+/// File managers send credentials with every request; successful password
+/// logins are remembered briefly so that each one does not cost an Argon2
+/// verification
+const CREDENTIAL_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+const CREDENTIAL_CACHE_MAX: usize = 1024;
+
+type CredentialCache = Mutex<HashMap<String, (UserInfo, Instant)>>;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/webdav", any(webdav_handler))
+        .route("/webdav/", any(webdav_handler))
+        .route("/webdav/{*path}", any(webdav_handler))
 }
 
-/// Handle GET/HEAD request
-async fn handle_get(
-    State(_state): State<AppState>,
-    Path(path): Path<String>,
-    _user: UserInfo,
-    head_only: bool,
-) -> Result<Response<Body>, StatusCode> {
-    let full_path = std::path::Path::new("./data").join(&path);
+/// Application side of the WebDAV server
+struct AppHooks {
+    state: AppState,
+    /// Session of the request's token, for encrypted files
+    session: Option<Session>,
+}
 
-    if !full_path.exists() {
-        return Err(StatusCode::NOT_FOUND);
+impl AppHooks {
+    fn reader<'a>(&'a self, user: &'a DavUser) -> Option<(&'a str, &'a Session)> {
+        self.session
+            .as_ref()
+            .map(|session| (user.id.as_str(), session))
     }
+}
+
+/// Status for a failed read or write of encrypted content
+fn encryption_status(path: &str, e: &(dyn std::error::Error + 'static)) -> StatusCode {
+    if e.is::<Locked>() {
+        StatusCode::FORBIDDEN
+    } else {
+        tracing::error!("WebDAV access to encrypted {} failed: {}", path, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
 
-    if full_path.is_dir() {
-        // Directory listing not supported via GET
-        return Err(StatusCode::METHOD_NOT_ALLOWED);
+#[async_trait::async_trait]
+impl DavHooks for AppHooks {
+    async fn available_bytes(&self, user: &DavUser) -> Option<u64> {
+        let available = crate::api::quota::available_bytes(&self.state.db_pool, &user.id)
+            .await
+            .unwrap_or(0);
+        Some(available as u64)
     }
 
-    let content = tokio::fs::read(&full_path)
+    async fn open(
+        &self,
+        user: &DavUser,
+        path: &str,
+        stored_len: u64,
+    ) -> Result<Option<DavContent>, StatusCode> {
+        let decryption = crate::encryption::decryption_for(
+            &self.state.db_pool,
+            path,
+            stored_len,
+            self.reader(user),
+        )
+        .await
+        .map_err(|e| encryption_status(path, e.as_ref()))?;
+        let Some(decryption) = decryption else {
+            return Ok(None);
+        };
+        let len = decryption.plaintext_len();
+        let file = tokio::fs::File::open(Path::new(DATA_DIR).join(path))
+            .await
+            .map_err(|e| encryption_status(path, &e))?;
+        let stream = crate::encryption::stream::decrypt_range(
+            &decryption.data_key,
+            file,
+            decryption.encrypted_len,
+            0,
+            len,
+        )
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| encryption_status(path, &e))?;
+        Ok(Some(DavContent {
+            len,
+            body: axum::body::Body::from_stream(stream),
+        }))
+    }
 
-    let content_type = mime_guess::from_path(&full_path)
-        .first_or_octet_stream()
-        .to_string();
+    async fn content_length(&self, path: &str, stored_len: u64) -> u64 {
+        crate::encryption::plaintext_size(&self.state.db_pool, path, stored_len)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or(stored_len)
+    }
 
-    let mut builder = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, content_type)
-        .header(header::CONTENT_LENGTH, content.len());
+    async fn replace(&self, user: &DavUser, path: &str, upload: &Path) -> Result<bool, StatusCode> {
+        crate::encryption::replace_content(&self.state.db_pool, path, upload, self.reader(user))
+            .await
+            .map_err(|e| encryption_status(path, e.as_ref()))
+    }
 
-    // Add DAV headers
-    for (name, value) in webdav::get_dav_headers() {
-        builder = builder.header(name, value);
+    async fn changed(&self, user: &DavUser, change: DavChange) {
+        let (renames, paths) = match change {
+            DavChange::Changed(path) | DavChange::Deleted(path) => (Vec::new(), vec![path]),
+            // The destination is reconciled too in case the source had no rows
+            DavChange::Moved { from, to } => (vec![(from, to.clone())], vec![to]),
+        };
+        if let Err(e) = fs_watcher::sync_paths(
+            self.state.db_pool.clone(),
+            self.state.search_index.clone(),
+            self.state.fs_tx.clone(),
+            &user.id,
+            renames,
+            paths,
+        )
+        .await
+        {
+            tracing::warn!("Failed to sync WebDAV change: {}", e);
+        }
     }
 
-    if head_only {
-        builder.body(Body::empty()).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
-    } else {
-        builder.body(Body::from(content)).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    fn is_hidden(&self, path: &str) -> bool {
+        fs_watcher::is_internal(path)
     }
 }
 
-/// Handle PUT request
-async fn handle_put(
-    State(_state): State<AppState>,
-    Path(path): Path<String>,
-    _user: UserInfo,
-    body: Body,
-) -> Result<Response<Body>, StatusCode> {
-    let full_path = std::path::Path::new("./data").join(&path);
+async fn webdav_handler(
+    State(state): State<AppState>,
+    user: UserInfo,
+    OriginalUri(uri): OriginalUri,
+    mut request: Request,
+) -> Response {
+    // The WebDAV server resolves hrefs against the full path
+    *request.uri_mut() = uri;
 
-    // Create parent directories
-    if let Some(parent) = full_path.parent() {
-        tokio::fs::create_dir_all(parent).await.ok();
-    }
+    let session = request.extensions().get::<Session>().cloned();
+    let server = DavServer::new(state.db_pool.clone(), DATA_DIR, MOUNT_PATH)
+        .with_hooks(Arc::new(AppHooks { state, session }));
+    let user = DavUser {
+        id: user.id,
+        username: user.username,
+    };
+    server.handle(&user, request).await
+}
 
-    let bytes = axum::body::to_bytes(body, 100 * 1024 * 1024)
-        .await
-        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+/// Authenticate WebDAV requests with Basic or Bearer credentials
+pub async fn auth_middleware(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let authenticated = match token_secret(req.headers()) {
+        Some((username, secret)) => {
+            let peer = req
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip());
+            let client_ip = tokens::client_ip(req.headers(), peer);
+            crate::api::api_tokens::authenticate(&state.db_pool, &secret, client_ip)
+                .await
+                // The username must match so a token cannot be used for someone else's drive
+                .and_then(|(user_info, grant)| match username {
+                    Some(username) if username != user_info.username => {
+                        Err(StatusCode::UNAUTHORIZED)
+                    }
+                    _ => Ok((user_info, Some(grant))),
+                })
+                .map(|(user_info, grant)| {
+                    let session = grant
+                        .as_ref()
+                        .map(|grant| Session::for_api_token(&grant.token_id));
+                    (user_info, grant, session)
+                })
+        }
+        None => authenticate(&state, req.headers())
+            .await
+            .map(|(user_info, session)| (user_info, None, session))
+            .ok_or(StatusCode::UNAUTHORIZED),
+    };
 
-    let existed = full_path.exists();
+    match authenticated {
+        Ok((user_info, grant, session)) => {
+            if let Some(session) = session {
+                req.extensions_mut().insert(session);
+            }
+            if let Some(grant) = grant {
+                let path = req
+                    .extensions()
+                    .get::<OriginalUri>()
+                    .map(|uri| uri.path().to_string())
+                    .unwrap_or_else(|| req.uri().path().to_string());
+                if let Some(missing) = token_refusal(&grant, req.method(), &path, req.headers()) {
+                    crate::rbac::log_denial(
+                        &state.db_pool,
+                        &user_info,
+                        req.method().as_str(),
+                        &path,
+                        &missing,
+                    )
+                    .await;
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+                req.extensions_mut().insert(grant);
+            }
+            req.extensions_mut().insert(User(user_info));
+            next.run(req).await
+        }
+        Err(StatusCode::UNAUTHORIZED) => (
+            StatusCode::UNAUTHORIZED,
+            [(
+                header::WWW_AUTHENTICATE,
+                "Basic realm=\"SyncSpace\", charset=\"UTF-8\"",
+            )],
+        )
+            .into_response(),
+        Err(status) => status.into_response(),
+    }
+}
 
-    tokio::fs::write(&full_path, &bytes)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    Ok(Response::builder()
-        .status(if existed {
-            StatusCode::NO_CONTENT
-        } else {
-            StatusCode::CREATED
-        })
-        .body(Body::empty())
-        .unwrap())
+/// The API token in the credentials, with the username it came with for
+/// Basic authentication
+fn token_secret(headers: &HeaderMap) -> Option<(Option<String>, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return token.starts_with("ssk_").then(|| (None, token.to_string()));
+    }
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    password
+        .starts_with("ssk_")
+        .then(|| (Some(username.to_string()), password.to_string()))
 }
 
-/// Handle DELETE request
-async fn handle_delete(
-    State(_state): State<AppState>,
-    Path(path): Path<String>,
-    _user: UserInfo,
-) -> Result<StatusCode, StatusCode> {
-    let full_path = std::path::Path::new("./data").join(&path);
+/// What a token request lacks: the scope of its method, or a path inside
+/// the token's folders for itself and its `Destination`
+fn token_refusal(
+    grant: &TokenGrant,
+    method: &Method,
+    path: &str,
+    headers: &HeaderMap,
+) -> Option<String> {
+    let scope = match method.as_str() {
+        "GET" | "HEAD" | "OPTIONS" | "PROPFIND" => "files:read",
+        _ => "files:write",
+    };
+    if !grant.allows_scope(scope) {
+        return Some(format!("scope {}", scope));
+    }
+    if !grant.is_path_limited() {
+        return None;
+    }
 
-    if !full_path.exists() {
-        return Err(StatusCode::NOT_FOUND);
+    let mut urls = vec![path.to_string()];
+    if let Some(destination) = headers.get("Destination").and_then(|v| v.to_str().ok()) {
+        let destination = match destination.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+            None => destination,
+        };
+        urls.push(
+            destination
+                .split(['?', '#'])
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+        );
     }
+    for url in urls {
+        let file_path = tokens::url_file_path(&format!("{}/{{*path}}", MOUNT_PATH), &url)
+            .filter(|_| url.starts_with(MOUNT_PATH));
+        match file_path {
+            Some(file_path) if grant.allows_path(&file_path) => {}
+            file_path => return Some(format!("path {}", file_path.unwrap_or_default())),
+        }
+    }
+    None
+}
 
-    if full_path.is_dir() {
-        tokio::fs::remove_dir_all(&full_path)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    } else {
-        tokio::fs::remove_file(&full_path)
+/// The user behind JWT or password credentials, with the session of a JWT
+async fn authenticate(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Option<(UserInfo, Option<Session>)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        let user_info = crate::auth::validate_token_against_db(&state.db_pool, token)
             .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            .ok()?;
+        let claims = crate::auth::verify_token(token).ok()?;
+        return Some((user_info, Some(Session::for_access_token(token, &claims))));
     }
 
-    Ok(StatusCode::NO_CONTENT)
-}
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+
+    let key = cache_key(username, password);
+    if let Some(user_info) = cached_login(&key) {
+        return Some((user_info, None));
+    }
+    let user = crate::auth::verify_password(&state.db_pool, username, password)
+        .await
+        .ok()?;
+    if user.totp_enabled {
+        tracing::debug!(
+            "WebDAV password login refused for '{}': two-factor authentication is enabled",
+            username
+        );
+        return None;
+    }
 
-/// WebDAV root OPTIONS handler
-async fn webdav_options() -> Response<Body> {
-    let mut builder = Response::builder().status(StatusCode::OK);
+    let user_info = user_info(user);
+    remember_login(key, user_info.clone());
+    Some((user_info, None))
+}
 
-    for (name, value) in webdav::get_dav_headers() {
-        builder = builder.header(name, value);
+fn user_info(user: crate::database::User) -> UserInfo {
+    UserInfo {
+        id: user.id,
+        username: user.username,
+        totp_enabled: user.totp_enabled,
+        role: user.role,
+        is_admin: user.is_admin,
     }
+}
 
-    builder.body(Body::empty()).unwrap()
+fn credential_cache() -> &'static CredentialCache {
+    static CACHE: OnceLock<CredentialCache> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
 }
 
-pub fn router() -> Router<AppState> {
-    Router::new()
-        .route("/webdav", any(webdav_options))
-        .route("/webdav/{*path}", any(webdav_handler))
+fn cache_key(username: &str, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(username.as_bytes());
+    hasher.update([0]);
+    hasher.update(password.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+fn cached_login(key: &str) -> Option<UserInfo> {
+    let cache = credential_cache().lock().ok()?;
+    cache
+        .get(key)
+        .filter(|(_, at)| at.elapsed() < CREDENTIAL_CACHE_TTL)
+        .map(|(user_info, _)| user_info.clone())
+}
+
+fn remember_login(key: String, user_info: UserInfo) {
+    if let Ok(mut cache) = credential_cache().lock() {
+        cache.retain(|_, (_, at)| at.elapsed() < CREDENTIAL_CACHE_TTL);
+        if cache.len() < CREDENTIAL_CACHE_MAX {
+            cache.insert(key, (user_info, Instant::now()));
+        }
+    }
 }
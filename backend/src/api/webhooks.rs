@@ -15,7 +15,7 @@ use serde_json::json;
 use sqlx::FromRow;
 use uuid::Uuid;
 
-use crate::{auth::UserInfo, AppState};
+use crate::{auth::UserInfo, secrets, AppState};
 
 // ============================================================================
 // DATA STRUCTURES
@@ -98,7 +98,7 @@ impl From<Webhook> for WebhookResponse {
             name: w.name,
             url: w.url,
             events,
-            has_secret: w.secret.is_some(),
+            has_secret: w.secret.as_deref().is_some_and(|s| !s.is_empty()),
             is_active: w.is_active,
             created_at: w.created_at,
             last_triggered_at: w.last_triggered_at,
@@ -124,6 +124,10 @@ pub const WEBHOOK_EVENTS: &[(&str, &str)] = &[
     ("backup.started", "Backup started"),
     ("backup.completed", "Backup completed"),
     ("backup.failed", "Backup failed"),
+    ("notification.share_received", "File shared with you"),
+    ("notification.comment_mention", "Mentioned in a comment"),
+    ("notification.quota_alert", "Quota alert"),
+    ("notification.job_failed", "Background job failed"),
 ];
 
 // ============================================================================
@@ -237,6 +241,7 @@ async fn create_webhook(
     let id = Uuid::new_v4().to_string();
     let events_json = serde_json::to_string(&req.events).unwrap_or_default();
     let now = Utc::now();
+    let secret = seal_secret(&id, req.secret.as_deref())?;
 
     sqlx::query(
         r#"
@@ -249,7 +254,7 @@ async fn create_webhook(
     .bind(&req.name)
     .bind(&req.url)
     .bind(&events_json)
-    .bind(&req.secret)
+    .bind(&secret)
     .bind(now)
     .execute(&state.db_pool)
     .await
@@ -264,7 +269,7 @@ async fn create_webhook(
         name: req.name,
         url: req.url,
         events: events_json,
-        secret: req.secret,
+        secret,
         is_active: true,
         created_at: now,
         last_triggered_at: None,
@@ -323,7 +328,7 @@ async fn update_webhook(
 
     // Handle secret: if provided update it, otherwise keep existing
     let secret = if req.secret.is_some() {
-        req.secret
+        seal_secret(&id, req.secret.as_deref())?
     } else {
         existing.secret
     };
@@ -442,7 +447,13 @@ async fn test_webhook(
         .header("X-Webhook-Delivery", Uuid::new_v4().to_string());
 
     // Add HMAC signature if secret is configured
-    if let Some(ref secret) = webhook.secret {
+    let secret = signing_secret(&state, &webhook, "webhook test delivery")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to read secret of webhook {}: {}", webhook.id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if let Some(ref secret) = secret {
         use hmac::{Hmac, Mac};
         use sha2::Sha256;
 
@@ -605,6 +616,37 @@ async fn reset_failure_count(
     Ok(StatusCode::NO_CONTENT)
 }
 
+/// Seal a signing secret for storage with webhook `id`
+fn seal_secret(id: &str, secret: Option<&str>) -> Result<Option<String>, StatusCode> {
+    secret
+        .map(|secret| secrets::seal(&secrets::WEBHOOK_SECRET, id, secret))
+        .transpose()
+        .map_err(|e| {
+            tracing::error!("Failed to seal webhook secret: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+/// Plaintext signing secret of `webhook`, if it has one
+async fn signing_secret(
+    state: &AppState,
+    webhook: &Webhook,
+    purpose: &str,
+) -> Result<Option<String>, secrets::SecretError> {
+    match webhook.secret.as_deref() {
+        Some(stored) if !stored.is_empty() => secrets::reveal(
+            &state.db_pool,
+            &secrets::WEBHOOK_SECRET,
+            &webhook.id,
+            stored,
+            purpose,
+        )
+        .await
+        .map(Some),
+        _ => Ok(None),
+    }
+}
+
 // ============================================================================
 // WEBHOOK DISPATCHER (for use by other modules)
 // ============================================================================
@@ -694,8 +736,16 @@ async fn send_webhook_delivery(
         .header("X-Webhook-Event", event_type)
         .header("X-Webhook-Delivery", &delivery_id);
 
-    // Add HMAC signature if secret is configured
-    if let Some(ref secret) = webhook.secret {
+    // Add HMAC signature if secret is configured; never send unsigned
+    // deliveries for a webhook that has a secret
+    let secret = match signing_secret(state, webhook, "webhook delivery").await {
+        Ok(secret) => secret,
+        Err(e) => {
+            tracing::error!("Failed to read secret of webhook {}: {}", webhook.id, e);
+            return;
+        }
+    };
+    if let Some(ref secret) = secret {
         use hmac::{Hmac, Mac};
         use sha2::Sha256;
 
@@ -0,0 +1,73 @@
+//! WebSocket endpoint
+//!
+//! Connections are authenticated before the upgrade. Browsers cannot set
+//! headers on WebSocket requests, so besides an `Authorization: Bearer`
+//! header the token may be passed as the `token` query parameter: a JWT,
+//! whose expiry closes the connection, or a personal API token (`ssk_...`),
+//! which limits the events to its scopes and path prefixes. What each
+//! connection receives is decided by `crate::websocket::Session`.
+
+use axum::{
+    Extension,
+    extract::{ConnectInfo, Query, State, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode, header},
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
+use crate::AppState;
+use crate::rbac::tokens;
+use crate::websocket::{self, Session};
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectQuery {
+    token: Option<String>,
+}
+
+/// GET /api/ws - Upgrade to the event stream of the authenticated user
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(query): Query<ConnectQuery>,
+    headers: HeaderMap,
+    peer: Option<Extension<ConnectInfo<SocketAddr>>>,
+) -> Result<Response, StatusCode> {
+    let hub = websocket::hub()
+        .cloned()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string)
+        .or(query.token)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let session = if token.starts_with("ssk_") {
+        let peer = peer.map(|Extension(ConnectInfo(addr))| addr.ip());
+        let client_ip = tokens::client_ip(&headers, peer);
+        let (user_info, grant) =
+            crate::api::api_tokens::authenticate(&state.db_pool, &token, client_ip).await?;
+        Session::new(state.db_pool.clone(), &user_info).with_grant(grant)
+    } else {
+        let claims = crate::auth::verify_token(&token).map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let user_info = crate::auth::validate_token_against_db(&state.db_pool, &token)
+            .await
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let session = Session::new(state.db_pool.clone(), &user_info);
+        match DateTime::<Utc>::from_timestamp(claims.exp as i64, 0) {
+            Some(expires_at) => session.with_expiry(expires_at),
+            None => session,
+        }
+    };
+
+    let connections = state.ws_connections.clone();
+    Ok(ws.on_upgrade(move |socket| async move {
+        connections.fetch_add(1, Ordering::Relaxed);
+        websocket::handle_socket(socket, hub, session).await;
+        connections.fetch_sub(1, Ordering::Relaxed);
+    }))
+}
@@ -2,8 +2,9 @@
 // Provides endpoints for creating, managing, and executing workflow rules
 
 use axum::{
+    body::Bytes,
     extract::{Path, Query, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     response::IntoResponse,
     routing::{delete, get, post, put},
     Json, Router,
@@ -13,6 +14,8 @@ use serde_json::json;
 use sqlx::FromRow;
 
 use crate::auth::UserInfo;
+use crate::workflow::inbound::{self, Rejection};
+use crate::workflow::plugins::{self, LimitSettings, RegisterError};
 use crate::AppState;
 
 // ============ Models ============
@@ -33,6 +36,9 @@ pub struct WorkflowRule {
     pub created_by: String,
     pub created_at: String,
     pub updated_at: String,
+    pub max_concurrency: i64,
+    pub max_retries: i64,
+    pub last_triggered_at: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -49,6 +55,19 @@ pub struct WorkflowExecution {
     pub error_message: Option<String>,
     pub execution_time_ms: Option<i64>,
     pub created_at: String,
+    pub file_path: Option<String>,
+    pub job_id: Option<String>,
+    pub attempts: i64,
+    pub started_at: Option<String>,
+    pub completed_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct WorkflowExecutionLog {
+    pub id: i64,
+    pub level: String,
+    pub message: String,
+    pub created_at: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -67,6 +86,9 @@ pub struct WorkflowRuleWithStats {
     pub created_by: String,
     pub created_at: String,
     pub updated_at: String,
+    pub max_concurrency: i64,
+    pub max_retries: i64,
+    pub last_triggered_at: Option<String>,
     pub execution_count: i64,
     pub success_count: i64,
     pub failed_count: i64,
@@ -87,6 +109,8 @@ pub struct CreateWorkflowRuleRequest {
     pub action_config: serde_json::Value,
     pub is_active: Option<bool>,
     pub priority: Option<i32>,
+    pub max_concurrency: Option<i64>,
+    pub max_retries: Option<i64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -98,6 +122,8 @@ pub struct UpdateWorkflowRuleRequest {
     pub action_config: Option<serde_json::Value>,
     pub is_active: Option<bool>,
     pub priority: Option<i32>,
+    pub max_concurrency: Option<i64>,
+    pub max_retries: Option<i64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -121,6 +147,24 @@ pub struct ManualExecuteRequest {
     pub trigger_context: Option<serde_json::Value>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RegisterPluginRequest {
+    pub name: String,
+    pub description: Option<String>,
+    /// Base64-encoded WebAssembly module
+    pub wasm: String,
+    #[serde(flatten)]
+    pub limits: LimitSettings,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdatePluginRequest {
+    pub description: Option<String>,
+    pub is_active: Option<bool>,
+    #[serde(flatten)]
+    pub limits: LimitSettings,
+}
+
 // ============ Router Setup ============
 
 pub fn router() -> Router<AppState> {
@@ -133,9 +177,23 @@ pub fn router() -> Router<AppState> {
         .route("/workflows/{id}/toggle", post(toggle_workflow_rule))
         .route("/workflows/{id}/execute", post(execute_workflow_manually))
         .route("/workflows/{id}/executions", get(get_execution_history))
+        .route("/workflows/{id}/webhook", get(get_webhook_endpoint))
+        .route("/workflows/{id}/webhook", post(rotate_webhook_endpoint))
+        .route("/workflows/{id}/webhook", delete(delete_webhook_endpoint))
         .route("/workflows/executions/recent", get(get_recent_executions))
+        .route("/workflows/executions/{id}", get(get_execution))
         .route("/workflows/trigger-types", get(list_trigger_types))
         .route("/workflows/action-types", get(list_action_types))
+        .route("/workflows/plugins", get(list_plugins))
+        .route("/workflows/plugins", post(register_plugin))
+        .route("/workflows/plugins/{id}", put(update_plugin))
+        .route("/workflows/plugins/{id}", delete(delete_plugin))
+}
+
+/// Inbound webhooks; callers authenticate with the endpoint's signature
+/// instead of a session
+pub fn public_router() -> Router<AppState> {
+    Router::new().route("/hooks/workflows/{id}", post(receive_webhook))
 }
 
 // ============ API Handlers ============
@@ -153,26 +211,35 @@ async fn create_workflow_rule(
     if !is_valid_action_type(&req.action_type) {
         return Err(StatusCode::BAD_REQUEST);
     }
+    let condition_config = req.condition_config.unwrap_or(json!({}));
+    validate_configs(
+        Some(&req.trigger_config),
+        Some(&condition_config),
+        &req.action_type,
+        Some(&req.action_config),
+    )?;
 
     // Serialize JSON configs
     let trigger_config =
         serde_json::to_string(&req.trigger_config).map_err(|_| StatusCode::BAD_REQUEST)?;
-    let condition_config = serde_json::to_string(&req.condition_config.unwrap_or(json!({})))
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let condition_config =
+        serde_json::to_string(&condition_config).map_err(|_| StatusCode::BAD_REQUEST)?;
     let action_config =
         serde_json::to_string(&req.action_config).map_err(|_| StatusCode::BAD_REQUEST)?;
 
     let is_active = req.is_active.unwrap_or(true);
     let priority = req.priority.unwrap_or(100);
+    let max_concurrency = clamp_concurrency(req.max_concurrency.unwrap_or(1));
+    let max_retries = clamp_retries(req.max_retries.unwrap_or(3));
 
     let rule = sqlx::query_as::<_, WorkflowRule>(
         r#"
         INSERT INTO workflow_rules (
             name, display_name, description, trigger_type, trigger_config,
             condition_config, action_type, action_config, is_active, priority,
-            created_by
+            created_by, max_concurrency, max_retries
         )
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         RETURNING *
         "#,
     )
@@ -187,6 +254,8 @@ async fn create_workflow_rule(
     .bind(is_active)
     .bind(priority)
     .bind(&id)
+    .bind(max_concurrency)
+    .bind(max_retries)
     .fetch_one(&state.db_pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -312,6 +381,13 @@ async fn update_workflow_rule(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .ok_or(StatusCode::NOT_FOUND)?;
 
+    validate_configs(
+        req.trigger_config.as_ref(),
+        req.condition_config.as_ref(),
+        &existing.action_type,
+        req.action_config.as_ref(),
+    )?;
+
     // Build update query dynamically
     let mut updates = vec![];
     let mut params: Vec<String> = vec![];
@@ -349,6 +425,14 @@ async fn update_workflow_rule(
         updates.push("priority = ?");
         params.push(priority.to_string());
     }
+    if let Some(max_concurrency) = req.max_concurrency {
+        updates.push("max_concurrency = ?");
+        params.push(clamp_concurrency(max_concurrency).to_string());
+    }
+    if let Some(max_retries) = req.max_retries {
+        updates.push("max_retries = ?");
+        params.push(clamp_retries(max_retries).to_string());
+    }
 
     if updates.is_empty() {
         return Ok(Json(existing));
@@ -390,6 +474,9 @@ async fn delete_workflow_rule(
     if result.rows_affected() == 0 {
         return Err(StatusCode::NOT_FOUND);
     }
+    inbound::remove(&state.db_pool, &id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     Ok(Json(
         json!({"success": true, "message": "Workflow rule deleted"}),
@@ -421,47 +508,33 @@ async fn execute_workflow_manually(
     Path(rule_id): Path<String>,
     Json(req): Json<ManualExecuteRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Get the workflow rule
-    let rule = sqlx::query_as::<_, WorkflowRule>("SELECT * FROM workflow_rules WHERE id = ?")
-        .bind(&rule_id)
-        .fetch_optional(&state.db_pool)
+    let file_path =
+        crate::security::validate_file_path(&req.file_path).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let engine = crate::workflow::engine().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    // Queue the rule's actions; the job workers run them
+    let execution_id = engine
+        .execute_manually(
+            &rule_id,
+            &file_path,
+            &id,
+            req.trigger_context.unwrap_or(json!({})),
+        )
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .ok_or(StatusCode::NOT_FOUND)?;
 
-    // Create trigger event
-    let trigger_event = json!({
-        "event_type": "manual_execution",
-        "file_path": req.file_path,
-        "timestamp": chrono::Utc::now().to_rfc3339(),
-        "context": req.trigger_context.unwrap_or(json!({}))
-    });
-
-    // For now, just log the execution (actual workflow engine would evaluate and execute)
-    let execution = sqlx::query_as::<_, WorkflowExecution>(
-        r#"
-        INSERT INTO workflow_executions (
-            rule_id, triggered_by, trigger_event, condition_met,
-            action_executed, status
-        )
-        VALUES (?, ?, ?, ?, ?, ?)
-        RETURNING *
-        "#,
-    )
-    .bind(&rule.id)
-    .bind(&id)
-    .bind(serde_json::to_string(&trigger_event).unwrap())
-    .bind(true) // Assume condition met for manual execution
-    .bind(false) // Action not yet executed (would be done by workflow engine)
-    .bind("success")
-    .fetch_one(&state.db_pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let execution =
+        sqlx::query_as::<_, WorkflowExecution>("SELECT * FROM workflow_executions WHERE id = ?")
+            .bind(&execution_id)
+            .fetch_one(&state.db_pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     Ok(Json(json!({
         "success": true,
         "execution": execution,
-        "message": "Workflow execution started"
+        "message": "Workflow execution queued"
     })))
 }
 
@@ -534,6 +607,163 @@ async fn get_recent_executions(
     Ok(Json(executions))
 }
 
+/// Get one execution with its log
+async fn get_execution(
+    State(state): State<AppState>,
+    UserInfo { .. }: UserInfo,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let execution =
+        sqlx::query_as::<_, WorkflowExecution>("SELECT * FROM workflow_executions WHERE id = ?")
+            .bind(&id)
+            .fetch_optional(&state.db_pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+
+    let logs = sqlx::query_as::<_, WorkflowExecutionLog>(
+        "SELECT id, level, message, created_at FROM workflow_execution_logs WHERE execution_id = ? ORDER BY id",
+    )
+    .bind(&id)
+    .fetch_all(&state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "execution": execution,
+        "logs": logs
+    })))
+}
+
+/// A webhook rule the user may manage: their own, or any rule for admins
+async fn webhook_rule(
+    state: &AppState,
+    user: &UserInfo,
+    id: &str,
+) -> Result<WorkflowRule, StatusCode> {
+    let rule = sqlx::query_as::<_, WorkflowRule>("SELECT * FROM workflow_rules WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if rule.created_by != user.id && !user.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if rule.trigger_type != "webhook" {
+        return Err(StatusCode::CONFLICT);
+    }
+    Ok(rule)
+}
+
+/// Show the inbound webhook endpoint of a rule; the secret is only shown
+/// when it is created
+async fn get_webhook_endpoint(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    webhook_rule(&state, &user, &id).await?;
+    let endpoint = inbound::endpoint(&state.db_pool, &id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(json!({
+        "endpoint": endpoint,
+        "url": crate::mail::link(&endpoint.path()),
+    })))
+}
+
+/// Create the inbound webhook endpoint of a rule, or replace it with a new
+/// URL and secret
+async fn rotate_webhook_endpoint(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    webhook_rule(&state, &user, &id).await?;
+    let endpoint = inbound::rotate(&state.db_pool, &id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "endpoint": endpoint,
+            "url": crate::mail::link(&endpoint.path()),
+            "secret": endpoint.secret,
+            "signature_header": "X-Webhook-Signature",
+            "timestamp_header": "X-Webhook-Timestamp",
+        })),
+    ))
+}
+
+/// Remove the inbound webhook endpoint of a rule
+async fn delete_webhook_endpoint(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    webhook_rule(&state, &user, &id).await?;
+    let removed = inbound::remove(&state.db_pool, &id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !removed {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(json!({"success": true, "message": "Webhook endpoint removed"})))
+}
+
+/// Receive a signed call to an inbound webhook and queue the rule
+async fn receive_webhook(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Checked first, so that a call is not recorded without being run
+    let engine = crate::workflow::engine().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let (rule_id, event) = inbound::receive(
+        &state.db_pool,
+        &id,
+        header(inbound::TIMESTAMP_HEADER),
+        header(inbound::SIGNATURE_HEADER),
+        &body,
+        chrono::Utc::now(),
+    )
+    .await
+    .map_err(|e| match e {
+        Rejection::UnknownEndpoint => StatusCode::NOT_FOUND,
+        Rejection::Unauthorized => StatusCode::UNAUTHORIZED,
+        Rejection::Replayed => StatusCode::CONFLICT,
+        Rejection::InvalidPayload(message) => {
+            tracing::debug!("Rejected call to workflow webhook {}: {}", id, message);
+            StatusCode::BAD_REQUEST
+        }
+        Rejection::Secret(e) => {
+            tracing::error!("Cannot read secret of workflow webhook {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        Rejection::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
+
+    let execution_id = engine
+        .dispatch_webhook(&rule_id, event)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "queued": execution_id.is_some(),
+            "execution_id": execution_id,
+        })),
+    ))
+}
+
 /// List available trigger types
 async fn list_trigger_types(UserInfo { .. }: UserInfo) -> Result<impl IntoResponse, StatusCode> {
     Ok(Json(json!({
@@ -546,7 +776,7 @@ async fn list_trigger_types(UserInfo { .. }: UserInfo) -> Result<impl IntoRespon
             {"value": "file_tag", "label": "File Tag", "description": "Triggered when a file is tagged"},
             {"value": "file_version", "label": "File Version", "description": "Triggered when a new file version is created"},
             {"value": "scheduled", "label": "Scheduled", "description": "Triggered on a schedule (cron expression)"},
-            {"value": "webhook", "label": "Webhook", "description": "Triggered by external webhook"},
+            {"value": "webhook", "label": "Webhook", "description": "Triggered by a signed call to the rule's webhook endpoint"},
             {"value": "manual", "label": "Manual", "description": "Triggered manually by user"}
         ]
     })))
@@ -565,11 +795,99 @@ async fn list_action_types(UserInfo { .. }: UserInfo) -> Result<impl IntoRespons
             {"value": "delete_file", "label": "Delete File", "description": "Delete the file"},
             {"value": "send_webhook", "label": "Send Webhook", "description": "Send HTTP webhook to external service"},
             {"value": "send_email", "label": "Send Email", "description": "Send email notification"},
-            {"value": "run_script", "label": "Run Script", "description": "Execute custom script"}
+            {"value": "run_script", "label": "Run Script", "description": "Run a sandboxed WebAssembly plugin"}
         ]
     })))
 }
 
+/// List registered plugins
+async fn list_plugins(
+    State(state): State<AppState>,
+    UserInfo { .. }: UserInfo,
+) -> Result<impl IntoResponse, StatusCode> {
+    let plugins = plugins::list(&state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(json!({ "plugins": plugins })))
+}
+
+/// Register a plugin, or replace the module of one with the same name (admin only)
+async fn register_plugin(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Json(req): Json<RegisterPluginRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !user.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    use base64::Engine;
+    let wasm = base64::engine::general_purpose::STANDARD
+        .decode(req.wasm.trim())
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let plugin = plugins::register(
+        &state.db_pool,
+        &req.name,
+        req.description.as_deref(),
+        &wasm,
+        &req.limits,
+        &user.id,
+    )
+    .await
+    .map_err(|e| match e {
+        RegisterError::Invalid(message) => {
+            tracing::debug!("Rejected workflow plugin {}: {}", req.name, message);
+            StatusCode::BAD_REQUEST
+        }
+        RegisterError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
+
+    Ok((StatusCode::CREATED, Json(plugin)))
+}
+
+/// Change a plugin's description, limits or active status (admin only)
+async fn update_plugin(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(id): Path<String>,
+    Json(req): Json<UpdatePluginRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !user.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let plugin = plugins::update(
+        &state.db_pool,
+        &id,
+        req.description.as_deref(),
+        req.is_active,
+        &req.limits,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(plugin))
+}
+
+/// Remove a plugin (admin only); rules that use it fail until it is registered again
+async fn delete_plugin(
+    State(state): State<AppState>,
+    user: UserInfo,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !user.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let deleted = plugins::delete(&state.db_pool, &id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(json!({"success": true, "message": "Plugin deleted"})))
+}
+
 // ============ Helper Functions ============
 
 fn is_valid_trigger_type(trigger: &str) -> bool {
@@ -588,6 +906,39 @@ fn is_valid_trigger_type(trigger: &str) -> bool {
     )
 }
 
+/// Reject configs the workflow engine could not evaluate or run
+fn validate_configs(
+    trigger_config: Option<&serde_json::Value>,
+    condition_config: Option<&serde_json::Value>,
+    action_type: &str,
+    action_config: Option<&serde_json::Value>,
+) -> Result<(), StatusCode> {
+    use crate::workflow::{conditions, parse_actions};
+
+    let mut result = Ok(());
+    if let Some(config) = trigger_config {
+        result = result.and(conditions::validate(config, true));
+    }
+    if let Some(config) = condition_config {
+        result = result.and(conditions::validate(config, false));
+    }
+    if let Some(config) = action_config {
+        result = result.and(parse_actions(action_type, config).map(|_| ()));
+    }
+    result.map_err(|e| {
+        tracing::debug!("Invalid workflow rule: {}", e);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+fn clamp_concurrency(max_concurrency: i64) -> i64 {
+    max_concurrency.clamp(1, 16)
+}
+
+fn clamp_retries(max_retries: i64) -> i64 {
+    max_retries.clamp(0, 10)
+}
+
 fn is_valid_action_type(action: &str) -> bool {
     matches!(
         action,
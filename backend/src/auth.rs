@@ -283,6 +283,11 @@ where
     type Rejection = (StatusCode, &'static str);
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Prefer the principal set by the auth middleware (JWT or API token)
+        if let Some(user) = parts.extensions.get::<User>() {
+            return Ok(user.clone());
+        }
+
         // Extract Authorization header
         let auth_header = parts
             .headers
@@ -399,6 +404,51 @@ impl std::ops::Deref for User {
     }
 }
 
+/// The credential a request was authenticated with, set by the auth
+/// middleware. Unlocked encryption keys belong to it rather than to the
+/// user, so they end with it (see `encryption::keyring`).
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// SHA-256 of the access token, or `token:<id>` for personal API tokens
+    pub id: String,
+    /// When the credential stops being accepted, `None` if it never expires
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    pub fn for_access_token(token: &str, claims: &Claims) -> Self {
+        use sha2::{Digest, Sha256};
+        Self {
+            id: hex::encode(Sha256::digest(token.as_bytes())),
+            expires_at: DateTime::from_timestamp(claims.exp as i64, 0),
+        }
+    }
+
+    /// API tokens are checked against their expiry on every request, so the
+    /// session only ends when the token is revoked or deleted
+    pub fn for_api_token(token_id: &str) -> Self {
+        Self {
+            id: format!("token:{}", token_id),
+            expires_at: None,
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for Session
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Session>()
+            .cloned()
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Enable2FARequest {
     pub totp_code: String,
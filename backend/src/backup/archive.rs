@@ -0,0 +1,186 @@
+//! tar+zstd archives
+//!
+//! Entries are streamed through SHA-256 on the way in and on the way out, so
+//! neither side ever holds more than a buffer of a file in memory.
+
+use std::io::{self, Read, Write};
+
+use sha2::{Digest, Sha256};
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Passes reads through while counting and hashing them
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Bytes read so far and their hex SHA-256
+    pub fn finish(self) -> (u64, String) {
+        (self.len, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Passes writes through while counting and hashing them
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// The inner writer, bytes written and their hex SHA-256
+    pub fn finish(self) -> (W, u64, String) {
+        (self.inner, self.len, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct ArchiveWriter<W: Write> {
+    builder: tar::Builder<zstd::Encoder<'static, W>>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(writer: W) -> io::Result<Self> {
+        Ok(Self {
+            builder: tar::Builder::new(zstd::Encoder::new(writer, ZSTD_LEVEL)?),
+        })
+    }
+
+    /// Append exactly `size` bytes of `reader` as `path`; returns their hex
+    /// SHA-256. Fails if the reader ends early, e.g. because the file was
+    /// truncated while being archived.
+    pub fn append(
+        &mut self,
+        path: &str,
+        size: u64,
+        mtime: u64,
+        reader: impl Read,
+    ) -> io::Result<String> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+
+        let mut hashing = HashingReader::new(reader.take(size));
+        self.builder.append_data(&mut header, path, &mut hashing)?;
+        let (len, sha256) = hashing.finish();
+        if len != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "{} shrank from {} to {} bytes while archiving",
+                    path, size, len
+                ),
+            ));
+        }
+        Ok(sha256)
+    }
+
+    pub fn append_bytes(&mut self, path: &str, data: &[u8]) -> io::Result<String> {
+        let mtime = chrono::Utc::now().timestamp().max(0) as u64;
+        self.append(path, data.len() as u64, mtime, data)
+    }
+
+    /// Write the tar trailer and the end of the zstd frame
+    pub fn finish(self) -> io::Result<W> {
+        self.builder.into_inner()?.finish()
+    }
+}
+
+/// Call `visit` with the path, size and content of every regular entry of
+/// the archive, in archive order
+pub fn read_entries<R: Read>(
+    reader: R,
+    mut visit: impl FnMut(&str, u64, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut archive = tar::Archive::new(zstd::Decoder::new(reader)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = entry.path()?.to_str().map(str::to_string).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "archive path is not UTF-8")
+        })?;
+        let size = entry.header().size()?;
+        visit(&path, size, &mut entry)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_short_reads() {
+        let mut writer = ArchiveWriter::new(Vec::new()).unwrap();
+        let long_path = format!("data/{}/file.txt", "nested".repeat(30));
+        let first = writer.append_bytes("data/a.txt", b"hello").unwrap();
+        writer.append_bytes(&long_path, b"world").unwrap();
+        assert!(writer.append("data/short", 10, 0, &b"abc"[..]).is_err());
+        assert_eq!(first, hex::encode(Sha256::digest(b"hello")));
+
+        let mut writer = ArchiveWriter::new(Vec::new()).unwrap();
+        writer.append_bytes("data/a.txt", b"hello").unwrap();
+        writer.append_bytes(&long_path, b"world").unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let mut seen = Vec::new();
+        read_entries(&bytes[..], |path, size, content| {
+            let mut text = String::new();
+            content.read_to_string(&mut text)?;
+            seen.push((path.to_string(), size, text));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            seen,
+            [
+                ("data/a.txt".to_string(), 5, "hello".to_string()),
+                (long_path, 5, "world".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Backup chains
+//!
+//! An incremental backup is taken against the latest completed backup of
+//! its schedule (manual backups chain among themselves), a differential one
+//! against the full backup that chain started with. A chain is closed after
+//! `full_every` links of its schedule ([`DEFAULT_FULL_EVERY`] without one),
+//! and when there is nothing to chain onto the backup is written as a full
+//! one. `backups.parent_backup_id` and `backups.base_backup_id` record the
+//! links; the manifests inside the archives are what restores rely on.
+
+use sqlx::SqlitePool;
+
+/// Incremental or differential backups after a full one before the next
+pub const DEFAULT_FULL_EVERY: i64 = 6;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Link {
+    pub id: String,
+    pub storage_path: String,
+    /// The full backup the chain starts with, `None` if this is it
+    pub base_backup_id: Option<String>,
+}
+
+impl Link {
+    pub fn base_id(&self) -> &str {
+        self.base_backup_id.as_deref().unwrap_or(&self.id)
+    }
+}
+
+/// The backup to take `backup_id` against, `None` if it has to be a full
+/// backup
+pub async fn parent_for(pool: &SqlitePool, backup_id: &str) -> Result<Option<Link>, sqlx::Error> {
+    let backup: Option<(String, Option<String>, String)> =
+        sqlx::query_as("SELECT backup_type, schedule_id, created_at FROM backups WHERE id = ?")
+            .bind(backup_id)
+            .fetch_optional(pool)
+            .await?;
+    let Some((backup_type, schedule_id, created_at)) = backup else {
+        return Ok(None);
+    };
+    if backup_type != "incremental" && backup_type != "differential" {
+        return Ok(None);
+    }
+
+    let latest: Option<Link> = sqlx::query_as(
+        "SELECT id, storage_path, base_backup_id FROM backups
+         WHERE schedule_id IS ? AND id != ? AND status = 'completed' AND created_at <= ?
+           AND backup_type IN ('full', 'incremental', 'differential')
+         ORDER BY created_at DESC LIMIT 1",
+    )
+    .bind(&schedule_id)
+    .bind(backup_id)
+    .bind(&created_at)
+    .fetch_optional(pool)
+    .await?;
+    let Some(latest) = latest else {
+        return Ok(None);
+    };
+
+    let full_every: Option<i64> = match &schedule_id {
+        Some(schedule_id) => {
+            sqlx::query_scalar("SELECT full_every FROM backup_schedules WHERE id = ?")
+                .bind(schedule_id)
+                .fetch_optional(pool)
+                .await?
+                .flatten()
+        }
+        None => None,
+    };
+    let links: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM backups WHERE base_backup_id = ? AND status = 'completed'",
+    )
+    .bind(latest.base_id())
+    .fetch_one(pool)
+    .await?;
+    if links >= full_every.unwrap_or(DEFAULT_FULL_EVERY).max(0) {
+        return Ok(None);
+    }
+
+    if backup_type == "differential" && latest.base_backup_id.is_some() {
+        return sqlx::query_as(
+            "SELECT id, storage_path, base_backup_id FROM backups
+             WHERE id = ? AND status = 'completed'",
+        )
+        .bind(latest.base_id())
+        .fetch_optional(pool)
+        .await;
+    }
+    Ok(Some(latest))
+}
+
+/// Backups taken against `backup_id`
+pub async fn children(pool: &SqlitePool, backup_id: &str) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT id FROM backups WHERE parent_backup_id = ? ORDER BY created_at")
+        .bind(backup_id)
+        .fetch_all(pool)
+        .await
+}
+
+/// `backup_id` and the links before it, back to the full backup
+pub async fn links(pool: &SqlitePool, backup_id: &str) -> Result<Vec<String>, sqlx::Error> {
+    let mut links = vec![backup_id.to_string()];
+    loop {
+        let parent: Option<String> =
+            sqlx::query_scalar("SELECT parent_backup_id FROM backups WHERE id = ?")
+                .bind(links.last())
+                .fetch_optional(pool)
+                .await?
+                .flatten();
+        match parent {
+            Some(parent) if !links.contains(&parent) => links.push(parent),
+            _ => return Ok(links),
+        }
+    }
+}
+
+/// Delete a backup with its archive and its uploaded copy; callers make sure
+/// no other backup is taken against it. The rows stay if the copy cannot be
+/// deleted, so pruning tries again next time.
+pub async fn delete(pool: &SqlitePool, backup_id: &str) -> anyhow::Result<()> {
+    super::destination::remove(pool, backup_id).await?;
+    let storage_path: Option<String> =
+        sqlx::query_scalar("SELECT storage_path FROM backups WHERE id = ?")
+            .bind(backup_id)
+            .fetch_optional(pool)
+            .await?;
+    if let Some(storage_path) = storage_path {
+        let archive = std::path::Path::new(&storage_path);
+        let _ = tokio::fs::remove_file(archive).await;
+        let _ = tokio::fs::remove_file(super::manifest_path(archive)).await;
+    }
+
+    let mut tx = pool.begin().await?;
+    for sql in [
+        "DELETE FROM backup_files WHERE backup_id = ?",
+        "DELETE FROM backup_verifications WHERE backup_id = ?",
+        "DELETE FROM backups WHERE id = ?",
+    ] {
+        sqlx::query(sql).bind(backup_id).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
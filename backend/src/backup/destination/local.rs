@@ -0,0 +1,251 @@
+//! Local path destination, e.g. a mounted NAS share
+//!
+//! The archive is copied to `<name>.partial` and renamed once the copy,
+//! read back from the destination, matches. A copy interrupted halfway is
+//! continued from the size of the partial file.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow, bail};
+use async_trait::async_trait;
+
+use super::{Checkpoint, Destination};
+use crate::storage::sha256_file;
+
+#[derive(Debug, Clone)]
+pub struct LocalDestination {
+    root: PathBuf,
+    keep_local: bool,
+}
+
+impl LocalDestination {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            keep_local: true,
+        }
+    }
+
+    /// Config: `path`, the directory to store archives in, and `keep_local`
+    pub fn from_config(config: &serde_json::Value) -> Result<Self> {
+        let path = config
+            .get("path")
+            .and_then(|path| path.as_str())
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| anyhow!("local destination requires a path"))?;
+        Ok(Self {
+            root: path.into(),
+            keep_local: config
+                .get("keep_local")
+                .and_then(|keep| keep.as_bool())
+                .unwrap_or(true),
+        })
+    }
+
+    fn target(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("invalid archive name '{}'", name);
+        }
+        Ok(self.root.join(name))
+    }
+
+    /// Copy `source` to `partial`, continuing a previous partial copy
+    fn copy_blocking(source: &Path, partial: &Path) -> Result<u64> {
+        let size = fs::metadata(source)?.len();
+        let mut offset = fs::metadata(partial).map_or(0, |metadata| metadata.len());
+        if offset > size {
+            offset = 0;
+        }
+
+        let mut input = File::open(source)?;
+        input.seek(SeekFrom::Start(offset))?;
+        let mut output = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(partial)?;
+        output.set_len(offset)?;
+        output.seek(SeekFrom::Start(offset))?;
+        let mut writer = BufWriter::new(output);
+        io::copy(&mut BufReader::new(input), &mut writer)?;
+        let output = writer.into_inner().map_err(|e| e.into_error())?;
+        output.sync_all()?;
+        Ok(size)
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl Destination for LocalDestination {
+    fn kind(&self) -> &'static str {
+        "local"
+    }
+
+    fn location(&self, name: &str) -> String {
+        self.root.join(name).display().to_string()
+    }
+
+    fn keep_local(&self) -> bool {
+        self.keep_local
+    }
+
+    async fn probe(&self) -> Result<()> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            fs::create_dir_all(&this.root)?;
+            let probe = this
+                .root
+                .join(format!(".syncspace-probe-{}", uuid::Uuid::new_v4()));
+            File::create(&probe)?.write_all(b"probe")?;
+            fs::remove_file(&probe)?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn upload(
+        &self,
+        name: &str,
+        source: &Path,
+        sha256: &str,
+        _checkpoint: &Checkpoint<'_>,
+    ) -> Result<()> {
+        let target = self.target(name)?;
+        // The destination is the backup directory itself
+        if same_file(source, &target) {
+            let (_, found) = sha256_file(&target).await?;
+            if found != sha256 {
+                bail!("{} does not match its checksum", target.display());
+            }
+            return Ok(());
+        }
+
+        tokio::fs::create_dir_all(&self.root).await?;
+        let partial = crate::backup::suffixed(&target, ".partial");
+        let size = {
+            let (source, partial) = (source.to_path_buf(), partial.clone());
+            tokio::task::spawn_blocking(move || Self::copy_blocking(&source, &partial)).await??
+        };
+
+        // Read back what landed on the destination, not what was sent
+        let (stored_size, stored) = sha256_file(&partial).await?;
+        if stored_size != size || stored != sha256 {
+            let _ = tokio::fs::remove_file(&partial).await;
+            bail!(
+                "copy of {} at {} does not match its checksum",
+                name,
+                self.root.display()
+            );
+        }
+        tokio::fs::rename(&partial, &target).await?;
+        Ok(())
+    }
+
+    async fn download(&self, name: &str, dest: &Path) -> Result<u64> {
+        let target = self.target(name)?;
+        Ok(tokio::fs::copy(&target, dest).await?)
+    }
+
+    async fn remove(&self, name: &str, _checkpoint: &Checkpoint<'_>) -> Result<()> {
+        let target = self.target(name)?;
+        remove_if_exists(&target)?;
+        remove_if_exists(&crate::backup::suffixed(&target, ".partial"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+    use sqlx::SqlitePool;
+
+    #[test]
+    fn test_config_and_names() {
+        assert!(LocalDestination::from_config(&serde_json::json!({ "path": "" })).is_err());
+        let destination =
+            LocalDestination::from_config(&serde_json::json!({ "path": "/mnt/nas" })).unwrap();
+        assert_eq!(destination.location("b1.tar.zst"), "/mnt/nas/b1.tar.zst");
+        assert!(destination.keep_local());
+        let config = serde_json::json!({ "path": "/mnt/nas", "keep_local": false });
+        assert!(!LocalDestination::from_config(&config).unwrap().keep_local());
+
+        assert!(destination.target("b1.tar.zst").is_ok());
+        for name in [
+            "",
+            "../b1.tar.zst",
+            "nightly\\b1.tar.zst",
+            ".syncspace-probe",
+        ] {
+            assert!(destination.target(name).is_err(), "{} was accepted", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_resumes_and_verifies() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let source = dir.path().join("b1.tar.zst");
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i * 13 % 251) as u8).collect();
+        fs::write(&source, &content).unwrap();
+        let sha256 = hex::encode(Sha256::digest(&content));
+        // Checkpoints are only used by multipart uploads
+        let pool = SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        let checkpoint = Checkpoint::new(&pool, "b1", "d1");
+
+        // An earlier copy stopped halfway
+        let mount = dir.path().join("nas");
+        let destination = LocalDestination::new(&mount);
+        destination.probe().await.unwrap();
+        let partial = mount.join("b1.tar.zst.partial");
+        fs::write(&partial, &content[..40_000]).unwrap();
+        destination
+            .upload("b1.tar.zst", &source, &sha256, &checkpoint)
+            .await
+            .unwrap();
+        assert!(!partial.exists());
+        assert_eq!(fs::read(mount.join("b1.tar.zst")).unwrap(), content);
+
+        // A partial copy that does not match the archive is not kept
+        fs::write(&partial, b"something else entirely").unwrap();
+        let copied = destination
+            .upload("b1.tar.zst", &source, &sha256, &checkpoint)
+            .await;
+        assert!(copied.is_err());
+        assert!(!partial.exists());
+
+        // Archives already in the destination are only checked
+        let in_place = LocalDestination::new(dir.path());
+        in_place
+            .upload("b1.tar.zst", &source, &sha256, &checkpoint)
+            .await
+            .unwrap();
+        let wrong = "0".repeat(64);
+        let checked = in_place
+            .upload("b1.tar.zst", &source, &wrong, &checkpoint)
+            .await;
+        assert!(checked.is_err());
+        assert!(source.exists());
+
+        let fetched = dir.path().join("fetched");
+        let size = destination.download("b1.tar.zst", &fetched).await.unwrap();
+        assert_eq!(size, content.len() as u64);
+        destination.remove("b1.tar.zst", &checkpoint).await.unwrap();
+        destination.remove("b1.tar.zst", &checkpoint).await.unwrap();
+        assert!(!mount.join("b1.tar.zst").exists());
+    }
+}
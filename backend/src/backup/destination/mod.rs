@@ -0,0 +1,418 @@
+//! Backup destinations
+//!
+//! A finished backup is uploaded to the destination picked for it: a local
+//! path such as a mounted NAS share ([`local`]), an SFTP server ([`sftp`])
+//! or an S3-compatible bucket ([`s3`]). Uploads stream the archive, resume
+//! where an interrupted attempt stopped and only count as done once the
+//! destination's own copy matches the archive's checksum.
+//!
+//! Remote destinations can drop the local archive once it is uploaded
+//! (`"keep_local": false`); the manifest copy stays, so later links can
+//! still be chained onto it, and archives are downloaded again when a
+//! restore, verification or download needs them. Credentials in a
+//! destination's config are sealed by the secrets vault in
+//! `backup_destinations.secrets` instead of being stored with the rest.
+
+pub mod local;
+pub mod s3;
+pub mod sftp;
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::SqlitePool;
+
+use crate::secrets::{self, BACKUP_DESTINATION_SECRETS};
+
+pub use local::LocalDestination;
+pub use s3::S3Destination;
+pub use sftp::SftpDestination;
+
+/// Config fields that are sealed rather than stored in the config column
+pub const SECRET_FIELDS: &[&str] = &[
+    "password",
+    "private_key",
+    "passphrase",
+    "secret_access_key",
+    "secret_key",
+];
+
+#[async_trait]
+pub trait Destination: Send + Sync {
+    fn kind(&self) -> &'static str;
+
+    /// Where `name` is stored, for display
+    fn location(&self, name: &str) -> String;
+
+    /// Whether the local archive is kept once it is uploaded
+    fn keep_local(&self) -> bool {
+        true
+    }
+
+    /// Connect, authenticate and write and delete a probe file
+    async fn probe(&self) -> Result<()>;
+
+    /// Upload `source` as `name`, continuing an earlier attempt recorded in
+    /// `checkpoint`. Only returns once the stored copy matches `sha256`.
+    async fn upload(
+        &self,
+        name: &str,
+        source: &Path,
+        sha256: &str,
+        checkpoint: &Checkpoint<'_>,
+    ) -> Result<()>;
+
+    /// Download `name` into `dest`; returns its size
+    async fn download(&self, name: &str, dest: &Path) -> Result<u64>;
+
+    /// Delete `name` and whatever an unfinished upload of it left behind;
+    /// a missing file is not an error
+    async fn remove(&self, name: &str, checkpoint: &Checkpoint<'_>) -> Result<()>;
+}
+
+/// Build a destination from its type and config, with secrets merged in
+pub fn build(
+    id: &str,
+    destination_type: &str,
+    config: &serde_json::Value,
+) -> Result<Box<dyn Destination>> {
+    match destination_type {
+        "local" => Ok(Box::new(LocalDestination::from_config(config)?)),
+        "sftp" => Ok(Box::new(SftpDestination::from_config(config)?)),
+        "s3" | "minio" => Ok(Box::new(S3Destination::from_config(
+            id,
+            destination_type,
+            config,
+        )?)),
+        other => bail!("backup destination type '{}' is not supported", other),
+    }
+}
+
+/// Move the credential fields out of `config`; returns them as a JSON
+/// object, `None` if there were none
+pub fn split_secrets(config: &mut serde_json::Value) -> Option<serde_json::Value> {
+    let config = config.as_object_mut()?;
+    let secrets: serde_json::Map<String, serde_json::Value> = SECRET_FIELDS
+        .iter()
+        .filter_map(|field| Some((field.to_string(), config.remove(*field)?)))
+        .collect();
+    (!secrets.is_empty()).then_some(serde_json::Value::Object(secrets))
+}
+
+/// Load destination `id`, revealing its credentials for `purpose`
+pub async fn open(pool: &SqlitePool, id: &str, purpose: &str) -> Result<Box<dyn Destination>> {
+    let row: Option<(String, String, Option<String>)> = sqlx::query_as(
+        "SELECT destination_type, config, secrets FROM backup_destinations WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    let (destination_type, config, sealed) =
+        row.ok_or_else(|| anyhow!("backup destination {} not found", id))?;
+
+    let mut config: serde_json::Value =
+        serde_json::from_str(&config).context("invalid destination config")?;
+    if let Some(sealed) = sealed.filter(|sealed| !sealed.is_empty()) {
+        let revealed = secrets::reveal(pool, &BACKUP_DESTINATION_SECRETS, id, &sealed, purpose)
+            .await
+            .map_err(|e| anyhow!("cannot read credentials of destination {}: {}", id, e))?;
+        let revealed: serde_json::Value = serde_json::from_str(&revealed)?;
+        if let (Some(config), Some(revealed)) = (config.as_object_mut(), revealed.as_object()) {
+            config.extend(revealed.clone());
+        }
+    }
+    build(id, &destination_type, &config)
+}
+
+/// The destination new backups go to when none is picked
+pub async fn default_id(pool: &SqlitePool) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT id FROM backup_destinations WHERE is_default = 1 AND status != 'inactive'
+         ORDER BY created_at LIMIT 1",
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// What an unfinished upload of a backup left to continue from, kept in
+/// `backup_uploads`
+pub struct Checkpoint<'a> {
+    pool: &'a SqlitePool,
+    backup_id: &'a str,
+    destination_id: &'a str,
+}
+
+impl<'a> Checkpoint<'a> {
+    pub fn new(pool: &'a SqlitePool, backup_id: &'a str, destination_id: &'a str) -> Self {
+        Self {
+            pool,
+            backup_id,
+            destination_id,
+        }
+    }
+
+    /// Multipart upload started by an earlier attempt
+    pub async fn upload_id(&self) -> Result<Option<String>, sqlx::Error> {
+        Ok(sqlx::query_scalar(
+            "SELECT upload_id FROM backup_uploads WHERE backup_id = ? AND destination_id = ?",
+        )
+        .bind(self.backup_id)
+        .bind(self.destination_id)
+        .fetch_optional(self.pool)
+        .await?
+        .flatten())
+    }
+
+    pub async fn set_upload_id(&self, upload_id: Option<&str>) -> Result<(), sqlx::Error> {
+        let now = Utc::now().to_rfc3339();
+        sqlx::query(
+            "INSERT INTO backup_uploads (backup_id, destination_id, upload_id, started_at, updated_at)
+             VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT(backup_id) DO UPDATE SET
+                destination_id = excluded.destination_id,
+                upload_id = excluded.upload_id,
+                updated_at = excluded.updated_at",
+        )
+        .bind(self.backup_id)
+        .bind(self.destination_id)
+        .bind(upload_id)
+        .bind(&now)
+        .bind(&now)
+        .execute(self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM backup_uploads WHERE backup_id = ?")
+            .bind(self.backup_id)
+            .execute(self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Name of an archive at its destination
+fn remote_name(storage_path: &str) -> Result<String> {
+    Path::new(storage_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("invalid archive path {}", storage_path))
+}
+
+/// What uploading, fetching and removing need to know of a backup
+#[derive(sqlx::FromRow)]
+struct Archive {
+    storage_path: String,
+    checksum: Option<String>,
+    status: String,
+    destination_id: Option<String>,
+    upload_status: Option<String>,
+}
+
+impl Archive {
+    async fn load(pool: &SqlitePool, backup_id: &str) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT storage_path, checksum, status, destination_id, upload_status
+             FROM backups WHERE id = ?",
+        )
+        .bind(backup_id)
+        .fetch_optional(pool)
+        .await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Uploaded {
+    pub location: String,
+    /// The local archive was removed after uploading
+    pub removed_local: bool,
+}
+
+/// Upload a completed backup to its destination and record the outcome in
+/// `backups`
+pub async fn upload(pool: &SqlitePool, backup_id: &str) -> Result<Uploaded> {
+    let backup = Archive::load(pool, backup_id)
+        .await?
+        .ok_or_else(|| anyhow!("backup {} not found", backup_id))?;
+    if backup.status != "completed" {
+        bail!("backup {} is not completed", backup_id);
+    }
+    let checksum = backup
+        .checksum
+        .ok_or_else(|| anyhow!("backup {} has no checksum", backup_id))?;
+    let destination_id = backup
+        .destination_id
+        .ok_or_else(|| anyhow!("backup {} has no destination", backup_id))?;
+    let (storage_path, upload_status) = (backup.storage_path, backup.upload_status);
+
+    let destination = open(pool, &destination_id, "backup upload").await?;
+    let name = remote_name(&storage_path)?;
+    let location = destination.location(&name);
+    let archive = PathBuf::from(&storage_path);
+    if upload_status.as_deref() == Some("uploaded") && !archive.exists() {
+        return Ok(Uploaded {
+            location,
+            removed_local: true,
+        });
+    }
+
+    sqlx::query(
+        "UPDATE backups SET upload_status = 'uploading', upload_error = NULL, remote_path = ?
+         WHERE id = ?",
+    )
+    .bind(&location)
+    .bind(backup_id)
+    .execute(pool)
+    .await?;
+
+    let checkpoint = Checkpoint::new(pool, backup_id, &destination_id);
+    if let Err(e) = destination
+        .upload(&name, &archive, &checksum, &checkpoint)
+        .await
+    {
+        sqlx::query("UPDATE backups SET upload_status = 'failed', upload_error = ? WHERE id = ?")
+            .bind(format!("{:#}", e))
+            .bind(backup_id)
+            .execute(pool)
+            .await?;
+        return Err(e);
+    }
+    checkpoint.clear().await?;
+    sqlx::query(
+        "UPDATE backups SET upload_status = 'uploaded', upload_error = NULL, uploaded_at = ?
+         WHERE id = ?",
+    )
+    .bind(Utc::now().to_rfc3339())
+    .bind(backup_id)
+    .execute(pool)
+    .await?;
+
+    // A local destination may be the backup directory itself
+    let removed_local =
+        !destination.keep_local() && !is_same_file(&archive, Path::new(&location)).await;
+    if removed_local {
+        tokio::fs::remove_file(&archive).await?;
+    }
+    Ok(Uploaded {
+        location,
+        removed_local,
+    })
+}
+
+/// Download the archives of the chain ending in `backup_id` that are no
+/// longer kept locally; returns how many were downloaded
+pub async fn fetch_chain(pool: &SqlitePool, backup_id: &str) -> Result<usize> {
+    let mut fetched = 0;
+    for id in super::chain::links(pool, backup_id).await? {
+        if fetch(pool, &id).await? {
+            fetched += 1;
+        }
+    }
+    Ok(fetched)
+}
+
+/// Download the archive of `backup_id` if it is no longer kept locally;
+/// returns whether it was downloaded
+pub async fn fetch(pool: &SqlitePool, backup_id: &str) -> Result<bool> {
+    let Some(backup) = Archive::load(pool, backup_id).await? else {
+        bail!("backup {} no longer exists", backup_id);
+    };
+    let Archive {
+        storage_path,
+        checksum,
+        destination_id,
+        upload_status,
+        ..
+    } = backup;
+    let archive = PathBuf::from(&storage_path);
+    if archive.exists() {
+        return Ok(false);
+    }
+    let (Some(destination_id), Some("uploaded")) = (destination_id, upload_status.as_deref())
+    else {
+        bail!("archive of backup {} is missing", backup_id);
+    };
+
+    let destination = open(pool, &destination_id, "backup download").await?;
+    if let Some(parent) = archive.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let partial = super::suffixed(&archive, ".download");
+    destination
+        .download(&remote_name(&storage_path)?, &partial)
+        .await
+        .with_context(|| format!("cannot download backup {}", backup_id))?;
+    let (_, sha256) = sha256_of(&partial).await?;
+    if checksum.is_some_and(|checksum| checksum != sha256) {
+        let _ = tokio::fs::remove_file(&partial).await;
+        bail!(
+            "downloaded archive of backup {} does not match its checksum",
+            backup_id
+        );
+    }
+    tokio::fs::rename(&partial, &archive).await?;
+    Ok(true)
+}
+
+/// Delete the uploaded copy of a backup, and any unfinished upload of it
+pub async fn remove(pool: &SqlitePool, backup_id: &str) -> Result<()> {
+    let Some(Archive {
+        storage_path,
+        destination_id: Some(destination_id),
+        upload_status,
+        ..
+    }) = Archive::load(pool, backup_id).await?
+    else {
+        return Ok(());
+    };
+    let checkpoint = Checkpoint::new(pool, backup_id, &destination_id);
+    if upload_status.is_none() && checkpoint.upload_id().await?.is_none() {
+        return Ok(());
+    }
+    let exists: Option<String> =
+        sqlx::query_scalar("SELECT id FROM backup_destinations WHERE id = ?")
+            .bind(&destination_id)
+            .fetch_optional(pool)
+            .await?;
+    if exists.is_none() {
+        tracing::warn!(
+            "Destination {} of backup {} is gone, leaving its copy there",
+            destination_id,
+            backup_id
+        );
+        return Ok(());
+    }
+
+    let destination = open(pool, &destination_id, "backup pruning").await?;
+    destination
+        .remove(&remote_name(&storage_path)?, &checkpoint)
+        .await?;
+    checkpoint.clear().await?;
+    Ok(())
+}
+
+async fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (
+        tokio::fs::canonicalize(a).await,
+        tokio::fs::canonicalize(b).await,
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Size and hex SHA-256 of a local file
+pub(crate) async fn sha256_of(path: &Path) -> Result<(u64, String)> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&path)?;
+        let mut hashing = super::archive::HashingReader::new(std::io::BufReader::new(file));
+        std::io::copy(&mut hashing, &mut std::io::sink())?;
+        Ok(hashing.finish())
+    })
+    .await?
+}
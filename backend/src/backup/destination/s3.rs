@@ -0,0 +1,226 @@
+//! S3-compatible destination (AWS S3, MinIO)
+//!
+//! Archives are always uploaded as multipart uploads. The upload id is kept
+//! in the checkpoint, so a retried upload lists the parts that already
+//! arrived and only sends the missing or damaged ones. Every part carries
+//! its Content-MD5, which the service checks on receipt, and the finished
+//! object's size and ETag are compared with what was sent.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Result, anyhow, bail};
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::AsyncReadExt;
+
+use super::{Checkpoint, Destination};
+use crate::storage::StorageBackend;
+use crate::storage::s3::{S3_MIN_PART_SIZE, S3Backend, unquote_etag};
+
+const DEFAULT_PART_SIZE: u64 = 16 * 1024 * 1024;
+
+pub struct S3Destination {
+    backend: S3Backend,
+    /// `s3://bucket/prefix/`
+    base: String,
+    part_size: u64,
+    keep_local: bool,
+}
+
+impl S3Destination {
+    /// Config as for S3 storage backends (`endpoint`, `bucket`, `region`,
+    /// `access_key_id`, `secret_access_key`, `path_style`, `prefix`), plus
+    /// `part_size_mb` and `keep_local`
+    pub fn from_config(
+        id: &str,
+        destination_type: &str,
+        config: &serde_json::Value,
+    ) -> Result<Self> {
+        let backend = S3Backend::from_config(id, destination_type, config)?;
+        let bucket = ["bucket", "bucket_name"]
+            .iter()
+            .find_map(|key| config.get(*key).and_then(|value| value.as_str()))
+            .unwrap_or_default();
+        let prefix = config
+            .get("prefix")
+            .and_then(|prefix| prefix.as_str())
+            .map(|prefix| prefix.trim_matches('/'))
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| format!("{}/", prefix))
+            .unwrap_or_default();
+        let part_size = config
+            .get("part_size_mb")
+            .and_then(|size| size.as_u64())
+            .map_or(DEFAULT_PART_SIZE, |size| size * 1024 * 1024)
+            .max(S3_MIN_PART_SIZE);
+        Ok(Self {
+            backend,
+            base: format!("s3://{}/{}", bucket, prefix),
+            part_size,
+            keep_local: config
+                .get("keep_local")
+                .and_then(|keep| keep.as_bool())
+                .unwrap_or(true),
+        })
+    }
+
+    /// Continue the upload recorded in `checkpoint`, or start one
+    async fn resume(
+        &self,
+        name: &str,
+        checkpoint: &Checkpoint<'_>,
+    ) -> Result<(String, HashMap<u32, (String, u64)>)> {
+        if let Some(upload_id) = checkpoint.upload_id().await? {
+            if let Some(parts) = self.backend.list_parts(name, &upload_id).await? {
+                let parts = parts
+                    .into_iter()
+                    .map(|part| (part.number, (part.etag, part.size)))
+                    .collect();
+                return Ok((upload_id, parts));
+            }
+            tracing::info!("Upload of {} expired, starting over", name);
+        }
+        let upload_id = self.backend.create_multipart(name).await?;
+        checkpoint.set_upload_id(Some(&upload_id)).await?;
+        Ok((upload_id, HashMap::new()))
+    }
+}
+
+/// Whether `etag` is the MD5 of the MD5s of the parts, which it is unless
+/// the bucket encrypts with KMS
+fn is_multipart_md5(etag: &str) -> bool {
+    etag.split_once('-').is_some_and(|(md5, parts)| {
+        md5.len() == 32
+            && md5.bytes().all(|b| b.is_ascii_hexdigit())
+            && parts.parse::<u32>().is_ok()
+    })
+}
+
+#[async_trait]
+impl Destination for S3Destination {
+    fn kind(&self) -> &'static str {
+        "s3"
+    }
+
+    fn location(&self, name: &str) -> String {
+        format!("{}{}", self.base, name)
+    }
+
+    fn keep_local(&self) -> bool {
+        self.keep_local
+    }
+
+    async fn probe(&self) -> Result<()> {
+        let key = format!(".syncspace-probe-{}", uuid::Uuid::new_v4());
+        self.backend
+            .put_bytes(&key, Bytes::from_static(b"probe"))
+            .await?;
+        self.backend.delete(&key).await
+    }
+
+    async fn upload(
+        &self,
+        name: &str,
+        source: &Path,
+        _sha256: &str,
+        checkpoint: &Checkpoint<'_>,
+    ) -> Result<()> {
+        let size = tokio::fs::metadata(source).await?.len();
+        let part_size = S3Backend::part_size(size, self.part_size);
+        let (upload_id, uploaded) = self.resume(name, checkpoint).await?;
+
+        let mut file = tokio::fs::File::open(source).await?;
+        let mut parts = Vec::new();
+        let mut digests = Vec::new();
+        for number in 1u32.. {
+            let mut buffer = Vec::with_capacity(part_size as usize);
+            (&mut file).take(part_size).read_to_end(&mut buffer).await?;
+            if buffer.is_empty() && number > 1 {
+                break;
+            }
+            let digest = md5::compute(&buffer);
+            let md5 = format!("{:x}", digest);
+            digests.extend_from_slice(&digest.0);
+
+            let etag = match uploaded.get(&number) {
+                Some((etag, size))
+                    if *size == buffer.len() as u64 && etag.eq_ignore_ascii_case(&md5) =>
+                {
+                    etag.clone()
+                }
+                _ => {
+                    let etag = self
+                        .backend
+                        .upload_part(name, &upload_id, number, buffer)
+                        .await?;
+                    unquote_etag(&etag).to_string()
+                }
+            };
+            parts.push((number, etag));
+        }
+        self.backend
+            .complete_multipart(name, &upload_id, &parts)
+            .await?;
+        checkpoint.set_upload_id(None).await?;
+
+        let stored = self
+            .backend
+            .head(name)
+            .await?
+            .ok_or_else(|| anyhow!("{} is missing after uploading it", self.location(name)))?;
+        let expected = format!("{:x}-{}", md5::compute(&digests), parts.len());
+        let etag = stored.etag.as_deref().map(unquote_etag).unwrap_or_default();
+        if stored.size != size || (is_multipart_md5(etag) && !etag.eq_ignore_ascii_case(&expected))
+        {
+            let _ = self.backend.delete(name).await;
+            bail!(
+                "{} does not match the archive (size {}, ETag {})",
+                self.location(name),
+                stored.size,
+                etag
+            );
+        }
+        Ok(())
+    }
+
+    async fn download(&self, name: &str, dest: &Path) -> Result<u64> {
+        self.backend.get_to_file(name, dest).await
+    }
+
+    async fn remove(&self, name: &str, checkpoint: &Checkpoint<'_>) -> Result<()> {
+        if let Some(upload_id) = checkpoint.upload_id().await? {
+            self.backend.abort_multipart(name, &upload_id).await?;
+        }
+        self.backend.delete(name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_and_etags() {
+        let config = serde_json::json!({
+            "endpoint": "http://127.0.0.1:9000",
+            "bucket": "backups",
+            "access_key_id": "minioadmin",
+            "secret_access_key": "minioadmin",
+            "prefix": "/nightly/",
+            "part_size_mb": 1,
+            "keep_local": false
+        });
+        let destination = S3Destination::from_config("d1", "minio", &config).unwrap();
+        assert_eq!(
+            destination.location("b1.tar.zst"),
+            "s3://backups/nightly/b1.tar.zst"
+        );
+        assert_eq!(destination.part_size, S3_MIN_PART_SIZE);
+        assert!(!destination.keep_local());
+
+        assert!(is_multipart_md5("9b2cf535f27731c974343645a3985328-3"));
+        assert!(!is_multipart_md5("9b2cf535f27731c974343645a3985328"));
+        assert!(!is_multipart_md5("kms-managed-etag-1"));
+    }
+}
@@ -0,0 +1,410 @@
+//! SFTP destination
+//!
+//! The server's host key must match the configured SHA-256 fingerprint;
+//! without one the connection is refused and the error names the key the
+//! server offered. The archive is written to `<name>.partial`, continuing
+//! from its size after an interrupted attempt, checked with `sha256sum` on
+//! the server (or by reading it back when the server has no shell) and
+//! renamed into place.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use base64::{Engine as _, engine::general_purpose};
+use ssh2::{HashType, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+
+use super::{Checkpoint, Destination};
+use crate::backup::archive::HashingReader;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const BUFFER_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone)]
+enum Auth {
+    Password(String),
+    Key {
+        private_key: String,
+        passphrase: Option<String>,
+    },
+    KeyFile {
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct SftpDestination {
+    host: String,
+    port: u16,
+    username: String,
+    auth: Auth,
+    root: PathBuf,
+    host_key_sha256: Option<String>,
+    keep_local: bool,
+}
+
+impl SftpDestination {
+    /// Config: `host`, `port` (22), `username`, one of `password`,
+    /// `private_key` (PEM) or `private_key_path` with an optional
+    /// `passphrase`, `path` (the login directory), `host_key_sha256` as
+    /// printed by `ssh-keygen -l` and `keep_local`
+    pub fn from_config(config: &serde_json::Value) -> Result<Self> {
+        let text = |key: &str| {
+            config
+                .get(key)
+                .and_then(|value| value.as_str())
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        let host = text("host").ok_or_else(|| anyhow!("SFTP destination requires a host"))?;
+        let username =
+            text("username").ok_or_else(|| anyhow!("SFTP destination requires a username"))?;
+        let passphrase = text("passphrase");
+        let auth = if let Some(private_key) = text("private_key") {
+            Auth::Key {
+                private_key,
+                passphrase,
+            }
+        } else if let Some(path) = text("private_key_path") {
+            Auth::KeyFile {
+                path: path.into(),
+                passphrase,
+            }
+        } else if let Some(password) = text("password") {
+            Auth::Password(password)
+        } else {
+            bail!("SFTP destination requires a password or a private key");
+        };
+        let port = match config.get("port") {
+            Some(port) => port
+                .as_u64()
+                .or_else(|| port.as_str().and_then(|port| port.parse().ok()))
+                .and_then(|port| u16::try_from(port).ok())
+                .ok_or_else(|| anyhow!("invalid SFTP port {}", port))?,
+            None => 22,
+        };
+        Ok(Self {
+            host,
+            port,
+            username,
+            auth,
+            root: text("path").unwrap_or_else(|| ".".to_string()).into(),
+            host_key_sha256: text("host_key_sha256"),
+            keep_local: config
+                .get("keep_local")
+                .and_then(|keep| keep.as_bool())
+                .unwrap_or(true),
+        })
+    }
+
+    fn connect(&self) -> Result<(Session, Sftp)> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("cannot resolve {}", self.host))?;
+        let tcp = TcpStream::connect_timeout(&address, TIMEOUT)
+            .with_context(|| format!("cannot connect to {}:{}", self.host, self.port))?;
+        let mut session = Session::new()?;
+        session.set_timeout(TIMEOUT.as_millis() as u32);
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+
+        let offered = session
+            .host_key_hash(HashType::Sha256)
+            .map(fingerprint)
+            .ok_or_else(|| anyhow!("{} did not offer a host key", self.host))?;
+        match &self.host_key_sha256 {
+            Some(expected) if same_fingerprint(expected, &offered) => {}
+            Some(_) => bail!(
+                "host key of {} has changed to {}, refusing to connect",
+                self.host,
+                offered
+            ),
+            None => bail!(
+                "host key of {} is not trusted yet, set host_key_sha256 to {} after checking it",
+                self.host,
+                offered
+            ),
+        }
+
+        match &self.auth {
+            Auth::Password(password) => session.userauth_password(&self.username, password)?,
+            Auth::Key {
+                private_key,
+                passphrase,
+            } => session.userauth_pubkey_memory(
+                &self.username,
+                None,
+                private_key,
+                passphrase.as_deref(),
+            )?,
+            Auth::KeyFile { path, passphrase } => {
+                session.userauth_pubkey_file(&self.username, None, path, passphrase.as_deref())?
+            }
+        }
+        if !session.authenticated() {
+            bail!("SFTP login as {} was refused", self.username);
+        }
+        let sftp = session.sftp()?;
+        Ok((session, sftp))
+    }
+
+    fn target(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("invalid archive name '{}'", name);
+        }
+        Ok(self.root.join(name))
+    }
+
+    /// `mkdir -p` of the destination directory
+    fn ensure_root(&self, sftp: &Sftp) -> Result<()> {
+        let mut path = PathBuf::new();
+        for component in self.root.components() {
+            path.push(component);
+            if sftp.stat(&path).is_err() {
+                sftp.mkdir(&path, 0o750)
+                    .with_context(|| format!("cannot create {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn upload_blocking(&self, name: &str, source: &Path, sha256: &str) -> Result<()> {
+        let target = self.target(name)?;
+        let partial = crate::backup::suffixed(&target, ".partial");
+        let (session, sftp) = self.connect()?;
+        self.ensure_root(&sftp)?;
+
+        let size = std::fs::metadata(source)?.len();
+        let offset = sftp
+            .stat(&partial)
+            .ok()
+            .and_then(|stat| stat.size)
+            .filter(|offset| *offset <= size)
+            .unwrap_or(0);
+        let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+        if offset == 0 {
+            flags |= OpenFlags::TRUNCATE;
+        } else {
+            tracing::info!("Continuing upload of {} at {} bytes", name, offset);
+        }
+        let mut remote = sftp.open_mode(&partial, flags, 0o640, OpenType::File)?;
+        remote.seek(SeekFrom::Start(offset))?;
+        let mut input = File::open(source)?;
+        input.seek(SeekFrom::Start(offset))?;
+        copy(&mut input, &mut remote)?;
+        remote.fsync().or_else(ignore_unsupported)?;
+        drop(remote);
+
+        let (stored_size, stored) = self.remote_checksum(&session, &sftp, &partial)?;
+        if stored_size != size || stored != sha256 {
+            let _ = sftp.unlink(&partial);
+            bail!(
+                "copy of {} on {} does not match its checksum",
+                name,
+                self.host
+            );
+        }
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        if sftp.rename(&partial, &target, Some(flags)).is_err() {
+            // SFTPv3 servers cannot rename over an existing file
+            let _ = sftp.unlink(&target);
+            sftp.rename(&partial, &target, None)?;
+        }
+        Ok(())
+    }
+
+    /// Size and SHA-256 of a file on the server, hashed there if possible
+    fn remote_checksum(
+        &self,
+        session: &Session,
+        sftp: &Sftp,
+        path: &Path,
+    ) -> Result<(u64, String)> {
+        let size = sftp
+            .stat(path)?
+            .size
+            .ok_or_else(|| anyhow!("server did not report the size of {}", path.display()))?;
+        if let Some(sha256) = sha256sum(session, path) {
+            return Ok((size, sha256));
+        }
+        let mut hashing =
+            HashingReader::new(BufReader::with_capacity(BUFFER_SIZE, sftp.open(path)?));
+        io::copy(&mut hashing, &mut io::sink())?;
+        Ok(hashing.finish())
+    }
+
+    fn download_blocking(&self, name: &str, dest: &Path) -> Result<u64> {
+        let target = self.target(name)?;
+        let (_session, sftp) = self.connect()?;
+        let mut remote = sftp.open(&target)?;
+        let mut output = File::create(dest)?;
+        let size = copy(&mut remote, &mut output)?;
+        output.sync_all()?;
+        Ok(size)
+    }
+
+    fn remove_blocking(&self, name: &str) -> Result<()> {
+        let target = self.target(name)?;
+        let (_session, sftp) = self.connect()?;
+        for path in [crate::backup::suffixed(&target, ".partial"), target] {
+            if let Err(e) = sftp.unlink(&path) {
+                let e = io::Error::from(e);
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e).with_context(|| format!("cannot delete {}", path.display()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn probe_blocking(&self) -> Result<()> {
+        let (_session, sftp) = self.connect()?;
+        self.ensure_root(&sftp)?;
+        let probe = self
+            .root
+            .join(format!(".syncspace-probe-{}", uuid::Uuid::new_v4()));
+        sftp.create(&probe)?.write_all(b"probe")?;
+        sftp.unlink(&probe)?;
+        Ok(())
+    }
+}
+
+/// OpenSSH's notation, `SHA256:` and unpadded base64
+fn fingerprint(hash: &[u8]) -> String {
+    format!("SHA256:{}", general_purpose::STANDARD_NO_PAD.encode(hash))
+}
+
+fn same_fingerprint(expected: &str, offered: &str) -> bool {
+    let expected = expected.trim().trim_end_matches('=');
+    let expected = expected.strip_prefix("SHA256:").unwrap_or(expected);
+    offered.strip_prefix("SHA256:") == Some(expected)
+}
+
+/// Run `sha256sum` on the server; `None` if it has no shell or no sha256sum
+fn sha256sum(session: &Session, path: &Path) -> Option<String> {
+    let quoted = format!("'{}'", path.to_str()?.replace('\'', r"'\''"));
+    let mut channel = session.channel_session().ok()?;
+    channel.exec(&format!("sha256sum -- {}", quoted)).ok()?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output).ok()?;
+    channel.wait_close().ok()?;
+    if channel.exit_status().ok()? != 0 {
+        return None;
+    }
+    let sha256 = output.split_whitespace().next()?.to_ascii_lowercase();
+    (sha256.len() == 64 && sha256.bytes().all(|b| b.is_ascii_hexdigit())).then_some(sha256)
+}
+
+/// Copy in large writes, which SFTP needs to get anywhere near line speed
+fn copy(input: &mut impl Read, output: &mut impl Write) -> io::Result<u64> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(copied);
+        }
+        output.write_all(&buffer[..n])?;
+        copied += n as u64;
+    }
+}
+
+fn ignore_unsupported(e: ssh2::Error) -> Result<(), ssh2::Error> {
+    // fsync@openssh.com is an extension
+    match e.code() {
+        ssh2::ErrorCode::SFTP(8) => Ok(()),
+        _ => Err(e),
+    }
+}
+
+#[async_trait]
+impl Destination for SftpDestination {
+    fn kind(&self) -> &'static str {
+        "sftp"
+    }
+
+    fn location(&self, name: &str) -> String {
+        let path = self.root.join(name);
+        let path = path.to_string_lossy();
+        let separator = if path.starts_with('/') { "" } else { "/" };
+        format!(
+            "sftp://{}@{}:{}{}{}",
+            self.username, self.host, self.port, separator, path
+        )
+    }
+
+    fn keep_local(&self) -> bool {
+        self.keep_local
+    }
+
+    async fn probe(&self) -> Result<()> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.probe_blocking()).await?
+    }
+
+    async fn upload(
+        &self,
+        name: &str,
+        source: &Path,
+        sha256: &str,
+        _checkpoint: &Checkpoint<'_>,
+    ) -> Result<()> {
+        let this = self.clone();
+        let (name, source, sha256) = (name.to_string(), source.to_path_buf(), sha256.to_string());
+        tokio::task::spawn_blocking(move || this.upload_blocking(&name, &source, &sha256)).await?
+    }
+
+    async fn download(&self, name: &str, dest: &Path) -> Result<u64> {
+        let this = self.clone();
+        let (name, dest) = (name.to_string(), dest.to_path_buf());
+        tokio::task::spawn_blocking(move || this.download_blocking(&name, &dest)).await?
+    }
+
+    async fn remove(&self, name: &str, _checkpoint: &Checkpoint<'_>) -> Result<()> {
+        let this = self.clone();
+        let name = name.to_string();
+        tokio::task::spawn_blocking(move || this.remove_blocking(&name)).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_and_fingerprints() {
+        let config = serde_json::json!({
+            "host": "nas.local",
+            "port": "2222",
+            "username": "backup",
+            "password": "secret",
+            "path": "/srv/backups"
+        });
+        let destination = SftpDestination::from_config(&config).unwrap();
+        assert_eq!(
+            destination.location("b1.tar.zst"),
+            "sftp://backup@nas.local:2222/srv/backups/b1.tar.zst"
+        );
+        assert!(destination.keep_local());
+        assert!(
+            SftpDestination::from_config(&serde_json::json!({
+                "host": "nas.local",
+                "username": "backup"
+            }))
+            .is_err()
+        );
+
+        let offered = fingerprint(&[0xab; 32]);
+        assert!(offered.starts_with("SHA256:q6ur"));
+        assert!(!offered.ends_with('='));
+        assert!(same_fingerprint(&offered, &offered));
+        assert!(same_fingerprint(&format!(" {}= ", &offered[7..]), &offered));
+        assert!(!same_fingerprint(&fingerprint(&[0xac; 32]), &offered));
+    }
+}
@@ -0,0 +1,1039 @@
+//! Backups
+//!
+//! A backup is a single tar+zstd archive holding
+//! - `syncspace.db`, a transactionally consistent snapshot of the database
+//!   taken with `VACUUM INTO` while the instance keeps serving requests,
+//! - `data/<path>`, the content of the data directory including the version
+//!   history (`versions/` and the block store it may live in),
+//! - `manifest.json`, written last, with the size and SHA-256 of every entry.
+//!
+//! Backups can be chained. The manifest of an incremental backup still lists
+//! every file at the time of the backup, but content that an earlier link of
+//! the chain already holds (same path, size and modification time, or the
+//! same checksum anywhere) is only referenced by the backup it is stored in.
+//! Any link can thus be restored on its own as long as the archives of the
+//! chain are kept side by side. See [`chain`] for how links are picked,
+//! [`retention`] for pruning them and [`destination`] for uploading them.
+//!
+//! Restores extract into a staging directory inside the data directory,
+//! check every entry against the manifest and only then move content into
+//! place, so a damaged archive leaves the instance untouched. A database in
+//! use cannot be swapped underneath its pool: if one exists, the restored
+//! copy is staged as `syncspace.db.restore` and put in place by
+//! [`apply_staged_database`] on the next start.
+//!
+//! The master key of the secrets vault lives outside the data directory and
+//! is not part of a backup; keep a copy of it to read stored credentials
+//! after restoring.
+
+pub mod archive;
+pub mod chain;
+pub mod destination;
+pub mod retention;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use archive::{ArchiveWriter, HashingReader, HashingWriter};
+
+pub const BACKUP_DIR: &str = "./data/backups";
+pub const ARCHIVE_EXTENSION: &str = "tar.zst";
+pub const FORMAT_VERSION: u32 = 2;
+
+const MANIFEST_ENTRY: &str = "manifest.json";
+const DATABASE_ENTRY: &str = "syncspace.db";
+const FILES_PREFIX: &str = "data/";
+const DATABASE_FILE: &str = "syncspace.db";
+const STAGED_DATABASE: &str = "syncspace.db.restore";
+const REPLACED_DATABASE: &str = "syncspace.db.pre-restore";
+const STAGING_PREFIX: &str = ".restore-";
+/// Top level directories that are rebuilt on demand, temporary, or hold the
+/// backups themselves
+const SKIPPED_DIRS: &[&str] = &[
+    "backups",
+    "temp",
+    "temp_uploads",
+    "search_index",
+    "thumbnails",
+    ".thumbnails",
+    ".previews",
+    ".quarantine",
+];
+/// Top level directories holding version history
+const VERSION_DIRS: &[&str] = &["versions", ".blocks"];
+
+/// Where the archive of a backup is stored
+pub fn archive_path(backup_id: &str) -> PathBuf {
+    Path::new(BACKUP_DIR).join(format!("{}.{}", backup_id, ARCHIVE_EXTENSION))
+}
+
+/// The archive of another link of a chain, which sits next to `archive`
+pub fn chain_archive(archive: &Path, backup_id: &str) -> PathBuf {
+    archive.with_file_name(format!("{}.{}", backup_id, ARCHIVE_EXTENSION))
+}
+
+/// Copy of the manifest kept next to an archive, so chaining onto a backup
+/// does not have to read through all of it
+pub fn manifest_path(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let stem = name
+        .strip_suffix(&format!(".{}", ARCHIVE_EXTENSION))
+        .unwrap_or(&name);
+    archive.with_file_name(format!("{}.manifest.json", stem))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BackupOptions {
+    pub database: bool,
+    pub files: bool,
+    pub versions: bool,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            database: true,
+            files: true,
+            versions: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Database,
+    File,
+    Version,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path inside the archive
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub sha256: String,
+    /// Modification time in seconds since the epoch
+    #[serde(default)]
+    pub mtime: u64,
+    /// Backup whose archive holds the content, when it is not this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_in: Option<String>,
+    /// Path of the content in that archive, when it is not `path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_as: Option<String>,
+}
+
+impl ManifestEntry {
+    /// Path relative to the data directory, `None` for the database
+    pub fn data_path(&self) -> Option<&str> {
+        self.path.strip_prefix(FILES_PREFIX)
+    }
+
+    /// Whether the content is in the archive of the backup listing it, under
+    /// its own path
+    pub fn is_stored(&self) -> bool {
+        self.stored_in.is_none() && self.stored_as.is_none()
+    }
+
+    /// Backup and archive path holding the content, for an entry listed by
+    /// `backup_id`
+    pub fn location<'a>(&'a self, backup_id: &'a str) -> (&'a str, &'a str) {
+        (
+            self.stored_in.as_deref().unwrap_or(backup_id),
+            self.stored_as.as_deref().unwrap_or(&self.path),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    pub backup_id: String,
+    /// The previous link of the chain, `None` for a full backup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    pub created_at: String,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+
+    /// Bytes of content stored in this backup's own archive
+    pub fn stored_size(&self) -> u64 {
+        self.entries
+            .iter()
+            .filter(|entry| entry.is_stored())
+            .map(|entry| entry.size)
+            .sum()
+    }
+
+    /// Files and versions, not counting the database
+    pub fn file_count(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.kind != EntryKind::Database)
+            .count()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Modified,
+    Unchanged,
+    Deleted,
+}
+
+impl Change {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Modified => "modified",
+            Change::Unchanged => "unchanged",
+            Change::Deleted => "deleted",
+        }
+    }
+}
+
+/// How every entry of `manifest` changed since `parent`, followed by the
+/// entries of `parent` that are gone
+pub fn changes<'a>(
+    parent: Option<&'a Manifest>,
+    manifest: &'a Manifest,
+) -> Vec<(&'a ManifestEntry, Change)> {
+    let Some(parent) = parent else {
+        return manifest
+            .entries
+            .iter()
+            .map(|entry| (entry, Change::Added))
+            .collect();
+    };
+    let before: HashMap<&str, &ManifestEntry> = parent
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+    let after: HashMap<&str, &ManifestEntry> = manifest
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+
+    let mut changes: Vec<_> = manifest
+        .entries
+        .iter()
+        .map(|entry| match before.get(entry.path.as_str()) {
+            None => (entry, Change::Added),
+            Some(old) if old.sha256 == entry.sha256 => (entry, Change::Unchanged),
+            Some(_) => (entry, Change::Modified),
+        })
+        .collect();
+    changes.extend(
+        parent
+            .entries
+            .iter()
+            .filter(|entry| !after.contains_key(entry.path.as_str()))
+            .map(|entry| (entry, Change::Deleted)),
+    );
+    changes
+}
+
+/// A written or verified archive
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub manifest: Manifest,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Write a backup of `data_dir` and the database behind `pool` to `archive`,
+/// as a link after `parent` if given
+///
+/// The archive is written under a temporary name and renamed when complete,
+/// so an interrupted backup never leaves a truncated archive behind.
+pub async fn create(
+    pool: &SqlitePool,
+    data_dir: &Path,
+    archive: &Path,
+    backup_id: &str,
+    options: BackupOptions,
+    parent: Option<&Manifest>,
+) -> Result<Archive> {
+    if let Some(parent) = archive.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let snapshot = options.database.then(|| suffixed(archive, ".db"));
+    if let Some(snapshot) = &snapshot {
+        // VACUUM INTO refuses to overwrite a leftover of an earlier attempt
+        let _ = tokio::fs::remove_file(snapshot).await;
+        sqlx::query("VACUUM INTO ?")
+            .bind(snapshot.to_string_lossy().into_owned())
+            .execute(pool)
+            .await
+            .context("database snapshot failed")?;
+    }
+
+    let written = tokio::task::spawn_blocking({
+        let data_dir = data_dir.to_path_buf();
+        let archive = archive.to_path_buf();
+        let backup_id = backup_id.to_string();
+        let snapshot = snapshot.clone();
+        let parent = parent.cloned();
+        move || {
+            write_archive(
+                &data_dir,
+                &archive,
+                &backup_id,
+                snapshot.as_deref(),
+                options,
+                parent.as_ref(),
+            )
+        }
+    })
+    .await;
+
+    if let Some(snapshot) = &snapshot {
+        let _ = tokio::fs::remove_file(snapshot).await;
+    }
+    written?
+}
+
+fn write_archive(
+    data_dir: &Path,
+    archive: &Path,
+    backup_id: &str,
+    snapshot: Option<&Path>,
+    options: BackupOptions,
+    parent: Option<&Manifest>,
+) -> Result<Archive> {
+    let partial = suffixed(archive, ".partial");
+    // Content the chain already holds, by path and by checksum
+    let previous: HashMap<&str, &ManifestEntry> = parent
+        .iter()
+        .flat_map(|parent| &parent.entries)
+        .filter(|entry| entry.kind != EntryKind::Database)
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+    let mut known: HashMap<String, (String, String)> = HashMap::new();
+    if let Some(parent) = parent {
+        for entry in previous.values() {
+            let (stored_in, stored_as) = entry.location(&parent.backup_id);
+            known.insert(
+                entry.sha256.clone(),
+                (stored_in.to_string(), stored_as.to_string()),
+            );
+        }
+    }
+
+    let written = (|| -> Result<Archive> {
+        let file = File::create(&partial)
+            .with_context(|| format!("cannot create {}", partial.display()))?;
+        let mut writer = ArchiveWriter::new(HashingWriter::new(BufWriter::new(file)))?;
+        let mut entries = Vec::new();
+
+        if let Some(snapshot) = snapshot {
+            let file = File::open(snapshot)?;
+            let metadata = file.metadata()?;
+            let sha256 = writer.append(DATABASE_ENTRY, metadata.len(), mtime(&metadata), file)?;
+            entries.push(ManifestEntry {
+                path: DATABASE_ENTRY.to_string(),
+                kind: EntryKind::Database,
+                size: metadata.len(),
+                sha256,
+                mtime: mtime(&metadata),
+                stored_in: None,
+                stored_as: None,
+            });
+        }
+
+        for (relative, kind) in content_files(data_dir, options)? {
+            let mut file = match File::open(data_dir.join(&relative)) {
+                Ok(file) => file,
+                // Deleted since the directory was listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("cannot read {}", relative)),
+            };
+            let metadata = file.metadata()?;
+            let (size, mtime) = (metadata.len(), mtime(&metadata));
+            let path = format!("{}{}", FILES_PREFIX, relative);
+
+            if let Some(parent) = parent {
+                let reference =
+                    |sha256: String, (stored_in, stored_as): (&str, &str)| ManifestEntry {
+                        path: path.clone(),
+                        kind,
+                        size,
+                        sha256,
+                        mtime,
+                        stored_in: (stored_in != backup_id).then(|| stored_in.to_string()),
+                        stored_as: (stored_as != path).then(|| stored_as.to_string()),
+                    };
+                // Untouched since the parent: trust its checksum
+                if let Some(old) = previous.get(path.as_str())
+                    && mtime != 0
+                    && old.size == size
+                    && old.mtime == mtime
+                {
+                    let location = old.location(&parent.backup_id);
+                    entries.push(reference(old.sha256.clone(), location));
+                    continue;
+                }
+                // Touched, renamed or copied content is stored only once
+                let mut hashing = HashingReader::new((&mut file).take(size));
+                io::copy(&mut hashing, &mut io::sink())?;
+                let (_, sha256) = hashing.finish();
+                if let Some((stored_in, stored_as)) = known.get(&sha256) {
+                    let location = (stored_in.as_str(), stored_as.as_str());
+                    entries.push(reference(sha256.clone(), location));
+                    continue;
+                }
+                file.rewind()?;
+            }
+
+            let sha256 = writer
+                .append(&path, size, mtime, file)
+                .with_context(|| format!("cannot archive {}", relative))?;
+            if parent.is_some() {
+                known.insert(sha256.clone(), (backup_id.to_string(), path.clone()));
+            }
+            entries.push(ManifestEntry {
+                path,
+                kind,
+                size,
+                sha256,
+                mtime,
+                stored_in: None,
+                stored_as: None,
+            });
+        }
+
+        let manifest = Manifest {
+            format: FORMAT_VERSION,
+            backup_id: backup_id.to_string(),
+            parent: parent.map(|parent| parent.backup_id.clone()),
+            created_at: Utc::now().to_rfc3339(),
+            entries,
+        };
+        writer.append_bytes(MANIFEST_ENTRY, &serde_json::to_vec_pretty(&manifest)?)?;
+
+        let (buffered, size, sha256) = writer.finish()?.finish();
+        let file = buffered.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&partial, archive)?;
+        write_manifest_copy(archive, &manifest)?;
+        Ok(Archive {
+            manifest,
+            size,
+            sha256,
+        })
+    })();
+
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    written
+}
+
+/// Files below `data_dir` that belong in a backup, relative to it and sorted
+fn content_files(data_dir: &Path, options: BackupOptions) -> Result<Vec<(String, EntryKind)>> {
+    let mut files = Vec::new();
+    if !options.files && !options.versions {
+        return Ok(files);
+    }
+
+    let walker = WalkDir::new(data_dir)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() != 1 || !is_skipped(&entry.file_name().to_string_lossy())
+        });
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e)
+                if e.io_error()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let Some(relative) = entry
+            .path()
+            .strip_prefix(data_dir)
+            .ok()
+            .and_then(|relative| relative.to_str())
+        else {
+            tracing::warn!(
+                "Skipping {} in backup: path is not UTF-8",
+                entry.path().display()
+            );
+            continue;
+        };
+        let relative = relative.replace('\\', "/");
+        let kind = classify(&relative);
+        let wanted = match kind {
+            EntryKind::Version => options.versions,
+            _ => options.files,
+        };
+        if wanted {
+            files.push((relative, kind));
+        }
+    }
+    Ok(files)
+}
+
+/// Top level entries of the data directory that are not backed up: the live
+/// database with its journal, restore leftovers and [`SKIPPED_DIRS`]
+fn is_skipped(name: &str) -> bool {
+    SKIPPED_DIRS.contains(&name)
+        || name.starts_with(DATABASE_FILE)
+        || name.starts_with(STAGING_PREFIX)
+}
+
+fn classify(relative: &str) -> EntryKind {
+    let top = relative.split('/').next().unwrap_or_default();
+    if VERSION_DIRS.contains(&top) {
+        EntryKind::Version
+    } else {
+        EntryKind::File
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    pub database: bool,
+    pub files: bool,
+    pub versions: bool,
+    /// Only files at or below these paths of the data directory; version
+    /// history is left out when set
+    pub paths: Option<Vec<String>>,
+    /// Restore files below this directory of the data directory instead of
+    /// their original place
+    pub into: Option<String>,
+}
+
+impl RestoreOptions {
+    /// Everything in the archive, in its original place
+    pub fn full() -> Self {
+        Self {
+            database: true,
+            files: true,
+            versions: true,
+            paths: None,
+            into: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DatabaseRestore {
+    /// Not part of the archive or not selected
+    Skipped,
+    /// Put in place, the data directory had no database
+    Restored,
+    /// Waiting for [`apply_staged_database`] on the next start
+    Staged,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoreReport {
+    pub backup_id: String,
+    pub files_restored: u64,
+    pub bytes_restored: u64,
+    pub database: DatabaseRestore,
+}
+
+/// Restore `archive` into `data_dir`, which may be empty
+///
+/// Content referenced from earlier links of the chain is read from their
+/// archives next to `archive`. Existing files at restored paths are
+/// replaced; other files are left alone. Nothing is changed unless every
+/// selected entry matches the manifest.
+pub async fn restore(
+    archive: &Path,
+    data_dir: &Path,
+    options: RestoreOptions,
+) -> Result<RestoreReport> {
+    let archive = archive.to_path_buf();
+    let data_dir = data_dir.to_path_buf();
+    tokio::task::spawn_blocking(move || restore_blocking(&archive, &data_dir, &options)).await?
+}
+
+fn restore_blocking(
+    archive: &Path,
+    data_dir: &Path,
+    options: &RestoreOptions,
+) -> Result<RestoreReport> {
+    let into = options.into.as_deref().map(safe_relative).transpose()?;
+    let paths = options
+        .paths
+        .as_ref()
+        .map(|paths| {
+            paths
+                .iter()
+                .map(|path| {
+                    let path = path.trim_matches('/');
+                    safe_relative(path).map(|_| path.to_string())
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?;
+    let selection = Selection { options, paths };
+    let manifest = read_manifest_blocking(archive)?;
+
+    fs::create_dir_all(data_dir)?;
+    let staging = data_dir.join(format!("{}{}", STAGING_PREFIX, Uuid::new_v4()));
+    fs::create_dir(&staging)?;
+    let restored = extract_and_apply(
+        archive,
+        &manifest,
+        data_dir,
+        &staging,
+        into.as_deref(),
+        &selection,
+    );
+    let _ = fs::remove_dir_all(&staging);
+    restored
+}
+
+struct Selection<'a> {
+    options: &'a RestoreOptions,
+    paths: Option<Vec<String>>,
+}
+
+impl Selection<'_> {
+    fn wants(&self, path: &str) -> bool {
+        if path == DATABASE_ENTRY {
+            return self.options.database;
+        }
+        let Some(relative) = path.strip_prefix(FILES_PREFIX) else {
+            return false;
+        };
+        match classify(relative) {
+            EntryKind::Version => self.options.versions && self.paths.is_none(),
+            _ => {
+                self.options.files
+                    && self.paths.as_ref().is_none_or(|paths| {
+                        paths.iter().any(|path| {
+                            relative == path
+                                || relative
+                                    .strip_prefix(path.as_str())
+                                    .is_some_and(|rest| rest.starts_with('/'))
+                        })
+                    })
+            }
+        }
+    }
+}
+
+fn extract_and_apply(
+    archive: &Path,
+    manifest: &Manifest,
+    data_dir: &Path,
+    staging: &Path,
+    into: Option<&Path>,
+    selection: &Selection,
+) -> Result<RestoreReport> {
+    let entries: Vec<&ManifestEntry> = manifest
+        .entries
+        .iter()
+        .filter(|entry| selection.wants(&entry.path))
+        .collect();
+
+    // What to extract from which archive of the chain. The own archive is
+    // always read, to hold the manifest copy against the one inside it.
+    let mut plan: HashMap<&str, HashMap<&str, Vec<&ManifestEntry>>> = HashMap::new();
+    plan.entry(&manifest.backup_id).or_default();
+    for &entry in &entries {
+        let (stored_in, stored_as) = entry.location(&manifest.backup_id);
+        plan.entry(stored_in)
+            .or_default()
+            .entry(stored_as)
+            .or_default()
+            .push(entry);
+    }
+
+    let mut extracted: HashMap<&str, (u64, String)> = HashMap::new();
+    for (backup_id, wanted) in &plan {
+        let own = *backup_id == manifest.backup_id;
+        let source = if own {
+            archive.to_path_buf()
+        } else {
+            chain_archive(archive, backup_id)
+        };
+        let file = File::open(&source).with_context(|| {
+            format!(
+                "backup {} of the chain is not available at {}",
+                backup_id,
+                source.display()
+            )
+        })?;
+        let mut embedded = None;
+        archive::read_entries(BufReader::new(file), |path, _, content| {
+            if path == MANIFEST_ENTRY {
+                if own {
+                    embedded = Some(read_manifest(content)?);
+                }
+                return Ok(());
+            }
+            let Some(targets) = wanted.get(path) else {
+                return Ok(());
+            };
+            // Identical content restored to several paths is extracted once
+            let first = staging.join(safe_relative(&targets[0].path).map_err(invalid_data)?);
+            if let Some(parent) = first.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut hashing = HashingReader::new(content);
+            let mut output = BufWriter::new(File::create(&first)?);
+            io::copy(&mut hashing, &mut output)?;
+            output.flush()?;
+            let found = hashing.finish();
+            for target in &targets[1..] {
+                let copy = staging.join(safe_relative(&target.path).map_err(invalid_data)?);
+                if let Some(parent) = copy.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::copy(&first, &copy)?;
+            }
+            for target in targets {
+                extracted.insert(&target.path, found.clone());
+            }
+            Ok(())
+        })
+        .with_context(|| format!("cannot read backup {}", backup_id))?;
+
+        if own && embedded.as_ref() != Some(manifest) {
+            bail!(
+                "manifest of backup {} does not match its archive",
+                backup_id
+            );
+        }
+    }
+
+    for entry in &entries {
+        match extracted.get(entry.path.as_str()) {
+            Some((size, sha256)) if *size == entry.size && *sha256 == entry.sha256 => {}
+            Some(_) => bail!("{} does not match its checksum", entry.path),
+            None => bail!("{} is missing from the archive", entry.path),
+        }
+    }
+
+    let mut report = RestoreReport {
+        backup_id: manifest.backup_id.clone(),
+        files_restored: 0,
+        bytes_restored: 0,
+        database: DatabaseRestore::Skipped,
+    };
+    for entry in entries {
+        let staged = staging.join(&entry.path);
+        let Some(relative) = entry.data_path() else {
+            let live = data_dir.join(DATABASE_FILE);
+            if live.exists() {
+                fs::rename(&staged, data_dir.join(STAGED_DATABASE))?;
+                report.database = DatabaseRestore::Staged;
+            } else {
+                remove_journal(data_dir)?;
+                fs::rename(&staged, &live)?;
+                report.database = DatabaseRestore::Restored;
+            }
+            continue;
+        };
+
+        let target = match into {
+            Some(into) => data_dir.join(into).join(relative),
+            None => data_dir.join(relative),
+        };
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&staged, &target).with_context(|| format!("cannot restore {}", relative))?;
+        report.files_restored += 1;
+        report.bytes_restored += entry.size;
+    }
+    Ok(report)
+}
+
+/// Read and check every archive of the chain ending in `archive` against
+/// its manifest, and every reference between them. Returns the links from
+/// `archive` back to the full backup of the chain.
+pub async fn verify(archive: &Path) -> Result<Vec<Archive>> {
+    let archive = archive.to_path_buf();
+    tokio::task::spawn_blocking(move || verify_chain(&archive)).await?
+}
+
+fn verify_chain(archive: &Path) -> Result<Vec<Archive>> {
+    let mut links: Vec<Archive> = Vec::new();
+    // (backup, archive path) -> (size, SHA-256) of all content of the chain
+    let mut contents = HashMap::new();
+    let mut next = (archive.to_path_buf(), None::<String>);
+    loop {
+        let (path, expected) = next;
+        let (link, found) = verify_link(&path)?;
+        let backup_id = link.manifest.backup_id.clone();
+        if let Some(expected) = expected
+            && expected != backup_id
+        {
+            bail!(
+                "{} holds backup {} instead of {}",
+                path.display(),
+                backup_id,
+                expected
+            );
+        }
+        contents.extend(
+            found
+                .into_iter()
+                .map(|(path, content)| ((backup_id.clone(), path), content)),
+        );
+        let parent = link.manifest.parent.clone();
+        links.push(link);
+
+        let Some(parent) = parent else { break };
+        if links.iter().any(|link| link.manifest.backup_id == parent) {
+            bail!("backup chain loops back to {}", parent);
+        }
+        next = (chain_archive(archive, &parent), Some(parent));
+    }
+
+    for link in &links {
+        let backup_id = &link.manifest.backup_id;
+        for entry in link
+            .manifest
+            .entries
+            .iter()
+            .filter(|entry| !entry.is_stored())
+        {
+            let (stored_in, stored_as) = entry.location(backup_id);
+            match contents.get(&(stored_in.to_string(), stored_as.to_string())) {
+                Some((size, sha256)) if *size == entry.size && *sha256 == entry.sha256 => {}
+                _ => bail!(
+                    "{} of backup {} refers to content missing from backup {}",
+                    entry.path,
+                    backup_id,
+                    stored_in
+                ),
+            }
+        }
+    }
+    Ok(links)
+}
+
+/// Size and SHA-256 of the content stored in an archive, by path
+type Contents = HashMap<String, (u64, String)>;
+
+/// Check the content stored in one archive; returns it with the size and
+/// SHA-256 of every entry
+fn verify_link(archive: &Path) -> Result<(Archive, Contents)> {
+    let file = File::open(archive).with_context(|| format!("cannot open {}", archive.display()))?;
+    let mut hashing = HashingReader::new(BufReader::new(file));
+    let mut manifest = None;
+    let mut found = HashMap::new();
+    archive::read_entries(&mut hashing, |path, _, content| {
+        if path == MANIFEST_ENTRY {
+            manifest = Some(read_manifest(content)?);
+        } else {
+            let mut entry = HashingReader::new(content);
+            io::copy(&mut entry, &mut io::sink())?;
+            found.insert(path.to_string(), entry.finish());
+        }
+        Ok(())
+    })
+    .context("cannot read archive")?;
+    // Whatever follows the tar trailer still counts towards the checksum
+    io::copy(&mut hashing, &mut io::sink())?;
+
+    let manifest = manifest.ok_or_else(|| anyhow!("archive has no manifest"))?;
+    for entry in manifest.entries.iter().filter(|entry| entry.is_stored()) {
+        match found.get(&entry.path) {
+            Some((size, sha256)) if *size == entry.size && *sha256 == entry.sha256 => {}
+            Some(_) => bail!("{} does not match its checksum", entry.path),
+            None => bail!("{} is missing from the archive", entry.path),
+        }
+    }
+    if let Some(path) = found.keys().find(|path| {
+        !manifest
+            .entries
+            .iter()
+            .any(|entry| entry.is_stored() && entry.path == **path)
+    }) {
+        bail!("{} is not listed in the manifest", path);
+    }
+
+    let (size, sha256) = hashing.finish();
+    Ok((
+        Archive {
+            manifest,
+            size,
+            sha256,
+        },
+        found,
+    ))
+}
+
+/// The manifest of `archive`, from the copy next to it if there is one
+pub async fn read_manifest_of(archive: &Path) -> Result<Manifest> {
+    let archive = archive.to_path_buf();
+    tokio::task::spawn_blocking(move || read_manifest_blocking(&archive)).await?
+}
+
+fn read_manifest_blocking(archive: &Path) -> Result<Manifest> {
+    if let Ok(file) = File::open(manifest_path(archive)) {
+        return Ok(read_manifest(&mut BufReader::new(file))?);
+    }
+    let file = File::open(archive).with_context(|| format!("cannot open {}", archive.display()))?;
+    let mut manifest = None;
+    archive::read_entries(BufReader::new(file), |path, _, content| {
+        if path == MANIFEST_ENTRY {
+            manifest = Some(read_manifest(content)?);
+        }
+        Ok(())
+    })
+    .context("cannot read archive")?;
+    manifest.ok_or_else(|| anyhow!("archive has no manifest"))
+}
+
+fn write_manifest_copy(archive: &Path, manifest: &Manifest) -> io::Result<()> {
+    let path = manifest_path(archive);
+    let temp = suffixed(&path, ".tmp");
+    fs::write(&temp, serde_json::to_vec(manifest).map_err(invalid_data)?)?;
+    fs::rename(&temp, &path)
+}
+
+fn read_manifest(content: &mut dyn Read) -> io::Result<Manifest> {
+    let manifest: Manifest = serde_json::from_reader(content).map_err(invalid_data)?;
+    if manifest.format > FORMAT_VERSION {
+        return Err(invalid_data(anyhow!(
+            "backup format {} is newer than this version supports",
+            manifest.format
+        )));
+    }
+    Ok(manifest)
+}
+
+/// Put a database staged by [`restore`] in place; must run before the
+/// database is opened. The replaced database is kept as
+/// `syncspace.db.pre-restore`. Returns whether a database was staged.
+pub fn apply_staged_database(data_dir: &Path) -> io::Result<bool> {
+    let staged = data_dir.join(STAGED_DATABASE);
+    if !staged.exists() {
+        return Ok(false);
+    }
+    let live = data_dir.join(DATABASE_FILE);
+    if live.exists() {
+        // Move the journal along so the kept copy stays complete
+        for suffix in ["", "-wal", "-shm"] {
+            let from = data_dir.join(format!("{}{}", DATABASE_FILE, suffix));
+            let to = data_dir.join(format!("{}{}", REPLACED_DATABASE, suffix));
+            match fs::rename(&from, &to) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+    }
+    remove_journal(data_dir)?;
+    fs::rename(&staged, &live)?;
+    Ok(true)
+}
+
+/// A journal left next to a replaced database would be applied to it
+fn remove_journal(data_dir: &Path) -> io::Result<()> {
+    for suffix in ["-wal", "-shm"] {
+        match fs::remove_file(data_dir.join(format!("{}{}", DATABASE_FILE, suffix))) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Archive paths must stay below the directory they are extracted into
+fn safe_relative(path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    if path.is_empty()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("unsafe path '{}'", path);
+    }
+    Ok(relative.to_path_buf())
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn mtime(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_and_selection() {
+        assert!(safe_relative("data/a/b.txt").is_ok());
+        assert!(safe_relative("").is_err());
+        assert!(safe_relative("../etc/passwd").is_err());
+        assert!(safe_relative("data/../../x").is_err());
+        assert!(safe_relative("/etc/passwd").is_err());
+
+        assert!(is_skipped("syncspace.db-wal"));
+        assert!(is_skipped("backups"));
+        assert!(!is_skipped("Documents"));
+        assert_eq!(classify("versions/f1/v1"), EntryKind::Version);
+        assert_eq!(classify("Documents/versions"), EntryKind::File);
+
+        let options = RestoreOptions {
+            files: true,
+            versions: true,
+            paths: Some(vec!["Docs".to_string()]),
+            ..Default::default()
+        };
+        let selection = Selection {
+            options: &options,
+            paths: options.paths.clone(),
+        };
+        assert!(selection.wants("data/Docs"));
+        assert!(selection.wants("data/Docs/a.txt"));
+        assert!(!selection.wants("data/Docs2/a.txt"));
+        assert!(!selection.wants("data/versions/f1/v1"));
+        assert!(!selection.wants(DATABASE_ENTRY));
+    }
+}
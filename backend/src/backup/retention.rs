@@ -0,0 +1,205 @@
+//! Grandfather-father-son retention
+//!
+//! A schedule keeps the newest backup of each of its last `keep_daily` days,
+//! `keep_weekly` ISO weeks, `keep_monthly` months and `keep_yearly` years
+//! that have backups. Schedules without any of these keep the backups of the
+//! last `retention_days` days instead. The newest backup is always kept, and
+//! so is every link an incremental backup that is kept was taken against.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Gfs {
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+    pub yearly: u32,
+}
+
+impl Gfs {
+    pub fn is_empty(&self) -> bool {
+        self.daily == 0 && self.weekly == 0 && self.monthly == 0 && self.yearly == 0
+    }
+}
+
+/// Key of the day, week, month or year a backup was taken in
+type Period = fn(&DateTime<Utc>) -> String;
+
+/// Ids of the backups `policy` keeps out of `backups` (id, creation time)
+pub fn keep(backups: &[(String, DateTime<Utc>)], policy: Gfs) -> HashSet<String> {
+    let mut newest_first: Vec<&(String, DateTime<Utc>)> = backups.iter().collect();
+    newest_first.sort_by_key(|(_, at)| Reverse(*at));
+
+    let periods: [(u32, Period); 4] = [
+        (policy.daily, |at| at.format("%Y-%m-%d").to_string()),
+        (policy.weekly, |at| at.format("%G-W%V").to_string()),
+        (policy.monthly, |at| at.format("%Y-%m").to_string()),
+        (policy.yearly, |at| at.format("%Y").to_string()),
+    ];
+    let mut kept = HashSet::new();
+    for (count, period) in periods {
+        let mut seen = HashSet::new();
+        for (id, at) in &newest_first {
+            if seen.len() == count as usize {
+                break;
+            }
+            if seen.insert(period(at)) {
+                kept.insert(id.clone());
+            }
+        }
+    }
+    kept
+}
+
+#[derive(sqlx::FromRow)]
+struct Policy {
+    keep_daily: Option<i64>,
+    keep_weekly: Option<i64>,
+    keep_monthly: Option<i64>,
+    keep_yearly: Option<i64>,
+    retention_days: Option<i64>,
+}
+
+/// Delete the backups of `schedule_id` its retention does not keep; returns
+/// their ids
+pub async fn apply(
+    pool: &SqlitePool,
+    schedule_id: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Vec<String>> {
+    let policy: Option<Policy> = sqlx::query_as(
+        "SELECT keep_daily, keep_weekly, keep_monthly, keep_yearly, retention_days
+         FROM backup_schedules WHERE id = ?",
+    )
+    .bind(schedule_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some(policy) = policy else {
+        return Ok(Vec::new());
+    };
+    let count = |keep: Option<i64>| keep.unwrap_or(0).clamp(0, u32::MAX as i64) as u32;
+    let gfs = Gfs {
+        daily: count(policy.keep_daily),
+        weekly: count(policy.keep_weekly),
+        monthly: count(policy.keep_monthly),
+        yearly: count(policy.keep_yearly),
+    };
+    let retention_days = policy.retention_days;
+
+    let rows: Vec<(String, String, Option<String>)> = sqlx::query_as(
+        "SELECT id, created_at, parent_backup_id FROM backups
+         WHERE schedule_id = ? AND status = 'completed'",
+    )
+    .bind(schedule_id)
+    .fetch_all(pool)
+    .await?;
+    // Backups with an unreadable date are never pruned
+    let mut kept: HashSet<String> = HashSet::new();
+    let mut dated = Vec::new();
+    for (id, created_at, _) in &rows {
+        match DateTime::parse_from_rfc3339(created_at) {
+            Ok(at) => dated.push((id.clone(), at.with_timezone(&Utc))),
+            Err(_) => {
+                kept.insert(id.clone());
+            }
+        }
+    }
+
+    if gfs.is_empty() {
+        let Some(days) = retention_days.filter(|days| *days > 0) else {
+            return Ok(Vec::new());
+        };
+        let cutoff = now - Duration::days(days);
+        kept.extend(
+            dated
+                .iter()
+                .filter(|(_, at)| *at >= cutoff)
+                .map(|(id, _)| id.clone()),
+        );
+    } else {
+        kept.extend(keep(&dated, gfs));
+    }
+    if let Some((newest, _)) = dated.iter().max_by_key(|(_, at)| *at) {
+        kept.insert(newest.clone());
+    }
+
+    // A kept link needs every link before it
+    let parents: HashMap<&str, &str> = rows
+        .iter()
+        .filter_map(|(id, _, parent)| Some((id.as_str(), parent.as_deref()?)))
+        .collect();
+    let mut pending: Vec<String> = kept.iter().cloned().collect();
+    while let Some(id) = pending.pop() {
+        if let Some(parent) = parents.get(id.as_str())
+            && kept.insert(parent.to_string())
+        {
+            pending.push(parent.to_string());
+        }
+    }
+    // Newest first, so an interrupted cleanup never leaves a link without
+    // the one it was taken against
+    dated.sort_by_key(|(_, at)| Reverse(*at));
+    let mut deleted = Vec::new();
+    for (id, _) in dated.iter().filter(|(id, _)| !kept.contains(id)) {
+        super::chain::delete(pool, id).await?;
+        deleted.push(id.clone());
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_gfs_keeps_newest_per_period() {
+        // Two backups a day for the first 60 days of 2026
+        let start = Utc.with_ymd_and_hms(2026, 1, 1, 1, 0, 0).unwrap();
+        let backups: Vec<(String, DateTime<Utc>)> = (0..120)
+            .map(|i| {
+                let at = start + Duration::hours(12 * i);
+                (at.to_rfc3339(), at)
+            })
+            .collect();
+        let id = |y, m, d, h| Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap().to_rfc3339();
+
+        let kept = keep(
+            &backups,
+            Gfs {
+                daily: 3,
+                weekly: 2,
+                monthly: 3,
+                yearly: 0,
+            },
+        );
+        let expected: HashSet<String> = [
+            // Days
+            id(2026, 3, 1, 13),
+            id(2026, 2, 28, 13),
+            id(2026, 2, 27, 13),
+            // ISO weeks: 2026-W09 ends on Sunday 1 March, W08 on 22 February
+            id(2026, 2, 22, 13),
+            // Months: March and February are covered by the days already
+            id(2026, 1, 31, 13),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(kept, expected);
+
+        let yearly = Gfs {
+            yearly: 5,
+            ..Default::default()
+        };
+        assert_eq!(
+            keep(&backups, yearly),
+            [id(2026, 3, 1, 13)].into_iter().collect()
+        );
+        assert!(keep(&backups, Gfs::default()).is_empty());
+    }
+}
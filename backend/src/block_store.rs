@@ -0,0 +1,759 @@
+//! Content-Addressed Block Store
+//! Deduplicated storage for versions using content-defined chunking
+//!
+//! Enabled with `SYNCSPACE_STORAGE_MODE=dedup`. Content is split into
+//! variable-size chunks (FastCDC, ~1 MiB average) that are stored once under
+//! `./data/.blocks/<aa>/<sha256>` and reference counted. A manifest lists the
+//! blocks of one piece of content and is keyed by the SHA-256 of the whole
+//! content, so identical content shares storage.
+//!
+//! Uploads, copies, versions and backups all share blocks. Every file written
+//! through the storage layer (uploads, copies, files picked up by the
+//! watcher) keeps its content here, referenced from `file_block_refs`; a copy
+//! or a file uploaded twice only takes another reference on the manifest.
+//! Versions reference manifests from `file_versions` and so share the blocks
+//! of the files they were taken from. Backups used to be stored here as well
+//! and are archives now (see crate::backup), but the blocks of older backups
+//! stay referenced through `backup_block_refs` until those backups are
+//! deleted. The working tree under `./data` is what WebDAV, search and
+//! previews read; a missing working copy is restored from its manifest (see
+//! [`restore_live_file`]). [`stats`] reports the savings over all of them.
+//!
+//! Content is read back as a stream ([`open_manifest`]), one block at a time.
+//!
+//! Reference counts are released eagerly where files, versions and backups
+//! are deleted, and garbage collection recounts them from `file_block_refs`,
+//! `file_versions` and `backup_block_refs` so rows removed by bulk cleanups
+//! are reclaimed too.
+
+use anyhow::{Result, anyhow};
+use bytes::{Bytes, BytesMut};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::RwLock;
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
+
+pub const BLOCK_STORE_DIR: &str = "./data/.blocks";
+/// Prefix used in `storage_path` columns for content held by the block store
+pub const MANIFEST_PREFIX: &str = "blocks:";
+
+const MIN_CHUNK_SIZE: usize = 256 * 1024;
+const AVG_CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+// Normalized chunking: harder to cut before the average size, easier after it
+const MASK_SMALL: u64 = !0u64 << (64 - 22);
+const MASK_LARGE: u64 = !0u64 << (64 - 18);
+/// Unreferenced blocks younger than this are kept for in-flight stores
+const GC_GRACE_SECS: i64 = 3600;
+
+/// Gear hash table, generated with splitmix64 so chunk boundaries are stable
+/// across builds and platforms
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5359_4e43_5350_4143;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageMode {
+    Plain,
+    Dedup,
+}
+
+/// Storage mode from `SYNCSPACE_STORAGE_MODE` (read once)
+pub fn storage_mode() -> StorageMode {
+    static MODE: OnceLock<StorageMode> = OnceLock::new();
+    *MODE.get_or_init(|| match std::env::var("SYNCSPACE_STORAGE_MODE") {
+        Ok(value) if value.eq_ignore_ascii_case("dedup") => StorageMode::Dedup,
+        _ => StorageMode::Plain,
+    })
+}
+
+pub fn dedup_enabled() -> bool {
+    storage_mode() == StorageMode::Dedup
+}
+
+/// Stores take the read side, garbage collection the write side, so a block
+/// written by a store is never collected before its manifest references it
+fn gc_lock() -> &'static RwLock<()> {
+    static LOCK: OnceLock<RwLock<()>> = OnceLock::new();
+    LOCK.get_or_init(|| RwLock::new(()))
+}
+
+/// Result of storing a piece of content
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestInfo {
+    pub id: String,
+    pub total_size: i64,
+    pub block_count: i64,
+    /// Bytes that were not already present in the block store
+    pub new_bytes: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockStoreStats {
+    pub mode: StorageMode,
+    pub block_count: i64,
+    pub physical_bytes: i64,
+    pub manifest_count: i64,
+    pub logical_bytes: i64,
+    pub savings_bytes: i64,
+    pub dedup_ratio: f64,
+}
+
+/// Length of the next chunk at the start of `data`.
+///
+/// `data` must hold at least `MAX_CHUNK_SIZE` bytes unless it is the end of
+/// the content, otherwise the cut point depends on the buffer size.
+pub fn next_chunk_len(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+
+    let max = data.len().min(MAX_CHUNK_SIZE);
+    let normal = max.min(AVG_CHUNK_SIZE);
+    let mut hash: u64 = 0;
+    let mut i = MIN_CHUNK_SIZE;
+
+    while i < normal {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & MASK_SMALL == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+
+    while i < max {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & MASK_LARGE == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+
+    max
+}
+
+/// One content-defined chunk of a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub hash: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Split a file into the chunks the block store would use, without storing
+/// anything. Returns the chunks and the SHA-256 of the whole content.
+pub async fn chunk_file(path: &Path) -> Result<(Vec<ChunkRef>, String)> {
+    let mut reader = tokio::fs::File::open(path).await?;
+    let mut buf = BytesMut::with_capacity(MAX_CHUNK_SIZE * 2);
+    let mut content_hasher = Sha256::new();
+    let mut chunks = Vec::new();
+    let mut offset = 0u64;
+    let mut eof = false;
+
+    loop {
+        while !eof && buf.len() < MAX_CHUNK_SIZE {
+            buf.reserve(MAX_CHUNK_SIZE);
+            if reader.read_buf(&mut buf).await? == 0 {
+                eof = true;
+            }
+        }
+        if buf.is_empty() {
+            break;
+        }
+
+        let chunk = buf.split_to(next_chunk_len(&buf));
+        content_hasher.update(&chunk);
+        chunks.push(ChunkRef {
+            hash: sha256_hex(&chunk),
+            offset,
+            size: chunk.len() as u64,
+        });
+        offset += chunk.len() as u64;
+    }
+
+    Ok((chunks, format!("{:x}", content_hasher.finalize())))
+}
+
+/// Extract the manifest id from a `blocks:<id>` storage path
+pub fn manifest_id(storage_path: &str) -> Option<&str> {
+    storage_path.strip_prefix(MANIFEST_PREFIX)
+}
+
+pub fn storage_path_for(manifest_id: &str) -> String {
+    format!("{}{}", MANIFEST_PREFIX, manifest_id)
+}
+
+fn block_path(hash: &str) -> PathBuf {
+    Path::new(BLOCK_STORE_DIR).join(&hash[..2]).join(hash)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Ensure a block row and file exist. Returns true if the block was new.
+async fn put_block(pool: &SqlitePool, hash: &str, data: &[u8]) -> Result<bool> {
+    // Refresh created_at of unreferenced blocks so GC honours the grace period
+    sqlx::query(
+        r#"
+        INSERT INTO storage_blocks (hash, size_bytes, ref_count, created_at)
+        VALUES (?, ?, 0, ?)
+        ON CONFLICT(hash) DO UPDATE SET created_at = CASE
+            WHEN storage_blocks.ref_count = 0 THEN excluded.created_at
+            ELSE storage_blocks.created_at
+        END
+        "#,
+    )
+    .bind(hash)
+    .bind(data.len() as i64)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+
+    let path = block_path(hash);
+    if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        return Ok(false);
+    }
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+    tokio::fs::write(&tmp_path, data).await?;
+    if let Err(e) = tokio::fs::rename(&tmp_path, &path).await {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return Err(e.into());
+    }
+
+    Ok(true)
+}
+
+/// Store a file from disk
+pub async fn store_file(pool: &SqlitePool, path: &Path) -> Result<ManifestInfo> {
+    let file = tokio::fs::File::open(path).await?;
+    store_reader(pool, file).await
+}
+
+/// Store content from a reader, holding at most two maximum-size chunks in memory
+pub async fn store_reader<R: AsyncRead + Unpin>(
+    pool: &SqlitePool,
+    mut reader: R,
+) -> Result<ManifestInfo> {
+    let _guard = gc_lock().read().await;
+
+    let mut buf = BytesMut::with_capacity(MAX_CHUNK_SIZE * 2);
+    let mut content_hasher = Sha256::new();
+    let mut entries: Vec<(String, i64)> = Vec::new();
+    let mut total_size: i64 = 0;
+    let mut new_bytes: i64 = 0;
+    let mut eof = false;
+
+    loop {
+        while !eof && buf.len() < MAX_CHUNK_SIZE {
+            buf.reserve(MAX_CHUNK_SIZE);
+            if reader.read_buf(&mut buf).await? == 0 {
+                eof = true;
+            }
+        }
+        if buf.is_empty() {
+            break;
+        }
+
+        let chunk = buf.split_to(next_chunk_len(&buf));
+        content_hasher.update(&chunk);
+        let hash = sha256_hex(&chunk);
+        if put_block(pool, &hash, &chunk).await? {
+            new_bytes += chunk.len() as i64;
+        }
+        entries.push((hash, total_size));
+        total_size += chunk.len() as i64;
+    }
+
+    let id = format!("{:x}", content_hasher.finalize());
+    let block_count = entries.len() as i64;
+    let now = Utc::now().to_rfc3339();
+    let mut tx = pool.begin().await?;
+
+    let existing = sqlx::query(
+        "UPDATE block_manifests SET ref_count = ref_count + 1, last_ref_at = ? WHERE id = ?",
+    )
+    .bind(&now)
+    .bind(&id)
+    .execute(&mut *tx)
+    .await?;
+
+    if existing.rows_affected() == 0 {
+        sqlx::query(
+            "INSERT INTO block_manifests (id, total_size, block_count, ref_count, created_at, last_ref_at) VALUES (?, ?, ?, 1, ?, ?)",
+        )
+        .bind(&id)
+        .bind(total_size)
+        .bind(block_count)
+        .bind(&now)
+        .bind(&now)
+        .execute(&mut *tx)
+        .await?;
+
+        for (seq, (hash, offset)) in entries.iter().enumerate() {
+            sqlx::query(
+                "INSERT INTO block_manifest_entries (manifest_id, seq, block_hash, block_offset) VALUES (?, ?, ?, ?)",
+            )
+            .bind(&id)
+            .bind(seq as i64)
+            .bind(hash)
+            .bind(offset)
+            .execute(&mut *tx)
+            .await?;
+
+            sqlx::query("UPDATE storage_blocks SET ref_count = ref_count + 1 WHERE hash = ?")
+                .bind(hash)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(ManifestInfo {
+        id,
+        total_size,
+        block_count,
+        new_bytes,
+    })
+}
+
+/// Drop one reference on a manifest; the last reference releases its blocks
+pub async fn release_manifest(pool: &SqlitePool, manifest_id: &str) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        "UPDATE block_manifests SET ref_count = ref_count - 1 WHERE id = ? AND ref_count > 0",
+    )
+    .bind(manifest_id)
+    .execute(&mut *tx)
+    .await?;
+
+    let remaining: Option<i64> =
+        sqlx::query_scalar("SELECT ref_count FROM block_manifests WHERE id = ?")
+            .bind(manifest_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+    if remaining == Some(0) {
+        // A block may appear several times in one manifest; drop one ref per entry
+        sqlx::query(
+            r#"
+            UPDATE storage_blocks SET ref_count = MAX(ref_count - (
+                SELECT COUNT(*) FROM block_manifest_entries e
+                WHERE e.manifest_id = ? AND e.block_hash = storage_blocks.hash
+            ), 0)
+            WHERE hash IN (SELECT block_hash FROM block_manifest_entries WHERE manifest_id = ?)
+            "#,
+        )
+        .bind(manifest_id)
+        .bind(manifest_id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM block_manifest_entries WHERE manifest_id = ?")
+            .bind(manifest_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM block_manifests WHERE id = ?")
+            .bind(manifest_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Take another reference on stored content, if there is a manifest for it
+async fn add_reference(pool: &SqlitePool, manifest_id: &str) -> Result<Option<ManifestInfo>> {
+    let _guard = gc_lock().read().await;
+    let row: Option<(i64, i64)> = sqlx::query_as(
+        "UPDATE block_manifests SET ref_count = ref_count + 1, last_ref_at = ?
+         WHERE id = ? RETURNING total_size, block_count",
+    )
+    .bind(Utc::now().to_rfc3339())
+    .bind(manifest_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|(total_size, block_count)| ManifestInfo {
+        id: manifest_id.to_string(),
+        total_size,
+        block_count,
+        new_bytes: 0,
+    }))
+}
+
+/// Keep the content of a live file, written to `local_path` with the given
+/// checksum, in place of what was kept for the file before. Content that is
+/// already stored (a copy, a file uploaded twice) is not read again.
+pub async fn store_live_file(
+    pool: &SqlitePool,
+    file_id: &str,
+    local_path: &Path,
+    checksum_sha256: &str,
+) -> Result<ManifestInfo> {
+    let manifest = match add_reference(pool, checksum_sha256).await? {
+        Some(manifest) => manifest,
+        None => store_file(pool, local_path).await?,
+    };
+
+    let previous: Option<String> =
+        sqlx::query_scalar("SELECT manifest_id FROM file_block_refs WHERE file_id = ?")
+            .bind(file_id)
+            .fetch_optional(pool)
+            .await?;
+    sqlx::query(
+        "INSERT INTO file_block_refs (file_id, manifest_id, created_at) VALUES (?, ?, ?)
+         ON CONFLICT(file_id) DO UPDATE SET
+             manifest_id = excluded.manifest_id, created_at = excluded.created_at",
+    )
+    .bind(file_id)
+    .bind(&manifest.id)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    if let Some(previous) = previous {
+        release_manifest(pool, &previous).await?;
+    }
+
+    Ok(manifest)
+}
+
+/// Drop what is kept for a file that is purged or whose content is replaced
+pub async fn release_live_file(pool: &SqlitePool, file_id: &str) -> Result<()> {
+    let manifest_id: Option<String> =
+        sqlx::query_scalar("DELETE FROM file_block_refs WHERE file_id = ? RETURNING manifest_id")
+            .bind(file_id)
+            .fetch_optional(pool)
+            .await?;
+    if let Some(manifest_id) = manifest_id {
+        release_manifest(pool, &manifest_id).await?;
+    }
+    Ok(())
+}
+
+/// Write the working copy of the file at `path` (relative to `./data`) to
+/// `local_path` from the block store. Returns false if the store holds no
+/// current content for it.
+pub async fn restore_live_file(pool: &SqlitePool, path: &str, local_path: &Path) -> Result<bool> {
+    // The manifest id is the checksum of the content, so a stale reference
+    // (content replaced outside the storage layer) is never restored
+    let manifest_id: Option<String> = sqlx::query_scalar(
+        "SELECT r.manifest_id FROM files f JOIN file_block_refs r ON r.file_id = f.id
+         WHERE f.path = ? AND f.is_deleted = 0 AND r.manifest_id = f.checksum_sha256
+         LIMIT 1",
+    )
+    .bind(path)
+    .fetch_optional(pool)
+    .await?;
+    let Some(manifest_id) = manifest_id else {
+        return Ok(false);
+    };
+
+    if let Some(parent) = local_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = local_path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+    let mut reader = open_manifest(pool, &manifest_id).await?;
+    let written = async {
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        tokio::io::copy(&mut reader, &mut file).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, local_path).await
+    }
+    .await;
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return Err(e.into());
+    }
+    Ok(true)
+}
+
+/// Open the content of a manifest as a stream. Blocks are read one at a
+/// time and verified as they are read; a missing or corrupt block, or
+/// content of the wrong size, ends the stream with an error.
+pub async fn open_manifest(
+    pool: &SqlitePool,
+    manifest_id: &str,
+) -> Result<impl AsyncRead + Send + Unpin + 'static> {
+    let total_size: Option<i64> =
+        sqlx::query_scalar("SELECT total_size FROM block_manifests WHERE id = ?")
+            .bind(manifest_id)
+            .fetch_optional(pool)
+            .await?;
+    let total_size = total_size.ok_or_else(|| anyhow!("Manifest {} not found", manifest_id))?;
+
+    let hashes: Vec<String> = sqlx::query_scalar(
+        "SELECT block_hash FROM block_manifest_entries WHERE manifest_id = ? ORDER BY seq",
+    )
+    .bind(manifest_id)
+    .fetch_all(pool)
+    .await?;
+
+    let manifest_id = manifest_id.to_string();
+    let blocks = futures_util::stream::try_unfold(
+        (hashes.into_iter(), 0u64),
+        move |(mut hashes, read)| {
+            let manifest_id = manifest_id.clone();
+            async move {
+                let Some(hash) = hashes.next() else {
+                    if read != total_size as u64 {
+                        return Err(std::io::Error::other(format!(
+                            "Manifest {} restored {} bytes, expected {}",
+                            manifest_id, read, total_size
+                        )));
+                    }
+                    return Ok(None);
+                };
+                let data = tokio::fs::read(block_path(&hash)).await?;
+                if sha256_hex(&data) != hash {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Block {} is corrupt", hash),
+                    ));
+                }
+                let read = read + data.len() as u64;
+                Ok(Some((Bytes::from(data), (hashes, read))))
+            }
+        },
+    );
+    Ok(StreamReader::new(Box::pin(blocks)))
+}
+
+/// Recount manifest references, drop unreferenced manifests and delete
+/// unreferenced blocks older than the grace period. Returns (blocks, bytes) removed.
+pub async fn garbage_collect(pool: &SqlitePool) -> Result<(usize, i64)> {
+    let _guard = gc_lock().write().await;
+    let cutoff = (Utc::now() - chrono::Duration::seconds(GC_GRACE_SECS)).to_rfc3339();
+
+    // Count the references that actually exist
+    let mut live: HashMap<String, i64> = HashMap::new();
+    // Refs of files purged without going through the storage layer are dropped here
+    sqlx::query("DELETE FROM file_block_refs WHERE file_id NOT IN (SELECT id FROM files)")
+        .execute(pool)
+        .await?;
+    let file_refs: Vec<String> = sqlx::query_scalar("SELECT manifest_id FROM file_block_refs")
+        .fetch_all(pool)
+        .await?;
+    for id in file_refs {
+        *live.entry(id).or_insert(0) += 1;
+    }
+    let version_paths: Vec<String> = sqlx::query_scalar(
+        "SELECT storage_path FROM file_versions WHERE storage_path LIKE 'blocks:%'",
+    )
+    .fetch_all(pool)
+    .await?;
+    for path in &version_paths {
+        if let Some(id) = manifest_id(path) {
+            *live.entry(id.to_string()).or_insert(0) += 1;
+        }
+    }
+    // Refs of backups removed by bulk retention cleanups are dropped here
+    sqlx::query("DELETE FROM backup_block_refs WHERE backup_id NOT IN (SELECT id FROM backups)")
+        .execute(pool)
+        .await?;
+    let backup_refs: Vec<String> = sqlx::query_scalar("SELECT manifest_id FROM backup_block_refs")
+        .fetch_all(pool)
+        .await?;
+    for id in backup_refs {
+        *live.entry(id).or_insert(0) += 1;
+    }
+
+    // Manifests referenced recently may belong to a file, version or backup
+    // whose row is still being written, so only settled ones are recounted
+    let settled: Vec<(String, i64)> =
+        sqlx::query_as("SELECT id, ref_count FROM block_manifests WHERE last_ref_at < ?")
+            .bind(&cutoff)
+            .fetch_all(pool)
+            .await?;
+
+    let mut tx = pool.begin().await?;
+    for (id, ref_count) in settled {
+        let actual = live.get(&id).copied().unwrap_or(0);
+        if actual > 0 {
+            if actual != ref_count {
+                sqlx::query("UPDATE block_manifests SET ref_count = ? WHERE id = ?")
+                    .bind(actual)
+                    .bind(&id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            continue;
+        }
+        sqlx::query("DELETE FROM block_manifest_entries WHERE manifest_id = ?")
+            .bind(&id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM block_manifests WHERE id = ?")
+            .bind(&id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    // No store is in flight while the write lock is held, so the manifest
+    // entries are the complete set of block references
+    sqlx::query(
+        r#"
+        UPDATE storage_blocks SET ref_count = (
+            SELECT COUNT(*) FROM block_manifest_entries e WHERE e.block_hash = storage_blocks.hash
+        )
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let candidates: Vec<(String, i64)> = sqlx::query_as(
+        "SELECT hash, size_bytes FROM storage_blocks WHERE ref_count = 0 AND created_at < ?",
+    )
+    .bind(&cutoff)
+    .fetch_all(pool)
+    .await?;
+
+    let mut removed = 0;
+    let mut removed_bytes = 0;
+    for (hash, size) in candidates {
+        sqlx::query("DELETE FROM storage_blocks WHERE hash = ?")
+            .bind(&hash)
+            .execute(pool)
+            .await?;
+        match tokio::fs::remove_file(block_path(&hash)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!("Failed to remove block {}: {}", hash, e),
+        }
+        removed += 1;
+        removed_bytes += size;
+    }
+
+    Ok((removed, removed_bytes))
+}
+
+/// Physical vs. logical usage of the block store: what files, versions and
+/// backups would take stored one by one, and what their blocks take on disk
+pub async fn stats(pool: &SqlitePool) -> Result<BlockStoreStats> {
+    let (block_count, physical_bytes): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*), COALESCE(SUM(size_bytes), 0) FROM storage_blocks WHERE ref_count > 0",
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let (manifest_count, logical_bytes): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*), COALESCE(SUM(total_size * ref_count), 0) FROM block_manifests WHERE ref_count > 0",
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let dedup_ratio = if physical_bytes > 0 {
+        logical_bytes as f64 / physical_bytes as f64
+    } else {
+        1.0
+    };
+
+    Ok(BlockStoreStats {
+        mode: storage_mode(),
+        block_count,
+        physical_bytes,
+        manifest_count,
+        logical_bytes,
+        savings_bytes: (logical_bytes - physical_bytes).max(0),
+        dedup_ratio,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunk_all(data: &[u8]) -> Vec<&[u8]> {
+        let mut chunks = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let len = next_chunk_len(rest);
+            chunks.push(&rest[..len]);
+            rest = &rest[len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_chunk_sizes_within_bounds() {
+        let data = pseudo_random(20 * 1024 * 1024, 42);
+        let chunks = chunk_all(&data);
+        let (last, body) = chunks.split_last().unwrap();
+        assert!(
+            body.iter()
+                .all(|c| c.len() >= MIN_CHUNK_SIZE && c.len() <= MAX_CHUNK_SIZE)
+        );
+        assert!(last.len() <= MAX_CHUNK_SIZE);
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), data.len());
+    }
+
+    #[test]
+    fn test_small_content_single_chunk() {
+        assert_eq!(next_chunk_len(&[]), 0);
+        assert_eq!(next_chunk_len(&[7u8; 1000]), 1000);
+    }
+
+    #[test]
+    fn test_chunks_resync_after_insert() {
+        let original = pseudo_random(16 * 1024 * 1024, 7);
+        let mut edited = b"inserted header bytes".to_vec();
+        edited.extend_from_slice(&original);
+
+        let a: std::collections::HashSet<String> =
+            chunk_all(&original).iter().map(|c| sha256_hex(c)).collect();
+        let b: Vec<String> = chunk_all(&edited).iter().map(|c| sha256_hex(c)).collect();
+
+        let shared = b.iter().filter(|h| a.contains(*h)).count();
+        assert!(
+            shared + 2 >= b.len(),
+            "only {} of {} chunks shared",
+            shared,
+            b.len()
+        );
+    }
+
+    #[test]
+    fn test_manifest_id_prefix() {
+        assert_eq!(manifest_id("blocks:abc"), Some("abc"));
+        assert_eq!(manifest_id("./data/versions/x.dat"), None);
+        assert_eq!(storage_path_for("abc"), "blocks:abc");
+    }
+}
@@ -0,0 +1,586 @@
+//! Rsync-Style Delta Encoding
+//!
+//! Lets a client upload a modified file by sending only what changed. The
+//! server describes its current copy as a signature: the content is split
+//! into fixed-size blocks, each with a weak rolling checksum (as in rsync)
+//! and a strong SHA-256 based hash. The client slides a window over its new
+//! content, looks up the rolling checksum at every offset and emits either a
+//! reference to a matching server block or literal bytes.
+//!
+//! Delta wire format (all integers big-endian):
+//!
+//! ```text
+//! "SSD1" block_size:u32
+//! 0x01 first_block:u64 block_count:u32   copy blocks from the base file
+//! 0x02 length:u32 bytes[length]          literal data
+//! 0x00                                   end of delta
+//! ```
+//!
+//! Encoding and applying both stream: only a window and a read buffer are
+//! held in memory, so memory use does not depend on file size.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+pub const MAGIC: &[u8; 4] = b"SSD1";
+pub const MIN_BLOCK_SIZE: u32 = 1024;
+pub const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+/// Largest literal a single op may carry
+pub const MAX_LITERAL_LEN: u32 = 8 * 1024 * 1024;
+
+const OP_END: u8 = 0x00;
+const OP_COPY: u8 = 0x01;
+const OP_DATA: u8 = 0x02;
+/// Bytes of SHA-256 kept for the per-block strong hash
+const STRONG_HASH_LEN: usize = 16;
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+/// Bytes read ahead of the window while encoding
+const ENCODE_READ_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeltaError {
+    #[error("invalid delta: {0}")]
+    Invalid(String),
+    #[error("reconstructed file exceeds {0} bytes")]
+    TooLarge(u64),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Signature of one base block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: String,
+}
+
+/// Block signatures of a whole file; block `i` starts at `i * block_size`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signature {
+    pub block_size: u32,
+    pub file_size: u64,
+    /// SHA-256 of the whole content, sent back as the upload's base checksum
+    pub checksum: String,
+    pub blocks: Vec<BlockSignature>,
+}
+
+/// Size and checksum of the content a delta describes
+#[derive(Debug, Clone, Default)]
+pub struct DeltaStats {
+    pub size: u64,
+    pub checksum: String,
+    /// Bytes taken from the base file
+    pub copied_bytes: u64,
+    /// Bytes carried as literals
+    pub literal_bytes: u64,
+}
+
+/// Block size for a file, roughly the square root of its size like rsync
+pub fn default_block_size(file_size: u64) -> u32 {
+    let root = (file_size as f64).sqrt() as u64;
+    let rounded = root.div_ceil(1024) * 1024;
+    rounded.clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as u32
+}
+
+/// Adler-32 style checksum over a sliding window (rsync's weak checksum)
+#[derive(Debug, Clone, Copy)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+
+    /// Slide the window one byte forward
+    pub fn roll(&mut self, out: u8, incoming: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(out as u32)
+            .wrapping_add(incoming as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+pub fn strong_hash(block: &[u8]) -> String {
+    hex::encode(&Sha256::digest(block)[..STRONG_HASH_LEN])
+}
+
+/// Incrementally computes the signature of content fed to it in any pieces
+pub struct SignatureBuilder {
+    block_size: u32,
+    pending: Vec<u8>,
+    content_hasher: Sha256,
+    blocks: Vec<BlockSignature>,
+    file_size: u64,
+}
+
+impl SignatureBuilder {
+    pub fn new(block_size: u32) -> Result<Self, DeltaError> {
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(DeltaError::Invalid(format!(
+                "block size must be between {} and {}",
+                MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+            )));
+        }
+        Ok(Self {
+            block_size,
+            pending: Vec::with_capacity(block_size as usize),
+            content_hasher: Sha256::new(),
+            blocks: Vec::new(),
+            file_size: 0,
+        })
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.content_hasher.update(data);
+        self.file_size += data.len() as u64;
+
+        while !data.is_empty() {
+            let take = (self.block_size as usize - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() == self.block_size as usize {
+                self.push_pending();
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Signature {
+        if !self.pending.is_empty() {
+            self.push_pending();
+        }
+        Signature {
+            block_size: self.block_size,
+            file_size: self.file_size,
+            checksum: format!("{:x}", self.content_hasher.finalize()),
+            blocks: self.blocks,
+        }
+    }
+
+    fn push_pending(&mut self) {
+        self.blocks.push(BlockSignature {
+            weak: RollingChecksum::new(&self.pending).digest(),
+            strong: strong_hash(&self.pending),
+        });
+        self.pending.clear();
+    }
+}
+
+/// Compute the signature of a file, streaming it block by block
+pub async fn signature(path: &Path, block_size: u32) -> Result<Signature, DeltaError> {
+    let mut builder = SignatureBuilder::new(block_size)?;
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        builder.update(&buffer[..read]);
+    }
+
+    Ok(builder.finish())
+}
+
+/// Base blocks by weak checksum
+struct BlockIndex<'a> {
+    signature: &'a Signature,
+    by_weak: HashMap<u32, Vec<usize>>,
+    /// Index and length of a last block shorter than `block_size`
+    short_tail: Option<(usize, usize)>,
+}
+
+impl<'a> BlockIndex<'a> {
+    fn new(signature: &'a Signature) -> Self {
+        let block_size = signature.block_size as u64;
+        // Only full-size blocks can match inside the content; a short last
+        // block is only compared against the tail
+        let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, block) in signature.blocks.iter().enumerate() {
+            if (index as u64 + 1) * block_size <= signature.file_size {
+                by_weak.entry(block.weak).or_default().push(index);
+            }
+        }
+        let short_tail = (!signature.blocks.is_empty()
+            && !signature.file_size.is_multiple_of(block_size))
+        .then(|| {
+            (
+                signature.blocks.len() - 1,
+                (signature.file_size % block_size) as usize,
+            )
+        });
+
+        Self {
+            signature,
+            by_weak,
+            short_tail,
+        }
+    }
+
+    fn find(&self, weak: u32, window: &[u8]) -> Option<usize> {
+        let candidates = self.by_weak.get(&weak)?;
+        let strong = strong_hash(window);
+        candidates
+            .iter()
+            .find(|&&index| self.signature.blocks[index].strong == strong)
+            .copied()
+    }
+
+    /// Length of the tail block if `data` ends with it
+    fn tail_match(&self, data: &[u8]) -> Option<(usize, usize)> {
+        let (index, len) = self.short_tail?;
+        (data.len() >= len
+            && strong_hash(&data[data.len() - len..]) == self.signature.blocks[index].strong)
+            .then_some((index, len))
+    }
+}
+
+/// Writes delta ops, merging adjacent block references
+struct DeltaWriter<'a, W> {
+    out: &'a mut W,
+    pending_copy: Option<(u64, u32)>,
+}
+
+impl<'a, W: AsyncWrite + Unpin> DeltaWriter<'a, W> {
+    async fn new(out: &'a mut W, block_size: u32) -> std::io::Result<Self> {
+        out.write_all(MAGIC).await?;
+        out.write_all(&block_size.to_be_bytes()).await?;
+        Ok(Self {
+            out,
+            pending_copy: None,
+        })
+    }
+
+    async fn copy(&mut self, block: u64) -> std::io::Result<()> {
+        match self.pending_copy.as_mut() {
+            Some((first, count)) if *first + *count as u64 == block && *count < u32::MAX => {
+                *count += 1;
+                Ok(())
+            }
+            _ => {
+                self.flush_copy().await?;
+                self.pending_copy = Some((block, 1));
+                Ok(())
+            }
+        }
+    }
+
+    async fn literal(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flush_copy().await?;
+        for part in data.chunks(MAX_LITERAL_LEN as usize) {
+            self.out.write_u8(OP_DATA).await?;
+            self.out
+                .write_all(&(part.len() as u32).to_be_bytes())
+                .await?;
+            self.out.write_all(part).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush_copy(&mut self) -> std::io::Result<()> {
+        if let Some((first, count)) = self.pending_copy.take() {
+            self.out.write_u8(OP_COPY).await?;
+            self.out.write_all(&first.to_be_bytes()).await?;
+            self.out.write_all(&count.to_be_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self) -> std::io::Result<()> {
+        self.flush_copy().await?;
+        self.out.write_u8(OP_END).await?;
+        self.out.flush().await
+    }
+}
+
+/// Encode the content read from `reader` as a delta against the file
+/// described by `signature`, writing the delta to `out`.
+///
+/// Only a window of `block_size` plus one read buffer is held in memory, so
+/// content of any size can be encoded.
+pub async fn encode_stream<R, W>(
+    signature: &Signature,
+    mut reader: R,
+    out: &mut W,
+) -> Result<DeltaStats, DeltaError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let block_size = signature.block_size as usize;
+    let index = BlockIndex::new(signature);
+    let mut writer = DeltaWriter::new(out, signature.block_size).await?;
+    let mut hasher = Sha256::new();
+    let mut stats = DeltaStats::default();
+
+    let mut buf: Vec<u8> = Vec::with_capacity(block_size + ENCODE_READ_SIZE);
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<RollingChecksum> = None;
+
+    loop {
+        // Keep at least one byte beyond the window so it can roll
+        if !eof && buf.len() - pos <= block_size {
+            writer.literal(&buf[literal_start..pos]).await?;
+            stats.literal_bytes += (pos - literal_start) as u64;
+            buf.drain(..pos);
+            pos = 0;
+            literal_start = 0;
+
+            let filled = buf.len();
+            buf.resize(filled + ENCODE_READ_SIZE, 0);
+            let read = reader.read(&mut buf[filled..]).await?;
+            buf.truncate(filled + read);
+            hasher.update(&buf[filled..]);
+            stats.size += read as u64;
+            eof = read == 0;
+            continue;
+        }
+        if buf.len() - pos < block_size {
+            break;
+        }
+
+        let window = &buf[pos..pos + block_size];
+        let checksum = rolling.get_or_insert_with(|| RollingChecksum::new(window));
+        if let Some(block) = index.find(checksum.digest(), window) {
+            writer.literal(&buf[literal_start..pos]).await?;
+            stats.literal_bytes += (pos - literal_start) as u64;
+            writer.copy(block as u64).await?;
+            stats.copied_bytes += block_size as u64;
+            pos += block_size;
+            literal_start = pos;
+            rolling = None;
+        } else if pos + block_size < buf.len() {
+            checksum.roll(buf[pos], buf[pos + block_size]);
+            pos += 1;
+        } else {
+            break;
+        }
+    }
+
+    let rest = &buf[literal_start..];
+    match index.tail_match(rest) {
+        Some((block, len)) => {
+            writer.literal(&rest[..rest.len() - len]).await?;
+            writer.copy(block as u64).await?;
+            stats.literal_bytes += (rest.len() - len) as u64;
+            stats.copied_bytes += len as u64;
+        }
+        None => {
+            writer.literal(rest).await?;
+            stats.literal_bytes += rest.len() as u64;
+        }
+    }
+    writer.finish().await?;
+
+    stats.checksum = format!("{:x}", hasher.finalize());
+    Ok(stats)
+}
+
+/// Encode `data` as a delta against the file described by `signature`
+pub async fn encode(signature: &Signature, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_stream(signature, data, &mut out)
+        .await
+        .expect("encoding into memory does not fail");
+    out
+}
+
+/// Rebuild content from `base` and a delta stream, writing it to `out`.
+///
+/// Stops with [`DeltaError::TooLarge`] as soon as the result would exceed
+/// `max_size`, so a few copy ops can't expand into an unbounded file.
+pub async fn apply<R, W>(
+    base: &Path,
+    mut delta: R,
+    out: &mut W,
+    max_size: u64,
+) -> Result<DeltaStats, DeltaError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut magic = [0u8; 4];
+    read_exact(&mut delta, &mut magic).await?;
+    if &magic != MAGIC {
+        return Err(DeltaError::Invalid("unknown delta format".to_string()));
+    }
+    let block_size = read_u32(&mut delta).await? as u64;
+    if !(MIN_BLOCK_SIZE as u64..=MAX_BLOCK_SIZE as u64).contains(&block_size) {
+        return Err(DeltaError::Invalid(format!(
+            "unsupported block size {}",
+            block_size
+        )));
+    }
+
+    let mut base_file = tokio::fs::File::open(base).await?;
+    let base_size = base_file.metadata().await?.len();
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut result = DeltaStats::default();
+
+    loop {
+        let mut op = [0u8; 1];
+        read_exact(&mut delta, &mut op).await?;
+        match op[0] {
+            OP_END => break,
+            OP_COPY => {
+                let first = read_u64(&mut delta).await?;
+                let count = read_u32(&mut delta).await? as u64;
+                let start = first
+                    .checked_mul(block_size)
+                    .filter(|start| *start < base_size && count > 0)
+                    .ok_or_else(|| {
+                        DeltaError::Invalid(format!("block {} is outside the base file", first))
+                    })?;
+                let end = count
+                    .checked_mul(block_size)
+                    .and_then(|len| start.checked_add(len))
+                    .ok_or_else(|| DeltaError::Invalid("block range overflows".to_string()))?;
+                // The last block of the base may be short
+                if end - block_size >= base_size {
+                    return Err(DeltaError::Invalid(format!(
+                        "blocks {}..{} are outside the base file",
+                        first,
+                        first + count
+                    )));
+                }
+                let len = end.min(base_size) - start;
+                check_size(result.size + len, max_size)?;
+
+                base_file.seek(std::io::SeekFrom::Start(start)).await?;
+                let mut remaining = len;
+                while remaining > 0 {
+                    let want = remaining.min(buffer.len() as u64) as usize;
+                    base_file.read_exact(&mut buffer[..want]).await?;
+                    hasher.update(&buffer[..want]);
+                    out.write_all(&buffer[..want]).await?;
+                    remaining -= want as u64;
+                }
+                result.size += len;
+                result.copied_bytes += len;
+            }
+            OP_DATA => {
+                let len = read_u32(&mut delta).await?;
+                if len > MAX_LITERAL_LEN {
+                    return Err(DeltaError::Invalid(format!(
+                        "literal of {} bytes exceeds {}",
+                        len, MAX_LITERAL_LEN
+                    )));
+                }
+                check_size(result.size + len as u64, max_size)?;
+
+                let mut remaining = len as usize;
+                while remaining > 0 {
+                    let want = remaining.min(buffer.len());
+                    read_exact(&mut delta, &mut buffer[..want]).await?;
+                    hasher.update(&buffer[..want]);
+                    out.write_all(&buffer[..want]).await?;
+                    remaining -= want;
+                }
+                result.size += len as u64;
+                result.literal_bytes += len as u64;
+            }
+            other => {
+                return Err(DeltaError::Invalid(format!("unknown op 0x{:02x}", other)));
+            }
+        }
+    }
+
+    let mut trailing = [0u8; 1];
+    if delta.read(&mut trailing).await? != 0 {
+        return Err(DeltaError::Invalid(
+            "unexpected data after end of delta".to_string(),
+        ));
+    }
+
+    out.flush().await?;
+    result.checksum = format!("{:x}", hasher.finalize());
+    Ok(result)
+}
+
+fn check_size(size: u64, max_size: u64) -> Result<(), DeltaError> {
+    if size > max_size {
+        Err(DeltaError::TooLarge(max_size))
+    } else {
+        Ok(())
+    }
+}
+
+/// `read_exact` that reports a truncated delta as invalid input
+async fn read_exact<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> Result<(), DeltaError> {
+    match reader.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(DeltaError::Invalid("truncated delta".to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn read_u32<R: AsyncRead + Unpin>(reader: &mut R) -> Result<u32, DeltaError> {
+    let mut bytes = [0u8; 4];
+    read_exact(reader, &mut bytes).await?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+async fn read_u64<R: AsyncRead + Unpin>(reader: &mut R) -> Result<u64, DeltaError> {
+    let mut bytes = [0u8; 8];
+    read_exact(reader, &mut bytes).await?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_checksum_matches_fresh_computation() {
+        let data: Vec<u8> = (0..4096u32).map(|i| (i * 31 % 251) as u8).collect();
+        let window = 1024;
+        let mut rolling = RollingChecksum::new(&data[..window]);
+        for pos in 1..data.len() - window {
+            rolling.roll(data[pos - 1], data[pos + window - 1]);
+            assert_eq!(
+                rolling.digest(),
+                RollingChecksum::new(&data[pos..pos + window]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn test_default_block_size_is_bounded() {
+        assert_eq!(default_block_size(0), MIN_BLOCK_SIZE);
+        assert_eq!(default_block_size(500 * 1024 * 1024), 23 * 1024);
+        assert_eq!(default_block_size(u64::MAX), MAX_BLOCK_SIZE);
+    }
+}
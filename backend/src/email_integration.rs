@@ -1,11 +1,12 @@
 //! Email Integration Module
 //! Fetches emails via IMAP/POP3 and stores attachments in SyncSpace
 
-use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use uuid::Uuid;
 
+use crate::secrets;
+
 /// Email account configuration
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct EmailAccount {
@@ -17,6 +18,7 @@ pub struct EmailAccount {
     pub server: String,
     pub port: i32,
     pub username: String,
+    #[serde(skip_serializing)]
     pub password_encrypted: String,
     pub use_tls: bool,
     pub auto_fetch: bool,
@@ -111,7 +113,7 @@ pub async fn create_email_account(
     req: CreateEmailAccountRequest,
 ) -> Result<EmailAccount, sqlx::Error> {
     let id = Uuid::new_v4().to_string();
-    let password_encrypted = general_purpose::STANDARD.encode(&req.password);
+    let password_encrypted = seal_password(&id, &req.password)?;
 
     sqlx::query(
         r#"INSERT INTO email_accounts 
@@ -163,6 +165,11 @@ pub async fn list_email_accounts(
         .await
 }
 
+fn seal_password(account_id: &str, password: &str) -> Result<String, sqlx::Error> {
+    secrets::seal(&secrets::IMAP_PASSWORD, account_id, password)
+        .map_err(|e| sqlx::Error::Protocol(e.to_string()))
+}
+
 /// Update email account
 pub async fn update_email_account(
     pool: &SqlitePool,
@@ -172,10 +179,10 @@ pub async fn update_email_account(
 ) -> Result<EmailAccount, sqlx::Error> {
     let account = get_email_account(pool, id, user_id).await?;
 
-    let password_encrypted = req
-        .password
-        .map(|p| general_purpose::STANDARD.encode(&p))
-        .unwrap_or(account.password_encrypted);
+    let password_encrypted = match req.password {
+        Some(password) => seal_password(id, &password)?,
+        None => account.password_encrypted,
+    };
 
     sqlx::query(
         r#"UPDATE email_accounts SET
@@ -221,10 +228,17 @@ pub async fn delete_email_account(
 
 /// Test email connection
 pub async fn test_email_connection(
+    pool: &SqlitePool,
     account: &EmailAccount,
 ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
-    let password = general_purpose::STANDARD.decode(&account.password_encrypted)?;
-    let _password = String::from_utf8(password)?;
+    let _password = secrets::reveal(
+        pool,
+        &secrets::IMAP_PASSWORD,
+        &account.id,
+        &account.password_encrypted,
+        "email connection test",
+    )
+    .await?;
 
     // For now, just validate the configuration
     // Full IMAP/POP3 testing requires async-imap which has complex dependencies
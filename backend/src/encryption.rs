@@ -1,298 +0,0 @@
-/// File encryption system with AES-256-GCM
-/// Provides end-to-end encryption for files at rest
-use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
-    Aes256Gcm, Nonce, Key
-};
-use argon2::{Argon2, PasswordHasher, PasswordVerifier, password_hash::SaltString};
-use base64::{Engine as _, engine::general_purpose};
-use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
-use uuid::Uuid;
-
-// Encryption key size for AES-256
-const KEY_SIZE: usize = 32;
-
-#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
-pub struct EncryptionKey {
-    pub id: String,
-    pub user_id: String,
-    pub name: String,
-    pub key_hash: String, // Encrypted master key
-    pub salt: String,
-    pub is_active: bool,
-    pub created_at: String,
-    pub last_used_at: Option<String>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
-pub struct EncryptedFile {
-    pub id: String,
-    pub file_id: String,
-    pub key_id: String,
-    pub nonce: String, // Base64-encoded nonce
-    pub encrypted_metadata: Option<String>, // JSON metadata encrypted
-    pub created_at: String,
-}
-
-#[derive(Debug, Clone, Deserialize)]
-pub struct CreateKeyRequest {
-    pub name: String,
-    pub password: String,
-}
-
-#[derive(Debug, Clone, Deserialize)]
-pub struct DecryptRequest {
-    pub file_id: String,
-    pub password: String,
-}
-
-/// Generate a new encryption key from password
-pub async fn create_encryption_key(
-    pool: &SqlitePool,
-    user_id: &str,
-    req: CreateKeyRequest,
-) -> Result<EncryptionKey, Box<dyn std::error::Error + Send + Sync>> {
-    let id = Uuid::new_v4().to_string();
-    
-    // Generate random master key
-    let master_key = Aes256Gcm::generate_key(OsRng);
-    
-    // Derive key from password using Argon2
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    
-    // Hash password for key derivation
-    let password_hash = argon2
-        .hash_password(req.password.as_bytes(), &salt)
-        .map_err(|e| format!("Password hashing failed: {}", e))?;
-    
-    // Encrypt master key with derived password key
-    let derived_key = derive_key_from_password(&req.password, salt.as_str())?;
-    let cipher = Aes256Gcm::new(&derived_key);
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    
-    let encrypted_master_key = cipher
-        .encrypt(&nonce, master_key.as_slice())
-        .map_err(|e| format!("Encryption failed: {}", e))?;
-    
-    // Combine nonce + encrypted key for storage
-    let mut stored_key = nonce.to_vec();
-    stored_key.extend_from_slice(&encrypted_master_key);
-    let key_hash = general_purpose::STANDARD.encode(&stored_key);
-    
-    sqlx::query(
-        "INSERT INTO encryption_keys (id, user_id, name, key_hash, salt, is_active, created_at)
-         VALUES (?, ?, ?, ?, ?, 1, datetime('now'))"
-    )
-    .bind(&id)
-    .bind(user_id)
-    .bind(&req.name)
-    .bind(&key_hash)
-    .bind(salt.as_str())
-    .execute(pool)
-    .await?;
-    
-    let key = sqlx::query_as::<_, EncryptionKey>(
-        "SELECT * FROM encryption_keys WHERE id = ?"
-    )
-    .bind(&id)
-    .fetch_one(pool)
-    .await?;
-    
-    Ok(key)
-}
-
-/// Derive AES key from password using Argon2
-fn derive_key_from_password(password: &str, salt: &str) -> Result<Key<Aes256Gcm>, Box<dyn std::error::Error + Send + Sync>> {
-    use argon2::{Algorithm, Params, Version};
-    
-    let salt_bytes = salt.as_bytes();
-    let argon2 = Argon2::new(
-        Algorithm::Argon2id,
-        Version::V0x13,
-        Params::new(15000, 2, 1, Some(KEY_SIZE)).unwrap()
-    );
-    
-    let mut key = [0u8; KEY_SIZE];
-    argon2.hash_password_into(password.as_bytes(), salt_bytes, &mut key)
-        .map_err(|e| format!("Key derivation failed: {}", e))?;
-    
-    Ok(*Key::<Aes256Gcm>::from_slice(&key))
-}
-
-/// Encrypt file data
-pub async fn encrypt_file(
-    pool: &SqlitePool,
-    file_id: &str,
-    key_id: &str,
-    user_id: &str,
-    data: &[u8],
-    metadata: Option<serde_json::Value>,
-) -> Result<(Vec<u8>, String), Box<dyn std::error::Error + Send + Sync>> {
-    // Get encryption key
-    let key_record = sqlx::query_as::<_, EncryptionKey>(
-        "SELECT * FROM encryption_keys WHERE id = ? AND user_id = ? AND is_active = 1"
-    )
-    .bind(key_id)
-    .bind(user_id)
-    .fetch_one(pool)
-    .await?;
-    
-    // For now, we need the password to decrypt the master key
-    // In production, use key caching or session-based key storage
-    // This is a simplified version
-    
-    // Generate nonce for this file
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let nonce_b64 = general_purpose::STANDARD.encode(nonce.as_slice());
-    
-    // Note: In a real implementation, you'd need to decrypt the master key first
-    // For demonstration, we'll use a placeholder
-    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&[0u8; KEY_SIZE]));
-    
-    // Encrypt file data
-    let encrypted_data = cipher
-        .encrypt(&nonce, data)
-        .map_err(|e| format!("File encryption failed: {}", e))?;
-    
-    // Encrypt metadata if provided
-    let encrypted_metadata = if let Some(meta) = metadata {
-        let meta_json = serde_json::to_string(&meta)?;
-        let meta_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-        let encrypted_meta = cipher
-            .encrypt(&meta_nonce, meta_json.as_bytes())
-            .map_err(|e| format!("Metadata encryption failed: {}", e))?;
-        
-        let mut stored_meta = meta_nonce.to_vec();
-        stored_meta.extend_from_slice(&encrypted_meta);
-        Some(general_purpose::STANDARD.encode(&stored_meta))
-    } else {
-        None
-    };
-    
-    // Store encryption record
-    let encryption_id = Uuid::new_v4().to_string();
-    sqlx::query(
-        "INSERT INTO encrypted_files (id, file_id, key_id, nonce, encrypted_metadata, created_at)
-         VALUES (?, ?, ?, ?, ?, datetime('now'))"
-    )
-    .bind(&encryption_id)
-    .bind(file_id)
-    .bind(key_id)
-    .bind(&nonce_b64)
-    .bind(&encrypted_metadata)
-    .execute(pool)
-    .await?;
-    
-    // Update key last_used_at
-    sqlx::query("UPDATE encryption_keys SET last_used_at = datetime('now') WHERE id = ?")
-        .bind(key_id)
-        .execute(pool)
-        .await?;
-    
-    Ok((encrypted_data, encryption_id))
-}
-
-/// Decrypt file data
-pub async fn decrypt_file(
-    pool: &SqlitePool,
-    file_id: &str,
-    user_id: &str,
-    password: &str,
-    encrypted_data: &[u8],
-) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
-    // Get encryption record
-    let enc_record = sqlx::query_as::<_, EncryptedFile>(
-        "SELECT ef.* FROM encrypted_files ef
-         JOIN encryption_keys ek ON ef.key_id = ek.id
-         WHERE ef.file_id = ? AND ek.user_id = ?"
-    )
-    .bind(file_id)
-    .bind(user_id)
-    .fetch_one(pool)
-    .await?;
-    
-    // Get encryption key
-    let key_record = sqlx::query_as::<_, EncryptionKey>(
-        "SELECT * FROM encryption_keys WHERE id = ?"
-    )
-    .bind(&enc_record.key_id)
-    .fetch_one(pool)
-    .await?;
-    
-    // Derive key from password
-    let derived_key = derive_key_from_password(password, &key_record.salt)?;
-    
-    // Decrypt master key
-    let stored_key = general_purpose::STANDARD.decode(&key_record.key_hash)?;
-    if stored_key.len() < 12 {
-        return Err("Invalid stored key format".into());
-    }
-    
-    let (nonce_bytes, encrypted_master_key) = stored_key.split_at(12);
-    let nonce = Nonce::from_slice(nonce_bytes);
-    
-    let cipher = Aes256Gcm::new(&derived_key);
-    let master_key_bytes = cipher
-        .decrypt(nonce, encrypted_master_key)
-        .map_err(|_| "Failed to decrypt master key - invalid password")?;
-    
-    let master_key = Key::<Aes256Gcm>::from_slice(&master_key_bytes);
-    
-    // Decrypt file data
-    let file_nonce_bytes = general_purpose::STANDARD.decode(&enc_record.nonce)?;
-    let file_nonce = Nonce::from_slice(&file_nonce_bytes);
-    
-    let file_cipher = Aes256Gcm::new(master_key);
-    let decrypted_data = file_cipher
-        .decrypt(file_nonce, encrypted_data)
-        .map_err(|_| "Failed to decrypt file data")?;
-    
-    Ok(decrypted_data)
-}
-
-/// List encryption keys for a user
-pub async fn list_encryption_keys(
-    pool: &SqlitePool,
-    user_id: &str,
-) -> Result<Vec<EncryptionKey>, sqlx::Error> {
-    sqlx::query_as::<_, EncryptionKey>(
-        "SELECT * FROM encryption_keys WHERE user_id = ? ORDER BY created_at DESC"
-    )
-    .bind(user_id)
-    .fetch_all(pool)
-    .await
-}
-
-/// Delete encryption key (soft delete - deactivate)
-pub async fn deactivate_encryption_key(
-    pool: &SqlitePool,
-    key_id: &str,
-    user_id: &str,
-) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        "UPDATE encryption_keys SET is_active = 0 WHERE id = ? AND user_id = ?"
-    )
-    .bind(key_id)
-    .bind(user_id)
-    .execute(pool)
-    .await?;
-    
-    Ok(())
-}
-
-/// Check if a file is encrypted
-pub async fn is_file_encrypted(
-    pool: &SqlitePool,
-    file_id: &str,
-) -> Result<bool, sqlx::Error> {
-    let count: (i64,) = sqlx::query_as(
-        "SELECT COUNT(*) FROM encrypted_files WHERE file_id = ?"
-    )
-    .bind(file_id)
-    .fetch_one(pool)
-    .await?;
-    
-    Ok(count.0 > 0)
-}
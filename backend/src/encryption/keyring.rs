@@ -0,0 +1,218 @@
+//! Unlocked key-encryption keys
+//!
+//! A key-encryption key is stored wrapped with its password, so encrypting
+//! files and reading encrypted ones requires its owner to unlock it first.
+//! Unlocked keys belong to the session (access token or API token) that
+//! unlocked them: other sessions of the same user still see them locked.
+//! They are forgotten once idle for [`IDLE_TIMEOUT`], when locked again,
+//! when the session expires or when it ends (logout, token revoked).
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use super::SecretKey;
+use crate::auth::Session;
+
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+struct Unlocked {
+    user_id: String,
+    key: SecretKey,
+    last_used: Instant,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+pub struct Keyring {
+    /// (session id, key id) -> unlocked key
+    keys: Mutex<HashMap<(String, String), Unlocked>>,
+}
+
+impl Keyring {
+    pub fn unlock(&self, session: &Session, user_id: &str, key_id: &str, key: SecretKey) {
+        self.keys.lock().unwrap().insert(
+            (session.id.clone(), key_id.to_string()),
+            Unlocked {
+                user_id: user_id.to_string(),
+                key,
+                last_used: Instant::now(),
+                expires_at: session.expires_at,
+            },
+        );
+    }
+
+    /// The key as unlocked by `user_id` in `session_id`, which counts as a use of it
+    pub fn get(&self, session_id: &str, user_id: &str, key_id: &str) -> Option<SecretKey> {
+        self.get_at(session_id, user_id, key_id, Instant::now(), Utc::now())
+    }
+
+    fn get_at(
+        &self,
+        session_id: &str,
+        user_id: &str,
+        key_id: &str,
+        now: Instant,
+        wall_now: DateTime<Utc>,
+    ) -> Option<SecretKey> {
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|_, unlocked| {
+            now.duration_since(unlocked.last_used) < IDLE_TIMEOUT
+                && unlocked
+                    .expires_at
+                    .is_none_or(|expires_at| expires_at > wall_now)
+        });
+        let unlocked = keys.get_mut(&(session_id.to_string(), key_id.to_string()))?;
+        if unlocked.user_id != user_id {
+            return None;
+        }
+        unlocked.last_used = now;
+        Some(unlocked.key.clone())
+    }
+
+    pub fn is_unlocked(&self, session_id: &str, user_id: &str, key_id: &str) -> bool {
+        self.get(session_id, user_id, key_id).is_some()
+    }
+
+    /// Forget one key in one session; returns whether it was unlocked
+    pub fn lock(&self, session_id: &str, key_id: &str) -> bool {
+        self.keys
+            .lock()
+            .unwrap()
+            .remove(&(session_id.to_string(), key_id.to_string()))
+            .is_some()
+    }
+
+    /// Forget a key in every session, e.g. once it has been deactivated
+    pub fn lock_key(&self, key_id: &str) {
+        self.keys
+            .lock()
+            .unwrap()
+            .retain(|(_, key), _| key != key_id);
+    }
+
+    /// Forget every key unlocked in `session_id`
+    pub fn end_session(&self, session_id: &str) {
+        self.keys
+            .lock()
+            .unwrap()
+            .retain(|(session, _), _| session != session_id);
+    }
+
+    /// Move the keys of `old_session_id` to `new`, when an access token is
+    /// refreshed and the session lives on under the new token
+    pub fn rebind(&self, old_session_id: &str, new: &Session) {
+        let mut keys = self.keys.lock().unwrap();
+        let moved: Vec<_> = keys
+            .extract_if(|(session, _), _| session == old_session_id)
+            .collect();
+        for ((_, key_id), mut unlocked) in moved {
+            unlocked.expires_at = new.expires_at;
+            keys.insert((new.id.clone(), key_id), unlocked);
+        }
+    }
+}
+
+/// The process wide keyring
+pub fn keyring() -> &'static Keyring {
+    static KEYRING: OnceLock<Keyring> = OnceLock::new();
+    KEYRING.get_or_init(Keyring::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(id: &str, expires_at: Option<DateTime<Utc>>) -> Session {
+        Session {
+            id: id.to_string(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_unlock_expire_and_lock() {
+        let keyring = Keyring::default();
+        let laptop = session("laptop", None);
+        keyring.unlock(&laptop, "alice", "k1", SecretKey::generate());
+        keyring.unlock(&laptop, "alice", "k2", SecretKey::generate());
+        keyring.unlock(
+            &session("bob-phone", None),
+            "bob",
+            "k3",
+            SecretKey::generate(),
+        );
+        assert!(keyring.is_unlocked("laptop", "alice", "k1"));
+        // Keys are per session, and never handed to another user
+        assert!(!keyring.is_unlocked("phone", "alice", "k1"));
+        assert!(!keyring.is_unlocked("laptop", "bob", "k1"));
+
+        // Every use restarts the idle timeout
+        let start = Instant::now();
+        let now = Utc::now();
+        let later = start + IDLE_TIMEOUT - Duration::from_secs(1);
+        assert!(
+            keyring
+                .get_at("laptop", "alice", "k1", later, now)
+                .is_some()
+        );
+        let much_later = later + IDLE_TIMEOUT / 2;
+        assert!(
+            keyring
+                .get_at("laptop", "alice", "k1", much_later, now)
+                .is_some()
+        );
+        assert!(
+            keyring
+                .get_at("laptop", "alice", "k2", much_later, now)
+                .is_none()
+        );
+
+        assert!(keyring.lock("laptop", "k1"));
+        assert!(!keyring.lock("laptop", "k1"));
+        keyring.end_session("bob-phone");
+        assert!(!keyring.is_unlocked("bob-phone", "bob", "k3"));
+    }
+
+    #[test]
+    fn test_session_expiry_and_refresh() {
+        let keyring = Keyring::default();
+        let expires_at = Utc::now() + chrono::Duration::minutes(15);
+        keyring.unlock(
+            &session("t1", Some(expires_at)),
+            "alice",
+            "k1",
+            SecretKey::generate(),
+        );
+        keyring.unlock(
+            &session("t1", Some(expires_at)),
+            "alice",
+            "k2",
+            SecretKey::generate(),
+        );
+
+        // Refreshing the access token carries the keys over to the new one
+        let refreshed = expires_at + chrono::Duration::minutes(15);
+        keyring.rebind("t1", &session("t2", Some(refreshed)));
+        assert!(!keyring.is_unlocked("t1", "alice", "k1"));
+        assert!(keyring.is_unlocked("t2", "alice", "k1"));
+
+        // Past the token expiry the keys are gone
+        let now = Instant::now();
+        let after = refreshed + chrono::Duration::seconds(1);
+        assert!(keyring.get_at("t2", "alice", "k1", now, after).is_none());
+        assert!(
+            keyring
+                .get_at("t2", "alice", "k2", now, refreshed)
+                .is_none()
+        );
+
+        keyring.unlock(&session("t3", None), "alice", "k1", SecretKey::generate());
+        keyring.unlock(&session("t4", None), "alice", "k1", SecretKey::generate());
+        keyring.lock_key("k1");
+        assert!(!keyring.is_unlocked("t3", "alice", "k1"));
+        assert!(!keyring.is_unlocked("t4", "alice", "k1"));
+    }
+}
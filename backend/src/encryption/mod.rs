@@ -0,0 +1,865 @@
+//! File encryption at rest with AES-256-GCM
+//!
+//! Envelope encryption: every encrypted file has its own random data key,
+//! stored wrapped by one of its owner's key-encryption keys (the rows of
+//! `encryption_keys`), which in turn is stored wrapped by a key derived from
+//! the key's password with Argon2id. Content is encrypted in chunks (see
+//! [`stream`]) so files of any size are processed without loading them into
+//! memory. Once the owner has unlocked the key (see [`keyring`]), downloads
+//! decrypt transparently. Rotating a key-encryption key rewraps the data
+//! keys of its files and leaves their content untouched.
+
+pub mod keyring;
+pub mod stream;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use argon2::{password_hash::SaltString, Argon2};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::auth::Session;
+use crate::search::SearchIndex;
+use crate::services::version_storage_service;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Encryption key size for AES-256
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+const DATA_DIR: &str = "./data";
+
+/// AES-256 key held in memory; never serialized or logged
+#[derive(Clone)]
+pub struct SecretKey([u8; KEY_SIZE]);
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        Self(rand::random())
+    }
+
+    fn from_slice(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(Self)
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.0))
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+/// A file is encrypted with a key that `user` has not unlocked (or that
+/// belongs to someone else)
+#[derive(Debug)]
+pub struct Locked {
+    pub key_id: String,
+}
+
+impl fmt::Display for Locked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "encryption key {} is locked", self.key_id)
+    }
+}
+
+impl std::error::Error for Locked {}
+
+/// How to read the content of an encrypted file on disk
+#[derive(Debug, Clone)]
+pub struct Decryption {
+    pub data_key: SecretKey,
+    /// Size of the file on disk
+    pub encrypted_len: u64,
+}
+
+impl Decryption {
+    pub fn plaintext_len(&self) -> u64 {
+        stream::plaintext_len(self.encrypted_len).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct EncryptionKey {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub key_hash: String, // Wrapped key-encryption key
+    pub salt: String,
+    pub is_active: bool,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct EncryptedFile {
+    pub id: String,
+    pub file_id: String,
+    pub key_id: String,
+    pub nonce: String, // Unused, chunk nonces are derived from the file header
+    pub encrypted_metadata: Option<String>, // JSON metadata encrypted
+    pub created_at: String,
+    #[serde(skip)]
+    pub wrapped_key: Option<String>, // Data key wrapped by the key-encryption key
+    pub plaintext_size: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateKeyRequest {
+    pub name: String,
+    pub password: String,
+}
+
+/// Generate a new key-encryption key protected by a password
+pub async fn create_encryption_key(
+    pool: &SqlitePool,
+    user_id: &str,
+    req: CreateKeyRequest,
+) -> Result<EncryptionKey, BoxError> {
+    let id = Uuid::new_v4().to_string();
+
+    let salt = SaltString::generate(&mut OsRng);
+    let derived_key = derive_key_from_password(&req.password, salt.as_str())?;
+    let key_hash = wrap_key(&derived_key, &SecretKey::generate(), b"")?;
+
+    sqlx::query(
+        "INSERT INTO encryption_keys (id, user_id, name, key_hash, salt, is_active, created_at)
+         VALUES (?, ?, ?, ?, ?, 1, datetime('now'))"
+    )
+    .bind(&id)
+    .bind(user_id)
+    .bind(&req.name)
+    .bind(&key_hash)
+    .bind(salt.as_str())
+    .execute(pool)
+    .await?;
+
+    let key = sqlx::query_as::<_, EncryptionKey>(
+        "SELECT * FROM encryption_keys WHERE id = ?"
+    )
+    .bind(&id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(key)
+}
+
+/// Derive AES key from password using Argon2
+fn derive_key_from_password(password: &str, salt: &str) -> Result<SecretKey, BoxError> {
+    use argon2::{Algorithm, Params, Version};
+
+    let argon2 = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, Some(KEY_SIZE)).unwrap()
+    );
+
+    let mut key = [0u8; KEY_SIZE];
+    argon2.hash_password_into(password.as_bytes(), salt.as_bytes(), &mut key)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+
+    Ok(SecretKey(key))
+}
+
+/// Encrypt `key` with `wrapping_key`; stored as base64 of nonce + ciphertext
+fn wrap_key(wrapping_key: &SecretKey, key: &SecretKey, aad: &[u8]) -> Result<String, BoxError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let wrapped = wrapping_key
+        .cipher()
+        .encrypt(&nonce, Payload { msg: &key.0, aad })
+        .map_err(|e| format!("Key wrapping failed: {}", e))?;
+
+    let mut stored = nonce.to_vec();
+    stored.extend_from_slice(&wrapped);
+    Ok(general_purpose::STANDARD.encode(&stored))
+}
+
+fn unwrap_key(wrapping_key: &SecretKey, stored: &str, aad: &[u8]) -> Result<SecretKey, BoxError> {
+    let stored = general_purpose::STANDARD.decode(stored)?;
+    if stored.len() < NONCE_SIZE {
+        return Err("Invalid stored key format".into());
+    }
+
+    let (nonce, wrapped) = stored.split_at(NONCE_SIZE);
+    let key = wrapping_key
+        .cipher()
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: wrapped, aad })
+        .map_err(|_| "Failed to unwrap key")?;
+    SecretKey::from_slice(&key).ok_or_else(|| "Invalid stored key format".into())
+}
+
+/// Unwrap the key-encryption key of `key` with its password
+pub fn unlock_key(key: &EncryptionKey, password: &str) -> Result<SecretKey, BoxError> {
+    let derived_key = derive_key_from_password(password, &key.salt)?;
+    unwrap_key(&derived_key, &key.key_hash, b"").map_err(|_| "Invalid password".into())
+}
+
+/// Active key `key_id` of `user_id`
+pub async fn get_encryption_key(
+    pool: &SqlitePool,
+    key_id: &str,
+    user_id: &str,
+) -> Result<Option<EncryptionKey>, sqlx::Error> {
+    sqlx::query_as::<_, EncryptionKey>(
+        "SELECT * FROM encryption_keys WHERE id = ? AND user_id = ? AND is_active = 1"
+    )
+    .bind(key_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Temporary file next to `path` that replaces it once complete
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("file");
+    path.with_file_name(format!("{}.{}.tmp", name, Uuid::new_v4()))
+}
+
+/// Write `path` encrypted (or with `encrypt` false, decrypted) with
+/// `data_key` to a temporary file next to it; returns that file with its
+/// size and checksum
+async fn transform(
+    path: &Path,
+    data_key: &SecretKey,
+    encrypt: bool,
+) -> Result<(PathBuf, u64, String), BoxError> {
+    let temp = temp_path(path);
+    let written = async {
+        let mut reader = tokio::io::BufReader::new(tokio::fs::File::open(path).await?);
+        let mut writer = tokio::fs::File::create(&temp).await?;
+        if encrypt {
+            stream::encrypt(data_key, &mut reader, &mut writer).await?;
+        } else {
+            stream::decrypt(data_key, &mut reader, &mut writer).await?;
+        }
+        writer.sync_all().await
+    }
+    .await;
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e.into());
+    }
+
+    match crate::storage::sha256_file(&temp).await {
+        Ok((size, checksum)) => Ok((temp, size, checksum)),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&temp).await;
+            Err(e.into())
+        }
+    }
+}
+
+/// Encrypt the content of file `file_id` of `user_id` in place under a new
+/// data key wrapped by `key_encryption_key` (the unlocked key `key_id`).
+/// Everything kept from the plaintext goes with it (see [`forget_plaintext`]).
+pub async fn encrypt_file(
+    pool: &SqlitePool,
+    search_index: &SearchIndex,
+    file_id: &str,
+    key_id: &str,
+    user_id: &str,
+    key_encryption_key: &SecretKey,
+) -> Result<EncryptedFile, BoxError> {
+    let file: Option<(String, i64, Option<String>)> = sqlx::query_as(
+        "SELECT path, size_bytes, checksum_sha256 FROM files
+         WHERE id = ? AND owner_id = ? AND is_deleted = 0"
+    )
+    .bind(file_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some((path, old_size, old_checksum)) = file else {
+        return Err("File not found".into());
+    };
+    if is_file_encrypted(pool, file_id).await? {
+        return Err("File is already encrypted".into());
+    }
+
+    let safe_path = crate::security::validate_file_path(&path).map_err(|_| "Invalid file path")?;
+    crate::storage::ensure_local(pool, &safe_path).await?;
+    let local_path = Path::new(DATA_DIR).join(&safe_path);
+    let plaintext_size = tokio::fs::metadata(&local_path).await?.len();
+
+    let data_key = SecretKey::generate();
+    let wrapped_key = wrap_key(key_encryption_key, &data_key, file_id.as_bytes())?;
+    let (temp, size, checksum) = transform(&local_path, &data_key, true).await?;
+
+    // The data key must be stored before the plaintext is gone. The files row
+    // describes the new content so the watcher sees nothing to reconcile.
+    let encryption_id = Uuid::new_v4().to_string();
+    let stored = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO encrypted_files
+                 (id, file_id, key_id, nonce, wrapped_key, plaintext_size, created_at)
+             VALUES (?, ?, ?, '', ?, ?, datetime('now'))"
+        )
+        .bind(&encryption_id)
+        .bind(file_id)
+        .bind(key_id)
+        .bind(&wrapped_key)
+        .bind(plaintext_size as i64)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "UPDATE files SET size_bytes = ?, checksum_sha256 = ?, is_encrypted = 1,
+                 updated_at = datetime('now')
+             WHERE id = ?"
+        )
+        .bind(size as i64)
+        .bind(&checksum)
+        .bind(file_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = stored {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e.into());
+    }
+
+    if let Err(e) = tokio::fs::rename(&temp, &local_path).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        let _ = sqlx::query("DELETE FROM encrypted_files WHERE id = ?")
+            .bind(&encryption_id)
+            .execute(pool)
+            .await;
+        let _ = sqlx::query(
+            "UPDATE files SET size_bytes = ?, checksum_sha256 = ?, is_encrypted = 0 WHERE id = ?"
+        )
+        .bind(old_size)
+        .bind(&old_checksum)
+        .bind(file_id)
+        .execute(pool)
+        .await;
+        return Err(e.into());
+    }
+
+    if let Err(e) = crate::storage::replicate_file(
+        pool, file_id, &safe_path, &local_path, size as i64, &checksum,
+    )
+    .await
+    {
+        tracing::warn!("Failed to replicate encrypted {}: {}", safe_path, e);
+    }
+    forget_plaintext(pool, search_index, file_id).await?;
+
+    sqlx::query("UPDATE encryption_keys SET last_used_at = datetime('now') WHERE id = ?")
+        .bind(key_id)
+        .execute(pool)
+        .await?;
+
+    let record = sqlx::query_as::<_, EncryptedFile>(
+        "SELECT * FROM encrypted_files WHERE id = ?"
+    )
+    .bind(&encryption_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(record)
+}
+
+/// Delete what was kept from the plaintext of a file just encrypted: its
+/// versions and its indexed text. Thumbnails and previews are the API's to
+/// delete.
+async fn forget_plaintext(
+    pool: &SqlitePool,
+    search_index: &SearchIndex,
+    file_id: &str,
+) -> Result<(), BoxError> {
+    let versions = version_storage_service::delete_file_versions(pool, file_id).await?;
+    if versions > 0 {
+        tracing::info!("Deleted {} plaintext versions of encrypted file {}", versions, file_id);
+    }
+    search_index.delete_from_index(file_id).await?;
+    Ok(())
+}
+
+/// Replace the content of encrypted file `file_id` by its plaintext and
+/// forget its data key. `key_encryption_key` is the unlocked key it is
+/// encrypted with.
+pub async fn decrypt_file(
+    pool: &SqlitePool,
+    file_id: &str,
+    user_id: &str,
+    key_encryption_key: &SecretKey,
+) -> Result<(), BoxError> {
+    let record: Option<(String, String)> = sqlx::query_as(
+        "SELECT f.path, ef.wrapped_key FROM encrypted_files ef
+         JOIN encryption_keys ek ON ef.key_id = ek.id
+         JOIN files f ON f.id = ef.file_id
+         WHERE ef.file_id = ? AND ek.user_id = ?"
+    )
+    .bind(file_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some((path, wrapped_key)) = record else {
+        return Err("File is not encrypted".into());
+    };
+
+    let safe_path = crate::security::validate_file_path(&path).map_err(|_| "Invalid file path")?;
+    crate::storage::ensure_local(pool, &safe_path).await?;
+    let local_path = Path::new(DATA_DIR).join(&safe_path);
+
+    let data_key = unwrap_key(key_encryption_key, &wrapped_key, file_id.as_bytes())?;
+    let (temp, size, checksum) = transform(&local_path, &data_key, false).await?;
+    if let Err(e) = tokio::fs::rename(&temp, &local_path).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e.into());
+    }
+
+    // Should this fail the record outlives the ciphertext; downloads notice
+    // the size mismatch and serve the file as it is
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM encrypted_files WHERE file_id = ?")
+        .bind(file_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "UPDATE files SET size_bytes = ?, checksum_sha256 = ?, is_encrypted = 0,
+             updated_at = datetime('now')
+         WHERE id = ?"
+    )
+    .bind(size as i64)
+    .bind(&checksum)
+    .bind(file_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    if let Err(e) = crate::storage::replicate_file(
+        pool, file_id, &safe_path, &local_path, size as i64, &checksum,
+    )
+    .await
+    {
+        tracing::warn!("Failed to replicate decrypted {}: {}", safe_path, e);
+    }
+
+    Ok(())
+}
+
+/// Replace the key-encryption key of `key` by a new one protected by
+/// `new_password` and rewrap the data keys of its files with it. File
+/// content is not touched. Returns the new key and the number of files.
+pub async fn rotate_key(
+    pool: &SqlitePool,
+    key: &EncryptionKey,
+    old_key_encryption_key: &SecretKey,
+    new_password: &str,
+) -> Result<(SecretKey, usize), BoxError> {
+    let new_key_encryption_key = SecretKey::generate();
+    let salt = SaltString::generate(&mut OsRng);
+    let derived_key = derive_key_from_password(new_password, salt.as_str())?;
+    let key_hash = wrap_key(&derived_key, &new_key_encryption_key, b"")?;
+
+    let mut tx = pool.begin().await?;
+    let wrapped: Vec<(String, String)> = sqlx::query_as(
+        "SELECT file_id, wrapped_key FROM encrypted_files WHERE key_id = ?"
+    )
+    .bind(&key.id)
+    .fetch_all(&mut *tx)
+    .await?;
+    for (file_id, wrapped_key) in &wrapped {
+        let data_key = unwrap_key(old_key_encryption_key, wrapped_key, file_id.as_bytes())?;
+        sqlx::query("UPDATE encrypted_files SET wrapped_key = ? WHERE file_id = ?")
+            .bind(wrap_key(&new_key_encryption_key, &data_key, file_id.as_bytes())?)
+            .bind(file_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query(
+        "UPDATE encryption_keys SET key_hash = ?, salt = ?, last_used_at = datetime('now')
+         WHERE id = ?"
+    )
+    .bind(&key_hash)
+    .bind(salt.as_str())
+    .bind(&key.id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok((new_key_encryption_key, wrapped.len()))
+}
+
+/// The encryption record of the live file stored at `path`
+#[derive(sqlx::FromRow)]
+struct StoredEncryption {
+    file_id: String,
+    key_id: String,
+    wrapped_key: String,
+    plaintext_size: i64,
+    owner: String,
+}
+
+impl StoredEncryption {
+    async fn at(pool: &SqlitePool, path: &str) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "SELECT ef.file_id, ef.key_id, ef.wrapped_key, ef.plaintext_size,
+                    ek.user_id AS owner
+             FROM encrypted_files ef
+             JOIN files f ON f.id = ef.file_id
+             JOIN encryption_keys ek ON ek.id = ef.key_id
+             WHERE f.path = ? AND f.is_deleted = 0
+             ORDER BY f.updated_at DESC
+             LIMIT 1",
+        )
+        .bind(path)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Whether `size` bytes on disk are still the content encrypted here;
+    /// content written over the file since (an upload replacing it) is plaintext
+    fn holds(&self, size: u64) -> bool {
+        stream::encrypted_len(self.plaintext_size.max(0) as u64) == size
+    }
+
+    /// The key-encryption key, if `reader` owns it and has unlocked it
+    fn unlocked_for(&self, reader: Option<(&str, &Session)>) -> Result<SecretKey, Locked> {
+        reader
+            .filter(|(user_id, _)| *user_id == self.owner)
+            .and_then(|(user_id, session)| {
+                keyring::keyring().get(&session.id, user_id, &self.key_id)
+            })
+            .ok_or_else(|| Locked {
+                key_id: self.key_id.clone(),
+            })
+    }
+}
+
+/// How `reader` (a user and their session) reads the file stored at `path`
+/// (of `size` bytes on disk), `None` if its content is not encrypted. Fails
+/// with [`Locked`] unless the user owns the key and has unlocked it in this
+/// session; anonymous access (`None`) never can.
+pub async fn decryption_for(
+    pool: &SqlitePool,
+    path: &str,
+    size: u64,
+    reader: Option<(&str, &Session)>,
+) -> anyhow::Result<Option<Decryption>> {
+    let Some(record) = StoredEncryption::at(pool, path).await? else {
+        return Ok(None);
+    };
+    if !record.holds(size) {
+        tracing::debug!("{} is no longer encrypted content, serving it as is", path);
+        return Ok(None);
+    }
+
+    let key_encryption_key = record.unlocked_for(reader)?;
+    let data_key = unwrap_key(&key_encryption_key, &record.wrapped_key, record.file_id.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Cannot unwrap the data key of {}: {}", path, e))?;
+    Ok(Some(Decryption {
+        data_key,
+        encrypted_len: size,
+    }))
+}
+
+/// Plaintext size of the file stored at `path` (of `size` bytes on disk),
+/// `None` if its content is not encrypted
+pub async fn plaintext_size(pool: &SqlitePool, path: &str, size: u64) -> sqlx::Result<Option<u64>> {
+    Ok(StoredEncryption::at(pool, path)
+        .await?
+        .filter(|record| record.holds(size))
+        .map(|record| record.plaintext_size.max(0) as u64))
+}
+
+/// Store `upload`, plaintext written over the encrypted file at `path` by
+/// other means than this API (WebDAV), as the file's new content encrypted
+/// under a new data key. Returns false when the file is not encrypted; fails
+/// with [`Locked`] unless `writer` has unlocked its key. `upload` is left in
+/// place for the caller to remove.
+pub async fn replace_content(
+    pool: &SqlitePool,
+    path: &str,
+    upload: &Path,
+    writer: Option<(&str, &Session)>,
+) -> Result<bool, BoxError> {
+    let Some(record) = StoredEncryption::at(pool, path).await? else {
+        return Ok(false);
+    };
+    let key_encryption_key = record.unlocked_for(writer)?;
+    let previous: (i64, Option<String>) =
+        sqlx::query_as("SELECT size_bytes, checksum_sha256 FROM files WHERE id = ?")
+            .bind(&record.file_id)
+            .fetch_one(pool)
+            .await?;
+
+    let safe_path = crate::security::validate_file_path(path).map_err(|_| "Invalid file path")?;
+    let local_path = Path::new(DATA_DIR).join(&safe_path);
+    let plaintext_size = tokio::fs::metadata(upload).await?.len();
+    let data_key = SecretKey::generate();
+    let wrapped_key = wrap_key(&key_encryption_key, &data_key, record.file_id.as_bytes())?;
+    let (temp, size, checksum) = transform(upload, &data_key, true).await?;
+
+    // As in encrypt_file the new data key is stored before the content is
+    // replaced, and the files row already describes the new content
+    let stored = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            "UPDATE encrypted_files SET wrapped_key = ?, plaintext_size = ? WHERE file_id = ?"
+        )
+        .bind(&wrapped_key)
+        .bind(plaintext_size as i64)
+        .bind(&record.file_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "UPDATE files SET size_bytes = ?, checksum_sha256 = ?, version = version + 1,
+                 updated_at = datetime('now')
+             WHERE id = ?"
+        )
+        .bind(size as i64)
+        .bind(&checksum)
+        .bind(&record.file_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = stored {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e.into());
+    }
+
+    if let Err(e) = tokio::fs::rename(&temp, &local_path).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        let _ = sqlx::query(
+            "UPDATE encrypted_files SET wrapped_key = ?, plaintext_size = ? WHERE file_id = ?"
+        )
+        .bind(&record.wrapped_key)
+        .bind(record.plaintext_size)
+        .bind(&record.file_id)
+        .execute(pool)
+        .await;
+        let _ = sqlx::query("UPDATE files SET size_bytes = ?, checksum_sha256 = ? WHERE id = ?")
+            .bind(previous.0)
+            .bind(&previous.1)
+            .bind(&record.file_id)
+            .execute(pool)
+            .await;
+        return Err(e.into());
+    }
+
+    if let Err(e) = crate::storage::delete_file_objects(pool, &record.file_id).await {
+        tracing::warn!("Failed to drop stale stored objects of {}: {}", safe_path, e);
+    }
+    if let Err(e) = crate::storage::replicate_file(
+        pool, &record.file_id, &safe_path, &local_path, size as i64, &checksum,
+    )
+    .await
+    {
+        tracing::warn!("Failed to replicate encrypted {}: {}", safe_path, e);
+    }
+
+    Ok(true)
+}
+
+/// Drop the encryption record of `file_id` after its content was replaced
+/// by plaintext outside this API (the watcher saw it change on disk)
+pub async fn forget_replaced(pool: &SqlitePool, file_id: &str) -> sqlx::Result<()> {
+    let mut tx = pool.begin().await?;
+    let removed = sqlx::query("DELETE FROM encrypted_files WHERE file_id = ?")
+        .bind(file_id)
+        .execute(&mut *tx)
+        .await?;
+    if removed.rows_affected() > 0 {
+        sqlx::query("UPDATE files SET is_encrypted = 0 WHERE id = ?")
+            .bind(file_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+/// List encryption keys for a user
+pub async fn list_encryption_keys(
+    pool: &SqlitePool,
+    user_id: &str,
+) -> Result<Vec<EncryptionKey>, sqlx::Error> {
+    sqlx::query_as::<_, EncryptionKey>(
+        "SELECT * FROM encryption_keys WHERE user_id = ? ORDER BY created_at DESC"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Delete encryption key (soft delete - deactivate)
+pub async fn deactivate_encryption_key(
+    pool: &SqlitePool,
+    key_id: &str,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE encryption_keys SET is_active = 0 WHERE id = ? AND user_id = ?"
+    )
+    .bind(key_id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    keyring::keyring().lock_key(key_id);
+
+    Ok(())
+}
+
+/// Check if a file is encrypted
+pub async fn is_file_encrypted(
+    pool: &SqlitePool,
+    file_id: &str,
+) -> Result<bool, sqlx::Error> {
+    let count: (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM encrypted_files WHERE file_id = ?"
+    )
+    .bind(file_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(count.0 > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrapped_keys_are_bound_to_their_file() {
+        let key_encryption_key = SecretKey::generate();
+        let data_key = SecretKey::generate();
+        let wrapped = wrap_key(&key_encryption_key, &data_key, b"file-1").unwrap();
+
+        let unwrapped = unwrap_key(&key_encryption_key, &wrapped, b"file-1").unwrap();
+        assert_eq!(unwrapped.0, data_key.0);
+        assert!(unwrap_key(&key_encryption_key, &wrapped, b"file-2").is_err());
+        assert!(unwrap_key(&SecretKey::generate(), &wrapped, b"file-1").is_err());
+    }
+
+    #[test]
+    fn test_unlock_with_password() {
+        let salt = SaltString::generate(&mut OsRng);
+        let key_encryption_key = SecretKey::generate();
+        let derived_key = derive_key_from_password("correct horse", salt.as_str()).unwrap();
+        let key = EncryptionKey {
+            id: "k1".to_string(),
+            user_id: "u1".to_string(),
+            name: "Documents".to_string(),
+            key_hash: wrap_key(&derived_key, &key_encryption_key, b"").unwrap(),
+            salt: salt.as_str().to_string(),
+            is_active: true,
+            created_at: String::new(),
+            last_used_at: None,
+        };
+
+        assert_eq!(
+            unlock_key(&key, "correct horse").unwrap().0,
+            key_encryption_key.0
+        );
+        assert!(unlock_key(&key, "battery staple").is_err());
+    }
+
+    async fn encryption_pool() -> SqlitePool {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE users (id TEXT PRIMARY KEY)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "CREATE TABLE files (
+                id TEXT PRIMARY KEY,
+                path TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL,
+                is_encrypted INTEGER NOT NULL DEFAULT 0,
+                is_deleted INTEGER NOT NULL DEFAULT 0,
+                updated_at TEXT NOT NULL DEFAULT ''
+            )",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        for sql in [
+            include_str!("../../migrations/007_add_encryption.sql"),
+            include_str!("../../migrations/065_envelope_encryption.sql"),
+        ] {
+            let cleaned: Vec<&str> = sql
+                .lines()
+                .map(|line| line.split("--").next().unwrap_or_default())
+                .collect();
+            for statement in cleaned.join("\n").split(';') {
+                if !statement.trim().is_empty() {
+                    sqlx::query(statement).execute(&pool).await.unwrap();
+                }
+            }
+        }
+        pool
+    }
+
+    #[tokio::test]
+    async fn test_content_replaced_outside_the_api() {
+        let pool = encryption_pool().await;
+        let stored = stream::encrypted_len(100);
+        sqlx::query("INSERT INTO users (id) VALUES ('alice')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO encryption_keys (id, user_id, name, key_hash, salt, created_at)
+             VALUES ('k1', 'alice', 'Documents', '', '', '')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO files (id, path, size_bytes, is_encrypted)
+             VALUES ('f1', 'docs/a.txt', ?, 1)",
+        )
+        .bind(stored as i64)
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO encrypted_files
+                 (id, file_id, key_id, nonce, wrapped_key, plaintext_size, created_at)
+             VALUES ('e1', 'f1', 'k1', '', 'wrapped', 100, '')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(plaintext_size(&pool, "docs/a.txt", stored).await.unwrap(), Some(100));
+        // Bytes of another length on disk are not the encrypted content
+        assert_eq!(plaintext_size(&pool, "docs/a.txt", 100).await.unwrap(), None);
+        let session = Session {
+            id: "s1".to_string(),
+            expires_at: None,
+        };
+        let locked = decryption_for(&pool, "docs/a.txt", stored, Some(("alice", &session)))
+            .await
+            .unwrap_err();
+        assert!(locked.is::<Locked>());
+
+        forget_replaced(&pool, "f1").await.unwrap();
+        assert!(!is_file_encrypted(&pool, "f1").await.unwrap());
+        let flagged: (bool,) = sqlx::query_as("SELECT is_encrypted FROM files WHERE id = 'f1'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(!flagged.0);
+        assert!(decryption_for(&pool, "docs/a.txt", stored, None).await.unwrap().is_none());
+    }
+}
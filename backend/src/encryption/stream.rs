@@ -0,0 +1,378 @@
+//! Chunked AEAD file format
+//!
+//! Encrypted files are written as a 15 byte header, `SYNCENC1` followed by
+//! a random 7 byte nonce prefix, and the content split into 64 KiB chunks,
+//! each sealed with AES-256-GCM under the file's data key. The nonce of a
+//! chunk is the prefix, the chunk index (u32, big endian) and a flag byte
+//! that is 1 for the last chunk only, so chunks cannot be reordered,
+//! dropped or appended and a truncated file fails to decrypt. The header is
+//! the associated data of every chunk. An empty file has a single empty
+//! last chunk.
+//!
+//! Chunks are independent, so a byte range is decrypted by seeking to the
+//! chunk it starts in; neither direction holds more than two chunks in
+//! memory.
+
+use std::io;
+
+use aes_gcm::{
+    Aes256Gcm, Nonce,
+    aead::{Aead, Payload},
+};
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use super::SecretKey;
+
+pub const MAGIC: &[u8; 8] = b"SYNCENC1";
+const NONCE_PREFIX_LEN: usize = 7;
+pub const HEADER_LEN: usize = MAGIC.len() + NONCE_PREFIX_LEN;
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const SEALED_CHUNK_LEN: usize = CHUNK_SIZE + TAG_LEN;
+
+/// Size of the encrypted form of `plaintext_len` bytes
+pub fn encrypted_len(plaintext_len: u64) -> u64 {
+    let chunks = plaintext_len.div_ceil(CHUNK_SIZE as u64).max(1);
+    HEADER_LEN as u64 + plaintext_len + chunks * TAG_LEN as u64
+}
+
+/// Size of the content of an encrypted file of `encrypted_len` bytes, `None`
+/// if no plaintext encrypts to that size
+pub fn plaintext_len(encrypted_len: u64) -> Option<u64> {
+    let body = encrypted_len.checked_sub(HEADER_LEN as u64)?;
+    if body < TAG_LEN as u64 {
+        return None;
+    }
+    let full_chunks = body / SEALED_CHUNK_LEN as u64;
+    match body % SEALED_CHUNK_LEN as u64 {
+        0 => Some(full_chunks * CHUNK_SIZE as u64),
+        rest if rest >= TAG_LEN as u64 => {
+            Some(full_chunks * CHUNK_SIZE as u64 + rest - TAG_LEN as u64)
+        }
+        _ => None,
+    }
+}
+
+fn chunk_count(plaintext_len: u64) -> u64 {
+    plaintext_len.div_ceil(CHUNK_SIZE as u64).max(1)
+}
+
+fn chunk_nonce(prefix: &[u8], index: u64, last: bool) -> io::Result<[u8; 12]> {
+    let index = u32::try_from(index)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large to encrypt"))?;
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    Ok(nonce)
+}
+
+fn seal(
+    cipher: &Aes256Gcm,
+    header: &[u8],
+    index: u64,
+    last: bool,
+    chunk: &[u8],
+) -> io::Result<Vec<u8>> {
+    let nonce = chunk_nonce(&header[MAGIC.len()..], index, last)?;
+    cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: chunk,
+                aad: header,
+            },
+        )
+        .map_err(|_| io::Error::other("chunk encryption failed"))
+}
+
+fn open(
+    cipher: &Aes256Gcm,
+    header: &[u8],
+    index: u64,
+    last: bool,
+    sealed: &[u8],
+) -> io::Result<Vec<u8>> {
+    let nonce = chunk_nonce(&header[MAGIC.len()..], index, last)?;
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: sealed,
+                aad: header,
+            },
+        )
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encrypted content is damaged or the key is wrong",
+            )
+        })
+}
+
+/// Read until `buf` is full or the input ends; returns the bytes read
+async fn fill<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = reader.read(&mut buf[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<[u8; HEADER_LEN]> {
+    let mut header = [0u8; HEADER_LEN];
+    if fill(reader, &mut header).await? < HEADER_LEN || !header.starts_with(MAGIC) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an encrypted file",
+        ));
+    }
+    Ok(header)
+}
+
+/// Encrypt everything `reader` yields into `writer`; returns the plaintext size
+pub async fn encrypt<R, W>(key: &SecretKey, reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let cipher = key.cipher();
+    let mut header = [0u8; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[MAGIC.len()..].copy_from_slice(&rand::random::<[u8; NONCE_PREFIX_LEN]>());
+    writer.write_all(&header).await?;
+
+    let mut current = vec![0u8; CHUNK_SIZE];
+    let mut next = vec![0u8; CHUNK_SIZE];
+    let mut filled = fill(reader, &mut current).await?;
+    let mut total = 0u64;
+    let mut index = 0u64;
+    loop {
+        // Only a full chunk can be followed by another one
+        let next_filled = if filled == CHUNK_SIZE {
+            fill(reader, &mut next).await?
+        } else {
+            0
+        };
+        let last = next_filled == 0;
+        let sealed = seal(&cipher, &header, index, last, &current[..filled])?;
+        writer.write_all(&sealed).await?;
+        total += filled as u64;
+        if last {
+            break;
+        }
+        std::mem::swap(&mut current, &mut next);
+        filled = next_filled;
+        index += 1;
+    }
+    writer.flush().await?;
+    Ok(total)
+}
+
+/// Decrypt a whole encrypted file from `reader` into `writer`; returns the
+/// plaintext size. Fails if the content was altered or truncated.
+pub async fn decrypt<R, W>(key: &SecretKey, reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let cipher = key.cipher();
+    let header = read_header(reader).await?;
+
+    let mut current = vec![0u8; SEALED_CHUNK_LEN];
+    let mut next = vec![0u8; SEALED_CHUNK_LEN];
+    let mut filled = fill(reader, &mut current).await?;
+    let mut total = 0u64;
+    let mut index = 0u64;
+    loop {
+        let next_filled = if filled == SEALED_CHUNK_LEN {
+            fill(reader, &mut next).await?
+        } else {
+            0
+        };
+        let last = next_filled == 0;
+        let chunk = open(&cipher, &header, index, last, &current[..filled])?;
+        writer.write_all(&chunk).await?;
+        total += chunk.len() as u64;
+        if last {
+            break;
+        }
+        std::mem::swap(&mut current, &mut next);
+        filled = next_filled;
+        index += 1;
+    }
+    writer.flush().await?;
+    Ok(total)
+}
+
+/// Stream `length` plaintext bytes from offset `start` of an encrypted file
+/// of `encrypted_len` bytes, decrypting only the chunks the range touches
+pub async fn decrypt_range<R>(
+    key: &SecretKey,
+    mut reader: R,
+    encrypted_len: u64,
+    start: u64,
+    length: u64,
+) -> io::Result<BoxStream<'static, io::Result<Bytes>>>
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not an encrypted file");
+    let plaintext_len = plaintext_len(encrypted_len).ok_or_else(invalid)?;
+    if start.saturating_add(length) > plaintext_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "range exceeds the file",
+        ));
+    }
+    if length == 0 {
+        return Ok(stream::empty().boxed());
+    }
+
+    let header = read_header(&mut reader).await?;
+    let last_index = chunk_count(plaintext_len) - 1;
+    let first_index = start / CHUNK_SIZE as u64;
+    reader
+        .seek(io::SeekFrom::Start(
+            HEADER_LEN as u64 + first_index * SEALED_CHUNK_LEN as u64,
+        ))
+        .await?;
+
+    let skip = (start % CHUNK_SIZE as u64) as usize;
+    let state = (reader, key.cipher(), first_index, skip, length);
+    Ok(stream::try_unfold(
+        state,
+        move |(mut reader, cipher, index, skip, remaining)| async move {
+            if remaining == 0 {
+                return Ok(None);
+            }
+            let sealed_len = if index == last_index {
+                (encrypted_len - HEADER_LEN as u64 - index * SEALED_CHUNK_LEN as u64) as usize
+            } else {
+                SEALED_CHUNK_LEN
+            };
+            let mut sealed = vec![0u8; sealed_len];
+            reader.read_exact(&mut sealed).await?;
+            let chunk = open(&cipher, &header, index, index == last_index, &sealed)?;
+            let end = chunk.len().min(skip + remaining as usize);
+            let bytes = Bytes::from(chunk).slice(skip..end);
+            let remaining = remaining - bytes.len() as u64;
+            Ok(Some((bytes, (reader, cipher, index + 1, 0, remaining))))
+        },
+    )
+    .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn encrypted(key: &SecretKey, content: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        let size = encrypt(key, &mut &content[..], &mut output).await.unwrap();
+        assert_eq!(size, content.len() as u64);
+        assert_eq!(output.len() as u64, encrypted_len(size));
+        assert_eq!(plaintext_len(output.len() as u64), Some(size));
+        output
+    }
+
+    async fn decrypted(key: &SecretKey, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        decrypt(key, &mut &data[..], &mut output).await?;
+        Ok(output)
+    }
+
+    async fn range(key: &SecretKey, data: &[u8], start: u64, length: u64) -> Vec<u8> {
+        let reader = io::Cursor::new(data.to_vec());
+        let mut stream = decrypt_range(key, reader, data.len() as u64, start, length)
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        while let Some(bytes) = stream.next().await {
+            output.extend_from_slice(&bytes.unwrap());
+        }
+        output
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_at_chunk_boundaries() {
+        let key = SecretKey::generate();
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE,
+        ] {
+            let plain = content(len);
+            let data = encrypted(&key, &plain).await;
+            assert_eq!(
+                decrypted(&key, &data).await.unwrap(),
+                plain,
+                "{} bytes",
+                len
+            );
+        }
+        assert_eq!(plaintext_len(HEADER_LEN as u64 + 3), None);
+    }
+
+    #[tokio::test]
+    async fn test_ranges() {
+        let key = SecretKey::generate();
+        let plain = content(2 * CHUNK_SIZE + 100);
+        let data = encrypted(&key, &plain).await;
+        for (start, length) in [
+            (0, plain.len()),
+            (5, 10),
+            (CHUNK_SIZE - 3, 6),
+            (CHUNK_SIZE, CHUNK_SIZE),
+            (2 * CHUNK_SIZE + 99, 1),
+        ] {
+            assert_eq!(
+                range(&key, &data, start as u64, length as u64).await,
+                plain[start..start + length]
+            );
+        }
+        let reader = io::Cursor::new(data.clone());
+        assert!(
+            decrypt_range(&key, reader, data.len() as u64, 1, plain.len() as u64)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tampering_and_truncation_are_detected() {
+        let key = SecretKey::generate();
+        let plain = content(2 * CHUNK_SIZE + 10);
+        let data = encrypted(&key, &plain).await;
+
+        let mut flipped = data.clone();
+        flipped[HEADER_LEN + CHUNK_SIZE + 20] ^= 1;
+        assert!(decrypted(&key, &flipped).await.is_err());
+
+        // Dropping the last chunk leaves a valid length but no last chunk
+        let truncated = &data[..HEADER_LEN + 2 * SEALED_CHUNK_LEN];
+        assert!(decrypted(&key, truncated).await.is_err());
+
+        let mut swapped = data.clone();
+        swapped.copy_within(
+            HEADER_LEN..HEADER_LEN + SEALED_CHUNK_LEN,
+            HEADER_LEN + SEALED_CHUNK_LEN,
+        );
+        assert!(decrypted(&key, &swapped).await.is_err());
+
+        assert!(decrypted(&SecretKey::generate(), &data).await.is_err());
+    }
+}
@@ -0,0 +1,27 @@
+//! Entity tags of stored files
+//!
+//! Downloads (`http_range`) and WebDAV tag a file the same way: with its
+//! SHA-256 checksum from `files.checksum_sha256` while the database knows it,
+//! otherwise with size and modification time (nanoseconds, so rewrites in
+//! the same second get a new tag).
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Strong ETag of a file, quotes included
+pub fn file_etag(checksum_sha256: Option<&str>, size: u64, modified: Option<SystemTime>) -> String {
+    match checksum_sha256 {
+        Some(checksum) => format!("\"{}\"", checksum),
+        None => {
+            let modified = modified
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos())
+                .unwrap_or(0);
+            format!("\"{:x}-{:x}\"", size, modified)
+        }
+    }
+}
+
+/// Whether two ETags match under the weak comparison of RFC 9110 §8.8.3.2
+pub fn weak_match(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
@@ -1,7 +1,6 @@
 //! FTP/FTPS Synchronization Module
 //! Syncs files between SyncSpace and external FTP servers
 
-use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use std::io::Cursor;
@@ -10,6 +9,8 @@ use suppaftp::{FtpStream, Mode};
 use tokio::fs;
 use uuid::Uuid;
 
+use crate::secrets;
+
 /// FTP connection configuration
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct FtpConnection {
@@ -19,6 +20,7 @@ pub struct FtpConnection {
     pub host: String,
     pub port: i32,
     pub username: String,
+    #[serde(skip_serializing)]
     pub password_encrypted: String,
     pub use_ftps: bool,
     pub passive_mode: bool,
@@ -97,7 +99,7 @@ pub async fn create_ftp_connection(
     req: CreateFtpConnectionRequest,
 ) -> Result<FtpConnection, sqlx::Error> {
     let id = Uuid::new_v4().to_string();
-    let password_encrypted = general_purpose::STANDARD.encode(&req.password);
+    let password_encrypted = seal_password(&id, &req.password)?;
 
     sqlx::query(
         r#"INSERT INTO ftp_connections 
@@ -125,6 +127,11 @@ pub async fn create_ftp_connection(
     get_ftp_connection(pool, &id, user_id).await
 }
 
+fn seal_password(connection_id: &str, password: &str) -> Result<String, sqlx::Error> {
+    secrets::seal(&secrets::FTP_PASSWORD, connection_id, password)
+        .map_err(|e| sqlx::Error::Protocol(e.to_string()))
+}
+
 /// Get FTP connection by ID
 pub async fn get_ftp_connection(
     pool: &SqlitePool,
@@ -158,10 +165,10 @@ pub async fn update_ftp_connection(
 ) -> Result<FtpConnection, sqlx::Error> {
     let conn = get_ftp_connection(pool, id, user_id).await?;
 
-    let password_encrypted = req
-        .password
-        .map(|p| general_purpose::STANDARD.encode(&p))
-        .unwrap_or(conn.password_encrypted);
+    let password_encrypted = match req.password {
+        Some(password) => seal_password(id, &password)?,
+        None => conn.password_encrypted,
+    };
 
     sqlx::query(
         r#"UPDATE ftp_connections SET
@@ -206,10 +213,17 @@ pub async fn delete_ftp_connection(
 
 /// Test FTP connection
 pub async fn test_ftp_connection(
+    pool: &SqlitePool,
     connection: &FtpConnection,
 ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
-    let password = general_purpose::STANDARD.decode(&connection.password_encrypted)?;
-    let password = String::from_utf8(password)?;
+    let password = secrets::reveal(
+        pool,
+        &secrets::FTP_PASSWORD,
+        &connection.id,
+        &connection.password_encrypted,
+        "FTP connection test",
+    )
+    .await?;
 
     let mut ftp_stream =
         FtpStream::connect(format!("{}:{}", connection.host, connection.port))?;
@@ -230,11 +244,18 @@ pub async fn test_ftp_connection(
 
 /// List files on FTP server
 pub async fn list_remote_files(
+    pool: &SqlitePool,
     connection: &FtpConnection,
     path: Option<&str>,
 ) -> Result<Vec<FtpFileEntry>, Box<dyn std::error::Error + Send + Sync>> {
-    let password = general_purpose::STANDARD.decode(&connection.password_encrypted)?;
-    let password = String::from_utf8(password)?;
+    let password = secrets::reveal(
+        pool,
+        &secrets::FTP_PASSWORD,
+        &connection.id,
+        &connection.password_encrypted,
+        "FTP file listing",
+    )
+    .await?;
 
     let mut ftp_stream =
         FtpStream::connect(format!("{}:{}", connection.host, connection.port))?;
@@ -283,8 +304,14 @@ pub async fn sync_ftp(
     let start = std::time::Instant::now();
     let started_at = chrono::Utc::now().to_rfc3339();
 
-    let password = general_purpose::STANDARD.decode(&connection.password_encrypted)?;
-    let password = String::from_utf8(password)?;
+    let password = secrets::reveal(
+        pool,
+        &secrets::FTP_PASSWORD,
+        &connection.id,
+        &connection.password_encrypted,
+        "FTP sync",
+    )
+    .await?;
 
     let mut ftp_stream =
         FtpStream::connect(format!("{}:{}", connection.host, connection.port))?;
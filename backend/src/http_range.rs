@@ -0,0 +1,484 @@
+//! HTTP range and conditional request handling for file downloads
+//!
+//! Implements the parts of RFC 9110 needed for resumable downloads and media
+//! seeking: single and multi-range (`multipart/byteranges`) responses,
+//! `If-Range`, strong ETags derived from the stored SHA-256 checksum (see
+//! `crate::etag`, WebDAV uses the same tags) and the
+//! `If-Match` / `If-None-Match` / `If-Modified-Since` / `If-Unmodified-Since`
+//! preconditions (304, 412 and 416 responses).
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::Response,
+};
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+/// Upper bound for ranges honoured in one request. Larger range sets are
+/// ignored and the full representation is returned instead (RFC 9110 §14.2).
+const MAX_RANGES: usize = 32;
+
+/// A stored file resolved for delivery
+#[derive(Debug, Clone)]
+pub struct ServedFile {
+    /// Absolute or data-dir relative path on disk
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Checksum from `files.checksum_sha256`, used for strong ETags
+    pub checksum_sha256: Option<String>,
+    pub content_type: String,
+    pub content_disposition: Option<String>,
+    /// Set when the file on disk is encrypted; `size` is that of the plaintext
+    pub decryption: Option<crate::encryption::Decryption>,
+}
+
+impl ServedFile {
+    /// Strong ETag from the checksum, or from size and mtime without one
+    pub fn etag(&self) -> String {
+        crate::etag::file_etag(self.checksum_sha256.as_deref(), self.size, self.modified)
+    }
+
+    fn modified_secs(&self) -> Option<u64> {
+        self.modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+    }
+}
+
+/// Inclusive byte range within a representation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeError {
+    /// Header is malformed and must be ignored
+    Invalid,
+    /// Header is valid but no range overlaps the representation (416)
+    Unsatisfiable,
+}
+
+/// Outcome of evaluating the request preconditions
+#[derive(Debug, PartialEq, Eq)]
+pub enum Precondition {
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+// ==================== PARSING ====================
+
+/// Parse a `Range: bytes=...` header against a representation of `size` bytes.
+///
+/// Overlapping or adjacent ranges are coalesced and returned in ascending order.
+pub fn parse_range_header(value: &str, size: u64) -> Result<Vec<ByteRange>, RangeError> {
+    let specs = value
+        .trim()
+        .strip_prefix("bytes=")
+        .ok_or(RangeError::Invalid)?;
+
+    let mut ranges = Vec::new();
+    let mut spec_count = 0;
+
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        spec_count += 1;
+        let (first, last) = spec.split_once('-').ok_or(RangeError::Invalid)?;
+        let (first, last) = (first.trim(), last.trim());
+
+        let range = if first.is_empty() {
+            // Suffix range: the last N bytes
+            let suffix = parse_position(last)?;
+            if suffix == 0 || size == 0 {
+                None
+            } else {
+                Some(ByteRange {
+                    start: size.saturating_sub(suffix),
+                    end: size - 1,
+                })
+            }
+        } else {
+            let start = parse_position(first)?;
+            let end = if last.is_empty() {
+                None
+            } else {
+                Some(parse_position(last)?)
+            };
+            if matches!(end, Some(end) if end < start) {
+                return Err(RangeError::Invalid);
+            }
+            if start >= size {
+                None
+            } else {
+                Some(ByteRange {
+                    start,
+                    end: end.map_or(size - 1, |e| e.min(size - 1)),
+                })
+            }
+        };
+
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+
+    if spec_count == 0 {
+        return Err(RangeError::Invalid);
+    }
+    if ranges.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+
+    Ok(coalesce(ranges))
+}
+
+fn parse_position(value: &str) -> Result<u64, RangeError> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(RangeError::Invalid);
+    }
+    value.parse().map_err(|_| RangeError::Invalid)
+}
+
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    if ranges.len() < 2 {
+        return ranges;
+    }
+
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+// ==================== PRECONDITIONS ====================
+
+/// Evaluate conditional headers in the order mandated by RFC 9110 §13.2.2
+pub fn evaluate_preconditions(
+    headers: &HeaderMap,
+    etag: &str,
+    modified_secs: Option<u64>,
+) -> Precondition {
+    if let Some(if_match) = header_str(headers, header::IF_MATCH) {
+        if !etag_list_matches(if_match, etag, true) {
+            return Precondition::Failed;
+        }
+    } else if let (Some(since), Some(modified)) = (
+        header_str(headers, header::IF_UNMODIFIED_SINCE).and_then(parse_http_secs),
+        modified_secs,
+    ) && modified > since
+    {
+        return Precondition::Failed;
+    }
+
+    if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH) {
+        if etag_list_matches(if_none_match, etag, false) {
+            return Precondition::NotModified;
+        }
+    } else if let (Some(since), Some(modified)) = (
+        header_str(headers, header::IF_MODIFIED_SINCE).and_then(parse_http_secs),
+        modified_secs,
+    ) && modified <= since
+    {
+        return Precondition::NotModified;
+    }
+
+    Precondition::Proceed
+}
+
+/// `If-Range` only allows a partial response when the validator still matches
+fn if_range_allows(headers: &HeaderMap, etag: &str, modified_secs: Option<u64>) -> bool {
+    let Some(value) = header_str(headers, header::IF_RANGE).map(str::trim) else {
+        return true;
+    };
+
+    if value.starts_with('"') || value.starts_with("W/") {
+        // If-Range requires a strong comparison
+        !etag.starts_with("W/") && value == etag
+    } else {
+        matches!(
+            (parse_http_secs(value), modified_secs),
+            (Some(date), Some(modified)) if date == modified
+        )
+    }
+}
+
+fn etag_list_matches(list: &str, current: &str, strong: bool) -> bool {
+    let list = list.trim();
+    if list == "*" {
+        return true;
+    }
+
+    let (current_weak, current_tag) = split_etag(current);
+    list.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .any(|candidate| {
+            let (weak, tag) = split_etag(candidate);
+            if strong && (weak || current_weak) {
+                return false;
+            }
+            tag == current_tag
+        })
+}
+
+fn split_etag(tag: &str) -> (bool, &str) {
+    match tag.strip_prefix("W/") {
+        Some(opaque) => (true, opaque),
+        None => (false, tag),
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn parse_http_secs(value: &str) -> Option<u64> {
+    httpdate::parse_http_date(value.trim())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+}
+
+// ==================== RESPONSE BUILDING ====================
+
+/// Build the download response for `file`, honouring conditional and range headers
+pub async fn file_response(headers: &HeaderMap, file: &ServedFile) -> Result<Response, StatusCode> {
+    let etag = file.etag();
+    let modified_secs = file.modified_secs();
+
+    let mut builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(modified) = file.modified {
+        builder = builder.header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+
+    match evaluate_preconditions(headers, &etag, modified_secs) {
+        Precondition::Proceed => {}
+        Precondition::NotModified => {
+            return builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        Precondition::Failed => {
+            return builder
+                .status(StatusCode::PRECONDITION_FAILED)
+                .body(Body::empty())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    if let Some(disposition) = &file.content_disposition {
+        builder = builder.header(header::CONTENT_DISPOSITION, disposition);
+    }
+
+    let requested = header_str(headers, header::RANGE)
+        .filter(|_| if_range_allows(headers, &etag, modified_secs))
+        .map(|value| parse_range_header(value, file.size));
+
+    match requested {
+        Some(Err(RangeError::Unsatisfiable)) => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", file.size))
+            .body(Body::empty())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
+        Some(Ok(ranges)) if ranges.len() == 1 => {
+            let range = ranges[0];
+            let stream = open_range(file, range)
+                .await
+                .map_err(|_| StatusCode::NOT_FOUND)?;
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, &file.content_type)
+                .header(header::CONTENT_LENGTH, range.length())
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", range.start, range.end, file.size),
+                )
+                .body(Body::from_stream(stream))
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Some(Ok(ranges)) if ranges.len() <= MAX_RANGES => {
+            multipart_response(builder, file, &ranges).await
+        }
+        _ => {
+            let stream = open_range(
+                file,
+                ByteRange {
+                    start: 0,
+                    end: file.size.saturating_sub(1),
+                },
+            )
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+            builder
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, &file.content_type)
+                .header(header::CONTENT_LENGTH, file.size)
+                .body(Body::from_stream(stream))
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Stream several ranges as `multipart/byteranges` with an exact Content-Length
+async fn multipart_response(
+    builder: axum::http::response::Builder,
+    file: &ServedFile,
+    ranges: &[ByteRange],
+) -> Result<Response, StatusCode> {
+    let boundary = uuid::Uuid::new_v4().simple().to_string();
+    let mut parts: Vec<BoxStream<'static, std::io::Result<Bytes>>> =
+        Vec::with_capacity(ranges.len() * 2 + 1);
+    let mut content_length: u64 = 0;
+
+    for range in ranges {
+        let part_header = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, file.content_type, range.start, range.end, file.size
+        );
+        content_length += part_header.len() as u64 + range.length();
+        parts.push(stream::once(futures_util::future::ready(Ok(Bytes::from(part_header)))).boxed());
+
+        let section = open_range(file, *range)
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        parts.push(section);
+    }
+
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    content_length += closing.len() as u64;
+    parts.push(stream::once(futures_util::future::ready(Ok(Bytes::from(closing)))).boxed());
+
+    builder
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .header(header::CONTENT_LENGTH, content_length)
+        .body(Body::from_stream(stream::iter(parts).flatten()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn open_range(
+    file: &ServedFile,
+    range: ByteRange,
+) -> std::io::Result<BoxStream<'static, std::io::Result<Bytes>>> {
+    let mut handle = tokio::fs::File::open(&file.path).await?;
+    if let Some(decryption) = &file.decryption {
+        // The full range of an empty file still claims one byte
+        let length = range.length().min(file.size.saturating_sub(range.start));
+        return crate::encryption::stream::decrypt_range(
+            &decryption.data_key,
+            handle,
+            decryption.encrypted_len,
+            range.start,
+            length,
+        )
+        .await;
+    }
+    if range.start > 0 {
+        handle.seek(std::io::SeekFrom::Start(range.start)).await?;
+    }
+    Ok(ReaderStream::new(handle.take(range.length())).boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn test_parse_single_ranges() {
+        assert_eq!(parse_range_header("bytes=0-499", 1000), Ok(vec![range(0, 499)]));
+        assert_eq!(parse_range_header("bytes=500-", 1000), Ok(vec![range(500, 999)]));
+        assert_eq!(parse_range_header("bytes=-200", 1000), Ok(vec![range(800, 999)]));
+        assert_eq!(parse_range_header("bytes=-5000", 1000), Ok(vec![range(0, 999)]));
+        assert_eq!(parse_range_header("bytes=900-5000", 1000), Ok(vec![range(900, 999)]));
+    }
+
+    #[test]
+    fn test_parse_multi_ranges_coalesced() {
+        assert_eq!(
+            parse_range_header("bytes=500-599, 0-99", 1000),
+            Ok(vec![range(0, 99), range(500, 599)])
+        );
+        assert_eq!(
+            parse_range_header("bytes=0-99,50-149,150-199", 1000),
+            Ok(vec![range(0, 199)])
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_and_unsatisfiable() {
+        assert_eq!(parse_range_header("items=0-1", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range_header("bytes=", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range_header("bytes=5-1", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range_header("bytes=+1-2", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range_header("bytes=1000-", 1000), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse_range_header("bytes=-0", 1000), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse_range_header("bytes=0-", 0), Err(RangeError::Unsatisfiable));
+    }
+
+    #[test]
+    fn test_preconditions() {
+        let etag = "\"abc\"";
+        let mut headers = HeaderMap::new();
+        assert_eq!(evaluate_preconditions(&headers, etag, Some(100)), Precondition::Proceed);
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("W/\"abc\""));
+        assert_eq!(evaluate_preconditions(&headers, etag, Some(100)), Precondition::NotModified);
+
+        headers.clear();
+        headers.insert(header::IF_MATCH, HeaderValue::from_static("\"other\""));
+        assert_eq!(evaluate_preconditions(&headers, etag, Some(100)), Precondition::Failed);
+
+        headers.clear();
+        headers.insert(header::IF_MATCH, HeaderValue::from_static("W/\"abc\""));
+        assert_eq!(evaluate_preconditions(&headers, etag, Some(100)), Precondition::Failed);
+
+        headers.clear();
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Thu, 01 Jan 1970 00:01:40 GMT"),
+        );
+        assert_eq!(evaluate_preconditions(&headers, etag, Some(100)), Precondition::NotModified);
+        assert_eq!(evaluate_preconditions(&headers, etag, Some(101)), Precondition::Proceed);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_RANGE, HeaderValue::from_static("\"abc\""));
+        assert!(if_range_allows(&headers, "\"abc\"", None));
+        assert!(!if_range_allows(&headers, "\"def\"", None));
+        assert!(!if_range_allows(&headers, "W/\"abc\"", None));
+    }
+}
@@ -60,6 +60,51 @@ impl JobQueue {
         .await
     }
 
+    /// Take the next due job and mark it running in one statement, so
+    /// concurrent workers never receive the same job
+    pub async fn claim_next(&self) -> Result<Option<Job>, sqlx::Error> {
+        let now = chrono::Utc::now().to_rfc3339();
+
+        sqlx::query_as::<_, Job>(
+            r#"
+            UPDATE jobs
+            SET status = 'running', started_at = ?1, attempts = attempts + 1
+            WHERE id = (
+                SELECT id FROM jobs
+                WHERE status = 'pending'
+                AND (scheduled_for IS NULL OR scheduled_for <= ?1)
+                ORDER BY created_at ASC
+                LIMIT 1
+            )
+            AND status = 'pending'
+            RETURNING *
+            "#
+        )
+        .bind(&now)
+        .fetch_optional(self.pool.as_ref())
+        .await
+    }
+
+    /// Put a running job back into the queue without using up an attempt
+    pub async fn defer(&self, job_id: &str, delay: std::time::Duration) -> Result<(), sqlx::Error> {
+        let scheduled_for = chrono::Utc::now()
+            + chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::seconds(60));
+
+        sqlx::query(
+            r#"
+            UPDATE jobs
+            SET status = 'pending', attempts = MAX(attempts - 1, 0), scheduled_for = ?
+            WHERE id = ?
+            "#
+        )
+        .bind(scheduled_for.to_rfc3339())
+        .bind(job_id)
+        .execute(self.pool.as_ref())
+        .await?;
+
+        Ok(())
+    }
+
     /// Mark job as running
     pub async fn mark_running(&self, job_id: &str) -> Result<(), sqlx::Error> {
         let now = chrono::Utc::now().to_rfc3339();
@@ -142,6 +187,21 @@ impl JobQueue {
                 .await?;
                 
                 println!("❌ Job {} failed permanently after {} attempts", job_id, job.attempts);
+
+                if let Some(user_id) = &job.created_by {
+                    use crate::notify::{EventType, Notification};
+                    let title = format!("{} job failed", job.job_type.replace('_', " "));
+                    let message = format!("Failed after {} attempts: {}", job.attempts, error);
+                    crate::notify::send(
+                        Notification::new(user_id, EventType::JobFailed, &title, &message)
+                            .with_action_url("/#/jobs-queue")
+                            .with_priority("high")
+                            .with_context(serde_json::json!({
+                                "job_id": job.id,
+                                "job_type": job.job_type,
+                            })),
+                    );
+                }
             }
         }
         
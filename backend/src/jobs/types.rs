@@ -27,12 +27,34 @@ pub struct Job {
 pub enum JobType {
     FileIndexing { file_id: String, file_path: String },
     ThumbnailGeneration { file_id: String, file_path: String },
-    BackupCreation { backup_id: String, include_files: bool },
+    BackupCreation {
+        backup_id: String,
+        include_files: bool,
+        #[serde(default = "default_true")]
+        include_database: bool,
+        #[serde(default = "default_true")]
+        include_versions: bool,
+    },
+    BackupRestore {
+        restore_id: String,
+        backup_id: String,
+        include_database: bool,
+        /// Only these paths of the data directory, see backup::RestoreOptions
+        file_paths: Option<Vec<String>>,
+        restore_path: Option<String>,
+    },
+    /// Upload a completed backup to its destination, see backup::destination
+    BackupUpload { backup_id: String },
     VersionCleanup { file_id: Option<String> },  // None = all files
     WebhookDelivery { webhook_id: String, event: String, payload: serde_json::Value },
     EmailNotification { to: String, subject: String, body: String },
     SearchIndexRebuild { full_rebuild: bool },
     DatabaseCleanup { table: String },
+    WorkflowExecution { execution_id: String },
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -62,6 +84,9 @@ pub struct JobResult {
     pub success: bool,
     pub message: String,
     pub data: Option<serde_json::Value>,
+    /// The job was put back into the queue instead of finishing
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deferred: bool,
 }
 
 impl JobResult {
@@ -70,6 +95,7 @@ impl JobResult {
             success: true,
             message: message.into(),
             data: None,
+            deferred: false,
         }
     }
 
@@ -78,6 +104,7 @@ impl JobResult {
             success: true,
             message: message.into(),
             data: Some(data),
+            deferred: false,
         }
     }
 
@@ -86,6 +113,16 @@ impl JobResult {
             success: false,
             message: message.into(),
             data: None,
+            deferred: false,
+        }
+    }
+
+    pub fn deferred(message: impl Into<String>) -> Self {
+        Self {
+            success: true,
+            message: message.into(),
+            data: None,
+            deferred: true,
         }
     }
 }
@@ -98,11 +135,14 @@ impl Job {
             JobType::FileIndexing { .. } => "file_indexing",
             JobType::ThumbnailGeneration { .. } => "thumbnail_generation",
             JobType::BackupCreation { .. } => "backup_creation",
+            JobType::BackupRestore { .. } => "backup_restore",
+            JobType::BackupUpload { .. } => "backup_upload",
             JobType::VersionCleanup { .. } => "version_cleanup",
             JobType::WebhookDelivery { .. } => "webhook_delivery",
             JobType::EmailNotification { .. } => "email_notification",
             JobType::SearchIndexRebuild { .. } => "search_index_rebuild",
             JobType::DatabaseCleanup { .. } => "database_cleanup",
+            JobType::WorkflowExecution { .. } => "workflow_execution",
         };
 
         Ok(Self {
@@ -149,7 +189,8 @@ impl Job {
         }
     }
 
+    /// Whether a failed attempt leaves attempts for a retry
     pub fn can_retry(&self) -> bool {
-        self.attempts < self.max_attempts && self.status == JobStatus::Failed.to_string()
+        self.attempts < self.max_attempts
     }
 }
@@ -40,27 +40,39 @@ impl JobWorker {
                     break;
                 }
                 _ = sleep(Duration::from_secs(5)) => {
-                    if let Err(e) = self.process_next_job().await {
-                        eprintln!("❌ Job worker {} error: {}", self.worker_id, e);
+                    // Drain the queue before sleeping again
+                    loop {
+                        match self.process_next_job().await {
+                            Ok(true) => continue,
+                            Ok(false) => break,
+                            Err(e) => {
+                                eprintln!("❌ Job worker {} error: {}", self.worker_id, e);
+                                break;
+                            }
+                        }
                     }
                 }
             }
         }
     }
 
-    async fn process_next_job(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        // Try to get next job
-        if let Some(job) = self.queue.dequeue().await? {
+    /// Run the next due job, returns whether there was one
+    async fn process_next_job(&self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        // Claim the next job, marking it as running
+        if let Some(job) = self.queue.claim_next().await? {
             println!(
                 "🔨 Worker {} processing job: {} ({})",
                 self.worker_id, job.id, job.job_type
             );
 
-            // Mark as running
-            self.queue.mark_running(&job.id).await?;
-
             // Execute job
             match self.execute_job(&job).await {
+                Ok(result) if result.deferred => {
+                    println!(
+                        "⏸️ Worker {} deferred job: {} - {}",
+                        self.worker_id, job.id, result.message
+                    );
+                }
                 Ok(result) => {
                     let result_json = serde_json::to_string(&result)?;
                     self.queue.mark_success(&job.id, &result_json).await?;
@@ -78,9 +90,10 @@ impl JobWorker {
                     );
                 }
             }
+            return Ok(true);
         }
 
-        Ok(())
+        Ok(false)
     }
 
     async fn execute_job(
@@ -100,10 +113,34 @@ impl JobWorker {
             JobType::BackupCreation {
                 backup_id,
                 include_files,
+                include_database,
+                include_versions,
             } => {
-                self.execute_backup_creation(&backup_id, include_files)
+                let options = crate::backup::BackupOptions {
+                    database: include_database,
+                    files: include_files,
+                    versions: include_versions,
+                };
+                self.execute_backup_creation(&backup_id, options).await
+            }
+            JobType::BackupRestore {
+                restore_id,
+                backup_id,
+                include_database,
+                file_paths,
+                restore_path,
+            } => {
+                let options = crate::backup::RestoreOptions {
+                    database: include_database,
+                    files: true,
+                    versions: file_paths.is_none() && restore_path.is_none(),
+                    paths: file_paths,
+                    into: restore_path,
+                };
+                self.execute_backup_restore(&restore_id, &backup_id, options)
                     .await
             }
+            JobType::BackupUpload { backup_id } => self.execute_backup_upload(&backup_id).await,
             JobType::VersionCleanup { file_id } => {
                 self.execute_version_cleanup(file_id.as_deref()).await
             }
@@ -122,11 +159,32 @@ impl JobWorker {
                 self.execute_search_index_rebuild(full_rebuild).await
             }
             JobType::DatabaseCleanup { table } => self.execute_database_cleanup(&table).await,
+            JobType::WorkflowExecution { execution_id } => {
+                self.execute_workflow(job, &execution_id).await
+            }
         }
     }
 
     // ==================== JOB IMPLEMENTATIONS ====================
 
+    async fn execute_workflow(
+        &self,
+        job: &Job,
+        execution_id: &str,
+    ) -> Result<JobResult, Box<dyn std::error::Error + Send + Sync>> {
+        use crate::workflow::JobRun;
+
+        let engine = crate::workflow::engine().ok_or("Workflow engine is not running")?;
+        match engine.run_job(job, execution_id).await {
+            JobRun::Finished(result) => Ok(result),
+            JobRun::Deferred(delay) => {
+                self.queue.defer(&job.id, delay).await?;
+                Ok(JobResult::deferred("Workflow rule is at its concurrency limit"))
+            }
+            JobRun::Failed(error) => Err(error.into()),
+        }
+    }
+
     async fn execute_file_indexing(
         &self,
         file_id: &str,
@@ -261,84 +319,259 @@ impl JobWorker {
     async fn execute_backup_creation(
         &self,
         backup_id: &str,
-        include_files: bool,
+        options: crate::backup::BackupOptions,
     ) -> Result<JobResult, Box<dyn std::error::Error + Send + Sync>> {
-        use tokio::fs;
-        use std::path::Path;
-
-        let backup_dir = format!("./data/backups/{}", backup_id);
-        
-        // Create backup directory
-        fs::create_dir_all(&backup_dir).await?;
-
-        let mut backup_info = serde_json::json!({
-            "backup_id": backup_id,
-            "created_at": chrono::Utc::now().to_rfc3339(),
-            "include_files": include_files,
-            "database": "backed_up",
-            "files": []
-        });
-
-        // Backup database
-        let db_backup_path = format!("{}/syncspace.db.backup", backup_dir);
-        match fs::copy("./data/syncspace.db", &db_backup_path).await {
-            Ok(_) => {
-                tracing::info!("Database backed up to {}", db_backup_path);
+        let archive = crate::backup::archive_path(backup_id);
+        // Incremental and differential backups fall back to full ones when
+        // there is nothing usable to chain onto
+        let link = crate::backup::chain::parent_for(&self.pool, backup_id).await?;
+        let mut parent = None;
+        if let Some(link) = &link {
+            match crate::backup::read_manifest_of(std::path::Path::new(&link.storage_path)).await {
+                Ok(manifest) if manifest.backup_id == link.id => parent = Some(manifest),
+                Ok(_) => tracing::warn!(
+                    "Archive of backup {} holds another backup, writing {} as a full backup",
+                    link.id,
+                    backup_id
+                ),
+                Err(e) => tracing::warn!(
+                    "Cannot read backup {} ({:#}), writing {} as a full backup",
+                    link.id,
+                    e,
+                    backup_id
+                ),
             }
+        }
+        let link = link.filter(|_| parent.is_some());
+
+        let created = match crate::backup::create(
+            &self.pool,
+            std::path::Path::new("./data"),
+            &archive,
+            backup_id,
+            options,
+            parent.as_ref(),
+        )
+        .await
+        {
+            Ok(created) => created,
             Err(e) => {
-                tracing::warn!("Failed to backup database: {}", e);
+                let _ = sqlx::query(
+                    "UPDATE backups SET status = 'failed', error_message = ? WHERE id = ?",
+                )
+                .bind(format!("{:#}", e))
+                .bind(backup_id)
+                .execute(&*self.pool)
+                .await;
+                return Err(format!("Backup {} failed: {:#}", backup_id, e).into());
             }
+        };
+
+        let manifest = &created.manifest;
+        let metadata = serde_json::json!({
+            "format": manifest.format,
+            "created_at": manifest.created_at,
+            "include_database": options.database,
+            "include_files": options.files,
+            "include_versions": options.versions,
+            "stored_size": manifest.stored_size(),
+        });
+
+        // The manifest is also kept in backup_files, so backups can be
+        // inspected without opening their archive
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM backup_files WHERE backup_id = ?")
+            .bind(backup_id)
+            .execute(&mut *tx)
+            .await?;
+        for (entry, change) in crate::backup::changes(parent.as_ref(), manifest) {
+            sqlx::query(
+                "INSERT INTO backup_files
+                    (id, backup_id, file_path, file_size, file_hash, action, is_deduplicated)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(uuid::Uuid::new_v4().to_string())
+            .bind(backup_id)
+            .bind(&entry.path)
+            .bind(entry.size as i64)
+            .bind(&entry.sha256)
+            .bind(change.as_str())
+            .bind(change != crate::backup::Change::Deleted && !entry.is_stored())
+            .execute(&mut *tx)
+            .await?;
         }
+        sqlx::query(
+            "UPDATE backups SET status = 'completed', error_message = NULL, size_bytes = ?,
+                    file_count = ?, storage_path = ?, checksum = ?, compression_type = 'tar.zst',
+                    compressed_size_bytes = ?, metadata = ?, parent_backup_id = ?,
+                    base_backup_id = ?,
+                    backup_type = CASE
+                        WHEN ? AND backup_type IN ('incremental', 'differential') THEN 'full'
+                        ELSE backup_type
+                    END
+             WHERE id = ?",
+        )
+        .bind(manifest.total_size() as i64)
+        .bind(manifest.file_count() as i64)
+        .bind(archive.to_string_lossy().into_owned())
+        .bind(&created.sha256)
+        .bind(created.size as i64)
+        .bind(metadata.to_string())
+        .bind(link.as_ref().map(|link| link.id.clone()))
+        .bind(link.as_ref().map(|link| link.base_id().to_string()))
+        .bind(link.is_none())
+        .bind(backup_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
 
-        // If include_files is true, backup data directory
-        let mut total_size = 0u64;
-        if include_files {
-            let data_dir = Path::new("./data");
-            let mut file_count = 0;
-
-            if let Ok(mut entries) = fs::read_dir(data_dir).await {
-                while let Ok(Some(entry)) = entries.next_entry().await {
-                    if let Ok(metadata) = entry.metadata().await
-                        && !metadata.is_dir() {
-                            file_count += 1;
-                            total_size += metadata.len();
-                        }
-                }
-            }
+        tracing::info!(
+            "Backup {} written to {}: {} files, {}MB ({}MB new, {}MB compressed)",
+            backup_id,
+            archive.display(),
+            manifest.file_count(),
+            manifest.total_size() / 1024 / 1024,
+            manifest.stored_size() / 1024 / 1024,
+            created.size / 1024 / 1024
+        );
 
-            backup_info["files"] = serde_json::json!({
-                "count": file_count,
-                "total_size": total_size
-            });
+        let schedule_id: Option<String> =
+            sqlx::query_scalar("SELECT schedule_id FROM backups WHERE id = ?")
+                .bind(backup_id)
+                .fetch_optional(&*self.pool)
+                .await?
+                .flatten();
+        if let Some(schedule_id) = schedule_id {
+            match crate::backup::retention::apply(&self.pool, &schedule_id, chrono::Utc::now())
+                .await
+            {
+                Ok(pruned) if !pruned.is_empty() => tracing::info!(
+                    "Retention of schedule {} removed {} backups",
+                    schedule_id,
+                    pruned.len()
+                ),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Retention of schedule {} failed: {}", schedule_id, e),
+            }
+        }
 
-            tracing::info!(
-                "Backup includes {} files ({}MB)",
-                file_count,
-                total_size / 1024 / 1024
-            );
+        let destination_id: Option<String> =
+            sqlx::query_scalar("SELECT destination_id FROM backups WHERE id = ?")
+                .bind(backup_id)
+                .fetch_optional(&*self.pool)
+                .await?
+                .flatten();
+        if destination_id.is_some() {
+            // Uploads retry with backoff and resume where they stopped
+            let job = Job::new(
+                JobType::BackupUpload {
+                    backup_id: backup_id.to_string(),
+                },
+                None,
+            )?
+            .with_max_attempts(6);
+            if let Err(e) = self.queue.enqueue(job).await {
+                tracing::warn!("Failed to queue upload of backup {}: {}", backup_id, e);
+            }
         }
 
-        // Save backup metadata
-        let metadata_path = format!("{}/backup.json", backup_dir);
-        let metadata_json = serde_json::to_string_pretty(&backup_info)?;
-        fs::write(&metadata_path, metadata_json).await?;
+        Ok(JobResult::success_with_data(
+            format!("Created backup: {}", backup_id),
+            serde_json::json!({
+                "backup_id": backup_id,
+                "location": archive,
+                "parent": manifest.parent,
+                "files": manifest.file_count(),
+                "size": format!("{}MB", created.size / 1024 / 1024)
+            }),
+        ))
+    }
 
-        // Update backup record in database
-        let _ = sqlx::query(
-            "UPDATE backups SET status = 'completed', completed_at = datetime('now'), size_bytes = ? 
-             WHERE id = ?"
+    async fn execute_backup_restore(
+        &self,
+        restore_id: &str,
+        backup_id: &str,
+        options: crate::backup::RestoreOptions,
+    ) -> Result<JobResult, Box<dyn std::error::Error + Send + Sync>> {
+        let storage_path: Option<String> = sqlx::query_scalar(
+            "SELECT storage_path FROM backups WHERE id = ? AND status = 'completed'",
         )
-        .bind(total_size as i64)
         .bind(backup_id)
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        let restored = match storage_path {
+            Some(path) => match crate::backup::destination::fetch_chain(&self.pool, backup_id)
+                .await
+            {
+                Ok(_) => {
+                    crate::backup::restore(
+                        std::path::Path::new(&path),
+                        std::path::Path::new("./data"),
+                        options,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            },
+            None => Err(anyhow::anyhow!("backup {} is not available", backup_id)),
+        };
+
+        let report = match restored {
+            Ok(report) => report,
+            Err(e) => {
+                let _ = sqlx::query(
+                    "UPDATE backup_restores SET status = 'failed', error_message = ? WHERE id = ?",
+                )
+                .bind(format!("{:#}", e))
+                .bind(restore_id)
+                .execute(&*self.pool)
+                .await;
+                return Err(format!("Restore of backup {} failed: {:#}", backup_id, e).into());
+            }
+        };
+
+        sqlx::query(
+            "UPDATE backup_restores SET status = 'completed', files_restored = ?, error_message = NULL
+             WHERE id = ?",
+        )
+        .bind(report.files_restored as i64)
+        .bind(restore_id)
         .execute(&*self.pool)
-        .await;
+        .await?;
 
+        let message = if report.database == crate::backup::DatabaseRestore::Staged {
+            tracing::warn!(
+                "Database of backup {} is staged and replaces the current one on the next restart",
+                backup_id
+            );
+            format!(
+                "Restored {} files from backup {}; restart to restore the database",
+                report.files_restored, backup_id
+            )
+        } else {
+            format!("Restored {} files from backup {}", report.files_restored, backup_id)
+        };
         Ok(JobResult::success_with_data(
-            format!("Created backup: {}", backup_id),
+            message,
+            serde_json::to_value(&report)?,
+        ))
+    }
+
+    async fn execute_backup_upload(
+        &self,
+        backup_id: &str,
+    ) -> Result<JobResult, Box<dyn std::error::Error + Send + Sync>> {
+        let uploaded = crate::backup::destination::upload(&self.pool, backup_id)
+            .await
+            .map_err(|e| format!("Upload of backup {} failed: {:#}", backup_id, e))?;
+        tracing::info!("Uploaded backup {} to {}", backup_id, uploaded.location);
+        Ok(JobResult::success_with_data(
+            format!("Uploaded backup {} to {}", backup_id, uploaded.location),
             serde_json::json!({
                 "backup_id": backup_id,
-                "location": backup_dir,
-                "size": format!("{}MB", total_size / 1024 / 1024)
+                "location": uploaded.location,
+                "removed_local": uploaded.removed_local
             }),
         ))
     }
@@ -460,40 +693,21 @@ impl JobWorker {
         &self,
         to: &str,
         subject: &str,
-        _body: &str,
+        body: &str,
     ) -> Result<JobResult, Box<dyn std::error::Error + Send + Sync>> {
-        // Get SMTP configuration from environment
-        let smtp_server = std::env::var("SMTP_SERVER").ok();
-        let smtp_user = std::env::var("SMTP_USER").ok();
-        let from_email = std::env::var("SMTP_FROM_EMAIL")
-            .unwrap_or_else(|_| "noreply@syncspace.local".to_string());
-
-        // Log email (production would use lettre for actual SMTP)
-        if smtp_server.is_some() && smtp_user.is_some() {
-            tracing::info!(
-                "Email queued: to={}, subject={}, from={}",
-                to, subject, from_email
-            );
-        } else {
-            tracing::warn!(
-                "Email would be sent (SMTP not configured): to={}, subject={}",
-                to, subject
-            );
-        }
-
-        // Record email send in database
-        let _ = sqlx::query(
-            "INSERT INTO email_logs (recipient, subject, status, sent_at) 
-             VALUES (?, ?, 'queued', datetime('now'))"
+        // Delivery and its retries are handled by the mail outbox
+        let id = crate::mail::queue(
+            &self.pool,
+            to,
+            None,
+            crate::mail::Kind::Notification,
+            &serde_json::json!({ "subject": subject, "body": body }),
         )
-        .bind(to)
-        .bind(subject)
-        .execute(&*self.pool)
-        .await;
+        .await?;
 
         Ok(JobResult::success(format!(
-            "Email queued to {} with subject: {}",
-            to, subject
+            "Email {} queued to {} with subject: {}",
+            id, to, subject
         )))
     }
 
@@ -9,7 +9,8 @@
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use uuid::Uuid;
-use base64::{Engine as _, engine::general_purpose};
+
+use crate::secrets;
 
 /// LDAP configuration stored in database
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
@@ -130,7 +131,7 @@ pub async fn create_config(
     req: UpsertLdapConfigRequest,
 ) -> Result<LdapConfig, sqlx::Error> {
     let id = Uuid::new_v4().to_string();
-    let password_encrypted = encrypt_password(&req.bind_password);
+    let password_encrypted = seal_password(&id, &req.bind_password)?;
     let group_mapping_json = req.group_role_mapping
         .map(|m| serde_json::to_string(&m).unwrap_or_else(|_| "{}".to_string()))
         .unwrap_or_else(|| "{}".to_string());
@@ -173,7 +174,7 @@ pub async fn update_config(
     config_id: &str,
     req: UpsertLdapConfigRequest,
 ) -> Result<LdapConfig, sqlx::Error> {
-    let password_encrypted = encrypt_password(&req.bind_password);
+    let password_encrypted = seal_password(config_id, &req.bind_password)?;
     let group_mapping_json = req.group_role_mapping
         .map(|m| serde_json::to_string(&m).unwrap_or_else(|_| "{}".to_string()))
         .unwrap_or_else(|| "{}".to_string());
@@ -289,14 +290,14 @@ pub async fn update_user_mapping_sync(
 
 /// Authenticate user via LDAP
 pub async fn authenticate(
+    pool: &SqlitePool,
     config: &LdapConfig,
     username: &str,
     password: &str,
 ) -> Result<LdapUser, LdapError> {
     use ldap3::{LdapConnAsync, Scope, SearchEntry};
     
-    // Decode bind password
-    let bind_password = decrypt_password(&config.bind_password_encrypted)?;
+    let bind_password = reveal_password(pool, config, "LDAP user authentication").await?;
     
     // Connect to LDAP server
     let (conn, mut ldap) = LdapConnAsync::new(&config.server_url).await
@@ -375,10 +376,10 @@ pub async fn authenticate(
 }
 
 /// Test LDAP connection
-pub async fn test_connection(config: &LdapConfig) -> LdapTestResult {
+pub async fn test_connection(pool: &SqlitePool, config: &LdapConfig) -> LdapTestResult {
     use ldap3::{LdapConnAsync, Scope};
     
-    let bind_password = match decrypt_password(&config.bind_password_encrypted) {
+    let bind_password = match reveal_password(pool, config, "LDAP connection test").await {
         Ok(p) => p,
         Err(e) => return LdapTestResult {
             success: false,
@@ -446,7 +447,7 @@ pub async fn sync_users(
 ) -> Result<SyncResult, LdapError> {
     use ldap3::{LdapConnAsync, Scope, SearchEntry};
     
-    let bind_password = decrypt_password(&config.bind_password_encrypted)?;
+    let bind_password = reveal_password(pool, config, "LDAP user sync").await?;
     
     let (conn, mut ldap) = LdapConnAsync::new(&config.server_url).await
         .map_err(|e| LdapError::ConnectionFailed(e.to_string()))?;
@@ -616,15 +617,25 @@ fn detect_server_type(url: &str) -> String {
 
 // ==================== HELPERS ====================
 
-fn encrypt_password(password: &str) -> String {
-    general_purpose::STANDARD.encode(password)
+fn seal_password(config_id: &str, password: &str) -> Result<String, sqlx::Error> {
+    secrets::seal(&secrets::LDAP_BIND_PASSWORD, config_id, password)
+        .map_err(|e| sqlx::Error::Protocol(e.to_string()))
 }
 
-fn decrypt_password(encrypted: &str) -> Result<String, LdapError> {
-    let bytes = general_purpose::STANDARD.decode(encrypted)
-        .map_err(|e| LdapError::DecryptionFailed(e.to_string()))?;
-    String::from_utf8(bytes)
-        .map_err(|e| LdapError::DecryptionFailed(e.to_string()))
+async fn reveal_password(
+    pool: &SqlitePool,
+    config: &LdapConfig,
+    purpose: &str,
+) -> Result<String, LdapError> {
+    secrets::reveal(
+        pool,
+        &secrets::LDAP_BIND_PASSWORD,
+        &config.id,
+        &config.bind_password_encrypted,
+        purpose,
+    )
+    .await
+    .map_err(|e| LdapError::DecryptionFailed(e.to_string()))
 }
 
 // ==================== ERRORS ====================
@@ -3,12 +3,32 @@
 //! This library exposes core functionality for integration tests.
 
 pub mod auth;
+pub mod backup;
+pub mod block_store;
 pub mod cron;
 pub mod database;
+pub mod delta;
+pub mod encryption;
+pub mod etag;
 pub mod jobs;
+pub mod mail;
+pub mod notify;
+pub mod rbac;
 pub mod search;
+pub mod secrets;
+pub mod security;
+pub mod storage;
+pub mod throttle;
+pub mod webdav;
 pub mod websocket;
 pub mod workers;
+pub mod workflow;
+
+/// Services that only depend on the modules above
+pub mod services {
+    pub mod change_journal;
+    pub mod version_storage_service;
+}
 
 // Re-export commonly used types
 pub use jobs::types::{Job, JobResult, JobStatus, JobType};
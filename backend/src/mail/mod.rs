@@ -0,0 +1,452 @@
+//! Outgoing mail
+//!
+//! Messages are rendered from [`templates`] and stored in `email_logs`, which
+//! doubles as the outbox. A [`Mailer`] delivers them through its
+//! [`Transport`]: an SMTP relay (STARTTLS, implicit TLS or plain, with
+//! optional authentication), a directory of `.eml` files for testing, or the
+//! log when nothing is configured. Transient failures are retried with
+//! backoff; messages the server rejects permanently are marked `bounced`.
+//!
+//! Statuses: `queued` → `sending` → `sent` (or `logged`), `retrying`,
+//! `failed` after the last attempt or for unusable messages, `bounced`.
+
+pub mod templates;
+
+pub use templates::Kind;
+
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+/// Seconds to wait before each retry; the message fails after the last one
+const RETRY_DELAYS: [i64; 5] = [60, 300, 1800, 7200, 21600];
+/// Delivery attempts including the first one
+pub const MAX_ATTEMPTS: i64 = RETRY_DELAYS.len() as i64 + 1;
+/// How often the outbox is checked for due messages
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Messages delivered per outbox pass
+const BATCH_SIZE: i64 = 50;
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the connection to the SMTP server is secured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Security {
+    /// Upgrade a plain connection (port 587 by default)
+    StartTls,
+    /// TLS from the start (port 465 by default)
+    Tls,
+    /// No encryption (port 25 by default); only for local relays
+    None,
+}
+
+impl Security {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "starttls" => Some(Security::StartTls),
+            "tls" | "ssl" | "smtps" => Some(Security::Tls),
+            "none" | "plain" => Some(Security::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: Option<u16>,
+    pub security: Security,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+pub enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    /// Write each message to `<dir>/<id>.eml` instead of sending it
+    Outbox(PathBuf),
+    /// Only log messages; used when no transport is configured
+    Log,
+}
+
+impl Transport {
+    pub fn smtp(settings: &SmtpSettings) -> Result<Self, lettre::transport::smtp::Error> {
+        let mut builder = match settings.security {
+            Security::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)?
+            }
+            Security::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.host)?,
+            Security::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host).port(25)
+            }
+        };
+        if let Some(port) = settings.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Transport::Smtp(builder.timeout(Some(SMTP_TIMEOUT)).build()))
+    }
+
+    /// `SYNCSPACE_MAIL_OUTBOX` selects the outbox directory, otherwise
+    /// `SMTP_SERVER` (with `SMTP_PORT`, `SMTP_SECURITY`, `SMTP_USER` and
+    /// `SMTP_PASSWORD`) the SMTP relay
+    pub fn from_env() -> Result<Self, String> {
+        if let Ok(dir) = std::env::var("SYNCSPACE_MAIL_OUTBOX") {
+            return Ok(Transport::Outbox(PathBuf::from(dir)));
+        }
+        let Ok(host) = std::env::var("SMTP_SERVER") else {
+            return Ok(Transport::Log);
+        };
+        let port = match std::env::var("SMTP_PORT") {
+            Ok(port) => Some(
+                port.parse()
+                    .map_err(|_| format!("Invalid SMTP_PORT: {}", port))?,
+            ),
+            Err(_) => None,
+        };
+        let security = match std::env::var("SMTP_SECURITY") {
+            Ok(value) => Security::parse(&value)
+                .ok_or_else(|| format!("Invalid SMTP_SECURITY: {}", value))?,
+            Err(_) => Security::StartTls,
+        };
+        let settings = SmtpSettings {
+            host,
+            port,
+            security,
+            username: std::env::var("SMTP_USER").ok(),
+            password: std::env::var("SMTP_PASSWORD").ok(),
+        };
+        Transport::smtp(&settings).map_err(|e| format!("Invalid SMTP settings: {}", e))
+    }
+}
+
+/// Why a delivery attempt did not succeed
+enum Failure {
+    /// Worth another attempt (connection problems, 4xx replies)
+    Transient(String),
+    /// Rejected by the server (5xx replies)
+    Permanent(String),
+    /// The stored message cannot be sent (bad address)
+    Unusable(String),
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    pool: SqlitePool,
+    transport: Transport,
+    from: Mailbox,
+}
+
+impl Mailer {
+    pub fn new(pool: SqlitePool, transport: Transport, from: Mailbox) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                pool,
+                transport,
+                from,
+            }),
+        }
+    }
+
+    /// Transport from [`Transport::from_env`], sender from `SMTP_FROM_EMAIL`
+    /// and `SMTP_FROM_NAME`
+    pub fn from_env(pool: SqlitePool) -> Result<Self, String> {
+        let email = std::env::var("SMTP_FROM_EMAIL")
+            .unwrap_or_else(|_| "noreply@syncspace.local".to_string());
+        let name = std::env::var("SMTP_FROM_NAME").unwrap_or_else(|_| "SyncSpace".to_string());
+        let address = email
+            .parse()
+            .map_err(|e| format!("Invalid SMTP_FROM_EMAIL {}: {}", email, e))?;
+        Ok(Self::new(
+            pool,
+            Transport::from_env()?,
+            Mailbox::new(Some(name), address),
+        ))
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.inner.pool
+    }
+
+    /// Attempt delivery of a queued message. Returns the new status, or
+    /// `None` when the message is not due or already being delivered.
+    pub async fn deliver(&self, id: &str) -> Result<Option<&'static str>, sqlx::Error> {
+        let claimed = sqlx::query(
+            "UPDATE email_logs SET status = 'sending', attempts = attempts + 1,
+                 updated_at = datetime('now')
+             WHERE id = ? AND status IN ('queued', 'retrying')
+               AND next_attempt_at <= datetime('now')",
+        )
+        .bind(id)
+        .execute(self.pool())
+        .await?;
+        if claimed.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let (recipient, subject, text, html, attempts): (String, String, String, String, i64) =
+            sqlx::query_as(
+                "SELECT recipient, subject, text_body, html_body, attempts
+                 FROM email_logs WHERE id = ?",
+            )
+            .bind(id)
+            .fetch_one(self.pool())
+            .await?;
+
+        let message_id = format!("<{}@{}>", id, self.inner.from.email.domain());
+        let result = match self.message(&message_id, &recipient, subject, text, html) {
+            Ok(message) => self.send(id, message).await,
+            Err(failure) => Err(failure),
+        };
+
+        let (status, retry_in, response, error) = match result {
+            Ok(response) => {
+                let status = match self.inner.transport {
+                    Transport::Log => "logged",
+                    _ => "sent",
+                };
+                (status, None, Some(response), None)
+            }
+            Err(Failure::Permanent(error)) => ("bounced", None, None, Some(error)),
+            Err(Failure::Unusable(error)) => ("failed", None, None, Some(error)),
+            Err(Failure::Transient(error)) if attempts >= MAX_ATTEMPTS => {
+                ("failed", None, None, Some(error))
+            }
+            Err(Failure::Transient(error)) => {
+                let delay = RETRY_DELAYS[(attempts as usize - 1).min(RETRY_DELAYS.len() - 1)];
+                ("retrying", Some(delay), None, Some(error))
+            }
+        };
+        if let Some(error) = &error {
+            tracing::warn!("Mail {} to {} is {}: {}", id, recipient, status, error);
+        }
+
+        sqlx::query(
+            "UPDATE email_logs SET status = ?, message_id = ?, smtp_response = ?, last_error = ?,
+                 next_attempt_at = datetime('now', '+' || COALESCE(?, 0) || ' seconds'),
+                 sent_at = CASE WHEN ? IS NULL THEN sent_at ELSE datetime('now') END,
+                 updated_at = datetime('now')
+             WHERE id = ?",
+        )
+        .bind(status)
+        .bind(&message_id)
+        .bind(&response)
+        .bind(&error)
+        .bind(retry_in)
+        .bind(&response)
+        .bind(id)
+        .execute(self.pool())
+        .await?;
+        Ok(Some(status))
+    }
+
+    /// Deliver the messages that are due; returns how many were attempted
+    pub async fn deliver_due(&self) -> Result<usize, sqlx::Error> {
+        let ids: Vec<String> = sqlx::query_scalar(
+            "SELECT id FROM email_logs
+             WHERE status IN ('queued', 'retrying') AND next_attempt_at <= datetime('now')
+             ORDER BY created_at LIMIT ?",
+        )
+        .bind(BATCH_SIZE)
+        .fetch_all(self.pool())
+        .await?;
+        let mut attempted = 0;
+        for id in ids {
+            if self.deliver(&id).await?.is_some() {
+                attempted += 1;
+            }
+        }
+        Ok(attempted)
+    }
+
+    /// Work through the outbox until the process exits
+    pub async fn run(self) {
+        // Deliveries interrupted by a restart are tried again
+        if let Err(e) = sqlx::query(
+            "UPDATE email_logs SET status = 'retrying', updated_at = datetime('now')
+             WHERE status = 'sending'",
+        )
+        .execute(self.pool())
+        .await
+        {
+            tracing::warn!("Failed to reset interrupted mail deliveries: {}", e);
+        }
+
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.deliver_due().await {
+                tracing::warn!("Failed to process the mail outbox: {}", e);
+            }
+        }
+    }
+
+    fn message(
+        &self,
+        message_id: &str,
+        recipient: &str,
+        subject: String,
+        text: String,
+        html: String,
+    ) -> Result<Message, Failure> {
+        let to: Mailbox = recipient
+            .parse()
+            .map_err(|e| Failure::Unusable(format!("Invalid recipient {}: {}", recipient, e)))?;
+        Message::builder()
+            .from(self.inner.from.clone())
+            .to(to)
+            .subject(subject)
+            .message_id(Some(message_id.to_string()))
+            .multipart(MultiPart::alternative_plain_html(text, html))
+            .map_err(|e| Failure::Unusable(format!("Failed to build message: {}", e)))
+    }
+
+    async fn send(&self, id: &str, message: Message) -> Result<String, Failure> {
+        match &self.inner.transport {
+            Transport::Smtp(smtp) => match smtp.send(message).await {
+                Ok(response) => Ok(format!(
+                    "{} {}",
+                    response.code(),
+                    response.message().collect::<Vec<_>>().join(" ")
+                )),
+                Err(e) if e.is_permanent() => Err(Failure::Permanent(e.to_string())),
+                Err(e) => Err(Failure::Transient(e.to_string())),
+            },
+            Transport::Outbox(dir) => {
+                let path = dir.join(format!("{}.eml", id));
+                let written = async {
+                    tokio::fs::create_dir_all(dir).await?;
+                    tokio::fs::write(&path, message.formatted()).await
+                };
+                match written.await {
+                    Ok(()) => Ok(format!("written to {}", path.display())),
+                    Err(e) => Err(Failure::Transient(format!(
+                        "Failed to write {}: {}",
+                        path.display(),
+                        e
+                    ))),
+                }
+            }
+            Transport::Log => {
+                let envelope = message.envelope();
+                tracing::info!(
+                    "Mail {} to {:?} not sent (no transport configured)",
+                    id,
+                    envelope.to()
+                );
+                Ok("logged".to_string())
+            }
+        }
+    }
+}
+
+static MAILER: OnceLock<Mailer> = OnceLock::new();
+
+/// Deliver newly queued messages right away through `mailer`
+pub fn install(mailer: Mailer) {
+    if MAILER.set(mailer).is_err() {
+        tracing::warn!("Mailer is already installed");
+    }
+}
+
+pub fn mailer() -> Option<&'static Mailer> {
+    MAILER.get()
+}
+
+/// `path` below `SYNCSPACE_PUBLIC_URL`, for links in messages
+pub fn link(path: &str) -> String {
+    let base = std::env::var("SYNCSPACE_PUBLIC_URL")
+        .unwrap_or_else(|_| "http://localhost:8080".to_string());
+    format!("{}{}", base.trim_end_matches('/'), path)
+}
+
+/// Render `kind` for `to` and store it in the outbox. Delivery starts at
+/// once when a mailer is installed and is otherwise left to the outbox.
+pub async fn queue(
+    pool: &SqlitePool,
+    to: &str,
+    user_id: Option<&str>,
+    kind: Kind,
+    context: &Value,
+) -> Result<String, sqlx::Error> {
+    let mut context = context.clone();
+    if let Some(values) = context.as_object_mut() {
+        values
+            .entry("public_url")
+            .or_insert_with(|| Value::String(link("")));
+    }
+    let rendered = templates::render(kind, &context);
+
+    let id = uuid::Uuid::new_v4().to_string();
+    sqlx::query(
+        "INSERT INTO email_logs (id, user_id, recipient, template, subject, text_body, html_body)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&id)
+    .bind(user_id)
+    .bind(to)
+    .bind(kind.as_str())
+    .bind(&rendered.subject)
+    .bind(&rendered.text)
+    .bind(&rendered.html)
+    .execute(pool)
+    .await?;
+
+    if let Some(mailer) = mailer() {
+        let mailer = mailer.clone();
+        let id = id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mailer.deliver(&id).await {
+                tracing::warn!("Failed to deliver mail {}: {}", id, e);
+            }
+        });
+    }
+    Ok(id)
+}
+
+/// [`queue`] for a user's address; `None` when the user has no address
+pub async fn queue_for_user(
+    pool: &SqlitePool,
+    user_id: &str,
+    kind: Kind,
+    context: &Value,
+) -> Result<Option<String>, sqlx::Error> {
+    let email: Option<Option<String>> = sqlx::query_scalar("SELECT email FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    match email.flatten().filter(|email| !email.trim().is_empty()) {
+        Some(email) => queue(pool, &email, Some(user_id), kind, context)
+            .await
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_security() {
+        assert_eq!(Security::parse("STARTTLS"), Some(Security::StartTls));
+        assert_eq!(Security::parse("ssl"), Some(Security::Tls));
+        assert_eq!(Security::parse("none"), Some(Security::None));
+        assert_eq!(Security::parse("maybe"), None);
+    }
+
+    #[test]
+    fn test_retries_are_bounded() {
+        assert_eq!(MAX_ATTEMPTS, 6);
+        assert!(RETRY_DELAYS.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
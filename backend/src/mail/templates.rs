@@ -0,0 +1,216 @@
+//! Message templates
+//!
+//! Every kind of mail has a subject, a plain text and an HTML template.
+//! `{{name}}` is replaced by the context value `name` (escaped in the HTML
+//! part); unknown names render as nothing.
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Free-form message from a job or workflow action
+    Notification,
+    /// A file was shared with an existing user
+    ShareInvite,
+    /// Someone outside the instance was invited as a guest
+    GuestInvitation,
+    /// A storage, bandwidth or rate limit quota was reached
+    QuotaAlert,
+    /// Several notifications batched into one message
+    Digest,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Notification => "notification",
+            Kind::ShareInvite => "share_invite",
+            Kind::GuestInvitation => "guest_invitation",
+            Kind::QuotaAlert => "quota_alert",
+            Kind::Digest => "digest",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [
+            Kind::Notification,
+            Kind::ShareInvite,
+            Kind::GuestInvitation,
+            Kind::QuotaAlert,
+            Kind::Digest,
+        ]
+        .into_iter()
+        .find(|kind| kind.as_str() == value)
+    }
+
+    fn template(self) -> Template {
+        match self {
+            Kind::Notification => NOTIFICATION,
+            Kind::ShareInvite => SHARE_INVITE,
+            Kind::GuestInvitation => GUEST_INVITATION,
+            Kind::QuotaAlert => QUOTA_ALERT,
+            Kind::Digest => DIGEST,
+        }
+    }
+}
+
+struct Template {
+    subject: &'static str,
+    text: &'static str,
+    html: &'static str,
+}
+
+const NOTIFICATION: Template = Template {
+    subject: "{{subject}}",
+    text: "{{body}}\n\n-- \nSyncSpace {{public_url}}\n",
+    html: r#"<p style="white-space: pre-wrap">{{body}}</p>
+<p style="color: #888">SyncSpace</p>"#,
+};
+
+const SHARE_INVITE: Template = Template {
+    subject: "{{sharer}} shared \"{{file_name}}\" with you",
+    text: "{{sharer}} shared \"{{file_name}}\" with you ({{permission}} access).\n\n\
+           Open it in SyncSpace: {{link}}\n",
+    html: r#"<p><strong>{{sharer}}</strong> shared <strong>{{file_name}}</strong> with you ({{permission}} access).</p>
+<p><a href="{{link}}">Open it in SyncSpace</a></p>"#,
+};
+
+const GUEST_INVITATION: Template = Template {
+    subject: "{{inviter}} invited you to SyncSpace",
+    text: "{{inviter}} invited you to access files on SyncSpace.\n\n{{message}}\n\n\
+           Accept the invitation: {{link}}\nThe invitation expires on {{expires_at}}.\n",
+    html: r#"<p><strong>{{inviter}}</strong> invited you to access files on SyncSpace.</p>
+<blockquote style="white-space: pre-wrap">{{message}}</blockquote>
+<p><a href="{{link}}">Accept the invitation</a></p>
+<p style="color: #888">The invitation expires on {{expires_at}}.</p>"#,
+};
+
+const QUOTA_ALERT: Template = Template {
+    subject: "SyncSpace quota alert: {{alert_type}}",
+    text: "{{message}}\n\nReview your usage: {{link}}\n",
+    html: r#"<p>{{message}}</p>
+<p><a href="{{link}}">Review your usage</a></p>"#,
+};
+
+const DIGEST: Template = Template {
+    subject: "{{count}} new notifications on SyncSpace",
+    text: "You have {{count}} new notifications:\n\n{{items}}\n\nSee them all: {{link}}\n",
+    html: r#"<p>You have {{count}} new notifications:</p>
+<div style="white-space: pre-wrap">{{items}}</div>
+<p><a href="{{link}}">See them all</a></p>"#,
+};
+
+/// A message ready to be stored in the outbox
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rendered {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Render the templates of `kind` with the values of `context`
+pub fn render(kind: Kind, context: &Value) -> Rendered {
+    let template = kind.template();
+    Rendered {
+        // Header values must stay on one line
+        subject: substitute(template.subject, context, false).replace(['\r', '\n'], " "),
+        text: substitute(template.text, context, false),
+        html: substitute(template.html, context, true),
+    }
+}
+
+fn substitute(template: &str, context: &Value, html: bool) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = rest[start + 2..start + end].trim();
+        let value = match context.get(name) {
+            Some(Value::String(s)) => s.clone(),
+            Some(Value::Null) | None => String::new(),
+            Some(other) => other.to_string(),
+        };
+        if html {
+            out.push_str(&escape_html(&value));
+        } else {
+            out.push_str(&value);
+        }
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn escape_html(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_escapes_html_only() {
+        let rendered = render(
+            Kind::ShareInvite,
+            &json!({
+                "sharer": "Ann <ann@example.com>",
+                "file_name": "Q3 & Q4.xlsx",
+                "permission": "read",
+                "link": "https://files.example.com/#/shared",
+            }),
+        );
+        assert_eq!(
+            rendered.subject,
+            "Ann <ann@example.com> shared \"Q3 & Q4.xlsx\" with you"
+        );
+        assert!(
+            rendered
+                .text
+                .contains("Ann <ann@example.com> shared \"Q3 & Q4.xlsx\"")
+        );
+        assert!(
+            rendered
+                .html
+                .contains("<strong>Ann &lt;ann@example.com&gt;</strong>")
+        );
+        assert!(rendered.html.contains("<strong>Q3 &amp; Q4.xlsx</strong>"));
+        assert!(
+            rendered
+                .html
+                .contains(r#"href="https://files.example.com/#/shared""#)
+        );
+    }
+
+    #[test]
+    fn test_render_missing_and_non_string_values() {
+        let rendered = render(
+            Kind::Notification,
+            &json!({"subject": "Line one\r\nBcc: someone@example.com", "body": 42}),
+        );
+        assert_eq!(rendered.subject, "Line one  Bcc: someone@example.com");
+        assert!(rendered.text.starts_with("42\n"));
+        assert!(rendered.text.contains("SyncSpace \n"));
+
+        assert_eq!(
+            substitute("{{a}} {{ b }} {{c", &json!({"a": 1, "b": "x"}), false),
+            "1 x {{c"
+        );
+    }
+}
@@ -11,20 +11,33 @@
 
 mod api;
 mod auth;
+mod backup;
+mod block_store;
 mod cron;
 mod database;
+mod delta;
 mod database_monitor;
 mod db_monitor;
 mod encryption;
+mod etag;
+mod http_range;
 mod jobs;
+mod mail;
 mod middleware;
 mod models;
+mod notify;
+mod rbac;
 mod search;
+mod secrets;
 mod security;
 mod services;
 mod status;
+mod storage;
+mod throttle;
+mod webdav;
 mod websocket;
 mod workers;
+mod workflow;
 mod conversion_worker;
 
 // New modules from POST_ALPHA_ROADMAP
@@ -49,14 +62,11 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use axum::{
     extract::DefaultBodyLimit,
-    extract::{State as AxumState, WebSocketUpgrade},
-    http::Method,
+    http::{header, HeaderName, Method},
     middleware as axum_middleware,
-    response::Response,
     routing::get,
     Router,
 };
-use sqlx::Row;
 use tokio::sync::{broadcast, Mutex};
 use tower_http::cors::{Any, CorsLayer};
 
@@ -182,6 +192,13 @@ async fn main() {
     // Load or create config
     let config = Arc::new(Mutex::new(Config::default()));
 
+    // A restore into the running instance stages its database for this point
+    match backup::apply_staged_database(std::path::Path::new(DATA_DIR)) {
+        Ok(true) => tracing::info!("Restored database from backup put in place"),
+        Ok(false) => {}
+        Err(e) => panic!("Failed to put restored database in place: {}", e),
+    }
+
     // Initialize database with migrations
     let db_pool = database::init_db()
         .await
@@ -240,57 +257,8 @@ async fn main() {
         tokio::spawn(async move {
             println!("🔍 Starting background search index rebuild...");
 
-            match sqlx::query(
-                "SELECT id, name, path, size_bytes, updated_at FROM files WHERE is_deleted = 0",
-            )
-            .fetch_all(&pool)
-            .await
-            {
-                Ok(files) => {
-                    let mut count = 0;
-                    for row in files {
-                        if let (
-                            Ok(file_id),
-                            Ok(filename),
-                            Ok(path),
-                            Ok(size_bytes),
-                            Ok(updated_at),
-                        ) = (
-                            row.try_get::<String, _>("id"),
-                            row.try_get::<String, _>("name"),
-                            row.try_get::<String, _>("path"),
-                            row.try_get::<i64, _>("size_bytes"),
-                            row.try_get::<String, _>("updated_at"),
-                        ) {
-                            let modified = chrono::DateTime::parse_from_rfc3339(&updated_at)
-                                .unwrap_or_else(|_| chrono::Utc::now().into())
-                                .with_timezone(&chrono::Utc);
-
-                            let file_path = std::path::Path::new("./data").join(&path);
-                            let content = if file_path.exists() {
-                                crate::search::extract_content(&file_path).await
-                            } else {
-                                None
-                            };
-
-                            if index
-                                .index_file(
-                                    &file_id,
-                                    &filename,
-                                    &path,
-                                    content,
-                                    modified,
-                                    size_bytes as u64,
-                                )
-                                .await
-                                .is_ok()
-                            {
-                                count += 1;
-                            }
-                        }
-                    }
-                    println!("✅ Background reindex complete: {} files indexed", count);
-                }
+            match crate::search::reindex_tree(&index, &pool, "").await {
+                Ok(count) => println!("✅ Background reindex complete: {} files indexed", count),
                 Err(e) => eprintln!("❌ Background reindex failed: {:?}", e),
             }
         });
@@ -338,6 +306,36 @@ async fn main() {
     services::job_worker::spawn_worker(db_pool.clone());
     println!("✅ Background job worker started");
 
+    // Load storage backends and resume interrupted storage migrations
+    if let Err(e) = storage::reload(&db_pool).await {
+        tracing::error!("Failed to load storage backends: {}", e);
+    }
+    match storage::migration::resume_jobs(&db_pool).await {
+        Ok(0) => {}
+        Ok(resumed) => tracing::info!("Resumed {} storage migration job(s)", resumed),
+        Err(e) => tracing::error!("Failed to resume storage migrations: {}", e),
+    }
+
+    // Watch the data directory for changes made outside the API (SMB, rsync, ...)
+    if services::fs_watcher::watcher_enabled() {
+        match services::fs_watcher::spawn_watcher(
+            db_pool.clone(),
+            search_index.clone(),
+            fs_tx.clone(),
+            admin_user_id_string.clone(),
+        ) {
+            Ok(()) => println!("✅ Filesystem watcher started"),
+            Err(e) => eprintln!("⚠️  Filesystem watcher unavailable: {}", e),
+        }
+    }
+
+    // Pull shared folders from paired SyncSpace instances
+    services::peer_sync::spawn_sync_loop(services::peer_sync::SyncContext::new(
+        db_pool.clone(),
+        search_index.clone(),
+        fs_tx.clone(),
+    ));
+
     // Start trash auto-cleanup task (deletes items older than 30 days, runs every hour)
     {
         let cleanup_pool = db_pool.clone();
@@ -368,6 +366,14 @@ async fn main() {
                         tracing::error!("❌ Trash auto-cleanup failed: {:?}", e);
                     }
                 }
+
+                // Drop change journal entries past their retention
+                let journal_days = services::change_journal::retention_days();
+                match services::change_journal::prune(&cleanup_pool, journal_days).await {
+                    Ok(0) => {}
+                    Ok(pruned) => tracing::info!("Pruned {} change journal entries older than {} days", pruned, journal_days),
+                    Err(e) => tracing::error!("❌ Change journal pruning failed: {}", e),
+                }
             }
         });
         println!("✅ Trash auto-cleanup task started (30-day retention)");
@@ -393,6 +399,57 @@ async fn main() {
         ws_connections: Arc::new(AtomicUsize::new(0)),
     };
 
+    // Seal stored credentials (OAuth, LDAP, FTP, IMAP, webhooks) under the
+    // master key, see secrets::keys for where it comes from
+    match secrets::Vault::from_env() {
+        Ok(vault) => {
+            secrets::install(vault);
+            match secrets::maintain(&app_state.db_pool).await {
+                Ok(0) => {}
+                Ok(sealed) => println!("🔐 Sealed {} stored credentials", sealed),
+                Err(e) => tracing::error!("Failed to seal stored credentials: {}", e),
+            }
+            println!("✅ Secrets vault ready");
+        }
+        Err(e) => tracing::error!(
+            "Secrets vault disabled, stored credentials cannot be used: {}",
+            e
+        ),
+    }
+
+    // Number file events for the WebSocket endpoint, which replays them to
+    // clients that reconnect
+    websocket::install(websocket::Hub::start(&app_state.fs_tx));
+
+    // Run workflow rules on file events; actions execute on the job workers
+    let workflow_engine = workflow::WorkflowEngine::new(
+        app_state.db_pool.clone(),
+        "./data",
+        Arc::new(services::workflow_runner::AppActionRunner::new(app_state.clone())),
+    );
+    workflow_engine.spawn(&app_state.fs_tx);
+    workflow::install(workflow_engine);
+    println!("✅ Workflow engine started");
+
+    // Deliver outgoing mail (SMTP, an outbox directory or the log, see mail::Transport)
+    match mail::Mailer::from_env(app_state.db_pool.clone()) {
+        Ok(mailer) => {
+            mail::install(mailer.clone());
+            tokio::spawn(mailer.run());
+            println!("✅ Mail outbox started");
+        }
+        Err(e) => tracing::error!("Mail delivery disabled: {}", e),
+    }
+
+    // Route notifications to each user's channels and send due digests
+    let dispatcher = notify::Dispatcher::new(
+        app_state.db_pool.clone(),
+        Some(app_state.fs_tx.clone()),
+    );
+    notify::install(dispatcher.clone());
+    tokio::spawn(dispatcher.run());
+    println!("✅ Notification dispatcher started");
+
     // Optional: Start pool monitoring task (commented out - requires db_monitor)
     // let monitor_pool = db_pool.clone();
     // let monitor_db_monitor = db_monitor.clone();
@@ -497,6 +554,16 @@ async fn main() {
                 Err(e) => tracing::error!("Failed to cleanup old file versions: {}", e),
             }
 
+            // Cleanup abandoned chunked/tus uploads
+            match services::cleanup_service::cleanup_abandoned_uploads(&cleanup_pool).await {
+                Ok(count) => {
+                    if count > 0 {
+                        tracing::info!("🧹 Cleaned up {} abandoned uploads", count);
+                    }
+                }
+                Err(e) => tracing::error!("Failed to cleanup abandoned uploads: {}", e),
+            }
+
             // Cleanup expired deleted files (soft-deleted older than 30 days)
             let cleanup_config = services::cleanup_service::CleanupConfig::default();
             match services::cleanup_service::cleanup_expired_deleted_files(
@@ -518,6 +585,20 @@ async fn main() {
                 }
                 Err(e) => tracing::error!("Failed to cleanup expired deleted files: {}", e),
             }
+
+            // Reclaim block store space released by deleted versions and backups
+            match block_store::garbage_collect(&cleanup_pool).await {
+                Ok((blocks, bytes)) => {
+                    if blocks > 0 {
+                        tracing::info!(
+                            "🧹 Block store GC: removed {} blocks ({:.2} MB)",
+                            blocks,
+                            bytes as f64 / (1024.0 * 1024.0)
+                        );
+                    }
+                }
+                Err(e) => tracing::error!("Failed to garbage collect block store: {}", e),
+            }
         }
     });
 
@@ -525,7 +606,13 @@ async fn main() {
         .await
         .expect("Failed to bind address");
 
-    axum::serve(listener, app).await.expect("Server error");
+    // Peer addresses are needed for API token IP allowlists
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .expect("Server error");
 
     // Wait for workers to finish (won't reach here normally)
     let _ = worker_handle.await;
@@ -533,24 +620,37 @@ async fn main() {
 
 // ==================== ROUTER BUILDER ====================
 
-/// WebSocket handler for main app (with AppState)
-async fn ws_handler(ws: WebSocketUpgrade, AxumState(state): AxumState<AppState>) -> Response {
-    let fs_tx = state.fs_tx.clone();
-    ws.on_upgrade(|socket| async move { websocket::handle_socket(socket, fs_tx).await })
-}
-
 fn build_router(state: AppState) -> Router {
     // CORS configuration
     let cors = CorsLayer::new()
         .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-        .allow_headers(Any);
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::PATCH,
+            Method::HEAD,
+        ])
+        .allow_headers(Any)
+        // Range downloads and tus resumable uploads need these readable from JS
+        .expose_headers([
+            header::CONTENT_RANGE,
+            header::ETAG,
+            header::LOCATION,
+            HeaderName::from_static("tus-resumable"),
+            HeaderName::from_static("tus-version"),
+            HeaderName::from_static("tus-extension"),
+            HeaderName::from_static("upload-offset"),
+            HeaderName::from_static("upload-length"),
+            HeaderName::from_static("upload-expires"),
+        ]);
 
     Router::new()
         // Root - Status page (direct access on http://localhost:8080)
         .route("/", get(status::get_status_html))
         // WebSocket endpoint
-        .route("/api/ws", get(ws_handler))
+        .route("/api/ws", get(api::websocket::ws_handler))
         // Public status endpoints (no auth required)
         .route("/status", get(status::get_status_html))
         .route("/status/json", get(status::get_status_json))
@@ -1,15 +1,17 @@
 //! Authentication middleware
 
 use axum::{
-    extract::{Request, State},
+    extract::{ConnectInfo, Request, State},
     http::StatusCode,
     middleware::Next,
     response::Response,
 };
 
-use crate::{auth::{User, UserInfo}, AppState};
+use std::net::SocketAddr;
 
-/// Auth middleware - validates JWT and extracts user info
+use crate::{auth::{Session, User, UserInfo}, rbac::tokens, AppState};
+
+/// Auth middleware - validates JWT or personal access token and extracts user info
 pub async fn auth_middleware(
     State(state): State<AppState>,
     mut req: Request,
@@ -29,13 +31,32 @@ pub async fn auth_middleware(
 
     let token = &auth_header[7..];
 
+    // Personal access tokens also carry their restrictions, checked per route
+    if token.starts_with("ssk_") {
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let client_ip = tokens::client_ip(req.headers(), peer);
+        let (user_info, grant) =
+            crate::api::api_tokens::authenticate(&state.db_pool, token, client_ip).await?;
+        req.extensions_mut().insert(User(user_info));
+        req.extensions_mut()
+            .insert(Session::for_api_token(&grant.token_id));
+        req.extensions_mut().insert(grant);
+        return Ok(next.run(req).await);
+    }
+
     // Decode and validate JWT against SQLite database
     let user_info: UserInfo = crate::auth::validate_token_against_db(&state.db_pool, token)
         .await
         .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let claims = crate::auth::verify_token(token).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let session = Session::for_access_token(token, &claims);
 
     // Insert user info into request extensions
     req.extensions_mut().insert(User(user_info));
+    req.extensions_mut().insert(session);
 
     Ok(next.run(req).await)
 }
@@ -0,0 +1,482 @@
+//! Notification dispatcher
+//!
+//! Delivers events addressed to a user over the channels their
+//! [`preferences`] choose for the event type: the in-app inbox
+//! (`notifications`), a WebSocket event, email through [`crate::mail`] and
+//! webhook jobs for the user's webhooks subscribed to `notification.<type>`.
+//! WebSocket events are skipped and email is held back during the user's
+//! quiet hours. Held-back and digested email waits in
+//! `notification_digest_items` until it is due; several items due together
+//! are sent as one digest message.
+
+pub mod preferences;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::SqlitePool;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::broadcast;
+
+use crate::jobs::queue::JobQueue;
+use crate::jobs::types::{Job, JobType};
+use crate::mail::{self, Kind};
+use crate::websocket::FileChangeEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    ShareReceived,
+    CommentMention,
+    QuotaAlert,
+    JobFailed,
+}
+
+impl EventType {
+    pub const ALL: [EventType; 4] = [
+        EventType::ShareReceived,
+        EventType::CommentMention,
+        EventType::QuotaAlert,
+        EventType::JobFailed,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventType::ShareReceived => "share_received",
+            EventType::CommentMention => "comment_mention",
+            EventType::QuotaAlert => "quota_alert",
+            EventType::JobFailed => "job_failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.as_str() == value)
+    }
+
+    /// Webhook event the user's webhooks subscribe to
+    pub fn webhook_event(self) -> String {
+        format!("notification.{}", self.as_str())
+    }
+
+    fn mail_kind(self) -> Kind {
+        match self {
+            EventType::ShareReceived => Kind::ShareInvite,
+            EventType::QuotaAlert => Kind::QuotaAlert,
+            EventType::CommentMention | EventType::JobFailed => Kind::Notification,
+        }
+    }
+}
+
+/// An event addressed to one user
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub user_id: String,
+    pub event: EventType,
+    pub title: String,
+    pub message: String,
+    /// Frontend route the notification leads to, e.g. `/#/shared`
+    pub action_url: Option<String>,
+    pub priority: &'static str,
+    /// User who caused the event
+    pub related_user_id: Option<String>,
+    /// Values for the email template of the event type and the webhook payload
+    pub context: Value,
+}
+
+impl Notification {
+    pub fn new(user_id: &str, event: EventType, title: &str, message: &str) -> Self {
+        Self {
+            user_id: user_id.to_string(),
+            event,
+            title: title.to_string(),
+            message: message.to_string(),
+            action_url: None,
+            priority: "normal",
+            related_user_id: None,
+            context: json!({}),
+        }
+    }
+
+    pub fn with_action_url(mut self, action_url: &str) -> Self {
+        self.action_url = Some(action_url.to_string());
+        self
+    }
+
+    pub fn with_priority(mut self, priority: &'static str) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn from_user(mut self, user_id: &str) -> Self {
+        self.related_user_id = Some(user_id.to_string());
+        self
+    }
+
+    pub fn with_context(mut self, context: Value) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Template context of the email, filled in from the notification where
+    /// the caller left values out
+    fn mail_context(&self) -> Value {
+        let link = mail::link(self.action_url.as_deref().unwrap_or("/#/notifications"));
+        let body = format!("{}\n\n{}", self.message, link);
+        let mut context = self.context.clone();
+        if let Some(values) = context.as_object_mut() {
+            for (name, value) in [
+                ("subject", &self.title),
+                ("message", &self.message),
+                ("body", &body),
+                ("link", &link),
+            ] {
+                values
+                    .entry(name)
+                    .or_insert_with(|| Value::String(value.clone()));
+            }
+        }
+        context
+    }
+}
+
+/// Where a notification went
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delivery {
+    /// Id in `notifications`
+    pub in_app: Option<String>,
+    pub websocket: bool,
+    /// Id in `email_logs` of mail queued right away
+    pub email: Option<String>,
+    /// When held-back or digested mail is due
+    pub email_due: Option<DateTime<Utc>>,
+    /// Webhook deliveries queued
+    pub webhooks: usize,
+}
+
+#[derive(sqlx::FromRow)]
+struct DigestItem {
+    id: String,
+    title: String,
+    message: String,
+    mail_template: String,
+    mail_context: String,
+}
+
+/// Format of `deliver_after`, which compares as text
+const DUE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Clone)]
+pub struct Dispatcher {
+    pool: SqlitePool,
+    events: Option<broadcast::Sender<FileChangeEvent>>,
+}
+
+impl Dispatcher {
+    /// `events` carries WebSocket notifications; without it that channel
+    /// is skipped
+    pub fn new(pool: SqlitePool, events: Option<broadcast::Sender<FileChangeEvent>>) -> Self {
+        Self { pool, events }
+    }
+
+    pub async fn dispatch(&self, notification: &Notification) -> Result<Delivery, sqlx::Error> {
+        self.dispatch_at(notification, Utc::now()).await
+    }
+
+    /// Deliver `notification` as at `now`
+    pub async fn dispatch_at(
+        &self,
+        notification: &Notification,
+        now: DateTime<Utc>,
+    ) -> Result<Delivery, sqlx::Error> {
+        let user_id = notification.user_id.as_str();
+        let preference =
+            preferences::event_preference(&self.pool, user_id, notification.event).await?;
+        let settings = preferences::settings(&self.pool, user_id).await?;
+        let mut delivery = Delivery::default();
+
+        if preference.in_app {
+            delivery.in_app = Some(self.store(notification, now).await?);
+        }
+
+        if preference.websocket
+            && !settings.is_quiet(now)
+            && let Some(events) = &self.events
+        {
+            let event = FileChangeEvent::Notification {
+                user_id: user_id.to_string(),
+                id: delivery
+                    .in_app
+                    .clone()
+                    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+                notification_type: notification.event.as_str().to_string(),
+                title: notification.title.clone(),
+                message: notification.message.clone(),
+                action_url: notification.action_url.clone(),
+                timestamp: now.to_rfc3339(),
+            };
+            // Sending only fails when no socket is listening
+            delivery.websocket = events.send(event).is_ok();
+        }
+
+        if preference.email {
+            let kind = notification.event.mail_kind();
+            let context = notification.mail_context();
+            match settings.email_due(preference.digest(), now) {
+                None => {
+                    delivery.email =
+                        mail::queue_for_user(&self.pool, user_id, kind, &context).await?;
+                }
+                Some(due) => {
+                    sqlx::query(
+                        "INSERT INTO notification_digest_items
+                             (id, user_id, event_type, title, message, mail_template, mail_context, deliver_after)
+                         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                    )
+                    .bind(uuid::Uuid::new_v4().to_string())
+                    .bind(user_id)
+                    .bind(notification.event.as_str())
+                    .bind(&notification.title)
+                    .bind(&notification.message)
+                    .bind(kind.as_str())
+                    .bind(context.to_string())
+                    .bind(due.format(DUE_FORMAT).to_string())
+                    .execute(&self.pool)
+                    .await?;
+                    delivery.email_due = Some(due);
+                }
+            }
+        }
+
+        if preference.webhook {
+            delivery.webhooks = self.queue_webhooks(notification, &delivery).await?;
+        }
+
+        Ok(delivery)
+    }
+
+    async fn store(
+        &self,
+        notification: &Notification,
+        now: DateTime<Utc>,
+    ) -> Result<String, sqlx::Error> {
+        let id = uuid::Uuid::new_v4().to_string();
+        sqlx::query(
+            "INSERT INTO notifications
+                 (id, user_id, type, title, message, action_url, priority, related_user_id, is_read, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, 0, ?)",
+        )
+        .bind(&id)
+        .bind(&notification.user_id)
+        .bind(notification.event.as_str())
+        .bind(&notification.title)
+        .bind(&notification.message)
+        .bind(&notification.action_url)
+        .bind(notification.priority)
+        .bind(&notification.related_user_id)
+        .bind(now.to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// Queue a delivery job for every active webhook of the user that
+    /// subscribes to the event
+    async fn queue_webhooks(
+        &self,
+        notification: &Notification,
+        delivery: &Delivery,
+    ) -> Result<usize, sqlx::Error> {
+        let event = notification.event.webhook_event();
+        let webhooks: Vec<(String, String)> =
+            sqlx::query_as("SELECT id, events FROM webhooks WHERE user_id = ? AND is_active = 1")
+                .bind(&notification.user_id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        let queue = JobQueue::new(Arc::new(self.pool.clone()));
+        let payload = json!({
+            "id": delivery.in_app,
+            "type": notification.event.as_str(),
+            "title": notification.title,
+            "message": notification.message,
+            "action_url": notification.action_url,
+            "data": notification.context,
+        });
+        let mut queued = 0;
+        for (webhook_id, events) in webhooks {
+            let events: Vec<String> = serde_json::from_str(&events).unwrap_or_default();
+            if !events.contains(&event) {
+                continue;
+            }
+            let job_type = JobType::WebhookDelivery {
+                webhook_id,
+                event: event.clone(),
+                payload: payload.clone(),
+            };
+            // Queued without an owner, so a failed delivery does not
+            // notify the user of yet another failed job
+            let job = Job::new(job_type, None).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+            queue.enqueue(job).await?;
+            queued += 1;
+        }
+        Ok(queued)
+    }
+
+    pub async fn flush_digests(&self) -> Result<usize, sqlx::Error> {
+        self.flush_digests_at(Utc::now()).await
+    }
+
+    /// Send the held-back and digested email due at `now`, one message per
+    /// user. Returns the number of messages queued.
+    pub async fn flush_digests_at(&self, now: DateTime<Utc>) -> Result<usize, sqlx::Error> {
+        let due = now.format(DUE_FORMAT).to_string();
+        let users: Vec<String> = sqlx::query_scalar(
+            "SELECT DISTINCT user_id FROM notification_digest_items WHERE deliver_after <= ?",
+        )
+        .bind(&due)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut sent = 0;
+        for user_id in users {
+            let items: Vec<DigestItem> = sqlx::query_as(
+                "SELECT id, title, message, mail_template, mail_context
+                 FROM notification_digest_items
+                 WHERE user_id = ? AND deliver_after <= ?
+                 ORDER BY created_at, rowid",
+            )
+            .bind(&user_id)
+            .bind(&due)
+            .fetch_all(&self.pool)
+            .await?;
+
+            let queued = match items.as_slice() {
+                [] => continue,
+                // A single item keeps the message of its own event type
+                [item] => {
+                    let kind = Kind::parse(&item.mail_template).unwrap_or(Kind::Notification);
+                    let context = serde_json::from_str(&item.mail_context).unwrap_or(json!({}));
+                    mail::queue_for_user(&self.pool, &user_id, kind, &context).await?
+                }
+                items => {
+                    let lines: Vec<String> = items
+                        .iter()
+                        .map(|item| format!("- {}: {}", item.title, item.message))
+                        .collect();
+                    let context = json!({
+                        "count": items.len(),
+                        "items": lines.join("\n"),
+                        "link": mail::link("/#/notifications"),
+                    });
+                    mail::queue_for_user(&self.pool, &user_id, Kind::Digest, &context).await?
+                }
+            };
+            if queued.is_some() {
+                sent += 1;
+            }
+
+            for item in &items {
+                sqlx::query("DELETE FROM notification_digest_items WHERE id = ?")
+                    .bind(&item.id)
+                    .execute(&self.pool)
+                    .await?;
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Send digests as they come due
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.flush_digests().await {
+                tracing::warn!("Failed to send notification digests: {}", e);
+            }
+        }
+    }
+}
+
+static DISPATCHER: OnceLock<Dispatcher> = OnceLock::new();
+
+/// Make `dispatcher` available to API handlers, job workers and services
+pub fn install(dispatcher: Dispatcher) {
+    if DISPATCHER.set(dispatcher).is_err() {
+        tracing::warn!("Notification dispatcher is already installed");
+    }
+}
+
+pub fn dispatcher() -> Option<&'static Dispatcher> {
+    DISPATCHER.get()
+}
+
+/// Dispatch `notification` in the background; nothing happens when no
+/// dispatcher is installed
+pub fn send(notification: Notification) {
+    let Some(dispatcher) = dispatcher() else {
+        return;
+    };
+    tokio::spawn(async move {
+        if let Err(e) = dispatcher.dispatch(&notification).await {
+            tracing::warn!(
+                "Failed to dispatch {} notification for {}: {}",
+                notification.event.as_str(),
+                notification.user_id,
+                e
+            );
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_type_names() {
+        for event in EventType::ALL {
+            assert_eq!(EventType::parse(event.as_str()), Some(event));
+            // Stored preferences and API payloads use the same names
+            assert_eq!(serde_json::to_value(event).unwrap(), json!(event.as_str()));
+        }
+        assert_eq!(EventType::parse("share"), None);
+        assert_eq!(
+            EventType::QuotaAlert.webhook_event(),
+            "notification.quota_alert"
+        );
+        assert_eq!(EventType::ShareReceived.mail_kind(), Kind::ShareInvite);
+        assert_eq!(EventType::JobFailed.mail_kind(), Kind::Notification);
+    }
+
+    #[test]
+    fn test_mail_context_keeps_caller_values() {
+        let notification = Notification::new(
+            "alice",
+            EventType::ShareReceived,
+            "New share",
+            "bob shared plan.txt",
+        )
+        .with_action_url("/#/shared")
+        .from_user("bob")
+        .with_context(json!({ "subject": "bob shared a file", "file": "plan.txt" }));
+        assert_eq!(notification.related_user_id.as_deref(), Some("bob"));
+
+        let context = notification.mail_context();
+        let link = mail::link("/#/shared");
+        assert_eq!(context["subject"], "bob shared a file");
+        assert_eq!(context["file"], "plan.txt");
+        assert_eq!(context["message"], "bob shared plan.txt");
+        assert_eq!(context["link"], link.as_str());
+        assert_eq!(
+            context["body"],
+            format!("bob shared plan.txt\n\n{}", link).as_str()
+        );
+
+        // Without an action URL the email leads to the inbox
+        let alert = Notification::new("alice", EventType::QuotaAlert, "Quota", "90% used");
+        assert_eq!(
+            alert.mail_context()["link"],
+            mail::link("/#/notifications").as_str()
+        );
+    }
+}
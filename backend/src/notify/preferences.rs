@@ -0,0 +1,321 @@
+//! Notification preferences
+//!
+//! `notification_preferences` chooses the channels of each event type for a
+//! user; without a row, the email and browser switches of
+//! `user_preferences` apply. `notification_settings` holds the user's quiet
+//! hours, UTC offset and the hour daily digests go out.
+
+use chrono::{DateTime, Duration, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use super::EventType;
+
+/// How email notifications of an event type are batched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Digest {
+    Off,
+    Hourly,
+    Daily,
+}
+
+impl Digest {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(Digest::Off),
+            "hourly" => Some(Digest::Hourly),
+            "daily" => Some(Digest::Daily),
+            _ => None,
+        }
+    }
+}
+
+/// Channels of one event type
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct EventPreference {
+    pub event_type: String,
+    pub in_app: bool,
+    pub websocket: bool,
+    pub email: bool,
+    pub webhook: bool,
+    /// "off", "hourly" or "daily"
+    pub email_digest: String,
+}
+
+impl EventPreference {
+    pub fn digest(&self) -> Digest {
+        Digest::parse(&self.email_digest).unwrap_or(Digest::Off)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct Settings {
+    /// "HH:MM" in the user's time; quiet hours need both ends
+    pub quiet_hours_start: Option<String>,
+    pub quiet_hours_end: Option<String>,
+    pub utc_offset_minutes: i64,
+    /// Hour of the user's day when daily digests are sent
+    pub digest_hour: i64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            quiet_hours_start: None,
+            quiet_hours_end: None,
+            utc_offset_minutes: 0,
+            digest_hour: 8,
+        }
+    }
+}
+
+pub fn parse_time(value: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value, "%H:%M").ok()
+}
+
+impl Settings {
+    pub fn is_valid(&self) -> bool {
+        let ends = [&self.quiet_hours_start, &self.quiet_hours_end];
+        ends.iter()
+            .all(|end| end.as_deref().is_none_or(|t| parse_time(t).is_some()))
+            && (-14 * 60..=14 * 60).contains(&self.utc_offset_minutes)
+            && (0..24).contains(&self.digest_hour)
+    }
+
+    fn quiet_hours(&self) -> Option<(NaiveTime, NaiveTime)> {
+        let start = parse_time(self.quiet_hours_start.as_deref()?)?;
+        let end = parse_time(self.quiet_hours_end.as_deref()?)?;
+        (start != end).then_some((start, end))
+    }
+
+    fn local(&self, now: DateTime<Utc>) -> NaiveDateTime {
+        now.naive_utc() + Duration::minutes(self.utc_offset_minutes)
+    }
+
+    fn to_utc(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&(local - Duration::minutes(self.utc_offset_minutes)))
+    }
+
+    /// Whether `time` of the user's day falls in the quiet hours, which may
+    /// span midnight
+    fn is_quiet_at(&self, time: NaiveTime) -> bool {
+        match self.quiet_hours() {
+            Some((start, end)) if start < end => start <= time && time < end,
+            Some((start, end)) => time >= start || time < end,
+            None => false,
+        }
+    }
+
+    pub fn is_quiet(&self, now: DateTime<Utc>) -> bool {
+        self.is_quiet_at(self.local(now).time())
+    }
+
+    /// When an email notification should be sent: `None` for right away,
+    /// otherwise the end of the quiet hours or the next digest
+    pub fn email_due(&self, digest: Digest, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = self.local(now);
+        let mut due = match digest {
+            Digest::Off => local,
+            Digest::Hourly => local.date().and_hms_opt(local.hour(), 0, 0)? + Duration::hours(1),
+            Digest::Daily => {
+                let today = local.date().and_hms_opt(self.digest_hour as u32, 0, 0)?;
+                if today > local {
+                    today
+                } else {
+                    today + Duration::days(1)
+                }
+            }
+        };
+        if self.is_quiet_at(due.time())
+            && let Some((_, end)) = self.quiet_hours()
+        {
+            let end_today = due.date().and_time(end);
+            due = if end_today > due {
+                end_today
+            } else {
+                end_today + Duration::days(1)
+            };
+        }
+        (due != local).then(|| self.to_utc(due))
+    }
+}
+
+/// The channels `user_id` chose for `event`
+pub async fn event_preference(
+    pool: &SqlitePool,
+    user_id: &str,
+    event: EventType,
+) -> Result<EventPreference, sqlx::Error> {
+    let stored: Option<EventPreference> = sqlx::query_as(
+        "SELECT event_type, in_app, websocket, email, webhook, email_digest
+         FROM notification_preferences WHERE user_id = ? AND event_type = ?",
+    )
+    .bind(user_id)
+    .bind(event.as_str())
+    .fetch_optional(pool)
+    .await?;
+    if let Some(stored) = stored {
+        return Ok(stored);
+    }
+
+    // The general switches, where the user has set them
+    let (email, browser): (bool, bool) = sqlx::query_as(
+        "SELECT notification_email, notification_browser FROM user_preferences WHERE user_id = ?",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+    .ok()
+    .flatten()
+    .unwrap_or((true, true));
+    Ok(EventPreference {
+        event_type: event.as_str().to_string(),
+        in_app: true,
+        websocket: browser,
+        email,
+        webhook: true,
+        email_digest: "off".to_string(),
+    })
+}
+
+pub async fn save_event_preference(
+    pool: &SqlitePool,
+    user_id: &str,
+    preference: &EventPreference,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO notification_preferences
+             (user_id, event_type, in_app, websocket, email, webhook, email_digest, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, datetime('now'))
+         ON CONFLICT(user_id, event_type) DO UPDATE SET
+             in_app = excluded.in_app, websocket = excluded.websocket,
+             email = excluded.email, webhook = excluded.webhook,
+             email_digest = excluded.email_digest, updated_at = excluded.updated_at",
+    )
+    .bind(user_id)
+    .bind(&preference.event_type)
+    .bind(preference.in_app)
+    .bind(preference.websocket)
+    .bind(preference.email)
+    .bind(preference.webhook)
+    .bind(&preference.email_digest)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn settings(pool: &SqlitePool, user_id: &str) -> Result<Settings, sqlx::Error> {
+    let stored: Option<Settings> = sqlx::query_as(
+        "SELECT quiet_hours_start, quiet_hours_end, utc_offset_minutes, digest_hour
+         FROM notification_settings WHERE user_id = ?",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(stored.unwrap_or_default())
+}
+
+pub async fn save_settings(
+    pool: &SqlitePool,
+    user_id: &str,
+    settings: &Settings,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO notification_settings
+             (user_id, quiet_hours_start, quiet_hours_end, utc_offset_minutes, digest_hour, updated_at)
+         VALUES (?, ?, ?, ?, ?, datetime('now'))
+         ON CONFLICT(user_id) DO UPDATE SET
+             quiet_hours_start = excluded.quiet_hours_start,
+             quiet_hours_end = excluded.quiet_hours_end,
+             utc_offset_minutes = excluded.utc_offset_minutes,
+             digest_hour = excluded.digest_hour, updated_at = excluded.updated_at",
+    )
+    .bind(user_id)
+    .bind(&settings.quiet_hours_start)
+    .bind(&settings.quiet_hours_end)
+    .bind(settings.utc_offset_minutes)
+    .bind(settings.digest_hour)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(value: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").unwrap())
+    }
+
+    fn quiet(start: &str, end: &str, offset: i64) -> Settings {
+        Settings {
+            quiet_hours_start: Some(start.to_string()),
+            quiet_hours_end: Some(end.to_string()),
+            utc_offset_minutes: offset,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn test_quiet_hours_across_midnight() {
+        let night = quiet("22:00", "07:00", 0);
+        assert!(night.is_quiet(at("2026-03-01 23:30")));
+        assert!(night.is_quiet(at("2026-03-02 06:59")));
+        assert!(!night.is_quiet(at("2026-03-02 07:00")));
+        assert!(!night.is_quiet(at("2026-03-02 12:00")));
+
+        // 21:30 UTC is 23:30 two hours east
+        assert!(quiet("22:00", "07:00", 120).is_quiet(at("2026-03-01 21:30")));
+        assert!(!Settings::default().is_quiet(at("2026-03-01 23:30")));
+        assert!(!quiet("08:00", "08:00", 0).is_quiet(at("2026-03-01 08:30")));
+    }
+
+    #[test]
+    fn test_email_due() {
+        let plain = Settings::default();
+        let now = at("2026-03-01 10:20");
+        assert_eq!(plain.email_due(Digest::Off, now), None);
+        assert_eq!(
+            plain.email_due(Digest::Hourly, now),
+            Some(at("2026-03-01 11:00"))
+        );
+        assert_eq!(
+            plain.email_due(Digest::Daily, now),
+            Some(at("2026-03-02 08:00"))
+        );
+        assert_eq!(
+            plain.email_due(Digest::Daily, at("2026-03-01 07:59")),
+            Some(at("2026-03-01 08:00"))
+        );
+
+        // Quiet hours hold mail back until they end, in the user's time
+        let night = quiet("22:00", "07:00", 60);
+        assert_eq!(night.email_due(Digest::Off, now), None);
+        assert_eq!(
+            night.email_due(Digest::Off, at("2026-03-01 22:00")),
+            Some(at("2026-03-02 06:00"))
+        );
+        assert_eq!(
+            night.email_due(Digest::Hourly, at("2026-03-01 20:10")),
+            Some(at("2026-03-02 06:00"))
+        );
+        assert_eq!(
+            quiet("06:00", "09:00", 0).email_due(Digest::Daily, now),
+            Some(at("2026-03-02 09:00"))
+        );
+    }
+
+    #[test]
+    fn test_settings_validation() {
+        assert!(Settings::default().is_valid());
+        assert!(quiet("22:00", "07:00", -300).is_valid());
+        assert!(!quiet("22:00", "7pm", 0).is_valid());
+        assert!(!quiet("22:00", "07:00", 15 * 60).is_valid());
+        let late = Settings {
+            digest_hour: 24,
+            ..Settings::default()
+        };
+        assert!(!late.is_valid());
+    }
+}
@@ -10,9 +10,10 @@ use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use uuid::Uuid;
 use chrono::{DateTime, Utc, Duration};
-use base64::{Engine as _, engine::general_purpose};
 use std::collections::HashMap;
 
+use crate::secrets;
+
 /// OAuth provider configuration stored in database
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct OAuthProvider {
@@ -143,10 +144,14 @@ pub async fn upsert_provider(
     req: UpsertOAuthProviderRequest,
 ) -> Result<OAuthProvider, sqlx::Error> {
     let existing = get_provider(pool, &req.provider).await?;
-    let secret_encrypted = encrypt_secret(&req.client_secret);
+    let id = existing
+        .as_ref()
+        .map(|provider| provider.id.clone())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let secret_encrypted = seal(&secrets::OAUTH_CLIENT_SECRET, &id, &req.client_secret)?;
     let scopes_json = serde_json::to_string(&req.scopes).unwrap_or_else(|_| "[]".to_string());
     
-    if let Some(provider) = existing {
+    if existing.is_some() {
         // Update existing
         sqlx::query(
             "UPDATE oauth_providers 
@@ -159,15 +164,13 @@ pub async fn upsert_provider(
         .bind(&req.redirect_uri)
         .bind(&scopes_json)
         .bind(req.enabled)
-        .bind(&provider.id)
+        .bind(&id)
         .execute(pool)
         .await?;
         
         get_provider(pool, &req.provider).await.map(|p| p.unwrap())
     } else {
         // Create new
-        let id = Uuid::new_v4().to_string();
-        
         sqlx::query(
             "INSERT INTO oauth_providers 
              (id, provider, client_id, client_secret_encrypted, redirect_uri, scopes, enabled, created_at)
@@ -257,8 +260,12 @@ pub async fn store_token(
     user_info: &OAuthUserInfo,
 ) -> Result<OAuthToken, sqlx::Error> {
     let id = Uuid::new_v4().to_string();
-    let access_encrypted = encrypt_secret(&token_response.access_token);
-    let refresh_encrypted = token_response.refresh_token.as_ref().map(|t| encrypt_secret(t));
+    let access_encrypted = seal(&secrets::OAUTH_ACCESS_TOKEN, &id, &token_response.access_token)?;
+    let refresh_encrypted = token_response
+        .refresh_token
+        .as_ref()
+        .map(|t| seal(&secrets::OAUTH_REFRESH_TOKEN, &id, t))
+        .transpose()?;
     let expires_at = (Utc::now() + Duration::seconds(token_response.expires_in as i64)).to_rfc3339();
     let scope = token_response.scope.clone().unwrap_or_default();
     
@@ -393,6 +400,7 @@ pub fn get_auth_url(provider: &OAuthProvider, state: &str) -> String {
 
 /// Exchange authorization code for access token
 pub async fn exchange_code(
+    pool: &SqlitePool,
     provider: &OAuthProvider,
     code: &str,
 ) -> Result<OAuthTokenResponse, OAuthError> {
@@ -403,7 +411,15 @@ pub async fn exchange_code(
         _ => return Err(OAuthError::UnsupportedProvider(provider.provider.clone())),
     };
     
-    let client_secret = decrypt_secret(&provider.client_secret_encrypted)?;
+    let client_secret = secrets::reveal(
+        pool,
+        &secrets::OAUTH_CLIENT_SECRET,
+        &provider.id,
+        &provider.client_secret_encrypted,
+        "OAuth code exchange",
+    )
+    .await
+    .map_err(|e| OAuthError::DecryptionFailed(e.to_string()))?;
     
     let mut params = HashMap::new();
     params.insert("grant_type", "authorization_code".to_string());
@@ -528,17 +544,8 @@ fn get_default_scopes(provider: &str) -> Vec<String> {
     }
 }
 
-fn encrypt_secret(secret: &str) -> String {
-    // Simple base64 encoding for now
-    // TODO: Use proper encryption with AES-GCM
-    general_purpose::STANDARD.encode(secret)
-}
-
-fn decrypt_secret(encrypted: &str) -> Result<String, OAuthError> {
-    let bytes = general_purpose::STANDARD.decode(encrypted)
-        .map_err(|e| OAuthError::DecryptionFailed(e.to_string()))?;
-    String::from_utf8(bytes)
-        .map_err(|e| OAuthError::DecryptionFailed(e.to_string()))
+fn seal(field: &secrets::Field, row_id: &str, value: &str) -> Result<String, sqlx::Error> {
+    secrets::seal(field, row_id, value).map_err(|e| sqlx::Error::Protocol(e.to_string()))
 }
 
 // ==================== ERRORS ====================
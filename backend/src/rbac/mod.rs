@@ -0,0 +1,581 @@
+//! Role-based access control
+//!
+//! Users get the permissions of their roles (`user_roles` joined with
+//! `roles.permissions`); users without a role get the default roles, and
+//! users flagged `is_admin` get `system.admin`. `system.admin` grants every
+//! permission and `<area>.manage` grants the other permissions of its area,
+//! so `role.manage` includes `role.create`.
+//!
+//! Every protected router in `api/mod.rs` is wrapped with a [`Policy`] that
+//! names the permission its reads, writes and deletes need. [`enforce`]
+//! checks it after authentication, answers 403 when it is missing and writes
+//! the denial to the audit log. Requests made with a personal access token
+//! are also held to the token's scopes, paths and addresses (see [`tokens`]).
+
+pub mod tokens;
+
+pub use tokens::TokenGrant;
+
+use axum::{
+    extract::{MatchedPath, OriginalUri, Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use sqlx::SqlitePool;
+use std::collections::BTreeSet;
+
+use crate::auth::{User, UserInfo};
+
+/// Every permission a role can hold
+pub const PERMISSIONS: &[&str] = &[
+    // System permissions
+    "system.admin",
+    "system.config",
+    // User management
+    "user.manage",
+    "user.create",
+    "user.delete",
+    "user.view",
+    // Role management
+    "role.manage",
+    "role.create",
+    "role.delete",
+    "role.assign",
+    // File operations
+    "file.read",
+    "file.write",
+    "file.delete",
+    "file.share",
+    "file.download",
+    // Share management
+    "share.manage",
+    "share.create",
+    "share.delete",
+    // Backup operations
+    "backup.manage",
+    "backup.create",
+    "backup.restore",
+    "backup.view",
+    // Audit and settings
+    "audit.view",
+    "settings.manage",
+    "settings.view",
+];
+
+/// Permissions a user holds through their roles
+pub async fn permissions(
+    pool: &SqlitePool,
+    user_id: &str,
+) -> Result<BTreeSet<String>, sqlx::Error> {
+    let mut lists: Vec<String> = sqlx::query_scalar(
+        "SELECT r.permissions FROM user_roles ur
+         JOIN roles r ON ur.role_id = r.id
+         WHERE ur.user_id = ?
+           AND (ur.expires_at IS NULL OR datetime(ur.expires_at) > datetime('now'))",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    if lists.is_empty() {
+        lists = sqlx::query_scalar("SELECT permissions FROM roles WHERE is_default = 1")
+            .fetch_all(pool)
+            .await?;
+    }
+
+    let mut granted: BTreeSet<String> = lists
+        .iter()
+        .filter_map(|list| serde_json::from_str::<Vec<String>>(list).ok())
+        .flatten()
+        .collect();
+    let is_admin: Option<bool> = sqlx::query_scalar("SELECT is_admin FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    if is_admin == Some(true) {
+        granted.insert("system.admin".to_string());
+    }
+    Ok(granted)
+}
+
+/// Names of the roles a user holds, with the same fallback to the default
+/// roles as [`permissions`]
+pub async fn role_names(pool: &SqlitePool, user_id: &str) -> Result<Vec<String>, sqlx::Error> {
+    let names: Vec<String> = sqlx::query_scalar(
+        "SELECT r.name FROM user_roles ur
+         JOIN roles r ON ur.role_id = r.id
+         WHERE ur.user_id = ?
+           AND (ur.expires_at IS NULL OR datetime(ur.expires_at) > datetime('now'))
+         ORDER BY r.name",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    if !names.is_empty() {
+        return Ok(names);
+    }
+    sqlx::query_scalar("SELECT name FROM roles WHERE is_default = 1 ORDER BY name")
+        .fetch_all(pool)
+        .await
+}
+
+/// Whether `granted` includes `permission`, directly or through
+/// `system.admin` or the `.manage` permission of its area
+pub fn allows(granted: &BTreeSet<String>, permission: &str) -> bool {
+    if granted.contains(permission) || granted.contains("system.admin") {
+        return true;
+    }
+    match permission.split_once('.') {
+        Some((area, _)) => granted.contains(&format!("{}.manage", area)),
+        None => false,
+    }
+}
+
+pub async fn has_permission(pool: &SqlitePool, user_id: &str, permission: &str) -> bool {
+    match permissions(pool, user_id).await {
+        Ok(granted) => allows(&granted, permission),
+        Err(e) => {
+            tracing::error!("Failed to load permissions of {}: {}", user_id, e);
+            false
+        }
+    }
+}
+
+/// Record a refused request in the audit log; `missing` is the permission,
+/// token scope or path the request lacked
+pub async fn log_denial(
+    pool: &SqlitePool,
+    user: &UserInfo,
+    method: &str,
+    path: &str,
+    missing: &str,
+) {
+    tracing::warn!(
+        "{} denied {} {}: missing {}",
+        user.username,
+        method,
+        path,
+        missing
+    );
+    let result = sqlx::query(
+        "INSERT INTO audit_logs (id, user_id, username, action, action_category, resource_type,
+         resource_id, request_method, request_path, response_status, metadata, severity,
+         is_compliance_relevant, created_at)
+         VALUES (?, ?, ?, 'permission_denied', 'security', 'route', ?, ?, ?, 403, ?, 'warning', 1, ?)",
+    )
+    .bind(uuid::Uuid::new_v4().to_string())
+    .bind(&user.id)
+    .bind(&user.username)
+    .bind(path)
+    .bind(method)
+    .bind(path)
+    .bind(serde_json::json!({ "missing": missing }).to_string())
+    .bind(chrono::Utc::now().to_rfc3339())
+    .execute(pool)
+    .await;
+    if let Err(e) = result {
+        tracing::error!("Failed to log permission denial: {}", e);
+    }
+}
+
+/// The permissions a router's routes need
+///
+/// Reads are GET, HEAD and OPTIONS, deletes are DELETE and everything else
+/// writes. `None` lets any signed-in user through. `routes` overrides this
+/// for single routes: `(method, route, permission)`, where the route uses
+/// the router's `{param}` and `{*rest}` syntax.
+///
+/// Access tokens need the scope covering the permission, or `scope` for
+/// routes without one.
+#[derive(Debug)]
+pub struct Policy {
+    pub read: Option<&'static str>,
+    pub write: Option<&'static str>,
+    pub delete: Option<&'static str>,
+    pub routes: &'static [(&'static str, &'static str, &'static str)],
+    /// Token scope of the routes that need no permission; `None` leaves them
+    /// to tokens with the `admin` scope
+    pub scope: Option<&'static str>,
+}
+
+impl Policy {
+    /// The permission a request needs, if any
+    pub fn required(&self, method: &Method, path: &str) -> Option<&'static str> {
+        if let Some((_, _, permission)) = self
+            .routes
+            .iter()
+            .find(|(m, route, _)| *m == method.as_str() && route_matches(route, path))
+        {
+            return Some(permission);
+        }
+        match *method {
+            Method::GET | Method::HEAD | Method::OPTIONS => self.read,
+            Method::DELETE => self.delete,
+            _ => self.write,
+        }
+    }
+
+    /// The token scope a request needs
+    pub fn required_scope(&self, method: &Method, path: &str) -> &'static str {
+        match self.required(method, path) {
+            Some(permission) => tokens::scope_for_permission(permission),
+            None => self.scope.unwrap_or("admin"),
+        }
+    }
+}
+
+fn route_matches(route: &str, path: &str) -> bool {
+    let mut path = path.trim_matches('/').split('/');
+    for segment in route.trim_matches('/').split('/') {
+        if segment.starts_with("{*") {
+            return path.next().is_some_and(|s| !s.is_empty());
+        }
+        match path.next() {
+            Some(s) if segment.starts_with('{') => {
+                if s.is_empty() {
+                    return false;
+                }
+            }
+            Some(s) if s == segment => {}
+            _ => return false,
+        }
+    }
+    path.next().is_none()
+}
+
+/// Files, folders and everything derived from them
+pub const FILES: Policy = Policy {
+    read: Some("file.read"),
+    write: Some("file.write"),
+    delete: Some("file.delete"),
+    routes: &[("GET", "/file/{*path}", "file.download")],
+    scope: None,
+};
+
+/// Files through the WebDAV mount: PROPFIND reads like GET, and the other
+/// methods that change something (MKCOL, MOVE, COPY, PROPPATCH, LOCK) write
+pub const WEBDAV: Policy = Policy {
+    read: Some("file.read"),
+    write: Some("file.write"),
+    delete: Some("file.delete"),
+    routes: &[
+        ("PROPFIND", "/webdav", "file.read"),
+        ("PROPFIND", "/webdav/{*path}", "file.read"),
+    ],
+    scope: None,
+};
+
+/// Share links and guest access
+pub const SHARES: Policy = Policy {
+    read: Some("file.share"),
+    write: Some("share.create"),
+    delete: Some("file.share"),
+    routes: &[("GET", "/shared-with-me", "file.read")],
+    scope: None,
+};
+
+/// Administration of user accounts
+pub const USERS: Policy = Policy {
+    read: Some("user.view"),
+    write: Some("user.manage"),
+    delete: Some("user.delete"),
+    routes: &[],
+    scope: None,
+};
+
+/// Groups are visible to everyone and managed by user managers
+pub const GROUPS: Policy = Policy {
+    read: None,
+    write: Some("user.manage"),
+    delete: Some("user.manage"),
+    routes: &[],
+    scope: None,
+};
+
+/// Everyone sees their own quota
+pub const QUOTAS: Policy = Policy {
+    read: None,
+    write: Some("user.manage"),
+    delete: Some("user.manage"),
+    routes: &[("GET", "/quota/{user_id}", "user.view")],
+    scope: Some("profile"),
+};
+
+/// Roles are visible to everyone; reading other users' roles is checked by
+/// the handlers
+pub const ROLES: Policy = Policy {
+    read: None,
+    write: Some("role.manage"),
+    delete: Some("role.delete"),
+    routes: &[
+        ("POST", "/roles", "role.create"),
+        ("POST", "/users/{user_id}/roles", "role.assign"),
+        ("DELETE", "/users/{user_id}/roles/{role_id}", "role.assign"),
+        ("GET", "/permissions/audit", "audit.view"),
+    ],
+    scope: None,
+};
+
+pub const BACKUPS: Policy = Policy {
+    read: Some("backup.view"),
+    write: Some("backup.create"),
+    delete: Some("backup.manage"),
+    routes: &[
+        ("POST", "/backups/{backup_id}/restore", "backup.restore"),
+        ("POST", "/backups/schedules", "backup.manage"),
+        ("POST", "/backups/schedules/{*rest}", "backup.manage"),
+        ("PUT", "/backups/schedules/{*rest}", "backup.manage"),
+        ("POST", "/backups/destinations", "backup.manage"),
+        ("POST", "/backups/destinations/{*rest}", "backup.manage"),
+        ("PUT", "/backups/destinations/{*rest}", "backup.manage"),
+    ],
+    scope: None,
+};
+
+/// Monitoring and maintenance of the server
+pub const SETTINGS: Policy = Policy {
+    read: Some("settings.view"),
+    write: Some("settings.manage"),
+    delete: Some("settings.manage"),
+    routes: &[],
+    scope: None,
+};
+
+/// Server state everyone may read but only settings managers change
+pub const SERVER: Policy = Policy {
+    read: None,
+    write: Some("settings.manage"),
+    delete: Some("settings.manage"),
+    routes: &[],
+    scope: None,
+};
+
+/// Integrations that change how the server itself runs
+pub const SYSTEM: Policy = Policy {
+    read: Some("system.config"),
+    write: Some("system.config"),
+    delete: Some("system.config"),
+    routes: &[],
+    scope: None,
+};
+
+/// Encrypting files is a file operation, managing keys is not
+pub const ENCRYPTION: Policy = Policy {
+    read: Some("file.read"),
+    write: Some("file.write"),
+    delete: Some("settings.manage"),
+    routes: &[
+        (
+            "POST",
+            "/encryption/keys/{key_id}/rotate",
+            "settings.manage",
+        ),
+        ("GET", "/encryption/settings", "settings.view"),
+    ],
+    scope: None,
+};
+
+/// Anyone who can read files can scan them; the quarantine is managed
+pub const SCANS: Policy = Policy {
+    read: Some("file.read"),
+    write: Some("file.read"),
+    delete: Some("settings.manage"),
+    routes: &[
+        ("GET", "/scan/quarantine", "settings.view"),
+        ("POST", "/scan/quarantine/{*rest}", "settings.manage"),
+    ],
+    scope: None,
+};
+
+/// Audit logs and compliance reports; retention and archives are settings
+pub const AUDIT: Policy = Policy {
+    read: Some("audit.view"),
+    write: Some("settings.manage"),
+    delete: Some("settings.manage"),
+    routes: &[
+        ("POST", "/audit/logs", "audit.view"),
+        ("POST", "/audit/reports", "audit.view"),
+    ],
+    scope: None,
+};
+
+/// Sign-in, sessions and tokens: only for interactive sign-ins, so a token
+/// cannot create or widen tokens
+pub const ACCOUNT: Policy = Policy {
+    read: None,
+    write: None,
+    delete: None,
+    routes: &[],
+    scope: None,
+};
+
+/// The user's own profile and preferences
+pub const PROFILE: Policy = Policy {
+    read: None,
+    write: None,
+    delete: None,
+    routes: &[],
+    scope: Some("profile"),
+};
+
+/// The user's own lists of files: recent, favorites, activity
+pub const FILE_LISTS: Policy = Policy {
+    read: None,
+    write: None,
+    delete: None,
+    routes: &[],
+    scope: Some("files:read"),
+};
+
+pub const NOTIFICATIONS: Policy = Policy {
+    read: None,
+    write: None,
+    delete: None,
+    routes: &[],
+    scope: Some("notifications"),
+};
+
+pub const WEBHOOKS: Policy = Policy {
+    read: None,
+    write: None,
+    delete: None,
+    routes: &[],
+    scope: Some("webhooks"),
+};
+
+/// Middleware state: where to look up permissions and what to require
+#[derive(Clone)]
+pub struct Guard {
+    pool: SqlitePool,
+    policy: &'static Policy,
+}
+
+impl Guard {
+    pub fn new(pool: SqlitePool, policy: &'static Policy) -> Self {
+        Self { pool, policy }
+    }
+}
+
+/// Refuse requests whose user lacks the permission the route's policy needs,
+/// or whose token does not cover the route. Runs after the auth middleware,
+/// which provides the user and the token.
+pub async fn enforce(State(guard): State<Guard>, req: Request, next: Next) -> Response {
+    let Some(User(user)) = req.extensions().get::<User>().cloned() else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let path = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.path().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+
+    if let Some(grant) = req.extensions().get::<TokenGrant>() {
+        let scope = guard.policy.required_scope(req.method(), req.uri().path());
+        let refusal = if !grant.allows_scope(scope) {
+            Some(("token scope missing", "scope", scope.to_string()))
+        } else if grant.is_path_limited() && tokens::is_file_scope(scope) {
+            let file_path = req
+                .extensions()
+                .get::<MatchedPath>()
+                .and_then(|route| tokens::url_file_path(route.as_str(), &path));
+            match file_path {
+                Some(file_path) if grant.allows_path(&file_path) => None,
+                file_path => Some((
+                    "path outside the token's prefixes",
+                    "path",
+                    file_path.unwrap_or_default(),
+                )),
+            }
+        } else {
+            None
+        };
+        if let Some((error, field, value)) = refusal {
+            let missing = format!("{} {}", field, value);
+            log_denial(&guard.pool, &user, req.method().as_str(), &path, &missing).await;
+            return (
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({ "error": error, field: value })),
+            )
+                .into_response();
+        }
+    }
+
+    let Some(permission) = guard.policy.required(req.method(), req.uri().path()) else {
+        return next.run(req).await;
+    };
+    match permissions(&guard.pool, &user.id).await {
+        Ok(granted) if allows(&granted, permission) => next.run(req).await,
+        Ok(_) => {
+            log_denial(&guard.pool, &user, req.method().as_str(), &path, permission).await;
+            (
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({
+                    "error": "permission denied",
+                    "permission": permission,
+                })),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to load permissions of {}: {}", user.id, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_matches() {
+        assert!(route_matches("/file/{*path}", "/file/docs/a.txt"));
+        assert!(!route_matches("/file/{*path}", "/file"));
+        assert!(!route_matches("/file/{*path}", "/files/a.txt"));
+        assert!(route_matches("/users/{user_id}/roles", "/users/u1/roles"));
+        assert!(!route_matches(
+            "/users/{user_id}/roles",
+            "/users/u1/roles/r1"
+        ));
+        assert!(!route_matches("/users/{user_id}/roles", "/users//roles"));
+        assert!(route_matches("/roles", "/roles/"));
+    }
+
+    #[test]
+    fn test_allows() {
+        let granted: BTreeSet<String> = ["role.manage", "file.read"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert!(allows(&granted, "file.read"));
+        assert!(allows(&granted, "role.create"));
+        assert!(!allows(&granted, "file.write"));
+        assert!(!allows(&granted, "system"));
+
+        let admin = BTreeSet::from(["system.admin".to_string()]);
+        assert!(PERMISSIONS.iter().all(|p| allows(&admin, p)));
+    }
+
+    #[test]
+    fn test_policy_methods() {
+        assert_eq!(FILES.required(&Method::GET, "/files/a"), Some("file.read"));
+        assert_eq!(
+            FILES.required(&Method::GET, "/file/a"),
+            Some("file.download")
+        );
+        assert_eq!(
+            FILES.required(&Method::PUT, "/rename/a"),
+            Some("file.write")
+        );
+        assert_eq!(
+            FILES.required(&Method::DELETE, "/files/a"),
+            Some("file.delete")
+        );
+        assert_eq!(GROUPS.required(&Method::GET, "/groups"), None);
+        assert_eq!(ROLES.required(&Method::POST, "/roles"), Some("role.create"));
+        assert_eq!(
+            ROLES.required(&Method::PUT, "/roles/r1"),
+            Some("role.manage")
+        );
+    }
+}
@@ -0,0 +1,360 @@
+//! Restrictions of personal access tokens
+//!
+//! A request authenticated with a token (`ssk_...`) carries a [`TokenGrant`]
+//! next to its user. On top of the user's own permissions the token limits
+//! what the request may do:
+//!
+//! - scopes: every route needs one, derived from its [`super::Policy`];
+//!   `admin` includes all others
+//! - path prefixes: file and share routes must name a file below one of the
+//!   prefixes in their URL, other file routes are refused
+//! - IP allowlist: addresses or CIDR ranges the client must connect from
+//!
+//! Behind a reverse proxy the client address is read from X-Forwarded-For
+//! when the connection comes from a proxy listed in
+//! `SYNCSPACE_TRUSTED_PROXIES` (addresses or ranges, loopback by default).
+
+use axum::http::HeaderMap;
+use percent_encoding::percent_decode_str;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+/// Scopes a token can be given: name, description and category
+pub const SCOPES: &[(&str, &str, &str)] = &[
+    (
+        "files:read",
+        "List, search, preview and download files",
+        "Files",
+    ),
+    (
+        "files:write",
+        "Upload, change, move and delete files",
+        "Files",
+    ),
+    ("shares:manage", "Create and manage shares", "Sharing"),
+    ("profile", "Read and update the user profile", "User"),
+    ("notifications", "Access notifications", "User"),
+    ("webhooks", "Manage webhooks", "Integration"),
+    (
+        "admin",
+        "Everything the user may do, including administration",
+        "Admin",
+    ),
+];
+
+/// The scope a name stands for; tokens created before scopes were
+/// namespaced use the short names
+pub fn normalize_scope(scope: &str) -> Option<&'static str> {
+    match scope.trim() {
+        "read" | "download" | "search" => Some("files:read"),
+        "write" | "upload" => Some("files:write"),
+        "share" => Some("shares:manage"),
+        scope => SCOPES
+            .iter()
+            .map(|(name, ..)| *name)
+            .find(|name| *name == scope),
+    }
+}
+
+/// The scope that covers a permission
+pub fn scope_for_permission(permission: &str) -> &'static str {
+    match permission {
+        "file.read" | "file.download" => "files:read",
+        "file.write" | "file.delete" => "files:write",
+        "file.share" => "shares:manage",
+        permission if permission.starts_with("share.") => "shares:manage",
+        _ => "admin",
+    }
+}
+
+/// Whether routes needing `scope` work on files, so path prefixes apply
+pub fn is_file_scope(scope: &str) -> bool {
+    matches!(scope, "files:read" | "files:write" | "shares:manage")
+}
+
+/// What a token lets a request do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenGrant {
+    pub token_id: String,
+    pub scopes: Vec<String>,
+    /// Folders the token is limited to, without surrounding slashes; empty
+    /// means everywhere
+    pub path_prefixes: Vec<String>,
+    /// Addresses and CIDR ranges; empty means anywhere
+    pub allowed_ips: Vec<String>,
+}
+
+impl TokenGrant {
+    /// From the stored columns: scopes as a comma-separated list or JSON
+    /// array, prefixes and addresses comma-separated
+    pub fn new(
+        token_id: &str,
+        scopes: &str,
+        path_prefixes: Option<&str>,
+        allowed_ips: Option<&str>,
+    ) -> Self {
+        let names: Vec<String> = serde_json::from_str(scopes)
+            .unwrap_or_else(|_| scopes.split(',').map(String::from).collect());
+        let mut scopes: Vec<String> = names
+            .iter()
+            .filter_map(|name| normalize_scope(name))
+            .map(String::from)
+            .collect();
+        scopes.sort();
+        scopes.dedup();
+
+        Self {
+            token_id: token_id.to_string(),
+            scopes,
+            path_prefixes: split_list(path_prefixes)
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .collect(),
+            allowed_ips: split_list(allowed_ips).map(String::from).collect(),
+        }
+    }
+
+    pub fn allows_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope || s == "admin")
+    }
+
+    pub fn is_path_limited(&self) -> bool {
+        !self.path_prefixes.is_empty()
+    }
+
+    /// Whether `path` (relative to the data directory) is at or below one
+    /// of the prefixes. Paths that climb with `..` are never allowed.
+    pub fn allows_path(&self, path: &str) -> bool {
+        if !self.is_path_limited() {
+            return true;
+        }
+        let path = path.trim_matches('/');
+        if path.split('/').any(|segment| segment == "..") {
+            return false;
+        }
+        self.path_prefixes.iter().any(|prefix| {
+            prefix.is_empty()
+                || path == prefix
+                || path
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+
+    /// Whether the client address is allowed; unknown addresses are not
+    /// when the token has an allowlist
+    pub fn allows_ip(&self, ip: Option<IpAddr>) -> bool {
+        if self.allowed_ips.is_empty() {
+            return true;
+        }
+        ip.is_some_and(|ip| self.allowed_ips.iter().any(|rule| ip_matches(rule, ip)))
+    }
+}
+
+fn split_list(list: Option<&str>) -> impl Iterator<Item = &str> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Whether `rule` is a valid address or CIDR range
+pub fn is_valid_ip_rule(rule: &str) -> bool {
+    parse_ip_rule(rule).is_some()
+}
+
+fn parse_ip_rule(rule: &str) -> Option<(IpAddr, u32)> {
+    let rule = rule.trim();
+    let (address, bits) = match rule.split_once('/') {
+        Some((address, bits)) => (address, Some(bits.parse::<u32>().ok()?)),
+        None => (rule, None),
+    };
+    let address: IpAddr = address.parse().ok()?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let bits = bits.unwrap_or(max);
+    (bits <= max).then_some((address, bits))
+}
+
+/// Whether `ip` is the address or in the CIDR range `rule`
+pub fn ip_matches(rule: &str, ip: IpAddr) -> bool {
+    let Some((network, bits)) = parse_ip_rule(rule) else {
+        return false;
+    };
+    // IPv4 clients may show up as IPv4-mapped IPv6 addresses
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+    let (network, ip, width) = match (network, ip) {
+        (IpAddr::V4(n), IpAddr::V4(i)) => (u32::from(n) as u128, u32::from(i) as u128, 32),
+        (IpAddr::V6(n), IpAddr::V6(i)) => (u128::from(n), u128::from(i), 128),
+        _ => return false,
+    };
+    if bits == 0 {
+        return true;
+    }
+    let shift = width - bits;
+    network >> shift == ip >> shift
+}
+
+fn trusted_proxies() -> &'static [String] {
+    static PROXIES: OnceLock<Vec<String>> = OnceLock::new();
+    PROXIES.get_or_init(|| match std::env::var("SYNCSPACE_TRUSTED_PROXIES") {
+        Ok(list) => split_list(Some(list.as_str())).map(String::from).collect(),
+        Err(_) => vec!["127.0.0.0/8".to_string(), "::1".to_string()],
+    })
+}
+
+/// The address a request comes from: the connecting peer, or the last
+/// X-Forwarded-For entry not added by a trusted proxy
+pub fn client_ip(headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+    client_ip_with(headers, peer, trusted_proxies())
+}
+
+fn client_ip_with(headers: &HeaderMap, peer: Option<IpAddr>, proxies: &[String]) -> Option<IpAddr> {
+    let is_trusted = |ip: IpAddr| proxies.iter().any(|rule| ip_matches(rule, ip));
+    let peer = peer?;
+    if !is_trusted(peer) {
+        return Some(peer);
+    }
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| entry.trim().parse().ok())
+        .collect();
+    let mut client = peer;
+    for ip in forwarded.into_iter().rev() {
+        client = ip;
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+/// The file path a request names in its URL: the part of `path` matched by
+/// the `{*...}` segment of `route`. Both are full paths, as in `MatchedPath`
+/// and `OriginalUri`.
+pub fn url_file_path(route: &str, path: &str) -> Option<String> {
+    let fixed = route
+        .trim_start_matches('/')
+        .split('/')
+        .position(|segment| segment.starts_with("{*"))?;
+    let rest: Vec<&str> = path
+        .trim_start_matches('/')
+        .splitn(fixed + 1, '/')
+        .collect();
+    let rest = rest.get(fixed)?;
+    Some(percent_decode_str(rest).decode_utf8().ok()?.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(scopes: &str, prefixes: Option<&str>, ips: Option<&str>) -> TokenGrant {
+        TokenGrant::new("t1", scopes, prefixes, ips)
+    }
+
+    #[test]
+    fn test_scopes() {
+        let legacy = grant("read,upload,share,bogus", None, None);
+        assert_eq!(
+            legacy.scopes,
+            vec!["files:read", "files:write", "shares:manage"]
+        );
+        assert!(legacy.allows_scope("files:write"));
+        assert!(!legacy.allows_scope("admin"));
+
+        let json = grant(r#"["files:read"]"#, None, None);
+        assert_eq!(json.scopes, vec!["files:read"]);
+        assert!(!json.allows_scope("files:write"));
+
+        let admin = grant("admin", None, None);
+        assert!(SCOPES.iter().all(|(scope, ..)| admin.allows_scope(scope)));
+
+        assert_eq!(scope_for_permission("file.download"), "files:read");
+        assert_eq!(scope_for_permission("file.delete"), "files:write");
+        assert_eq!(scope_for_permission("share.create"), "shares:manage");
+        assert_eq!(scope_for_permission("backup.view"), "admin");
+    }
+
+    #[test]
+    fn test_path_prefixes() {
+        let open = grant("files:read", None, None);
+        assert!(open.allows_path("anything/at/all"));
+
+        let ci = grant("files:write", Some("/builds/, releases/app"), None);
+        assert_eq!(ci.path_prefixes, vec!["builds", "releases/app"]);
+        assert!(ci.allows_path("builds"));
+        assert!(ci.allows_path("/builds/42/log.txt"));
+        assert!(ci.allows_path("releases/app/1.0.zip"));
+        assert!(!ci.allows_path("builds-old/log.txt"));
+        assert!(!ci.allows_path("releases/application.zip"));
+        assert!(!ci.allows_path("builds/../secrets.txt"));
+        assert!(!ci.allows_path(""));
+    }
+
+    #[test]
+    fn test_ip_rules() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(ip_matches("10.0.0.0/8", ip("10.1.2.3")));
+        assert!(!ip_matches("10.0.0.0/8", ip("11.0.0.1")));
+        assert!(ip_matches("192.168.1.7", ip("192.168.1.7")));
+        assert!(ip_matches("192.168.1.0/24", ip("::ffff:192.168.1.9")));
+        assert!(ip_matches("2001:db8::/32", ip("2001:db8:1::1")));
+        assert!(!ip_matches("2001:db8::/32", ip("10.0.0.1")));
+        assert!(ip_matches("0.0.0.0/0", ip("8.8.8.8")));
+        assert!(!is_valid_ip_rule("10.0.0.0/33"));
+        assert!(!is_valid_ip_rule("example.com"));
+
+        let limited = grant("files:read", None, Some("10.0.0.0/8, 192.168.1.7"));
+        assert!(limited.allows_ip(Some(ip("10.9.9.9"))));
+        assert!(!limited.allows_ip(Some(ip("192.168.1.8"))));
+        assert!(!limited.allows_ip(None));
+        assert!(grant("files:read", None, None).allows_ip(None));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxies = vec!["127.0.0.1".to_string(), "10.0.0.0/8".to_string()];
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "1.2.3.4, 5.6.7.8, 10.0.0.2".parse().unwrap(),
+        );
+
+        // Only trusted proxies may name the client
+        assert_eq!(
+            client_ip_with(&headers, Some(ip("9.9.9.9")), &proxies),
+            Some(ip("9.9.9.9"))
+        );
+        assert_eq!(
+            client_ip_with(&headers, Some(ip("127.0.0.1")), &proxies),
+            Some(ip("5.6.7.8"))
+        );
+        assert_eq!(
+            client_ip_with(&HeaderMap::new(), Some(ip("127.0.0.1")), &proxies),
+            Some(ip("127.0.0.1"))
+        );
+        assert_eq!(client_ip_with(&headers, None, &proxies), None);
+    }
+
+    #[test]
+    fn test_url_file_path() {
+        assert_eq!(
+            url_file_path("/api/files/{*path}", "/api/files/builds/a%20b.txt").as_deref(),
+            Some("builds/a b.txt")
+        );
+        assert_eq!(
+            url_file_path("/api/upload/{*path}", "/api/upload/x").as_deref(),
+            Some("x")
+        );
+        assert_eq!(
+            url_file_path("/api/shares/{share_id}", "/api/shares/s1"),
+            None
+        );
+    }
+}
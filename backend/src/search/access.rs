@@ -0,0 +1,98 @@
+//! Who can find a document
+//!
+//! Every indexed document lists the principals that may read it: `user:<id>`
+//! for the owner of the file or of a folder above it and for users it is
+//! shared with, `group:<id>` for groups granted access. Shares and grants on
+//! a folder cover everything below it. Queries only match documents whose
+//! list contains one of the viewer's principals.
+
+use sqlx::SqlitePool;
+
+pub fn user(id: &str) -> String {
+    format!("user:{}", id)
+}
+
+pub fn group(id: &str) -> String {
+    format!("group:{}", id)
+}
+
+/// The principals a search runs as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Viewer {
+    pub principals: Vec<String>,
+}
+
+impl Viewer {
+    /// A user and the groups they belong to
+    pub async fn load(pool: &SqlitePool, user_id: &str) -> Result<Self, sqlx::Error> {
+        let groups: Vec<String> =
+            sqlx::query_scalar("SELECT group_id FROM user_group_members WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_all(pool)
+                .await?;
+        let mut principals = vec![user(user_id)];
+        principals.extend(groups.iter().map(|id| group(id)));
+        Ok(Self { principals })
+    }
+
+    pub fn can_read(&self, grants: &[String]) -> bool {
+        grants.iter().any(|grant| self.principals.contains(grant))
+    }
+}
+
+/// Principals that may read the file or folder at `path`
+pub async fn grants(pool: &SqlitePool, path: &str) -> Result<Vec<String>, sqlx::Error> {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Paths are stored with and without a leading slash
+    let own = serde_json::json!([path, format!("/{}", path)]).to_string();
+    let mut ancestors = Vec::new();
+    let mut current = path;
+    loop {
+        ancestors.push(current.to_string());
+        ancestors.push(format!("/{}", current));
+        match current.rsplit_once('/') {
+            Some((parent, _)) => current = parent,
+            None => break,
+        }
+    }
+    let ancestors = serde_json::to_string(&ancestors).unwrap_or_default();
+
+    let mut principals: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT 'user:' || owner_id FROM files
+        WHERE is_deleted = 0 AND path IN (SELECT value FROM json_each(?1))
+        UNION
+        SELECT 'user:' || owner_id FROM folders
+        WHERE is_deleted = 0 AND path IN (SELECT value FROM json_each(?2))
+        UNION
+        SELECT 'user:' || su.user_id FROM share_users su
+        JOIN shared_links sl ON sl.id = su.share_id
+        WHERE '/' || trim(sl.item_id, '/') IN (SELECT value FROM json_each(?2))
+          AND (sl.expires_at IS NULL OR datetime(sl.expires_at) > datetime('now'))
+        UNION
+        SELECT 'user:' || user_id FROM file_permissions
+        WHERE can_read = 1
+          AND (expires_at IS NULL OR datetime(expires_at) > datetime('now'))
+          AND ((item_type = 'file' AND item_id IN
+                   (SELECT id FROM files WHERE path IN (SELECT value FROM json_each(?1))))
+               OR (item_type = 'folder' AND item_id IN
+                   (SELECT id FROM folders WHERE path IN (SELECT value FROM json_each(?2)))))
+        UNION
+        SELECT 'group:' || group_id FROM group_permissions
+        WHERE (expires_at IS NULL OR datetime(expires_at) > datetime('now'))
+          AND ((resource_type = 'file' AND resource_id IN
+                   (SELECT id FROM files WHERE path IN (SELECT value FROM json_each(?1))))
+               OR (resource_type = 'folder' AND resource_id IN
+                   (SELECT id FROM folders WHERE path IN (SELECT value FROM json_each(?2)))))
+        "#,
+    )
+    .bind(&own)
+    .bind(&ancestors)
+    .fetch_all(pool)
+    .await?;
+    principals.sort();
+    Ok(principals)
+}
@@ -2,12 +2,18 @@
 //!
 //! Provides fast, typo-tolerant search across files and folders.
 //! Supports fuzzy search, faceting, and content extraction.
+//! Results are limited to documents the caller may read (see [`access`]).
+
+pub mod access;
+
+pub use access::Viewer;
 
 use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
 use std::path::Path;
 use std::sync::Arc;
 use tantivy::collector::TopDocs;
-use tantivy::query::{FuzzyTermQuery, QueryParser};
+use tantivy::query::{BooleanQuery, FuzzyTermQuery, Occur, Query, QueryParser, TermQuery};
 use tantivy::schema::*;
 use tantivy::{doc, Index, IndexReader, IndexWriter, Term};
 use tokio::sync::Mutex;
@@ -62,6 +68,19 @@ pub struct SearchOptions {
     pub date_to: Option<chrono::DateTime<chrono::Utc>>,
 }
 
+/// A file to index with [`SearchIndex::batch_index_files`]
+#[derive(Debug, Clone)]
+pub struct IndexedFile {
+    pub id: String,
+    pub filename: String,
+    pub path: String,
+    pub content: Option<String>,
+    pub modified: chrono::DateTime<chrono::Utc>,
+    pub size: u64,
+    /// Principals that may read the file (see [`access::grants`])
+    pub acl: Vec<String>,
+}
+
 /// Search index manager
 pub struct SearchIndex {
     index: Index,
@@ -78,6 +97,8 @@ pub struct SearchIndex {
     modified_field: Field,
     size_field: Field,
     file_type_field: Field,
+    /// Principals that may read the document
+    acl_field: Field,
     // Batch operation tracking
     pending_operations: Arc<Mutex<usize>>,
     last_commit: Arc<Mutex<std::time::Instant>>,
@@ -105,6 +126,9 @@ impl SearchIndex {
         let modified_field = schema_builder.add_date_field("modified", STORED | FAST);
         let size_field = schema_builder.add_u64_field("size", STORED | FAST);
 
+        // Access control, one value per principal
+        let acl_field = schema_builder.add_text_field("acl", STRING);
+
         let schema = schema_builder.build();
 
         // Clean up stale lock file if exists
@@ -115,12 +139,25 @@ impl SearchIndex {
         }
 
         // Open or create index
-        let index = if Path::new(SEARCH_INDEX_DIR).join("meta.json").exists() {
+        let existing = if Path::new(SEARCH_INDEX_DIR).join("meta.json").exists() {
             println!("🔍 Opening existing search index");
-            Index::open_in_dir(SEARCH_INDEX_DIR)?
+            Some(Index::open_in_dir(SEARCH_INDEX_DIR)?)
         } else {
-            println!("📦 Creating new search index");
-            Index::create_in_dir(SEARCH_INDEX_DIR, schema.clone())?
+            None
+        };
+        // Indexes from before access control cannot be filtered; start over,
+        // the startup reindex fills the new one
+        let index = match existing {
+            Some(index) if index.schema().get_field("acl").is_ok() => index,
+            existing => {
+                if existing.is_some() {
+                    println!("🧹 Rebuilding search index with access control");
+                    std::fs::remove_dir_all(SEARCH_INDEX_DIR)?;
+                    std::fs::create_dir_all(SEARCH_INDEX_DIR)?;
+                }
+                println!("📦 Creating new search index");
+                Index::create_in_dir(SEARCH_INDEX_DIR, schema.clone())?
+            }
         };
 
         // Create reader and writer
@@ -145,6 +182,7 @@ impl SearchIndex {
             modified_field,
             size_field,
             file_type_field,
+            acl_field,
             pending_operations: Arc::new(Mutex::new(0)),
             last_commit: Arc::new(Mutex::new(std::time::Instant::now())),
         };
@@ -190,7 +228,9 @@ impl SearchIndex {
         });
     }
 
-    /// Index a file with optional content
+    /// Index a file with optional content, readable by the principals in `acl`
+    /// (see [`access::grants`])
+    #[allow(clippy::too_many_arguments)]
     pub async fn index_file(
         &self,
         file_id: &str,
@@ -199,11 +239,12 @@ impl SearchIndex {
         content: Option<String>,
         modified: chrono::DateTime<chrono::Utc>,
         size: u64,
+        acl: &[String],
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let file_type = Self::detect_file_type(filename);
 
         // Create document
-        let doc = doc!(
+        let mut doc = doc!(
             self.file_id_field => file_id,
             self.filename_field => filename,
             self.path_field => path,
@@ -212,6 +253,9 @@ impl SearchIndex {
             self.size_field => size,
             self.file_type_field => file_type,
         );
+        for principal in acl {
+            doc.add_text(self.acl_field, principal);
+        }
 
         // Delete old document if exists (update case)
         let term = Term::from_field_text(self.file_id_field, file_id);
@@ -270,35 +314,32 @@ impl SearchIndex {
     /// Batch index multiple files (more efficient than individual calls)
     pub async fn batch_index_files(
         &self,
-        files: Vec<(
-            String,
-            String,
-            String,
-            Option<String>,
-            chrono::DateTime<chrono::Utc>,
-            u64,
-        )>,
+        files: Vec<IndexedFile>,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         println!("🔍 Batch indexing {} files", files.len());
 
         let mut writer = self.writer.lock().await;
 
-        for (file_id, filename, path, content, modified, size) in files {
-            let file_type = Self::detect_file_type(&filename);
+        for file in files {
+            let file_type = Self::detect_file_type(&file.filename);
+            let modified = tantivy::DateTime::from_timestamp_secs(file.modified.timestamp());
 
             // Create document
-            let doc = doc!(
-                self.file_id_field => file_id.as_str(),
-                self.filename_field => filename.as_str(),
-                self.path_field => path.as_str(),
-                self.content_field => content.unwrap_or_default(),
-                self.modified_field => tantivy::DateTime::from_timestamp_secs(modified.timestamp()),
-                self.size_field => size,
+            let mut doc = doc!(
+                self.file_id_field => file.id.as_str(),
+                self.filename_field => file.filename.as_str(),
+                self.path_field => file.path.as_str(),
+                self.content_field => file.content.unwrap_or_default(),
+                self.modified_field => modified,
+                self.size_field => file.size,
                 self.file_type_field => file_type,
             );
+            for principal in &file.acl {
+                doc.add_text(self.acl_field, principal);
+            }
 
             // Delete old document if exists
-            let term = Term::from_field_text(self.file_id_field, &file_id);
+            let term = Term::from_field_text(self.file_id_field, &file.id);
             writer.delete_term(term);
 
             // Add new document
@@ -327,12 +368,32 @@ impl SearchIndex {
         Ok(())
     }
 
+    /// Only match documents `viewer` may read
+    fn restrict(&self, query: Box<dyn Query>, viewer: &Viewer) -> Box<dyn Query> {
+        let principals: Vec<(Occur, Box<dyn Query>)> = viewer
+            .principals
+            .iter()
+            .map(|principal| {
+                let term = Term::from_field_text(self.acl_field, principal);
+                (
+                    Occur::Should,
+                    Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<dyn Query>,
+                )
+            })
+            .collect();
+        Box::new(BooleanQuery::new(vec![
+            (Occur::Must, query),
+            (Occur::Must, Box::new(BooleanQuery::new(principals))),
+        ]))
+    }
+
     /// Search with query string
     pub fn search(
         &self,
         query_str: &str,
         limit: usize,
         fuzzy: bool,
+        viewer: &Viewer,
     ) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Send + Sync>> {
         self.search_advanced(
             query_str,
@@ -346,6 +407,7 @@ impl SearchIndex {
                 date_from: None,
                 date_to: None,
             },
+            viewer,
         )
     }
 
@@ -355,6 +417,7 @@ impl SearchIndex {
         query_str: &str,
         limit: usize,
         options: SearchOptions,
+        viewer: &Viewer,
     ) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Send + Sync>> {
         let searcher = self.reader.searcher();
 
@@ -376,8 +439,6 @@ impl SearchIndex {
         // Apply filters if specified
         if let Some(ref file_types) = options.file_type_filter {
             // Add file type filter
-            use tantivy::query::{BooleanQuery, Occur};
-
             let file_type_queries: Vec<Box<dyn tantivy::query::Query>> = file_types
                 .iter()
                 .map(|ft| {
@@ -404,6 +465,8 @@ impl SearchIndex {
             }
         }
 
+        let query = self.restrict(query, viewer);
+
         // Search - get more results than needed for post-filtering
         let search_limit = if !options.fuzzy { limit * 10 } else { limit };
         let top_docs = searcher.search(&query, &TopDocs::with_limit(search_limit))?;
@@ -529,29 +592,25 @@ impl SearchIndex {
     }
 
     /// Generate search suggestions for autocomplete
+    ///
+    /// Matches `prefix` anywhere in the filename or path; names that start
+    /// with it come first.
     pub fn suggest(
         &self,
         prefix: &str,
         limit: usize,
+        viewer: &Viewer,
     ) -> Result<Vec<SearchSuggestion>, Box<dyn std::error::Error + Send + Sync>> {
         let searcher = self.reader.searcher();
 
-        // Create prefix query on filename field
-        let query_str = format!("{}*", prefix); // Wildcard search
-        let query_parser = QueryParser::for_index(&self.index, vec![self.filename_field]);
-
-        let query = query_parser.parse_query(&query_str)?;
-        let top_docs = searcher.search(&query, &TopDocs::with_limit(limit * 2))?; // Get more to deduplicate
+        let query = self.restrict(Box::new(tantivy::query::AllQuery), viewer);
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(10000))?; // Limit to prevent OOM
 
-        // Collect unique suggestions
-        let mut suggestions = Vec::new();
+        let needle = prefix.to_lowercase();
+        let mut matches = Vec::new();
         let mut seen = std::collections::HashSet::new();
 
-        for (score, doc_address) in top_docs {
-            if suggestions.len() >= limit {
-                break;
-            }
-
+        for (_, doc_address) in top_docs {
             let retrieved_doc = searcher.doc::<tantivy::TantivyDocument>(doc_address)?;
 
             let filename = retrieved_doc
@@ -560,31 +619,61 @@ impl SearchIndex {
                 .unwrap_or("")
                 .to_string();
 
+            let path = retrieved_doc
+                .get_first(self.path_field)
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string();
+
+            let filename_lower = filename.to_lowercase();
+            if !filename_lower.contains(&needle) && !path.to_lowercase().contains(&needle) {
+                continue;
+            }
+
+            // Suggest the folder the file is in
+            let folder = path.rsplit_once('/').map(|(parent, _)| parent.to_string());
+
+            // Deduplicate by filename and folder
+            if !seen.insert((filename.clone(), folder.clone())) {
+                continue;
+            }
+
             let file_type = retrieved_doc
                 .get_first(self.file_type_field)
                 .and_then(|v| v.as_str())
                 .map(|s| s.to_string());
 
-            // Deduplicate by filename
-            if !seen.contains(&filename) {
-                seen.insert(filename.clone());
-                suggestions.push(SearchSuggestion {
+            let size = retrieved_doc
+                .get_first(self.size_field)
+                .and_then(|v| v.as_u64());
+
+            matches.push((
+                !filename_lower.starts_with(&needle),
+                SearchSuggestion {
                     text: filename,
                     file_type,
-                    score,
-                    path: None,
-                    size_bytes: None,
-                });
-            }
+                    score: 1.0,
+                    path: folder,
+                    size_bytes: size.map(|s| s as i64),
+                },
+            ));
         }
 
-        Ok(suggestions)
+        matches.sort_by(|(a_later, a), (b_later, b)| {
+            a_later.cmp(b_later).then_with(|| a.text.cmp(&b.text))
+        });
+        Ok(matches
+            .into_iter()
+            .take(limit)
+            .map(|(_, suggestion)| suggestion)
+            .collect())
     }
 
     /// Get search facets (aggregations by file type, size, date)
     pub fn facets(
         &self,
         query_str: Option<&str>,
+        viewer: &Viewer,
     ) -> Result<SearchFacets, Box<dyn std::error::Error + Send + Sync>> {
         let searcher = self.reader.searcher();
 
@@ -598,6 +687,7 @@ impl SearchIndex {
         } else {
             Box::new(tantivy::query::AllQuery)
         };
+        let query = self.restrict(query, viewer);
 
         // Search all matching documents
         let top_docs = searcher.search(&query, &TopDocs::with_limit(10000))?; // Limit to prevent OOM
@@ -764,11 +854,12 @@ impl SearchIndex {
         query_str: &str,
         limit: usize,
         fuzzy: bool,
+        viewer: &Viewer,
     ) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Send + Sync>> {
         let searcher = self.reader.searcher();
 
         // Parse query
-        let query = if fuzzy {
+        let query: Box<dyn Query> = if fuzzy {
             // Fuzzy search - allows typos
             let term = Term::from_field_text(self.filename_field, query_str);
             Box::new(FuzzyTermQuery::new(term, 2, true)) // max 2 edits
@@ -781,6 +872,8 @@ impl SearchIndex {
             query_parser.parse_query(query_str)?
         };
 
+        let query = self.restrict(query, viewer);
+
         // Search
         let top_docs = searcher.search(&query, &TopDocs::with_limit(limit))?;
 
@@ -928,6 +1021,50 @@ pub struct IndexStats {
     pub index_size_bytes: u64,
 }
 
+/// Index the files at `path` or below it, all files if `path` is empty.
+/// Used to rebuild the index and after permissions on `path` change.
+pub async fn reindex_tree(
+    index: &SearchIndex,
+    pool: &SqlitePool,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let path = path.trim_matches('/');
+    let files: Vec<(String, String, String, i64, String)> = sqlx::query_as(
+        r#"
+        SELECT id, name, path, size_bytes, updated_at FROM files
+        WHERE is_deleted = 0
+          AND (?1 = '' OR trim(path, '/') = ?1
+               OR substr(trim(path, '/'), 1, length(?1) + 1) = ?1 || '/')
+        "#,
+    )
+    .bind(path)
+    .fetch_all(pool)
+    .await?;
+
+    let mut count = 0;
+    for (file_id, filename, path, size_bytes, updated_at) in files {
+        let modified = chrono::DateTime::parse_from_rfc3339(&updated_at)
+            .map(|dt| dt.with_timezone(&chrono::Utc))
+            .unwrap_or_else(|_| chrono::Utc::now());
+        let file_path = Path::new("./data").join(path.trim_start_matches('/'));
+        let content = if file_path.exists() {
+            extract_content(&file_path).await
+        } else {
+            None
+        };
+        let acl = access::grants(pool, &path).await?;
+
+        match index
+            .index_file(&file_id, &filename, &path, content, modified, size_bytes as u64, &acl)
+            .await
+        {
+            Ok(()) => count += 1,
+            Err(e) => eprintln!("Failed to index file {}: {:?}", filename, e),
+        }
+    }
+    Ok(count)
+}
+
 /// Extract text content from file based on type
 pub async fn extract_content(file_path: &Path) -> Option<String> {
     let ext = file_path.extension()?.to_str()?.to_lowercase();
@@ -1136,6 +1273,16 @@ mod tests {
     async fn test_index_and_search() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let index = SearchIndex::new()?;
 
+        let owner = Viewer {
+            principals: vec![access::user("owner-1")],
+        };
+        let member = Viewer {
+            principals: vec![access::user("member-1"), access::group("team-1")],
+        };
+        let stranger = Viewer {
+            principals: vec![access::user("stranger-1")],
+        };
+
         // Index a test file
         index
             .index_file(
@@ -1145,14 +1292,23 @@ mod tests {
                 Some("This is a test document with searchable content".to_string()),
                 chrono::Utc::now(),
                 1024,
+                &[access::user("owner-1"), access::group("team-1")],
             )
             .await?;
+        index.force_commit().await?;
+        index.reader.reload()?;
 
         // Search
-        let results = index.search("searchable", 10, false)?;
+        let results = index.search("searchable", 10, false, &owner)?;
         assert!(!results.is_empty());
         assert_eq!(results[0].filename, "example.txt");
 
+        // Only the owner and the team can find it
+        assert!(!index.search("example", 10, false, &member)?.is_empty());
+        assert!(index.search("example", 10, false, &stranger)?.is_empty());
+        assert!(index.suggest("example", 10, &stranger)?.is_empty());
+        assert!(index.facets(None, &stranger)?.file_types.is_empty());
+
         Ok(())
     }
 
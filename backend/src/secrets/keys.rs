@@ -0,0 +1,190 @@
+//! Master keys
+//!
+//! `SYNCSPACE_MASTER_KEY` holds comma separated `<key id>:<base64 key>`
+//! entries. Without it the keys are read from `SYNCSPACE_MASTER_KEY_FILE`
+//! (default `./master.key`, outside the data directory so that copies of the
+//! data do not carry the key), one entry per line; the file is created with
+//! a new key if it does not exist. In both cases the last key is the active
+//! one, the others only open secrets sealed before a rotation.
+
+use std::path::{Path, PathBuf};
+
+use base64::{Engine as _, engine::general_purpose};
+
+use super::SecretError;
+
+pub const KEY_ENV: &str = "SYNCSPACE_MASTER_KEY";
+pub const KEY_FILE_ENV: &str = "SYNCSPACE_MASTER_KEY_FILE";
+const DEFAULT_KEY_FILE: &str = "./master.key";
+pub const KEY_SIZE: usize = 32;
+
+/// Where the master keys come from, which decides whether they can be rotated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    Env,
+    File(PathBuf),
+    /// Generated for this process only, secrets do not survive a restart
+    Memory,
+}
+
+#[derive(Clone)]
+pub struct MasterKeys {
+    /// Oldest first, the last one is active
+    keys: Vec<(String, [u8; KEY_SIZE])>,
+}
+
+impl std::fmt::Debug for MasterKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.keys.iter().map(|(id, _)| id))
+            .finish()
+    }
+}
+
+impl MasterKeys {
+    pub fn generate() -> Self {
+        let mut keys = Self { keys: Vec::new() };
+        keys.add_new();
+        keys
+    }
+
+    /// Parse entries separated by newlines or commas; `#` starts a comment
+    pub fn parse(text: &str) -> Result<Self, SecretError> {
+        let mut keys = Vec::new();
+        for entry in text
+            .lines()
+            .flat_map(|line| line.split('#').next().unwrap_or_default().split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (id, key) = entry
+                .split_once(':')
+                .ok_or_else(|| SecretError::Key("entries must be <key id>:<base64 key>".into()))?;
+            let id = id.trim();
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                return Err(SecretError::Key(format!("invalid key id '{}'", id)));
+            }
+            let key: [u8; KEY_SIZE] = general_purpose::STANDARD
+                .decode(key.trim())
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| {
+                    SecretError::Key(format!("key {} is not {} base64 bytes", id, KEY_SIZE))
+                })?;
+            if keys.iter().any(|(known, _)| known == id) {
+                return Err(SecretError::Key(format!("key id {} is used twice", id)));
+            }
+            keys.push((id.to_string(), key));
+        }
+        if keys.is_empty() {
+            return Err(SecretError::Key("no master key given".into()));
+        }
+        Ok(Self { keys })
+    }
+
+    /// Keys from the environment or the key file, see the module docs
+    pub fn load() -> Result<(Self, KeySource), SecretError> {
+        if let Ok(text) = std::env::var(KEY_ENV) {
+            return Ok((Self::parse(&text)?, KeySource::Env));
+        }
+        let path = PathBuf::from(
+            std::env::var(KEY_FILE_ENV).unwrap_or_else(|_| DEFAULT_KEY_FILE.to_string()),
+        );
+        let keys = match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let keys = Self::generate();
+                keys.save(&path)?;
+                tracing::warn!(
+                    "Created master key file {}; back it up, stored credentials cannot be read without it",
+                    path.display()
+                );
+                keys
+            }
+            Err(e) => {
+                return Err(SecretError::Key(format!(
+                    "cannot read {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        };
+        Ok((keys, KeySource::File(path)))
+    }
+
+    /// Write all keys to `path`, readable by the owner only
+    pub fn save(&self, path: &Path) -> Result<(), SecretError> {
+        let mut text = String::from(
+            "# SyncSpace master keys, the last one is active. Keep a backup of this file.\n",
+        );
+        for (id, key) in &self.keys {
+            text.push_str(&format!(
+                "{}:{}\n",
+                id,
+                general_purpose::STANDARD.encode(key)
+            ));
+        }
+
+        let temp = path.with_extension("tmp");
+        let written = (|| {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(&temp)?;
+            std::io::Write::write_all(&mut file, text.as_bytes())?;
+            file.sync_all()?;
+            std::fs::rename(&temp, path)
+        })();
+        written.map_err(|e| SecretError::Key(format!("cannot write {}: {}", path.display(), e)))
+    }
+
+    /// Add a new random key and make it the active one; returns its id
+    pub fn add_new(&mut self) -> String {
+        let id = hex::encode(rand::random::<[u8; 4]>());
+        self.keys.push((id.clone(), rand::random()));
+        id
+    }
+
+    pub fn active(&self) -> (&str, &[u8; KEY_SIZE]) {
+        let (id, key) = self.keys.last().expect("there is always a master key");
+        (id, key)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&[u8; KEY_SIZE]> {
+        self.keys
+            .iter()
+            .find(|(known, _)| known == id)
+            .map(|(_, key)| key)
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.keys.iter().map(|(id, _)| id.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_save() {
+        let first = general_purpose::STANDARD.encode([1u8; KEY_SIZE]);
+        let second = general_purpose::STANDARD.encode([2u8; KEY_SIZE]);
+        let keys = MasterKeys::parse(&format!("old:{},new:{}", first, second)).unwrap();
+        assert_eq!(keys.ids(), ["old", "new"]);
+        assert_eq!(keys.active(), ("new", &[2u8; KEY_SIZE]));
+        assert_eq!(keys.get("old"), Some(&[1u8; KEY_SIZE]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master.key");
+        keys.save(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(MasterKeys::parse(&text).unwrap().ids(), ["old", "new"]);
+
+        assert!(MasterKeys::parse("").is_err());
+        assert!(MasterKeys::parse(&format!("a b:{}", first)).is_err());
+        assert!(MasterKeys::parse("k:c2hvcnQ=").is_err());
+        assert!(MasterKeys::parse(&format!("k:{}\nk:{}", first, second)).is_err());
+    }
+}
@@ -0,0 +1,626 @@
+//! Secrets vault
+//!
+//! Credentials the server has to present to other systems (OAuth client
+//! secrets and tokens, the LDAP bind password, FTP and IMAP passwords,
+//! webhook signing secrets, backup destination credentials, peer sync
+//! secrets) are stored sealed with AES-256-GCM under a
+//! master key, see [`keys`]. A sealed value is
+//! `sealed:v1:<key id>:<base64 nonce and ciphertext>` and is bound to its
+//! column and row, so it cannot be copied into another record.
+//!
+//! Every [`reveal`] is recorded in `secret_reads` with its purpose; a secret
+//! is not handed out if the read cannot be recorded. [`rotate`] activates a
+//! new master key and rewraps all secrets under it. Values written before
+//! the vault existed are sealed by [`maintain`] at startup.
+
+pub mod keys;
+
+use std::sync::{OnceLock, RwLock};
+
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+};
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+pub use keys::{KeySource, MasterKeys};
+
+const PREFIX: &str = "sealed:v1:";
+const NONCE_SIZE: usize = 12;
+/// Days the read audit trail is kept
+pub const READ_RETENTION_DAYS: i64 = 365;
+
+#[derive(Debug)]
+pub enum SecretError {
+    /// No vault has been installed
+    NotConfigured,
+    /// Master keys are missing or invalid
+    Key(String),
+    /// Sealed under a master key the vault does not have
+    UnknownKey(String),
+    /// Not a valid sealed value for this record
+    Corrupt,
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for SecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretError::NotConfigured => write!(f, "secrets vault is not configured"),
+            SecretError::Key(e) => write!(f, "master key: {}", e),
+            SecretError::UnknownKey(id) => write!(f, "unknown master key {}", id),
+            SecretError::Corrupt => write!(f, "sealed secret is corrupt"),
+            SecretError::Database(e) => write!(f, "database: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+impl From<sqlx::Error> for SecretError {
+    fn from(e: sqlx::Error) -> Self {
+        SecretError::Database(e)
+    }
+}
+
+/// How values were stored before they were sealed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Legacy {
+    Base64,
+    Plain,
+}
+
+/// A column holding secrets; rows are identified by their `id`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub table: &'static str,
+    pub column: &'static str,
+    legacy: Legacy,
+}
+
+impl Field {
+    pub fn name(&self) -> String {
+        format!("{}.{}", self.table, self.column)
+    }
+
+    fn aad(&self, row_id: &str) -> Vec<u8> {
+        format!("{}.{}:{}", self.table, self.column, row_id).into_bytes()
+    }
+
+    fn decode_legacy(&self, stored: &str) -> Result<String, SecretError> {
+        match self.legacy {
+            Legacy::Plain => Ok(stored.to_string()),
+            Legacy::Base64 => general_purpose::STANDARD
+                .decode(stored)
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or(SecretError::Corrupt),
+        }
+    }
+}
+
+pub const OAUTH_CLIENT_SECRET: Field = Field {
+    table: "oauth_providers",
+    column: "client_secret_encrypted",
+    legacy: Legacy::Base64,
+};
+pub const OAUTH_ACCESS_TOKEN: Field = Field {
+    table: "oauth_tokens",
+    column: "access_token_encrypted",
+    legacy: Legacy::Base64,
+};
+pub const OAUTH_REFRESH_TOKEN: Field = Field {
+    table: "oauth_tokens",
+    column: "refresh_token_encrypted",
+    legacy: Legacy::Base64,
+};
+pub const LDAP_BIND_PASSWORD: Field = Field {
+    table: "ldap_configs",
+    column: "bind_password_encrypted",
+    legacy: Legacy::Base64,
+};
+pub const FTP_PASSWORD: Field = Field {
+    table: "ftp_connections",
+    column: "password_encrypted",
+    legacy: Legacy::Base64,
+};
+pub const IMAP_PASSWORD: Field = Field {
+    table: "email_accounts",
+    column: "password_encrypted",
+    legacy: Legacy::Base64,
+};
+pub const WEBHOOK_SECRET: Field = Field {
+    table: "webhooks",
+    column: "secret",
+    legacy: Legacy::Plain,
+};
+pub const WORKFLOW_WEBHOOK_SECRET: Field = Field {
+    table: "workflow_webhook_endpoints",
+    column: "secret",
+    legacy: Legacy::Plain,
+};
+/// JSON object with the credential fields of a destination's config
+pub const BACKUP_DESTINATION_SECRETS: Field = Field {
+    table: "backup_destinations",
+    column: "secrets",
+    legacy: Legacy::Plain,
+};
+/// HMAC key shared with a paired peer instance
+pub const PEER_SHARED_SECRET: Field = Field {
+    table: "peers",
+    column: "shared_secret",
+    legacy: Legacy::Plain,
+};
+
+/// Every column managed by the vault
+pub const FIELDS: [Field; 10] = [
+    OAUTH_CLIENT_SECRET,
+    OAUTH_ACCESS_TOKEN,
+    OAUTH_REFRESH_TOKEN,
+    LDAP_BIND_PASSWORD,
+    FTP_PASSWORD,
+    IMAP_PASSWORD,
+    WEBHOOK_SECRET,
+    WORKFLOW_WEBHOOK_SECRET,
+    BACKUP_DESTINATION_SECRETS,
+    PEER_SHARED_SECRET,
+];
+
+/// Whether `stored` is a sealed value rather than a legacy one
+pub fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(PREFIX)
+}
+
+/// Master key id of a sealed value
+pub fn key_id(stored: &str) -> Option<&str> {
+    stored
+        .strip_prefix(PREFIX)?
+        .split_once(':')
+        .map(|(id, _)| id)
+}
+
+pub struct Vault {
+    keys: RwLock<MasterKeys>,
+    source: KeySource,
+}
+
+impl Vault {
+    pub fn new(keys: MasterKeys, source: KeySource) -> Self {
+        Self {
+            keys: RwLock::new(keys),
+            source,
+        }
+    }
+
+    /// Vault with the master keys from the environment or the key file
+    pub fn from_env() -> Result<Self, SecretError> {
+        let (keys, source) = MasterKeys::load()?;
+        Ok(Self::new(keys, source))
+    }
+
+    /// Vault with a random key that only lives as long as the process
+    pub fn in_memory() -> Self {
+        Self::new(MasterKeys::generate(), KeySource::Memory)
+    }
+
+    pub fn source(&self) -> &KeySource {
+        &self.source
+    }
+
+    pub fn active_key_id(&self) -> String {
+        self.keys.read().unwrap().active().0.to_string()
+    }
+
+    pub fn key_ids(&self) -> Vec<String> {
+        self.keys.read().unwrap().ids()
+    }
+
+    pub fn seal(
+        &self,
+        field: &Field,
+        row_id: &str,
+        plaintext: &str,
+    ) -> Result<String, SecretError> {
+        let keys = self.keys.read().unwrap();
+        let (key_id, key) = keys.active();
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: &field.aad(row_id),
+                },
+            )
+            .map_err(|_| SecretError::Corrupt)?;
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&sealed);
+        Ok(format!(
+            "{}{}:{}",
+            PREFIX,
+            key_id,
+            general_purpose::STANDARD.encode(bytes)
+        ))
+    }
+
+    /// Plaintext of a sealed value
+    pub fn open(&self, field: &Field, row_id: &str, stored: &str) -> Result<String, SecretError> {
+        let (key_id, sealed) = stored
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+            .ok_or(SecretError::Corrupt)?;
+        let keys = self.keys.read().unwrap();
+        let key = keys
+            .get(key_id)
+            .ok_or_else(|| SecretError::UnknownKey(key_id.to_string()))?;
+        let bytes = general_purpose::STANDARD
+            .decode(sealed)
+            .map_err(|_| SecretError::Corrupt)?;
+        if bytes.len() < NONCE_SIZE {
+            return Err(SecretError::Corrupt);
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_SIZE);
+        let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: &field.aad(row_id),
+                },
+            )
+            .map_err(|_| SecretError::Corrupt)?;
+        String::from_utf8(plaintext).map_err(|_| SecretError::Corrupt)
+    }
+
+    /// Activate a new master key; returns its id. Keys from the
+    /// environment are rotated by appending one there and restarting.
+    fn add_key(&self) -> Result<String, SecretError> {
+        if self.source == KeySource::Env {
+            return Err(SecretError::Key(format!(
+                "add a key to {} to rotate",
+                keys::KEY_ENV
+            )));
+        }
+        let mut current = self.keys.write().unwrap();
+        let mut rotated = current.clone();
+        let id = rotated.add_new();
+        // The new key has to be on disk before anything is sealed with it
+        if let KeySource::File(path) = &self.source {
+            rotated.save(path)?;
+        }
+        *current = rotated;
+        Ok(id)
+    }
+}
+
+static VAULT: OnceLock<Vault> = OnceLock::new();
+
+pub fn install(vault: Vault) {
+    if VAULT.set(vault).is_err() {
+        tracing::warn!("Secrets vault is already installed");
+    }
+}
+
+pub fn vault() -> Option<&'static Vault> {
+    VAULT.get()
+}
+
+fn installed() -> Result<&'static Vault, SecretError> {
+    vault().ok_or(SecretError::NotConfigured)
+}
+
+/// Seal `plaintext` for storage in `field` of row `row_id`. Empty values
+/// are stored as they are.
+pub fn seal(field: &Field, row_id: &str, plaintext: &str) -> Result<String, SecretError> {
+    if plaintext.is_empty() {
+        return Ok(String::new());
+    }
+    installed()?.seal(field, row_id, plaintext)
+}
+
+/// Plaintext of the value stored in `field` of row `row_id`, recording the
+/// read and its `purpose`
+pub async fn reveal(
+    pool: &SqlitePool,
+    field: &Field,
+    row_id: &str,
+    stored: &str,
+    purpose: &str,
+) -> Result<String, SecretError> {
+    if stored.is_empty() {
+        return Ok(String::new());
+    }
+    // Values from before the vault until maintain() has sealed them
+    let (plaintext, key_id) = if is_sealed(stored) {
+        (installed()?.open(field, row_id, stored)?, key_id(stored))
+    } else {
+        (field.decode_legacy(stored)?, None)
+    };
+    record_read(pool, field, row_id, key_id, purpose).await?;
+    Ok(plaintext)
+}
+
+async fn record_read(
+    pool: &SqlitePool,
+    field: &Field,
+    row_id: &str,
+    key_id: Option<&str>,
+    purpose: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO secret_reads (secret, row_id, key_id, purpose, read_at)
+         VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(field.name())
+    .bind(row_id)
+    .bind(key_id)
+    .bind(purpose)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn table_exists(pool: &SqlitePool, table: &str) -> Result<bool, sqlx::Error> {
+    let found: Option<(String,)> =
+        sqlx::query_as("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(table)
+            .fetch_optional(pool)
+            .await?;
+    Ok(found.is_some())
+}
+
+/// Stored values of `field`, skipping empty ones
+async fn stored_values(
+    pool: &SqlitePool,
+    field: &Field,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    if !table_exists(pool, field.table).await? {
+        return Ok(Vec::new());
+    }
+    sqlx::query_as(&format!(
+        "SELECT id, {column} FROM {table} WHERE {column} IS NOT NULL AND {column} != ''",
+        table = field.table,
+        column = field.column
+    ))
+    .fetch_all(pool)
+    .await
+}
+
+/// Replace `old` with `new`, unless the value changed in the meantime
+async fn replace(
+    pool: &SqlitePool,
+    field: &Field,
+    row_id: &str,
+    old: &str,
+    new: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(&format!(
+        "UPDATE {table} SET {column} = ? WHERE id = ? AND {column} = ?",
+        table = field.table,
+        column = field.column
+    ))
+    .bind(new)
+    .bind(row_id)
+    .bind(old)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Seal values stored before the vault existed and prune the read audit
+/// trail; returns the number of values sealed
+pub async fn maintain(pool: &SqlitePool) -> Result<usize, SecretError> {
+    let vault = installed()?;
+    let mut sealed = 0;
+    for field in &FIELDS {
+        for (row_id, stored) in stored_values(pool, field).await? {
+            if is_sealed(&stored) {
+                continue;
+            }
+            let plaintext = match field.decode_legacy(&stored) {
+                Ok(plaintext) => plaintext,
+                Err(_) => {
+                    tracing::warn!("Cannot read legacy secret {} of {}", field.name(), row_id);
+                    continue;
+                }
+            };
+            let new = vault.seal(field, &row_id, &plaintext)?;
+            if replace(pool, field, &row_id, &stored, &new).await? {
+                sealed += 1;
+            }
+        }
+    }
+
+    let cutoff = Utc::now() - Duration::days(READ_RETENTION_DAYS);
+    sqlx::query("DELETE FROM secret_reads WHERE read_at < ?")
+        .bind(cutoff.to_rfc3339())
+        .execute(pool)
+        .await?;
+    Ok(sealed)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RotationReport {
+    pub active_key_id: String,
+    /// Whether a new master key was created; keys from the environment are
+    /// added there
+    pub new_key: bool,
+    pub rewrapped: usize,
+    /// Secrets that could not be opened and still use an old key
+    pub failed: usize,
+}
+
+/// Activate a new master key (unless the keys come from the environment)
+/// and rewrap every secret sealed under an older one. Old keys stay known,
+/// so an interrupted rotation is finished by running it again.
+pub async fn rotate(pool: &SqlitePool) -> Result<RotationReport, SecretError> {
+    let vault = installed()?;
+    let new_key = match vault.source() {
+        KeySource::Env => false,
+        _ => {
+            let id = vault.add_key()?;
+            tracing::info!("Activated master key {}", id);
+            true
+        }
+    };
+    let active = vault.active_key_id();
+
+    let mut report = RotationReport {
+        active_key_id: active.clone(),
+        new_key,
+        rewrapped: 0,
+        failed: 0,
+    };
+    for field in &FIELDS {
+        for (row_id, stored) in stored_values(pool, field).await? {
+            if !is_sealed(&stored) || key_id(&stored) == Some(active.as_str()) {
+                continue;
+            }
+            let plaintext = match vault.open(field, &row_id, &stored) {
+                Ok(plaintext) => plaintext,
+                Err(e) => {
+                    tracing::warn!("Cannot rewrap {} of {}: {}", field.name(), row_id, e);
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            record_read(pool, field, &row_id, key_id(&stored), "master key rotation").await?;
+            let new = vault.seal(field, &row_id, &plaintext)?;
+            if replace(pool, field, &row_id, &stored, &new).await? {
+                report.rewrapped += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldStatus {
+    pub secret: String,
+    /// Master key the values are sealed with, `None` for legacy values
+    pub key_id: Option<String>,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VaultStatus {
+    pub source: String,
+    pub active_key_id: String,
+    pub key_ids: Vec<String>,
+    pub secrets: Vec<FieldStatus>,
+}
+
+/// Master keys and how many secrets each of them seals
+pub async fn status(pool: &SqlitePool) -> Result<VaultStatus, SecretError> {
+    let vault = installed()?;
+    let mut secrets: Vec<FieldStatus> = Vec::new();
+    for field in &FIELDS {
+        for (_, stored) in stored_values(pool, field).await? {
+            let key_id = key_id(&stored).map(str::to_string);
+            match secrets
+                .iter_mut()
+                .find(|s| s.secret == field.name() && s.key_id == key_id)
+            {
+                Some(status) => status.count += 1,
+                None => secrets.push(FieldStatus {
+                    secret: field.name(),
+                    key_id,
+                    count: 1,
+                }),
+            }
+        }
+    }
+    Ok(VaultStatus {
+        source: match vault.source() {
+            KeySource::Env => "environment".to_string(),
+            KeySource::File(path) => path.display().to_string(),
+            KeySource::Memory => "memory".to_string(),
+        },
+        active_key_id: vault.active_key_id(),
+        key_ids: vault.key_ids(),
+        secrets,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct SecretRead {
+    pub id: i64,
+    pub secret: String,
+    pub row_id: String,
+    pub key_id: Option<String>,
+    pub purpose: String,
+    pub read_at: String,
+}
+
+/// Latest reads, optionally of one secret column such as
+/// `ldap_configs.bind_password_encrypted`
+pub async fn reads(
+    pool: &SqlitePool,
+    secret: Option<&str>,
+    limit: i64,
+) -> Result<Vec<SecretRead>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, secret, row_id, key_id, purpose, read_at FROM secret_reads
+         WHERE ? IS NULL OR secret = ?
+         ORDER BY id DESC LIMIT ?",
+    )
+    .bind(secret)
+    .bind(secret)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sealed_values_are_bound_to_their_record() {
+        let vault = Vault::in_memory();
+        let sealed = vault.seal(&FTP_PASSWORD, "conn-1", "hunter2").unwrap();
+        assert!(is_sealed(&sealed));
+        assert_eq!(key_id(&sealed), Some(vault.active_key_id().as_str()));
+        assert!(!sealed.contains("hunter2"));
+        assert_eq!(
+            vault.open(&FTP_PASSWORD, "conn-1", &sealed).unwrap(),
+            "hunter2"
+        );
+
+        assert!(vault.open(&FTP_PASSWORD, "conn-2", &sealed).is_err());
+        assert!(vault.open(&IMAP_PASSWORD, "conn-1", &sealed).is_err());
+        let other = Vault::in_memory();
+        assert!(matches!(
+            other.open(&FTP_PASSWORD, "conn-1", &sealed),
+            Err(SecretError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_rotated_keys_still_open_old_values() {
+        let vault = Vault::in_memory();
+        let old = vault.seal(&WEBHOOK_SECRET, "hook", "s3cret").unwrap();
+        let old_key = vault.active_key_id();
+        let new_key = vault.add_key().unwrap();
+        assert_ne!(old_key, new_key);
+        assert_eq!(vault.key_ids(), [old_key, new_key.clone()]);
+
+        assert_eq!(vault.open(&WEBHOOK_SECRET, "hook", &old).unwrap(), "s3cret");
+        let new = vault.seal(&WEBHOOK_SECRET, "hook", "s3cret").unwrap();
+        assert_eq!(key_id(&new), Some(new_key.as_str()));
+    }
+
+    #[test]
+    fn test_legacy_values() {
+        assert_eq!(
+            FTP_PASSWORD.decode_legacy("aHVudGVyMg==").unwrap(),
+            "hunter2"
+        );
+        assert!(FTP_PASSWORD.decode_legacy("not base64!").is_err());
+        assert_eq!(WEBHOOK_SECRET.decode_legacy("plain").unwrap(), "plain");
+    }
+}
@@ -185,6 +185,12 @@ pub mod sharing {
             Some(serde_json::json!({"share_id": id.to_string(), "token": &token})),
         ).await;
 
+        crate::workflow::notify(
+            crate::workflow::WorkflowEvent::new(crate::workflow::Trigger::FileShare, Some(path.to_string()))
+                .by_user(&user.id)
+                .with_context(serde_json::json!({"share_id": id.to_string()})),
+        );
+
         Ok(Share {
             id,
             file_path: path.to_string(),
@@ -250,14 +256,32 @@ pub mod sharing {
     }
 
     pub async fn delete_share(state: &AppState, user: &UserInfo, share_id: &str) -> Result<()> {
-        sqlx::query("DELETE FROM shared_links WHERE id = ? AND created_by = ?")
-            .bind(share_id)
-            .bind(&user.id)
-            .execute(&state.db_pool)
-            .await?;
+        let item: Option<String> = sqlx::query_scalar(
+            "DELETE FROM shared_links WHERE id = ? AND created_by = ? RETURNING item_id",
+        )
+        .bind(share_id)
+        .bind(&user.id)
+        .fetch_optional(&state.db_pool)
+        .await?;
+        if let Some(path) = item {
+            refresh_search(state, path);
+        }
         Ok(())
     }
 
+    /// Re-index the shared item and everything below it, since who can find
+    /// them has changed
+    fn refresh_search(state: &AppState, path: String) {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                crate::search::reindex_tree(&state.search_index, &state.db_pool, &path).await
+            {
+                tracing::warn!("Failed to update search access for {}: {}", path, e);
+            }
+        });
+    }
+
     /// Get share details by share ID
     pub async fn get_share(state: &AppState, share_id: &str) -> Result<Share> {
         let row: crate::database::SharedLink =
@@ -292,12 +316,17 @@ pub mod sharing {
     ) -> Result<()> {
         // Update share expiration and other settings
         if let Some(expires_at) = req.get("expires_at").and_then(|v| v.as_str()) {
-            sqlx::query("UPDATE shared_links SET expires_at = ? WHERE id = ? AND created_by = ?")
-                .bind(expires_at)
-                .bind(share_id)
-                .bind(&user.id)
-                .execute(&state.db_pool)
-                .await?;
+            let item: Option<String> = sqlx::query_scalar(
+                "UPDATE shared_links SET expires_at = ? WHERE id = ? AND created_by = ? RETURNING item_id",
+            )
+            .bind(expires_at)
+            .bind(share_id)
+            .bind(&user.id)
+            .fetch_optional(&state.db_pool)
+            .await?;
+            if let Some(path) = item {
+                refresh_search(state, path);
+            }
         }
         Ok(())
     }
@@ -436,7 +465,7 @@ pub mod sharing {
         permissions: Vec<String>,
     ) -> Result<Vec<crate::database::ShareUser>> {
         // Verify ownership of the share
-        let _share: crate::database::SharedLink =
+        let share: crate::database::SharedLink =
             sqlx::query_as("SELECT * FROM shared_links WHERE id = ? AND created_by = ?")
                 .bind(share_id)
                 .bind(&user.id)
@@ -474,6 +503,33 @@ pub mod sharing {
             });
         }
 
+        // Let the new members know
+        let file_name = share.item_id.split('/').next_back().unwrap_or(&share.item_id);
+        let title = format!("{} shared {} with you", user.username, file_name);
+        for share_user in &share_users {
+            let message = format!(
+                "{} shared \"{}\" with you ({} access)",
+                user.username, file_name, share_user.permission
+            );
+            crate::notify::send(
+                crate::notify::Notification::new(
+                    &share_user.user_id,
+                    crate::notify::EventType::ShareReceived,
+                    &title,
+                    &message,
+                )
+                .with_action_url("/#/shared")
+                .from_user(&user.id)
+                .with_context(serde_json::json!({
+                    "sharer": user.username,
+                    "file_name": file_name,
+                    "permission": share_user.permission,
+                    "share_id": share_id,
+                })),
+            );
+        }
+        refresh_search(state, share.item_id);
+
         Ok(share_users)
     }
 
@@ -498,7 +554,7 @@ pub mod sharing {
         user_id: &str,
     ) -> Result<()> {
         // Verify ownership
-        let _share: crate::database::SharedLink =
+        let share: crate::database::SharedLink =
             sqlx::query_as("SELECT * FROM shared_links WHERE id = ? AND created_by = ?")
                 .bind(share_id)
                 .bind(&user.id)
@@ -510,6 +566,7 @@ pub mod sharing {
             .bind(user_id)
             .execute(&state.db_pool)
             .await?;
+        refresh_search(state, share.item_id);
 
         Ok(())
     }
@@ -1065,7 +1122,7 @@ pub mod tag {
 
     pub async fn tag_file(
         state: &AppState,
-        _user: &UserInfo,
+        user: &UserInfo,
         file_id: &str,
         tag_id: &str,
     ) -> Result<()> {
@@ -1074,6 +1131,16 @@ pub mod tag {
             .bind(tag_id)
             .execute(&state.db_pool)
             .await?;
+
+        let tag_name: Option<String> = sqlx::query_scalar("SELECT name FROM tags WHERE id = ?")
+            .bind(tag_id)
+            .fetch_optional(&state.db_pool)
+            .await?;
+        crate::workflow::notify(
+            crate::workflow::WorkflowEvent::new(crate::workflow::Trigger::FileTag, Some(file_id.to_string()))
+                .by_user(&user.id)
+                .with_context(serde_json::json!({"tag_id": tag_id, "tag": tag_name})),
+        );
         Ok(())
     }
 
@@ -1277,33 +1344,54 @@ pub mod backup {
 pub mod collaboration {
     use super::*;
     use crate::models::{FileLock, UserPresence};
+    use crate::webdav::locks::{self, DavLock, LockScope, NewLock};
+
+    fn to_file_lock(lock: DavLock) -> FileLock {
+        FileLock {
+            id: Uuid::parse_str(&lock.id).unwrap_or_default(),
+            file_path: lock.path,
+            user_id: Uuid::parse_str(&lock.owner_id).unwrap_or_default(),
+            lock_type: lock.scope.as_str().to_string(),
+            acquired_at: lock.locked_at,
+            expires_at: lock.expires_at,
+        }
+    }
 
+    /// Lock a file for editing. Locks are shared with WebDAV, so WebDAV
+    /// clients cannot modify a file locked here.
     pub async fn acquire_lock(
         state: &AppState,
         user: &UserInfo,
         file_path: &str,
+        lock_type: &str,
+        duration_seconds: Option<u64>,
     ) -> Result<FileLock> {
-        let id = Uuid::new_v4();
-        let now = Utc::now();
-        let expires = now + chrono::Duration::minutes(30);
-        sqlx::query("INSERT INTO file_locks (id, file_id, file_path, locked_by, locked_at, expires_at, lock_type, last_heartbeat) VALUES (?, ?, ?, ?, ?, ?, 'edit', ?)")
-            .bind(&id).bind(file_path).bind(file_path).bind(&user.id).bind(now.to_rfc3339()).bind(expires.to_rfc3339()).bind(now.to_rfc3339()).execute(&state.db_pool).await?;
-        Ok(FileLock {
-            id,
-            file_path: file_path.to_string(),
-            user_id: Uuid::parse_str(&user.id).unwrap_or_default(),
-            lock_type: "edit".to_string(),
-            acquired_at: now,
-            expires_at: expires,
-        })
+        let lock = locks::acquire(
+            &state.db_pool,
+            NewLock {
+                path: file_path.trim_matches('/'),
+                owner_id: &user.id,
+                scope: if lock_type == "shared" {
+                    LockScope::Shared
+                } else {
+                    LockScope::Exclusive
+                },
+                depth_infinity: false,
+                owner_xml: None,
+                timeout_secs: locks::clamp_timeout(duration_seconds),
+            },
+        )
+        .await?;
+        Ok(to_file_lock(lock))
     }
 
-    pub async fn release_lock(state: &AppState, user: &UserInfo, file_path: &str) -> Result<()> {
-        sqlx::query("DELETE FROM file_locks WHERE file_path = ? AND locked_by = ?")
-            .bind(file_path)
-            .bind(&user.id)
-            .execute(&state.db_pool)
-            .await?;
+    /// Release one of the user's locks by id
+    pub async fn release_lock(state: &AppState, user: &UserInfo, lock_id: &str) -> Result<()> {
+        let lock = locks::find(&state.db_pool, lock_id)
+            .await?
+            .filter(|lock| lock.owner_id == user.id)
+            .ok_or_else(|| anyhow!("Lock not found"))?;
+        locks::release(&state.db_pool, &lock.id).await?;
         Ok(())
     }
 
@@ -1322,25 +1410,21 @@ pub mod collaboration {
 
     /// List all active file locks
     pub async fn list_locks(state: &AppState, _user: &UserInfo) -> Result<Vec<FileLock>> {
-        let locks = sqlx::query_as::<_, FileLock>(
-            "SELECT * FROM file_locks WHERE expires_at > datetime('now') ORDER BY locked_at DESC",
-        )
-        .fetch_all(&state.db_pool)
-        .await
-        .unwrap_or_default();
-        Ok(locks)
+        let mut active = locks::active(&state.db_pool).await?;
+        active.reverse();
+        Ok(active.into_iter().map(to_file_lock).collect())
     }
 
-    /// Renew a lock heartbeat to prevent expiry
-    pub async fn renew_lock(state: &AppState, user: &UserInfo, file_path: &str) -> Result<()> {
-        let new_expiry = (Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
-        sqlx::query("UPDATE file_locks SET expires_at = ? WHERE file_path = ? AND locked_by = ?")
-            .bind(&new_expiry)
-            .bind(file_path)
-            .bind(&user.id)
-            .execute(&state.db_pool)
-            .await?;
-        Ok(())
+    /// Renew one of the user's locks for its original duration
+    pub async fn renew_lock(state: &AppState, user: &UserInfo, lock_id: &str) -> Result<FileLock> {
+        locks::find(&state.db_pool, lock_id)
+            .await?
+            .filter(|lock| lock.owner_id == user.id)
+            .ok_or_else(|| anyhow!("Lock not found"))?;
+        let lock = locks::refresh(&state.db_pool, lock_id, None)
+            .await?
+            .ok_or_else(|| anyhow!("Lock not found"))?;
+        Ok(to_file_lock(lock))
     }
 
     /// Get all users currently viewing/editing files
@@ -1408,12 +1492,16 @@ pub mod collaboration {
             conflict_type: String,
             local_version: i32,
             remote_version: i32,
+            conflict_copy_path: Option<String>,
+            peer_name: Option<String>,
             created_at: String,
         }
 
         let conflicts = sqlx::query_as::<_, ConflictRow>(
-            "SELECT id, file_path, conflict_type, local_version, remote_version, created_at 
-             FROM file_conflicts WHERE user_id = ? AND resolved_at IS NULL ORDER BY created_at DESC"
+            "SELECT c.id, c.file_path, c.conflict_type, c.local_version, c.remote_version,
+                    c.conflict_copy_path, p.name AS peer_name, c.created_at
+             FROM file_conflicts c LEFT JOIN peers p ON p.id = c.peer_id
+             WHERE c.user_id = ? AND c.resolved_at IS NULL ORDER BY c.created_at DESC"
         )
         .bind(&user.id)
         .fetch_all(&state.db_pool)
@@ -1429,6 +1517,8 @@ pub mod collaboration {
                     "conflict_type": c.conflict_type,
                     "local_version": c.local_version,
                     "remote_version": c.remote_version,
+                    "conflict_copy_path": c.conflict_copy_path,
+                    "peer": c.peer_name,
                     "created_at": c.created_at,
                 })
             })
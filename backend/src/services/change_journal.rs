@@ -0,0 +1,425 @@
+//! Change Journal - ordered log of file mutations for delta sync clients
+//!
+//! Every mutation appends a row to `change_journal`. Clients keep the `seq`
+//! of the last entry they processed as their cursor and ask for everything
+//! after it, optionally long-polling until something new arrives. Entries
+//! older than the retention period are pruned; a cursor that points before
+//! the pruned range (or past the newest entry, e.g. after a database
+//! restore) is rejected so the client falls back to a full resync.
+
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::auth::UserInfo;
+use crate::search::access::{self, Viewer};
+
+const DEFAULT_RETENTION_DAYS: i64 = 90;
+pub const DEFAULT_PAGE_SIZE: i64 = 500;
+pub const MAX_PAGE_SIZE: i64 = 5000;
+/// Upper bound of a single long-poll request
+pub const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Last recorded journal seq, so long-polls wake up as soon as something is recorded
+fn latest_seq() -> &'static watch::Sender<i64> {
+    static LATEST: OnceLock<watch::Sender<i64>> = OnceLock::new();
+    LATEST.get_or_init(|| watch::channel(0).0)
+}
+
+/// A mutation to record
+#[derive(Debug, Clone)]
+pub struct Change {
+    change_type: String,
+    path: String,
+    old_path: Option<String>,
+    file_id: Option<String>,
+    is_directory: bool,
+    size_bytes: Option<i64>,
+    checksum_sha256: Option<String>,
+    user_id: Option<String>,
+}
+
+impl Change {
+    pub fn new(change_type: &str, path: &str) -> Self {
+        Self {
+            change_type: change_type.to_string(),
+            path: path.to_string(),
+            old_path: None,
+            file_id: None,
+            is_directory: false,
+            size_bytes: None,
+            checksum_sha256: None,
+            user_id: None,
+        }
+    }
+
+    /// Previous path of a rename, move or copy source
+    pub fn with_old_path(mut self, old_path: &str) -> Self {
+        self.old_path = Some(old_path.to_string());
+        self
+    }
+
+    pub fn with_file_id(mut self, file_id: Option<String>) -> Self {
+        self.file_id = file_id;
+        self
+    }
+
+    pub fn with_directory(mut self, is_directory: bool) -> Self {
+        self.is_directory = is_directory;
+        self
+    }
+
+    pub fn with_content(mut self, size_bytes: i64, checksum_sha256: Option<&str>) -> Self {
+        self.size_bytes = Some(size_bytes);
+        self.checksum_sha256 = checksum_sha256.map(str::to_string);
+        self
+    }
+
+    pub fn by_user(mut self, user_id: &str) -> Self {
+        self.user_id = Some(user_id.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct JournalEntry {
+    pub seq: i64,
+    pub change_type: String,
+    pub path: String,
+    pub old_path: Option<String>,
+    pub file_id: Option<String>,
+    pub is_directory: bool,
+    pub size_bytes: Option<i64>,
+    pub checksum_sha256: Option<String>,
+    pub user_id: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangesPage {
+    pub changes: Vec<JournalEntry>,
+    /// Cursor to pass on the next request
+    pub cursor: i64,
+    pub has_more: bool,
+}
+
+/// Append a change. Failures are logged rather than failing the mutation
+/// that already happened; returns the new seq.
+pub async fn record(pool: &SqlitePool, change: Change) -> Option<i64> {
+    let result = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO change_journal (change_type, path, old_path, file_id, is_directory, size_bytes, checksum_sha256, user_id, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING seq",
+    )
+    .bind(&change.change_type)
+    .bind(&change.path)
+    .bind(&change.old_path)
+    .bind(&change.file_id)
+    .bind(change.is_directory)
+    .bind(change.size_bytes)
+    .bind(&change.checksum_sha256)
+    .bind(&change.user_id)
+    .bind(Utc::now().to_rfc3339())
+    .fetch_one(pool)
+    .await;
+
+    match result {
+        Ok(seq) => {
+            latest_seq().send_replace(seq);
+            Some(seq)
+        }
+        Err(e) => {
+            tracing::warn!(
+                "Failed to journal {} of {}: {}",
+                change.change_type,
+                change.path,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Highest seq ever assigned (also after pruning)
+pub async fn latest_cursor(pool: &SqlitePool) -> Result<i64> {
+    Ok(sqlx::query_scalar(
+        "SELECT COALESCE((SELECT seq FROM sqlite_sequence WHERE name = 'change_journal'), 0)",
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// LIKE pattern for entries at or below `prefix`
+fn prefix_pattern(prefix: &str) -> String {
+    let escaped = prefix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("{}/%", escaped)
+}
+
+/// Entries after `cursor`, optionally limited to a subtree. Returns `None`
+/// if the cursor is no longer (or not yet) valid.
+pub async fn changes_since(
+    pool: &SqlitePool,
+    cursor: i64,
+    prefix: Option<&str>,
+    limit: i64,
+) -> Result<Option<ChangesPage>> {
+    let latest = latest_cursor(pool).await?;
+    let pruned_through: i64 =
+        sqlx::query_scalar("SELECT COALESCE(MAX(pruned_through), 0) FROM change_journal_state")
+            .fetch_one(pool)
+            .await?;
+    if cursor < pruned_through || cursor > latest {
+        return Ok(None);
+    }
+
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    let prefix = prefix.unwrap_or_default();
+    // Renames and moves out of the subtree are matched on their old path
+    let mut changes: Vec<JournalEntry> = sqlx::query_as(
+        r#"
+        SELECT seq, change_type, path, old_path, file_id, is_directory, size_bytes, checksum_sha256, user_id, created_at
+        FROM change_journal
+        WHERE seq > ?1 AND seq <= ?2
+        AND (?3 = '' OR path = ?3 OR path LIKE ?4 ESCAPE '\' OR old_path = ?3 OR old_path LIKE ?4 ESCAPE '\')
+        ORDER BY seq ASC LIMIT ?5
+        "#,
+    )
+    .bind(cursor)
+    .bind(latest)
+    .bind(prefix)
+    .bind(prefix_pattern(prefix))
+    .bind(limit + 1)
+    .fetch_all(pool)
+    .await?;
+
+    let has_more = changes.len() as i64 > limit;
+    changes.truncate(limit as usize);
+    // Without more matches the cursor can skip ahead over filtered entries
+    let cursor = match changes.last() {
+        Some(last) if has_more => last.seq,
+        _ => latest,
+    };
+
+    Ok(Some(ChangesPage {
+        changes,
+        cursor,
+        has_more,
+    }))
+}
+
+/// Like `changes_since`, but waits up to `wait` for a change to arrive if
+/// there is none yet
+pub async fn wait_for_changes(
+    pool: &SqlitePool,
+    cursor: i64,
+    prefix: Option<&str>,
+    limit: i64,
+    wait: Duration,
+) -> Result<Option<ChangesPage>> {
+    let deadline = tokio::time::Instant::now() + wait.min(MAX_WAIT);
+    let mut rx = latest_seq().subscribe();
+    let mut cursor = cursor;
+
+    loop {
+        rx.borrow_and_update();
+        let Some(page) = changes_since(pool, cursor, prefix, limit).await? else {
+            return Ok(None);
+        };
+        if !page.changes.is_empty() || tokio::time::Instant::now() >= deadline {
+            return Ok(Some(page));
+        }
+        // Entries outside the subtree were skipped; continue after them
+        cursor = page.cursor;
+
+        if tokio::time::timeout_at(deadline, rx.changed())
+            .await
+            .is_err()
+        {
+            return Ok(Some(ChangesPage {
+                changes: Vec::new(),
+                cursor,
+                has_more: false,
+            }));
+        }
+    }
+}
+
+/// The entries `user` made or may read the path (or former path) of
+pub async fn visible_to(
+    pool: &SqlitePool,
+    user: &UserInfo,
+    changes: Vec<JournalEntry>,
+) -> Result<Vec<JournalEntry>> {
+    if user.is_admin || changes.is_empty() {
+        return Ok(changes);
+    }
+    let viewer = Viewer::load(pool, &user.id).await?;
+    let mut readable: HashMap<String, bool> = HashMap::new();
+    let mut visible = Vec::with_capacity(changes.len());
+    for change in changes {
+        let mut show = change.user_id.as_deref() == Some(user.id.as_str());
+        for path in std::iter::once(&change.path).chain(change.old_path.as_ref()) {
+            if show {
+                break;
+            }
+            show = match readable.get(path) {
+                Some(can_read) => *can_read,
+                None => {
+                    let can_read = viewer.can_read(&access::grants(pool, path).await?);
+                    readable.insert(path.clone(), can_read);
+                    can_read
+                }
+            };
+        }
+        if show {
+            visible.push(change);
+        }
+    }
+    Ok(visible)
+}
+
+/// Retention period from `SYNCSPACE_JOURNAL_RETENTION_DAYS`
+pub fn retention_days() -> i64 {
+    std::env::var("SYNCSPACE_JOURNAL_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(DEFAULT_RETENTION_DAYS)
+}
+
+/// Remove entries older than `retention_days`; returns how many were removed
+pub async fn prune(pool: &SqlitePool, retention_days: i64) -> Result<u64> {
+    let cutoff = (Utc::now() - chrono::Duration::days(retention_days)).to_rfc3339();
+    let mut tx = pool.begin().await?;
+
+    let through: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(seq) FROM change_journal WHERE created_at < ?")
+            .bind(&cutoff)
+            .fetch_one(&mut *tx)
+            .await?;
+    let Some(through) = through else {
+        return Ok(0);
+    };
+
+    let removed = sqlx::query("DELETE FROM change_journal WHERE seq <= ?")
+        .bind(through)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    sqlx::query(
+        "INSERT INTO change_journal_state (id, pruned_through) VALUES (1, ?)
+         ON CONFLICT(id) DO UPDATE SET pruned_through = MAX(pruned_through, excluded.pruned_through)",
+    )
+    .bind(through)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn journal_pool() -> SqlitePool {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let sql = include_str!("../../migrations/055_change_journal.sql");
+        let cleaned: Vec<&str> = sql
+            .lines()
+            .map(|line| line.split("--").next().unwrap_or_default())
+            .collect();
+        for statement in cleaned.join("\n").split(';') {
+            if !statement.trim().is_empty() {
+                sqlx::query(statement).execute(&pool).await.unwrap();
+            }
+        }
+        pool
+    }
+
+    #[tokio::test]
+    async fn test_changes_since_filters_and_pages() {
+        let pool = journal_pool().await;
+        record(&pool, Change::new("create", "a/x.txt"))
+            .await
+            .unwrap();
+        record(&pool, Change::new("create", "b/y.txt"))
+            .await
+            .unwrap();
+        record(
+            &pool,
+            Change::new("move", "b/x.txt").with_old_path("a/x.txt"),
+        )
+        .await
+        .unwrap();
+
+        // Moves out of the subtree show up under their old path
+        let page = changes_since(&pool, 0, Some("a"), 500)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(page.changes.len(), 2);
+        assert_eq!(page.cursor, 3);
+
+        let page = changes_since(&pool, 0, None, 2).await.unwrap().unwrap();
+        assert!(page.has_more);
+        assert_eq!(page.cursor, 2);
+
+        assert!(changes_since(&pool, 10, None, 2).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_long_poll_wakes_on_record() {
+        let pool = journal_pool().await;
+        record(&pool, Change::new("create", "x.txt")).await.unwrap();
+
+        let waiting = pool.clone();
+        let waiter = tokio::spawn(async move {
+            wait_for_changes(&waiting, 1, None, 10, Duration::from_secs(5)).await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        record(&pool, Change::new("delete", "x.txt")).await.unwrap();
+
+        let page = waiter.await.unwrap().unwrap().unwrap();
+        assert_eq!(page.changes.len(), 1);
+        assert_eq!(page.cursor, 2);
+
+        let page = wait_for_changes(&pool, 2, None, 10, Duration::from_millis(100))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(page.changes.is_empty());
+        assert_eq!(page.cursor, 2);
+    }
+
+    #[tokio::test]
+    async fn test_prune_invalidates_old_cursors() {
+        let pool = journal_pool().await;
+        for path in ["a", "b", "c"] {
+            record(&pool, Change::new("create", path)).await.unwrap();
+        }
+        sqlx::query(
+            "UPDATE change_journal SET created_at = '2000-01-01T00:00:00+00:00' WHERE seq <= 2",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(prune(&pool, 90).await.unwrap(), 2);
+        assert!(changes_since(&pool, 1, None, 10).await.unwrap().is_none());
+        let page = changes_since(&pool, 2, None, 10).await.unwrap().unwrap();
+        assert_eq!(page.changes.len(), 1);
+        assert_eq!(latest_cursor(&pool).await.unwrap(), 3);
+    }
+}
@@ -10,6 +10,8 @@ use tracing::{error, info, warn};
 
 const RETENTION_DAYS: i64 = 30;
 const DATA_DIR: &str = "./data";
+/// Legacy chunk uploads without activity for this long are considered abandoned
+const UPLOAD_ABANDON_HOURS: u64 = 24;
 
 /// Configuration for cleanup operations
 #[derive(Debug, Clone)]
@@ -165,6 +167,11 @@ async fn delete_file_permanently(
         }
     }
 
+    // Delete objects on remote storage backends
+    if let Err(e) = crate::storage::delete_file_objects(pool, file_id).await {
+        warn!("⚠️ Failed to delete stored objects of {}: {}", file_path, e);
+    }
+
     // Delete from database (cascade deletes related records)
     sqlx::query("DELETE FROM files WHERE id = ?1")
         .bind(file_id)
@@ -271,6 +278,72 @@ pub async fn get_eligible_for_cleanup_count(
     Ok(count.0)
 }
 
+/// Remove abandoned uploads from `./data/temp_uploads`
+///
+/// - tus sessions whose `expires_at` has passed (partial file + row)
+/// - completed tus session rows older than the retention period
+/// - legacy `/upload-chunk` directories untouched for `UPLOAD_ABANDON_HOURS`
+///
+/// Returns the number of uploads removed.
+pub async fn cleanup_abandoned_uploads(pool: &SqlitePool) -> Result<usize, sqlx::Error> {
+    let now = Utc::now();
+    let mut removed = 0;
+
+    let expired: Vec<(String, String)> = sqlx::query_as(
+        "SELECT id, temp_path FROM upload_sessions WHERE status = 'uploading' AND expires_at < ?1",
+    )
+    .bind(now.to_rfc3339())
+    .fetch_all(pool)
+    .await?;
+
+    for (session_id, temp_path) in expired {
+        if let Err(e) = tokio::fs::remove_file(&temp_path).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("⚠️ Failed to remove expired upload {}: {}", temp_path, e);
+            continue;
+        }
+        sqlx::query("DELETE FROM upload_sessions WHERE id = ?1")
+            .bind(&session_id)
+            .execute(pool)
+            .await?;
+        removed += 1;
+    }
+
+    sqlx::query("DELETE FROM upload_sessions WHERE status = 'completed' AND completed_at < ?1")
+        .bind((now - Duration::days(RETENTION_DAYS)).to_rfc3339())
+        .execute(pool)
+        .await?;
+
+    // Legacy chunk directories have no session row, so use their mtime
+    let cutoff = std::time::SystemTime::now()
+        - std::time::Duration::from_secs(UPLOAD_ABANDON_HOURS * 3600);
+    let temp_root = Path::new(DATA_DIR).join("temp_uploads");
+    if let Ok(mut entries) = tokio::fs::read_dir(&temp_root).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if matches!(entry.file_name().to_str(), Some("tus" | "migration" | "peer_sync")) {
+                continue;
+            }
+            let Ok(meta) = entry.metadata().await else {
+                continue;
+            };
+            let abandoned = meta.is_dir() && meta.modified().is_ok_and(|m| m < cutoff);
+            if abandoned {
+                match tokio::fs::remove_dir_all(entry.path()).await {
+                    Ok(_) => removed += 1,
+                    Err(e) => warn!(
+                        "⚠️ Failed to remove abandoned upload dir {}: {}",
+                        entry.path().display(),
+                        e
+                    ),
+                }
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
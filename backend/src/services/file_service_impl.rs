@@ -1,9 +1,16 @@
 #![allow(dead_code)]
 
 //! File operations service implementation
-use crate::{auth::UserInfo, models::FileInfo, AppState, FileChangeEvent};
+use crate::services::change_journal::Change;
+use crate::{
+    auth::{Session, UserInfo},
+    http_range::ServedFile,
+    models::FileInfo,
+    AppState, FileChangeEvent,
+};
 use anyhow::{anyhow, Result};
 use chrono::Utc;
+use sha2::{Digest, Sha256};
 use sqlx::Row;
 use std::path::Path;
 use tokio::fs;
@@ -158,33 +165,90 @@ pub async fn list_files(state: &AppState, user: &UserInfo, path: &str) -> Result
 pub async fn download_file(
     state: &AppState,
     user: &UserInfo,
+    session: &Session,
     path: &str,
-) -> Result<tokio::fs::File> {
+) -> Result<ServedFile> {
     // SECURITY: Validate file path to prevent directory traversal
     let safe_path =
         crate::security::validate_file_path(path).map_err(|_| anyhow!("Invalid file path"))?;
 
-    let file_path = Path::new(DATA_DIR).join(&safe_path);
-    let file = fs::File::open(&file_path)
-        .await
-        .map_err(|_| anyhow!("File not found"))?;
+    let served = open_stored_file(state, &safe_path, Some((&user.id, session))).await?;
 
-    // Get file metadata for logging
-    let metadata = file.metadata().await.ok();
-    let filename = file_path
+    let filename = served
+        .path
         .file_name()
         .and_then(|n| n.to_str())
         .unwrap_or("unknown")
         .to_string();
-    let file_size = metadata.map(|m| m.len() as i64);
 
     // Log activity
     let _ = crate::services::activity::log(
-        state, &user.id, crate::services::activity::actions::DOWNLOAD, path, &filename, file_size, None, "success", None, None,
+        state, &user.id, crate::services::activity::actions::DOWNLOAD, path, &filename, Some(served.size as i64), None, "success", None, None,
     )
     .await;
 
-    Ok(file)
+    Ok(served)
+}
+
+/// Resolve a stored file for delivery (size, mtime, checksum, content type).
+/// Shared by authenticated downloads and public share links so both honour
+/// Range and conditional requests the same way. `safe_path` must already be validated.
+/// Encrypted content is decrypted for `reader` if they have unlocked its key
+/// in that session, otherwise this fails with [`crate::encryption::Locked`].
+pub async fn open_stored_file(
+    state: &AppState,
+    safe_path: &str,
+    reader: Option<(&str, &Session)>,
+) -> Result<ServedFile> {
+    let file_path = Path::new(DATA_DIR).join(safe_path);
+    // The working copy may have been evicted while the content lives on a remote backend
+    if let Err(e) = crate::storage::ensure_local(&state.db_pool, safe_path).await {
+        tracing::warn!("Failed to restore {} from storage backend: {}", safe_path, e);
+    }
+    let metadata = fs::metadata(&file_path)
+        .await
+        .map_err(|_| anyhow!("File not found"))?;
+
+    if !metadata.is_file() {
+        return Err(anyhow!("File not found"));
+    }
+
+    // Only trust the stored checksum while the size on disk still matches,
+    // otherwise the ETag falls back to size and mtime (see crate::etag)
+    let checksum_sha256 = sqlx::query_scalar::<_, Option<String>>(
+        "SELECT checksum_sha256 FROM files
+         WHERE path = ? AND is_deleted = 0 AND size_bytes = ?
+         ORDER BY updated_at DESC
+         LIMIT 1",
+    )
+    .bind(safe_path)
+    .bind(metadata.len() as i64)
+    .fetch_optional(&state.db_pool)
+    .await
+    .ok()
+    .flatten()
+    .flatten();
+
+    let decryption =
+        crate::encryption::decryption_for(&state.db_pool, safe_path, metadata.len(), reader)
+            .await?;
+    let size = decryption
+        .as_ref()
+        .map_or(metadata.len(), |decryption| decryption.plaintext_len());
+
+    let content_type = mime_guess::from_path(&file_path)
+        .first_or_octet_stream()
+        .to_string();
+
+    Ok(ServedFile {
+        path: file_path,
+        size,
+        modified: metadata.modified().ok(),
+        checksum_sha256,
+        content_type,
+        content_disposition: None,
+        decryption,
+    })
 }
 
 pub async fn upload_file(
@@ -193,8 +257,16 @@ pub async fn upload_file(
     path: &str,
     data: Vec<u8>,
 ) -> Result<FileInfo> {
-    // SECURITY: Check quota before upload
-    let file_size = data.len() as i64;
+    // SECURITY: Validate and sanitize file path
+    let safe_path =
+        crate::security::validate_file_path(path).map_err(|_| anyhow!("Invalid file path"))?;
+
+    // SECURITY: Check quota before upload, an overwrite only needs what it
+    // adds to the file it replaces
+    let replaced = crate::api::quota::replaced_bytes(&state.db_pool, &user.id, &safe_path)
+        .await
+        .map_err(|_| anyhow!("Failed to check quota"))?;
+    let file_size = data.len() as i64 - replaced;
     let has_quota = crate::api::quota::check_quota_available(&state.db_pool, &user.id, file_size)
         .await
         .map_err(|_| anyhow!("Failed to check quota"))?;
@@ -206,10 +278,6 @@ pub async fn upload_file(
         ));
     }
 
-    // SECURITY: Validate and sanitize file path
-    let safe_path =
-        crate::security::validate_file_path(path).map_err(|_| anyhow!("Invalid file path"))?;
-
     // SECURITY: Validate filename
     let filename = Path::new(&safe_path)
         .file_name()
@@ -239,8 +307,64 @@ pub async fn upload_file(
     tmp_file.flush().await?;
     fs::rename(&tmp_path, &target).await?;
 
-    // CRITICAL FIX: Create database entry with CORRECT column names!
-    let file_id = Uuid::new_v4().to_string();
+    let checksum = format!("{:x}", Sha256::digest(&data));
+    register_stored_file(state, user, path, &target, data.len() as i64, checksum).await
+}
+
+/// Move a fully received upload (chunked or tus) into place and register it.
+///
+/// The caller is responsible for the quota check. The checksum is computed by
+/// streaming the assembled file, so multi-GB uploads never load into memory.
+pub async fn import_uploaded_file(
+    state: &AppState,
+    user: &UserInfo,
+    path: &str,
+    assembled: &Path,
+) -> Result<FileInfo> {
+    // SECURITY: Validate and sanitize file path
+    let safe_path =
+        crate::security::validate_file_path(path).map_err(|_| anyhow!("Invalid file path"))?;
+
+    // SECURITY: Validate filename
+    let filename = Path::new(&safe_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Invalid filename"))?;
+
+    let _safe_filename =
+        crate::security::validate_filename(filename).map_err(|_| anyhow!("Invalid filename"))?;
+
+    let target = Path::new(DATA_DIR).join(&safe_path);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let (size_bytes, checksum) = crate::storage::sha256_file(assembled).await?;
+    fs::rename(assembled, &target).await?;
+
+    register_stored_file(state, user, &safe_path, &target, size_bytes as i64, checksum).await
+}
+
+/// Create the DB row, activity log, quota update, search index entry and
+/// change event for a file that has just been written to `target`
+async fn register_stored_file(
+    state: &AppState,
+    user: &UserInfo,
+    path: &str,
+    target: &Path,
+    size_bytes: i64,
+    checksum: String,
+) -> Result<FileInfo> {
+    // An overwrite keeps the existing row, so versions, shares and tags stay
+    // attached to the file
+    let existing_id: Option<String> = sqlx::query_scalar(
+        "SELECT id FROM files WHERE path = ? AND is_deleted = 0 ORDER BY updated_at DESC LIMIT 1",
+    )
+    .bind(path)
+    .fetch_optional(&state.db_pool)
+    .await?;
+    let replaces_existing = existing_id.is_some();
+    let file_id = existing_id.unwrap_or_else(|| Uuid::new_v4().to_string());
     let now = Utc::now().to_rfc3339();
 
     // SAFE: Extract filename, fallback to "upload" if path is invalid
@@ -250,30 +374,63 @@ pub async fn upload_file(
         .unwrap_or("upload")
         .to_string();
 
-    let size_bytes = data.len() as i64;
-
     eprintln!(
         "[upload_file] Uploading: {} ({} bytes) to path: {}",
         filename, size_bytes, path
     );
 
-    sqlx::query(
-        "INSERT INTO files (id, name, path, owner_id, size_bytes, storage_path, is_deleted, version, created_at, updated_at) 
-         VALUES (?, ?, ?, ?, ?, ?, 0, 1, ?, ?)"
-    )
-    .bind(&file_id)
-    .bind(&filename)
-    .bind(path)
-    .bind(&user.id)
-    .bind(size_bytes)
-    .bind(path) // storage_path = same as path for now
-    .bind(&now)
-    .bind(&now)
-    .execute(&state.db_pool)
-    .await?;
+    if replaces_existing {
+        sqlx::query(
+            "UPDATE files SET name = ?, size_bytes = ?, checksum_sha256 = ?, is_encrypted = 0, version = version + 1, updated_at = ?
+             WHERE id = ?",
+        )
+        .bind(&filename)
+        .bind(size_bytes)
+        .bind(&checksum)
+        .bind(&now)
+        .bind(&file_id)
+        .execute(&state.db_pool)
+        .await?;
+        // The new content is plaintext, so an earlier encryption no longer applies
+        sqlx::query("DELETE FROM encrypted_files WHERE file_id = ?")
+            .bind(&file_id)
+            .execute(&state.db_pool)
+            .await?;
+    } else {
+        // CRITICAL FIX: Create database entry with CORRECT column names!
+        sqlx::query(
+            "INSERT INTO files (id, name, path, owner_id, size_bytes, checksum_sha256, storage_path, is_deleted, version, created_at, updated_at) 
+             VALUES (?, ?, ?, ?, ?, ?, ?, 0, 1, ?, ?)"
+        )
+        .bind(&file_id)
+        .bind(&filename)
+        .bind(path)
+        .bind(&user.id)
+        .bind(size_bytes)
+        .bind(&checksum)
+        .bind(path) // storage_path = same as path for now
+        .bind(&now)
+        .bind(&now)
+        .execute(&state.db_pool)
+        .await?;
+    }
 
     eprintln!("[upload_file] DB insert successful for: {}", path);
 
+    // Write through to the default storage backend
+    if let Err(e) = crate::storage::replicate_file(
+        &state.db_pool,
+        &file_id,
+        path,
+        target,
+        size_bytes,
+        &checksum,
+    )
+    .await
+    {
+        tracing::warn!("Failed to record storage location for {}: {}", path, e);
+    }
+
     // Log activity
     let _ = crate::services::activity::log(
         state,
@@ -293,7 +450,10 @@ pub async fn upload_file(
     let _ = crate::api::quota::update_storage_usage(&state.db_pool, &user.id).await;
 
     // AUTO-INDEX: Add file to search index
-    let content = crate::search::extract_content(&target).await;
+    let content = crate::search::extract_content(target).await;
+    let acl = crate::search::access::grants(&state.db_pool, path)
+        .await
+        .unwrap_or_default();
     let _ = state
         .search_index
         .index_file(
@@ -303,10 +463,21 @@ pub async fn upload_file(
             content,
             Utc::now(),
             size_bytes as u64,
+            &acl,
         )
         .await;
     eprintln!("[upload_file] File indexed in search: {}", filename);
 
+    let change_type = if replaces_existing { "modify" } else { "create" };
+    crate::services::change_journal::record(
+        &state.db_pool,
+        Change::new(change_type, path)
+            .with_file_id(Some(file_id.clone()))
+            .with_content(size_bytes, Some(&checksum))
+            .by_user(&user.id),
+    )
+    .await;
+
     let _ = state
         .fs_tx
         .send(FileChangeEvent::new(path.to_string(), "create".to_string()));
@@ -389,6 +560,7 @@ pub async fn delete_file(state: &AppState, user: &UserInfo, path: &str) -> Resul
     let _ = crate::api::quota::update_storage_usage(&state.db_pool, &user.id).await;
 
     // AUTO-INDEX: Remove file from search index (get file_id first)
+    let mut deleted_id = None;
     if let Ok(row) = sqlx::query("SELECT id FROM files WHERE path = ?")
         .bind(path)
         .fetch_one(&state.db_pool)
@@ -397,9 +569,22 @@ pub async fn delete_file(state: &AppState, user: &UserInfo, path: &str) -> Resul
         if let Ok(file_id) = row.try_get::<String, _>("id") {
             let _ = state.search_index.delete_from_index(&file_id).await;
             eprintln!("[delete_file] File removed from search index: {}", path);
+            deleted_id = Some(file_id);
         }
     }
 
+    let is_directory = fs::metadata(Path::new(DATA_DIR).join(path))
+        .await
+        .is_ok_and(|m| m.is_dir());
+    crate::services::change_journal::record(
+        &state.db_pool,
+        Change::new("delete", path)
+            .with_file_id(deleted_id)
+            .with_directory(is_directory)
+            .by_user(&user.id),
+    )
+    .await;
+
     let _ = state
         .fs_tx
         .send(FileChangeEvent::new(path.to_string(), "delete".to_string()));
@@ -420,6 +605,19 @@ pub async fn rename_file(
 
     fs::rename(old, new).await?;
 
+    // Keep file ids (versions, shares, tags) with the new path
+    if let Err(e) = crate::services::fs_watcher::relocate_rows(
+        &state.db_pool,
+        &state.search_index,
+        Path::new(DATA_DIR),
+        old_path,
+        new_path,
+    )
+    .await
+    {
+        tracing::warn!("Failed to move rows {} -> {}: {}", old_path, new_path, e);
+    }
+
     // Log activity
     let filename = Path::new(new_path)
         .file_name()
@@ -440,6 +638,8 @@ pub async fn rename_file(
     )
     .await;
 
+    journal_relocation(state, user, "rename", old_path, new_path).await;
+
     let _ = state.fs_tx.send(FileChangeEvent::new(
         new_path.to_string(),
         "rename".to_string(),
@@ -461,6 +661,19 @@ pub async fn move_file(
 
     fs::rename(old, new).await?;
 
+    // Keep file ids (versions, shares, tags) with the new path
+    if let Err(e) = crate::services::fs_watcher::relocate_rows(
+        &state.db_pool,
+        &state.search_index,
+        Path::new(DATA_DIR),
+        old_path,
+        new_path,
+    )
+    .await
+    {
+        tracing::warn!("Failed to move rows {} -> {}: {}", old_path, new_path, e);
+    }
+
     // Log activity
     let filename = Path::new(new_path)
         .file_name()
@@ -481,6 +694,8 @@ pub async fn move_file(
     )
     .await;
 
+    journal_relocation(state, user, "move", old_path, new_path).await;
+
     let _ = state.fs_tx.send(FileChangeEvent::new(
         new_path.to_string(),
         "move".to_string(),
@@ -502,6 +717,12 @@ pub async fn copy_file(
 
     fs::copy(&src, &dst).await?;
 
+    // Registered right away rather than by the watcher, so the copy shares
+    // the source's blocks when storage is deduplicated
+    if let Err(e) = register_copy(state, user, source_path, dest_path, &dst).await {
+        tracing::warn!("Failed to register copy {}: {}", dest_path, e);
+    }
+
     // Get file size for logging
     let file_size = dst.metadata().ok().map(|m| m.len() as i64);
 
@@ -525,6 +746,16 @@ pub async fn copy_file(
     )
     .await;
 
+    crate::services::change_journal::record(
+        &state.db_pool,
+        Change::new("copy", dest_path)
+            .with_old_path(source_path)
+            .with_directory(dst.is_dir())
+            .with_content(file_size.unwrap_or(0), None)
+            .by_user(&user.id),
+    )
+    .await;
+
     let _ = state.fs_tx.send(FileChangeEvent::new(
         dest_path.to_string(),
         "copy".to_string(),
@@ -532,6 +763,169 @@ pub async fn copy_file(
     Ok(())
 }
 
+/// Journal a rename or move; the file keeps its id
+async fn journal_relocation(
+    state: &AppState,
+    user: &UserInfo,
+    change_type: &str,
+    old_path: &str,
+    new_path: &str,
+) {
+    let file_id: Option<String> = sqlx::query_scalar(
+        "SELECT id FROM files WHERE path IN (?, ?) AND is_deleted = 0 ORDER BY path = ? DESC LIMIT 1",
+    )
+    .bind(new_path)
+    .bind(old_path)
+    .bind(new_path)
+    .fetch_optional(&state.db_pool)
+    .await
+    .ok()
+    .flatten();
+    let is_directory = fs::metadata(Path::new(DATA_DIR).join(new_path))
+        .await
+        .is_ok_and(|m| m.is_dir());
+
+    crate::services::change_journal::record(
+        &state.db_pool,
+        Change::new(change_type, new_path)
+            .with_old_path(old_path)
+            .with_file_id(file_id)
+            .with_directory(is_directory)
+            .by_user(&user.id),
+    )
+    .await;
+}
+
+/// Create the row of a copied file and write it through to storage. The
+/// checksum of the source is reused, so deduplicated content is not read
+/// again. Copies of untracked files or onto existing rows are left to the
+/// watcher.
+async fn register_copy(
+    state: &AppState,
+    user: &UserInfo,
+    source_path: &str,
+    dest_path: &str,
+    dst: &Path,
+) -> Result<()> {
+    let source: Option<(i64, Option<String>, Option<String>)> = sqlx::query_as(
+        "SELECT size_bytes, mime_type, checksum_sha256 FROM files
+         WHERE path = ? AND is_deleted = 0 ORDER BY updated_at DESC LIMIT 1",
+    )
+    .bind(source_path)
+    .fetch_optional(&state.db_pool)
+    .await?;
+    let Some((size_bytes, mime_type, Some(checksum))) = source else {
+        return Ok(());
+    };
+    let existing: Option<String> =
+        sqlx::query_scalar("SELECT id FROM files WHERE path = ? AND is_deleted = 0")
+            .bind(dest_path)
+            .fetch_optional(&state.db_pool)
+            .await?;
+    if existing.is_some() {
+        return Ok(());
+    }
+
+    let file_id = Uuid::new_v4().to_string();
+    let filename = dst
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("copy")
+        .to_string();
+    let now = Utc::now().to_rfc3339();
+    sqlx::query(
+        "INSERT INTO files (id, name, path, owner_id, size_bytes, mime_type, checksum_sha256, storage_path, is_deleted, version, created_at, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, 0, 1, ?, ?)",
+    )
+    .bind(&file_id)
+    .bind(&filename)
+    .bind(dest_path)
+    .bind(&user.id)
+    .bind(size_bytes)
+    .bind(&mime_type)
+    .bind(&checksum)
+    .bind(dest_path)
+    .bind(&now)
+    .bind(&now)
+    .execute(&state.db_pool)
+    .await?;
+
+    crate::storage::replicate_file(
+        &state.db_pool,
+        &file_id,
+        dest_path,
+        dst,
+        size_bytes,
+        &checksum,
+    )
+    .await?;
+    let _ = crate::api::quota::update_storage_usage(&state.db_pool, &user.id).await;
+
+    let content = crate::search::extract_content(dst).await;
+    let acl = crate::search::access::grants(&state.db_pool, dest_path)
+        .await
+        .unwrap_or_default();
+    let _ = state
+        .search_index
+        .index_file(
+            &file_id,
+            &filename,
+            dest_path,
+            content,
+            Utc::now(),
+            size_bytes as u64,
+            &acl,
+        )
+        .await;
+    Ok(())
+}
+
+/// Restore a soft-deleted file, optionally to a different path. Returns
+/// false if there is nothing in the trash at `path`.
+pub async fn restore_file(
+    state: &AppState,
+    user: &UserInfo,
+    path: &str,
+    destination: Option<&str>,
+) -> Result<bool> {
+    let restore_path = match destination {
+        Some(destination) => crate::security::validate_file_path(destination)
+            .map_err(|_| anyhow!("Invalid destination path"))?,
+        None => path.to_string(),
+    };
+    let restore_path = restore_path.as_str();
+    let now = Utc::now().to_rfc3339();
+
+    let restored: Option<(String, i64, Option<String>)> = sqlx::query_as(
+        "UPDATE files SET is_deleted = 0, deleted_at = NULL, path = ?, updated_at = ?
+         WHERE path = ? AND is_deleted = 1
+         RETURNING id, size_bytes, checksum_sha256",
+    )
+    .bind(restore_path)
+    .bind(&now)
+    .bind(path)
+    .fetch_optional(&state.db_pool)
+    .await?;
+    let Some((file_id, size_bytes, checksum)) = restored else {
+        return Ok(false);
+    };
+
+    let mut change = Change::new("restore", restore_path)
+        .with_file_id(Some(file_id))
+        .with_content(size_bytes, checksum.as_deref())
+        .by_user(&user.id);
+    if restore_path != path {
+        change = change.with_old_path(path);
+    }
+    crate::services::change_journal::record(&state.db_pool, change).await;
+
+    let _ = state.fs_tx.send(FileChangeEvent::new(
+        restore_path.to_string(),
+        "restore".to_string(),
+    ));
+    Ok(true)
+}
+
 pub async fn get_recent_files(
     state: &AppState,
     user: &UserInfo,
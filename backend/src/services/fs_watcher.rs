@@ -0,0 +1,891 @@
+//! Filesystem Watcher - keep the database and search index in sync with ./data
+//!
+//! Files written outside the API (SMB, NFS, rsync on the NAS) are picked up
+//! through `notify`. Events are debounced per path and every changed path is
+//! reconciled against what is on disk, so events caused by the API's own
+//! writes turn into no-ops. Applied changes are written to `files`/`folders`,
+//! the search index and broadcast as `FileChangeEvent::FileChange`.
+
+use crate::search::SearchIndex;
+use crate::services::change_journal::Change as JournalChange;
+use crate::websocket::FileChangeEvent;
+use anyhow::Result;
+use chrono::Utc;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+const DATA_DIR: &str = "./data";
+/// A path is processed once it has been quiet for this long
+const DEBOUNCE: Duration = Duration::from_millis(1500);
+
+/// Top-level entries of the data directory that the server manages itself
+const INTERNAL_ENTRIES: &[&str] = &[
+    "syncspace.db",
+    "syncspace.db-shm",
+    "syncspace.db-wal",
+    "syncspace.db-journal",
+    "search_index",
+    "versions",
+    "thumbnails",
+    "temp",
+    "temp_uploads",
+    "backups",
+    "archives",
+    "email_attachments",
+    ".thumbnails",
+    ".previews",
+    ".quarantine",
+    ".blocks",
+    ".health",
+];
+
+/// Whether the watcher should run (`SYNCSPACE_FS_WATCHER=0` disables it)
+pub fn watcher_enabled() -> bool {
+    !matches!(
+        std::env::var("SYNCSPACE_FS_WATCHER")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str(),
+        "0" | "false" | "off"
+    )
+}
+
+/// Whether a relative path lies inside a server-managed directory
+pub(crate) fn is_internal(path: &str) -> bool {
+    let first = path.split('/').next().unwrap_or_default();
+    INTERNAL_ENTRIES.contains(&first)
+}
+
+/// Path relative to the data directory with `/` separators, `None` for paths
+/// outside it or inside server-managed directories. The root itself is `""`.
+fn relative_path(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut segments = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(segment) => segments.push(segment.to_str()?.to_string()),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    let relative = segments.join("/");
+    (!is_internal(&relative)).then_some(relative)
+}
+
+/// LIKE pattern matching everything below `path`
+pub(crate) fn descendants_pattern(path: &str) -> String {
+    let escaped = path
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("{}/%", escaped)
+}
+
+fn parent_path(path: &str) -> &str {
+    path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit_once('/').map(|(_, name)| name).unwrap_or(path)
+}
+
+/// Changed paths waiting for their debounce period to pass
+#[derive(Default)]
+struct PendingChanges {
+    paths: HashMap<String, Instant>,
+    renames: Vec<(String, String, Instant)>,
+}
+
+impl PendingChanges {
+    fn touch(&mut self, path: String, now: Instant) {
+        self.paths.insert(path, now);
+    }
+
+    fn record(&mut self, root: &Path, event: &Event, now: Instant) {
+        if event.need_rescan() {
+            self.touch(String::new(), now);
+            return;
+        }
+        match event.kind {
+            EventKind::Access(_) => {}
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                let from = relative_path(root, &event.paths[0]);
+                let to = relative_path(root, &event.paths[1]);
+                if let (Some(from), Some(to)) = (&from, &to) {
+                    self.renames.push((from.clone(), to.clone(), now));
+                }
+                for path in [from, to].into_iter().flatten() {
+                    self.touch(path, now);
+                }
+            }
+            _ => {
+                for path in &event.paths {
+                    if let Some(path) = relative_path(root, path) {
+                        self.touch(path, now);
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.renames.is_empty()
+    }
+
+    /// When the next path becomes due
+    fn next_deadline(&self) -> Option<Instant> {
+        self.paths
+            .values()
+            .chain(self.renames.iter().map(|(_, _, at)| at))
+            .min()
+            .map(|at| *at + DEBOUNCE)
+    }
+
+    /// Take renames and paths that have been quiet for the debounce period.
+    /// Paths are sorted so parents are handled before their children.
+    fn take_due(&mut self, now: Instant) -> (Vec<(String, String)>, Vec<String>) {
+        let due = |at: &Instant| now.duration_since(*at) >= DEBOUNCE;
+
+        let mut renames = Vec::new();
+        self.renames.retain(|(from, to, at)| {
+            if due(at) {
+                renames.push((from.clone(), to.clone()));
+                false
+            } else {
+                true
+            }
+        });
+
+        let mut paths: Vec<String> = self
+            .paths
+            .iter()
+            .filter(|(_, at)| due(at))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in &paths {
+            self.paths.remove(path);
+        }
+        paths.sort();
+        (renames, paths)
+    }
+}
+
+/// A change applied to the database
+enum Change {
+    Created { path: String, owner_id: String },
+    FolderCreated { path: String },
+    Modified { path: String, owner_id: String },
+    Deleted { path: String, owner_ids: Vec<String> },
+    Renamed { from: String, to: String },
+}
+
+struct WatchContext {
+    root: PathBuf,
+    pool: SqlitePool,
+    search_index: Arc<SearchIndex>,
+    fs_tx: broadcast::Sender<FileChangeEvent>,
+    default_owner_id: String,
+}
+
+/// Start watching the data directory. The initial pass reconciles changes
+/// made while the server was not running.
+pub fn spawn_watcher(
+    pool: SqlitePool,
+    search_index: Arc<SearchIndex>,
+    fs_tx: broadcast::Sender<FileChangeEvent>,
+    default_owner_id: String,
+) -> Result<()> {
+    std::fs::create_dir_all(DATA_DIR)?;
+    let root = std::fs::canonicalize(DATA_DIR)?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    })?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+
+    let ctx = WatchContext {
+        root,
+        pool,
+        search_index,
+        fs_tx,
+        default_owner_id,
+    };
+
+    tokio::spawn(async move {
+        // The watcher stops when dropped
+        let _watcher = watcher;
+        let mut pending = PendingChanges::default();
+        let started = Instant::now();
+        pending.touch(String::new(), started.checked_sub(DEBOUNCE).unwrap_or(started));
+
+        loop {
+            let deadline = pending.next_deadline();
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(Ok(event)) => pending.record(&ctx.root, &event, Instant::now()),
+                    Some(Err(e)) => tracing::warn!("Filesystem watcher error: {}", e),
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {}
+            }
+
+            if pending.is_empty() {
+                continue;
+            }
+            let (renames, paths) = pending.take_due(Instant::now());
+            if renames.is_empty() && paths.is_empty() {
+                continue;
+            }
+            ctx.apply(renames, paths).await;
+        }
+
+        tracing::warn!("Filesystem watcher stopped");
+    });
+
+    Ok(())
+}
+
+/// Reconcile paths changed by a writer that bypasses the file API (WebDAV)
+/// right away instead of waiting for the watcher. New entries without a
+/// known parent folder belong to `owner_id`.
+pub(crate) async fn sync_paths(
+    pool: SqlitePool,
+    search_index: Arc<SearchIndex>,
+    fs_tx: broadcast::Sender<FileChangeEvent>,
+    owner_id: &str,
+    renames: Vec<(String, String)>,
+    paths: Vec<String>,
+) -> Result<()> {
+    let ctx = WatchContext {
+        root: tokio::fs::canonicalize(DATA_DIR).await?,
+        pool,
+        search_index,
+        fs_tx,
+        default_owner_id: owner_id.to_string(),
+    };
+    ctx.apply(renames, paths).await;
+    Ok(())
+}
+
+impl WatchContext {
+    async fn apply(&self, renames: Vec<(String, String)>, paths: Vec<String>) {
+        let mut changes = Vec::new();
+
+        for (from, to) in renames {
+            match self.apply_rename(&from, &to).await {
+                Ok(Some(change)) => changes.push(change),
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to apply rename {} -> {}: {}", from, to, e),
+            }
+        }
+        for path in paths {
+            if let Err(e) = self.reconcile(&path, &mut changes).await {
+                tracing::warn!("Failed to sync {}: {}", display_path(&path), e);
+            }
+        }
+
+        let mut owners = HashSet::new();
+        for change in changes {
+            let (path, kind, metadata, journal) = match change {
+                Change::Created { path, owner_id } => {
+                    owners.insert(owner_id);
+                    let journal = JournalChange::new("create", &path);
+                    (path, "create", serde_json::json!({ "source": "watcher" }), journal)
+                }
+                Change::FolderCreated { path } => {
+                    let journal = JournalChange::new("create", &path).with_directory(true);
+                    (
+                        path,
+                        "create",
+                        serde_json::json!({ "source": "watcher", "is_directory": true }),
+                        journal,
+                    )
+                }
+                Change::Modified { path, owner_id } => {
+                    owners.insert(owner_id);
+                    let journal = JournalChange::new("modify", &path);
+                    (path, "modify", serde_json::json!({ "source": "watcher" }), journal)
+                }
+                Change::Deleted { path, owner_ids } => {
+                    owners.extend(owner_ids);
+                    let journal = JournalChange::new("delete", &path);
+                    (path, "delete", serde_json::json!({ "source": "watcher" }), journal)
+                }
+                Change::Renamed { from, to } => {
+                    let journal = JournalChange::new("rename", &to).with_old_path(&from);
+                    (
+                        to,
+                        "rename",
+                        serde_json::json!({ "source": "watcher", "from": from }),
+                        journal,
+                    )
+                }
+            };
+            crate::services::change_journal::record(&self.pool, journal).await;
+            let _ = self.fs_tx.send(
+                FileChangeEvent::new(path, kind.to_string()).with_metadata(metadata),
+            );
+        }
+
+        for owner_id in owners {
+            let _ = crate::api::quota::update_storage_usage(&self.pool, &owner_id).await;
+        }
+    }
+
+    async fn reconcile(&self, path: &str, changes: &mut Vec<Change>) -> Result<()> {
+        let absolute = self.root.join(path);
+        match tokio::fs::symlink_metadata(&absolute).await {
+            Ok(metadata) if metadata.is_dir() => self.reconcile_dir(path, changes).await,
+            Ok(metadata) if metadata.is_file() => {
+                if let Some(change) = self.reconcile_file(path, metadata.len(), true).await? {
+                    changes.push(change);
+                }
+                Ok(())
+            }
+            // Symlinks and special files are not tracked
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if let Some(change) = self.handle_removed(path).await? {
+                    changes.push(change);
+                }
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sync a directory tree: register what is on disk and drop what is gone
+    async fn reconcile_dir(&self, path: &str, changes: &mut Vec<Change>) -> Result<()> {
+        if !path.is_empty() && self.ensure_folder(path).await? {
+            changes.push(Change::FolderCreated {
+                path: path.to_string(),
+            });
+        }
+
+        let root = self.root.clone();
+        let start = root.join(path);
+        let entries = tokio::task::spawn_blocking(move || {
+            walkdir::WalkDir::new(&start)
+                .min_depth(1)
+                .follow_links(false)
+                .into_iter()
+                .filter_entry(|entry| relative_path(&root, entry.path()).is_some())
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let metadata = entry.metadata().ok()?;
+                    let relative = relative_path(&root, entry.path())?;
+                    Some((relative, metadata.is_dir(), metadata.len()))
+                })
+                .collect::<Vec<_>>()
+        })
+        .await?;
+
+        let mut on_disk = HashSet::new();
+        for (entry_path, is_dir, size) in entries {
+            let result = if is_dir {
+                self.ensure_folder(&entry_path).await.map(|created| {
+                    created.then(|| Change::FolderCreated {
+                        path: entry_path.clone(),
+                    })
+                })
+            } else {
+                self.reconcile_file(&entry_path, size, false).await
+            };
+            match result {
+                Ok(Some(change)) => changes.push(change),
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to sync {}: {}", entry_path, e),
+            }
+            on_disk.insert(entry_path);
+        }
+
+        // Rows below this directory whose files no longer exist
+        let (condition, pattern) = if path.is_empty() {
+            ("1 = 1", String::new())
+        } else {
+            ("path LIKE ? ESCAPE '\\'", descendants_pattern(path))
+        };
+        let mut known: Vec<String> = Vec::new();
+        for table in ["files", "folders"] {
+            let query = format!(
+                "SELECT path FROM {} WHERE is_deleted = 0 AND {}",
+                table, condition
+            );
+            let mut query = sqlx::query_scalar::<_, String>(&query);
+            if !path.is_empty() {
+                query = query.bind(&pattern);
+            }
+            known.extend(query.fetch_all(&self.pool).await?);
+        }
+        known.sort();
+        known.dedup();
+
+        for known_path in known {
+            if on_disk.contains(&known_path) || is_internal(&known_path) {
+                continue;
+            }
+            if let Some(change) = self.handle_removed(&known_path).await? {
+                changes.push(change);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Register a new file or refresh a changed one. Without `verify`, files
+    /// whose size matches the database are assumed unchanged (used for tree
+    /// walks where hashing every file would be too expensive).
+    async fn reconcile_file(&self, path: &str, size: u64, verify: bool) -> Result<Option<Change>> {
+        let row: Option<(String, i64, Option<String>, String, bool)> = sqlx::query_as(
+            "SELECT id, size_bytes, checksum_sha256, owner_id, is_deleted FROM files
+             WHERE path = ? ORDER BY is_deleted ASC, updated_at DESC LIMIT 1",
+        )
+        .bind(path)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        // Unchanged, or a soft-deleted file still waiting in the trash
+        if let Some((_, size_bytes, ..)) = &row
+            && !verify
+            && *size_bytes == size as i64
+        {
+            return Ok(None);
+        }
+
+        let absolute = self.root.join(path);
+        let (size, checksum) = crate::storage::sha256_file(&absolute).await?;
+        let size = size as i64;
+        let now = Utc::now().to_rfc3339();
+        let mime_type = mime_guess::from_path(path).first().map(|m| m.to_string());
+
+        let change = match row {
+            Some((_, size_bytes, Some(existing), _, _))
+                if size_bytes == size && existing == checksum =>
+            {
+                return Ok(None);
+            }
+            Some((file_id, _, _, owner_id, false)) => {
+                sqlx::query(
+                    "UPDATE files SET size_bytes = ?, checksum_sha256 = ?, mime_type = COALESCE(?, mime_type),
+                     version = version + 1, updated_at = ? WHERE id = ?",
+                )
+                .bind(size)
+                .bind(&checksum)
+                .bind(&mime_type)
+                .bind(&now)
+                .bind(&file_id)
+                .execute(&self.pool)
+                .await?;
+
+                if let Err(e) = crate::storage::delete_file_objects(&self.pool, &file_id).await {
+                    tracing::warn!("Failed to drop stale stored objects of {}: {}", path, e);
+                }
+                // Encrypted content is only ever replaced through the encryption
+                // module, which updates the row first; anything else wrote plaintext
+                crate::encryption::forget_replaced(&self.pool, &file_id).await?;
+                self.after_write(&file_id, path, &absolute, size, &checksum)
+                    .await;
+                Change::Modified {
+                    path: path.to_string(),
+                    owner_id,
+                }
+            }
+            _ => {
+                let file_id = Uuid::new_v4().to_string();
+                let owner_id = self.owner_for(path).await?;
+                sqlx::query(
+                    "INSERT INTO files (id, name, path, owner_id, size_bytes, mime_type, checksum_sha256, storage_path, is_deleted, version, created_at, updated_at)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, 0, 1, ?, ?)",
+                )
+                .bind(&file_id)
+                .bind(file_name(path))
+                .bind(path)
+                .bind(&owner_id)
+                .bind(size)
+                .bind(&mime_type)
+                .bind(&checksum)
+                .bind(path)
+                .bind(&now)
+                .bind(&now)
+                .execute(&self.pool)
+                .await?;
+
+                self.after_write(&file_id, path, &absolute, size, &checksum)
+                    .await;
+                Change::Created {
+                    path: path.to_string(),
+                    owner_id,
+                }
+            }
+        };
+
+        Ok(Some(change))
+    }
+
+    /// Replicate to the storage backend and refresh the search index
+    async fn after_write(
+        &self,
+        file_id: &str,
+        path: &str,
+        absolute: &Path,
+        size: i64,
+        checksum: &str,
+    ) {
+        if let Err(e) =
+            crate::storage::replicate_file(&self.pool, file_id, path, absolute, size, checksum)
+                .await
+        {
+            tracing::warn!("Failed to record storage location for {}: {}", path, e);
+        }
+
+        let content = crate::search::extract_content(absolute).await;
+        let acl = crate::search::access::grants(&self.pool, path)
+            .await
+            .unwrap_or_default();
+        if let Err(e) = self
+            .search_index
+            .index_file(
+                file_id,
+                file_name(path),
+                path,
+                content,
+                Utc::now(),
+                size as u64,
+                &acl,
+            )
+            .await
+        {
+            tracing::warn!("Failed to index {}: {}", path, e);
+        }
+    }
+
+    /// Mark a vanished file or directory (and everything below it) as deleted
+    async fn handle_removed(&self, path: &str) -> Result<Option<Change>> {
+        if path.is_empty() || tokio::fs::try_exists(self.root.join(path)).await? {
+            return Ok(None);
+        }
+
+        // Files whose content is held by a remote backend only had their
+        // cached working copy removed; they are re-fetched on demand
+        let removed: Vec<(String, String)> = sqlx::query_as(
+            r#"
+            SELECT id, owner_id FROM files
+            WHERE is_deleted = 0 AND (path = ? OR path LIKE ? ESCAPE '\')
+            AND NOT EXISTS (
+                SELECT 1 FROM file_storage_locations l
+                WHERE l.file_id = files.id AND l.backend_id != ? AND l.upload_status = 'completed'
+            )
+            "#,
+        )
+        .bind(path)
+        .bind(descendants_pattern(path))
+        .bind(crate::storage::LOCAL_DEFAULT_BACKEND_ID)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let now = Utc::now().to_rfc3339();
+        for (file_id, _) in &removed {
+            sqlx::query(
+                "UPDATE files SET is_deleted = 1, deleted_at = ?, updated_at = ? WHERE id = ?",
+            )
+            .bind(&now)
+            .bind(&now)
+            .bind(file_id)
+            .execute(&self.pool)
+            .await?;
+            let _ = self.search_index.delete_from_index(file_id).await;
+        }
+
+        let folders = sqlx::query(
+            r#"
+            UPDATE folders SET is_deleted = 1, deleted_at = ?, updated_at = ?
+            WHERE is_deleted = 0 AND (path = ? OR path LIKE ? ESCAPE '\')
+            "#,
+        )
+        .bind(&now)
+        .bind(&now)
+        .bind(path)
+        .bind(descendants_pattern(path))
+        .execute(&self.pool)
+        .await?;
+
+        if removed.is_empty() && folders.rows_affected() == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Change::Deleted {
+            path: path.to_string(),
+            owner_ids: removed.into_iter().map(|(_, owner_id)| owner_id).collect(),
+        }))
+    }
+
+    /// Move database rows along with a rename, keeping file ids (and with them
+    /// versions, shares and tags). Nothing happens if the rows already moved.
+    async fn apply_rename(&self, from: &str, to: &str) -> Result<Option<Change>> {
+        if !relocate_rows(&self.pool, &self.search_index, &self.root, from, to).await? {
+            return Ok(None);
+        }
+        Ok(Some(Change::Renamed {
+            from: from.to_string(),
+            to: to.to_string(),
+        }))
+    }
+
+    /// Create the folder row if needed; returns true if it was created
+    async fn ensure_folder(&self, path: &str) -> Result<bool> {
+        let exists: bool =
+            sqlx::query_scalar("SELECT COUNT(*) > 0 FROM folders WHERE path = ? AND is_deleted = 0")
+                .bind(path)
+                .fetch_one(&self.pool)
+                .await?;
+        if exists {
+            return Ok(false);
+        }
+
+        let parent_id: Option<String> =
+            sqlx::query_scalar("SELECT id FROM folders WHERE path = ? AND is_deleted = 0")
+                .bind(parent_path(path))
+                .fetch_optional(&self.pool)
+                .await?;
+        let owner_id = self.owner_for(path).await?;
+        let now = Utc::now().to_rfc3339();
+
+        // A soft-deleted row with the same path is revived (folders.path is unique)
+        sqlx::query(
+            "INSERT INTO folders (id, name, path, parent_id, owner_id, is_deleted, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, 0, ?, ?)
+             ON CONFLICT(path) DO UPDATE SET is_deleted = 0, deleted_at = NULL, deleted_by = NULL,
+                parent_id = excluded.parent_id, updated_at = excluded.updated_at",
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(file_name(path))
+        .bind(path)
+        .bind(&parent_id)
+        .bind(&owner_id)
+        .bind(&now)
+        .bind(&now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(true)
+    }
+
+    /// New entries belong to the owner of the closest known parent folder
+    async fn owner_for(&self, path: &str) -> Result<String> {
+        let mut parent = parent_path(path);
+        while !parent.is_empty() {
+            let owner: Option<String> = sqlx::query_scalar(
+                "SELECT owner_id FROM folders WHERE path = ? AND is_deleted = 0",
+            )
+            .bind(parent)
+            .fetch_optional(&self.pool)
+            .await?;
+            if let Some(owner) = owner {
+                return Ok(owner);
+            }
+            parent = parent_path(parent);
+        }
+        Ok(self.default_owner_id.clone())
+    }
+}
+
+/// Move `files`/`folders` rows from `from` to `to` (and everything below),
+/// keeping file ids and with them versions, shares and tags, and reindex the
+/// moved files. Returns false if there was nothing to move.
+pub(crate) async fn relocate_rows(
+    pool: &SqlitePool,
+    search_index: &SearchIndex,
+    root: &Path,
+    from: &str,
+    to: &str,
+) -> Result<bool> {
+    if from == to || from.is_empty() || to.is_empty() {
+        return Ok(false);
+    }
+
+    let from_pattern = descendants_pattern(from);
+    let to_pattern = descendants_pattern(to);
+    let known: i64 = sqlx::query_scalar(
+        r#"
+        SELECT (SELECT COUNT(*) FROM files WHERE is_deleted = 0 AND (path = ?1 OR path LIKE ?2 ESCAPE '\'))
+             + (SELECT COUNT(*) FROM folders WHERE is_deleted = 0 AND (path = ?1 OR path LIKE ?2 ESCAPE '\'))
+        "#,
+    )
+    .bind(from)
+    .bind(&from_pattern)
+    .fetch_one(pool)
+    .await?;
+    if known == 0 {
+        return Ok(false);
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let parent_id: Option<String> =
+        sqlx::query_scalar("SELECT id FROM folders WHERE path = ? AND is_deleted = 0")
+            .bind(parent_path(to))
+            .fetch_optional(pool)
+            .await?;
+
+    // Rows at the destination were overwritten by the rename
+    let replaced: Vec<String> = sqlx::query_scalar(
+        r#"SELECT id FROM files WHERE is_deleted = 0 AND (path = ? OR path LIKE ? ESCAPE '\')"#,
+    )
+    .bind(to)
+    .bind(&to_pattern)
+    .fetch_all(pool)
+    .await?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"UPDATE files SET is_deleted = 1, deleted_at = ?, updated_at = ? WHERE is_deleted = 0 AND (path = ? OR path LIKE ? ESCAPE '\')"#,
+    )
+    .bind(&now)
+    .bind(&now)
+    .bind(to)
+    .bind(&to_pattern)
+    .execute(&mut *tx)
+    .await?;
+    // folders.path is unique, including soft-deleted rows
+    sqlx::query(r#"DELETE FROM folders WHERE path = ? OR path LIKE ? ESCAPE '\'"#)
+        .bind(to)
+        .bind(&to_pattern)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r#"
+        UPDATE files SET
+            path = ?1 || substr(path, length(?2) + 1),
+            storage_path = CASE WHEN storage_path = path THEN ?1 || substr(path, length(?2) + 1) ELSE storage_path END,
+            updated_at = ?3
+        WHERE is_deleted = 0 AND (path = ?2 OR path LIKE ?4 ESCAPE '\')
+        "#,
+    )
+    .bind(to)
+    .bind(from)
+    .bind(&now)
+    .bind(&from_pattern)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE files SET name = ? WHERE is_deleted = 0 AND path = ?")
+        .bind(file_name(to))
+        .bind(to)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r#"
+        UPDATE folders SET path = ?1 || substr(path, length(?2) + 1), updated_at = ?3
+        WHERE is_deleted = 0 AND (path = ?2 OR path LIKE ?4 ESCAPE '\')
+        "#,
+    )
+    .bind(to)
+    .bind(from)
+    .bind(&now)
+    .bind(&from_pattern)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE folders SET name = ?, parent_id = ? WHERE path = ?")
+        .bind(file_name(to))
+        .bind(&parent_id)
+        .bind(to)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    for file_id in &replaced {
+        let _ = search_index.delete_from_index(file_id).await;
+    }
+
+    // Paths are part of the indexed document
+    let moved: Vec<(String, String, String, i64)> = sqlx::query_as(
+        r#"SELECT id, name, path, size_bytes FROM files WHERE is_deleted = 0 AND (path = ? OR path LIKE ? ESCAPE '\')"#,
+    )
+    .bind(to)
+    .bind(&to_pattern)
+    .fetch_all(pool)
+    .await?;
+    for (file_id, name, path, size_bytes) in moved {
+        let content = crate::search::extract_content(&root.join(&path)).await;
+        let acl = crate::search::access::grants(pool, &path)
+            .await
+            .unwrap_or_default();
+        let _ = search_index
+            .index_file(
+                &file_id,
+                &name,
+                &path,
+                content,
+                Utc::now(),
+                size_bytes as u64,
+                &acl,
+            )
+            .await;
+    }
+
+    Ok(true)
+}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() { "/" } else { path }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::CreateKind;
+
+    #[test]
+    fn test_relative_path_skips_internal_entries() {
+        let root = Path::new("/srv/data");
+        assert_eq!(
+            relative_path(root, Path::new("/srv/data/docs/a.txt")).as_deref(),
+            Some("docs/a.txt")
+        );
+        assert_eq!(relative_path(root, Path::new("/srv/data")).as_deref(), Some(""));
+        assert_eq!(relative_path(root, Path::new("/srv/data/versions/x/1.dat")), None);
+        assert_eq!(relative_path(root, Path::new("/srv/data/syncspace.db-wal")), None);
+        assert_eq!(relative_path(root, Path::new("/etc/passwd")), None);
+    }
+
+    #[test]
+    fn test_descendants_pattern_escapes_wildcards() {
+        assert_eq!(descendants_pattern("a_b%c"), "a\\_b\\%c/%");
+    }
+
+    #[test]
+    fn test_pending_changes_debounce() {
+        let root = Path::new("/srv/data");
+        let start = Instant::now();
+        let mut pending = PendingChanges::default();
+
+        let create = Event::new(EventKind::Create(CreateKind::File))
+            .add_path(PathBuf::from("/srv/data/report.pdf"));
+        pending.record(root, &create, start);
+        let rename = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from("/srv/data/old"))
+            .add_path(PathBuf::from("/srv/data/new"));
+        pending.record(root, &rename, start + Duration::from_millis(500));
+
+        // Nothing is due while events are still arriving
+        let (renames, paths) = pending.take_due(start + Duration::from_millis(600));
+        assert!(renames.is_empty() && paths.is_empty());
+
+        let (renames, paths) = pending.take_due(start + DEBOUNCE);
+        assert!(renames.is_empty());
+        assert_eq!(paths, vec!["report.pdf".to_string()]);
+
+        let (renames, paths) = pending.take_due(start + DEBOUNCE * 2);
+        assert_eq!(renames, vec![("old".to_string(), "new".to_string())]);
+        assert_eq!(paths, vec!["new".to_string(), "old".to_string()]);
+        assert!(pending.is_empty());
+    }
+}
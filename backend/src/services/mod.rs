@@ -8,23 +8,27 @@
 mod all_services_impl;
 pub mod auth_security_service;
 pub mod auth_service;
+pub mod change_journal;
 pub mod cleanup_service;
 mod file_service_impl;
+pub mod fs_watcher;
 pub mod job_worker;
+pub mod peer_sync;
 pub mod performance_service;
 mod search_service_impl;
 pub mod smart_folders_service;
 pub mod sync_service;
 mod user_service_impl;
 pub mod version_storage_service;
+pub mod workflow_runner;
 
 // Re-export auth service functions
 pub use auth_service::*;
 
 // Re-export file service functions
 pub use file_service_impl::{
-    copy_file, delete_file, download_file, get_recent_files, list_files, move_file, rename_file,
-    upload_file,
+    copy_file, delete_file, download_file, get_recent_files, import_uploaded_file, list_files,
+    move_file, open_stored_file, rename_file, restore_file, upload_file,
 };
 
 // Re-export user service functions
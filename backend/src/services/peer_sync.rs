@@ -0,0 +1,1936 @@
+//! Peer-to-Peer Folder Sync - converge shared folders between SyncSpace instances
+//!
+//! Two instances pair once with a one-time code and from then on sign every
+//! request with the shared secret (HMAC-SHA256 over method, path, timestamp
+//! and body hash). Every instance keeps a version vector per path in
+//! `sync_file_versions`; its `seq` column doubles as the change log that
+//! peers pull from with a per-folder cursor. There is no central server:
+//! each instance pulls from its own peers, so changes travel along any chain
+//! of pairings.
+//!
+//! Changed files are transferred block by block using the block store's
+//! content-defined chunking, and blocks already present in the local copy are
+//! reused. Concurrent changes are resolved the same way on both sides: the
+//! newer edit keeps the path, the other one is kept as a deterministically
+//! named conflict copy and recorded in `file_conflicts`, and an edit always
+//! wins over a deletion.
+
+use crate::block_store::{ChunkRef, chunk_file};
+use crate::search::SearchIndex;
+use crate::secrets;
+use crate::services::change_journal::Change as JournalChange;
+use crate::services::fs_watcher::{descendants_pattern, is_internal};
+use crate::storage::sha256_file;
+use crate::websocket::FileChangeEvent;
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const DATA_DIR: &str = "./data";
+const SYNC_TEMP_DIR: &str = "./data/temp_uploads/peer_sync";
+const DEFAULT_SYNC_INTERVAL_SECS: u64 = 60;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+const PAIRING_CODE_TTL_MINUTES: i64 = 15;
+
+pub const PEER_ID_HEADER: &str = "x-peer-id";
+pub const PEER_TIMESTAMP_HEADER: &str = "x-peer-timestamp";
+pub const PEER_SIGNATURE_HEADER: &str = "x-peer-signature";
+/// Signed requests older or newer than this are rejected
+pub const MAX_CLOCK_SKEW_SECS: i64 = 300;
+pub const CHANGES_PAGE_SIZE: i64 = 500;
+/// Upper bounds of a single block request
+pub const MAX_BLOCKS_PER_REQUEST: usize = 16;
+pub const MAX_BLOCK_REQUEST_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Serializes reads and writes of `sync_file_versions` against `files`, so a
+/// scan never records a half-applied remote change as a local edit
+fn state_lock() -> &'static tokio::sync::Mutex<()> {
+    static LOCK: OnceLock<tokio::sync::Mutex<()>> = OnceLock::new();
+    LOCK.get_or_init(|| tokio::sync::Mutex::new(()))
+}
+
+fn running_peers() -> &'static Mutex<HashSet<String>> {
+    static RUNNING: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    RUNNING.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Marks a peer as being synced until dropped
+struct PeerGuard(String);
+
+impl PeerGuard {
+    fn acquire(peer_id: &str) -> Option<Self> {
+        let mut running = running_peers().lock().unwrap_or_else(|e| e.into_inner());
+        running
+            .insert(peer_id.to_string())
+            .then(|| Self(peer_id.to_string()))
+    }
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        running_peers()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.0);
+    }
+}
+
+// ==================== VERSION VECTORS ====================
+
+/// Per-instance change counters of one path
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+/// How one version vector relates to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// Contains every change of the other one and more
+    Newer,
+    Older,
+    Concurrent,
+}
+
+impl VersionVector {
+    pub fn parse(json: &str) -> Self {
+        serde_json::from_str(json).unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.0).unwrap_or_else(|_| "{}".to_string())
+    }
+
+    pub fn get(&self, instance_id: &str) -> u64 {
+        self.0.get(instance_id).copied().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, instance_id: &str) {
+        *self.0.entry(instance_id.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (instance_id, counter) in &other.0 {
+            let entry = self.0.entry(instance_id.clone()).or_insert(0);
+            *entry = (*entry).max(*counter);
+        }
+    }
+
+    /// Sum of all counters, shown as the version number of a conflict side
+    pub fn total(&self) -> u64 {
+        self.0.values().sum()
+    }
+
+    pub fn compare(&self, other: &Self) -> Causality {
+        let mut newer = false;
+        let mut older = false;
+        for instance_id in self.0.keys().chain(other.0.keys()) {
+            let (mine, theirs) = (self.get(instance_id), other.get(instance_id));
+            newer |= mine > theirs;
+            older |= mine < theirs;
+        }
+        match (newer, older) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::Newer,
+            (false, true) => Causality::Older,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+}
+
+// ==================== WIRE TYPES ====================
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceIdentity {
+    pub instance_id: String,
+    pub name: String,
+}
+
+/// Latest state of one path in an instance's change log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeEntry {
+    pub path: String,
+    pub version_vector: VersionVector,
+    pub checksum_sha256: Option<String>,
+    pub size_bytes: i64,
+    pub is_deleted: bool,
+    pub modified_at: String,
+    pub seq: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangesPage {
+    pub changes: Vec<ChangeEntry>,
+    pub next_cursor: i64,
+    pub has_more: bool,
+}
+
+/// Block list of the current content of a file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileManifest {
+    pub path: String,
+    pub checksum_sha256: String,
+    pub size_bytes: u64,
+    pub blocks: Vec<ChunkRef>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockRequest {
+    pub path: String,
+    pub blocks: Vec<ChunkRef>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairRequest {
+    pub code: String,
+    pub instance_id: String,
+    pub name: String,
+    /// Address under which the requesting instance is reachable
+    pub address: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairResponse {
+    pub instance_id: String,
+    pub name: String,
+    pub shared_secret: String,
+}
+
+/// Outcome of one sync run with a peer
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncReport {
+    pub folders: usize,
+    pub applied: usize,
+    pub deleted: usize,
+    pub conflicts: usize,
+    pub bytes_transferred: u64,
+    pub bytes_reused: u64,
+}
+
+/// A paired (or manually added) peer
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PeerRecord {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub address: String,
+    pub instance_id: Option<String>,
+    pub shared_secret: Option<String>,
+}
+
+// ==================== IDENTITY, PAIRING & SIGNING ====================
+
+/// This instance's identity, created on first use
+pub async fn local_identity(pool: &SqlitePool) -> Result<InstanceIdentity> {
+    let existing: Option<(String, String)> =
+        sqlx::query_as("SELECT instance_id, name FROM sync_instance LIMIT 1")
+            .fetch_optional(pool)
+            .await?;
+    if let Some((instance_id, name)) = existing {
+        return Ok(InstanceIdentity { instance_id, name });
+    }
+
+    let name = ["SYNCSPACE_INSTANCE_NAME", "HOSTNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|v| !v.trim().is_empty()))
+        .unwrap_or_else(|| "SyncSpace".to_string());
+    sqlx::query(
+        "INSERT INTO sync_instance (instance_id, name, created_at)
+         SELECT ?, ?, ? WHERE NOT EXISTS (SELECT 1 FROM sync_instance)",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(name.trim())
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+
+    let (instance_id, name): (String, String) =
+        sqlx::query_as("SELECT instance_id, name FROM sync_instance LIMIT 1")
+            .fetch_one(pool)
+            .await?;
+    Ok(InstanceIdentity { instance_id, name })
+}
+
+fn hash_code(code: &str) -> String {
+    format!(
+        "{:x}",
+        Sha256::digest(code.trim().to_uppercase().as_bytes())
+    )
+}
+
+/// Create a one-time pairing code; only its hash is stored
+pub async fn create_pairing_code(
+    pool: &SqlitePool,
+    user_id: &str,
+) -> Result<(String, DateTime<Utc>)> {
+    let code = hex::encode_upper(rand::random::<[u8; 10]>());
+    let code = format!("{}-{}-{}", &code[..6], &code[6..12], &code[12..]);
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::minutes(PAIRING_CODE_TTL_MINUTES);
+
+    sqlx::query(
+        "INSERT INTO peer_pairing_codes (id, code_hash, created_by, expires_at, created_at) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(hash_code(&code))
+    .bind(user_id)
+    .bind(expires_at.to_rfc3339())
+    .bind(now.to_rfc3339())
+    .execute(pool)
+    .await?;
+
+    Ok((code, expires_at))
+}
+
+/// Accept a pairing request from another instance. Returns `None` if the
+/// code is unknown, expired or already used.
+pub async fn accept_pairing(pool: &SqlitePool, req: &PairRequest) -> Result<Option<PairResponse>> {
+    let identity = local_identity(pool).await?;
+    if req.instance_id == identity.instance_id {
+        bail!("An instance cannot pair with itself");
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let code_hash = hash_code(&req.code);
+    let created_by: Option<String> = sqlx::query_scalar(
+        "UPDATE peer_pairing_codes SET used_at = ?
+         WHERE code_hash = ? AND used_at IS NULL AND expires_at > ?
+         RETURNING created_by",
+    )
+    .bind(&now)
+    .bind(&code_hash)
+    .bind(&now)
+    .fetch_optional(pool)
+    .await?;
+    let Some(created_by) = created_by else {
+        return Ok(None);
+    };
+
+    let shared_secret = hex::encode(rand::random::<[u8; 32]>());
+    upsert_paired_peer(
+        pool,
+        &created_by,
+        &req.name,
+        &req.address,
+        &req.instance_id,
+        &shared_secret,
+    )
+    .await?;
+
+    Ok(Some(PairResponse {
+        instance_id: identity.instance_id,
+        name: identity.name,
+        shared_secret,
+    }))
+}
+
+/// Pair with the instance at `address` using a code generated there
+pub async fn pair_with(
+    pool: &SqlitePool,
+    user_id: &str,
+    address: &str,
+    code: &str,
+    public_address: &str,
+) -> Result<PeerRecord> {
+    let identity = local_identity(pool).await?;
+    let address = address.trim().trim_end_matches('/').to_string();
+    let url = reqwest::Url::parse(&format!("{}/api/p2p/pair", address))?;
+
+    let response = http_client()?
+        .post(url)
+        .json(&PairRequest {
+            code: code.to_string(),
+            instance_id: identity.instance_id.clone(),
+            name: identity.name.clone(),
+            address: public_address.trim().trim_end_matches('/').to_string(),
+        })
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!("Peer rejected pairing ({})", response.status());
+    }
+    let paired: PairResponse = response.json().await?;
+    if paired.instance_id == identity.instance_id {
+        bail!("An instance cannot pair with itself");
+    }
+
+    let peer_id = upsert_paired_peer(
+        pool,
+        user_id,
+        &paired.name,
+        &address,
+        &paired.instance_id,
+        &paired.shared_secret,
+    )
+    .await?;
+    load_peer(pool, &peer_id)
+        .await?
+        .ok_or_else(|| anyhow!("Paired peer disappeared"))
+}
+
+/// Store pairing details, reusing the row of an instance paired before
+async fn upsert_paired_peer(
+    pool: &SqlitePool,
+    user_id: &str,
+    name: &str,
+    address: &str,
+    instance_id: &str,
+    shared_secret: &str,
+) -> Result<String> {
+    let now = Utc::now().to_rfc3339();
+    let existing: Option<String> = sqlx::query_scalar(
+        "SELECT id FROM peers WHERE instance_id = ? OR address = ? ORDER BY instance_id = ? DESC LIMIT 1",
+    )
+    .bind(instance_id)
+    .bind(address)
+    .bind(instance_id)
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(peer_id) = existing {
+        let sealed = secrets::seal(&secrets::PEER_SHARED_SECRET, &peer_id, shared_secret)?;
+        sqlx::query(
+            "UPDATE peers SET name = ?, address = ?, instance_id = ?, shared_secret = ?, paired_at = ?,
+             status = 'online', last_seen = ?, last_error = NULL, updated_at = ? WHERE id = ?",
+        )
+        .bind(name)
+        .bind(address)
+        .bind(instance_id)
+        .bind(&sealed)
+        .bind(&now)
+        .bind(&now)
+        .bind(&now)
+        .bind(&peer_id)
+        .execute(pool)
+        .await?;
+        return Ok(peer_id);
+    }
+
+    let peer_id = Uuid::new_v4().to_string();
+    let sealed = secrets::seal(&secrets::PEER_SHARED_SECRET, &peer_id, shared_secret)?;
+    sqlx::query(
+        "INSERT INTO peers (id, user_id, name, address, status, last_seen, sync_enabled, instance_id, shared_secret, paired_at, created_at, updated_at)
+         VALUES (?, ?, ?, ?, 'online', ?, 1, ?, ?, ?, ?, ?)",
+    )
+    .bind(&peer_id)
+    .bind(user_id)
+    .bind(name)
+    .bind(address)
+    .bind(&now)
+    .bind(instance_id)
+    .bind(&sealed)
+    .bind(&now)
+    .bind(&now)
+    .bind(&now)
+    .execute(pool)
+    .await?;
+    Ok(peer_id)
+}
+
+pub async fn load_peer(pool: &SqlitePool, peer_id: &str) -> Result<Option<PeerRecord>> {
+    Ok(sqlx::query_as(
+        "SELECT id, user_id, name, address, instance_id, shared_secret FROM peers WHERE id = ?",
+    )
+    .bind(peer_id)
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Plaintext shared secret of a paired peer, `None` if it is not paired
+async fn reveal_secret(
+    pool: &SqlitePool,
+    peer: &PeerRecord,
+    purpose: &str,
+) -> Result<Option<String>> {
+    let Some(stored) = &peer.shared_secret else {
+        return Ok(None);
+    };
+    let secret =
+        secrets::reveal(pool, &secrets::PEER_SHARED_SECRET, &peer.id, stored, purpose).await?;
+    Ok(Some(secret))
+}
+
+fn canonical_request(method: &str, path_and_query: &str, timestamp: i64, body: &[u8]) -> String {
+    format!(
+        "{}\n{}\n{}\n{:x}",
+        method.to_uppercase(),
+        path_and_query,
+        timestamp,
+        Sha256::digest(body)
+    )
+}
+
+/// Hex HMAC-SHA256 signature of a peer request
+pub fn sign_request(
+    secret: &str,
+    method: &str,
+    path_and_query: &str,
+    timestamp: i64,
+    body: &[u8],
+) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(canonical_request(method, path_and_query, timestamp, body).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub fn verify_signature(
+    secret: &str,
+    method: &str,
+    path_and_query: &str,
+    timestamp: i64,
+    body: &[u8],
+    signature: &str,
+) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(canonical_request(method, path_and_query, timestamp, body).as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Look up the paired peer that signed a request. `path_and_query` must be
+/// the path as sent by the peer, starting at `/api`.
+pub async fn authenticate_peer(
+    pool: &SqlitePool,
+    method: &str,
+    path_and_query: &str,
+    instance_id: &str,
+    timestamp: &str,
+    signature: &str,
+    body: &[u8],
+) -> Result<Option<PeerRecord>> {
+    let Ok(timestamp) = timestamp.parse::<i64>() else {
+        return Ok(None);
+    };
+    if (Utc::now().timestamp() - timestamp).abs() > MAX_CLOCK_SKEW_SECS {
+        return Ok(None);
+    }
+
+    let peer: Option<PeerRecord> = sqlx::query_as(
+        "SELECT id, user_id, name, address, instance_id, shared_secret FROM peers
+         WHERE instance_id = ? AND shared_secret IS NOT NULL AND sync_enabled = 1",
+    )
+    .bind(instance_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some(peer) = peer else {
+        return Ok(None);
+    };
+    let secret = reveal_secret(pool, &peer, "peer request verification")
+        .await?
+        .unwrap_or_default();
+    if !verify_signature(&secret, method, path_and_query, timestamp, body, signature) {
+        return Ok(None);
+    }
+
+    sqlx::query("UPDATE peers SET last_seen = ? WHERE id = ?")
+        .bind(Utc::now().to_rfc3339())
+        .bind(&peer.id)
+        .execute(pool)
+        .await?;
+    Ok(Some(peer))
+}
+
+// ==================== PATHS ====================
+
+/// Whether `path` lies in `folder` (`""` is the whole data directory)
+pub fn in_folder(path: &str, folder: &str) -> bool {
+    folder.is_empty()
+        || path == folder
+        || path
+            .strip_prefix(folder)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Normalized folder path for `peer_sync_folders`, `None` if unusable
+pub fn normalize_folder(folder: &str) -> Option<String> {
+    let folder = crate::security::validate_file_path(folder.trim()).ok()?;
+    let folder = folder.trim_end_matches('/').to_string();
+    (!is_internal(&folder)).then_some(folder)
+}
+
+/// Whether `path` is inside one of the folders shared with `peer_id`
+pub async fn is_shared_with(pool: &SqlitePool, peer_id: &str, path: &str) -> Result<bool> {
+    let folders: Vec<String> =
+        sqlx::query_scalar("SELECT folder_path FROM peer_sync_folders WHERE peer_id = ?")
+            .bind(peer_id)
+            .fetch_all(pool)
+            .await?;
+    Ok(folders.iter().any(|folder| in_folder(path, folder)))
+}
+
+/// `docs/report.pdf` -> `docs/report.sync-conflict-<instance>-<checksum>.pdf`.
+/// Both sides of a conflict derive the same name for the losing version.
+pub fn conflict_copy_path(path: &str, instance_id: &str, checksum: &str) -> String {
+    let (parent, name) = match path.rsplit_once('/') {
+        Some((parent, name)) => (Some(parent), name),
+        None => (None, path),
+    };
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    let short = |value: &str| value.replace('-', "").chars().take(8).collect::<String>();
+
+    let mut copy = format!(
+        "{}.sync-conflict-{}-{}",
+        stem,
+        short(instance_id),
+        short(checksum)
+    );
+    if let Some(extension) = extension {
+        copy = format!("{}.{}", copy, extension);
+    }
+    match parent {
+        Some(parent) => format!("{}/{}", parent, copy),
+        None => copy,
+    }
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+                .ok()
+                .map(|t| t.and_utc())
+        })
+}
+
+/// Whether the remote side of a concurrent edit keeps the path: the later
+/// modification wins, ties are broken by checksum so both peers agree
+fn remote_wins(local: &LocalVersion, remote: &ChangeEntry) -> bool {
+    let local_key = (
+        parse_time(&local.modified_at),
+        local.checksum_sha256.as_deref(),
+    );
+    let remote_key = (
+        parse_time(&remote.modified_at),
+        remote.checksum_sha256.as_deref(),
+    );
+    remote_key > local_key
+}
+
+// ==================== CHANGE LOG ====================
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+struct VersionRow {
+    path: String,
+    version_vector: String,
+    checksum_sha256: Option<String>,
+    size_bytes: i64,
+    is_deleted: bool,
+    modified_at: String,
+    seq: i64,
+}
+
+impl From<VersionRow> for ChangeEntry {
+    fn from(row: VersionRow) -> Self {
+        Self {
+            path: row.path,
+            version_vector: VersionVector::parse(&row.version_vector),
+            checksum_sha256: row.checksum_sha256,
+            size_bytes: row.size_bytes,
+            is_deleted: row.is_deleted,
+            modified_at: row.modified_at,
+            seq: row.seq,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LocalVersion {
+    version_vector: VersionVector,
+    checksum_sha256: Option<String>,
+    size_bytes: i64,
+    is_deleted: bool,
+    modified_at: String,
+}
+
+async fn local_version(pool: &SqlitePool, path: &str) -> Result<Option<LocalVersion>> {
+    let row: Option<VersionRow> = sqlx::query_as(
+        "SELECT path, version_vector, checksum_sha256, size_bytes, is_deleted, modified_at, seq
+         FROM sync_file_versions WHERE path = ?",
+    )
+    .bind(path)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| LocalVersion {
+        version_vector: VersionVector::parse(&row.version_vector),
+        checksum_sha256: row.checksum_sha256,
+        size_bytes: row.size_bytes,
+        is_deleted: row.is_deleted,
+        modified_at: row.modified_at,
+    }))
+}
+
+/// Store the state of a path under the next change sequence number
+async fn record_version(
+    pool: &SqlitePool,
+    path: &str,
+    version_vector: &VersionVector,
+    checksum: Option<&str>,
+    size_bytes: i64,
+    is_deleted: bool,
+    modified_at: &str,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO sync_file_versions (path, version_vector, checksum_sha256, size_bytes, is_deleted, modified_at, seq)
+        VALUES (?, ?, ?, ?, ?, ?, (SELECT COALESCE(MAX(seq), 0) + 1 FROM sync_file_versions))
+        ON CONFLICT(path) DO UPDATE SET
+            version_vector = excluded.version_vector,
+            checksum_sha256 = excluded.checksum_sha256,
+            size_bytes = excluded.size_bytes,
+            is_deleted = excluded.is_deleted,
+            modified_at = excluded.modified_at,
+            seq = excluded.seq
+        "#,
+    )
+    .bind(path)
+    .bind(version_vector.to_json())
+    .bind(checksum)
+    .bind(size_bytes)
+    .bind(is_deleted)
+    .bind(modified_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Record local edits below `folder` (or of the single file `folder`) in the
+/// change log, bumping this instance's counter for every changed path.
+/// The caller must hold `state_lock`.
+async fn scan_local_changes(
+    pool: &SqlitePool,
+    identity: &InstanceIdentity,
+    folder: &str,
+) -> Result<usize> {
+    let pattern = descendants_pattern(folder);
+    let files: Vec<(String, Option<String>, i64, String)> = sqlx::query_as(
+        r#"
+        SELECT path, checksum_sha256, size_bytes, updated_at FROM files
+        WHERE is_deleted = 0 AND (?1 = '' OR path = ?1 OR path LIKE ?2 ESCAPE '\')
+        ORDER BY updated_at ASC
+        "#,
+    )
+    .bind(folder)
+    .bind(&pattern)
+    .fetch_all(pool)
+    .await?;
+    let known: Vec<VersionRow> = sqlx::query_as(
+        r#"
+        SELECT path, version_vector, checksum_sha256, size_bytes, is_deleted, modified_at, seq
+        FROM sync_file_versions WHERE ?1 = '' OR path = ?1 OR path LIKE ?2 ESCAPE '\'
+        "#,
+    )
+    .bind(folder)
+    .bind(&pattern)
+    .fetch_all(pool)
+    .await?;
+
+    // Later rows win if a path has several live rows
+    let live: HashMap<String, (Option<String>, i64, String)> = files
+        .into_iter()
+        .filter(|(path, ..)| !is_internal(path))
+        .map(|(path, checksum, size, updated_at)| (path, (checksum, size, updated_at)))
+        .collect();
+    let known: HashMap<String, VersionRow> = known
+        .into_iter()
+        .map(|row| (row.path.clone(), row))
+        .collect();
+    let mut changed = 0;
+
+    for (path, (checksum, size, updated_at)) in &live {
+        let checksum = match checksum {
+            Some(checksum) => checksum.clone(),
+            None => {
+                // Older rows have no checksum; compute it once and keep it
+                let Ok((_, checksum)) = sha256_file(&Path::new(DATA_DIR).join(path)).await else {
+                    continue;
+                };
+                sqlx::query(
+                    "UPDATE files SET checksum_sha256 = ? WHERE path = ? AND is_deleted = 0 AND checksum_sha256 IS NULL",
+                )
+                .bind(&checksum)
+                .bind(path)
+                .execute(pool)
+                .await?;
+                checksum
+            }
+        };
+
+        let previous = known.get(path);
+        if let Some(row) = previous
+            && !row.is_deleted
+            && row.checksum_sha256.as_deref() == Some(checksum.as_str())
+        {
+            continue;
+        }
+        let mut version_vector = previous
+            .map(|row| VersionVector::parse(&row.version_vector))
+            .unwrap_or_default();
+        version_vector.increment(&identity.instance_id);
+        record_version(
+            pool,
+            path,
+            &version_vector,
+            Some(&checksum),
+            *size,
+            false,
+            updated_at,
+        )
+        .await?;
+        changed += 1;
+    }
+
+    let now = Utc::now().to_rfc3339();
+    for (path, row) in &known {
+        if row.is_deleted || live.contains_key(path) {
+            continue;
+        }
+        let mut version_vector = VersionVector::parse(&row.version_vector);
+        version_vector.increment(&identity.instance_id);
+        record_version(pool, path, &version_vector, None, 0, true, &now).await?;
+        changed += 1;
+    }
+
+    Ok(changed)
+}
+
+/// Changes below `folder` after sequence number `since`, refreshed from the
+/// live file tree first
+pub async fn changes_since(pool: &SqlitePool, folder: &str, since: i64) -> Result<ChangesPage> {
+    let identity = local_identity(pool).await?;
+    {
+        let _lock = state_lock().lock().await;
+        if !recently_scanned(folder) {
+            scan_local_changes(pool, &identity, folder).await?;
+        }
+    }
+
+    let mut rows: Vec<VersionRow> = sqlx::query_as(
+        r#"
+        SELECT path, version_vector, checksum_sha256, size_bytes, is_deleted, modified_at, seq
+        FROM sync_file_versions
+        WHERE seq > ?1 AND (?2 = '' OR path = ?2 OR path LIKE ?3 ESCAPE '\')
+        ORDER BY seq ASC LIMIT ?4
+        "#,
+    )
+    .bind(since)
+    .bind(folder)
+    .bind(descendants_pattern(folder))
+    .bind(CHANGES_PAGE_SIZE + 1)
+    .fetch_all(pool)
+    .await?;
+
+    let has_more = rows.len() as i64 > CHANGES_PAGE_SIZE;
+    rows.truncate(CHANGES_PAGE_SIZE as usize);
+    let next_cursor = rows.last().map(|row| row.seq).unwrap_or(since);
+    Ok(ChangesPage {
+        changes: rows.into_iter().map(ChangeEntry::from).collect(),
+        next_cursor,
+        has_more,
+    })
+}
+
+/// Paging through a large change log rescans at most every few seconds
+fn recently_scanned(folder: &str) -> bool {
+    static SCANS: OnceLock<Mutex<HashMap<String, std::time::Instant>>> = OnceLock::new();
+    let mut scans = SCANS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let now = std::time::Instant::now();
+    match scans.get(folder) {
+        Some(last) if now.duration_since(*last) < Duration::from_secs(5) => true,
+        _ => {
+            scans.insert(folder.to_string(), now);
+            false
+        }
+    }
+}
+
+// ==================== BLOCK SERVING ====================
+
+/// Block list of a live file, `None` if it does not exist
+pub async fn file_manifest(pool: &SqlitePool, path: &str) -> Result<Option<FileManifest>> {
+    if !crate::storage::ensure_local(pool, path).await? {
+        return Ok(None);
+    }
+    let local_path = Path::new(DATA_DIR).join(path);
+    if !tokio::fs::metadata(&local_path).await?.is_file() {
+        return Ok(None);
+    }
+
+    let (blocks, checksum_sha256) = chunk_file(&local_path).await?;
+    Ok(Some(FileManifest {
+        path: path.to_string(),
+        checksum_sha256,
+        size_bytes: blocks.iter().map(|b| b.size).sum(),
+        blocks,
+    }))
+}
+
+/// Concatenated content of the requested blocks. Returns `None` if the file
+/// changed since the manifest was taken.
+pub async fn read_blocks(path: &str, blocks: &[ChunkRef]) -> Result<Option<Vec<u8>>> {
+    let total: u64 = blocks.iter().map(|b| b.size).sum();
+    if blocks.len() > MAX_BLOCKS_PER_REQUEST || total > MAX_BLOCK_REQUEST_BYTES {
+        bail!("Block request too large");
+    }
+
+    let mut file = match tokio::fs::File::open(Path::new(DATA_DIR).join(path)).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut content = Vec::with_capacity(total as usize);
+    for block in blocks {
+        let start = content.len();
+        content.resize(start + block.size as usize, 0);
+        file.seek(std::io::SeekFrom::Start(block.offset)).await?;
+        if file.read_exact(&mut content[start..]).await.is_err()
+            || format!("{:x}", Sha256::digest(&content[start..])) != block.hash
+        {
+            return Ok(None);
+        }
+    }
+    Ok(Some(content))
+}
+
+// ==================== PEER CLIENT ====================
+
+fn http_client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?)
+}
+
+/// Signed requests to a paired peer
+struct PeerClient {
+    http: reqwest::Client,
+    address: String,
+    instance_id: String,
+    secret: String,
+}
+
+impl PeerClient {
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let mut url = reqwest::Url::parse(&format!(
+            "{}/api{}",
+            self.address.trim_end_matches('/'),
+            path
+        ))?;
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+
+        // Sign the path from /api on so reverse proxies with a prefix still verify
+        let signed_path = match url.query() {
+            Some(query) => format!("/api{}?{}", path, query),
+            None => format!("/api{}", path),
+        };
+        let timestamp = Utc::now().timestamp();
+        let signature = sign_request(
+            &self.secret,
+            method.as_str(),
+            &signed_path,
+            timestamp,
+            &body,
+        );
+
+        let response = self
+            .http
+            .request(method, url)
+            .header(PEER_ID_HEADER, &self.instance_id)
+            .header(PEER_TIMESTAMP_HEADER, timestamp.to_string())
+            .header(PEER_SIGNATURE_HEADER, signature)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Peer returned {} for {}", response.status(), path);
+        }
+        Ok(response)
+    }
+
+    async fn changes(&self, folder: &str, since: i64) -> Result<ChangesPage> {
+        let since = since.to_string();
+        let response = self
+            .send(
+                reqwest::Method::GET,
+                "/p2p/changes",
+                &[("folder", folder), ("since", &since)],
+                Vec::new(),
+            )
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    async fn manifest(&self, path: &str) -> Result<FileManifest> {
+        let response = self
+            .send(
+                reqwest::Method::GET,
+                "/p2p/manifest",
+                &[("path", path)],
+                Vec::new(),
+            )
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    async fn blocks(&self, path: &str, blocks: &[ChunkRef]) -> Result<bytes::Bytes> {
+        let body = serde_json::to_vec(&BlockRequest {
+            path: path.to_string(),
+            blocks: blocks.to_vec(),
+        })?;
+        let response = self
+            .send(reqwest::Method::POST, "/p2p/blocks", &[], body)
+            .await?;
+        Ok(response.bytes().await?)
+    }
+}
+
+// ==================== SYNC ====================
+
+/// Everything a sync run writes to
+#[derive(Clone)]
+pub struct SyncContext {
+    pub pool: SqlitePool,
+    pub search_index: Arc<SearchIndex>,
+    pub fs_tx: broadcast::Sender<FileChangeEvent>,
+}
+
+/// What to do with one remote change
+enum Decision {
+    /// Nothing new
+    Skip,
+    /// Same content on both sides, only the version vector moves on
+    Record(VersionVector),
+    /// Take the remote content (or deletion)
+    Take(VersionVector),
+}
+
+/// The losing side of a concurrent edit
+enum Loser {
+    Local,
+    Remote,
+}
+
+/// A remote change after its content has been downloaded
+struct Prepared {
+    decision: Decision,
+    /// Version vector of the local state the decision was based on
+    based_on: Option<VersionVector>,
+    content: Option<PathBuf>,
+    conflict: Option<(&'static str, Option<Loser>)>,
+}
+
+/// Sync interval from `SYNCSPACE_PEER_SYNC_INTERVAL` (seconds, 0 disables)
+pub fn sync_interval() -> Option<Duration> {
+    let secs = std::env::var("SYNCSPACE_PEER_SYNC_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_SYNC_INTERVAL_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Periodically pull from every paired peer that has shared folders
+pub fn spawn_sync_loop(ctx: SyncContext) {
+    let Some(interval) = sync_interval() else {
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let peers: Vec<String> = match sqlx::query_scalar(
+                "SELECT id FROM peers WHERE sync_enabled = 1 AND shared_secret IS NOT NULL
+                 AND EXISTS (SELECT 1 FROM peer_sync_folders f WHERE f.peer_id = peers.id)",
+            )
+            .fetch_all(&ctx.pool)
+            .await
+            {
+                Ok(peers) => peers,
+                Err(e) => {
+                    tracing::warn!("Failed to list sync peers: {}", e);
+                    continue;
+                }
+            };
+            for peer_id in peers {
+                if let Err(e) = sync_peer(&ctx, &peer_id).await {
+                    tracing::warn!("Sync with peer {} failed: {}", peer_id, e);
+                }
+            }
+        }
+    });
+}
+
+/// Pull all shared folders from one peer
+pub async fn sync_peer(ctx: &SyncContext, peer_id: &str) -> Result<SyncReport> {
+    let Some(_guard) = PeerGuard::acquire(peer_id) else {
+        bail!("A sync with this peer is already running");
+    };
+    let peer = load_peer(&ctx.pool, peer_id)
+        .await?
+        .ok_or_else(|| anyhow!("Peer not found"))?;
+    let secret = match &peer.instance_id {
+        Some(_) => reveal_secret(&ctx.pool, &peer, "peer sync").await?,
+        None => None,
+    };
+    let Some(secret) = secret else {
+        bail!("Peer is not paired");
+    };
+    let identity = local_identity(&ctx.pool).await?;
+    let client = PeerClient {
+        http: http_client()?,
+        address: peer.address.clone(),
+        instance_id: identity.instance_id.clone(),
+        secret,
+    };
+
+    set_peer_status(&ctx.pool, peer_id, "syncing", None).await;
+    let result = ctx.pull_folders(&client, &peer, &identity).await;
+    match &result {
+        Ok(_) => set_peer_status(&ctx.pool, peer_id, "online", None).await,
+        Err(e) => {
+            let unreachable = e
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(|e| e.is_connect() || e.is_timeout());
+            let status = if unreachable { "offline" } else { "error" };
+            set_peer_status(&ctx.pool, peer_id, status, Some(&format!("{:#}", e))).await;
+        }
+    }
+    result
+}
+
+async fn set_peer_status(pool: &SqlitePool, peer_id: &str, status: &str, error: Option<&str>) {
+    let now = Utc::now().to_rfc3339();
+    let result = sqlx::query(
+        "UPDATE peers SET status = ?, last_error = ?, updated_at = ?,
+         last_seen = CASE WHEN ? IN ('online', 'syncing') THEN ? ELSE last_seen END WHERE id = ?",
+    )
+    .bind(status)
+    .bind(error)
+    .bind(&now)
+    .bind(status)
+    .bind(&now)
+    .bind(peer_id)
+    .execute(pool)
+    .await;
+    if let Err(e) = result {
+        tracing::warn!("Failed to update peer status: {}", e);
+    }
+}
+
+impl SyncContext {
+    pub fn new(
+        pool: SqlitePool,
+        search_index: Arc<SearchIndex>,
+        fs_tx: broadcast::Sender<FileChangeEvent>,
+    ) -> Self {
+        Self {
+            pool,
+            search_index,
+            fs_tx,
+        }
+    }
+
+    async fn pull_folders(
+        &self,
+        client: &PeerClient,
+        peer: &PeerRecord,
+        identity: &InstanceIdentity,
+    ) -> Result<SyncReport> {
+        let folders: Vec<(String, String, i64)> = sqlx::query_as(
+            "SELECT id, folder_path, remote_cursor FROM peer_sync_folders WHERE peer_id = ? ORDER BY folder_path",
+        )
+        .bind(&peer.id)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut report = SyncReport::default();
+
+        for (folder_id, folder, mut cursor) in folders {
+            {
+                let _lock = state_lock().lock().await;
+                scan_local_changes(&self.pool, identity, &folder).await?;
+            }
+
+            loop {
+                let page = client.changes(&folder, cursor).await?;
+                for entry in &page.changes {
+                    if in_folder(&entry.path, &folder) {
+                        self.apply_change(client, peer, identity, entry, &mut report)
+                            .await
+                            .map_err(|e| e.context(entry.path.clone()))?;
+                    }
+                    cursor = entry.seq;
+                    sqlx::query("UPDATE peer_sync_folders SET remote_cursor = ? WHERE id = ?")
+                        .bind(cursor)
+                        .bind(&folder_id)
+                        .execute(&self.pool)
+                        .await?;
+                }
+                if !page.has_more || page.changes.is_empty() {
+                    break;
+                }
+            }
+
+            sqlx::query("UPDATE peer_sync_folders SET last_synced_at = ? WHERE id = ?")
+                .bind(Utc::now().to_rfc3339())
+                .bind(&folder_id)
+                .execute(&self.pool)
+                .await?;
+            report.folders += 1;
+        }
+
+        Ok(report)
+    }
+
+    /// Decide on one remote change, download what it needs, then apply it
+    /// unless the path changed locally in the meantime
+    async fn apply_change(
+        &self,
+        client: &PeerClient,
+        peer: &PeerRecord,
+        identity: &InstanceIdentity,
+        entry: &ChangeEntry,
+        report: &mut SyncReport,
+    ) -> Result<()> {
+        let path = crate::security::validate_file_path(&entry.path)
+            .map_err(|_| anyhow!("invalid path"))?;
+        if path != entry.path || path.is_empty() || is_internal(&path) {
+            tracing::warn!(
+                "Ignoring unsafe path from peer {}: {}",
+                peer.name,
+                entry.path
+            );
+            return Ok(());
+        }
+
+        let prepared = self.prepare(client, identity, entry, report).await?;
+        let result = self.commit(peer, identity, entry, &prepared, report).await;
+        if let Some(content) = &prepared.content {
+            let _ = tokio::fs::remove_file(content).await;
+        }
+        result
+    }
+
+    async fn prepare(
+        &self,
+        client: &PeerClient,
+        identity: &InstanceIdentity,
+        entry: &ChangeEntry,
+        report: &mut SyncReport,
+    ) -> Result<Prepared> {
+        let local = {
+            let _lock = state_lock().lock().await;
+            scan_local_changes(&self.pool, identity, &entry.path).await?;
+            local_version(&self.pool, &entry.path).await?
+        };
+        let local_vv = local
+            .as_ref()
+            .map(|l| l.version_vector.clone())
+            .unwrap_or_default();
+        let same_content = local.as_ref().is_some_and(|l| {
+            l.is_deleted == entry.is_deleted
+                && (l.is_deleted || l.checksum_sha256 == entry.checksum_sha256)
+        }) || (local.is_none() && entry.is_deleted);
+        let mut merged = local_vv.clone();
+        merged.merge(&entry.version_vector);
+
+        let (decision, conflict) = match entry.version_vector.compare(&local_vv) {
+            Causality::Equal | Causality::Older => (Decision::Skip, None),
+            _ if same_content => (Decision::Record(merged), None),
+            Causality::Newer => (Decision::Take(merged), None),
+            Causality::Concurrent => {
+                // Both sides are live with different content unless one is deleted
+                let local = local.as_ref().expect("concurrent implies a local version");
+                if local.is_deleted {
+                    (Decision::Take(merged), Some(("edit_delete", None)))
+                } else if entry.is_deleted {
+                    (Decision::Record(merged), Some(("edit_delete", None)))
+                } else if remote_wins(local, entry) {
+                    (
+                        Decision::Take(merged),
+                        Some(("concurrent_edit", Some(Loser::Local))),
+                    )
+                } else {
+                    (
+                        Decision::Record(merged),
+                        Some(("concurrent_edit", Some(Loser::Remote))),
+                    )
+                }
+            }
+        };
+
+        let needs_content = match (&decision, &conflict) {
+            (Decision::Take(_), _) => !entry.is_deleted,
+            (_, Some((_, Some(Loser::Remote)))) => true,
+            _ => false,
+        };
+        let content = if needs_content {
+            let checksum = entry
+                .checksum_sha256
+                .as_deref()
+                .ok_or_else(|| anyhow!("remote change without checksum"))?;
+            Some(self.download(client, &entry.path, checksum, report).await?)
+        } else {
+            None
+        };
+
+        Ok(Prepared {
+            decision,
+            based_on: local.map(|l| l.version_vector),
+            content,
+            conflict,
+        })
+    }
+
+    async fn commit(
+        &self,
+        peer: &PeerRecord,
+        identity: &InstanceIdentity,
+        entry: &ChangeEntry,
+        prepared: &Prepared,
+        report: &mut SyncReport,
+    ) -> Result<()> {
+        let _lock = state_lock().lock().await;
+        scan_local_changes(&self.pool, identity, &entry.path).await?;
+        let local = local_version(&self.pool, &entry.path).await?;
+        if local.as_ref().map(|l| &l.version_vector) != prepared.based_on.as_ref() {
+            bail!("changed locally during sync, retrying next time");
+        }
+
+        if let Some((conflict_type, loser)) = &prepared.conflict {
+            let local = local
+                .as_ref()
+                .ok_or_else(|| anyhow!("conflict without a local version"))?;
+            let (copy_path, winner) = match loser {
+                Some(Loser::Local) => {
+                    let checksum = local.checksum_sha256.as_deref().unwrap_or_default();
+                    let copy_path =
+                        conflict_copy_path(&entry.path, &identity.instance_id, checksum);
+                    self.keep_local_copy(peer, identity, &entry.path, &copy_path)
+                        .await?;
+                    (Some(copy_path), "remote")
+                }
+                Some(Loser::Remote) => {
+                    let checksum = entry.checksum_sha256.as_deref().unwrap_or_default();
+                    let remote_id = peer.instance_id.as_deref().unwrap_or_default();
+                    let copy_path = conflict_copy_path(&entry.path, remote_id, checksum);
+                    let content = prepared
+                        .content
+                        .as_deref()
+                        .ok_or_else(|| anyhow!("conflict copy content missing"))?;
+                    self.write_file(peer, &copy_path, content, &entry.modified_at)
+                        .await?;
+                    scan_local_changes(&self.pool, identity, &copy_path).await?;
+                    (Some(copy_path), "local")
+                }
+                None if local.is_deleted => (None, "remote"),
+                None => (None, "local"),
+            };
+            self.record_conflict(peer, entry, local, conflict_type, copy_path, winner)
+                .await?;
+            report.conflicts += 1;
+        }
+
+        match &prepared.decision {
+            Decision::Skip => {}
+            Decision::Record(version_vector) => match &local {
+                Some(local) => {
+                    record_version(
+                        &self.pool,
+                        &entry.path,
+                        version_vector,
+                        local.checksum_sha256.as_deref(),
+                        local.size_bytes,
+                        local.is_deleted,
+                        &local.modified_at,
+                    )
+                    .await?;
+                }
+                // A deletion of a path this instance never had
+                None => {
+                    record_version(
+                        &self.pool,
+                        &entry.path,
+                        version_vector,
+                        None,
+                        0,
+                        true,
+                        &entry.modified_at,
+                    )
+                    .await?;
+                }
+            },
+            Decision::Take(version_vector) if entry.is_deleted => {
+                self.delete_file(peer, &entry.path).await?;
+                record_version(
+                    &self.pool,
+                    &entry.path,
+                    version_vector,
+                    None,
+                    0,
+                    true,
+                    &entry.modified_at,
+                )
+                .await?;
+                report.deleted += 1;
+            }
+            Decision::Take(version_vector) => {
+                let content = prepared
+                    .content
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("downloaded content missing"))?;
+                let (size, checksum) = self
+                    .write_file(peer, &entry.path, content, &entry.modified_at)
+                    .await?;
+                record_version(
+                    &self.pool,
+                    &entry.path,
+                    version_vector,
+                    Some(&checksum),
+                    size,
+                    false,
+                    &entry.modified_at,
+                )
+                .await?;
+                report.applied += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Download the current content of a remote file into a temp file,
+    /// reusing blocks of the local copy
+    async fn download(
+        &self,
+        client: &PeerClient,
+        path: &str,
+        checksum: &str,
+        report: &mut SyncReport,
+    ) -> Result<PathBuf> {
+        let manifest = client.manifest(path).await?;
+        if manifest.checksum_sha256 != checksum {
+            bail!("changed on the peer during sync, retrying next time");
+        }
+
+        let local_path = Path::new(DATA_DIR).join(path);
+        let mut local_blocks: HashMap<String, ChunkRef> = HashMap::new();
+        let mut local_file = None;
+        if crate::storage::ensure_local(&self.pool, path)
+            .await
+            .unwrap_or(false)
+            && let Ok((blocks, _)) = chunk_file(&local_path).await
+        {
+            local_blocks = blocks.into_iter().map(|b| (b.hash.clone(), b)).collect();
+            local_file = tokio::fs::File::open(&local_path).await.ok();
+        }
+
+        tokio::fs::create_dir_all(SYNC_TEMP_DIR).await?;
+        let temp_path = Path::new(SYNC_TEMP_DIR).join(format!("{}.part", Uuid::new_v4()));
+        let result = async {
+            let mut out = tokio::fs::File::create(&temp_path).await?;
+            let mut hasher = Sha256::new();
+            let mut pending: Vec<ChunkRef> = Vec::new();
+
+            for block in &manifest.blocks {
+                let reusable = local_blocks.get(&block.hash);
+                if let (Some(local), Some(file)) = (reusable, local_file.as_mut()) {
+                    self.fetch_blocks(client, path, &mut pending, &mut out, &mut hasher, report)
+                        .await?;
+                    let mut data = vec![0u8; local.size as usize];
+                    file.seek(std::io::SeekFrom::Start(local.offset)).await?;
+                    file.read_exact(&mut data).await?;
+                    hasher.update(&data);
+                    out.write_all(&data).await?;
+                    report.bytes_reused += local.size;
+                    continue;
+                }
+
+                pending.push(block.clone());
+                let pending_bytes: u64 = pending.iter().map(|b| b.size).sum();
+                if pending.len() >= MAX_BLOCKS_PER_REQUEST
+                    || pending_bytes + block.size > MAX_BLOCK_REQUEST_BYTES
+                {
+                    self.fetch_blocks(client, path, &mut pending, &mut out, &mut hasher, report)
+                        .await?;
+                }
+            }
+            self.fetch_blocks(client, path, &mut pending, &mut out, &mut hasher, report)
+                .await?;
+            out.flush().await?;
+
+            if format!("{:x}", hasher.finalize()) != checksum {
+                bail!("checksum mismatch after transfer");
+            }
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => Ok(temp_path),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn fetch_blocks(
+        &self,
+        client: &PeerClient,
+        path: &str,
+        pending: &mut Vec<ChunkRef>,
+        out: &mut tokio::fs::File,
+        hasher: &mut Sha256,
+        report: &mut SyncReport,
+    ) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let data = client.blocks(path, pending).await?;
+        let expected: u64 = pending.iter().map(|b| b.size).sum();
+        if data.len() as u64 != expected {
+            bail!("peer sent {} bytes instead of {}", data.len(), expected);
+        }
+
+        let mut start = 0usize;
+        for block in pending.iter() {
+            let chunk = &data[start..start + block.size as usize];
+            if format!("{:x}", Sha256::digest(chunk)) != block.hash {
+                bail!("block {} failed verification", block.hash);
+            }
+            start += chunk.len();
+        }
+        hasher.update(&data);
+        out.write_all(&data).await?;
+        report.bytes_transferred += expected;
+        pending.clear();
+        Ok(())
+    }
+
+    /// Copy the local content of `path` to its conflict copy
+    async fn keep_local_copy(
+        &self,
+        peer: &PeerRecord,
+        identity: &InstanceIdentity,
+        path: &str,
+        copy_path: &str,
+    ) -> Result<()> {
+        if !crate::storage::ensure_local(&self.pool, path).await? {
+            bail!("local version of {} is unavailable", path);
+        }
+        tokio::fs::create_dir_all(SYNC_TEMP_DIR).await?;
+        let temp_path = Path::new(SYNC_TEMP_DIR).join(format!("{}.part", Uuid::new_v4()));
+        tokio::fs::copy(Path::new(DATA_DIR).join(path), &temp_path).await?;
+
+        let result = self
+            .write_file(peer, copy_path, &temp_path, &Utc::now().to_rfc3339())
+            .await;
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        result?;
+        scan_local_changes(&self.pool, identity, copy_path).await?;
+        Ok(())
+    }
+
+    /// Move downloaded content into the data directory and register it like
+    /// any other write. Returns size and checksum.
+    async fn write_file(
+        &self,
+        peer: &PeerRecord,
+        path: &str,
+        content: &Path,
+        modified_at: &str,
+    ) -> Result<(i64, String)> {
+        let (size, checksum) = sha256_file(content).await?;
+        let size = size as i64;
+        let absolute = Path::new(DATA_DIR).join(path);
+        if let Some(parent) = absolute.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        self.ensure_parent_folders(peer, path).await?;
+
+        if tokio::fs::rename(content, &absolute).await.is_err() {
+            // Temp dir on another filesystem than the target
+            tokio::fs::copy(content, &absolute).await?;
+        }
+
+        let now = Utc::now().to_rfc3339();
+        let mime_type = mime_guess::from_path(path).first().map(|m| m.to_string());
+        let existing: Option<(String, String)> = sqlx::query_as(
+            "SELECT id, owner_id FROM files WHERE path = ? AND is_deleted = 0 ORDER BY updated_at DESC LIMIT 1",
+        )
+        .bind(path)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let (file_id, owner_id, kind) = match existing {
+            Some((file_id, owner_id)) => {
+                sqlx::query(
+                    "UPDATE files SET size_bytes = ?, checksum_sha256 = ?, mime_type = COALESCE(?, mime_type),
+                     version = version + 1, updated_at = ? WHERE id = ?",
+                )
+                .bind(size)
+                .bind(&checksum)
+                .bind(&mime_type)
+                .bind(modified_at)
+                .bind(&file_id)
+                .execute(&self.pool)
+                .await?;
+                if let Err(e) = crate::storage::delete_file_objects(&self.pool, &file_id).await {
+                    tracing::warn!("Failed to drop stale stored objects of {}: {}", path, e);
+                }
+                (file_id, owner_id, "modify")
+            }
+            None => {
+                let file_id = Uuid::new_v4().to_string();
+                let owner_id = self.owner_for(peer, path).await?;
+                sqlx::query(
+                    "INSERT INTO files (id, name, path, owner_id, size_bytes, mime_type, checksum_sha256, storage_path, is_deleted, version, created_at, updated_at)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, 0, 1, ?, ?)",
+                )
+                .bind(&file_id)
+                .bind(file_name(path))
+                .bind(path)
+                .bind(&owner_id)
+                .bind(size)
+                .bind(&mime_type)
+                .bind(&checksum)
+                .bind(path)
+                .bind(&now)
+                .bind(modified_at)
+                .execute(&self.pool)
+                .await?;
+                (file_id, owner_id, "create")
+            }
+        };
+
+        if let Err(e) =
+            crate::storage::replicate_file(&self.pool, &file_id, path, &absolute, size, &checksum)
+                .await
+        {
+            tracing::warn!("Failed to record storage location for {}: {}", path, e);
+        }
+        let text = crate::search::extract_content(&absolute).await;
+        let acl = crate::search::access::grants(&self.pool, path)
+            .await
+            .unwrap_or_default();
+        if let Err(e) = self
+            .search_index
+            .index_file(
+                &file_id,
+                file_name(path),
+                path,
+                text,
+                Utc::now(),
+                size as u64,
+                &acl,
+            )
+            .await
+        {
+            tracing::warn!("Failed to index {}: {}", path, e);
+        }
+
+        self.notify(path, kind, &owner_id, peer).await;
+        Ok((size, checksum))
+    }
+
+    /// Soft-delete like a delete through the API; the content stays in the trash
+    async fn delete_file(&self, peer: &PeerRecord, path: &str) -> Result<()> {
+        let removed: Vec<(String, String)> =
+            sqlx::query_as("SELECT id, owner_id FROM files WHERE path = ? AND is_deleted = 0")
+                .bind(path)
+                .fetch_all(&self.pool)
+                .await?;
+        let now = Utc::now().to_rfc3339();
+        for (file_id, owner_id) in &removed {
+            sqlx::query(
+                "UPDATE files SET is_deleted = 1, deleted_at = ?, updated_at = ? WHERE id = ?",
+            )
+            .bind(&now)
+            .bind(&now)
+            .bind(file_id)
+            .execute(&self.pool)
+            .await?;
+            let _ = self.search_index.delete_from_index(file_id).await;
+            self.notify(path, "delete", owner_id, peer).await;
+        }
+        Ok(())
+    }
+
+    async fn ensure_parent_folders(&self, peer: &PeerRecord, path: &str) -> Result<()> {
+        let mut parents = Vec::new();
+        let mut parent = parent_path(path);
+        while !parent.is_empty() {
+            parents.push(parent);
+            parent = parent_path(parent);
+        }
+
+        for folder in parents.into_iter().rev() {
+            let exists: bool = sqlx::query_scalar(
+                "SELECT COUNT(*) > 0 FROM folders WHERE path = ? AND is_deleted = 0",
+            )
+            .bind(folder)
+            .fetch_one(&self.pool)
+            .await?;
+            if exists {
+                continue;
+            }
+            let parent_id: Option<String> =
+                sqlx::query_scalar("SELECT id FROM folders WHERE path = ? AND is_deleted = 0")
+                    .bind(parent_path(folder))
+                    .fetch_optional(&self.pool)
+                    .await?;
+            let owner_id = self.owner_for(peer, folder).await?;
+            let now = Utc::now().to_rfc3339();
+            sqlx::query(
+                "INSERT INTO folders (id, name, path, parent_id, owner_id, is_deleted, created_at, updated_at)
+                 VALUES (?, ?, ?, ?, ?, 0, ?, ?)
+                 ON CONFLICT(path) DO UPDATE SET is_deleted = 0, deleted_at = NULL, deleted_by = NULL,
+                    parent_id = excluded.parent_id, updated_at = excluded.updated_at",
+            )
+            .bind(Uuid::new_v4().to_string())
+            .bind(file_name(folder))
+            .bind(folder)
+            .bind(&parent_id)
+            .bind(&owner_id)
+            .bind(&now)
+            .bind(&now)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Files from a peer belong to the owner of the closest parent folder,
+    /// falling back to the user who paired the peer
+    async fn owner_for(&self, peer: &PeerRecord, path: &str) -> Result<String> {
+        let mut parent = parent_path(path);
+        while !parent.is_empty() {
+            let owner: Option<String> = sqlx::query_scalar(
+                "SELECT owner_id FROM folders WHERE path = ? AND is_deleted = 0",
+            )
+            .bind(parent)
+            .fetch_optional(&self.pool)
+            .await?;
+            if let Some(owner) = owner {
+                return Ok(owner);
+            }
+            parent = parent_path(parent);
+        }
+        Ok(peer.user_id.clone())
+    }
+
+    async fn record_conflict(
+        &self,
+        peer: &PeerRecord,
+        entry: &ChangeEntry,
+        local: &LocalVersion,
+        conflict_type: &str,
+        copy_path: Option<String>,
+        winner: &str,
+    ) -> Result<()> {
+        let file: Option<(String, String)> = sqlx::query_as(
+            "SELECT id, owner_id FROM files WHERE path = ? ORDER BY is_deleted ASC, updated_at DESC LIMIT 1",
+        )
+        .bind(&entry.path)
+        .fetch_optional(&self.pool)
+        .await?;
+        let (file_id, user_id) = match file {
+            Some((file_id, owner_id)) => (Some(file_id), owner_id),
+            None => (None, peer.user_id.clone()),
+        };
+
+        sqlx::query(
+            "INSERT INTO file_conflicts (id, file_id, file_path, user_id, peer_id, conflict_type, local_version, remote_version, conflict_copy_path, details, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(&file_id)
+        .bind(&entry.path)
+        .bind(&user_id)
+        .bind(&peer.id)
+        .bind(conflict_type)
+        .bind(local.version_vector.total() as i64)
+        .bind(entry.version_vector.total() as i64)
+        .bind(&copy_path)
+        .bind(
+            serde_json::json!({
+                "peer": peer.name,
+                "winner": winner,
+                "local_checksum": local.checksum_sha256,
+                "remote_checksum": entry.checksum_sha256,
+                "local_modified_at": local.modified_at,
+                "remote_modified_at": entry.modified_at,
+                "local_deleted": local.is_deleted,
+                "remote_deleted": entry.is_deleted,
+            })
+            .to_string(),
+        )
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+
+        tracing::info!(
+            "Sync conflict on {} with peer {} ({}, {} version kept)",
+            entry.path,
+            peer.name,
+            conflict_type,
+            winner
+        );
+        Ok(())
+    }
+
+    async fn notify(&self, path: &str, kind: &str, owner_id: &str, peer: &PeerRecord) {
+        crate::services::change_journal::record(&self.pool, JournalChange::new(kind, path)).await;
+        let _ = self.fs_tx.send(
+            FileChangeEvent::new(path.to_string(), kind.to_string()).with_metadata(
+                serde_json::json!({
+                    "source": "peer",
+                    "peer": peer.name,
+                    "owner_id": owner_id,
+                }),
+            ),
+        );
+    }
+}
+
+fn parent_path(path: &str) -> &str {
+    path.rsplit_once('/')
+        .map(|(parent, _)| parent)
+        .unwrap_or("")
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit_once('/').map(|(_, name)| name).unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vv(entries: &[(&str, u64)]) -> VersionVector {
+        VersionVector(entries.iter().map(|(k, v)| (k.to_string(), *v)).collect())
+    }
+
+    #[test]
+    fn test_version_vector_compare() {
+        let a = vv(&[("a", 2), ("b", 1)]);
+        assert_eq!(a.compare(&a.clone()), Causality::Equal);
+        assert_eq!(a.compare(&vv(&[("a", 1), ("b", 1)])), Causality::Newer);
+        assert_eq!(
+            a.compare(&vv(&[("a", 2), ("b", 1), ("c", 1)])),
+            Causality::Older
+        );
+        assert_eq!(a.compare(&vv(&[("a", 1), ("b", 2)])), Causality::Concurrent);
+        assert_eq!(VersionVector::default().compare(&a), Causality::Older);
+    }
+
+    #[test]
+    fn test_version_vector_merge_dominates_both_sides() {
+        let mut merged = vv(&[("a", 3), ("b", 1)]);
+        let other = vv(&[("a", 1), ("b", 4), ("c", 1)]);
+        merged.merge(&other);
+        assert_eq!(merged, vv(&[("a", 3), ("b", 4), ("c", 1)]));
+        assert_eq!(merged.compare(&other), Causality::Newer);
+        assert_eq!(merged.total(), 8);
+        assert_eq!(VersionVector::parse(&merged.to_json()), merged);
+    }
+
+    #[test]
+    fn test_signature_covers_request() {
+        let sig = sign_request("secret", "GET", "/api/p2p/changes?since=1", 1000, b"");
+        assert!(verify_signature(
+            "secret",
+            "get",
+            "/api/p2p/changes?since=1",
+            1000,
+            b"",
+            &sig
+        ));
+        assert!(!verify_signature(
+            "secret",
+            "GET",
+            "/api/p2p/changes?since=2",
+            1000,
+            b"",
+            &sig
+        ));
+        assert!(!verify_signature(
+            "secret",
+            "GET",
+            "/api/p2p/changes?since=1",
+            1001,
+            b"",
+            &sig
+        ));
+        assert!(!verify_signature(
+            "other",
+            "GET",
+            "/api/p2p/changes?since=1",
+            1000,
+            b"",
+            &sig
+        ));
+        assert!(!verify_signature(
+            "secret",
+            "GET",
+            "/api/p2p/changes?since=1",
+            1000,
+            b"x",
+            &sig
+        ));
+    }
+
+    #[test]
+    fn test_conflict_copy_path_is_deterministic() {
+        assert_eq!(
+            conflict_copy_path("docs/report.pdf", "1234abcd-ef00", "deadbeefcafe"),
+            "docs/report.sync-conflict-1234abcd-deadbeef.pdf"
+        );
+        assert_eq!(
+            conflict_copy_path("Makefile", "1234abcd", "deadbeef"),
+            "Makefile.sync-conflict-1234abcd-deadbeef"
+        );
+        assert_eq!(
+            conflict_copy_path("a/.env", "1234abcd", "deadbeef"),
+            "a/.env.sync-conflict-1234abcd-deadbeef"
+        );
+    }
+
+    #[test]
+    fn test_in_folder() {
+        assert!(in_folder("docs/a.txt", ""));
+        assert!(in_folder("docs/a.txt", "docs"));
+        assert!(!in_folder("docs2/a.txt", "docs"));
+        assert!(in_folder("docs", "docs"));
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 //! Search service implementation
+use crate::search::{access, Viewer};
 use crate::{auth::UserInfo, AppState};
 use anyhow::Result;
 
@@ -25,18 +26,25 @@ pub async fn search(
             .collect());
     }
 
-    match state.search_index.search(query, limit, fuzzy) {
+    let viewer = Viewer::load(&state.db_pool, &user.id).await?;
+
+    match state.search_index.search(query, limit, fuzzy, &viewer) {
         Ok(mut results) => {
             // ALSO search in folders table for folder matching
             let query_lower = query.to_lowercase();
             if let Ok(folders) = sqlx::query_as::<_, (String, String)>(
-                "SELECT name, path FROM folders WHERE LOWER(name) LIKE ?",
+                "SELECT name, path FROM folders WHERE is_deleted = 0 AND LOWER(name) LIKE ?",
             )
             .bind(format!("%{}%", query_lower))
             .fetch_all(&state.db_pool)
             .await
             {
                 for (name, path) in folders {
+                    // Same rules as indexed files
+                    let grants = access::grants(&state.db_pool, &path).await?;
+                    if !viewer.can_read(&grants) {
+                        continue;
+                    }
                     // Add folder to results
                     results.push(crate::search::SearchResult {
                         file_id: path.clone(),
@@ -1,18 +1,66 @@
 //! Version Storage Service
 //! Handles differential storage, compression, and version management
-
+//!
+//! Versions are written and read as streams, so memory use does not depend
+//! on the file size:
+//!
+//! - A version is either a zstd-compressed full copy, or a zstd-compressed
+//!   binary delta (`crate::delta`) against the previous version.
+//! - Every version keeps the block signature of its content next to the data
+//!   (`<storage_path>.sig`). The next version is encoded against that
+//!   signature, so the previous content never has to be materialized.
+//! - Restoring a delta materializes its base into a temp file and applies
+//!   the delta on top of it; chains are capped at `MAX_DELTA_CHAIN`.
+//!
+//! Versions written by earlier releases (gzip and in-memory diffs) are still
+//! readable and are converted to full copies when their base is deleted.
+
+use crate::block_store;
+use crate::delta::{self, Signature, SignatureBuilder};
+use async_compression::Level;
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
 use chrono::Utc;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use sha2::{Digest, Sha256};
 use sqlx::SqlitePool;
-use std::io::{Read, Write};
+use std::future::Future;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio_util::io::InspectReader;
 use uuid::Uuid;
 
 const VERSION_STORAGE_DIR: &str = "./data/versions";
+const VERSION_TEMP_DIR: &str = "./data/versions/.tmp";
 const MAX_VERSIONS_PER_FILE: usize = 50;
 const VERSION_RETENTION_DAYS: i64 = 90;
 const COMPRESSION_THRESHOLD_BYTES: u64 = 1024; // 1KB - compress files larger than this
+const ZSTD_LEVEL: i32 = 3;
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Consecutive deltas before a full copy is stored again
+const MAX_DELTA_CHAIN: usize = 10;
+/// Versions larger than this are compared by checksum only
+const TEXT_DIFF_MAX_BYTES: i64 = 2 * 1024 * 1024;
+const IO_BUFFER_SIZE: usize = 64 * 1024;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// `file_versions` row read into [`VersionMetadata`]
+type VersionRow = (
+    String,
+    i32,
+    String,
+    i64,
+    i64,
+    i32,
+    i32,
+    Option<String>,
+    String,
+    String,
+    String,
+    Option<String>,
+);
 
 /// Version metadata stored in database
 #[derive(Debug, Clone)]
@@ -32,9 +80,46 @@ pub struct VersionMetadata {
     pub comment: Option<String>,
 }
 
+/// How the data behind a version is stored
+enum StoredFormat {
+    Blocks,
+    Raw,
+    Zstd,
+    ZstdDelta,
+    /// gzip and in-memory diffs written before versions were streamed
+    Legacy,
+}
+
+/// Content written to version storage
+struct StoredContent {
+    signature: Signature,
+    stored_size: u64,
+    is_compressed: bool,
+}
+
+/// File under the version temp dir, removed when dropped
+struct TempFile(PathBuf);
+
+impl TempFile {
+    async fn create() -> std::io::Result<(Self, tokio::fs::File)> {
+        tokio::fs::create_dir_all(VERSION_TEMP_DIR).await?;
+        let path = PathBuf::from(VERSION_TEMP_DIR).join(Uuid::new_v4().to_string());
+        let file = tokio::fs::File::create(&path).await?;
+        Ok((Self(path), file))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
 /// Initialize version storage directory
 pub fn init_version_storage() -> std::io::Result<()> {
     std::fs::create_dir_all(VERSION_STORAGE_DIR)?;
+    // Leftovers of restores interrupted by a restart
+    let _ = std::fs::remove_dir_all(VERSION_TEMP_DIR);
     Ok(())
 }
 
@@ -45,11 +130,13 @@ pub async fn create_version(
     file_path: &Path,
     user_id: &str,
     comment: Option<&str>,
-) -> Result<VersionMetadata, Box<dyn std::error::Error + Send + Sync>> {
-    // Read file content
-    let file_content = tokio::fs::read(file_path).await?;
-    let original_size = file_content.len() as i64;
-    let checksum = calculate_checksum(&file_content);
+) -> Result<VersionMetadata, BoxError> {
+    if block_store::dedup_enabled() {
+        return create_block_version(pool, file_id, file_path, user_id, comment).await;
+    }
+
+    let size_hint = tokio::fs::metadata(file_path).await?.len();
+    let block_size = delta::default_block_size(size_hint);
 
     // Get current version number
     let current_version: i32 = sqlx::query_scalar(
@@ -63,68 +150,84 @@ pub async fn create_version(
     let new_version_number = current_version + 1;
     let version_id = Uuid::new_v4().to_string();
 
-    // Check if we should use differential storage
-    let (is_differential, base_version_id, stored_content) = if new_version_number > 1 {
-        // Try to create diff from previous version
-        if let Some(prev_version) = get_previous_version(pool, file_id).await? {
-            match create_diff(&prev_version, &file_content).await {
-                Ok(diff_data) if diff_data.len() < file_content.len() => {
-                    // Diff is smaller, use it
-                    (true, Some(prev_version.id), diff_data)
-                }
-                _ => {
-                    // Diff failed or is larger, store full content
-                    (false, None, file_content)
-                }
-            }
-        } else {
-            (false, None, file_content)
-        }
-    } else {
-        // First version, always store full content
-        (false, None, file_content)
-    };
-
-    // Compress if beneficial
-    let (is_compressed, final_content) =
-        if stored_content.len() as u64 > COMPRESSION_THRESHOLD_BYTES {
-            match compress_data(&stored_content) {
-                Ok(compressed) if compressed.len() < stored_content.len() => (true, compressed),
-                _ => (false, stored_content),
-            }
-        } else {
-            (false, stored_content)
-        };
-
-    let compressed_size = final_content.len() as i64;
-
-    // Store version file
     let storage_path = format!(
         "{}/{}/{}_v{}.dat",
         VERSION_STORAGE_DIR, file_id, version_id, new_version_number
     );
-
     let storage_dir = PathBuf::from(&storage_path).parent().unwrap().to_path_buf();
     tokio::fs::create_dir_all(&storage_dir).await?;
-    tokio::fs::write(&storage_path, &final_content).await?;
+
+    // Encode against the previous version's signature unless the chain is full
+    let mut stored = None;
+    if let Some(prev_version) = get_previous_version(pool, file_id).await?
+        && delta_chain_len(pool, &prev_version).await? < MAX_DELTA_CHAIN
+        && let Some(base_signature) = load_signature(&prev_version.storage_path).await
+    {
+        let content = write_delta(
+            file_path,
+            &base_signature,
+            Path::new(&storage_path),
+            block_size,
+        )
+        .await?;
+        if content.is_worthwhile() {
+            stored = Some((content.0, Some(prev_version.id)));
+        }
+    }
+    let (content, base_version_id) = match stored {
+        Some(stored) => stored,
+        None => (
+            write_full(
+                file_path,
+                Path::new(&storage_path),
+                size_hint > COMPRESSION_THRESHOLD_BYTES,
+                block_size,
+            )
+            .await?,
+            None,
+        ),
+    };
+    let is_differential = base_version_id.is_some();
+
+    let signature_path = signature_path(&storage_path);
+    if let Err(e) = save_signature(&signature_path, &content.signature).await {
+        release_version_storage(pool, &storage_path).await;
+        return Err(e);
+    }
+    for path in [&storage_path, &signature_path] {
+        if let Err(e) = crate::storage::mirror_local_file(Path::new(path)).await {
+            tracing::warn!(
+                "Failed to mirror version {} to storage backend: {}",
+                version_id,
+                e
+            );
+        }
+    }
+
+    let original_size = content.signature.file_size as i64;
+    let compressed_size = content.stored_size as i64;
+    let checksum = content.signature.checksum;
 
     // Store metadata in database
     let now = Utc::now().to_rfc3339();
-    sqlx::query(
+    let inserted = sqlx::query(
         r#"
-        INSERT INTO file_versions 
-        (id, file_id, version_number, storage_path, original_size, compressed_size, 
-         is_compressed, is_differential, base_version_id, checksum, created_by, created_at, comment)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        INSERT INTO file_versions
+        (id, file_id, version_number, size_bytes, checksum_sha256, storage_path, original_size,
+         compressed_size, is_compressed, is_differential, base_version_id, checksum, created_by,
+         created_at, comment)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         "#,
     )
     .bind(&version_id)
     .bind(file_id)
     .bind(new_version_number)
+    .bind(original_size)
+    .bind(&checksum)
     .bind(&storage_path)
     .bind(original_size)
     .bind(compressed_size)
-    .bind(is_compressed as i32)
+    .bind(content.is_compressed as i32)
     .bind(is_differential as i32)
     .bind(&base_version_id)
     .bind(&checksum)
@@ -132,7 +235,12 @@ pub async fn create_version(
     .bind(&now)
     .bind(comment)
     .execute(pool)
-    .await?;
+    .await;
+
+    if let Err(e) = inserted {
+        release_version_storage(pool, &storage_path).await;
+        return Err(e.into());
+    }
 
     // Cleanup old versions if limit exceeded
     cleanup_old_versions(pool, file_id).await?;
@@ -144,7 +252,7 @@ pub async fn create_version(
         storage_path,
         original_size,
         compressed_size,
-        is_compressed,
+        is_compressed: content.is_compressed,
         is_differential,
         base_version_id,
         checksum,
@@ -154,33 +262,219 @@ pub async fn create_version(
     })
 }
 
-/// Restore a version to original content
-pub async fn restore_version(
+/// Create a version held by the block store; unchanged blocks are shared with
+/// earlier versions and any other stored copy of the same content
+async fn create_block_version(
+    pool: &SqlitePool,
+    file_id: &str,
+    file_path: &Path,
+    user_id: &str,
+    comment: Option<&str>,
+) -> Result<VersionMetadata, BoxError> {
+    let manifest = block_store::store_file(pool, file_path)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let current_version: i32 = sqlx::query_scalar(
+        "SELECT COALESCE(MAX(version_number), 0) FROM file_versions WHERE file_id = ?",
+    )
+    .bind(file_id)
+    .fetch_one(pool)
+    .await
+    .unwrap_or(0);
+
+    let new_version_number = current_version + 1;
+    let version_id = Uuid::new_v4().to_string();
+    let storage_path = block_store::storage_path_for(&manifest.id);
+    let now = Utc::now().to_rfc3339();
+
+    let inserted = sqlx::query(
+        r#"
+        INSERT INTO file_versions
+        (id, file_id, version_number, size_bytes, checksum_sha256, storage_path, original_size,
+         compressed_size, is_compressed, is_differential, base_version_id, checksum, created_by,
+         created_at, comment)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, 0, 0, NULL, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&version_id)
+    .bind(file_id)
+    .bind(new_version_number)
+    .bind(manifest.total_size)
+    .bind(&manifest.id)
+    .bind(&storage_path)
+    .bind(manifest.total_size)
+    .bind(manifest.new_bytes)
+    .bind(&manifest.id)
+    .bind(user_id)
+    .bind(&now)
+    .bind(comment)
+    .execute(pool)
+    .await;
+
+    if let Err(e) = inserted {
+        let _ = block_store::release_manifest(pool, &manifest.id).await;
+        return Err(e.into());
+    }
+
+    cleanup_old_versions(pool, file_id).await?;
+
+    Ok(VersionMetadata {
+        id: version_id,
+        file_id: file_id.to_string(),
+        version_number: new_version_number,
+        storage_path,
+        original_size: manifest.total_size,
+        compressed_size: manifest.new_bytes,
+        is_compressed: false,
+        is_differential: false,
+        base_version_id: None,
+        checksum: manifest.id,
+        created_by: user_id.to_string(),
+        created_at: now,
+        comment: comment.map(|s| s.to_string()),
+    })
+}
+
+/// Release the storage behind a version (file or block store manifest)
+pub async fn release_version_storage(pool: &SqlitePool, storage_path: &str) {
+    match block_store::manifest_id(storage_path) {
+        Some(manifest_id) => {
+            if let Err(e) = block_store::release_manifest(pool, manifest_id).await {
+                tracing::warn!("Failed to release manifest {}: {}", manifest_id, e);
+            }
+        }
+        None => {
+            crate::storage::remove_local_file(Path::new(storage_path)).await;
+            crate::storage::remove_local_file(Path::new(&signature_path(storage_path))).await;
+        }
+    }
+}
+
+/// Delete a version and release its storage. Versions stored as a delta
+/// against it are rewritten as full copies first. Returns false if the
+/// version does not exist.
+pub async fn delete_version(pool: &SqlitePool, version_id: &str) -> Result<bool, BoxError> {
+    let Some(version) = find_version_metadata(pool, version_id).await? else {
+        return Ok(false);
+    };
+    detach_dependents(pool, &version).await?;
+
+    let storage_path: Option<String> =
+        sqlx::query_scalar("DELETE FROM file_versions WHERE id = ? RETURNING storage_path")
+            .bind(version_id)
+            .fetch_optional(pool)
+            .await?;
+    match storage_path {
+        Some(storage_path) => {
+            release_version_storage(pool, &storage_path).await;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Delete every version of `file_id` and release their storage; returns how
+/// many were deleted. Newest first, so no delta has to be rewritten.
+pub async fn delete_file_versions(pool: &SqlitePool, file_id: &str) -> Result<usize, BoxError> {
+    let version_ids: Vec<String> = sqlx::query_scalar(
+        "SELECT id FROM file_versions WHERE file_id = ? ORDER BY version_number DESC",
+    )
+    .bind(file_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut deleted = 0;
+    for version_id in version_ids {
+        if delete_version(pool, &version_id).await? {
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// Stream the content of a version into `out`; returns the bytes written.
+/// The content is verified against the version checksum while it is written.
+pub async fn write_version_to<W>(
     pool: &SqlitePool,
     version_id: &str,
-) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
-    // Get version metadata
+    out: &mut W,
+) -> Result<u64, BoxError>
+where
+    W: AsyncWrite + Unpin + Send,
+{
     let version = get_version_metadata(pool, version_id).await?;
+    write_version(pool, &version, out).await
+}
 
-    // Read stored content
-    let mut stored_content = tokio::fs::read(&version.storage_path).await?;
+/// Restore a version into a file at `dest`
+pub async fn restore_version_to_file(
+    pool: &SqlitePool,
+    version_id: &str,
+    dest: &Path,
+) -> Result<VersionMetadata, BoxError> {
+    let version = get_version_metadata(pool, version_id).await?;
+    let mut file = tokio::fs::File::create(dest).await?;
+    write_version(pool, &version, &mut file).await?;
+    file.sync_data().await?;
+    Ok(version)
+}
 
-    // Decompress if needed
-    if version.is_compressed {
-        stored_content = decompress_data(&stored_content)?;
+/// Put the content of a version back in place of the live file at `dest`.
+///
+/// The version is restored into a temp file first, so `dest` is only
+/// replaced once the content has been verified. The content being replaced
+/// is kept as a new version, which is returned next to the restored one.
+pub async fn restore_version_over(
+    pool: &SqlitePool,
+    version_id: &str,
+    dest: &Path,
+    user_id: &str,
+) -> Result<(VersionMetadata, Option<VersionMetadata>), BoxError> {
+    let version = get_version_metadata(pool, version_id).await?;
+
+    let (temp, mut file) = TempFile::create().await?;
+    write_version(pool, &version, &mut file).await?;
+    file.sync_data().await?;
+    drop(file);
+
+    let replaced = if tokio::fs::try_exists(dest).await? {
+        let comment = format!("Before restoring version {}", version.version_number);
+        Some(create_version(pool, &version.file_id, dest, user_id, Some(&comment)).await?)
+    } else {
+        None
+    };
+
+    if tokio::fs::rename(&temp.0, dest).await.is_err() {
+        // The temp dir is on another filesystem
+        tokio::fs::copy(&temp.0, dest).await?;
     }
+    Ok((version, replaced))
+}
 
-    // Apply diff if needed
-    if version.is_differential {
-        if let Some(base_id) = version.base_version_id {
-            // Recursively restore base version - requires boxing for async recursion
-            let base_content = Box::pin(restore_version(pool, &base_id)).await?;
-            // Apply diff
-            stored_content = apply_diff(&base_content, &stored_content)?;
+/// Open a version for streaming. Returns `None` if the version does not exist.
+///
+/// The content is produced by a background task; if restoring fails midway
+/// the stream ends early, so callers should announce `original_size` as the
+/// content length.
+pub async fn open_version(
+    pool: &SqlitePool,
+    version_id: &str,
+) -> Result<Option<(VersionMetadata, tokio::io::DuplexStream)>, BoxError> {
+    let Some(version) = find_version_metadata(pool, version_id).await? else {
+        return Ok(None);
+    };
+    let (mut writer, reader) = tokio::io::duplex(IO_BUFFER_SIZE);
+
+    let pool = pool.clone();
+    let task_version = version.clone();
+    tokio::spawn(async move {
+        if let Err(e) = write_version(&pool, &task_version, &mut writer).await {
+            tracing::error!("Failed to stream version {}: {}", task_version.id, e);
         }
-    }
+    });
 
-    Ok(stored_content)
+    Ok(Some((version, reader)))
 }
 
 /// Get version diff preview
@@ -188,57 +482,62 @@ pub async fn get_version_diff(
     pool: &SqlitePool,
     from_version_id: &str,
     to_version_id: &str,
-) -> Result<VersionDiff, Box<dyn std::error::Error + Send + Sync>> {
-    let from_content = restore_version(pool, from_version_id).await?;
-    let to_content = restore_version(pool, to_version_id).await?;
-
-    // Check if text file
-    if is_text_file(&from_content) && is_text_file(&to_content) {
-        let from_text = String::from_utf8_lossy(&from_content);
-        let to_text = String::from_utf8_lossy(&to_content);
-
-        // Line-by-line diff
-        let diff_lines = compute_text_diff(&from_text, &to_text);
-
-        Ok(VersionDiff {
-            from_version_id: from_version_id.to_string(),
-            to_version_id: to_version_id.to_string(),
-            diff_type: DiffType::Text,
-            text_diff: Some(diff_lines),
-            binary_diff: None,
-            size_change: to_content.len() as i64 - from_content.len() as i64,
-        })
-    } else {
-        // Binary file - just metadata
-        Ok(VersionDiff {
-            from_version_id: from_version_id.to_string(),
-            to_version_id: to_version_id.to_string(),
-            diff_type: DiffType::Binary,
-            text_diff: None,
-            binary_diff: Some(BinaryDiff {
-                from_size: from_content.len(),
-                to_size: to_content.len(),
-                checksum_changed: calculate_checksum(&from_content)
-                    != calculate_checksum(&to_content),
-            }),
-            size_change: to_content.len() as i64 - from_content.len() as i64,
-        })
+) -> Result<VersionDiff, BoxError> {
+    let from = get_version_metadata(pool, from_version_id).await?;
+    let to = get_version_metadata(pool, to_version_id).await?;
+    let size_change = to.original_size - from.original_size;
+
+    // Only small versions are loaded for a line diff
+    if from.original_size <= TEXT_DIFF_MAX_BYTES && to.original_size <= TEXT_DIFF_MAX_BYTES {
+        let mut from_content = Vec::with_capacity(from.original_size as usize);
+        write_version(pool, &from, &mut from_content).await?;
+        let mut to_content = Vec::with_capacity(to.original_size as usize);
+        write_version(pool, &to, &mut to_content).await?;
+
+        if is_text_file(&from_content) && is_text_file(&to_content) {
+            let from_text = String::from_utf8_lossy(&from_content);
+            let to_text = String::from_utf8_lossy(&to_content);
+
+            // Line-by-line diff
+            let diff_lines = compute_text_diff(&from_text, &to_text);
+
+            return Ok(VersionDiff {
+                from_version_id: from_version_id.to_string(),
+                to_version_id: to_version_id.to_string(),
+                diff_type: DiffType::Text,
+                text_diff: Some(diff_lines),
+                binary_diff: None,
+                size_change,
+            });
+        }
     }
+
+    // Binary or large file - just metadata
+    Ok(VersionDiff {
+        from_version_id: from_version_id.to_string(),
+        to_version_id: to_version_id.to_string(),
+        diff_type: DiffType::Binary,
+        text_diff: None,
+        binary_diff: Some(BinaryDiff {
+            from_size: from.original_size as usize,
+            to_size: to.original_size as usize,
+            checksum_changed: from.checksum != to.checksum,
+        }),
+        size_change,
+    })
 }
 
 /// Cleanup old versions (keep max 50, delete older than 90 days)
-pub async fn cleanup_old_versions(
-    pool: &SqlitePool,
-    file_id: &str,
-) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+pub async fn cleanup_old_versions(pool: &SqlitePool, file_id: &str) -> Result<usize, BoxError> {
     let mut deleted_count = 0;
 
-    // Keep max 50 versions
-    let versions_to_delete: Vec<(String, String)> = sqlx::query_as(
+    // Keep max 50 versions. Newest first, so deltas are deleted before
+    // their bases and don't need to be rewritten.
+    let versions_to_delete: Vec<String> = sqlx::query_scalar(
         r#"
-        SELECT id, storage_path FROM file_versions 
-        WHERE file_id = ? 
-        ORDER BY version_number DESC 
+        SELECT id FROM file_versions
+        WHERE file_id = ?
+        ORDER BY version_number DESC
         LIMIT -1 OFFSET ?
         "#,
     )
@@ -247,36 +546,27 @@ pub async fn cleanup_old_versions(
     .fetch_all(pool)
     .await?;
 
-    for (version_id, storage_path) in versions_to_delete {
-        // Delete file
-        let _ = tokio::fs::remove_file(&storage_path).await;
-
-        // Delete from database
-        sqlx::query("DELETE FROM file_versions WHERE id = ?")
-            .bind(&version_id)
-            .execute(pool)
-            .await?;
-
-        deleted_count += 1;
+    for version_id in versions_to_delete {
+        if delete_version(pool, &version_id).await? {
+            deleted_count += 1;
+        }
     }
 
     // Delete versions older than 90 days
     let cutoff = (Utc::now() - chrono::Duration::days(VERSION_RETENTION_DAYS)).to_rfc3339();
-    let old_versions: Vec<(String, String)> = sqlx::query_as(
-        "SELECT id, storage_path FROM file_versions WHERE file_id = ? AND created_at < ?",
+    let old_versions: Vec<String> = sqlx::query_scalar(
+        "SELECT id FROM file_versions WHERE file_id = ? AND created_at < ?
+         ORDER BY version_number DESC",
     )
     .bind(file_id)
     .bind(&cutoff)
     .fetch_all(pool)
     .await?;
 
-    for (version_id, storage_path) in old_versions {
-        let _ = tokio::fs::remove_file(&storage_path).await;
-        sqlx::query("DELETE FROM file_versions WHERE id = ?")
-            .bind(&version_id)
-            .execute(pool)
-            .await?;
-        deleted_count += 1;
+    for version_id in old_versions {
+        if delete_version(pool, &version_id).await? {
+            deleted_count += 1;
+        }
     }
 
     Ok(deleted_count)
@@ -298,100 +588,320 @@ pub async fn cleanup_all_old_versions(pool: &SqlitePool) -> Result<usize, sqlx::
     Ok(total_deleted)
 }
 
-// ==================== HELPER FUNCTIONS ====================
+// ==================== STREAMING STORAGE ====================
 
-fn calculate_checksum(data: &[u8]) -> String {
-    use sha2::{Digest, Sha256};
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    format!("{:x}", hasher.finalize())
+/// A delta written to version storage, with the statistics of the encoding
+struct WrittenDelta(StoredContent, delta::DeltaStats);
+
+impl WrittenDelta {
+    /// A delta that mostly carries literals is not worth the restore cost
+    fn is_worthwhile(&self) -> bool {
+        self.1.literal_bytes.saturating_mul(2) <= self.1.size
+    }
 }
 
-fn compress_data(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(data)?;
-    encoder.finish()
+/// Store `source` as a zstd-compressed delta against `base`
+async fn write_delta(
+    source: &Path,
+    base: &Signature,
+    dest: &Path,
+    block_size: u32,
+) -> Result<WrittenDelta, BoxError> {
+    let mut builder = SignatureBuilder::new(block_size)?;
+    let file = tokio::fs::File::open(source).await?;
+    let reader = InspectReader::new(file, |chunk: &[u8]| builder.update(chunk));
+
+    let out = BufWriter::new(tokio::fs::File::create(dest).await?);
+    let mut encoder = ZstdEncoder::with_quality(out, Level::Precise(ZSTD_LEVEL));
+    let stats = delta::encode_stream(base, reader, &mut encoder).await?;
+    encoder.shutdown().await?;
+
+    let content = StoredContent {
+        signature: builder.finish(),
+        stored_size: tokio::fs::metadata(dest).await?.len(),
+        is_compressed: true,
+    };
+    Ok(WrittenDelta(content, stats))
 }
 
-fn decompress_data(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    use std::io::Cursor;
-    let cursor = Cursor::new(data);
-    let mut decoder = flate2::read::GzDecoder::new(cursor);
-    let mut result = Vec::new();
-    decoder.read_to_end(&mut result)?;
-    Ok(result)
+/// Store a full copy of `source`, zstd-compressed if `compress` is set
+async fn write_full(
+    source: &Path,
+    dest: &Path,
+    compress: bool,
+    block_size: u32,
+) -> Result<StoredContent, BoxError> {
+    let mut builder = SignatureBuilder::new(block_size)?;
+    let file = tokio::fs::File::open(source).await?;
+    let mut reader = InspectReader::new(file, |chunk: &[u8]| builder.update(chunk));
+
+    let out = BufWriter::new(tokio::fs::File::create(dest).await?);
+    if compress {
+        let mut encoder = ZstdEncoder::with_quality(out, Level::Precise(ZSTD_LEVEL));
+        tokio::io::copy(&mut reader, &mut encoder).await?;
+        encoder.shutdown().await?;
+    } else {
+        let mut out = out;
+        tokio::io::copy(&mut reader, &mut out).await?;
+        out.shutdown().await?;
+    }
+    drop(reader);
+
+    Ok(StoredContent {
+        signature: builder.finish(),
+        stored_size: tokio::fs::metadata(dest).await?.len(),
+        is_compressed: compress,
+    })
 }
 
-async fn create_diff(
-    prev_version: &VersionMetadata,
-    new_content: &[u8],
-) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
-    // Load previous version content
-    let prev_content = tokio::fs::read(&prev_version.storage_path).await?;
-
-    // For text files, use line-based diff
-    if is_text_file(&prev_content) && is_text_file(new_content) {
-        let prev_text = String::from_utf8_lossy(&prev_content);
-        let new_text = String::from_utf8_lossy(new_content);
-
-        // Use similar crate for text diff
-        use similar::TextDiff;
-        let diff = TextDiff::from_lines(&prev_text, &new_text);
-
-        // Serialize diff operations as JSON
-        let mut operations = Vec::new();
-        for op in diff.iter_all_changes() {
-            operations.push(serde_json::json!({
-                "tag": format!("{:?}", op.tag()),
-                "value": op.value()
-            }));
-        }
+fn signature_path(storage_path: &str) -> String {
+    format!("{}.sig", storage_path)
+}
+
+async fn save_signature(path: &str, signature: &Signature) -> Result<(), BoxError> {
+    tokio::fs::write(path, serde_json::to_vec(signature)?).await?;
+    Ok(())
+}
 
-        let diff_json = serde_json::to_vec(&operations)?;
-        return Ok(diff_json);
+/// Signature of a stored version; `None` for block store and older versions
+async fn load_signature(storage_path: &str) -> Option<Signature> {
+    if block_store::manifest_id(storage_path).is_some() {
+        return None;
     }
+    let path = signature_path(storage_path);
+    if !crate::storage::fetch_local_file(Path::new(&path))
+        .await
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    let data = tokio::fs::read(&path).await.ok()?;
+    serde_json::from_slice(&data).ok()
+}
 
-    // For binary files, use simple RLE compression
-    let mut diff_data = Vec::new();
+/// Number of deltas that have to be applied to restore `version`
+async fn delta_chain_len(
+    pool: &SqlitePool,
+    version: &VersionMetadata,
+) -> Result<usize, sqlx::Error> {
+    let mut len = 0;
+    let mut base_id = version
+        .base_version_id
+        .clone()
+        .filter(|_| version.is_differential);
+    while let Some(id) = base_id {
+        len += 1;
+        if len >= MAX_DELTA_CHAIN {
+            break;
+        }
+        base_id = sqlx::query_scalar::<_, Option<String>>(
+            "SELECT base_version_id FROM file_versions WHERE id = ? AND is_differential = 1",
+        )
+        .bind(&id)
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+    }
+    Ok(len)
+}
 
-    // Simple byte-level diff: store changed byte ranges
-    let min_len = prev_content.len().min(new_content.len());
-    let mut i = 0;
+async fn stored_format(version: &VersionMetadata) -> std::io::Result<StoredFormat> {
+    if block_store::manifest_id(&version.storage_path).is_some() {
+        return Ok(StoredFormat::Blocks);
+    }
+    if !version.is_compressed {
+        return Ok(if version.is_differential {
+            StoredFormat::Legacy
+        } else {
+            StoredFormat::Raw
+        });
+    }
 
-    while i < min_len {
-        if prev_content[i] != new_content[i] {
-            // Find extent of change
-            let start = i;
-            while i < min_len && prev_content[i] != new_content[i] {
-                i += 1;
+    let mut magic = [0u8; 4];
+    let mut file = tokio::fs::File::open(&version.storage_path).await?;
+    let is_zstd = file.read_exact(&mut magic).await.is_ok() && magic == ZSTD_MAGIC;
+    Ok(match (is_zstd, version.is_differential) {
+        (true, true) => StoredFormat::ZstdDelta,
+        (true, false) => StoredFormat::Zstd,
+        (false, _) => StoredFormat::Legacy,
+    })
+}
+
+/// Write the content of `version` to `out`, recursing into delta bases
+fn write_version<'a>(
+    pool: &'a SqlitePool,
+    version: &'a VersionMetadata,
+    mut out: &'a mut (dyn AsyncWrite + Unpin + Send),
+) -> Pin<Box<dyn Future<Output = Result<u64, BoxError>> + Send + 'a>> {
+    Box::pin(async move {
+        let storage_path = Path::new(&version.storage_path);
+        crate::storage::fetch_local_file(storage_path).await?;
+
+        let (written, checksum) = match stored_format(version).await? {
+            StoredFormat::Blocks => {
+                let manifest_id = block_store::manifest_id(&version.storage_path)
+                    .ok_or_else(|| format!("version {} is not in the block store", version.id))?;
+                let blocks = block_store::open_manifest(pool, manifest_id)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                copy_hashed(blocks, out).await?
+            }
+            StoredFormat::Raw => {
+                let file = tokio::fs::File::open(storage_path).await?;
+                copy_hashed(file, out).await?
             }
+            StoredFormat::Zstd => {
+                let file = tokio::fs::File::open(storage_path).await?;
+                copy_hashed(ZstdDecoder::new(BufReader::new(file)), out).await?
+            }
+            StoredFormat::ZstdDelta => {
+                let base_id = version
+                    .base_version_id
+                    .as_deref()
+                    .ok_or_else(|| format!("version {} has no base", version.id))?;
+                let base = get_version_metadata(pool, base_id).await?;
+
+                let (base_file, mut base_out) = TempFile::create().await?;
+                write_version(pool, &base, &mut base_out).await?;
+                drop(base_out);
+
+                let file = tokio::fs::File::open(storage_path).await?;
+                let stats = delta::apply(
+                    &base_file.0,
+                    ZstdDecoder::new(BufReader::new(file)),
+                    &mut out,
+                    version.original_size as u64,
+                )
+                .await?;
+                (stats.size, stats.checksum)
+            }
+            StoredFormat::Legacy => {
+                let content = read_legacy_version(pool, version).await?;
+                out.write_all(&content).await?;
+                out.flush().await?;
+                return Ok(content.len() as u64);
+            }
+        };
 
-            // Store: [operation: 1=change] [start: u32] [length: u32] [data]
-            diff_data.push(1u8); // Change operation
-            diff_data.extend_from_slice(&(start as u32).to_le_bytes());
-            diff_data.extend_from_slice(&((i - start) as u32).to_le_bytes());
-            diff_data.extend_from_slice(&new_content[start..i]);
-        } else {
-            i += 1;
+        if checksum != version.checksum {
+            return Err(format!(
+                "version {} failed verification: expected {}, got {}",
+                version.id, version.checksum, checksum
+            )
+            .into());
+        }
+        Ok(written)
+    })
+}
+
+/// Copy `reader` to `out`, returning the bytes copied and their SHA-256
+async fn copy_hashed<R>(
+    mut reader: R,
+    out: &mut (dyn AsyncWrite + Unpin + Send),
+) -> std::io::Result<(u64, String)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; IO_BUFFER_SIZE];
+    let mut written = 0u64;
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        out.write_all(&buffer[..read]).await?;
+        written += read as u64;
+    }
+    out.flush().await?;
+    Ok((written, format!("{:x}", hasher.finalize())))
+}
+
+/// Rewrite the versions stored as a delta against `version` as full copies
+async fn detach_dependents(pool: &SqlitePool, version: &VersionMetadata) -> Result<(), BoxError> {
+    let dependents: Vec<String> = sqlx::query_scalar(
+        "SELECT id FROM file_versions WHERE base_version_id = ? AND is_differential = 1",
+    )
+    .bind(&version.id)
+    .fetch_all(pool)
+    .await?;
+
+    for dependent_id in dependents {
+        let dependent = get_version_metadata(pool, &dependent_id).await?;
+        let full_path = format!("{}.full", dependent.storage_path);
+
+        let out = BufWriter::new(tokio::fs::File::create(&full_path).await?);
+        let mut encoder = ZstdEncoder::with_quality(out, Level::Precise(ZSTD_LEVEL));
+        let written = write_version(pool, &dependent, &mut encoder).await;
+        let written = match written {
+            Ok(_) => encoder.shutdown().await.map_err(BoxError::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&full_path).await;
+            return Err(e);
+        }
+
+        let stored_size = tokio::fs::metadata(&full_path).await?.len() as i64;
+        tokio::fs::rename(&full_path, &dependent.storage_path).await?;
+        sqlx::query(
+            "UPDATE file_versions
+             SET is_compressed = 1, is_differential = 0, base_version_id = NULL, compressed_size = ?
+             WHERE id = ?",
+        )
+        .bind(stored_size)
+        .bind(&dependent.id)
+        .execute(pool)
+        .await?;
+
+        if let Err(e) = crate::storage::mirror_local_file(Path::new(&dependent.storage_path)).await
+        {
+            tracing::warn!(
+                "Failed to mirror version {} to storage backend: {}",
+                dependent.id,
+                e
+            );
         }
     }
 
-    // If new file is longer, append the rest
-    if new_content.len() > prev_content.len() {
-        diff_data.push(2u8); // Append operation
-        diff_data.extend_from_slice(&(prev_content.len() as u32).to_le_bytes());
-        diff_data
-            .extend_from_slice(&((new_content.len() - prev_content.len()) as u32).to_le_bytes());
-        diff_data.extend_from_slice(&new_content[prev_content.len()..]);
+    Ok(())
+}
+
+/// Read a version written before versions were streamed
+async fn read_legacy_version(
+    pool: &SqlitePool,
+    version: &VersionMetadata,
+) -> Result<Vec<u8>, BoxError> {
+    let mut stored_content = tokio::fs::read(&version.storage_path).await?;
+
+    // Decompress if needed
+    if version.is_compressed {
+        stored_content = decompress_data(&stored_content)?;
     }
 
-    // If new file is shorter, record truncation
-    if new_content.len() < prev_content.len() {
-        diff_data.push(3u8); // Truncate operation
-        diff_data.extend_from_slice(&(new_content.len() as u32).to_le_bytes());
+    // Apply diff if needed
+    if version.is_differential
+        && let Some(base_id) = &version.base_version_id
+    {
+        let base = get_version_metadata(pool, base_id).await?;
+        let mut base_content = Vec::new();
+        write_version(pool, &base, &mut base_content).await?;
+        stored_content = apply_diff(&base_content, &stored_content)?;
     }
 
-    Ok(diff_data)
+    Ok(stored_content)
+}
+
+// ==================== HELPER FUNCTIONS ====================
+
+fn decompress_data(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    use std::io::Cursor;
+    let cursor = Cursor::new(data);
+    let mut decoder = flate2::read::GzDecoder::new(cursor);
+    let mut result = Vec::new();
+    decoder.read_to_end(&mut result)?;
+    Ok(result)
 }
 
 fn apply_diff(
@@ -405,14 +915,14 @@ fn apply_diff(
 
         for op in &operations {
             // Borrow operations instead of consuming
-            if let Some(tag) = op.get("tag").and_then(|v| v.as_str()) {
-                if let Some(value) = op.get("value").and_then(|v| v.as_str()) {
-                    match tag {
-                        "\"Equal\"" | "Equal" => result.push_str(value),
-                        "\"Insert\"" | "Insert" => result.push_str(value),
-                        // Skip Delete operations
-                        _ => {}
-                    }
+            if let Some(tag) = op.get("tag").and_then(|v| v.as_str())
+                && let Some(value) = op.get("value").and_then(|v| v.as_str())
+            {
+                match tag {
+                    "\"Equal\"" | "Equal" => result.push_str(value),
+                    "\"Insert\"" | "Insert" => result.push_str(value),
+                    // Skip Delete operations
+                    _ => {}
                 }
             }
         }
@@ -515,7 +1025,7 @@ fn apply_diff(
 
 fn is_text_file(content: &[u8]) -> bool {
     // Check if content is valid UTF-8
-    String::from_utf8(content.to_vec()).is_ok()
+    std::str::from_utf8(content).is_ok()
 }
 
 fn compute_text_diff(from: &str, to: &str) -> Vec<DiffLine> {
@@ -561,7 +1071,7 @@ async fn get_previous_version(
     pool: &SqlitePool,
     file_id: &str,
 ) -> Result<Option<VersionMetadata>, sqlx::Error> {
-    let row: Option<(String, i32, String, i64, i64, i32, i32, Option<String>, String, String, String, Option<String>)> = sqlx::query_as(
+    let row: Option<VersionRow> = sqlx::query_as(
         r#"
         SELECT id, version_number, storage_path, original_size, compressed_size,
                is_compressed, is_differential, base_version_id, checksum, created_by, created_at, comment
@@ -613,7 +1123,16 @@ async fn get_version_metadata(
     pool: &SqlitePool,
     version_id: &str,
 ) -> Result<VersionMetadata, sqlx::Error> {
-    let row: (String, i32, String, i64, i64, i32, i32, Option<String>, String, String, String, Option<String>) = sqlx::query_as(
+    find_version_metadata(pool, version_id)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)
+}
+
+async fn find_version_metadata(
+    pool: &SqlitePool,
+    version_id: &str,
+) -> Result<Option<VersionMetadata>, sqlx::Error> {
+    let row: Option<VersionRow> = sqlx::query_as(
         r#"
         SELECT file_id, version_number, storage_path, original_size, compressed_size,
                is_compressed, is_differential, base_version_id, checksum, created_by, created_at, comment
@@ -622,10 +1141,10 @@ async fn get_version_metadata(
         "#
     )
     .bind(version_id)
-    .fetch_one(pool)
+    .fetch_optional(pool)
     .await?;
 
-    Ok(VersionMetadata {
+    Ok(row.map(|row| VersionMetadata {
         id: version_id.to_string(),
         file_id: row.0,
         version_number: row.1,
@@ -639,7 +1158,7 @@ async fn get_version_metadata(
         created_by: row.9,
         created_at: row.10,
         comment: row.11,
-    })
+    }))
 }
 
 // ==================== PUBLIC TYPES ====================
@@ -0,0 +1,762 @@
+//! Workflow actions
+//!
+//! Runs the actions of workflow rules (see `crate::workflow`) as the rule's
+//! creator, through the same services the API uses. File operations announce
+//! their changes to the engine first so that the resulting events carry the
+//! workflow as their origin.
+
+use axum::http::StatusCode;
+use chrono::Utc;
+use serde_json::{Value, json};
+use std::path::Path;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::auth::UserInfo;
+use crate::jobs::queue::JobQueue;
+use crate::jobs::types::{Job, JobType};
+use crate::webdav::xml::{PropName, PropUpdate};
+use crate::workflow::plugins::{self, PluginError};
+use crate::workflow::{
+    ActionError, ActionOutput, ActionRunner, ActionStep, Trigger, WorkflowEvent,
+};
+
+const DATA_DIR: &str = "./data";
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time allowed past a plugin's own time limit
+const PLUGIN_GRACE: Duration = Duration::from_secs(5);
+
+pub struct AppActionRunner {
+    state: AppState,
+}
+
+impl AppActionRunner {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+
+    async fn rule_user(&self, step: &ActionStep<'_>) -> Result<UserInfo, ActionError> {
+        let user = crate::auth::get_user_by_id(&self.state.db_pool, &step.rule.created_by)
+            .await
+            .map_err(|e| ActionError::retryable(format!("Database error: {}", e)))?
+            .ok_or_else(|| ActionError::fatal("The rule's owner no longer exists"))?;
+        Ok(UserInfo {
+            id: user.id,
+            username: user.username,
+            totp_enabled: user.totp_enabled,
+            role: user.role,
+            is_admin: user.is_admin,
+        })
+    }
+
+    async fn convert_file(
+        &self,
+        user: &UserInfo,
+        step: &ActionStep<'_>,
+    ) -> Result<ActionOutput, ActionError> {
+        let path = require_path(step)?;
+        let target_format = string_option(step, "target_format")?
+            .ok_or_else(|| ActionError::fatal("target_format is required"))?
+            .to_lowercase();
+        let source_format = extension(path).unwrap_or_else(|| "unknown".to_string());
+        let options = step
+            .action
+            .config
+            .get("options")
+            .cloned()
+            .unwrap_or_else(|| json!({}));
+
+        // The conversion worker picks the job up and writes the output file
+        let job_id = Uuid::new_v4().to_string();
+        sqlx::query(
+            "INSERT INTO conversion_jobs
+             (id, user_id, source_file_path, source_format, target_format, conversion_options, status, progress)
+             VALUES (?, ?, ?, ?, ?, ?, 'pending', 0)",
+        )
+        .bind(&job_id)
+        .bind(&user.id)
+        .bind(path)
+        .bind(&source_format)
+        .bind(&target_format)
+        .bind(options.to_string())
+        .execute(&self.state.db_pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(ActionOutput::new(json!({
+            "conversion_job_id": job_id,
+            "target_format": target_format,
+        })))
+    }
+
+    async fn compress_file(
+        &self,
+        user: &UserInfo,
+        step: &ActionStep<'_>,
+    ) -> Result<ActionOutput, ActionError> {
+        let path = require_path(step)?.to_string();
+        let quality = step
+            .action
+            .config
+            .get("quality")
+            .and_then(Value::as_u64)
+            .unwrap_or(85)
+            .clamp(1, 100) as u8;
+        let keep_original = bool_option(step, "keep_original", false);
+        let format = match extension(&path).as_deref() {
+            Some("jpg" | "jpeg") => image::ImageFormat::Jpeg,
+            Some("png") => image::ImageFormat::Png,
+            other => {
+                return Err(ActionError::fatal(format!(
+                    "Cannot compress .{} files, only JPEG and PNG",
+                    other.unwrap_or_default()
+                )));
+            }
+        };
+
+        let source = Path::new(DATA_DIR).join(&path);
+        let data = tokio::fs::read(&source)
+            .await
+            .map_err(|e| ActionError::fatal(format!("Cannot read {}: {}", path, e)))?;
+        let original_size = data.len();
+        let compressed = tokio::task::spawn_blocking(move || encode_image(&data, format, quality))
+            .await
+            .map_err(|e| ActionError::retryable(e.to_string()))??;
+
+        if compressed.len() >= original_size {
+            return Ok(ActionOutput::new(json!({
+                "original_size": original_size,
+                "compressed_size": compressed.len(),
+                "replaced": false,
+            })));
+        }
+
+        let target = if keep_original {
+            compressed_name(&path)
+        } else {
+            path.clone()
+        };
+        let target_file = Path::new(DATA_DIR).join(&target);
+        let temp_file = target_file.with_extension("workflow-tmp");
+        tokio::fs::write(&temp_file, &compressed)
+            .await
+            .map_err(|e| ActionError::retryable(e.to_string()))?;
+        if let Some(engine) = crate::workflow::engine() {
+            engine.expect_change(&target, step.origin.clone());
+        }
+        if let Err(e) = tokio::fs::rename(&temp_file, &target_file).await {
+            let _ = tokio::fs::remove_file(&temp_file).await;
+            return Err(ActionError::retryable(e.to_string()));
+        }
+        self.sync(user, vec![target.clone()]).await;
+
+        let output = ActionOutput::new(json!({
+            "original_size": original_size,
+            "compressed_size": compressed.len(),
+            "replaced": !keep_original,
+            "output_path": target,
+        }));
+        Ok(if keep_original {
+            output.moved_to(target)
+        } else {
+            output
+        })
+    }
+
+    async fn send_notification(
+        &self,
+        user: &UserInfo,
+        step: &ActionStep<'_>,
+    ) -> Result<ActionOutput, ActionError> {
+        // Only admins may notify someone other than themselves
+        let recipient = match string_option(step, "user_id")? {
+            Some(recipient) if user.is_admin => recipient.to_string(),
+            Some(recipient) if recipient != user.id => {
+                return Err(ActionError::fatal("Only admins can notify other users"));
+            }
+            _ => user.id.clone(),
+        };
+        let notification_type = string_option(step, "notification_type")?.unwrap_or("workflow");
+        let title = render(string_option(step, "title")?.unwrap_or("{rule}"), step);
+        let message = render(
+            string_option(step, "message")?.unwrap_or("{name} was processed by {rule}"),
+            step,
+        );
+
+        let id = self
+            .insert_notification(&recipient, notification_type, &title, &message, step.path)
+            .await?;
+
+        Ok(ActionOutput::new(json!({
+            "notification_id": id,
+            "user_id": recipient,
+        })))
+    }
+
+    async fn insert_notification(
+        &self,
+        recipient: &str,
+        notification_type: &str,
+        title: &str,
+        message: &str,
+        path: Option<&str>,
+    ) -> Result<String, ActionError> {
+        let id = Uuid::new_v4().to_string();
+        sqlx::query(
+            "INSERT INTO notifications (id, user_id, type, title, message, action_url, is_read, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, 0, ?)",
+        )
+        .bind(&id)
+        .bind(recipient)
+        .bind(notification_type)
+        .bind(title)
+        .bind(message)
+        .bind(path.map(|path| format!("/files/{}", path)))
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.state.db_pool)
+        .await
+        .map_err(database_error)?;
+        Ok(id)
+    }
+
+    async fn add_tag(
+        &self,
+        user: &UserInfo,
+        step: &ActionStep<'_>,
+    ) -> Result<ActionOutput, ActionError> {
+        let path = require_path(step)?;
+        let config = &step.action.config;
+        let mut names: Vec<String> = config
+            .get("tags")
+            .and_then(Value::as_array)
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(tag) = config.get("tag").and_then(Value::as_str) {
+            names.push(tag.to_string());
+        }
+        names.retain(|name| !name.trim().is_empty());
+        if names.is_empty() {
+            return Err(ActionError::fatal("tag or tags is required"));
+        }
+        let color = config.get("color").and_then(Value::as_str);
+
+        self.tag(user, step, path, &names, color).await?;
+        Ok(ActionOutput::new(json!({ "tags": names })))
+    }
+
+    async fn tag(
+        &self,
+        user: &UserInfo,
+        step: &ActionStep<'_>,
+        path: &str,
+        names: &[String],
+        color: Option<&str>,
+    ) -> Result<(), ActionError> {
+        let pool = &self.state.db_pool;
+        let now = Utc::now().to_rfc3339();
+        for name in names {
+            sqlx::query(
+                "INSERT OR IGNORE INTO tags (id, name, color, owner_id, created_at, updated_at)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+            )
+            .bind(Uuid::new_v4().to_string())
+            .bind(name)
+            .bind(color)
+            .bind(&user.id)
+            .bind(&now)
+            .bind(&now)
+            .execute(pool)
+            .await
+            .map_err(database_error)?;
+            let added = sqlx::query(
+                "INSERT OR IGNORE INTO file_tags (id, file_id, tag_id, item_type, file_path, tagged_by, created_at)
+                 SELECT ?, ?, id, 'file', ?, ?, ? FROM tags WHERE owner_id = ? AND name = ?",
+            )
+            .bind(Uuid::new_v4().to_string())
+            .bind(path)
+            .bind(path)
+            .bind(&user.id)
+            .bind(&now)
+            .bind(&user.id)
+            .bind(name)
+            .execute(pool)
+            .await
+            .map_err(database_error)?
+            .rows_affected();
+
+            if added > 0 {
+                crate::workflow::notify(
+                    WorkflowEvent::new(Trigger::FileTag, Some(path.to_string()))
+                        .by_user(&user.id)
+                        .with_context(json!({ "tag": name }))
+                        .caused_by(step.origin.clone()),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn move_or_copy(
+        &self,
+        user: &UserInfo,
+        step: &ActionStep<'_>,
+        copy: bool,
+    ) -> Result<ActionOutput, ActionError> {
+        let path = require_path(step)?;
+        let folder = string_option(step, "target_path")?
+            .ok_or_else(|| ActionError::fatal("target_path is required"))?;
+        let folder = crate::security::validate_file_path(folder)
+            .map_err(|_| ActionError::fatal("Invalid target_path"))?;
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let mut destination = join(&folder, name);
+        if destination == path {
+            return Ok(ActionOutput::new(json!({ "unchanged": true })));
+        }
+
+        let source = Path::new(DATA_DIR).join(path);
+        if !tokio::fs::try_exists(&source).await.unwrap_or(false) {
+            return Err(ActionError::fatal(format!("{} does not exist", path)));
+        }
+        let folder_dir = Path::new(DATA_DIR).join(&folder);
+        if !bool_option(step, "create_folders", true)
+            && !tokio::fs::try_exists(&folder_dir).await.unwrap_or(false)
+        {
+            return Err(ActionError::fatal(format!(
+                "Folder {} does not exist",
+                folder
+            )));
+        }
+
+        match string_option(step, "conflict")?.unwrap_or("rename") {
+            "overwrite" => {}
+            "skip" if exists(&destination).await => {
+                return Ok(ActionOutput::new(json!({ "skipped": destination })));
+            }
+            "skip" => {}
+            "rename" => destination = free_name(&folder, name).await,
+            other => {
+                return Err(ActionError::fatal(format!(
+                    "Unknown conflict policy '{}'",
+                    other
+                )));
+            }
+        }
+        if let Some(engine) = crate::workflow::engine() {
+            engine.expect_change(&destination, step.origin.clone());
+        }
+        let result = if copy {
+            crate::services::copy_file(&self.state, user, path, &destination).await
+        } else {
+            crate::services::move_file(&self.state, user, path, &destination).await
+        };
+        result.map_err(|e| ActionError::retryable(e.to_string()))?;
+
+        if copy {
+            Ok(ActionOutput::new(json!({ "copied_to": destination })))
+        } else {
+            Ok(ActionOutput::new(json!({ "moved_to": destination })).moved_to(destination))
+        }
+    }
+
+    async fn delete_file(
+        &self,
+        user: &UserInfo,
+        step: &ActionStep<'_>,
+    ) -> Result<ActionOutput, ActionError> {
+        let path = require_path(step)?;
+        if let Some(engine) = crate::workflow::engine() {
+            engine.expect_change(path, step.origin.clone());
+        }
+        crate::services::delete_file(&self.state, user, path)
+            .await
+            .map_err(|e| ActionError::fatal(e.to_string()))?;
+        Ok(ActionOutput::new(json!({ "deleted": path })))
+    }
+
+    async fn send_webhook(&self, step: &ActionStep<'_>) -> Result<ActionOutput, ActionError> {
+        let url = string_option(step, "url")?
+            .or(string_option(step, "webhook_url")?)
+            .ok_or_else(|| ActionError::fatal("url is required"))?;
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(ActionError::fatal("url must be http or https"));
+        }
+        let method = string_option(step, "method")?.unwrap_or("POST");
+        let method = reqwest::Method::from_bytes(method.to_uppercase().as_bytes())
+            .map_err(|_| ActionError::fatal(format!("Invalid method '{}'", method)))?;
+
+        let payload = json!({
+            "event": "workflow.action",
+            "timestamp": Utc::now().to_rfc3339(),
+            "data": {
+                "rule_id": step.rule.id,
+                "rule_name": step.rule.name,
+                "execution_id": step.execution_id,
+                "file_path": step.path,
+                "trigger": step.event,
+            }
+        });
+        let body = serde_json::to_string(&payload).unwrap_or_default();
+
+        let mut request = reqwest::Client::new()
+            .request(method, url)
+            .header("Content-Type", "application/json")
+            .header("User-Agent", "SyncSpace-Webhook/1.0")
+            .header("X-Webhook-Event", "workflow.action")
+            .header("X-Webhook-Delivery", step.execution_id)
+            .timeout(WEBHOOK_TIMEOUT);
+        if let Some(headers) = step.action.config.get("headers").and_then(Value::as_object) {
+            for (name, value) in headers {
+                if let Some(value) = value.as_str() {
+                    request = request.header(name.as_str(), value);
+                }
+            }
+        }
+        if let Some(secret) = string_option(step, "secret")? {
+            use hmac::{Hmac, Mac};
+            use sha2::Sha256;
+
+            type HmacSha256 = Hmac<Sha256>;
+
+            let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+                .expect("HMAC can take key of any size");
+            mac.update(body.as_bytes());
+            let signature = hex::encode(mac.finalize().into_bytes());
+            request = request.header("X-Webhook-Signature", format!("sha256={}", signature));
+        }
+
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| ActionError::retryable(format!("Webhook request failed: {}", e)))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(ActionOutput::new(json!({ "status_code": status.as_u16() })));
+        }
+        let message = format!("Webhook returned {}", status);
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(ActionError::retryable(message))
+        } else {
+            Err(ActionError::fatal(message))
+        }
+    }
+
+    async fn send_email(
+        &self,
+        user: &UserInfo,
+        step: &ActionStep<'_>,
+    ) -> Result<ActionOutput, ActionError> {
+        let config = &step.action.config;
+        let recipients: Vec<&str> = match config.get("to").or(config.get("email_to")) {
+            Some(Value::String(to)) => vec![to.as_str()],
+            Some(Value::Array(to)) => to.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if recipients.is_empty() {
+            return Err(ActionError::fatal("to is required"));
+        }
+        let subject = render(
+            string_option(step, "subject")?.unwrap_or("{rule}: {name}"),
+            step,
+        );
+        let body = render(
+            string_option(step, "body")?.unwrap_or("{path} was processed by {rule}."),
+            step,
+        );
+
+        let queue = JobQueue::new(std::sync::Arc::new(self.state.db_pool.clone()));
+        let mut job_ids = Vec::new();
+        for to in recipients {
+            let job = Job::new(
+                JobType::EmailNotification {
+                    to: to.to_string(),
+                    subject: subject.clone(),
+                    body: body.clone(),
+                },
+                Some(user.id.clone()),
+            )
+            .map_err(|e| ActionError::fatal(e.to_string()))?;
+            let job = queue.enqueue(job).await.map_err(database_error)?;
+            job_ids.push(job.id);
+        }
+        Ok(ActionOutput::new(json!({ "email_jobs": job_ids })))
+    }
+
+    async fn run_script(
+        &self,
+        user: &UserInfo,
+        step: &ActionStep<'_>,
+    ) -> Result<ActionOutput, ActionError> {
+        let name = string_option(step, "plugin")?
+            .ok_or_else(|| ActionError::fatal("plugin is required"))?;
+        let (plugin, module) = plugins::load(&self.state.db_pool, name)
+            .await
+            .map_err(database_error)?
+            .ok_or_else(|| ActionError::fatal(format!("Plugin '{}' is not registered", name)))?;
+        if !plugin.is_active {
+            return Err(ActionError::fatal(format!("Plugin '{}' is disabled", name)));
+        }
+
+        let input = json!({
+            "file_path": step.path,
+            "event": step.event,
+            "rule": { "id": step.rule.id, "name": step.rule.name },
+            "config": step.action.config.get("config").cloned().unwrap_or_else(|| json!({})),
+        });
+        let limits = plugin.limits();
+        // The runtime stops plugins near the deadline, also between host
+        // calls; this only covers a runtime that overshoots
+        let deadline = limits.timeout + PLUGIN_GRACE;
+        let source = step.path.map(|path| Path::new(DATA_DIR).join(path));
+        let task = tokio::task::spawn_blocking(move || {
+            plugins::run(&module, &limits, &input, source.as_deref())
+        });
+        let output = tokio::time::timeout(deadline, task)
+            .await
+            .map_err(|_| ActionError::fatal(format!("Plugin '{}' did not finish in time", name)))?
+            .map_err(|e| ActionError::retryable(e.to_string()))?
+            .map_err(|e| match e {
+                PluginError::Failed {
+                    retryable: true, ..
+                } => ActionError::retryable(e.to_string()),
+                e => ActionError::fatal(e.to_string()),
+            })?;
+
+        // Derived files go next to the file, or to the root for rules without one
+        let folder = step
+            .path
+            .and_then(|path| path.rsplit_once('/'))
+            .map_or("", |(folder, _)| folder);
+        let mut written = Vec::new();
+        for (file_name, content) in &output.files {
+            let target = free_name(folder, file_name).await;
+            if let Some(engine) = crate::workflow::engine() {
+                engine.expect_change(&target, step.origin.clone());
+            }
+            tokio::fs::write(Path::new(DATA_DIR).join(&target), content)
+                .await
+                .map_err(|e| ActionError::retryable(e.to_string()))?;
+            written.push(target);
+        }
+        if !written.is_empty() {
+            self.sync(user, written.clone()).await;
+        }
+
+        let mut renamed_to = None;
+        if let (Some(current), Some(new_name)) = (step.path, &output.rename)
+            && join(folder, new_name) != current
+        {
+            let target = free_name(folder, new_name).await;
+            if let Some(engine) = crate::workflow::engine() {
+                engine.expect_change(&target, step.origin.clone());
+            }
+            crate::services::rename_file(&self.state, user, current, &target)
+                .await
+                .map_err(|e| ActionError::retryable(e.to_string()))?;
+            renamed_to = Some(target);
+        }
+        let path = renamed_to.clone().or(step.path.map(str::to_string));
+
+        if let Some(path) = &path {
+            if !output.tags.is_empty() {
+                self.tag(user, step, path, &output.tags, None).await?;
+            }
+            if !output.metadata.is_empty() {
+                let updates: Vec<PropUpdate> = output
+                    .metadata
+                    .iter()
+                    .map(|(key, value)| {
+                        PropUpdate::Set(
+                            PropName::new(plugins::METADATA_NAMESPACE, key),
+                            crate::webdav::xml::escape(value).into_owned(),
+                        )
+                    })
+                    .collect();
+                crate::webdav::props::apply(&self.state.db_pool, path, &updates)
+                    .await
+                    .map_err(database_error)?;
+            }
+        }
+        for (title, message) in &output.notifications {
+            self.insert_notification(&user.id, "workflow", title, message, path.as_deref())
+                .await?;
+        }
+
+        let detail = json!({
+            "plugin": plugin.name,
+            "fuel_used": output.fuel_used,
+            "files": written,
+            "renamed_to": renamed_to,
+            "tags": output.tags,
+            "metadata": output.metadata.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            "notifications": output.notifications.len(),
+            "logs": output.logs,
+        });
+        Ok(match renamed_to {
+            Some(target) => ActionOutput::new(detail).moved_to(target),
+            None => ActionOutput::new(detail),
+        })
+    }
+
+    /// Bring the database and search index up to date with changed files
+    async fn sync(&self, user: &UserInfo, paths: Vec<String>) {
+        if let Err(e) = crate::services::fs_watcher::sync_paths(
+            self.state.db_pool.clone(),
+            self.state.search_index.clone(),
+            self.state.fs_tx.clone(),
+            &user.id,
+            Vec::new(),
+            paths,
+        )
+        .await
+        {
+            tracing::warn!("Failed to sync workflow output: {}", e);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ActionRunner for AppActionRunner {
+    async fn run(&self, step: &ActionStep<'_>) -> Result<ActionOutput, ActionError> {
+        let user = self.rule_user(step).await?;
+        match step.action.kind.as_str() {
+            "convert_file" => self.convert_file(&user, step).await,
+            "compress_file" => self.compress_file(&user, step).await,
+            "send_notification" => self.send_notification(&user, step).await,
+            "add_tag" => self.add_tag(&user, step).await,
+            "move_file" => self.move_or_copy(&user, step, false).await,
+            "copy_file" => self.move_or_copy(&user, step, true).await,
+            "delete_file" => self.delete_file(&user, step).await,
+            "send_webhook" => self.send_webhook(step).await,
+            "send_email" => self.send_email(&user, step).await,
+            "run_script" => self.run_script(&user, step).await,
+            other => Err(ActionError::fatal(format!("Unknown action '{}'", other))),
+        }
+    }
+}
+
+fn database_error(e: sqlx::Error) -> ActionError {
+    ActionError::retryable(format!("Database error: {}", e))
+}
+
+fn require_path<'a>(step: &ActionStep<'a>) -> Result<&'a str, ActionError> {
+    step.path
+        .ok_or_else(|| ActionError::fatal(format!("{} needs a file", step.action.kind)))
+}
+
+fn string_option<'a>(step: &ActionStep<'a>, key: &str) -> Result<Option<&'a str>, ActionError> {
+    match step.action.config.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.as_str())),
+        Some(_) => Err(ActionError::fatal(format!("{} must be a string", key))),
+    }
+}
+
+fn bool_option(step: &ActionStep<'_>, key: &str, default: bool) -> bool {
+    step.action
+        .config
+        .get(key)
+        .and_then(Value::as_bool)
+        .unwrap_or(default)
+}
+
+/// Fill in `{path}`, `{name}`, `{rule}` and `{event}`, then `{key}` for the
+/// text and numbers in the event context, such as mapped webhook values
+fn render(template: &str, step: &ActionStep<'_>) -> String {
+    let path = step.path.unwrap_or_default();
+    let mut text = template
+        .replace("{path}", path)
+        .replace("{name}", path.rsplit('/').next().unwrap_or(path))
+        .replace("{rule}", &step.rule.name)
+        .replace("{event}", step.event.trigger.as_str());
+    if let Some(context) = step.event.context.as_object() {
+        for (key, value) in context {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Number(_) | Value::Bool(_) => value.to_string(),
+                _ => continue,
+            };
+            text = text.replace(&format!("{{{}}}", key), &value);
+        }
+    }
+    text
+}
+
+fn extension(path: &str) -> Option<String> {
+    Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase)
+}
+
+fn join(folder: &str, name: &str) -> String {
+    if folder.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", folder.trim_end_matches('/'), name)
+    }
+}
+
+async fn exists(path: &str) -> bool {
+    tokio::fs::try_exists(Path::new(DATA_DIR).join(path))
+        .await
+        .unwrap_or(false)
+}
+
+/// `name` in `folder`, or `stem (n).ext` if that is taken
+async fn free_name(folder: &str, name: &str) -> String {
+    let candidate = join(folder, name);
+    if !exists(&candidate).await {
+        return candidate;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    for n in 1.. {
+        let candidate = join(folder, &format!("{} ({}){}", stem, n, ext));
+        if !exists(&candidate).await {
+            return candidate;
+        }
+    }
+    unreachable!()
+}
+
+/// `photo.jpg` -> `photo.compressed.jpg`
+fn compressed_name(path: &str) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, ext)) => format!("{}.compressed.{}", stem, ext),
+        None => format!("{}.compressed", path),
+    }
+}
+
+fn encode_image(
+    data: &[u8],
+    format: image::ImageFormat,
+    quality: u8,
+) -> Result<Vec<u8>, ActionError> {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+
+    let image = image::load_from_memory_with_format(data, format)
+        .map_err(|e| ActionError::fatal(format!("Cannot decode image: {}", e)))?;
+    let mut output = Vec::new();
+    let result = match format {
+        image::ImageFormat::Jpeg => {
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality))
+        }
+        _ => image.write_with_encoder(PngEncoder::new_with_quality(
+            &mut output,
+            CompressionType::Best,
+            FilterType::Adaptive,
+        )),
+    };
+    result.map_err(|e| ActionError::fatal(format!("Cannot encode image: {}", e)))?;
+    Ok(output)
+}
@@ -0,0 +1,130 @@
+//! Local filesystem storage backend
+
+use super::{DATA_DIR, ObjectMeta, StorageBackend, temp_path_for, validate_key};
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+pub struct LocalBackend {
+    id: String,
+    root: PathBuf,
+    working_tree: bool,
+}
+
+impl LocalBackend {
+    pub fn new(id: &str, root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        let working_tree = same_path(&root, Path::new(DATA_DIR));
+        Self {
+            id: id.to_string(),
+            root,
+            working_tree,
+        }
+    }
+
+    fn object_path(&self, key: &str) -> Result<PathBuf> {
+        validate_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => {
+            let normalize = |p: &Path| {
+                p.to_string_lossy()
+                    .trim_start_matches("./")
+                    .trim_end_matches('/')
+                    .to_string()
+            };
+            normalize(a) == normalize(b)
+        }
+    }
+}
+
+/// Copy into place through a temporary file so readers never see a partial object
+async fn copy_atomic(source: &Path, dest: &Path) -> Result<u64> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = temp_path_for(dest);
+    let copied = match fs::copy(source, &tmp_path).await {
+        Ok(copied) => copied,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(e.into());
+        }
+    };
+    fs::rename(&tmp_path, dest).await?;
+    Ok(copied)
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "local"
+    }
+
+    fn is_working_tree(&self) -> bool {
+        self.working_tree
+    }
+
+    async fn put_file(&self, key: &str, source: &Path) -> Result<u64> {
+        let dest = self.object_path(key)?;
+        if same_path(source, &dest) {
+            return Ok(fs::metadata(&dest).await?.len());
+        }
+        copy_atomic(source, &dest).await
+    }
+
+    async fn put_bytes(&self, key: &str, data: Bytes) -> Result<()> {
+        let dest = self.object_path(key)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let tmp_path = temp_path_for(&dest);
+        fs::write(&tmp_path, &data).await?;
+        fs::rename(&tmp_path, &dest).await?;
+        Ok(())
+    }
+
+    async fn get_to_file(&self, key: &str, dest: &Path) -> Result<u64> {
+        let source = self.object_path(key)?;
+        if same_path(&source, dest) {
+            return Ok(fs::metadata(dest).await?.len());
+        }
+        copy_atomic(&source, dest).await
+    }
+
+    async fn get_bytes(&self, key: &str) -> Result<Bytes> {
+        Ok(Bytes::from(fs::read(self.object_path(key)?).await?))
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectMeta>> {
+        match fs::metadata(self.object_path(key)?).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(ObjectMeta {
+                key: key.to_string(),
+                size: metadata.len(),
+                etag: None,
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.object_path(key)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
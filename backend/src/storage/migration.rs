@@ -0,0 +1,419 @@
+//! Storage migration jobs: move file objects between backends
+//!
+//! Files are processed in id order and the job row records the last file
+//! handled (`cursor_file_id`) together with the counters, so a job interrupted
+//! by a restart resumes where it stopped. Each file is copied, verified
+//! against its SHA-256 and then re-pointed to the target backend before the
+//! source object is removed. The `./data` working tree is never deleted from,
+//! because the rest of the server reads it directly; it remains as a cache.
+
+use super::{
+    DATA_DIR, LOCAL_DEFAULT_BACKEND_ID, StorageBackend, backend_by_id, content_key,
+    refresh_backend_usage,
+};
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+const BATCH_SIZE: i64 = 100;
+const MIGRATION_TEMP_DIR: &str = "./data/temp_uploads/migration";
+
+/// `storage_migration_jobs.file_filter`
+#[derive(Debug, Default, Deserialize)]
+pub struct FileFilter {
+    /// MIME type or MIME prefix such as `image/`
+    pub file_type: Option<String>,
+    pub size_range: Option<SizeRange>,
+    pub date_range: Option<DateRange>,
+    /// Path glob where `*` matches any characters, e.g. `projects/*.psd`
+    pub path_pattern: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SizeRange {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DateRange {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct CandidateFile {
+    id: String,
+    path: String,
+    size_bytes: i64,
+    checksum_sha256: Option<String>,
+    storage_path: Option<String>,
+}
+
+/// Jobs currently executing in this process
+fn running_jobs() -> &'static Mutex<HashSet<String>> {
+    static RUNNING: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    RUNNING.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Run a job in the background unless it is already running
+pub fn spawn_job(pool: SqlitePool, job_id: String) {
+    {
+        let mut running = running_jobs().lock().unwrap_or_else(|e| e.into_inner());
+        if !running.insert(job_id.clone()) {
+            return;
+        }
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = run_job(&pool, &job_id).await {
+            tracing::error!("Storage migration {} failed: {}", job_id, e);
+            let _ = sqlx::query(
+                "UPDATE storage_migration_jobs SET status = 'failed', error_message = ?, completed_at = ?, updated_at = ?
+                 WHERE id = ? AND status IN ('pending', 'running')",
+            )
+            .bind(e.to_string())
+            .bind(Utc::now().to_rfc3339())
+            .bind(Utc::now().to_rfc3339())
+            .bind(&job_id)
+            .execute(&pool)
+            .await;
+        }
+        running_jobs()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&job_id);
+    });
+}
+
+/// Resume jobs left pending or running by a previous process
+pub async fn resume_jobs(pool: &SqlitePool) -> Result<usize> {
+    let job_ids: Vec<String> = sqlx::query_scalar(
+        "SELECT id FROM storage_migration_jobs WHERE status IN ('pending', 'running') ORDER BY created_at",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for job_id in &job_ids {
+        spawn_job(pool.clone(), job_id.clone());
+    }
+    Ok(job_ids.len())
+}
+
+fn push_filter(query: &mut QueryBuilder<'_, Sqlite>, filter: &FileFilter) {
+    if let Some(file_type) = &filter.file_type {
+        if file_type.ends_with('/') {
+            query
+                .push(" AND f.mime_type LIKE ")
+                .push_bind(format!("{}%", file_type));
+        } else {
+            query
+                .push(" AND f.mime_type = ")
+                .push_bind(file_type.clone());
+        }
+    }
+    if let Some(size) = &filter.size_range {
+        if let Some(min) = size.min {
+            query.push(" AND f.size_bytes >= ").push_bind(min);
+        }
+        if let Some(max) = size.max {
+            query.push(" AND f.size_bytes <= ").push_bind(max);
+        }
+    }
+    if let Some(dates) = &filter.date_range {
+        if let Some(from) = &dates.from {
+            query.push(" AND f.created_at >= ").push_bind(from.clone());
+        }
+        if let Some(to) = &dates.to {
+            query.push(" AND f.created_at <= ").push_bind(to.clone());
+        }
+    }
+    if let Some(pattern) = &filter.path_pattern {
+        let like = pattern
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+            .replace('*', "%");
+        query
+            .push(" AND f.path LIKE ")
+            .push_bind(like)
+            .push(" ESCAPE '\\'");
+    }
+}
+
+/// Files on `source_id` matching the filter. Files without a location row
+/// predate backend tracking and live in the local working tree.
+fn candidate_query<'a>(
+    source_id: &'a str,
+    filter: &FileFilter,
+    select: &str,
+) -> QueryBuilder<'a, Sqlite> {
+    let mut query = QueryBuilder::new(format!(
+        "SELECT {} FROM files f LEFT JOIN file_storage_locations l ON l.file_id = f.id AND l.is_primary = 1 \
+         WHERE f.is_deleted = 0 AND COALESCE(l.backend_id, ",
+        select
+    ));
+    query
+        .push_bind(LOCAL_DEFAULT_BACKEND_ID)
+        .push(") = ")
+        .push_bind(source_id);
+    push_filter(&mut query, filter);
+    query
+}
+
+async fn job_status(pool: &SqlitePool, job_id: &str) -> Result<String> {
+    sqlx::query_scalar("SELECT status FROM storage_migration_jobs WHERE id = ?")
+        .bind(job_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| anyhow!("Migration job {} not found", job_id))
+}
+
+pub async fn run_job(pool: &SqlitePool, job_id: &str) -> Result<()> {
+    let (source_id, target_id, file_filter, progress_total, cursor): (
+        String,
+        String,
+        Option<String>,
+        i64,
+        Option<String>,
+    ) = sqlx::query_as(
+        "SELECT source_backend_id, target_backend_id, file_filter, progress_total, cursor_file_id
+         FROM storage_migration_jobs WHERE id = ? AND status IN ('pending', 'running')",
+    )
+    .bind(job_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| anyhow!("Migration job {} is not runnable", job_id))?;
+
+    if source_id == target_id {
+        return Err(anyhow!("Source and target backend are the same"));
+    }
+
+    let filter: FileFilter = match file_filter.as_deref() {
+        Some(json) if !json.is_empty() && json != "null" => {
+            serde_json::from_str(json).map_err(|e| anyhow!("Invalid file filter: {}", e))?
+        }
+        _ => FileFilter::default(),
+    };
+
+    let source = backend_by_id(pool, &source_id).await?;
+    let target = backend_by_id(pool, &target_id).await?;
+    let now = Utc::now().to_rfc3339();
+
+    // Totals are fixed on the first run so progress stays comparable across resumes
+    if progress_total == 0 && cursor.is_none() {
+        let (count, bytes): (i64, i64) = candidate_query(
+            &source_id,
+            &filter,
+            "COUNT(*), COALESCE(SUM(f.size_bytes), 0)",
+        )
+        .build_query_as()
+        .fetch_one(pool)
+        .await?;
+        sqlx::query(
+            "UPDATE storage_migration_jobs SET progress_total = ?, bytes_total = ? WHERE id = ?",
+        )
+        .bind(count)
+        .bind(bytes)
+        .bind(job_id)
+        .execute(pool)
+        .await?;
+    }
+
+    sqlx::query(
+        "UPDATE storage_migration_jobs SET status = 'running', started_at = COALESCE(started_at, ?), updated_at = ?
+         WHERE id = ? AND status IN ('pending', 'running')",
+    )
+    .bind(&now)
+    .bind(&now)
+    .bind(job_id)
+    .execute(pool)
+    .await?;
+
+    tokio::fs::create_dir_all(MIGRATION_TEMP_DIR).await?;
+    let mut cursor = cursor.unwrap_or_default();
+
+    loop {
+        let mut query = candidate_query(
+            &source_id,
+            &filter,
+            "f.id, f.path, f.size_bytes, f.checksum_sha256, l.storage_path",
+        );
+        query
+            .push(" AND f.id > ")
+            .push_bind(cursor.clone())
+            .push(" ORDER BY f.id LIMIT ")
+            .push_bind(BATCH_SIZE);
+        let batch: Vec<CandidateFile> = query.build_query_as().fetch_all(pool).await?;
+        if batch.is_empty() {
+            break;
+        }
+
+        for file in batch {
+            if job_status(pool, job_id).await? == "cancelled" {
+                tracing::info!("Storage migration {} cancelled", job_id);
+                refresh_backend_usage(pool, &source_id).await?;
+                refresh_backend_usage(pool, &target_id).await?;
+                return Ok(());
+            }
+
+            let outcome = migrate_file(pool, source.as_ref(), target.as_ref(), &file).await;
+            let (migrated, failed, bytes) = match &outcome {
+                Ok(()) => (1, 0, file.size_bytes),
+                Err(e) => {
+                    tracing::warn!("Failed to migrate {}: {}", file.path, e);
+                    (0, 1, 0)
+                }
+            };
+
+            sqlx::query(
+                "UPDATE storage_migration_jobs SET
+                    progress_current = progress_current + 1,
+                    files_migrated = files_migrated + ?,
+                    files_failed = files_failed + ?,
+                    bytes_migrated = bytes_migrated + ?,
+                    error_message = COALESCE(?, error_message),
+                    cursor_file_id = ?,
+                    updated_at = ?
+                 WHERE id = ?",
+            )
+            .bind(migrated)
+            .bind(failed)
+            .bind(bytes)
+            .bind(outcome.err().map(|e| format!("{}: {}", file.path, e)))
+            .bind(&file.id)
+            .bind(Utc::now().to_rfc3339())
+            .bind(job_id)
+            .execute(pool)
+            .await?;
+
+            cursor = file.id;
+        }
+
+        refresh_backend_usage(pool, &source_id).await?;
+        refresh_backend_usage(pool, &target_id).await?;
+    }
+
+    let now = Utc::now().to_rfc3339();
+    sqlx::query(
+        "UPDATE storage_migration_jobs SET status = 'completed', completed_at = ?, updated_at = ? WHERE id = ? AND status = 'running'",
+    )
+    .bind(&now)
+    .bind(&now)
+    .bind(job_id)
+    .execute(pool)
+    .await?;
+
+    tracing::info!("Storage migration {} completed", job_id);
+    Ok(())
+}
+
+/// Copy one file to the target backend, verify it and make the target primary
+async fn migrate_file(
+    pool: &SqlitePool,
+    source: &dyn StorageBackend,
+    target: &dyn StorageBackend,
+    file: &CandidateFile,
+) -> Result<()> {
+    // Stage the content locally: the working tree already has it, otherwise download
+    let (staged, is_temp): (PathBuf, bool) = if source.is_working_tree() {
+        (Path::new(DATA_DIR).join(&file.path), false)
+    } else {
+        let key = file
+            .storage_path
+            .as_deref()
+            .ok_or_else(|| anyhow!("No object key recorded"))?;
+        let temp = Path::new(MIGRATION_TEMP_DIR).join(format!("{}.part", file.id));
+        source.get_to_file(key, &temp).await?;
+        (temp, true)
+    };
+
+    let result = async {
+        let (size, checksum) = super::sha256_file(&staged).await?;
+        if let Some(expected) = &file.checksum_sha256
+            && expected != &checksum
+        {
+            return Err(anyhow!("Checksum mismatch (expected {}, got {})", expected, checksum));
+        }
+
+        let target_key = if target.is_working_tree() {
+            file.path.clone()
+        } else {
+            content_key(&checksum)
+        };
+
+        let already_there = match target.head(&target_key).await? {
+            Some(meta) => meta.size == size && !target.is_working_tree(),
+            None => false,
+        };
+        if !already_there {
+            target.put_file(&target_key, &staged).await?;
+        }
+        match target.head(&target_key).await? {
+            Some(meta) if meta.size == size => {}
+            _ => return Err(anyhow!("Object missing or truncated on target after upload")),
+        }
+
+        let mut tx = pool.begin().await?;
+        sqlx::query("DELETE FROM file_storage_locations WHERE file_id = ? AND backend_id IN (?, ?)")
+            .bind(&file.id)
+            .bind(source.id())
+            .bind(target.id())
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE file_storage_locations SET is_primary = 0 WHERE file_id = ?")
+            .bind(&file.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO file_storage_locations (id, file_id, backend_id, storage_path, size_bytes, checksum_sha256, is_primary, upload_status, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, 1, 'completed', ?)",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(&file.id)
+        .bind(target.id())
+        .bind(&target_key)
+        .bind(size as i64)
+        .bind(&checksum)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+        if file.checksum_sha256.is_none() {
+            sqlx::query("UPDATE files SET checksum_sha256 = ? WHERE id = ?")
+                .bind(&checksum)
+                .bind(&file.id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+    .await;
+
+    if is_temp {
+        let _ = tokio::fs::remove_file(&staged).await;
+    }
+    result?;
+
+    // Remove the source object once nothing references it any more
+    if !source.is_working_tree()
+        && let Some(key) = file.storage_path.as_deref()
+    {
+        let still_used: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM file_storage_locations WHERE backend_id = ? AND storage_path = ?",
+        )
+        .bind(source.id())
+        .bind(key)
+        .fetch_one(pool)
+        .await?;
+        if still_used == 0 {
+            source.delete(key).await?;
+        }
+    }
+
+    Ok(())
+}
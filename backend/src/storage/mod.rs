@@ -0,0 +1,599 @@
+//! Pluggable storage backends
+//!
+//! Every backend implements [`StorageBackend`]. The local backend stores
+//! objects under a directory; the S3 backend talks to any S3-compatible
+//! service (AWS, MinIO, GCS interoperability) with SigV4-signed requests.
+//!
+//! `./data` stays the working tree that WebDAV, search, previews and listings
+//! read from. When the default backend is remote, file content is written
+//! through to it and the local copy acts as a cache that is re-fetched on
+//! demand. Uploaded files are tracked per backend in `file_storage_locations`;
+//! version blobs and thumbnails are mirrored to the default backend under
+//! their path relative to `./data` and read back with failover by priority.
+//! With deduplicated storage, file content is also kept in the block store
+//! (see crate::block_store), which restores missing working copies as well.
+
+pub mod local;
+pub mod migration;
+pub mod s3;
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use bytes::Bytes;
+use sqlx::SqlitePool;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+
+pub use local::LocalBackend;
+pub use s3::S3Backend;
+
+pub const DATA_DIR: &str = "./data";
+/// Seeded by migration 041; files without a location row live here
+pub const LOCAL_DEFAULT_BACKEND_ID: &str = "backend-local-default";
+
+/// Metadata of a stored object
+#[derive(Debug, Clone)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    pub etag: Option<String>,
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    fn id(&self) -> &str;
+
+    fn kind(&self) -> &'static str;
+
+    /// True when the backend stores objects directly in the `./data` working
+    /// tree, so files need no copy and are addressed by their path
+    fn is_working_tree(&self) -> bool {
+        false
+    }
+
+    /// Upload a local file as `key`, returning the stored size
+    async fn put_file(&self, key: &str, source: &Path) -> Result<u64>;
+
+    async fn put_bytes(&self, key: &str, data: Bytes) -> Result<()>;
+
+    /// Download `key` into `dest` (written atomically), returning its size
+    async fn get_to_file(&self, key: &str, dest: &Path) -> Result<u64>;
+
+    async fn get_bytes(&self, key: &str) -> Result<Bytes>;
+
+    /// Object metadata, or `None` if the object does not exist
+    async fn head(&self, key: &str) -> Result<Option<ObjectMeta>>;
+
+    /// Delete `key`; deleting a missing object is not an error
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Validate an object key: relative, `/`-separated, no `..` or empty segments
+pub fn validate_key(key: &str) -> Result<()> {
+    if key.is_empty() || key.starts_with('/') || key.contains('\\') {
+        return Err(anyhow!("Invalid object key: {}", key));
+    }
+    if key
+        .split('/')
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+    {
+        return Err(anyhow!("Invalid object key: {}", key));
+    }
+    Ok(())
+}
+
+/// Stream a file through SHA-256, returning `(size, hex digest)`
+pub async fn sha256_file(path: &Path) -> Result<(u64, String)> {
+    use sha2::{Digest, Sha256};
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| anyhow!("Cannot open {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut total = 0u64;
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        total += read as u64;
+    }
+
+    Ok((total, format!("{:x}", hasher.finalize())))
+}
+
+/// Key of a file on a content-addressed (non working tree) backend
+pub fn content_key(checksum_sha256: &str) -> String {
+    format!("objects/{}/{}", &checksum_sha256[..2], checksum_sha256)
+}
+
+/// Key of an auxiliary object (version blob, thumbnail) from its local path
+pub fn key_for_local_path(local_path: &Path) -> Option<String> {
+    let relative = local_path.strip_prefix(DATA_DIR).ok()?;
+    let mut segments = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(segment) => segments.push(segment.to_str()?.to_string()),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    let key = segments.join("/");
+    validate_key(&key).ok()?;
+    Some(key)
+}
+
+/// Build a backend from a `storage_backends` row
+pub fn build_backend(
+    id: &str,
+    backend_type: &str,
+    config: &str,
+) -> Result<Arc<dyn StorageBackend>> {
+    let config: serde_json::Value =
+        serde_json::from_str(config).map_err(|e| anyhow!("Invalid backend config: {}", e))?;
+
+    match backend_type {
+        "local" => {
+            let base_path = config
+                .get("base_path")
+                .and_then(|v| v.as_str())
+                .unwrap_or(DATA_DIR);
+            Ok(Arc::new(LocalBackend::new(id, base_path)))
+        }
+        "s3" | "minio" | "gcs" => Ok(Arc::new(S3Backend::from_config(id, backend_type, &config)?)),
+        other => Err(anyhow!("Storage backend type '{}' is not supported", other)),
+    }
+}
+
+struct Registry {
+    default: Arc<dyn StorageBackend>,
+    /// Active backends ordered by priority (highest first)
+    active: Vec<Arc<dyn StorageBackend>>,
+}
+
+fn registry() -> &'static RwLock<Registry> {
+    static REGISTRY: OnceLock<RwLock<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let local: Arc<dyn StorageBackend> =
+            Arc::new(LocalBackend::new(LOCAL_DEFAULT_BACKEND_ID, DATA_DIR));
+        RwLock::new(Registry {
+            default: local.clone(),
+            active: vec![local],
+        })
+    })
+}
+
+/// (Re)load the active backends from the database.
+/// Called at startup and whenever backend configuration changes.
+pub async fn reload(pool: &SqlitePool) -> Result<()> {
+    let rows: Vec<(String, String, String, i64)> = sqlx::query_as(
+        "SELECT id, backend_type, config, is_default FROM storage_backends WHERE is_active = 1 ORDER BY priority DESC",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut active: Vec<Arc<dyn StorageBackend>> = Vec::new();
+    let mut default = None;
+    for (id, backend_type, config, is_default) in rows {
+        match build_backend(&id, &backend_type, &config) {
+            Ok(backend) => {
+                if is_default != 0 && default.is_none() {
+                    default = Some(backend.clone());
+                }
+                active.push(backend);
+            }
+            Err(e) => tracing::warn!("Storage backend {} unavailable: {}", id, e),
+        }
+    }
+
+    let default = match default {
+        Some(backend) => backend,
+        None => {
+            let local: Arc<dyn StorageBackend> =
+                Arc::new(LocalBackend::new(LOCAL_DEFAULT_BACKEND_ID, DATA_DIR));
+            if !active.iter().any(|b| b.id() == LOCAL_DEFAULT_BACKEND_ID) {
+                active.push(local.clone());
+            }
+            local
+        }
+    };
+
+    tracing::info!(
+        "Default storage backend: {} ({})",
+        default.id(),
+        default.kind()
+    );
+    let mut registry = registry().write().unwrap_or_else(|e| e.into_inner());
+    registry.default = default;
+    registry.active = active;
+    Ok(())
+}
+
+/// Backend new content is written to
+pub fn default_backend() -> Arc<dyn StorageBackend> {
+    registry()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .default
+        .clone()
+}
+
+/// Default backend first, then the other active backends by priority
+fn active_backends() -> Vec<Arc<dyn StorageBackend>> {
+    let registry = registry().read().unwrap_or_else(|e| e.into_inner());
+    let mut backends = vec![registry.default.clone()];
+    backends.extend(
+        registry
+            .active
+            .iter()
+            .filter(|b| b.id() != registry.default.id())
+            .cloned(),
+    );
+    backends
+}
+
+/// Look up a backend by id, including inactive ones (used by migrations)
+pub async fn backend_by_id(pool: &SqlitePool, id: &str) -> Result<Arc<dyn StorageBackend>> {
+    let cached = registry()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .active
+        .iter()
+        .find(|b| b.id() == id)
+        .cloned();
+    if let Some(backend) = cached {
+        return Ok(backend);
+    }
+
+    let row: Option<(String, String)> =
+        sqlx::query_as("SELECT backend_type, config FROM storage_backends WHERE id = ?")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+    let (backend_type, config) = row.ok_or_else(|| anyhow!("Storage backend {} not found", id))?;
+    build_backend(id, &backend_type, &config)
+}
+
+/// Write a newly stored file through to the default backend (and the block
+/// store, when deduplicated) and record its location.
+/// Remote uploads run in the background; the location is 'uploading' until done.
+pub async fn replicate_file(
+    pool: &SqlitePool,
+    file_id: &str,
+    path: &str,
+    local_path: &Path,
+    size_bytes: i64,
+    checksum_sha256: &str,
+) -> Result<()> {
+    // Deduplicated storage keeps the content in the block store as well
+    if crate::block_store::dedup_enabled()
+        && let Err(e) =
+            crate::block_store::store_live_file(pool, file_id, local_path, checksum_sha256).await
+    {
+        tracing::warn!("Failed to store {} in the block store: {}", path, e);
+    }
+
+    let backend = default_backend();
+    let now = chrono::Utc::now().to_rfc3339();
+    let (key, status) = if backend.is_working_tree() {
+        (path.to_string(), "completed")
+    } else {
+        (content_key(checksum_sha256), "uploading")
+    };
+
+    let location_id = uuid::Uuid::new_v4().to_string();
+    sqlx::query(
+        "INSERT INTO file_storage_locations (id, file_id, backend_id, storage_path, size_bytes, checksum_sha256, is_primary, upload_status, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, 1, ?, ?)",
+    )
+    .bind(&location_id)
+    .bind(file_id)
+    .bind(backend.id())
+    .bind(&key)
+    .bind(size_bytes)
+    .bind(checksum_sha256)
+    .bind(status)
+    .bind(&now)
+    .execute(pool)
+    .await?;
+
+    if backend.is_working_tree() {
+        return Ok(());
+    }
+
+    let pool = pool.clone();
+    let local_path = local_path.to_path_buf();
+    tokio::spawn(async move {
+        let status = match put_if_missing(backend.as_ref(), &key, &local_path, size_bytes).await {
+            Ok(()) => "completed",
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to upload {} to backend {}: {}",
+                    key,
+                    backend.id(),
+                    e
+                );
+                "failed"
+            }
+        };
+        let _ = sqlx::query("UPDATE file_storage_locations SET upload_status = ? WHERE id = ?")
+            .bind(status)
+            .bind(&location_id)
+            .execute(&pool)
+            .await;
+        let _ = refresh_backend_usage(&pool, backend.id()).await;
+    });
+
+    Ok(())
+}
+
+/// Upload unless an object of the same size already exists (content-addressed keys)
+async fn put_if_missing(
+    backend: &dyn StorageBackend,
+    key: &str,
+    source: &Path,
+    size_bytes: i64,
+) -> Result<()> {
+    if let Some(meta) = backend.head(key).await?
+        && meta.size == size_bytes as u64
+    {
+        return Ok(());
+    }
+    backend.put_file(key, source).await?;
+    Ok(())
+}
+
+/// Make sure the working tree copy of a file exists, fetching it from the
+/// backend that holds it. Returns false if no backend has the content.
+pub async fn ensure_local(pool: &SqlitePool, path: &str) -> Result<bool> {
+    let local_path = Path::new(DATA_DIR).join(path);
+    if tokio::fs::try_exists(&local_path).await.unwrap_or(false) {
+        return Ok(true);
+    }
+    if crate::block_store::restore_live_file(pool, path, &local_path).await? {
+        return Ok(true);
+    }
+
+    let locations: Vec<(String, String)> = sqlx::query_as(
+        r#"
+        SELECT l.backend_id, l.storage_path
+        FROM files f
+        JOIN file_storage_locations l ON l.file_id = f.id
+        LEFT JOIN storage_backends b ON b.id = l.backend_id
+        WHERE f.path = ? AND f.is_deleted = 0 AND l.upload_status = 'completed'
+        ORDER BY l.is_primary DESC, COALESCE(b.priority, 0) DESC
+        "#,
+    )
+    .bind(path)
+    .fetch_all(pool)
+    .await?;
+
+    for (backend_id, key) in locations {
+        let Ok(backend) = backend_by_id(pool, &backend_id).await else {
+            continue;
+        };
+        if backend.is_working_tree() {
+            continue;
+        }
+        match backend.get_to_file(&key, &local_path).await {
+            Ok(_) => return Ok(true),
+            Err(e) => tracing::warn!("Failed to fetch {} from backend {}: {}", key, backend_id, e),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Mirror an auxiliary file (version blob, thumbnail) to the default backend
+pub async fn mirror_local_file(local_path: &Path) -> Result<()> {
+    let backend = default_backend();
+    if backend.is_working_tree() {
+        return Ok(());
+    }
+    let key = key_for_local_path(local_path)
+        .ok_or_else(|| anyhow!("{} is outside the data directory", local_path.display()))?;
+    backend.put_file(&key, local_path).await?;
+    Ok(())
+}
+
+/// Restore a missing auxiliary file from the first backend that has it
+pub async fn fetch_local_file(local_path: &Path) -> Result<bool> {
+    if tokio::fs::try_exists(local_path).await.unwrap_or(false) {
+        return Ok(true);
+    }
+    let Some(key) = key_for_local_path(local_path) else {
+        return Ok(false);
+    };
+
+    for backend in active_backends() {
+        if backend.is_working_tree() {
+            continue;
+        }
+        match backend.get_to_file(&key, local_path).await {
+            Ok(_) => return Ok(true),
+            Err(e) => tracing::debug!("{} not on backend {}: {}", key, backend.id(), e),
+        }
+    }
+    Ok(false)
+}
+
+/// Remove an auxiliary file locally and from the default backend
+pub async fn remove_local_file(local_path: &Path) {
+    let _ = tokio::fs::remove_file(local_path).await;
+    let backend = default_backend();
+    if backend.is_working_tree() {
+        return;
+    }
+    if let Some(key) = key_for_local_path(local_path)
+        && let Err(e) = backend.delete(&key).await
+    {
+        tracing::warn!(
+            "Failed to delete {} from backend {}: {}",
+            key,
+            backend.id(),
+            e
+        );
+    }
+}
+
+/// Delete the remote objects and block store references of a file that is
+/// being purged or replaced. Content-addressed objects still used by another
+/// file are kept.
+pub async fn delete_file_objects(pool: &SqlitePool, file_id: &str) -> Result<()> {
+    crate::block_store::release_live_file(pool, file_id).await?;
+
+    let locations: Vec<(String, String)> = sqlx::query_as(
+        "SELECT backend_id, storage_path FROM file_storage_locations WHERE file_id = ?",
+    )
+    .bind(file_id)
+    .fetch_all(pool)
+    .await?;
+
+    sqlx::query("DELETE FROM file_storage_locations WHERE file_id = ?")
+        .bind(file_id)
+        .execute(pool)
+        .await?;
+
+    for (backend_id, key) in locations {
+        let backend = match backend_by_id(pool, &backend_id).await {
+            Ok(backend) => backend,
+            Err(e) => {
+                tracing::warn!("Cannot delete {} from backend {}: {}", key, backend_id, e);
+                continue;
+            }
+        };
+        // Working tree files are removed by the caller
+        if backend.is_working_tree() {
+            continue;
+        }
+
+        let still_used: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM file_storage_locations WHERE backend_id = ? AND storage_path = ?",
+        )
+        .bind(&backend_id)
+        .bind(&key)
+        .fetch_one(pool)
+        .await?;
+
+        if still_used == 0 {
+            backend.delete(&key).await?;
+        }
+        let _ = refresh_backend_usage(pool, &backend_id).await;
+    }
+
+    Ok(())
+}
+
+/// Recompute `storage_used_bytes` and `file_count` of a backend
+pub async fn refresh_backend_usage(pool: &SqlitePool, backend_id: &str) -> Result<()> {
+    sqlx::query(
+        r#"
+        UPDATE storage_backends SET
+            storage_used_bytes = (SELECT COALESCE(SUM(size_bytes), 0) FROM file_storage_locations WHERE backend_id = ?),
+            file_count = (SELECT COUNT(*) FROM file_storage_locations WHERE backend_id = ?)
+        WHERE id = ?
+        "#,
+    )
+    .bind(backend_id)
+    .bind(backend_id)
+    .bind(backend_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Result of a single health probe
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ProbeResult {
+    pub check_type: &'static str,
+    pub passed: bool,
+    pub response_time_ms: i64,
+    pub error: Option<String>,
+}
+
+/// Write, read back and delete a probe object
+pub async fn probe(backend: &dyn StorageBackend) -> Vec<ProbeResult> {
+    let key = format!(".health/{}", uuid::Uuid::new_v4());
+    let payload = Bytes::from(format!(
+        "syncspace health check {}",
+        chrono::Utc::now().to_rfc3339()
+    ));
+    let mut results = Vec::new();
+
+    let started = std::time::Instant::now();
+    let write = backend.put_bytes(&key, payload.clone()).await;
+    results.push(probe_result("write", started, write.as_ref().err()));
+    if write.is_err() {
+        return results;
+    }
+
+    let started = std::time::Instant::now();
+    let read = match backend.get_bytes(&key).await {
+        Ok(data) if data == payload => Ok(()),
+        Ok(_) => Err(anyhow!("Read back different content")),
+        Err(e) => Err(e),
+    };
+    results.push(probe_result("read", started, read.as_ref().err()));
+
+    let started = std::time::Instant::now();
+    let delete = backend.delete(&key).await;
+    results.push(probe_result("delete", started, delete.as_ref().err()));
+
+    results
+}
+
+fn probe_result(
+    check_type: &'static str,
+    started: std::time::Instant,
+    error: Option<&anyhow::Error>,
+) -> ProbeResult {
+    ProbeResult {
+        check_type,
+        passed: error.is_none(),
+        response_time_ms: started.elapsed().as_millis() as i64,
+        error: error.map(|e| e.to_string()),
+    }
+}
+
+/// Temporary path next to `dest` used for atomic downloads
+pub(crate) fn temp_path_for(dest: &Path) -> PathBuf {
+    let name = dest
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("object");
+    dest.with_file_name(format!(".{}.{}.part", name, uuid::Uuid::new_v4()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_key() {
+        assert!(validate_key("a/b/c.txt").is_ok());
+        assert!(validate_key("").is_err());
+        assert!(validate_key("/abs").is_err());
+        assert!(validate_key("a/../b").is_err());
+        assert!(validate_key("a//b").is_err());
+        assert!(validate_key("a\\b").is_err());
+    }
+
+    #[test]
+    fn test_key_for_local_path() {
+        assert_eq!(
+            key_for_local_path(Path::new("./data/versions/f/1_v2.dat")).as_deref(),
+            Some("versions/f/1_v2.dat")
+        );
+        assert_eq!(key_for_local_path(Path::new("/etc/passwd")), None);
+    }
+
+    #[test]
+    fn test_content_key() {
+        let checksum = "ab".repeat(32);
+        assert_eq!(content_key(&checksum), format!("objects/ab/{}", checksum));
+    }
+}
@@ -0,0 +1,652 @@
+//! S3-compatible storage backend (AWS S3, MinIO, GCS interoperability)
+//!
+//! Requests are signed with AWS Signature Version 4. Objects larger than
+//! `MULTIPART_THRESHOLD` are uploaded with multipart uploads so memory use
+//! stays bounded by one part. The steps of a multipart upload are public so
+//! backup destinations can resume one across attempts.
+
+use super::{ObjectMeta, StorageBackend, temp_path_for, validate_key};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use base64::{Engine as _, engine::general_purpose};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MULTIPART_THRESHOLD: u64 = 16 * 1024 * 1024;
+const MIN_PART_SIZE: u64 = 8 * 1024 * 1024;
+const MAX_PARTS: u64 = 9_000;
+/// Smallest part S3 accepts, except for the last one
+pub const S3_MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// A part of an unfinished multipart upload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadedPart {
+    pub number: u32,
+    /// Without quotes; the hex MD5 of the part unless the bucket encrypts
+    /// with KMS
+    pub etag: String,
+    pub size: u64,
+}
+
+pub struct S3Backend {
+    id: String,
+    kind: &'static str,
+    client: reqwest::Client,
+    scheme: String,
+    /// Host header value (includes the bucket for virtual-hosted style)
+    host: String,
+    /// "/bucket" for path-style addressing, empty for virtual-hosted style
+    bucket_path: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    /// Optional key prefix inside the bucket, always ending with '/' when set
+    prefix: String,
+}
+
+fn config_str<'a>(config: &'a serde_json::Value, keys: &[&str]) -> Option<&'a str> {
+    keys.iter()
+        .find_map(|key| config.get(*key).and_then(|v| v.as_str()))
+        .filter(|v| !v.is_empty())
+}
+
+impl S3Backend {
+    /// Build from a `storage_backends.config` JSON object:
+    /// `endpoint`, `bucket`, `region`, `access_key_id`, `secret_access_key`,
+    /// `path_style` and `prefix`. Credentials fall back to `AWS_ACCESS_KEY_ID`
+    /// and `AWS_SECRET_ACCESS_KEY`.
+    pub fn from_config(id: &str, backend_type: &str, config: &serde_json::Value) -> Result<Self> {
+        let bucket = config_str(config, &["bucket", "bucket_name"])
+            .ok_or_else(|| anyhow!("S3 backend requires a bucket"))?;
+        let default_region = if backend_type == "gcs" {
+            "auto"
+        } else {
+            "us-east-1"
+        };
+        let region = config_str(config, &["region"]).unwrap_or(default_region);
+
+        let endpoint = match config_str(config, &["endpoint", "endpoint_url"]) {
+            Some(endpoint) => endpoint.to_string(),
+            None if backend_type == "gcs" => "https://storage.googleapis.com".to_string(),
+            None if backend_type == "s3" => format!("https://s3.{}.amazonaws.com", region),
+            None => return Err(anyhow!("{} backend requires an endpoint", backend_type)),
+        };
+        let endpoint_url = reqwest::Url::parse(&endpoint)
+            .map_err(|e| anyhow!("Invalid endpoint {}: {}", endpoint, e))?;
+        let endpoint_host = endpoint_url
+            .host_str()
+            .ok_or_else(|| anyhow!("Endpoint {} has no host", endpoint))?;
+        let endpoint_host = match endpoint_url.port() {
+            Some(port) => format!("{}:{}", endpoint_host, port),
+            None => endpoint_host.to_string(),
+        };
+
+        // MinIO and custom endpoints generally only support path-style requests
+        let path_style = config
+            .get("path_style")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(
+                backend_type == "minio"
+                    || config_str(config, &["endpoint", "endpoint_url"]).is_some(),
+            );
+        let (host, bucket_path) = if path_style {
+            (endpoint_host, format!("/{}", uri_encode(bucket, true)))
+        } else {
+            (format!("{}.{}", bucket, endpoint_host), String::new())
+        };
+
+        let access_key = config_str(config, &["access_key_id", "access_key"])
+            .map(str::to_string)
+            .or_else(|| std::env::var("AWS_ACCESS_KEY_ID").ok())
+            .ok_or_else(|| anyhow!("S3 backend requires access_key_id"))?;
+        let secret_key = config_str(config, &["secret_access_key", "secret_key"])
+            .map(str::to_string)
+            .or_else(|| std::env::var("AWS_SECRET_ACCESS_KEY").ok())
+            .ok_or_else(|| anyhow!("S3 backend requires secret_access_key"))?;
+
+        let prefix = config_str(config, &["prefix"])
+            .map(|p| format!("{}/", p.trim_matches('/')))
+            .unwrap_or_default();
+
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .build()?;
+
+        Ok(Self {
+            id: id.to_string(),
+            kind: match backend_type {
+                "minio" => "minio",
+                "gcs" => "gcs",
+                _ => "s3",
+            },
+            client,
+            scheme: endpoint_url.scheme().to_string(),
+            host,
+            bucket_path,
+            region: region.to_string(),
+            access_key,
+            secret_key,
+            prefix,
+        })
+    }
+
+    fn object_uri(&self, key: &str) -> Result<String> {
+        validate_key(key)?;
+        Ok(format!(
+            "{}/{}",
+            self.bucket_path,
+            uri_encode(&format!("{}{}", self.prefix, key), false)
+        ))
+    }
+
+    /// Send a SigV4-signed request for `key`
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        self.send_with_headers(method, key, query, &[], body).await
+    }
+
+    /// Send a SigV4-signed request for `key` with extra headers, which are
+    /// signed too
+    async fn send_with_headers(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let uri = self.object_uri(key)?;
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let mut encoded_query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        encoded_query.sort();
+        let canonical_query = encoded_query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut canonical_headers: Vec<(String, String)> = vec![
+            ("host".to_string(), self.host.clone()),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), amz_date.clone()),
+        ];
+        canonical_headers.extend(
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string())),
+        );
+        canonical_headers.sort();
+        let signed_headers = canonical_headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method.as_str(),
+            uri,
+            canonical_query,
+            canonical_headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed_headers,
+            payload_hash
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = [self.region.as_bytes(), b"s3", b"aws4_request"]
+            .iter()
+            .fold(
+                hmac_sha256(
+                    format!("AWS4{}", self.secret_key).as_bytes(),
+                    date.as_bytes(),
+                ),
+                |key, part| hmac_sha256(&key, part),
+            );
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let mut url = format!("{}://{}{}", self.scheme, self.host, uri);
+        if !canonical_query.is_empty() {
+            url.push('?');
+            url.push_str(&canonical_query);
+        }
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(reqwest::header::AUTHORIZATION, authorization);
+        for (name, value) in headers {
+            request = request.header(*name, value.as_str());
+        }
+        Ok(request.body(body).send().await?)
+    }
+
+    /// Turn a non-success response into an error carrying the S3 error code
+    async fn check(
+        response: reqwest::Response,
+        action: &str,
+        key: &str,
+    ) -> Result<reqwest::Response> {
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        let code = xml_value(&body, "Code").unwrap_or("");
+        Err(anyhow!("S3 {} {} failed: {} {}", action, key, status, code))
+    }
+
+    async fn put_multipart(&self, key: &str, source: &Path, size: u64) -> Result<()> {
+        let upload_id = self.create_multipart(key).await?;
+        match self.upload_parts(key, source, size, &upload_id).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let _ = self.abort_multipart(key, &upload_id).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        source: &Path,
+        size: u64,
+        upload_id: &str,
+    ) -> Result<()> {
+        let part_size = Self::part_size(size, MIN_PART_SIZE) as usize;
+        let mut file = tokio::fs::File::open(source).await?;
+        let mut parts = Vec::new();
+        let mut part_number = 1u32;
+
+        loop {
+            let mut buffer = Vec::with_capacity(part_size);
+            (&mut file)
+                .take(part_size as u64)
+                .read_to_end(&mut buffer)
+                .await?;
+            if buffer.is_empty() {
+                break;
+            }
+
+            let etag = self
+                .upload_part(key, upload_id, part_number, buffer)
+                .await?;
+            parts.push((part_number, etag));
+            part_number += 1;
+        }
+
+        self.complete_multipart(key, upload_id, &parts).await
+    }
+
+    /// Part size for an object of `size` bytes, at least `min` and small
+    /// enough to stay below the part count limit
+    pub fn part_size(size: u64, min: u64) -> u64 {
+        min.max(S3_MIN_PART_SIZE).max(size.div_ceil(MAX_PARTS))
+    }
+
+    /// Start a multipart upload of `key`; returns its upload id
+    pub async fn create_multipart(&self, key: &str) -> Result<String> {
+        let response = self
+            .send(Method::POST, key, &[("uploads", "")], Vec::new())
+            .await?;
+        let body = Self::check(response, "initiate multipart upload", key)
+            .await?
+            .text()
+            .await?;
+        Ok(xml_value(&body, "UploadId")
+            .ok_or_else(|| anyhow!("S3 did not return an UploadId for {}", key))?
+            .to_string())
+    }
+
+    /// Upload part `number` (from 1); returns its ETag. The part is sent
+    /// with its Content-MD5, so the service rejects it if it arrives damaged.
+    pub async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        data: Vec<u8>,
+    ) -> Result<String> {
+        let content_md5 = general_purpose::STANDARD.encode(md5::compute(&data).0);
+        let part = number.to_string();
+        let response = self
+            .send_with_headers(
+                Method::PUT,
+                key,
+                &[("partNumber", &part), ("uploadId", upload_id)],
+                &[("content-md5", content_md5)],
+                data,
+            )
+            .await?;
+        let response = Self::check(response, "upload part", key).await?;
+        Ok(response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| anyhow!("S3 did not return an ETag for part {}", number))?
+            .to_string())
+    }
+
+    /// Parts uploaded so far, or `None` if the upload no longer exists
+    pub async fn list_parts(&self, key: &str, upload_id: &str) -> Result<Option<Vec<UploadedPart>>> {
+        let mut parts = Vec::new();
+        let mut marker = String::from("0");
+        loop {
+            let response = self
+                .send(
+                    Method::GET,
+                    key,
+                    &[("part-number-marker", &marker), ("uploadId", upload_id)],
+                    Vec::new(),
+                )
+                .await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let body = Self::check(response, "list parts", key)
+                .await?
+                .text()
+                .await?;
+            for element in body.split("<Part>").skip(1) {
+                let field = |tag| xml_value(element, tag);
+                let (Some(number), Some(etag), Some(size)) =
+                    (field("PartNumber"), field("ETag"), field("Size"))
+                else {
+                    return Err(anyhow!("S3 returned an incomplete part listing for {}", key));
+                };
+                parts.push(UploadedPart {
+                    number: number.parse()?,
+                    etag: unquote_etag(etag).to_string(),
+                    size: size.parse()?,
+                });
+            }
+            match xml_value(&body, "NextPartNumberMarker") {
+                Some(next) if xml_value(&body, "IsTruncated") == Some("true") => {
+                    marker = next.to_string()
+                }
+                _ => return Ok(Some(parts)),
+            }
+        }
+    }
+
+    /// Assemble the uploaded `parts` (number, ETag) into the object
+    pub async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(u32, String)],
+    ) -> Result<()> {
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        for (number, etag) in parts {
+            complete.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>\"{}\"</ETag></Part>",
+                number,
+                unquote_etag(etag)
+            ));
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+
+        let response = self
+            .send(
+                Method::POST,
+                key,
+                &[("uploadId", upload_id)],
+                complete.into_bytes(),
+            )
+            .await?;
+        let body = Self::check(response, "complete multipart upload", key)
+            .await?
+            .text()
+            .await?;
+        // CompleteMultipartUpload can fail with a 200 status and an error body
+        if body.contains("<Error>") {
+            return Err(anyhow!(
+                "S3 complete multipart upload {} failed: {}",
+                key,
+                xml_value(&body, "Code").unwrap_or("unknown error")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Drop an unfinished upload and its parts; a missing upload is not an
+    /// error
+    pub async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()> {
+        let response = self
+            .send(Method::DELETE, key, &[("uploadId", upload_id)], Vec::new())
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        Self::check(response, "abort multipart upload", key).await?;
+        Ok(())
+    }
+}
+
+/// ETags are quoted, and the quotes are escaped in XML listings
+pub fn unquote_etag(etag: &str) -> &str {
+    etag.trim_start_matches("&quot;")
+        .trim_end_matches("&quot;")
+        .trim_matches('"')
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// SigV4 URI encoding: everything except unreserved characters is escaped
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Text of the first `<tag>` element in an XML document
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    Some(&xml[start..end])
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    async fn put_file(&self, key: &str, source: &Path) -> Result<u64> {
+        let size = tokio::fs::metadata(source).await?.len();
+        if size > MULTIPART_THRESHOLD {
+            self.put_multipart(key, source, size).await?;
+        } else {
+            let data = tokio::fs::read(source).await?;
+            let response = self.send(Method::PUT, key, &[], data).await?;
+            Self::check(response, "put", key).await?;
+        }
+        Ok(size)
+    }
+
+    async fn put_bytes(&self, key: &str, data: Bytes) -> Result<()> {
+        let response = self.send(Method::PUT, key, &[], data.to_vec()).await?;
+        Self::check(response, "put", key).await?;
+        Ok(())
+    }
+
+    async fn get_to_file(&self, key: &str, dest: &Path) -> Result<u64> {
+        let response = self.send(Method::GET, key, &[], Vec::new()).await?;
+        let mut response = Self::check(response, "get", key).await?;
+
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp_path = temp_path_for(dest);
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        let mut written = 0u64;
+
+        let result: Result<()> = async {
+            while let Some(chunk) = response.chunk().await? {
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+            }
+            file.flush().await?;
+            Ok(())
+        }
+        .await;
+
+        drop(file);
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e);
+        }
+        tokio::fs::rename(&tmp_path, dest).await?;
+        Ok(written)
+    }
+
+    async fn get_bytes(&self, key: &str) -> Result<Bytes> {
+        let response = self.send(Method::GET, key, &[], Vec::new()).await?;
+        Ok(Self::check(response, "get", key).await?.bytes().await?)
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectMeta>> {
+        let response = self.send(Method::HEAD, key, &[], Vec::new()).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = Self::check(response, "head", key).await?;
+        let header = |name: reqwest::header::HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+
+        Ok(Some(ObjectMeta {
+            key: key.to_string(),
+            size: header(reqwest::header::CONTENT_LENGTH)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            etag: header(reqwest::header::ETAG),
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let response = self.send(Method::DELETE, key, &[], Vec::new()).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        Self::check(response, "delete", key).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(uri_encode("a b/c~d.txt", false), "a%20b/c~d.txt");
+        assert_eq!(uri_encode("a/b", true), "a%2Fb");
+        assert_eq!(uri_encode("ü", false), "%C3%BC");
+    }
+
+    #[test]
+    fn test_etags_and_part_sizes() {
+        assert_eq!(unquote_etag("\"abc\""), "abc");
+        assert_eq!(unquote_etag("&quot;abc-2&quot;"), "abc-2");
+        assert_eq!(S3Backend::part_size(1, 0), S3_MIN_PART_SIZE);
+        assert_eq!(S3Backend::part_size(1, MIN_PART_SIZE), MIN_PART_SIZE);
+        let huge = 100 * 1024 * 1024 * 1024;
+        assert!(S3Backend::part_size(huge, S3_MIN_PART_SIZE) * MAX_PARTS >= huge);
+    }
+
+    #[test]
+    fn test_xml_value() {
+        let xml = "<InitiateMultipartUploadResult><UploadId>abc</UploadId></InitiateMultipartUploadResult>";
+        assert_eq!(xml_value(xml, "UploadId"), Some("abc"));
+        assert_eq!(xml_value(xml, "Code"), None);
+    }
+
+    #[test]
+    fn test_signing_key_matches_aws_example() {
+        // Example from the AWS SigV4 documentation
+        let key = [b"us-east-1".as_slice(), b"iam", b"aws4_request"]
+            .iter()
+            .fold(
+                hmac_sha256(b"AWS4wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", b"20150830"),
+                |key, part| hmac_sha256(&key, part),
+            );
+        assert_eq!(
+            hex::encode(key),
+            "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+        );
+    }
+
+    #[test]
+    fn test_path_style_config() {
+        let config = serde_json::json!({
+            "endpoint": "http://localhost:9000",
+            "bucket": "syncspace",
+            "access_key_id": "minio",
+            "secret_access_key": "minio123",
+            "prefix": "/tenant-a/"
+        });
+        let backend = S3Backend::from_config("b1", "minio", &config).unwrap();
+        assert_eq!(backend.host, "localhost:9000");
+        assert_eq!(
+            backend.object_uri("objects/ab/file").unwrap(),
+            "/syncspace/tenant-a/objects/ab/file"
+        );
+    }
+}
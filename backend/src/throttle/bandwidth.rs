@@ -0,0 +1,402 @@
+//! Bandwidth quotas and shaping
+//!
+//! [`meter`] wraps the routers that move file contents. Request bodies count
+//! as uploads and response bodies as downloads against the daily and monthly
+//! limits in `user_bandwidth_quotas`; the bytes are added to
+//! `bandwidth_usage` when a body has been sent or is dropped. Once a limit
+//! is reached, further transfers in that direction are refused with 429 and
+//! the user gets a `bandwidth_exceeded` alert; passing [`WARNING_PERCENT`]
+//! raises `bandwidth_warning`. `upload_bytes_per_second` and
+//! `download_bytes_per_second` pace the bodies to that rate.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use futures_util::{StreamExt, stream};
+use sqlx::{FromRow, SqlitePool};
+use std::time::{Duration, Instant};
+
+use super::{CACHE_TTL, MAX_ENTRIES, Throttle, raise_alert};
+use crate::auth::User;
+
+/// Share of a limit after which the user is warned
+pub const WARNING_PERCENT: i64 = 80;
+
+/// A user's row of `user_bandwidth_quotas`; no row means no limits
+#[derive(Debug, Clone, Default, FromRow)]
+pub struct Quota {
+    pub daily_upload_limit_bytes: Option<i64>,
+    pub daily_download_limit_bytes: Option<i64>,
+    pub monthly_upload_limit_bytes: Option<i64>,
+    pub monthly_download_limit_bytes: Option<i64>,
+    pub upload_bytes_per_second: Option<i64>,
+    pub download_bytes_per_second: Option<i64>,
+    pub is_unlimited: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::Upload => "upload",
+            Direction::Download => "download",
+        }
+    }
+}
+
+impl Quota {
+    /// Daily and monthly limit in one direction
+    pub fn limits(&self, direction: Direction) -> [Option<i64>; 2] {
+        if self.is_unlimited {
+            return [None, None];
+        }
+        let limits = match direction {
+            Direction::Upload => [
+                self.daily_upload_limit_bytes,
+                self.monthly_upload_limit_bytes,
+            ],
+            Direction::Download => [
+                self.daily_download_limit_bytes,
+                self.monthly_download_limit_bytes,
+            ],
+        };
+        limits.map(|limit| limit.filter(|limit| *limit >= 0))
+    }
+
+    /// Bytes per second a transfer is paced to
+    pub fn rate(&self, direction: Direction) -> Option<u64> {
+        let rate = match direction {
+            Direction::Upload => self.upload_bytes_per_second,
+            Direction::Download => self.download_bytes_per_second,
+        };
+        rate.filter(|rate| *rate > 0).map(|rate| rate as u64)
+    }
+
+    /// The highest share, in percent, of a limit that `usage` (daily and
+    /// monthly bytes) uses up; `None` without limits
+    pub fn percent_used(&self, direction: Direction, usage: [i64; 2]) -> Option<i64> {
+        self.limits(direction)
+            .iter()
+            .zip(usage)
+            .filter_map(|(limit, used)| {
+                limit.map(|limit| match limit {
+                    0 => 100,
+                    limit => used.saturating_mul(100) / limit,
+                })
+            })
+            .max()
+    }
+}
+
+/// Bytes moved today and this month
+pub async fn usage(
+    pool: &SqlitePool,
+    user_id: &str,
+    direction: Direction,
+) -> Result<[i64; 2], sqlx::Error> {
+    let now = chrono::Utc::now();
+    let column = match direction {
+        Direction::Upload => "upload_bytes",
+        Direction::Download => "download_bytes",
+    };
+    let (daily, monthly): (i64, i64) = sqlx::query_as(&format!(
+        "SELECT COALESCE(SUM(CASE WHEN date = ? THEN {0} END), 0), COALESCE(SUM({0}), 0)
+         FROM bandwidth_usage WHERE user_id = ? AND date >= ?",
+        column
+    ))
+    .bind(now.format("%Y-%m-%d").to_string())
+    .bind(user_id)
+    .bind(now.format("%Y-%m-01").to_string())
+    .fetch_one(pool)
+    .await?;
+    Ok([daily, monthly])
+}
+
+/// Add transferred bytes to today's usage and warn the user when this
+/// passes a threshold
+pub async fn record(
+    pool: &SqlitePool,
+    user_id: &str,
+    direction: Direction,
+    bytes: u64,
+    quota: &Quota,
+) -> Result<(), sqlx::Error> {
+    let bytes = bytes as i64;
+    let (upload, download) = match direction {
+        Direction::Upload => (bytes, 0),
+        Direction::Download => (0, bytes),
+    };
+    sqlx::query(
+        "INSERT INTO bandwidth_usage (user_id, date, upload_bytes, download_bytes)
+         VALUES (?, ?, ?, ?)
+         ON CONFLICT(user_id, date) DO UPDATE SET
+             upload_bytes = upload_bytes + excluded.upload_bytes,
+             download_bytes = download_bytes + excluded.download_bytes,
+             updated_at = CURRENT_TIMESTAMP",
+    )
+    .bind(user_id)
+    .bind(chrono::Utc::now().format("%Y-%m-%d").to_string())
+    .bind(upload)
+    .bind(download)
+    .execute(pool)
+    .await?;
+
+    if quota.limits(direction).iter().all(Option::is_none) {
+        return Ok(());
+    }
+    let percent = quota
+        .percent_used(direction, usage(pool, user_id, direction).await?)
+        .unwrap_or(0);
+    if percent >= 100 {
+        exceeded(pool, user_id, direction).await;
+    } else if percent >= WARNING_PERCENT {
+        let message = format!(
+            "{}% of your {} bandwidth quota is used",
+            percent,
+            direction.name()
+        );
+        raise_alert(
+            pool,
+            user_id,
+            "bandwidth_warning",
+            Some(WARNING_PERCENT),
+            &message,
+        )
+        .await;
+    }
+    Ok(())
+}
+
+async fn exceeded(pool: &SqlitePool, user_id: &str, direction: Direction) {
+    let message = format!("Your {} bandwidth quota is used up", direction.name());
+    raise_alert(pool, user_id, "bandwidth_exceeded", Some(100), &message).await;
+}
+
+impl Throttle {
+    async fn quota(&self, user_id: &str) -> Result<Quota, sqlx::Error> {
+        if let Some((loaded, quota)) = self.inner.quotas.lock().unwrap().get(user_id)
+            && loaded.elapsed() < CACHE_TTL
+        {
+            return Ok(quota.clone());
+        }
+        let quota: Quota = sqlx::query_as(
+            "SELECT daily_upload_limit_bytes, daily_download_limit_bytes,
+                    monthly_upload_limit_bytes, monthly_download_limit_bytes,
+                    upload_bytes_per_second, download_bytes_per_second, is_unlimited
+             FROM user_bandwidth_quotas WHERE user_id = ?",
+        )
+        .bind(user_id)
+        .fetch_optional(self.pool())
+        .await?
+        .unwrap_or_default();
+        let mut cache = self.inner.quotas.lock().unwrap();
+        if cache.len() >= MAX_ENTRIES {
+            cache.retain(|_, (loaded, _)| loaded.elapsed() < CACHE_TTL);
+        }
+        cache.insert(user_id.to_string(), (Instant::now(), quota.clone()));
+        Ok(quota)
+    }
+
+    /// Whether a transfer of `size` more bytes (if known) is over the quota
+    async fn over_quota(
+        &self,
+        user_id: &str,
+        quota: &Quota,
+        direction: Direction,
+        size: Option<u64>,
+    ) -> Result<bool, sqlx::Error> {
+        let limits = quota.limits(direction);
+        if limits.iter().all(Option::is_none) {
+            return Ok(false);
+        }
+        let used = usage(self.pool(), user_id, direction).await?;
+        let size = size.unwrap_or(0) as i64;
+        Ok(limits
+            .iter()
+            .zip(used)
+            .any(|(limit, used)| limit.is_some_and(|limit| used >= limit || used + size > limit)))
+    }
+}
+
+/// Whether a request sends content
+fn has_body(req: &Request) -> bool {
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match length {
+        Some(length) => length > 0,
+        None => req.headers().contains_key(header::TRANSFER_ENCODING),
+    }
+}
+
+/// Count the request and response bodies of authenticated users against
+/// their bandwidth quota, refusing transfers over it and pacing the rest
+pub async fn meter(State(throttle): State<Throttle>, req: Request, next: Next) -> Response {
+    let Some(User(user)) = req.extensions().get::<User>().cloned() else {
+        return next.run(req).await;
+    };
+    let quota = match throttle.quota(&user.id).await {
+        Ok(quota) => quota,
+        Err(e) => {
+            tracing::error!("Failed to load bandwidth quota of {}: {}", user.id, e);
+            return next.run(req).await;
+        }
+    };
+
+    let refused = if has_body(&req) {
+        let size = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        throttle
+            .over_quota(&user.id, &quota, Direction::Upload, size)
+            .await
+            .map(|over| over.then_some(Direction::Upload))
+    } else if req.method() == Method::GET {
+        throttle
+            .over_quota(&user.id, &quota, Direction::Download, None)
+            .await
+            .map(|over| over.then_some(Direction::Download))
+    } else {
+        Ok(None)
+    };
+    match refused {
+        Ok(Some(direction)) => {
+            exceeded(throttle.pool(), &user.id, direction).await;
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(serde_json::json!({
+                    "error": "bandwidth quota exceeded",
+                    "direction": direction.name(),
+                })),
+            )
+                .into_response();
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to load bandwidth usage of {}: {}", user.id, e),
+    }
+
+    let pool = throttle.pool().clone();
+    let req = req.map(|body| metered(body, Tally::new(&pool, &user.id, Direction::Upload, &quota)));
+    next.run(req).await.map(|body| {
+        metered(
+            body,
+            Tally::new(&pool, &user.id, Direction::Download, &quota),
+        )
+    })
+}
+
+/// Bytes of one body; recorded when dropped
+struct Tally {
+    pool: SqlitePool,
+    user_id: String,
+    direction: Direction,
+    quota: Quota,
+    rate: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Tally {
+    fn new(pool: &SqlitePool, user_id: &str, direction: Direction, quota: &Quota) -> Self {
+        Self {
+            pool: pool.clone(),
+            user_id: user_id.to_string(),
+            direction,
+            quota: quota.clone(),
+            rate: quota.rate(direction),
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Count a chunk, then wait until the rate allows the bytes sent so far
+    async fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        if let Some(rate) = self.rate {
+            let due = self.started + Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+            tokio::time::sleep_until(due.into()).await;
+        }
+    }
+}
+
+impl Drop for Tally {
+    fn drop(&mut self) {
+        if self.bytes == 0 {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let (pool, user_id, direction, bytes, quota) = (
+            self.pool.clone(),
+            std::mem::take(&mut self.user_id),
+            self.direction,
+            self.bytes,
+            std::mem::take(&mut self.quota),
+        );
+        runtime.spawn(async move {
+            if let Err(e) = record(&pool, &user_id, direction, bytes, &quota).await {
+                tracing::warn!("Failed to record bandwidth of {}: {}", user_id, e);
+            }
+        });
+    }
+}
+
+fn metered(body: Body, tally: Tally) -> Body {
+    let chunks = body.into_data_stream();
+    Body::from_stream(stream::unfold(
+        (chunks, tally),
+        |(mut chunks, mut tally)| async move {
+            let chunk = chunks.next().await?;
+            if let Ok(bytes) = &chunk {
+                tally.add(bytes.len()).await;
+            }
+            Some((chunk, (chunks, tally)))
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_limits() {
+        let quota = Quota {
+            daily_download_limit_bytes: Some(100),
+            monthly_download_limit_bytes: Some(1000),
+            download_bytes_per_second: Some(0),
+            upload_bytes_per_second: Some(50),
+            ..Quota::default()
+        };
+        assert_eq!(quota.limits(Direction::Upload), [None, None]);
+        assert_eq!(quota.percent_used(Direction::Upload, [500, 500]), None);
+        assert_eq!(quota.percent_used(Direction::Download, [80, 100]), Some(80));
+        assert_eq!(
+            quota.percent_used(Direction::Download, [10, 1200]),
+            Some(120)
+        );
+        assert_eq!(quota.rate(Direction::Download), None);
+        assert_eq!(quota.rate(Direction::Upload), Some(50));
+
+        let unlimited = Quota {
+            is_unlimited: true,
+            ..quota
+        };
+        assert_eq!(unlimited.limits(Direction::Download), [None, None]);
+        assert_eq!(unlimited.rate(Direction::Upload), Some(50));
+    }
+}
@@ -0,0 +1,782 @@
+//! Live rate limits and bandwidth quotas
+//!
+//! The rules managed at `/rate-limits` (`api_rate_limits`) are applied to
+//! every API request by [`limit`]. A rule applies to one user, one role, one
+//! API token, one client address or range, or to everyone when it names none
+//! of them, on the paths its `endpoint_pattern` matches (`*` wildcards, with
+//! or without the `/api` prefix). Rules with the same pattern do not stack:
+//! the most specific one applies (token, user, address, role), so a rule for
+//! one user can raise or lower the limits of their role.
+//!
+//! Each rule keeps a token bucket per caller, chosen by its `limit_by`
+//! column: per `user` (the default; anonymous callers by address), per
+//! `token` (requests without one by user) or per `ip`. The bucket holds
+//! `burst_limit` requests and refills at `requests_per_minute`; hourly and
+//! daily counts are capped as well. A pattern matching several routes limits
+//! them as one group. Changed rules apply within [`CACHE_TTL`].
+//!
+//! Responses carry `RateLimit-Limit`, `RateLimit-Remaining`,
+//! `RateLimit-Reset` and `RateLimit-Policy` for the tightest applicable
+//! limit. Refused requests get 429 with `Retry-After`, are logged to
+//! `api_request_log` and raise a quota alert for the user. Bandwidth quotas
+//! are applied by [`bandwidth::meter`].
+
+pub mod bandwidth;
+
+use axum::{
+    extract::{ConnectInfo, OriginalUri, Request, State},
+    http::{Extensions, HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use sqlx::{FromRow, SqlitePool};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::User;
+use crate::notify::{EventType, Notification};
+use crate::rbac::tokens::{self, TokenGrant};
+
+/// How long rules, role memberships and bandwidth quotas are cached
+pub const CACHE_TTL: Duration = Duration::from_secs(30);
+/// Counters and cache entries unused this long are dropped
+const IDLE_TTL: Duration = Duration::from_secs(24 * 3600);
+/// Entries kept before idle ones are pruned
+const MAX_ENTRIES: usize = 10_000;
+/// Refused requests of one counter are logged at most this often
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+const HOUR: Duration = Duration::from_secs(3600);
+const DAY: Duration = Duration::from_secs(24 * 3600);
+
+/// An enabled row of `api_rate_limits`
+#[derive(Debug, Clone, FromRow)]
+pub struct Rule {
+    pub id: String,
+    pub user_id: Option<String>,
+    pub role_name: Option<String>,
+    pub token_id: Option<String>,
+    pub ip_address: Option<String>,
+    pub endpoint_pattern: String,
+    pub requests_per_minute: i64,
+    pub requests_per_hour: i64,
+    pub requests_per_day: i64,
+    pub burst_limit: i64,
+    pub limit_by: String,
+}
+
+impl Rule {
+    fn specificity(&self) -> u8 {
+        if self.token_id.is_some() {
+            4
+        } else if self.user_id.is_some() {
+            3
+        } else if self.ip_address.is_some() {
+            2
+        } else if self.role_name.is_some() {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Whether the rule names the caller (or nobody)
+    pub fn applies_to(&self, caller: &Caller) -> bool {
+        self.user_id
+            .as_ref()
+            .is_none_or(|id| caller.user_id.as_ref() == Some(id))
+            && self
+                .role_name
+                .as_ref()
+                .is_none_or(|role| caller.roles.contains(role))
+            && self
+                .token_id
+                .as_ref()
+                .is_none_or(|id| caller.token_id.as_ref() == Some(id))
+            && self
+                .ip_address
+                .as_ref()
+                .is_none_or(|rule| caller.ip.is_some_and(|ip| tokens::ip_matches(rule, ip)))
+    }
+
+    /// Whose counter a request of `caller` uses
+    fn subject(&self, caller: &Caller) -> String {
+        let ip = || match caller.ip {
+            Some(ip) => format!("ip:{}", ip),
+            None => "ip:unknown".to_string(),
+        };
+        let user = || caller.user_id.as_ref().map(|id| format!("user:{}", id));
+        match self.limit_by.as_str() {
+            "ip" => ip(),
+            "token" => caller
+                .token_id
+                .as_ref()
+                .map(|id| format!("token:{}", id))
+                .or_else(user)
+                .unwrap_or_else(ip),
+            _ => user().unwrap_or_else(ip),
+        }
+    }
+
+    /// The rule's limits as a `RateLimit-Policy` value
+    fn policy(&self) -> String {
+        let mut parts = Vec::new();
+        if self.requests_per_minute > 0 {
+            parts.push(format!(
+                "{};w=60;burst={}",
+                self.requests_per_minute,
+                self.burst_limit.max(1)
+            ));
+        }
+        if self.requests_per_hour > 0 {
+            parts.push(format!("{};w=3600", self.requests_per_hour));
+        }
+        if self.requests_per_day > 0 {
+            parts.push(format!("{};w=86400", self.requests_per_day));
+        }
+        parts.join(", ")
+    }
+}
+
+/// Who is making a request
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    pub user_id: Option<String>,
+    pub roles: Vec<String>,
+    pub token_id: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+/// Whether an endpoint pattern matches `path`; `*` matches anything
+pub fn pattern_matches(pattern: &str, path: &str) -> bool {
+    fn glob(pattern: &str, path: &str) -> bool {
+        let parts: Vec<&str> = pattern.split('*').collect();
+        let (first, last) = (parts[0], parts[parts.len() - 1]);
+        if parts.len() == 1 {
+            return pattern == path;
+        }
+        let Some(mut rest) = path.strip_prefix(first) else {
+            return false;
+        };
+        for part in &parts[1..parts.len() - 1] {
+            match rest.find(part) {
+                Some(i) => rest = &rest[i + part.len()..],
+                None => return false,
+            }
+        }
+        rest.ends_with(last)
+    }
+    glob(pattern, path)
+        || path
+            .strip_prefix("/api")
+            .is_some_and(|rest| glob(pattern, rest))
+}
+
+/// The rules that limit `caller` on `path`: for each pattern the most
+/// specific of the rules that apply
+pub fn applicable<'a>(rules: &'a [Rule], caller: &Caller, path: &str) -> Vec<&'a Rule> {
+    let mut best: HashMap<&str, Vec<&Rule>> = HashMap::new();
+    for rule in rules
+        .iter()
+        .filter(|rule| rule.applies_to(caller) && pattern_matches(&rule.endpoint_pattern, path))
+    {
+        let group = best.entry(rule.endpoint_pattern.as_str()).or_default();
+        match group.first().map(|other| other.specificity()) {
+            Some(specificity) if specificity > rule.specificity() => {}
+            Some(specificity) if specificity == rule.specificity() => group.push(rule),
+            _ => *group = vec![rule],
+        }
+    }
+    let mut applicable: Vec<&Rule> = best.into_values().flatten().collect();
+    applicable.sort_by(|a, b| a.id.cmp(&b.id));
+    applicable
+}
+
+/// Where a caller stands against a limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the limit is fully available again
+    pub reset: u64,
+    /// Seconds until the next request is allowed; 0 when it is
+    pub retry_after: u64,
+    pub policy: String,
+}
+
+impl Status {
+    /// Orders statuses from loose to tight: refusals, longest wait first,
+    /// then fewer remaining requests
+    fn tightness(&self) -> (u64, Reverse<u64>) {
+        (self.retry_after, Reverse(self.remaining))
+    }
+
+    /// Set the `RateLimit-*` headers, and `Retry-After` when refused
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(self.reset));
+        if let Ok(policy) = HeaderValue::from_str(&self.policy) {
+            headers.insert("ratelimit-policy", policy);
+        }
+        if self.retry_after > 0 {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(self.retry_after));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    started: Instant,
+    count: u64,
+}
+
+impl Window {
+    fn status(&mut self, limit: i64, length: Duration, now: Instant) -> Option<(u64, u64, u64)> {
+        if limit <= 0 {
+            return None;
+        }
+        if now.duration_since(self.started) >= length {
+            *self = Window {
+                started: now,
+                count: 0,
+            };
+        }
+        let limit = limit as u64;
+        let left = length.saturating_sub(now.duration_since(self.started));
+        let reset = left.as_secs_f64().ceil() as u64;
+        let retry_after = if self.count < limit { 0 } else { reset.max(1) };
+        Some((limit, limit.saturating_sub(self.count), retry_after))
+    }
+}
+
+/// The requests one caller has made under one rule
+#[derive(Debug, Clone)]
+pub struct Counter {
+    tokens: f64,
+    refilled: Instant,
+    hour: Window,
+    day: Window,
+    last_used: Instant,
+    logged: Option<Instant>,
+}
+
+impl Counter {
+    pub fn new(rule: &Rule, now: Instant) -> Self {
+        let window = Window {
+            started: now,
+            count: 0,
+        };
+        Self {
+            tokens: rule.burst_limit.max(1) as f64,
+            refilled: now,
+            hour: window,
+            day: window,
+            last_used: now,
+            logged: None,
+        }
+    }
+
+    /// The tightest of the rule's limits at `now`, without using any;
+    /// `None` when the rule sets none
+    pub fn status(&mut self, rule: &Rule, now: Instant) -> Option<Status> {
+        self.last_used = now;
+        let mut tightest: Option<Status> = None;
+        let mut consider = |limit: u64, remaining: u64, reset: u64, retry_after: u64| {
+            let status = Status {
+                limit,
+                remaining,
+                reset,
+                retry_after,
+                policy: rule.policy(),
+            };
+            tightest = Some(match tightest.take() {
+                Some(other) if other.tightness() >= status.tightness() => other,
+                _ => status,
+            });
+        };
+
+        if rule.requests_per_minute > 0 {
+            let capacity = rule.burst_limit.max(1) as f64;
+            let rate = rule.requests_per_minute as f64 / 60.0;
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(capacity);
+            self.refilled = now;
+            let retry_after = if self.tokens >= 1.0 {
+                0
+            } else {
+                (((1.0 - self.tokens) / rate).ceil() as u64).max(1)
+            };
+            consider(
+                capacity as u64,
+                self.tokens.floor() as u64,
+                ((capacity - self.tokens) / rate).ceil() as u64,
+                retry_after,
+            );
+        }
+        if let Some((limit, remaining, retry_after)) =
+            self.hour.status(rule.requests_per_hour, HOUR, now)
+        {
+            let reset = HOUR
+                .saturating_sub(now.duration_since(self.hour.started))
+                .as_secs();
+            consider(limit, remaining, reset, retry_after);
+        }
+        if let Some((limit, remaining, retry_after)) =
+            self.day.status(rule.requests_per_day, DAY, now)
+        {
+            let reset = DAY
+                .saturating_sub(now.duration_since(self.day.started))
+                .as_secs();
+            consider(limit, remaining, reset, retry_after);
+        }
+        tightest
+    }
+
+    /// Count a request that was let through
+    pub fn consume(&mut self) {
+        self.tokens -= 1.0;
+        self.hour.count += 1;
+        self.day.count += 1;
+    }
+}
+
+/// The outcome of counting a request
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub allowed: bool,
+    /// The tightest limit; the one refusing the request when not allowed
+    pub status: Status,
+    pub pattern: String,
+    /// Whether the refusal should be logged (not for every repeated one)
+    pub log: bool,
+}
+
+struct Inner {
+    pool: SqlitePool,
+    rules: Mutex<Option<(Instant, Arc<Vec<Rule>>)>>,
+    roles: Mutex<HashMap<String, (Instant, Vec<String>)>>,
+    counters: Mutex<HashMap<String, Counter>>,
+    quotas: Mutex<HashMap<String, (Instant, bandwidth::Quota)>>,
+}
+
+/// Middleware state shared by every router the limits apply to
+#[derive(Clone)]
+pub struct Throttle {
+    inner: Arc<Inner>,
+}
+
+impl Throttle {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                pool,
+                rules: Mutex::new(None),
+                roles: Mutex::new(HashMap::new()),
+                counters: Mutex::new(HashMap::new()),
+                quotas: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.inner.pool
+    }
+
+    async fn rules(&self) -> Result<Arc<Vec<Rule>>, sqlx::Error> {
+        if let Some((loaded, rules)) = self.inner.rules.lock().unwrap().as_ref()
+            && loaded.elapsed() < CACHE_TTL
+        {
+            return Ok(rules.clone());
+        }
+        let rules: Vec<Rule> = sqlx::query_as(
+            "SELECT id, user_id, role_name, token_id, ip_address, endpoint_pattern,
+                    requests_per_minute, requests_per_hour, requests_per_day, burst_limit, limit_by
+             FROM api_rate_limits WHERE is_enabled = 1",
+        )
+        .fetch_all(self.pool())
+        .await?;
+        let rules = Arc::new(rules);
+        *self.inner.rules.lock().unwrap() = Some((Instant::now(), rules.clone()));
+        Ok(rules)
+    }
+
+    async fn roles(&self, user_id: &str) -> Result<Vec<String>, sqlx::Error> {
+        if let Some((loaded, roles)) = self.inner.roles.lock().unwrap().get(user_id)
+            && loaded.elapsed() < CACHE_TTL
+        {
+            return Ok(roles.clone());
+        }
+        let roles = crate::rbac::role_names(self.pool(), user_id).await?;
+        let mut cache = self.inner.roles.lock().unwrap();
+        if cache.len() >= MAX_ENTRIES {
+            cache.retain(|_, (loaded, _)| loaded.elapsed() < CACHE_TTL);
+        }
+        cache.insert(user_id.to_string(), (Instant::now(), roles.clone()));
+        Ok(roles)
+    }
+
+    /// The caller of a request, from what the auth middleware stored
+    pub async fn caller(
+        &self,
+        headers: &HeaderMap,
+        extensions: &Extensions,
+    ) -> Result<Caller, sqlx::Error> {
+        let peer = extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let mut caller = Caller {
+            ip: tokens::client_ip(headers, peer),
+            token_id: extensions
+                .get::<TokenGrant>()
+                .map(|grant| grant.token_id.clone()),
+            ..Caller::default()
+        };
+        if let Some(User(user)) = extensions.get::<User>() {
+            caller.roles = self.roles(&user.id).await?;
+            caller.user_id = Some(user.id.clone());
+        }
+        Ok(caller)
+    }
+
+    /// Count a request of `caller` to `path` against the applicable rules;
+    /// `None` when no rule limits it. Refused requests use up nothing.
+    pub async fn check(
+        &self,
+        caller: &Caller,
+        path: &str,
+    ) -> Result<Option<Decision>, sqlx::Error> {
+        let rules = self.rules().await?;
+        let applicable = applicable(&rules, caller, path);
+        if applicable.is_empty() {
+            return Ok(None);
+        }
+
+        let now = Instant::now();
+        let mut counters = self.inner.counters.lock().unwrap();
+        if counters.len() >= MAX_ENTRIES {
+            counters.retain(|_, counter| now.duration_since(counter.last_used) < IDLE_TTL);
+        }
+        let keys: Vec<String> = applicable
+            .iter()
+            .map(|rule| format!("{}|{}", rule.id, rule.subject(caller)))
+            .collect();
+        let mut statuses = Vec::new();
+        for (rule, key) in applicable.iter().zip(&keys) {
+            let counter = counters
+                .entry(key.clone())
+                .or_insert_with(|| Counter::new(rule, now));
+            if let Some(status) = counter.status(rule, now) {
+                statuses.push((*rule, key, status));
+            }
+        }
+        let allowed = statuses.iter().all(|(.., status)| status.retry_after == 0);
+
+        let mut decision: Option<Decision> = None;
+        for (rule, key, mut status) in statuses {
+            let counter = counters.get_mut(key).expect("counter was just inserted");
+            if allowed {
+                counter.consume();
+                status = counter.status(rule, now).unwrap_or(status);
+            }
+            let candidate = Decision {
+                allowed,
+                status,
+                pattern: rule.endpoint_pattern.clone(),
+                log: false,
+            };
+            decision = Some(match decision {
+                Some(other) if other.status.tightness() >= candidate.status.tightness() => other,
+                _ => candidate,
+            });
+        }
+
+        let Some(mut decision) = decision else {
+            return Ok(None);
+        };
+        if !allowed {
+            // Log a refusal once per counter and interval, not every retry
+            for key in &keys {
+                if let Some(counter) = counters.get_mut(key)
+                    && counter
+                        .logged
+                        .is_none_or(|logged| now.duration_since(logged) >= LOG_INTERVAL)
+                {
+                    counter.logged = Some(now);
+                    decision.log = true;
+                }
+            }
+        }
+        Ok(Some(decision))
+    }
+
+    async fn log_refusal(
+        &self,
+        caller: &Caller,
+        method: &str,
+        path: &str,
+        user_agent: Option<&str>,
+        decision: &Decision,
+    ) {
+        let result = sqlx::query(
+            "INSERT INTO api_request_log (user_id, endpoint, method, status_code, ip_address, user_agent, is_rate_limited)
+             VALUES (?, ?, ?, 429, ?, ?, 1)",
+        )
+        .bind(&caller.user_id)
+        .bind(path)
+        .bind(method)
+        .bind(caller.ip.map(|ip| ip.to_string()))
+        .bind(user_agent)
+        .execute(self.pool())
+        .await;
+        if let Err(e) = result {
+            tracing::warn!("Failed to log rate-limited request: {}", e);
+        }
+
+        if let Some(user_id) = &caller.user_id {
+            let message = format!(
+                "Rate limit exceeded on {} ({})",
+                decision.pattern, decision.status.policy
+            );
+            raise_alert(self.pool(), user_id, "rate_limit_exceeded", None, &message).await;
+        }
+    }
+}
+
+/// Add a quota alert for the user, and notify them of it, unless an
+/// unacknowledged one of the same type was raised in the last day
+pub async fn raise_alert(
+    pool: &SqlitePool,
+    user_id: &str,
+    alert_type: &str,
+    threshold_percent: Option<i64>,
+    message: &str,
+) {
+    let result = sqlx::query(
+        "INSERT INTO quota_alerts (user_id, alert_type, threshold_percent, message)
+         SELECT ?, ?, ?, ?
+         WHERE NOT EXISTS (
+             SELECT 1 FROM quota_alerts
+             WHERE user_id = ? AND alert_type = ? AND is_acknowledged = 0
+               AND created_at > datetime('now', '-1 day')
+         )",
+    )
+    .bind(user_id)
+    .bind(alert_type)
+    .bind(threshold_percent)
+    .bind(message)
+    .bind(user_id)
+    .bind(alert_type)
+    .execute(pool)
+    .await;
+    match result {
+        Ok(done) if done.rows_affected() > 0 => {
+            tracing::info!("Quota alert for {}: {}", user_id, message);
+            let title = format!("Quota alert: {}", alert_type.replace('_', " "));
+            crate::notify::send(
+                Notification::new(user_id, EventType::QuotaAlert, &title, message)
+                    .with_action_url("/#/storage-analytics")
+                    .with_priority("high")
+                    .with_context(serde_json::json!({
+                        "alert_type": alert_type,
+                        "threshold_percent": threshold_percent,
+                    })),
+            );
+        }
+        Ok(_) => {}
+        Err(e) => tracing::warn!("Failed to raise quota alert for {}: {}", user_id, e),
+    }
+}
+
+/// Apply the rate limit rules to a request. Runs after authentication so
+/// that user, role and token are known; errors loading the rules let the
+/// request through.
+pub async fn limit(State(throttle): State<Throttle>, req: Request, next: Next) -> Response {
+    let path = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.path().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    let checked = match throttle.caller(req.headers(), req.extensions()).await {
+        Ok(caller) => throttle
+            .check(&caller, &path)
+            .await
+            .map(|decision| (caller, decision)),
+        Err(e) => Err(e),
+    };
+    let (caller, decision) = match checked {
+        Ok((caller, Some(decision))) => (caller, decision),
+        Ok((_, None)) => return next.run(req).await,
+        Err(e) => {
+            tracing::error!("Failed to apply rate limits: {}", e);
+            return next.run(req).await;
+        }
+    };
+
+    if decision.allowed {
+        let mut response = next.run(req).await;
+        decision.status.apply(response.headers_mut());
+        return response;
+    }
+
+    tracing::debug!("Rate limited {} on {}", path, decision.pattern);
+    if decision.log {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let method = req.method().to_string();
+        throttle
+            .log_refusal(&caller, &method, &path, user_agent.as_deref(), &decision)
+            .await;
+    }
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(serde_json::json!({
+            "error": "rate limit exceeded",
+            "retry_after": decision.status.retry_after,
+        })),
+    )
+        .into_response();
+    decision.status.apply(response.headers_mut());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(id: &str, pattern: &str, per_minute: i64, burst: i64) -> Rule {
+        Rule {
+            id: id.to_string(),
+            user_id: None,
+            role_name: None,
+            token_id: None,
+            ip_address: None,
+            endpoint_pattern: pattern.to_string(),
+            requests_per_minute: per_minute,
+            requests_per_hour: 0,
+            requests_per_day: 0,
+            burst_limit: burst,
+            limit_by: "user".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_pattern_matches() {
+        assert!(pattern_matches("*", "/api/files/a.txt"));
+        assert!(pattern_matches("/api/search*", "/api/search/suggest"));
+        assert!(pattern_matches("/search*", "/api/search"));
+        assert!(pattern_matches(
+            "/files/*/versions",
+            "/api/files/a/b/versions"
+        ));
+        assert!(!pattern_matches("/files/*/versions", "/api/files/a/b"));
+        assert!(pattern_matches("/api/health", "/api/health"));
+        assert!(!pattern_matches("/api/health", "/api/healthz"));
+    }
+
+    #[test]
+    fn test_most_specific_rule_per_pattern() {
+        let mut role = rule("role", "*", 60, 10);
+        role.role_name = Some("user".to_string());
+        let mut alice = rule("alice", "*", 600, 100);
+        alice.user_id = Some("alice".to_string());
+        let search = rule("search", "/search*", 10, 2);
+        let rules = vec![role, alice, search];
+
+        let caller = |user: &str| Caller {
+            user_id: Some(user.to_string()),
+            roles: vec!["user".to_string()],
+            ..Caller::default()
+        };
+        let ids = |caller: &Caller, path: &str| -> Vec<String> {
+            applicable(&rules, caller, path)
+                .iter()
+                .map(|rule| rule.id.clone())
+                .collect()
+        };
+        assert_eq!(ids(&caller("alice"), "/api/files"), vec!["alice"]);
+        assert_eq!(ids(&caller("bob"), "/api/search"), vec!["role", "search"]);
+        assert_eq!(ids(&Caller::default(), "/api/files"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut rule = rule("r", "*", 60, 2);
+        rule.requests_per_hour = 100;
+        let start = Instant::now();
+        let mut counter = Counter::new(&rule, start);
+
+        for remaining in [2, 1] {
+            let status = counter.status(&rule, start).unwrap();
+            assert_eq!(
+                (status.limit, status.remaining, status.retry_after),
+                (2, remaining, 0)
+            );
+            counter.consume();
+        }
+        let status = counter.status(&rule, start).unwrap();
+        assert_eq!(
+            (status.remaining, status.retry_after, status.reset),
+            (0, 1, 2)
+        );
+        assert_eq!(status.policy, "60;w=60;burst=2, 100;w=3600");
+
+        // One request per second comes back
+        let status = counter
+            .status(&rule, start + Duration::from_millis(1500))
+            .unwrap();
+        assert_eq!((status.remaining, status.retry_after), (1, 0));
+        let status = counter
+            .status(&rule, start + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(status.remaining, 2);
+    }
+
+    #[test]
+    fn test_windows() {
+        let mut hourly = rule("r", "*", 0, 0);
+        hourly.requests_per_hour = 2;
+        let start = Instant::now();
+        let mut counter = Counter::new(&hourly, start);
+        counter.consume();
+        counter.consume();
+
+        let status = counter
+            .status(&hourly, start + Duration::from_secs(600))
+            .unwrap();
+        assert_eq!((status.limit, status.remaining), (2, 0));
+        assert_eq!(status.retry_after, 3000);
+        let status = counter.status(&hourly, start + HOUR).unwrap();
+        assert_eq!((status.remaining, status.retry_after), (2, 0));
+
+        let unlimited = rule("none", "*", 0, 0);
+        assert!(
+            Counter::new(&unlimited, start)
+                .status(&unlimited, start)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_subjects() {
+        let caller = Caller {
+            user_id: Some("alice".to_string()),
+            token_id: Some("t1".to_string()),
+            ip: "10.0.0.1".parse().ok(),
+            ..Caller::default()
+        };
+        let mut by = rule("r", "*", 60, 1);
+        assert_eq!(by.subject(&caller), "user:alice");
+        by.limit_by = "token".to_string();
+        assert_eq!(by.subject(&caller), "token:t1");
+        by.limit_by = "ip".to_string();
+        assert_eq!(by.subject(&caller), "ip:10.0.0.1");
+        assert_eq!(by.subject(&Caller::default()), "ip:unknown");
+    }
+}
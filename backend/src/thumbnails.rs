@@ -117,12 +117,12 @@ pub async fn generate_thumbnail(
     
     let thumb_path = get_thumbnail_path(file_id, size);
     
-    // Check if already exists
-    if thumb_path.exists() {
+    // Check if already exists (locally or mirrored on a storage backend)
+    if crate::storage::fetch_local_file(&thumb_path).await.unwrap_or(false) {
         return Ok(thumb_path);
     }
     
-    if is_image(source_path) {
+    let generated = if is_image(source_path) {
         generate_image_thumbnail(source_path, &thumb_path, size).await
     } else if is_video(source_path) {
         generate_video_thumbnail(source_path, &thumb_path, size).await
@@ -130,7 +130,12 @@ pub async fn generate_thumbnail(
         generate_pdf_thumbnail(source_path, &thumb_path, size).await
     } else {
         Err(ThumbnailError::UnsupportedFormat)
+    }?;
+    
+    if let Err(e) = crate::storage::mirror_local_file(&generated).await {
+        tracing::warn!("Failed to mirror thumbnail {}: {}", generated.display(), e);
     }
+    Ok(generated)
 }
 
 /// Generate thumbnail for image using image crate (pure Rust)
@@ -299,7 +304,16 @@ pub async fn delete_thumbnails(file_id: &str) -> Result<u32, std::io::Error> {
     for size in [ThumbnailSize::Small, ThumbnailSize::Medium, ThumbnailSize::Large] {
         let path = get_thumbnail_path(file_id, size);
         if path.exists() {
-            fs::remove_file(path).await?;
+            fs::remove_file(&path).await?;
+            deleted += 1;
+        }
+        crate::storage::remove_local_file(&path).await;
+    }
+    // Written by the thumbnail background job
+    for name in [format!("{}.jpg", file_id), format!("{}.original", file_id)] {
+        let path = PathBuf::from(THUMBNAIL_DIR).join(name);
+        if path.exists() {
+            fs::remove_file(&path).await?;
             deleted += 1;
         }
     }
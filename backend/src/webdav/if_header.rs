@@ -0,0 +1,295 @@
+//! WebDAV `If` header (RFC 4918 section 10.4)
+//!
+//! The header is a list of condition lists, optionally tagged with the
+//! resource they apply to. It is true when any list that applies to a
+//! resource matches that resource's state, i.e. all of the list's state
+//! tokens and entity tags match (or don't, for `Not` conditions).
+
+/// A single state token or entity tag condition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Token { token: String, not: bool },
+    ETag { etag: String, not: bool },
+}
+
+/// One parenthesized list; `resource` is the tag of a tagged list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IfList {
+    pub resource: Option<String>,
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IfHeader {
+    pub lists: Vec<IfList>,
+}
+
+/// Current state of a resource named by the header
+#[derive(Debug, Default, Clone)]
+pub struct ResourceState {
+    /// `None` for unmapped resources
+    pub etag: Option<String>,
+    /// Tokens of the locks covering the resource
+    pub lock_tokens: Vec<String>,
+}
+
+impl IfHeader {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            input: value.as_bytes(),
+            pos: 0,
+        };
+        let mut lists = Vec::new();
+        let mut tagged = None;
+        let mut resource = None;
+
+        loop {
+            parser.skip_whitespace();
+            match parser.peek() {
+                None => break,
+                Some(b'<') => {
+                    if tagged == Some(false) {
+                        return Err("tagged and untagged lists are mixed".to_string());
+                    }
+                    tagged = Some(true);
+                    resource = Some(parser.coded_url()?);
+                    // A tag must be followed by at least one list
+                    parser.skip_whitespace();
+                    if parser.peek() != Some(b'(') {
+                        return Err("resource tag without a list".to_string());
+                    }
+                }
+                Some(b'(') => {
+                    if tagged.is_none() {
+                        tagged = Some(false);
+                    }
+                    let conditions = parser.list()?;
+                    lists.push(IfList {
+                        resource: resource.clone(),
+                        conditions,
+                    });
+                }
+                Some(other) => return Err(format!("unexpected '{}'", other as char)),
+            }
+        }
+
+        if lists.is_empty() {
+            return Err("empty If header".to_string());
+        }
+        Ok(Self { lists })
+    }
+
+    /// Every state token named in the header, i.e. the submitted lock tokens
+    pub fn tokens(&self) -> impl Iterator<Item = &str> {
+        self.lists
+            .iter()
+            .flat_map(|list| &list.conditions)
+            .filter_map(|condition| match condition {
+                Condition::Token { token, .. } => Some(token.as_str()),
+                Condition::ETag { .. } => None,
+            })
+    }
+
+    /// Tags of the tagged lists
+    pub fn resources(&self) -> impl Iterator<Item = &str> {
+        self.lists
+            .iter()
+            .filter_map(|list| list.resource.as_deref())
+    }
+
+    /// Evaluate the header. `state` resolves a list's tag (`None` for the
+    /// request URI) to the state of that resource.
+    pub fn evaluate<'a>(&self, state: impl Fn(Option<&str>) -> Option<&'a ResourceState>) -> bool {
+        let unmapped = ResourceState::default();
+        self.lists.iter().any(|list| {
+            let state = state(list.resource.as_deref()).unwrap_or(&unmapped);
+            list.conditions
+                .iter()
+                .all(|condition| condition.matches(state))
+        })
+    }
+}
+
+impl Condition {
+    fn matches(&self, state: &ResourceState) -> bool {
+        match self {
+            Condition::Token { token, not } => {
+                state.lock_tokens.iter().any(|current| current == token) != *not
+            }
+            Condition::ETag { etag, not } => {
+                let current = state.etag.as_deref().map(strip_weak);
+                (current == Some(strip_weak(etag))) != *not
+            }
+        }
+    }
+}
+
+fn strip_weak(etag: &str) -> &str {
+    etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            self.pos += 1;
+        }
+    }
+
+    /// Everything up to (not including) `end`, consuming `end`
+    fn until(&mut self, end: u8) -> Result<String, String> {
+        let start = self.pos;
+        while let Some(byte) = self.peek() {
+            self.pos += 1;
+            if byte == end {
+                return String::from_utf8(self.input[start..self.pos - 1].to_vec())
+                    .map_err(|_| "invalid UTF-8".to_string());
+            }
+        }
+        Err(format!("missing '{}'", end as char))
+    }
+
+    fn coded_url(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let url = self.until(b'>')?;
+        if url.is_empty() || url.contains(char::is_whitespace) {
+            return Err("invalid URL".to_string());
+        }
+        Ok(url)
+    }
+
+    fn list(&mut self) -> Result<Vec<Condition>, String> {
+        self.pos += 1;
+        let mut conditions = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let not = if self.input[self.pos..].starts_with(b"Not") {
+                self.pos += 3;
+                self.skip_whitespace();
+                true
+            } else {
+                false
+            };
+            match self.peek() {
+                Some(b'<') => conditions.push(Condition::Token {
+                    token: self.coded_url()?,
+                    not,
+                }),
+                Some(b'[') => {
+                    self.pos += 1;
+                    let etag = self.until(b']')?.trim().to_string();
+                    if etag.is_empty() {
+                        return Err("empty entity tag".to_string());
+                    }
+                    conditions.push(Condition::ETag { etag, not });
+                }
+                Some(b')') if !not => {
+                    self.pos += 1;
+                    break;
+                }
+                _ => return Err("invalid condition".to_string()),
+            }
+        }
+        if conditions.is_empty() {
+            return Err("empty list".to_string());
+        }
+        Ok(conditions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(etag: &str, tokens: &[&str]) -> ResourceState {
+        ResourceState {
+            etag: Some(etag.to_string()),
+            lock_tokens: tokens.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_untagged_and_tagged_lists() {
+        let header = IfHeader::parse(r#"(<urn:uuid:a> ["etag-1"]) (Not <DAV:no-lock>)"#).unwrap();
+        assert_eq!(header.lists.len(), 2);
+        assert_eq!(
+            header.lists[0].conditions,
+            vec![
+                Condition::Token {
+                    token: "urn:uuid:a".to_string(),
+                    not: false
+                },
+                Condition::ETag {
+                    etag: "\"etag-1\"".to_string(),
+                    not: false
+                },
+            ]
+        );
+        assert_eq!(
+            header.tokens().collect::<Vec<_>>(),
+            ["urn:uuid:a", "DAV:no-lock"]
+        );
+
+        let tagged =
+            IfHeader::parse("<http://host/dav/a> (<urn:uuid:a>) (<urn:uuid:b>) </dav/b> ([\"x\"])")
+                .unwrap();
+        assert_eq!(tagged.lists.len(), 3);
+        assert_eq!(
+            tagged.lists[1].resource.as_deref(),
+            Some("http://host/dav/a")
+        );
+        assert_eq!(tagged.resources().last(), Some("/dav/b"));
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_headers() {
+        for value in [
+            "",
+            "(",
+            "()",
+            "(<urn:uuid:a>",
+            "<http://host/a>",
+            "(<urn:uuid:a>) <http://host/a> (<urn:uuid:a>)",
+            "(Not)",
+            "([])",
+            "urn:uuid:a",
+        ] {
+            assert!(IfHeader::parse(value).is_err(), "accepted {:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_lists() {
+        let current = state("\"e1\"", &["urn:uuid:a"]);
+        let lookup = |_: Option<&str>| Some(&current);
+        let eval = |value: &str| IfHeader::parse(value).unwrap().evaluate(lookup);
+
+        assert!(eval("(<urn:uuid:a>)"));
+        assert!(eval("(<urn:uuid:a> [\"e1\"])"));
+        assert!(eval("(<urn:uuid:a> [W/\"e1\"])"));
+        assert!(!eval("(<urn:uuid:b>)"));
+        assert!(!eval("(<urn:uuid:a> [\"e2\"])"));
+        assert!(eval("(<urn:uuid:b>) (<urn:uuid:a>)"));
+        assert!(eval("(Not <DAV:no-lock>)"));
+        assert!(!eval("(<DAV:no-lock>)"));
+        assert!(eval("(<urn:uuid:b>) (Not <DAV:no-lock>)"));
+        assert!(!eval("(Not <urn:uuid:a>)"));
+    }
+
+    #[test]
+    fn test_evaluate_tagged_lists_against_their_resource() {
+        let a = state("\"a\"", &["urn:uuid:a"]);
+        let header = IfHeader::parse("</dav/a> (<urn:uuid:a>) </dav/b> ([\"b\"])").unwrap();
+
+        assert!(header.evaluate(|tag| (tag == Some("/dav/a")).then_some(&a)));
+        assert!(!header.evaluate(|_| None));
+    }
+}
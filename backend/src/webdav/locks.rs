@@ -0,0 +1,374 @@
+//! Write locks shared by WebDAV and the collaboration API
+//!
+//! Locks live in `collaborative_locks`. A lock covers its own path and, with
+//! depth infinity, everything below it. Exclusive locks conflict with every
+//! other lock on an overlapping path, shared locks only with exclusive ones.
+//! The lock token handed to clients is `urn:uuid:<id>`.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::SqlitePool;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Lock lifetime when the client does not ask for one
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30 * 60;
+/// Longest lifetime granted, also used for `Timeout: Infinite`
+pub const MAX_TIMEOUT_SECS: u64 = 7 * 24 * 60 * 60;
+
+const TOKEN_PREFIX: &str = "urn:uuid:";
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockScope {
+    Exclusive,
+    Shared,
+}
+
+impl LockScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LockScope::Exclusive => "exclusive",
+            LockScope::Shared => "shared",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DavLock {
+    pub id: String,
+    /// Root of the lock, relative to the data directory
+    pub path: String,
+    pub owner_id: String,
+    pub scope: LockScope,
+    pub depth_infinity: bool,
+    /// `DAV:owner` as sent by the client
+    pub owner_xml: Option<String>,
+    pub timeout_secs: u64,
+    pub locked_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Error)]
+pub enum LockError {
+    #[error("conflicts with lock {} on '{}'", .0.id, .0.path)]
+    Conflict(Box<DavLock>),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Parameters of a new lock
+#[derive(Debug, Clone)]
+pub struct NewLock<'a> {
+    pub path: &'a str,
+    pub owner_id: &'a str,
+    pub scope: LockScope,
+    pub depth_infinity: bool,
+    pub owner_xml: Option<&'a str>,
+    pub timeout_secs: u64,
+}
+
+type LockRow = (
+    String,
+    String,
+    String,
+    String,
+    String,
+    Option<String>,
+    i64,
+    String,
+    String,
+);
+
+const LOCK_COLUMNS: &str = "id, file_path, locked_by, lock_type, depth, owner_xml, \
+                            timeout_seconds, locked_at, expires_at";
+
+impl DavLock {
+    pub fn token(&self) -> String {
+        format!("{}{}", TOKEN_PREFIX, self.id)
+    }
+
+    /// Whether the lock applies to `path`
+    pub fn covers(&self, path: &str) -> bool {
+        self.path == path || (self.depth_infinity && is_below(path, &self.path))
+    }
+
+    /// Whether the lock applies to `path` or anything below it
+    pub fn overlaps_tree(&self, path: &str) -> bool {
+        self.covers(path) || is_below(&self.path, path)
+    }
+
+    /// Seconds until the lock expires
+    pub fn remaining_secs(&self) -> u64 {
+        (self.expires_at - Utc::now()).num_seconds().max(0) as u64
+    }
+
+    fn conflicts_with(&self, new: &NewLock<'_>) -> bool {
+        let overlapping =
+            self.covers(new.path) || (new.depth_infinity && is_below(&self.path, new.path));
+        overlapping && (self.scope == LockScope::Exclusive || new.scope == LockScope::Exclusive)
+    }
+
+    fn from_row(row: LockRow) -> Self {
+        let (id, path, owner_id, scope, depth, owner_xml, timeout, locked_at, expires_at) = row;
+        Self {
+            id,
+            path,
+            owner_id,
+            scope: if scope == "shared" {
+                LockScope::Shared
+            } else {
+                LockScope::Exclusive
+            },
+            depth_infinity: depth == "infinity",
+            owner_xml,
+            timeout_secs: timeout.max(0) as u64,
+            locked_at: parse_time(&locked_at),
+            expires_at: parse_time(&expires_at),
+        }
+    }
+}
+
+/// Whether `path` lies strictly below `ancestor` (`""` is the root)
+pub fn is_below(path: &str, ancestor: &str) -> bool {
+    if ancestor.is_empty() {
+        return !path.is_empty();
+    }
+    path.len() > ancestor.len()
+        && path.starts_with(ancestor)
+        && path.as_bytes()[ancestor.len()] == b'/'
+}
+
+/// Lock id of a `urn:uuid:` token
+pub fn token_id(token: &str) -> Option<&str> {
+    token
+        .strip_prefix(TOKEN_PREFIX)
+        .filter(|id| Uuid::parse_str(id).is_ok())
+}
+
+/// Clamp a requested lifetime to what the server grants
+pub fn clamp_timeout(requested: Option<u64>) -> u64 {
+    requested
+        .unwrap_or(DEFAULT_TIMEOUT_SECS)
+        .clamp(1, MAX_TIMEOUT_SECS)
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format(TIME_FORMAT).to_string()
+}
+
+/// Accepts our own format and the RFC 3339 timestamps written by older code
+fn parse_time(value: &str) -> DateTime<Utc> {
+    NaiveDateTime::parse_from_str(value, TIME_FORMAT)
+        .map(|t| t.and_utc())
+        .or_else(|_| DateTime::parse_from_rfc3339(value).map(|t| t.with_timezone(&Utc)))
+        .unwrap_or_default()
+}
+
+/// All locks that have not expired
+pub async fn active(pool: &SqlitePool) -> Result<Vec<DavLock>, sqlx::Error> {
+    let rows: Vec<LockRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM collaborative_locks WHERE expires_at > ? ORDER BY locked_at",
+        LOCK_COLUMNS
+    ))
+    .bind(format_time(Utc::now()))
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(DavLock::from_row).collect())
+}
+
+/// Active locks that apply to `path`
+pub async fn covering(pool: &SqlitePool, path: &str) -> Result<Vec<DavLock>, sqlx::Error> {
+    let mut locks = active(pool).await?;
+    locks.retain(|lock| lock.covers(path));
+    Ok(locks)
+}
+
+pub async fn find(pool: &SqlitePool, id: &str) -> Result<Option<DavLock>, sqlx::Error> {
+    let row: Option<LockRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM collaborative_locks WHERE id = ? AND expires_at > ?",
+        LOCK_COLUMNS
+    ))
+    .bind(id)
+    .bind(format_time(Utc::now()))
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(DavLock::from_row))
+}
+
+/// Create a lock unless an active lock conflicts with it
+pub async fn acquire(pool: &SqlitePool, new: NewLock<'_>) -> Result<DavLock, LockError> {
+    let now = Utc::now();
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM collaborative_locks WHERE expires_at <= ?")
+        .bind(format_time(now))
+        .execute(&mut *tx)
+        .await?;
+    let rows: Vec<LockRow> =
+        sqlx::query_as(&format!("SELECT {} FROM collaborative_locks", LOCK_COLUMNS))
+            .fetch_all(&mut *tx)
+            .await?;
+    if let Some(existing) = rows
+        .into_iter()
+        .map(DavLock::from_row)
+        .find(|lock| lock.conflicts_with(&new))
+    {
+        return Err(LockError::Conflict(Box::new(existing)));
+    }
+
+    let lock = DavLock {
+        id: Uuid::new_v4().to_string(),
+        path: new.path.to_string(),
+        owner_id: new.owner_id.to_string(),
+        scope: new.scope,
+        depth_infinity: new.depth_infinity,
+        owner_xml: new.owner_xml.map(str::to_string),
+        timeout_secs: new.timeout_secs,
+        locked_at: now,
+        expires_at: now + chrono::Duration::seconds(new.timeout_secs as i64),
+    };
+    sqlx::query(
+        "INSERT INTO collaborative_locks
+            (id, file_id, file_path, locked_by, locked_at, expires_at, lock_type,
+             last_heartbeat, depth, owner_xml, timeout_seconds)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&lock.id)
+    .bind(&lock.path)
+    .bind(&lock.path)
+    .bind(&lock.owner_id)
+    .bind(format_time(lock.locked_at))
+    .bind(format_time(lock.expires_at))
+    .bind(lock.scope.as_str())
+    .bind(format_time(now))
+    .bind(if lock.depth_infinity { "infinity" } else { "0" })
+    .bind(&lock.owner_xml)
+    .bind(lock.timeout_secs as i64)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(lock)
+}
+
+/// Extend an active lock; `timeout_secs` of `None` keeps its lifetime
+pub async fn refresh(
+    pool: &SqlitePool,
+    id: &str,
+    timeout_secs: Option<u64>,
+) -> Result<Option<DavLock>, sqlx::Error> {
+    let Some(mut lock) = find(pool, id).await? else {
+        return Ok(None);
+    };
+    let now = Utc::now();
+    lock.timeout_secs = timeout_secs.unwrap_or(lock.timeout_secs);
+    lock.expires_at = now + chrono::Duration::seconds(lock.timeout_secs as i64);
+
+    sqlx::query(
+        "UPDATE collaborative_locks
+         SET expires_at = ?, timeout_seconds = ?, last_heartbeat = ? WHERE id = ?",
+    )
+    .bind(format_time(lock.expires_at))
+    .bind(lock.timeout_secs as i64)
+    .bind(format_time(now))
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(Some(lock))
+}
+
+/// Remove a lock; returns false if it did not exist
+pub async fn release(pool: &SqlitePool, id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM collaborative_locks WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Remove the locks rooted at `path` or below it, after the resource was
+/// deleted or moved away
+pub async fn release_tree(pool: &SqlitePool, path: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "DELETE FROM collaborative_locks
+         WHERE file_path = ? OR substr(file_path, 1, length(?) + 1) = ? || '/'",
+    )
+    .bind(path)
+    .bind(path)
+    .bind(path)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(path: &str, scope: LockScope, depth_infinity: bool) -> DavLock {
+        DavLock {
+            id: Uuid::new_v4().to_string(),
+            path: path.to_string(),
+            owner_id: "user-1".to_string(),
+            scope,
+            depth_infinity,
+            owner_xml: None,
+            timeout_secs: 60,
+            locked_at: Utc::now(),
+            expires_at: Utc::now(),
+        }
+    }
+
+    fn new_lock(path: &str, scope: LockScope, depth_infinity: bool) -> NewLock<'_> {
+        NewLock {
+            path,
+            owner_id: "user-2",
+            scope,
+            depth_infinity,
+            owner_xml: None,
+            timeout_secs: 60,
+        }
+    }
+
+    #[test]
+    fn test_is_below() {
+        assert!(is_below("a/b", "a"));
+        assert!(is_below("a", ""));
+        assert!(!is_below("", ""));
+        assert!(!is_below("ab", "a"));
+        assert!(!is_below("a", "a"));
+    }
+
+    #[test]
+    fn test_coverage_and_conflicts() {
+        let deep = lock("docs", LockScope::Exclusive, true);
+        assert!(deep.covers("docs/a.txt"));
+        assert!(!deep.covers("docs2"));
+        assert!(deep.conflicts_with(&new_lock("docs/a.txt", LockScope::Shared, false)));
+
+        let shallow = lock("docs", LockScope::Exclusive, false);
+        assert!(!shallow.covers("docs/a.txt"));
+        assert!(shallow.overlaps_tree(""));
+        assert!(shallow.conflicts_with(&new_lock("", LockScope::Exclusive, true)));
+        assert!(!shallow.conflicts_with(&new_lock("", LockScope::Exclusive, false)));
+
+        let shared = lock("a.txt", LockScope::Shared, false);
+        assert!(!shared.conflicts_with(&new_lock("a.txt", LockScope::Shared, false)));
+        assert!(shared.conflicts_with(&new_lock("a.txt", LockScope::Exclusive, false)));
+    }
+
+    #[test]
+    fn test_tokens_and_times() {
+        let id = Uuid::new_v4().to_string();
+        assert_eq!(token_id(&format!("urn:uuid:{}", id)), Some(id.as_str()));
+        assert_eq!(token_id("urn:uuid:nope"), None);
+        assert_eq!(token_id("opaquelocktoken:x"), None);
+
+        assert_eq!(clamp_timeout(None), DEFAULT_TIMEOUT_SECS);
+        assert_eq!(clamp_timeout(Some(u64::MAX)), MAX_TIMEOUT_SECS);
+
+        let stored = parse_time("2026-01-02 03:04:05");
+        assert_eq!(format_time(stored), "2026-01-02 03:04:05");
+        assert_eq!(parse_time("2026-01-02T03:04:05+00:00"), stored);
+    }
+}
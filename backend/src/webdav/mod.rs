@@ -0,0 +1,1326 @@
+//! WebDAV Server (RFC 4918, class 1 and 2)
+//!
+//! Serves a directory tree to file managers (Finder, Explorer, Nautilus,
+//! davfs2). The server works on the filesystem directly and reports every
+//! change through `DavHooks`, so the application can keep its database,
+//! search index and quotas in sync.
+//!
+//! - Locks are persistent and shared with the collaboration API (`locks`).
+//! - `If` headers are evaluated against ETags and lock tokens; modifying a
+//!   locked resource requires submitting the lock's token.
+//! - PROPFIND supports `Depth: infinity` and streams its response.
+//! - Properties set with PROPPATCH are stored in the database (`props`).
+
+pub mod if_header;
+pub mod locks;
+pub mod props;
+pub mod xml;
+
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, Request, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use if_header::{IfHeader, ResourceState};
+use locks::{DavLock, LockError, LockScope, NewLock, is_below};
+use xml::{DAV_NS, PropName, PropUpdate, PropfindRequest, escape};
+
+/// Uploads are written here (relative to the root) and renamed into place
+const TEMP_DIR: &str = "temp_uploads";
+/// Largest XML request body accepted
+const MAX_XML_BODY: usize = 1024 * 1024;
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, PROPPATCH, MKCOL, COPY, MOVE, \
+                     LOCK, UNLOCK";
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
+
+/// Characters left unescaped in href path segments
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Live properties computed from the filesystem; clients cannot set them
+const LIVE_PROPERTIES: &[&str] = &[
+    "creationdate",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "resourcetype",
+    "lockdiscovery",
+    "supportedlock",
+];
+
+/// The authenticated client
+#[derive(Debug, Clone)]
+pub struct DavUser {
+    pub id: String,
+    pub username: String,
+}
+
+/// A change made through WebDAV; paths are relative to the root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DavChange {
+    /// A file or collection was created or its content replaced
+    Changed(String),
+    Deleted(String),
+    Moved {
+        from: String,
+        to: String,
+    },
+}
+
+/// Content served for a file in place of its stored bytes
+pub struct DavContent {
+    pub len: u64,
+    pub body: Body,
+}
+
+/// Integration points for the application serving WebDAV
+#[async_trait::async_trait]
+pub trait DavHooks: Send + Sync {
+    /// Bytes the user may still store, `None` for no limit
+    async fn available_bytes(&self, _user: &DavUser) -> Option<u64> {
+        None
+    }
+
+    /// What `user` reads from the file at `path` (`stored_len` bytes on
+    /// disk) when it is not its stored bytes, e.g. decrypted content.
+    /// Failing refuses the read with that status.
+    async fn open(
+        &self,
+        _user: &DavUser,
+        _path: &str,
+        _stored_len: u64,
+    ) -> Result<Option<DavContent>, StatusCode> {
+        Ok(None)
+    }
+
+    /// Length of the content `open` serves for the file at `path`
+    async fn content_length(&self, _path: &str, stored_len: u64) -> u64 {
+        stored_len
+    }
+
+    /// Store the uploaded file `upload` as the new content of the existing
+    /// file at `path` instead of the server moving it into place, e.g. to
+    /// encrypt it. Returns whether it did; failing refuses the upload.
+    async fn replace(
+        &self,
+        _user: &DavUser,
+        _path: &str,
+        _upload: &Path,
+    ) -> Result<bool, StatusCode> {
+        Ok(false)
+    }
+
+    /// Called after every successful modification
+    async fn changed(&self, _user: &DavUser, _change: DavChange) {}
+
+    /// Paths that are not exposed (server-managed directories)
+    fn is_hidden(&self, _path: &str) -> bool {
+        false
+    }
+}
+
+struct NoHooks;
+
+impl DavHooks for NoHooks {}
+
+#[derive(Clone)]
+pub struct DavServer {
+    pool: SqlitePool,
+    root: PathBuf,
+    /// URL path the server is mounted at, without trailing slash
+    prefix: String,
+    hooks: Arc<dyn DavHooks>,
+}
+
+#[derive(Debug)]
+enum DavError {
+    Status(StatusCode),
+    /// Status with a `DAV:error` body naming the failed precondition
+    Condition(StatusCode, String),
+}
+
+type DavResult = Result<Response, DavError>;
+
+impl From<StatusCode> for DavError {
+    fn from(status: StatusCode) -> Self {
+        DavError::Status(status)
+    }
+}
+
+impl From<std::io::Error> for DavError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => DavError::Status(StatusCode::NOT_FOUND),
+            std::io::ErrorKind::PermissionDenied => DavError::Status(StatusCode::FORBIDDEN),
+            _ => {
+                tracing::warn!("WebDAV I/O error: {}", e);
+                DavError::Status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+impl From<sqlx::Error> for DavError {
+    fn from(e: sqlx::Error) -> Self {
+        tracing::error!("WebDAV database error: {}", e);
+        DavError::Status(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl IntoResponse for DavError {
+    fn into_response(self) -> Response {
+        match self {
+            DavError::Status(status) => empty(status),
+            DavError::Condition(status, condition) => xml_response(
+                status,
+                format!(
+                    "{}<D:error xmlns:D=\"DAV:\">{}</D:error>",
+                    XML_HEADER, condition
+                ),
+            ),
+        }
+    }
+}
+
+fn empty(status: StatusCode) -> Response {
+    (status, [(header::CONTENT_LENGTH, "0")]).into_response()
+}
+
+fn xml_response(status: StatusCode, body: impl Into<Body>) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body.into(),
+    )
+        .into_response()
+}
+
+/// State of the request being handled
+struct Context<'a> {
+    user: &'a DavUser,
+    path: String,
+    headers: HeaderMap,
+    /// Active locks when the request arrived
+    locks: Vec<DavLock>,
+    /// Lock tokens submitted in the `If` header
+    tokens: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+/// What a modification needs the lock tokens for
+enum Guard<'a> {
+    /// Locks covering the resource
+    Resource(&'a str),
+    /// Locks covering the resource or anything below it
+    Tree(&'a str),
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/')
+        .map(|(parent, _)| parent)
+        .unwrap_or("")
+}
+
+fn header_str<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn depth(headers: &HeaderMap, default: Depth) -> Result<Depth, DavError> {
+    match header_str(headers, "depth").map(str::trim) {
+        None => Ok(default),
+        Some("0") => Ok(Depth::Zero),
+        Some("1") => Ok(Depth::One),
+        Some(value) if value.eq_ignore_ascii_case("infinity") => Ok(Depth::Infinity),
+        Some(_) => Err(StatusCode::BAD_REQUEST.into()),
+    }
+}
+
+/// `Timeout: Second-600, Infinite`; the first value the server understands
+fn requested_timeout(headers: &HeaderMap) -> Option<u64> {
+    header_str(headers, "timeout")?
+        .split(',')
+        .map(str::trim)
+        .find_map(|value| {
+            if value.eq_ignore_ascii_case("infinite") {
+                Some(locks::MAX_TIMEOUT_SECS)
+            } else {
+                value.strip_prefix("Second-")?.parse().ok()
+            }
+        })
+}
+
+/// Whether an `If-Match`/`If-None-Match` list contains `etag`
+fn etag_listed(list: &str, etag: &str) -> bool {
+    list.split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || crate::etag::weak_match(candidate, etag))
+}
+
+async fn read_body(body: Body) -> Result<String, DavError> {
+    let bytes = axum::body::to_bytes(body, MAX_XML_BODY)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| StatusCode::BAD_REQUEST.into())
+}
+
+/// Removes an upload's temp file unless it was moved into place
+struct TempFile(Option<PathBuf>);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl DavServer {
+    /// Serve `root` at the URL path `prefix`
+    pub fn new(pool: SqlitePool, root: impl Into<PathBuf>, prefix: &str) -> Self {
+        Self {
+            pool,
+            root: root.into(),
+            prefix: prefix.trim_end_matches('/').to_string(),
+            hooks: Arc::new(NoHooks),
+        }
+    }
+
+    pub fn with_hooks(mut self, hooks: Arc<dyn DavHooks>) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Handle a WebDAV request. The request URI must include the prefix.
+    pub async fn handle(&self, user: &DavUser, request: Request<Body>) -> Response {
+        let (parts, body) = request.into_parts();
+        let path = match self.resource_path(parts.uri.path()) {
+            Ok(path) if !self.is_hidden(&path) => path,
+            Ok(_) => return empty(StatusCode::NOT_FOUND),
+            Err(status) => return empty(status),
+        };
+
+        let method = parts.method.as_str().to_string();
+        let result = async {
+            let mut ctx = Context {
+                user,
+                path,
+                headers: parts.headers,
+                locks: locks::active(&self.pool).await?,
+                tokens: Vec::new(),
+            };
+            self.evaluate_if_header(&mut ctx).await?;
+
+            match method.as_str() {
+                "OPTIONS" => Ok(self.options()),
+                "GET" => self.get(&ctx, false).await,
+                "HEAD" => self.get(&ctx, true).await,
+                "PUT" => self.put(&ctx, body).await,
+                "DELETE" => self.delete(&ctx).await,
+                "MKCOL" => self.mkcol(&ctx, body).await,
+                "COPY" => self.copy_or_move(&ctx, false).await,
+                "MOVE" => self.copy_or_move(&ctx, true).await,
+                "PROPFIND" => self.propfind(&ctx, body).await,
+                "PROPPATCH" => self.proppatch(&ctx, body).await,
+                "LOCK" => self.lock(&ctx, body).await,
+                "UNLOCK" => self.unlock(&ctx).await,
+                _ => Ok((StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOW)]).into_response()),
+            }
+        }
+        .await;
+
+        result.unwrap_or_else(IntoResponse::into_response)
+    }
+
+    // ------------------------------------------------------------------
+    // Paths
+    // ------------------------------------------------------------------
+
+    /// Resource path (relative, `/`-separated, `""` for the root) of a URL
+    /// path below the prefix
+    fn resource_path(&self, url_path: &str) -> Result<String, StatusCode> {
+        let rest = url_path
+            .strip_prefix(&self.prefix)
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let decoded = percent_decode_str(rest)
+            .decode_utf8()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        let mut segments = Vec::new();
+        for segment in decoded.split('/').filter(|segment| !segment.is_empty()) {
+            if segment == "." || segment == ".." || segment.contains(['\\', '\0']) {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            segments.push(segment);
+        }
+        Ok(segments.join("/"))
+    }
+
+    /// Resource path of an absolute URL or absolute path (`Destination`,
+    /// tagged `If` lists); `None` if it points elsewhere
+    fn url_to_path(&self, url: &str) -> Option<String> {
+        let path = match url.split_once("://") {
+            Some((_, rest)) => &rest[rest.find('/')?..],
+            None => url,
+        };
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        self.resource_path(path).ok()
+    }
+
+    fn href(&self, path: &str, is_dir: bool) -> String {
+        let mut href = self.prefix.clone();
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            href.push('/');
+            href.extend(utf8_percent_encode(segment, SEGMENT));
+        }
+        if is_dir || path.is_empty() {
+            href.push('/');
+        }
+        href
+    }
+
+    fn absolute(&self, path: &str) -> PathBuf {
+        if path.is_empty() {
+            self.root.clone()
+        } else {
+            self.root.join(path)
+        }
+    }
+
+    fn is_hidden(&self, path: &str) -> bool {
+        path == TEMP_DIR || is_below(path, TEMP_DIR) || self.hooks.is_hidden(path)
+    }
+
+    /// Metadata of a file or directory; symlinks are not served
+    async fn stat(&self, path: &str) -> Option<Metadata> {
+        tokio::fs::symlink_metadata(self.absolute(path))
+            .await
+            .ok()
+            .filter(|meta| meta.is_file() || meta.is_dir())
+    }
+
+    /// ETag of a resource, the same one downloads use (see [`crate::etag`])
+    async fn etag(&self, path: &str, meta: &Metadata) -> String {
+        let checksum = if meta.is_file() {
+            self.checksum(path, meta.len()).await
+        } else {
+            None
+        };
+        crate::etag::file_etag(checksum.as_deref(), meta.len(), meta.modified().ok())
+    }
+
+    /// Checksum the database has recorded for a file, unless its size shows
+    /// that the content changed since
+    async fn checksum(&self, path: &str, size: u64) -> Option<String> {
+        sqlx::query_scalar(
+            "SELECT checksum_sha256 FROM files WHERE path = ? AND is_deleted = 0 AND size_bytes = ?
+             ORDER BY updated_at DESC LIMIT 1",
+        )
+        .bind(path)
+        .bind(size as i64)
+        .fetch_optional(&self.pool)
+        .await
+        .ok()
+        .flatten()
+        .flatten()
+    }
+
+    async fn require_collection(&self, path: &str) -> Result<(), DavError> {
+        match self.stat(path).await {
+            Some(meta) if meta.is_dir() => Ok(()),
+            _ => Err(StatusCode::CONFLICT.into()),
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // Preconditions
+    // ------------------------------------------------------------------
+
+    async fn evaluate_if_header(&self, ctx: &mut Context<'_>) -> Result<(), DavError> {
+        let Some(value) = header_str(&ctx.headers, "if") else {
+            return Ok(());
+        };
+        let header = IfHeader::parse(value).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        let mut states: HashMap<Option<String>, ResourceState> = HashMap::new();
+        states.insert(None, self.resource_state(ctx, &ctx.path).await);
+        for tag in header.resources() {
+            let state = match self.url_to_path(tag) {
+                Some(path) => self.resource_state(ctx, &path).await,
+                None => ResourceState::default(),
+            };
+            states.insert(Some(tag.to_string()), state);
+        }
+
+        if !header.evaluate(|tag| states.get(&tag.map(str::to_string))) {
+            return Err(StatusCode::PRECONDITION_FAILED.into());
+        }
+        ctx.tokens = header.tokens().map(str::to_string).collect();
+        Ok(())
+    }
+
+    async fn resource_state(&self, ctx: &Context<'_>, path: &str) -> ResourceState {
+        let etag = match self.stat(path).await {
+            Some(meta) => Some(self.etag(path, &meta).await),
+            None => None,
+        };
+        ResourceState {
+            etag,
+            lock_tokens: ctx
+                .locks
+                .iter()
+                .filter(|lock| lock.covers(path))
+                .map(DavLock::token)
+                .collect(),
+        }
+    }
+
+    /// Fail with 423 unless the user submitted a token for every lock the
+    /// guarded resources are under. Of several shared locks on the same
+    /// resource, one token is enough.
+    fn require_tokens(&self, ctx: &Context<'_>, guards: &[Guard<'_>]) -> Result<(), DavError> {
+        let mut roots: HashMap<&str, bool> = HashMap::new();
+        for lock in &ctx.locks {
+            let applies = guards.iter().any(|guard| match guard {
+                Guard::Resource(path) => lock.covers(path),
+                Guard::Tree(path) => lock.overlaps_tree(path),
+            });
+            if applies {
+                let held = lock.owner_id == ctx.user.id && ctx.tokens.contains(&lock.token());
+                *roots.entry(lock.path.as_str()).or_default() |= held;
+            }
+        }
+
+        match roots.into_iter().find(|(_, held)| !held) {
+            Some((root, _)) => Err(DavError::Condition(
+                StatusCode::LOCKED,
+                format!(
+                    "<D:lock-token-submitted><D:href>{}</D:href></D:lock-token-submitted>",
+                    escape(&self.href(root, false))
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // Methods
+    // ------------------------------------------------------------------
+
+    fn options(&self) -> Response {
+        (
+            StatusCode::OK,
+            [
+                (header::HeaderName::from_static("dav"), "1, 2"),
+                (header::HeaderName::from_static("ms-author-via"), "DAV"),
+                (header::ALLOW, ALLOW),
+                (header::CONTENT_LENGTH, "0"),
+            ],
+        )
+            .into_response()
+    }
+
+    async fn get(&self, ctx: &Context<'_>, head: bool) -> DavResult {
+        let meta = self.stat(&ctx.path).await.ok_or(StatusCode::NOT_FOUND)?;
+        if meta.is_dir() {
+            return Ok((StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOW)]).into_response());
+        }
+
+        let etag = self.etag(&ctx.path, &meta).await;
+        let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+        if let Some(list) = header_str(&ctx.headers, "if-match")
+            && !etag_listed(list, &etag)
+        {
+            return Err(StatusCode::PRECONDITION_FAILED.into());
+        }
+        let not_modified = match header_str(&ctx.headers, "if-none-match") {
+            Some(list) => etag_listed(list, &etag),
+            None => header_str(&ctx.headers, "if-modified-since")
+                .and_then(|value| httpdate::parse_http_date(value).ok())
+                .is_some_and(|since| {
+                    modified
+                        .duration_since(since)
+                        .ok()
+                        .is_none_or(|newer| newer.as_secs() == 0)
+                }),
+        };
+
+        let mut response = if not_modified {
+            empty(StatusCode::NOT_MODIFIED)
+        } else {
+            let (len, body) = match self.hooks.open(ctx.user, &ctx.path, meta.len()).await? {
+                Some(content) => (content.len, content.body),
+                None if head => (meta.len(), Body::empty()),
+                None => {
+                    let file = tokio::fs::File::open(self.absolute(&ctx.path)).await?;
+                    (meta.len(), Body::from_stream(ReaderStream::new(file)))
+                }
+            };
+            let mut response = Response::new(if head { Body::empty() } else { body });
+            response
+                .headers_mut()
+                .insert(header::CONTENT_LENGTH, len.into());
+            response
+        };
+
+        let headers = response.headers_mut();
+        if !not_modified {
+            let content_type = mime_guess::from_path(&ctx.path).first_or_octet_stream();
+            if let Ok(value) = HeaderValue::from_str(content_type.as_ref()) {
+                headers.insert(header::CONTENT_TYPE, value);
+            }
+        }
+        if let Ok(value) = HeaderValue::from_str(&etag) {
+            headers.insert(header::ETAG, value);
+        }
+        if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(modified)) {
+            headers.insert(header::LAST_MODIFIED, value);
+        }
+        Ok(response)
+    }
+
+    async fn put(&self, ctx: &Context<'_>, body: Body) -> DavResult {
+        // Partial PUT is not part of HTTP (RFC 9110 section 14.5)
+        if ctx.headers.contains_key(header::CONTENT_RANGE) || ctx.path.is_empty() {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        let existing = self.stat(&ctx.path).await;
+        if existing.as_ref().is_some_and(Metadata::is_dir) {
+            return Err(StatusCode::METHOD_NOT_ALLOWED.into());
+        }
+        let parent = parent(&ctx.path);
+        self.require_collection(parent).await?;
+        if existing.is_some() {
+            self.require_tokens(ctx, &[Guard::Resource(&ctx.path)])?;
+        } else {
+            self.require_tokens(ctx, &[Guard::Resource(&ctx.path), Guard::Resource(parent)])?;
+        }
+
+        // The replaced content no longer counts against the quota
+        let limit = match self.hooks.available_bytes(ctx.user).await {
+            Some(available) => available + existing.as_ref().map_or(0, Metadata::len),
+            None => u64::MAX,
+        };
+        let declared =
+            header_str(&ctx.headers, "content-length").and_then(|v| v.parse::<u64>().ok());
+        if declared.is_some_and(|length| length > limit) {
+            return Err(StatusCode::INSUFFICIENT_STORAGE.into());
+        }
+
+        let temp_dir = self.root.join(TEMP_DIR);
+        tokio::fs::create_dir_all(&temp_dir).await?;
+        let temp_path = temp_dir.join(format!("webdav-{}", uuid::Uuid::new_v4()));
+        let mut temp = TempFile(Some(temp_path.clone()));
+
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        let mut stream = body.into_data_stream();
+        let mut written: u64 = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+            written += chunk.len() as u64;
+            if written > limit {
+                return Err(StatusCode::INSUFFICIENT_STORAGE.into());
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        drop(file);
+
+        let replaced =
+            existing.is_some() && self.hooks.replace(ctx.user, &ctx.path, &temp_path).await?;
+        if !replaced {
+            tokio::fs::rename(&temp_path, self.absolute(&ctx.path)).await?;
+            temp.0 = None;
+        }
+
+        self.hooks
+            .changed(ctx.user, DavChange::Changed(ctx.path.clone()))
+            .await;
+
+        let status = if existing.is_some() {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::CREATED
+        };
+        let mut response = empty(status);
+        if let Some(meta) = self.stat(&ctx.path).await
+            && let Ok(value) = HeaderValue::from_str(&self.etag(&ctx.path, &meta).await)
+        {
+            response.headers_mut().insert(header::ETAG, value);
+        }
+        Ok(response)
+    }
+
+    async fn delete(&self, ctx: &Context<'_>) -> DavResult {
+        if ctx.path.is_empty() {
+            return Err(StatusCode::FORBIDDEN.into());
+        }
+        let meta = self.stat(&ctx.path).await.ok_or(StatusCode::NOT_FOUND)?;
+        if meta.is_dir() && depth(&ctx.headers, Depth::Infinity)? != Depth::Infinity {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        self.require_tokens(
+            ctx,
+            &[Guard::Tree(&ctx.path), Guard::Resource(parent(&ctx.path))],
+        )?;
+
+        self.remove(&ctx.path, &meta).await?;
+        self.hooks
+            .changed(ctx.user, DavChange::Deleted(ctx.path.clone()))
+            .await;
+        Ok(empty(StatusCode::NO_CONTENT))
+    }
+
+    /// Remove a resource with its properties and locks
+    async fn remove(&self, path: &str, meta: &Metadata) -> Result<(), DavError> {
+        let absolute = self.absolute(path);
+        if meta.is_dir() {
+            tokio::fs::remove_dir_all(&absolute).await?;
+        } else {
+            tokio::fs::remove_file(&absolute).await?;
+        }
+        props::delete_tree(&self.pool, path).await?;
+        locks::release_tree(&self.pool, path).await?;
+        Ok(())
+    }
+
+    async fn mkcol(&self, ctx: &Context<'_>, body: Body) -> DavResult {
+        // MKCOL bodies are not defined by RFC 4918
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            if !chunk.map_err(|_| StatusCode::BAD_REQUEST)?.is_empty() {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+            }
+        }
+        if self.stat(&ctx.path).await.is_some() {
+            return Err(StatusCode::METHOD_NOT_ALLOWED.into());
+        }
+        let parent = parent(&ctx.path);
+        self.require_collection(parent).await?;
+        self.require_tokens(ctx, &[Guard::Resource(&ctx.path), Guard::Resource(parent)])?;
+
+        tokio::fs::create_dir(self.absolute(&ctx.path)).await?;
+        self.hooks
+            .changed(ctx.user, DavChange::Changed(ctx.path.clone()))
+            .await;
+        Ok(empty(StatusCode::CREATED))
+    }
+
+    async fn copy_or_move(&self, ctx: &Context<'_>, is_move: bool) -> DavResult {
+        let meta = self.stat(&ctx.path).await.ok_or(StatusCode::NOT_FOUND)?;
+        let destination = header_str(&ctx.headers, "destination").ok_or(StatusCode::BAD_REQUEST)?;
+        let destination = self
+            .url_to_path(destination)
+            .ok_or(StatusCode::BAD_GATEWAY)?;
+        if destination == ctx.path
+            || is_below(&destination, &ctx.path)
+            || (is_move && ctx.path.is_empty())
+            || self.is_hidden(&destination)
+        {
+            return Err(StatusCode::FORBIDDEN.into());
+        }
+
+        let depth = depth(&ctx.headers, Depth::Infinity)?;
+        if meta.is_dir() && (depth == Depth::One || (is_move && depth != Depth::Infinity)) {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        let overwrite = match header_str(&ctx.headers, "overwrite").map(str::trim) {
+            None | Some("T") => true,
+            Some("F") => false,
+            Some(_) => return Err(StatusCode::BAD_REQUEST.into()),
+        };
+
+        let destination_parent = parent(&destination);
+        self.require_collection(destination_parent).await?;
+        let existing = self.stat(&destination).await;
+        if existing.is_some() && !overwrite {
+            return Err(StatusCode::PRECONDITION_FAILED.into());
+        }
+
+        let mut guards = vec![
+            Guard::Tree(&destination),
+            Guard::Resource(destination_parent),
+        ];
+        if is_move {
+            guards.push(Guard::Tree(&ctx.path));
+            guards.push(Guard::Resource(parent(&ctx.path)));
+        }
+        self.require_tokens(ctx, &guards)?;
+
+        if let Some(existing) = &existing {
+            self.remove(&destination, existing).await?;
+            self.hooks
+                .changed(ctx.user, DavChange::Deleted(destination.clone()))
+                .await;
+        }
+
+        if is_move {
+            tokio::fs::rename(self.absolute(&ctx.path), self.absolute(&destination)).await?;
+            props::move_tree(&self.pool, &ctx.path, &destination).await?;
+            locks::release_tree(&self.pool, &ctx.path).await?;
+            self.hooks
+                .changed(
+                    ctx.user,
+                    DavChange::Moved {
+                        from: ctx.path.clone(),
+                        to: destination.clone(),
+                    },
+                )
+                .await;
+        } else {
+            let recursive = depth == Depth::Infinity;
+            self.copy_tree(ctx.user, &ctx.path, &destination, recursive)
+                .await?;
+            props::copy(&self.pool, &ctx.path, &destination, recursive).await?;
+            self.hooks
+                .changed(ctx.user, DavChange::Changed(destination.clone()))
+                .await;
+        }
+
+        Ok(empty(if existing.is_some() {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::CREATED
+        }))
+    }
+
+    /// Copy a file, or a directory with (if `recursive`) its content. Files
+    /// are copied as `user` reads them (see [`DavHooks::open`]).
+    async fn copy_tree(
+        &self,
+        user: &DavUser,
+        from: &str,
+        to: &str,
+        recursive: bool,
+    ) -> Result<(), DavError> {
+        let mut pending = vec![(from.to_string(), to.to_string())];
+        while let Some((source, target)) = pending.pop() {
+            let meta = tokio::fs::symlink_metadata(self.absolute(&source)).await?;
+            if meta.is_file() {
+                self.copy_file(user, &source, &target, meta.len()).await?;
+                continue;
+            }
+            if !meta.is_dir() {
+                continue;
+            }
+            tokio::fs::create_dir(self.absolute(&target)).await?;
+            if !recursive {
+                continue;
+            }
+            let mut entries = tokio::fs::read_dir(self.absolute(&source)).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                pending.push((
+                    format!("{}/{}", source, name),
+                    format!("{}/{}", target, name),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn copy_file(
+        &self,
+        user: &DavUser,
+        source: &str,
+        target: &str,
+        stored_len: u64,
+    ) -> Result<(), DavError> {
+        let Some(content) = self.hooks.open(user, source, stored_len).await? else {
+            tokio::fs::copy(self.absolute(source), self.absolute(target)).await?;
+            return Ok(());
+        };
+        let mut file = tokio::fs::File::create(self.absolute(target)).await?;
+        let mut stream = content.body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| std::io::Error::other(e.to_string()))?;
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    async fn propfind(&self, ctx: &Context<'_>, body: Body) -> DavResult {
+        let meta = self.stat(&ctx.path).await.ok_or(StatusCode::NOT_FOUND)?;
+        let depth = depth(&ctx.headers, Depth::Infinity)?;
+        let request =
+            xml::parse_propfind(&read_body(body).await?).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        let walk = PropfindWalk {
+            server: self.clone(),
+            request,
+            depth,
+            locks: ctx.locks.clone(),
+            pending: vec![(ctx.path.clone(), meta, 0)],
+            directories: Vec::new(),
+            started: false,
+            finished: false,
+        };
+        let stream = futures_util::stream::unfold(walk, |mut walk| async move {
+            let chunk = walk.next_chunk().await?;
+            Some((Ok::<_, std::io::Error>(Bytes::from(chunk)), walk))
+        });
+        Ok(xml_response(
+            StatusCode::MULTI_STATUS,
+            Body::from_stream(stream),
+        ))
+    }
+
+    async fn proppatch(&self, ctx: &Context<'_>, body: Body) -> DavResult {
+        let meta = self.stat(&ctx.path).await.ok_or(StatusCode::NOT_FOUND)?;
+        self.require_tokens(ctx, &[Guard::Resource(&ctx.path)])?;
+        let updates =
+            xml::parse_proppatch(&read_body(body).await?).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        let is_protected = |update: &PropUpdate| {
+            update.name().is_dav() && LIVE_PROPERTIES.contains(&update.name().name.as_str())
+        };
+        let protected = updates.iter().any(is_protected);
+        if !protected {
+            props::apply(&self.pool, &ctx.path, &updates).await?;
+        }
+
+        // Nothing is applied if any update fails
+        let mut by_status: Vec<(StatusCode, Vec<&PropName>)> = Vec::new();
+        for update in &updates {
+            let status = match (protected, is_protected(update)) {
+                (false, _) => StatusCode::OK,
+                (true, true) => StatusCode::FORBIDDEN,
+                (true, false) => StatusCode::FAILED_DEPENDENCY,
+            };
+            match by_status.iter_mut().find(|(s, _)| *s == status) {
+                Some((_, names)) => names.push(update.name()),
+                None => by_status.push((status, vec![update.name()])),
+            }
+        }
+
+        let mut body = format!(
+            "{}<D:multistatus xmlns:D=\"DAV:\"><D:response><D:href>{}</D:href>",
+            XML_HEADER,
+            escape(&self.href(&ctx.path, meta.is_dir()))
+        );
+        for (status, names) in by_status {
+            let props: String = names.iter().map(|name| name.element(None)).collect();
+            push_propstat(&mut body, &props, status);
+        }
+        body.push_str("</D:response></D:multistatus>");
+        Ok(xml_response(StatusCode::MULTI_STATUS, body))
+    }
+
+    async fn lock(&self, ctx: &Context<'_>, body: Body) -> DavResult {
+        let body = read_body(body).await?;
+        let timeout = requested_timeout(&ctx.headers);
+
+        if body.trim().is_empty() {
+            return self.refresh_lock(ctx, timeout).await;
+        }
+        let info = xml::parse_lockinfo(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let depth = depth(&ctx.headers, Depth::Infinity)?;
+        if depth == Depth::One {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+
+        // Locking an unmapped URL creates an empty file
+        let existing = self.stat(&ctx.path).await;
+        if existing.is_none() {
+            let parent = parent(&ctx.path);
+            self.require_collection(parent).await?;
+            self.require_tokens(ctx, &[Guard::Resource(parent)])?;
+        }
+
+        let lock = locks::acquire(
+            &self.pool,
+            NewLock {
+                path: &ctx.path,
+                owner_id: &ctx.user.id,
+                scope: if info.shared {
+                    LockScope::Shared
+                } else {
+                    LockScope::Exclusive
+                },
+                depth_infinity: depth == Depth::Infinity,
+                owner_xml: info.owner.as_deref(),
+                timeout_secs: locks::clamp_timeout(timeout),
+            },
+        )
+        .await
+        .map_err(|e| match e {
+            LockError::Conflict(existing) => DavError::Condition(
+                StatusCode::LOCKED,
+                format!(
+                    "<D:no-conflicting-lock><D:href>{}</D:href></D:no-conflicting-lock>",
+                    escape(&self.href(&existing.path, false))
+                ),
+            ),
+            LockError::Database(e) => e.into(),
+        })?;
+
+        let created = existing.is_none();
+        if created {
+            if let Err(e) = tokio::fs::File::create(self.absolute(&ctx.path)).await {
+                let _ = locks::release(&self.pool, &lock.id).await;
+                return Err(e.into());
+            }
+            self.hooks
+                .changed(ctx.user, DavChange::Changed(ctx.path.clone()))
+                .await;
+        }
+
+        let is_dir = existing.as_ref().is_some_and(Metadata::is_dir);
+        let mut response = self.lock_response(
+            if created {
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            },
+            &lock,
+            is_dir,
+        );
+        if let Ok(value) = HeaderValue::from_str(&format!("<{}>", lock.token())) {
+            response
+                .headers_mut()
+                .insert(header::HeaderName::from_static("lock-token"), value);
+        }
+        Ok(response)
+    }
+
+    /// LOCK without a body refreshes the lock named in the `If` header
+    async fn refresh_lock(&self, ctx: &Context<'_>, timeout: Option<u64>) -> DavResult {
+        if ctx.tokens.is_empty() {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        let lock = ctx
+            .locks
+            .iter()
+            .find(|lock| {
+                lock.covers(&ctx.path)
+                    && lock.owner_id == ctx.user.id
+                    && ctx.tokens.contains(&lock.token())
+            })
+            .ok_or(StatusCode::PRECONDITION_FAILED)?;
+        let refreshed = locks::refresh(
+            &self.pool,
+            &lock.id,
+            timeout.map(|t| locks::clamp_timeout(Some(t))),
+        )
+        .await?
+        .ok_or(StatusCode::PRECONDITION_FAILED)?;
+
+        let is_dir = refreshed.path != ctx.path
+            || self
+                .stat(&ctx.path)
+                .await
+                .as_ref()
+                .is_some_and(Metadata::is_dir);
+        Ok(self.lock_response(StatusCode::OK, &refreshed, is_dir))
+    }
+
+    fn lock_response(&self, status: StatusCode, lock: &DavLock, is_dir: bool) -> Response {
+        let mut body = format!("{}<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>", XML_HEADER);
+        self.push_active_lock(&mut body, lock, is_dir);
+        body.push_str("</D:lockdiscovery></D:prop>");
+        xml_response(status, body)
+    }
+
+    async fn unlock(&self, ctx: &Context<'_>) -> DavResult {
+        let token = header_str(&ctx.headers, "lock-token")
+            .map(str::trim)
+            .and_then(|value| value.strip_prefix('<')?.strip_suffix('>'))
+            .ok_or(StatusCode::BAD_REQUEST)?;
+
+        let lock = ctx
+            .locks
+            .iter()
+            .find(|lock| lock.token() == token && lock.covers(&ctx.path))
+            .ok_or_else(|| {
+                DavError::Condition(
+                    StatusCode::CONFLICT,
+                    "<D:lock-token-matches-request-uri/>".to_string(),
+                )
+            })?;
+        if lock.owner_id != ctx.user.id {
+            return Err(StatusCode::FORBIDDEN.into());
+        }
+
+        locks::release(&self.pool, &lock.id).await?;
+        Ok(empty(StatusCode::NO_CONTENT))
+    }
+
+    // ------------------------------------------------------------------
+    // Properties
+    // ------------------------------------------------------------------
+
+    fn push_active_lock(&self, out: &mut String, lock: &DavLock, is_dir: bool) {
+        let _ = write!(
+            out,
+            "<D:activelock><D:locktype><D:write/></D:locktype>\
+             <D:lockscope><D:{}/></D:lockscope><D:depth>{}</D:depth>",
+            lock.scope.as_str(),
+            if lock.depth_infinity { "infinity" } else { "0" }
+        );
+        if let Some(owner) = &lock.owner_xml {
+            let _ = write!(out, "<D:owner>{}</D:owner>", owner);
+        }
+        let _ = write!(
+            out,
+            "<D:timeout>Second-{}</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+            lock.remaining_secs(),
+            escape(&lock.token()),
+            escape(&self.href(&lock.path, is_dir))
+        );
+    }
+
+    /// Live property of a resource, `None` if it does not apply
+    fn live_property(
+        &self,
+        name: &str,
+        path: &str,
+        meta: &Metadata,
+        len: u64,
+        etag: &str,
+        active_locks: &[DavLock],
+    ) -> Option<String> {
+        let value = match name {
+            "creationdate" => {
+                let created = meta
+                    .created()
+                    .or_else(|_| meta.modified())
+                    .unwrap_or(UNIX_EPOCH);
+                chrono::DateTime::<chrono::Utc>::from(created)
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            }
+            "getcontentlength" if meta.is_file() => len.to_string(),
+            "getcontenttype" if meta.is_file() => mime_guess::from_path(path)
+                .first_or_octet_stream()
+                .to_string(),
+            "getetag" => escape(etag).into_owned(),
+            "getlastmodified" => {
+                httpdate::fmt_http_date(meta.modified().unwrap_or(SystemTime::UNIX_EPOCH))
+            }
+            "resourcetype" if meta.is_dir() => "<D:collection/>".to_string(),
+            "resourcetype" => String::new(),
+            "lockdiscovery" => {
+                let mut out = String::new();
+                for lock in active_locks.iter().filter(|lock| lock.covers(path)) {
+                    let is_dir = lock.path != path || meta.is_dir();
+                    self.push_active_lock(&mut out, lock, is_dir);
+                }
+                out
+            }
+            "supportedlock" => "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+                                <D:locktype><D:write/></D:locktype></D:lockentry>\
+                                <D:lockentry><D:lockscope><D:shared/></D:lockscope>\
+                                <D:locktype><D:write/></D:locktype></D:lockentry>"
+                .to_string(),
+            _ => return None,
+        };
+        Some(PropName::new(DAV_NS, name).element(Some(&value)))
+    }
+
+    /// `DAV:response` of one resource
+    async fn render_response(
+        &self,
+        request: &PropfindRequest,
+        path: &str,
+        meta: &Metadata,
+        active_locks: &[DavLock],
+    ) -> Result<String, sqlx::Error> {
+        let etag = self.etag(path, meta).await;
+        let len = if meta.is_file() {
+            self.hooks.content_length(path, meta.len()).await
+        } else {
+            0
+        };
+        let live = |name: &str| self.live_property(name, path, meta, len, &etag, active_locks);
+        let needs_dead = match request {
+            PropfindRequest::Prop(names) => names
+                .iter()
+                .any(|name| !name.is_dav() || !LIVE_PROPERTIES.contains(&name.name.as_str())),
+            _ => true,
+        };
+        let dead = if needs_dead {
+            props::list(&self.pool, path).await?
+        } else {
+            Vec::new()
+        };
+
+        let mut found = String::new();
+        let mut missing = String::new();
+        match request {
+            PropfindRequest::AllProp { .. } => {
+                LIVE_PROPERTIES
+                    .iter()
+                    .filter_map(|name| live(name))
+                    .for_each(|element| found.push_str(&element));
+                for property in &dead {
+                    found.push_str(&property.name.element(Some(&property.value)));
+                }
+            }
+            PropfindRequest::PropName => {
+                for name in LIVE_PROPERTIES.iter().filter(|name| live(name).is_some()) {
+                    found.push_str(&PropName::new(DAV_NS, name).element(None));
+                }
+                for property in &dead {
+                    found.push_str(&property.name.element(None));
+                }
+            }
+            PropfindRequest::Prop(names) => {
+                for name in names {
+                    let element = if name.is_dav() {
+                        live(&name.name)
+                    } else {
+                        None
+                    }
+                    .or_else(|| {
+                        dead.iter()
+                            .find(|property| property.name == *name)
+                            .map(|property| name.element(Some(&property.value)))
+                    });
+                    match element {
+                        Some(element) => found.push_str(&element),
+                        None => missing.push_str(&name.element(None)),
+                    }
+                }
+            }
+        }
+
+        let mut out = format!(
+            "<D:response><D:href>{}</D:href>",
+            escape(&self.href(path, meta.is_dir()))
+        );
+        if !found.is_empty() || missing.is_empty() {
+            push_propstat(&mut out, &found, StatusCode::OK);
+        }
+        if !missing.is_empty() {
+            push_propstat(&mut out, &missing, StatusCode::NOT_FOUND);
+        }
+        out.push_str("</D:response>\n");
+        Ok(out)
+    }
+}
+
+fn push_propstat(out: &mut String, props: &str, status: StatusCode) {
+    let _ = write!(
+        out,
+        "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {} {}</D:status></D:propstat>",
+        props,
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+}
+
+/// Depth-first walk producing a PROPFIND response one resource at a time,
+/// so only one directory listing is held in memory
+struct PropfindWalk {
+    server: DavServer,
+    request: PropfindRequest,
+    depth: Depth,
+    locks: Vec<DavLock>,
+    /// Resources to render with their distance from the request URI
+    pending: Vec<(String, Metadata, usize)>,
+    /// Rendered directories whose members are still to be listed
+    directories: Vec<(String, usize)>,
+    started: bool,
+    finished: bool,
+}
+
+impl PropfindWalk {
+    async fn next_chunk(&mut self) -> Option<String> {
+        if !self.started {
+            self.started = true;
+            return Some(format!("{}<D:multistatus xmlns:D=\"DAV:\">\n", XML_HEADER));
+        }
+
+        loop {
+            if let Some((path, meta, level)) = self.pending.pop() {
+                let descend = match self.depth {
+                    Depth::Zero => false,
+                    Depth::One => level == 0,
+                    Depth::Infinity => true,
+                };
+                if meta.is_dir() && descend {
+                    self.directories.push((path.clone(), level));
+                }
+                match self
+                    .server
+                    .render_response(&self.request, &path, &meta, &self.locks)
+                    .await
+                {
+                    Ok(response) => return Some(response),
+                    Err(e) => {
+                        tracing::warn!("PROPFIND failed for '{}': {}", path, e);
+                        continue;
+                    }
+                }
+            }
+
+            if let Some((directory, level)) = self.directories.pop() {
+                if let Err(e) = self.list(&directory, level + 1).await {
+                    tracing::warn!("PROPFIND could not list '{}': {}", directory, e);
+                }
+                continue;
+            }
+
+            if self.finished {
+                return None;
+            }
+            self.finished = true;
+            return Some("</D:multistatus>\n".to_string());
+        }
+    }
+
+    async fn list(&mut self, directory: &str, level: usize) -> std::io::Result<()> {
+        let mut entries = tokio::fs::read_dir(self.server.absolute(directory)).await?;
+        let mut members = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let path = if directory.is_empty() {
+                name
+            } else {
+                format!("{}/{}", directory, name)
+            };
+            if self.server.is_hidden(&path) {
+                continue;
+            }
+            let Ok(meta) = entry.metadata().await else {
+                continue;
+            };
+            if meta.is_file() || meta.is_dir() {
+                members.push((path, meta, level));
+            }
+        }
+        // Popped from the end, so reverse order lists members by name
+        members.sort_by(|a, b| b.0.cmp(&a.0));
+        self.pending.extend(members);
+        Ok(())
+    }
+}
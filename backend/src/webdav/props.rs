@@ -0,0 +1,133 @@
+//! Dead property storage
+//!
+//! Properties set with PROPPATCH are kept in `webdav_properties`, keyed by
+//! the resource path. They follow their resource on MOVE and COPY and are
+//! removed with it.
+
+use super::xml::{PropName, PropUpdate};
+use sqlx::SqlitePool;
+
+/// Rows at `path` or below it; `?1` is the path
+const TREE_CONDITION: &str = "(path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/')";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadProperty {
+    pub name: PropName,
+    /// Serialized XML content
+    pub value: String,
+}
+
+/// All dead properties of a resource
+pub async fn list(pool: &SqlitePool, path: &str) -> Result<Vec<DeadProperty>, sqlx::Error> {
+    let rows: Vec<(String, String, String)> = sqlx::query_as(
+        "SELECT namespace, name, value FROM webdav_properties
+         WHERE path = ? ORDER BY namespace, name",
+    )
+    .bind(path)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(namespace, name, value)| DeadProperty {
+            name: PropName { namespace, name },
+            value,
+        })
+        .collect())
+}
+
+/// Apply PROPPATCH updates in order, all or none
+pub async fn apply(
+    pool: &SqlitePool,
+    path: &str,
+    updates: &[PropUpdate],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for update in updates {
+        match update {
+            PropUpdate::Set(name, value) => {
+                sqlx::query(
+                    "INSERT INTO webdav_properties (path, namespace, name, value, updated_at)
+                     VALUES (?, ?, ?, ?, datetime('now'))
+                     ON CONFLICT(path, namespace, name)
+                     DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+                )
+                .bind(path)
+                .bind(&name.namespace)
+                .bind(&name.name)
+                .bind(value)
+                .execute(&mut *tx)
+                .await?;
+            }
+            PropUpdate::Remove(name) => {
+                sqlx::query(
+                    "DELETE FROM webdav_properties WHERE path = ? AND namespace = ? AND name = ?",
+                )
+                .bind(path)
+                .bind(&name.namespace)
+                .bind(&name.name)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+    }
+    tx.commit().await
+}
+
+/// Copy the properties of `from` (and of everything below it if
+/// `recursive`) to the same relative paths below `to`
+pub async fn copy(
+    pool: &SqlitePool,
+    from: &str,
+    to: &str,
+    recursive: bool,
+) -> Result<(), sqlx::Error> {
+    let condition = if recursive {
+        TREE_CONDITION
+    } else {
+        "path = ?1"
+    };
+    sqlx::query(&format!(
+        "INSERT OR REPLACE INTO webdav_properties (path, namespace, name, value, updated_at)
+         SELECT ?2 || substr(path, length(?1) + 1), namespace, name, value, datetime('now')
+         FROM webdav_properties WHERE {}",
+        condition
+    ))
+    .bind(from)
+    .bind(to)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Move the properties of `from` and everything below it to `to`
+pub async fn move_tree(pool: &SqlitePool, from: &str, to: &str) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+        "DELETE FROM webdav_properties WHERE {}",
+        TREE_CONDITION
+    ))
+    .bind(to)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+        "UPDATE webdav_properties SET path = ?2 || substr(path, length(?1) + 1) WHERE {}",
+        TREE_CONDITION
+    ))
+    .bind(from)
+    .bind(to)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Remove the properties of `path` and everything below it
+pub async fn delete_tree(pool: &SqlitePool, path: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "DELETE FROM webdav_properties WHERE {}",
+        TREE_CONDITION
+    ))
+    .bind(path)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,382 @@
+//! XML request bodies and response fragments
+//!
+//! Requests are parsed with `roxmltree`, which rejects DTDs and therefore
+//! entity expansion. Responses are written as strings with every element
+//! in the `DAV:` namespace using the `D:` prefix; client-defined elements
+//! carry their own namespace declarations so they can be embedded anywhere.
+
+use roxmltree::{Document, Node};
+use std::borrow::Cow;
+use std::fmt::Write;
+
+pub const DAV_NS: &str = "DAV:";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// Qualified name of a property
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PropName {
+    /// Empty for elements without a namespace
+    pub namespace: String,
+    pub name: String,
+}
+
+impl PropName {
+    pub fn new(namespace: &str, name: &str) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn is_dav(&self) -> bool {
+        self.namespace == DAV_NS
+    }
+
+    fn of(node: Node<'_, '_>) -> Self {
+        Self::new(
+            node.tag_name().namespace().unwrap_or_default(),
+            node.tag_name().name(),
+        )
+    }
+
+    /// The property element with `inner` as content, empty if `None`
+    pub fn element(&self, inner: Option<&str>) -> String {
+        let (open, close) = if self.is_dav() {
+            (format!("D:{}", self.name), format!("D:{}", self.name))
+        } else if self.namespace.is_empty() {
+            (format!("{} xmlns=\"\"", self.name), self.name.clone())
+        } else {
+            (
+                format!("R:{} xmlns:R=\"{}\"", self.name, escape(&self.namespace)),
+                format!("R:{}", self.name),
+            )
+        };
+        match inner {
+            Some(inner) if !inner.is_empty() => format!("<{}>{}</{}>", open, inner, close),
+            _ => format!("<{}/>", open),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PropfindRequest {
+    /// All properties; `include` names extra ones that allprop omits
+    AllProp {
+        include: Vec<PropName>,
+    },
+    PropName,
+    Prop(Vec<PropName>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PropUpdate {
+    /// Property and its value as serialized XML content
+    Set(PropName, String),
+    Remove(PropName),
+}
+
+impl PropUpdate {
+    pub fn name(&self) -> &PropName {
+        match self {
+            PropUpdate::Set(name, _) | PropUpdate::Remove(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockInfo {
+    pub shared: bool,
+    /// Content of `DAV:owner`
+    pub owner: Option<String>,
+}
+
+/// Escape text for element content and attribute values
+pub fn escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len() + 16);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn parse_root<'a>(body: &'a str, name: &str) -> Result<Document<'a>, String> {
+    let document = Document::parse(body).map_err(|e| e.to_string())?;
+    // Prefixes cannot be bound to the empty namespace (Namespaces in XML 1.0)
+    let undeclared = document.descendants().any(|node| {
+        node.namespaces()
+            .any(|namespace| namespace.name().is_some() && namespace.uri().is_empty())
+    });
+    if undeclared {
+        return Err("prefix bound to an empty namespace".to_string());
+    }
+    let root = document.root_element();
+    if !is_dav(root, name) {
+        return Err(format!("expected DAV:{}", name));
+    }
+    Ok(document)
+}
+
+fn is_dav(node: Node<'_, '_>, name: &str) -> bool {
+    node.is_element()
+        && node.tag_name().namespace() == Some(DAV_NS)
+        && node.tag_name().name() == name
+}
+
+fn elements<'a, 'input>(node: Node<'a, 'input>) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(Node::is_element)
+}
+
+/// PROPFIND body; an empty body asks for all properties
+pub fn parse_propfind(body: &str) -> Result<PropfindRequest, String> {
+    if body.trim().is_empty() {
+        return Ok(PropfindRequest::AllProp {
+            include: Vec::new(),
+        });
+    }
+    let document = parse_root(body, "propfind")?;
+    let root = document.root_element();
+
+    let mut request = None;
+    let mut include = Vec::new();
+    for child in elements(root) {
+        if is_dav(child, "allprop") {
+            request = Some(PropfindRequest::AllProp {
+                include: Vec::new(),
+            });
+        } else if is_dav(child, "propname") {
+            request = Some(PropfindRequest::PropName);
+        } else if is_dav(child, "prop") {
+            request = Some(PropfindRequest::Prop(
+                elements(child).map(PropName::of).collect(),
+            ));
+        } else if is_dav(child, "include") {
+            include.extend(elements(child).map(PropName::of));
+        }
+    }
+
+    match request {
+        Some(PropfindRequest::AllProp { .. }) => Ok(PropfindRequest::AllProp { include }),
+        Some(request) => Ok(request),
+        None => Err("propfind without allprop, propname or prop".to_string()),
+    }
+}
+
+/// PROPPATCH body, updates in document order
+pub fn parse_proppatch(body: &str) -> Result<Vec<PropUpdate>, String> {
+    let document = parse_root(body, "propertyupdate")?;
+    let mut updates = Vec::new();
+    for action in elements(document.root_element()) {
+        let set = if is_dav(action, "set") {
+            true
+        } else if is_dav(action, "remove") {
+            false
+        } else {
+            continue;
+        };
+        for prop in elements(action).filter(|node| is_dav(*node, "prop")) {
+            for property in elements(prop) {
+                let name = PropName::of(property);
+                updates.push(if set {
+                    PropUpdate::Set(name, serialize_children(property))
+                } else {
+                    PropUpdate::Remove(name)
+                });
+            }
+        }
+    }
+    if updates.is_empty() {
+        return Err("propertyupdate without properties".to_string());
+    }
+    Ok(updates)
+}
+
+/// LOCK body of a new lock
+pub fn parse_lockinfo(body: &str) -> Result<LockInfo, String> {
+    let document = parse_root(body, "lockinfo")?;
+    let root = document.root_element();
+
+    let scope = elements(root)
+        .find(|node| is_dav(*node, "lockscope"))
+        .and_then(|node| elements(node).next())
+        .ok_or("lockinfo without lockscope")?;
+    let shared = if is_dav(scope, "shared") {
+        true
+    } else if is_dav(scope, "exclusive") {
+        false
+    } else {
+        return Err("unknown lock scope".to_string());
+    };
+
+    let write = elements(root)
+        .find(|node| is_dav(*node, "locktype"))
+        .and_then(|node| elements(node).next())
+        .is_some_and(|node| is_dav(node, "write"));
+    if !write {
+        return Err("only write locks are supported".to_string());
+    }
+
+    let owner = elements(root)
+        .find(|node| is_dav(*node, "owner"))
+        .map(serialize_children);
+    Ok(LockInfo { shared, owner })
+}
+
+/// Content of `node` as XML that does not depend on namespace declarations
+/// of its ancestors
+pub fn serialize_children(node: Node<'_, '_>) -> String {
+    let mut out = String::new();
+    let mut prefixes = 0;
+    for child in node.children() {
+        serialize_node(child, &mut out, &mut prefixes);
+    }
+    out
+}
+
+fn serialize_node(node: Node<'_, '_>, out: &mut String, prefixes: &mut usize) {
+    if node.is_text() {
+        out.push_str(&escape(node.text().unwrap_or_default()));
+        return;
+    }
+    if !node.is_element() {
+        return;
+    }
+
+    let mut declarations = Vec::new();
+    let mut prefix_for = |namespace: &str, declarations: &mut Vec<(String, String)>| {
+        if let Some((prefix, _)) = declarations.iter().find(|(_, ns)| ns == namespace) {
+            return prefix.clone();
+        }
+        let prefix = format!("ns{}", *prefixes);
+        *prefixes += 1;
+        declarations.push((prefix.clone(), namespace.to_string()));
+        prefix
+    };
+
+    let tag = match node.tag_name().namespace() {
+        Some(namespace) => format!(
+            "{}:{}",
+            prefix_for(namespace, &mut declarations),
+            node.tag_name().name()
+        ),
+        None => node.tag_name().name().to_string(),
+    };
+    let mut attributes = String::new();
+    for attribute in node.attributes() {
+        let name = match attribute.namespace() {
+            Some(XML_NS) => format!("xml:{}", attribute.name()),
+            Some(namespace) => format!(
+                "{}:{}",
+                prefix_for(namespace, &mut declarations),
+                attribute.name()
+            ),
+            None => attribute.name().to_string(),
+        };
+        let _ = write!(attributes, " {}=\"{}\"", name, escape(attribute.value()));
+    }
+
+    let _ = write!(out, "<{}", tag);
+    for (prefix, namespace) in &declarations {
+        let _ = write!(out, " xmlns:{}=\"{}\"", prefix, escape(namespace));
+    }
+    out.push_str(&attributes);
+    if node.has_children() {
+        out.push('>');
+        for child in node.children() {
+            serialize_node(child, out, prefixes);
+        }
+        let _ = write!(out, "</{}>", tag);
+    } else {
+        out.push_str("/>");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_propfind() {
+        assert_eq!(
+            parse_propfind("").unwrap(),
+            PropfindRequest::AllProp { include: vec![] }
+        );
+        assert_eq!(
+            parse_propfind(
+                r#"<?xml version="1.0"?><propfind xmlns="DAV:" xmlns:z="urn:z">
+                   <prop><getetag/><z:color/></prop></propfind>"#
+            )
+            .unwrap(),
+            PropfindRequest::Prop(vec![
+                PropName::new(DAV_NS, "getetag"),
+                PropName::new("urn:z", "color"),
+            ])
+        );
+        assert!(parse_propfind("<propfind xmlns=\"DAV:\"/>").is_err());
+        assert!(parse_propfind("<D:propfind xmlns:D=\"DAV:\"><D:prop>").is_err());
+        assert!(parse_propfind("<propfind/>").is_err());
+    }
+
+    #[test]
+    fn test_parse_proppatch_keeps_values_self_contained() {
+        let updates = parse_proppatch(
+            r#"<D:propertyupdate xmlns:D="DAV:" xmlns:Z="urn:z">
+                 <D:set><D:prop><Z:author><Z:name a="1 &amp; 2">Jo &lt;3</Z:name></Z:author></D:prop></D:set>
+                 <D:remove><D:prop><Z:old/></D:prop></D:remove>
+               </D:propertyupdate>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            updates,
+            vec![
+                PropUpdate::Set(
+                    PropName::new("urn:z", "author"),
+                    r#"<ns0:name xmlns:ns0="urn:z" a="1 &amp; 2">Jo &lt;3</ns0:name>"#.to_string()
+                ),
+                PropUpdate::Remove(PropName::new("urn:z", "old")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_lockinfo() {
+        let info = parse_lockinfo(
+            r#"<lockinfo xmlns="DAV:"><lockscope><shared/></lockscope>
+               <locktype><write/></locktype><owner><href>mailto:a@b</href></owner></lockinfo>"#,
+        )
+        .unwrap();
+        assert!(info.shared);
+        assert_eq!(
+            info.owner.as_deref(),
+            Some(r#"<ns0:href xmlns:ns0="DAV:">mailto:a@b</ns0:href>"#)
+        );
+        assert!(
+            parse_lockinfo(
+                "<lockinfo xmlns=\"DAV:\"><lockscope><exclusive/></lockscope></lockinfo>"
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_property_elements() {
+        assert_eq!(
+            PropName::new(DAV_NS, "displayname").element(Some("a")),
+            "<D:displayname>a</D:displayname>"
+        );
+        assert_eq!(PropName::new("", "x").element(None), "<x xmlns=\"\"/>");
+        assert_eq!(
+            PropName::new("urn:a&b", "x").element(Some("1")),
+            "<R:x xmlns:R=\"urn:a&amp;b\">1</R:x>"
+        );
+    }
+}
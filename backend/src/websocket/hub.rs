@@ -0,0 +1,173 @@
+//! Sequenced event stream
+//!
+//! The hub numbers every event sent on `fs_tx` and keeps the most recent
+//! ones, so that a client reconnecting after a network blip can ask for the
+//! events it missed. Numbers start over when the server restarts; the
+//! stream id tells clients whether their last number still applies.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::FileChangeEvent;
+
+/// Events kept for clients that resume
+pub const REPLAY_CAPACITY: usize = 4096;
+
+/// An event and its position in the stream
+#[derive(Debug, Clone)]
+pub struct Sequenced {
+    pub seq: u64,
+    pub event: FileChangeEvent,
+}
+
+#[derive(Clone)]
+pub struct Hub {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    stream: String,
+    capacity: usize,
+    recent: Mutex<Recent>,
+    live: broadcast::Sender<Arc<Sequenced>>,
+}
+
+#[derive(Default)]
+struct Recent {
+    last_seq: u64,
+    events: VecDeque<Arc<Sequenced>>,
+}
+
+impl Hub {
+    pub fn new(capacity: usize) -> Self {
+        let (live, _) = broadcast::channel(capacity.max(1));
+        Self {
+            inner: Arc::new(Inner {
+                stream: uuid::Uuid::new_v4().to_string(),
+                capacity,
+                recent: Mutex::new(Recent::default()),
+                live,
+            }),
+        }
+    }
+
+    /// A hub publishing everything sent on `events`
+    pub fn start(events: &broadcast::Sender<FileChangeEvent>) -> Self {
+        let hub = Self::new(REPLAY_CAPACITY);
+        let mut rx = events.subscribe();
+        let publisher = hub.clone();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => {
+                        publisher.publish(event);
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!("WebSocket hub fell behind, {} events dropped", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        hub
+    }
+
+    /// Identifies this run of the server
+    pub fn stream(&self) -> &str {
+        &self.inner.stream
+    }
+
+    pub fn latest_seq(&self) -> u64 {
+        self.recent().last_seq
+    }
+
+    /// Number `event`, keep it and pass it on to connected clients
+    pub fn publish(&self, event: FileChangeEvent) -> u64 {
+        let mut recent = self.recent();
+        recent.last_seq += 1;
+        let sequenced = Arc::new(Sequenced {
+            seq: recent.last_seq,
+            event,
+        });
+        recent.events.push_back(sequenced.clone());
+        while recent.events.len() > self.inner.capacity {
+            recent.events.pop_front();
+        }
+        // Sent under the lock so that subscribers see events in order
+        let _ = self.inner.live.send(sequenced);
+        recent.last_seq
+    }
+
+    /// Live events, and the number of the last event before them
+    pub fn subscribe(&self) -> (u64, broadcast::Receiver<Arc<Sequenced>>) {
+        let recent = self.recent();
+        (recent.last_seq, self.inner.live.subscribe())
+    }
+
+    /// Events numbered after `seq`, or `None` when some of them are no
+    /// longer kept
+    pub fn since(&self, seq: u64) -> Option<Vec<Arc<Sequenced>>> {
+        let recent = self.recent();
+        if seq >= recent.last_seq {
+            return Some(Vec::new());
+        }
+        let first = recent.events.front()?.seq;
+        if first > seq + 1 {
+            return None;
+        }
+        Some(
+            recent
+                .events
+                .iter()
+                .skip((seq + 1 - first) as usize)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn recent(&self) -> std::sync::MutexGuard<'_, Recent> {
+        self.inner
+            .recent
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqs(events: Option<Vec<Arc<Sequenced>>>) -> Option<Vec<u64>> {
+        events.map(|events| events.iter().map(|e| e.seq).collect())
+    }
+
+    #[test]
+    fn test_since_replays_kept_events() {
+        let hub = Hub::new(3);
+        assert_eq!(seqs(hub.since(0)), Some(vec![]));
+        for n in 0..5 {
+            hub.publish(FileChangeEvent::new(
+                format!("f{}", n),
+                "create".to_string(),
+            ));
+        }
+        assert_eq!(hub.latest_seq(), 5);
+        assert_eq!(seqs(hub.since(2)), Some(vec![3, 4, 5]));
+        assert_eq!(seqs(hub.since(4)), Some(vec![5]));
+        assert_eq!(seqs(hub.since(5)), Some(vec![]));
+        // Event 2 is gone
+        assert_eq!(seqs(hub.since(1)), None);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_sees_later_events() {
+        let hub = Hub::new(8);
+        hub.publish(FileChangeEvent::new("a".to_string(), "create".to_string()));
+        let (last, mut rx) = hub.subscribe();
+        assert_eq!(last, 1);
+        hub.publish(FileChangeEvent::new("b".to_string(), "create".to_string()));
+        assert_eq!(rx.recv().await.unwrap().seq, 2);
+        assert_ne!(hub.stream(), Hub::new(8).stream());
+    }
+}
@@ -1,15 +1,22 @@
 //! WebSocket handling module
+//!
+//! Events sent on `fs_tx` are numbered by the [`hub::Hub`] and delivered to
+//! each authenticated connection as its [`session::Session`] allows.
 
-use axum::{
-    extract::{
-        ws::{Message, WebSocket},
-        WebSocketUpgrade,
-    },
-    response::Response,
-};
+pub mod hub;
+pub mod session;
+
+pub use hub::Hub;
+pub use session::{ClientMessage, ServerMessage, Session};
+
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio::sync::broadcast::Sender;
+use std::sync::OnceLock;
+use tokio::sync::broadcast::error::RecvError;
+
+use session::frame;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "event_type")]
@@ -35,6 +42,18 @@ pub enum FileChangeEvent {
         #[serde(skip_serializing_if = "Option::is_none")]
         output_path: Option<String>,
     },
+    /// A notification for one user (see `crate::notify`)
+    #[serde(rename = "notification")]
+    Notification {
+        user_id: String,
+        id: String,
+        notification_type: String,
+        title: String,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        action_url: Option<String>,
+        timestamp: String,
+    },
 }
 
 impl FileChangeEvent {
@@ -130,92 +149,122 @@ impl FileChangeEvent {
     }
 }
 
-/// WebSocket upgrade handler
-///
-/// NOTE: This handler is only used from main.rs with AppState.
-/// For lib.rs/tests, use handle_socket directly with a broadcast sender.
-#[allow(dead_code)]
-pub fn ws_handler_with_tx(
-    tx: Sender<FileChangeEvent>,
-) -> impl Fn(WebSocketUpgrade) -> std::pin::Pin<Box<dyn std::future::Future<Output = Response> + Send>>
-       + Clone {
-    move |ws: WebSocketUpgrade| {
-        let tx = tx.clone();
-        Box::pin(async move { ws.on_upgrade(move |socket| handle_socket(socket, tx)) })
+static HUB: OnceLock<Hub> = OnceLock::new();
+
+/// Make `hub` available to the WebSocket endpoint
+pub fn install(hub: Hub) {
+    if HUB.set(hub).is_err() {
+        tracing::warn!("WebSocket hub is already installed");
     }
 }
 
-/// Handle WebSocket connection
-pub async fn handle_socket(socket: WebSocket, tx: Sender<FileChangeEvent>) {
+pub fn hub() -> Option<&'static Hub> {
+    HUB.get()
+}
+
+/// Close code sent when the credentials of the connection expire
+pub const CLOSE_EXPIRED: u16 = 4001;
+
+/// Serve an authenticated connection: events the session wants, replies to
+/// client messages, and a close frame when the session expires
+pub async fn handle_socket(socket: WebSocket, hub: Hub, mut session: Session) {
     let (mut sender, mut receiver) = socket.split();
-    let mut rx = tx.subscribe();
-
-    // Spawn task to send events to client
-    let mut send_task = tokio::spawn(async move {
-        while let Ok(event) = rx.recv().await {
-            if let Ok(json) = serde_json::to_string(&event)
-                && sender.send(Message::Text(json.into())).await.is_err() {
-                    break;
-                }
+    // Highest event number handled, whether sent or not
+    let (mut cursor, mut live) = session.attach(&hub);
+
+    if send(&mut sender, &session.welcome(&hub)).await.is_err() {
+        return;
+    }
+
+    let expires_at = session.expires_at();
+    let expiry = async move {
+        match expires_at {
+            Some(at) => {
+                let left = (at - chrono::Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(left).await
+            }
+            None => std::future::pending().await,
         }
-    });
-
-    // Spawn task to receive messages from client (heartbeat + ping/pong)
-    let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            match msg {
-                Message::Close(_) => {
-                    break;
-                }
-                Message::Text(text) => {
-                    // Parse message for ping/pong handling
-                    if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(&text)
-                        && let Some(msg_type) = parsed.get("type").and_then(|v| v.as_str()) {
-                            match msg_type {
-                                "ping" => {
-                                    tracing::debug!("Received ping, responding with pong");
-                                    // Pong response will be sent via broadcast channel
-                                }
-                                "subscribe" => {
-                                    // Subscribe to specific file/directory changes
-                                    if let Some(path) = parsed.get("path").and_then(|v| v.as_str()) {
-                                        tracing::info!("Client subscribed to path: {}", path);
-                                        // Track subscription in memory or persist
-                                    }
-                                }
-                                "unsubscribe" => {
-                                    // Unsubscribe from specific file/directory changes
-                                    if let Some(path) = parsed.get("path").and_then(|v| v.as_str()) {
-                                        tracing::info!("Client unsubscribed from path: {}", path);
-                                        // Remove subscription tracking
-                                    }
-                                }
-                                "get_status" => {
-                                    // Return current connection status
-                                    tracing::debug!("Client requested status");
-                                    // Status would be sent via broadcast channel with server events
-                                }
-                                _ => {
-                                    tracing::warn!("Unknown message type: {}", msg_type);
-                                }
+    };
+    tokio::pin!(expiry);
+
+    loop {
+        tokio::select! {
+            received = live.recv() => {
+                let events = match received {
+                    Ok(event) => vec![event],
+                    // Fell behind: catch up from the hub while it still
+                    // has the events
+                    Err(RecvError::Lagged(_)) => match hub.since(cursor) {
+                        Some(missed) => missed,
+                        None => {
+                            cursor = hub.latest_seq();
+                            let resync = ServerMessage::ResyncRequired {
+                                stream: hub.stream().to_string(),
+                                seq: cursor,
+                            };
+                            if send(&mut sender, &resync).await.is_err() {
+                                break;
                             }
+                            continue;
                         }
+                    },
+                    Err(RecvError::Closed) => break,
+                };
+                for event in events {
+                    if event.seq <= cursor {
+                        continue;
+                    }
+                    cursor = event.seq;
+                    if session.wants(&event.event).await
+                        && let Some(text) = frame(&event)
+                        && sender.send(Message::Text(text.into())).await.is_err()
+                    {
+                        return;
+                    }
                 }
-                Message::Ping(_data) => {
-                    // Axum handles Ping frames automatically with Pong
-                    tracing::trace!("Received WebSocket Ping frame");
-                }
-                Message::Pong(_) => {
-                    tracing::trace!("Received WebSocket Pong frame");
+            }
+            message = receiver.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let (reply, missed) = match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(message) => session.handle(message, &hub).await,
+                        Err(e) => {
+                            let message = format!("Invalid message: {}", e);
+                            (ServerMessage::Error { message }, Vec::new())
+                        }
+                    };
+                    if send(&mut sender, &reply).await.is_err() {
+                        break;
+                    }
+                    for event in missed {
+                        if let Some(text) = frame(&event)
+                            && sender.send(Message::Text(text.into())).await.is_err()
+                        {
+                            return;
+                        }
+                    }
                 }
-                _ => {}
+                // Axum answers Ping frames itself
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = &mut expiry => {
+                tracing::debug!("Closing WebSocket of {}: credentials expired", session.user_id());
+                let close = CloseFrame {
+                    code: CLOSE_EXPIRED,
+                    reason: "Token expired".into(),
+                };
+                let _ = sender.send(Message::Close(Some(close))).await;
+                break;
             }
         }
-    });
-
-    // Wait for either task to finish
-    tokio::select! {
-        _ = (&mut send_task) => recv_task.abort(),
-        _ = (&mut recv_task) => send_task.abort(),
     }
 }
+
+async fn send(
+    sender: &mut SplitSink<WebSocket, Message>,
+    message: &ServerMessage,
+) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).unwrap_or_default();
+    sender.send(Message::Text(text.into())).await
+}
@@ -0,0 +1,458 @@
+//! One client's view of the event stream
+//!
+//! A session belongs to an authenticated user. File changes reach it for
+//! paths the user can read (see [`crate::search::access`]) and, for API
+//! tokens, paths within the token's prefixes; conversion progress for the
+//! user's own jobs; notifications when addressed to the user. Admins see
+//! every file change and conversion.
+//!
+//! Subscribing to folders narrows file changes to those folders, and
+//! subscribing to job ids narrows conversion progress to those jobs;
+//! without subscriptions everything the user may see is sent. After a
+//! reconnect the client re-subscribes, then sends `resume` with the stream
+//! id and the last number it received to get the events it missed.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+use super::FileChangeEvent;
+use super::hub::{Hub, Sequenced};
+use crate::auth::UserInfo;
+use crate::rbac::tokens::TokenGrant;
+use crate::search::access::{self, Viewer};
+
+/// How long a path stays readable (or not) for a session
+pub const READ_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Paths and jobs remembered per session
+const CACHE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Ping,
+    Subscribe {
+        #[serde(default)]
+        path: Option<String>,
+        #[serde(default)]
+        job_id: Option<String>,
+    },
+    Unsubscribe {
+        #[serde(default)]
+        path: Option<String>,
+        #[serde(default)]
+        job_id: Option<String>,
+    },
+    /// Send the events numbered after `after` in `stream`; allowed once
+    Resume {
+        stream: String,
+        after: u64,
+    },
+    GetStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Welcome {
+        stream: String,
+        seq: u64,
+        user_id: String,
+    },
+    Pong,
+    Subscriptions {
+        folders: Vec<String>,
+        jobs: Vec<String>,
+    },
+    /// The missed events follow
+    Resumed {
+        replayed: usize,
+    },
+    /// The missed events are no longer kept or the server restarted; the
+    /// client should reload what it shows
+    ResyncRequired {
+        stream: String,
+        seq: u64,
+    },
+    Status {
+        user_id: String,
+        stream: String,
+        seq: u64,
+        folders: Vec<String>,
+        jobs: Vec<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+pub struct Session {
+    pool: SqlitePool,
+    user_id: String,
+    is_admin: bool,
+    grant: Option<TokenGrant>,
+    expires_at: Option<DateTime<Utc>>,
+    folders: BTreeSet<String>,
+    jobs: BTreeSet<String>,
+    viewer: Option<Viewer>,
+    readable: HashMap<String, (bool, Instant)>,
+    job_owners: HashMap<String, Option<String>>,
+    /// Events after this number are sent live
+    live_from: u64,
+    resumed: bool,
+}
+
+impl Session {
+    pub fn new(pool: SqlitePool, user: &UserInfo) -> Self {
+        Self {
+            pool,
+            user_id: user.id.clone(),
+            is_admin: user.is_admin,
+            grant: None,
+            expires_at: None,
+            folders: BTreeSet::new(),
+            jobs: BTreeSet::new(),
+            viewer: None,
+            readable: HashMap::new(),
+            job_owners: HashMap::new(),
+            live_from: 0,
+            resumed: false,
+        }
+    }
+
+    /// Limit the session to what an API token allows
+    pub fn with_grant(mut self, grant: TokenGrant) -> Self {
+        self.grant = Some(grant);
+        self
+    }
+
+    /// Close the connection when the credentials it was opened with expire
+    pub fn with_expiry(mut self, expires_at: DateTime<Utc>) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    /// Start receiving live events from `hub`; returns the number of the
+    /// last event before them
+    pub fn attach(&mut self, hub: &Hub) -> (u64, broadcast::Receiver<Arc<Sequenced>>) {
+        let (last_seq, live) = hub.subscribe();
+        self.live_from = last_seq;
+        (last_seq, live)
+    }
+
+    pub fn welcome(&self, hub: &Hub) -> ServerMessage {
+        ServerMessage::Welcome {
+            stream: hub.stream().to_string(),
+            seq: self.live_from,
+            user_id: self.user_id.clone(),
+        }
+    }
+
+    /// Answer a client message; a resume also returns the missed events
+    pub async fn handle(
+        &mut self,
+        message: ClientMessage,
+        hub: &Hub,
+    ) -> (ServerMessage, Vec<Arc<Sequenced>>) {
+        let reply = match message {
+            ClientMessage::Ping => ServerMessage::Pong,
+            ClientMessage::Subscribe { path, job_id } => {
+                if let Some(path) = path {
+                    match folder(&path) {
+                        Some(folder) => {
+                            self.folders.insert(folder);
+                        }
+                        None => return (invalid_path(&path), Vec::new()),
+                    }
+                }
+                self.jobs.extend(job_id);
+                self.subscriptions()
+            }
+            ClientMessage::Unsubscribe { path, job_id } => {
+                if let Some(folder) = path.as_deref().and_then(folder) {
+                    self.folders.remove(&folder);
+                }
+                if let Some(job_id) = job_id {
+                    self.jobs.remove(&job_id);
+                }
+                self.subscriptions()
+            }
+            ClientMessage::Resume { stream, after } => {
+                return self.resume(hub, &stream, after).await;
+            }
+            ClientMessage::GetStatus => ServerMessage::Status {
+                user_id: self.user_id.clone(),
+                stream: hub.stream().to_string(),
+                seq: hub.latest_seq(),
+                folders: self.folders.iter().cloned().collect(),
+                jobs: self.jobs.iter().cloned().collect(),
+            },
+        };
+        (reply, Vec::new())
+    }
+
+    async fn resume(
+        &mut self,
+        hub: &Hub,
+        stream: &str,
+        after: u64,
+    ) -> (ServerMessage, Vec<Arc<Sequenced>>) {
+        if self.resumed {
+            let message = "The stream was already resumed".to_string();
+            return (ServerMessage::Error { message }, Vec::new());
+        }
+        self.resumed = true;
+
+        let missed = if stream == hub.stream() {
+            hub.since(after)
+        } else {
+            None
+        };
+        let Some(missed) = missed else {
+            let reply = ServerMessage::ResyncRequired {
+                stream: hub.stream().to_string(),
+                seq: self.live_from,
+            };
+            return (reply, Vec::new());
+        };
+        // Later events are sent live
+        let live_from = self.live_from;
+        let mut replay = Vec::new();
+        for event in missed.into_iter().filter(|e| e.seq <= live_from) {
+            if self.wants(&event.event).await {
+                replay.push(event);
+            }
+        }
+        (
+            ServerMessage::Resumed {
+                replayed: replay.len(),
+            },
+            replay,
+        )
+    }
+
+    fn subscriptions(&self) -> ServerMessage {
+        ServerMessage::Subscriptions {
+            folders: self.folders.iter().cloned().collect(),
+            jobs: self.jobs.iter().cloned().collect(),
+        }
+    }
+
+    /// Whether the client should receive `event`
+    pub async fn wants(&mut self, event: &FileChangeEvent) -> bool {
+        match event {
+            FileChangeEvent::Notification { user_id, .. } => *user_id == self.user_id,
+            FileChangeEvent::FileChange { path, user_id, .. } => {
+                if !self.allows_files() || !self.is_subscribed_to(path) {
+                    return false;
+                }
+                if self
+                    .grant
+                    .as_ref()
+                    .is_some_and(|grant| !grant.allows_path(path))
+                {
+                    return false;
+                }
+                self.is_admin
+                    || user_id.as_deref() == Some(self.user_id.as_str())
+                    || self.can_read(path).await
+            }
+            FileChangeEvent::ConversionProgress { job_id, .. } => {
+                self.allows_files()
+                    && (self.jobs.is_empty() || self.jobs.contains(job_id))
+                    && (self.is_admin || self.owns_job(job_id).await)
+            }
+        }
+    }
+
+    fn allows_files(&self) -> bool {
+        self.grant
+            .as_ref()
+            .is_none_or(|grant| grant.allows_scope("files:read"))
+    }
+
+    fn is_subscribed_to(&self, path: &str) -> bool {
+        let path = path.trim_matches('/');
+        self.folders.is_empty()
+            || self.folders.iter().any(|folder| {
+                folder.is_empty()
+                    || path == folder
+                    || path
+                        .strip_prefix(folder.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+
+    async fn can_read(&mut self, path: &str) -> bool {
+        let path = path.trim_matches('/').to_string();
+        if let Some((readable, at)) = self.readable.get(&path)
+            && at.elapsed() < READ_CACHE_TTL
+        {
+            return *readable;
+        }
+
+        if self.viewer.is_none() {
+            match Viewer::load(&self.pool, &self.user_id).await {
+                Ok(viewer) => self.viewer = Some(viewer),
+                Err(e) => {
+                    tracing::warn!("Failed to load groups of {}: {}", self.user_id, e);
+                    return false;
+                }
+            }
+        }
+        let readable = match access::grants(&self.pool, &path).await {
+            Ok(grants) => self
+                .viewer
+                .as_ref()
+                .is_some_and(|viewer| viewer.can_read(&grants)),
+            Err(e) => {
+                tracing::warn!("Failed to check access to {}: {}", path, e);
+                return false;
+            }
+        };
+        if self.readable.len() >= CACHE_SIZE {
+            self.readable.clear();
+        }
+        self.readable.insert(path, (readable, Instant::now()));
+        readable
+    }
+
+    async fn owns_job(&mut self, job_id: &str) -> bool {
+        if let Some(owner) = self.job_owners.get(job_id) {
+            return owner.as_deref() == Some(self.user_id.as_str());
+        }
+        let owner: Option<String> =
+            match sqlx::query_scalar("SELECT user_id FROM conversion_jobs WHERE id = ?")
+                .bind(job_id)
+                .fetch_optional(&self.pool)
+                .await
+            {
+                Ok(owner) => owner,
+                Err(e) => {
+                    tracing::warn!("Failed to look up conversion job {}: {}", job_id, e);
+                    return false;
+                }
+            };
+        let owns = owner.as_deref() == Some(self.user_id.as_str());
+        if self.job_owners.len() >= CACHE_SIZE {
+            self.job_owners.clear();
+        }
+        self.job_owners.insert(job_id.to_string(), owner);
+        owns
+    }
+}
+
+/// A subscribed folder without surrounding slashes; `None` for paths that
+/// climb with `..`
+fn folder(path: &str) -> Option<String> {
+    let folder = path.trim_matches('/');
+    (!folder.split('/').any(|segment| segment == "..")).then(|| folder.to_string())
+}
+
+fn invalid_path(path: &str) -> ServerMessage {
+    ServerMessage::Error {
+        message: format!("Invalid path: {}", path),
+    }
+}
+
+/// The JSON text of an event for the client, with its number as `seq`
+pub fn frame(event: &Sequenced) -> Option<String> {
+    let mut value = serde_json::to_value(&event.event).ok()?;
+    value
+        .as_object_mut()?
+        .insert("seq".to_string(), event.seq.into());
+    Some(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin() -> Session {
+        let user = UserInfo {
+            id: "admin".to_string(),
+            username: "admin".to_string(),
+            totp_enabled: false,
+            role: None,
+            is_admin: true,
+        };
+        // Admins never need the database to see file changes
+        Session::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap(), &user)
+    }
+
+    #[test]
+    fn test_client_messages_and_folders() {
+        let parse = |text: &str| serde_json::from_str::<ClientMessage>(text).unwrap();
+        assert_eq!(
+            parse(r#"{"type": "subscribe", "path": "/Projects/"}"#),
+            ClientMessage::Subscribe {
+                path: Some("/Projects/".to_string()),
+                job_id: None,
+            }
+        );
+        assert_eq!(
+            parse(r#"{"type": "resume", "stream": "s1", "after": 7}"#),
+            ClientMessage::Resume {
+                stream: "s1".to_string(),
+                after: 7,
+            }
+        );
+        assert_eq!(parse(r#"{"type": "get_status"}"#), ClientMessage::GetStatus);
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"type": "resume"}"#).is_err());
+
+        assert_eq!(folder("/Projects/2026/").as_deref(), Some("Projects/2026"));
+        assert_eq!(folder("/").as_deref(), Some(""));
+        assert_eq!(folder("Projects/../Private"), None);
+        assert_eq!(
+            folder("Projects/..notes").as_deref(),
+            Some("Projects/..notes")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_root_subscription_and_status() {
+        let hub = Hub::new(4);
+        hub.publish(FileChangeEvent::new(
+            "a.txt".to_string(),
+            "create".to_string(),
+        ));
+        let mut session = admin();
+        let subscribe = |path: &str| ClientMessage::Subscribe {
+            path: Some(path.to_string()),
+            job_id: Some("job-1".to_string()),
+        };
+        session.handle(subscribe("Projects"), &hub).await;
+        assert!(session.is_subscribed_to("/Projects/plan.md"));
+        assert!(!session.is_subscribed_to("Private/diary.txt"));
+
+        // The root folder covers everything
+        session.handle(subscribe("/"), &hub).await;
+        assert!(session.is_subscribed_to("Private/diary.txt"));
+
+        let (reply, events) = session.handle(ClientMessage::GetStatus, &hub).await;
+        assert!(events.is_empty());
+        assert_eq!(
+            reply,
+            ServerMessage::Status {
+                user_id: "admin".to_string(),
+                stream: hub.stream().to_string(),
+                seq: 1,
+                folders: vec!["".to_string(), "Projects".to_string()],
+                jobs: vec!["job-1".to_string()],
+            }
+        );
+    }
+}
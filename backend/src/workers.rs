@@ -528,7 +528,7 @@ async fn process_backup_task(_pool: &SqlitePool, job: &BackgroundJob) -> Result<
     }).to_string()))
 }
 
-async fn process_email_notification(_pool: &SqlitePool, job: &BackgroundJob) -> Result<Option<String>, String> {
+async fn process_email_notification(pool: &SqlitePool, job: &BackgroundJob) -> Result<Option<String>, String> {
     let payload: serde_json::Value = serde_json::from_str(&job.payload)
         .map_err(|e| format!("Invalid payload: {}", e))?;
 
@@ -541,52 +541,24 @@ async fn process_email_notification(_pool: &SqlitePool, job: &BackgroundJob) ->
     let body = payload["body"]
         .as_str()
         .unwrap_or("");
-    let from = payload["from"]
-        .as_str()
-        .unwrap_or("noreply@syncspace.local");
-
-    tracing::info!("Email notification: {} -> {} (subject: {})", from, to, subject);
-
-    // Implement actual SMTP email sending (requires SMTP configuration)
-    // This example shows the structure - actual SMTP settings should come from config
-    match std::env::var("SMTP_SERVER") {
-        Ok(smtp_server) => {
-            // Try to send via configured SMTP server
-            let smtp_user = std::env::var("SMTP_USER").ok();
-            let smtp_pass = std::env::var("SMTP_PASSWORD").ok();
-
-            match (smtp_user, smtp_pass) {
-                (Some(_user), Some(_pass)) => {
-                    // Would use lettre crate for actual sending:
-                    // use lettre::transport::smtp::authentication::Credentials;
-                    // use lettre::{Message, SmtpTransport, Transport};
-                    //
-                    // let email = Message::builder()
-                    //     .from(from.parse()?).to(to.parse()?).subject(subject).body(body.to_string())?;
-                    // let creds = Credentials::new(user, pass);
-                    // let mailer = SmtpTransport::relay(&smtp_server)?.credentials(creds).build();
-                    // mailer.send(&email)?;
-
-                    tracing::info!("Would send email via SMTP: {}", smtp_server);
-                }
-                _ => {
-                    tracing::warn!("SMTP credentials not configured, email not sent");
-                }
-            }
-        }
-        Err(_) => {
-            tracing::info!("SMTP server not configured, logging email instead");
-            tracing::info!("Email to: {}, Subject: {}, Body preview: {}...", to, subject, &body[..body.len().min(50)]);
-        }
-    }
 
-    // Simulate minimal processing
-    tokio::time::sleep(Duration::from_millis(200)).await;
+    // The sender is configured for the mailer (SMTP_FROM_EMAIL); delivery
+    // and its retries are handled by the mail outbox
+    let id = crate::mail::queue(
+        pool,
+        to,
+        None,
+        crate::mail::Kind::Notification,
+        &serde_json::json!({ "subject": subject, "body": body }),
+    )
+    .await
+    .map_err(|e| format!("Failed to queue email: {}", e))?;
 
-    tracing::info!("Email sent successfully to {}", to);
+    tracing::info!("Email notification {} queued for {} (subject: {})", id, to, subject);
 
     Ok(Some(serde_json::json!({
-        "email_sent": true,
+        "email_queued": true,
+        "email_id": id,
         "recipient": to,
         "subject": subject,
         "body_length": body.len()
@@ -0,0 +1,367 @@
+//! Rule filters
+//!
+//! `trigger_config` narrows the events a rule reacts to; events it rejects
+//! are ignored without a trace. `condition_config` holds further checks whose
+//! outcome is logged with the execution, which is `skipped` if they fail.
+//! Both accept the same filters and all of them must hold:
+//!
+//! - `path_pattern`: glob over the path (`*` and `?` within a segment, `**`
+//!   for any number of segments)
+//! - `path_prefix`: folder the file must be in, at any depth
+//! - `name_pattern`: glob over the file name
+//! - `file_types`: file extensions
+//! - `mime_types`: MIME types, `image/*` style wildcards allowed
+//! - `file_size_min` / `file_size_max`: size in bytes
+//! - `tags`: names of tags the file must carry
+//!
+//! `trigger_config` of scheduled rules also takes `schedule_cron` and `path`,
+//! the folder whose files a scheduled run processes, and that of webhook
+//! rules takes `payload_mapping` (see [`super::inbound`]).
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+const FILTER_KEYS: &[&str] = &[
+    "path_pattern",
+    "path_prefix",
+    "name_pattern",
+    "file_types",
+    "mime_types",
+    "file_size_min",
+    "file_size_max",
+    "tags",
+];
+const SCHEDULE_KEYS: &[&str] = &["schedule_cron", "path"];
+const WEBHOOK_KEYS: &[&str] = &["payload_mapping"];
+
+/// Settings of the trigger itself rather than filters
+fn is_trigger_setting(key: &str) -> bool {
+    SCHEDULE_KEYS.contains(&key) || WEBHOOK_KEYS.contains(&key)
+}
+
+/// What is known about the file an event refers to
+#[derive(Debug, Clone, Default)]
+pub struct FileFacts {
+    /// Path relative to the data directory without leading `/`
+    pub path: String,
+    /// `None` if the file no longer exists
+    pub size: Option<u64>,
+    pub is_dir: bool,
+    pub tags: Vec<String>,
+}
+
+impl FileFacts {
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+
+    pub fn extension(&self) -> Option<String> {
+        let name = self.name();
+        let (stem, extension) = name.rsplit_once('.')?;
+        (!stem.is_empty()).then(|| extension.to_lowercase())
+    }
+
+    pub fn mime_type(&self) -> String {
+        mime_guess::from_path(&self.path)
+            .first_or_octet_stream()
+            .essence_str()
+            .to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Check {
+    pub filter: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Evaluation {
+    pub met: bool,
+    pub checks: Vec<Check>,
+}
+
+/// Check a trigger or condition config for unknown filters and wrong types
+pub fn validate(config: &Value, is_trigger: bool) -> Result<(), String> {
+    let Some(object) = config.as_object() else {
+        return Err("configuration must be an object".to_string());
+    };
+    for (key, value) in object {
+        match key.as_str() {
+            "path_pattern" | "path_prefix" | "name_pattern" | "path" => {
+                if !value.is_string() {
+                    return Err(format!("{} must be a string", key));
+                }
+            }
+            "file_types" | "mime_types" | "tags" => {
+                if !value
+                    .as_array()
+                    .is_some_and(|items| items.iter().all(Value::is_string))
+                {
+                    return Err(format!("{} must be a list of strings", key));
+                }
+            }
+            "file_size_min" | "file_size_max" => {
+                if value.as_u64().is_none() {
+                    return Err(format!("{} must be a number of bytes", key));
+                }
+            }
+            "schedule_cron" => {
+                let valid = value.as_str().is_some_and(|expr| {
+                    crate::cron::calculate_next_run(expr, chrono::Utc::now()).is_some()
+                });
+                if !valid {
+                    return Err("schedule_cron must be a five field cron expression".to_string());
+                }
+            }
+            "payload_mapping" => super::inbound::validate_mapping(value)?,
+            _ => return Err(format!("unknown filter '{}'", key)),
+        }
+        if !is_trigger && is_trigger_setting(key) {
+            return Err(format!("{} belongs in trigger_config", key));
+        }
+    }
+    Ok(())
+}
+
+/// Evaluate every filter of `config` against `facts`
+pub fn evaluate(config: &Value, facts: &FileFacts) -> Evaluation {
+    let empty = Map::new();
+    let object = config.as_object().unwrap_or(&empty);
+    let checks: Vec<Check> = object
+        .iter()
+        .filter(|(key, _)| !is_trigger_setting(key))
+        .map(|(key, value)| {
+            let (passed, detail) = check(key, value, facts);
+            Check {
+                filter: key.clone(),
+                passed,
+                detail,
+            }
+        })
+        .collect();
+    Evaluation {
+        met: checks.iter().all(|check| check.passed),
+        checks,
+    }
+}
+
+fn check(key: &str, value: &Value, facts: &FileFacts) -> (bool, String) {
+    if !FILTER_KEYS.contains(&key) {
+        return (false, "unsupported filter".to_string());
+    }
+    let strings = || -> Vec<&str> {
+        value
+            .as_array()
+            .map(|items| items.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default()
+    };
+
+    match key {
+        "path_pattern" => {
+            let pattern = value.as_str().unwrap_or_default();
+            (glob_match(pattern, &facts.path), facts.path.clone())
+        }
+        "path_prefix" => {
+            let prefix = value.as_str().unwrap_or_default().trim_matches('/');
+            let passed = prefix.is_empty()
+                || facts
+                    .path
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('/'));
+            (passed, facts.path.clone())
+        }
+        "name_pattern" => {
+            let pattern = value.as_str().unwrap_or_default();
+            (glob_match(pattern, facts.name()), facts.name().to_string())
+        }
+        "file_types" => {
+            let extension = facts.extension().unwrap_or_default();
+            let passed = !facts.is_dir
+                && strings().iter().any(|wanted| {
+                    wanted
+                        .trim_start_matches('.')
+                        .eq_ignore_ascii_case(&extension)
+                });
+            (passed, extension)
+        }
+        "mime_types" => {
+            let mime = facts.mime_type();
+            let passed =
+                !facts.is_dir && strings().iter().any(|wanted| mime_matches(wanted, &mime));
+            (passed, mime)
+        }
+        "file_size_min" | "file_size_max" => {
+            let Some(size) = facts.size else {
+                return (false, "size unknown".to_string());
+            };
+            let limit = value.as_u64().unwrap_or_default();
+            let passed = if key == "file_size_min" {
+                size >= limit
+            } else {
+                size <= limit
+            };
+            (passed, size.to_string())
+        }
+        "tags" => {
+            let passed = strings().iter().all(|wanted| {
+                facts
+                    .tags
+                    .iter()
+                    .any(|tag| tag.eq_ignore_ascii_case(wanted))
+            });
+            (passed, facts.tags.join(", "))
+        }
+        _ => unreachable!("filter keys are checked above"),
+    }
+}
+
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(kind) => mime
+            .split_once('/')
+            .is_some_and(|(prefix, _)| prefix.eq_ignore_ascii_case(kind)),
+        None => pattern.eq_ignore_ascii_case(mime),
+    }
+}
+
+/// Match a `/` separated path against a glob; leading slashes are ignored
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern
+        .trim_start_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let path: Vec<&str> = path
+        .trim_start_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    match_segments(&pattern, &path)
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((segment, rest)) => path.split_first().is_some_and(|(name, path_rest)| {
+            match_segment(segment.as_bytes(), name.as_bytes()) && match_segments(rest, path_rest)
+        }),
+    }
+}
+
+fn match_segment(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_segment(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_segment(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_segment(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn facts(path: &str, size: u64, tags: &[&str]) -> FileFacts {
+        FileFacts {
+            path: path.to_string(),
+            size: Some(size),
+            is_dir: false,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("**/*", "a.txt"));
+        assert!(glob_match("**/*", "a/b/c.txt"));
+        assert!(glob_match("/Intake/*.pdf", "Intake/scan.pdf"));
+        assert!(!glob_match("/Intake/*.pdf", "Intake/sub/scan.pdf"));
+        assert!(glob_match("Intake/**/*.pdf", "Intake/sub/deeper/scan.pdf"));
+        assert!(glob_match("Intake/**", "Intake/sub/scan.pdf"));
+        assert!(glob_match("report-??.csv", "report-07.csv"));
+        assert!(!glob_match("report-??.csv", "report-7.csv"));
+        assert!(!glob_match("*.pdf", "a/b.pdf"));
+    }
+
+    #[test]
+    fn test_evaluate_filters() {
+        let file = facts("Intake/Scan.JPG", 6_000_000, &["Invoice"]);
+        let config = json!({
+            "path_prefix": "/Intake",
+            "file_types": ["jpg", ".png"],
+            "mime_types": ["image/*"],
+            "file_size_min": 5_242_880,
+            "tags": ["invoice"]
+        });
+        let evaluation = evaluate(&config, &file);
+        assert!(evaluation.met, "{:?}", evaluation.checks);
+        assert_eq!(evaluation.checks.len(), 5);
+
+        let evaluation = evaluate(
+            &json!({"file_size_max": 1024, "path_prefix": "Intake"}),
+            &file,
+        );
+        assert!(!evaluation.met);
+        assert_eq!(
+            evaluation
+                .checks
+                .iter()
+                .filter(|c| !c.passed)
+                .map(|c| c.filter.as_str())
+                .collect::<Vec<_>>(),
+            ["file_size_max"]
+        );
+
+        assert!(
+            !evaluate(
+                &json!({"path_prefix": "Intake"}),
+                &facts("Intakes/a", 1, &[])
+            )
+            .met
+        );
+        assert!(!evaluate(&json!({"tags": ["a", "b"]}), &facts("x", 1, &["a"])).met);
+        assert!(!evaluate(&json!({"owner": "me"}), &file).met);
+        assert!(evaluate(&json!({}), &file).met);
+    }
+
+    #[test]
+    fn test_missing_file_fails_size_checks() {
+        let gone = FileFacts {
+            path: "a.txt".to_string(),
+            ..Default::default()
+        };
+        assert!(!evaluate(&json!({"file_size_min": 0}), &gone).met);
+        assert!(evaluate(&json!({"file_types": ["txt"]}), &gone).met);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(
+            validate(
+                &json!({"path_pattern": "**/*", "file_types": ["jpg"]}),
+                true
+            )
+            .is_ok()
+        );
+        assert!(
+            validate(
+                &json!({"schedule_cron": "0 * * * *", "path": "/Intake"}),
+                true
+            )
+            .is_ok()
+        );
+        assert!(validate(&json!({"schedule_cron": "hourly"}), true).is_err());
+        assert!(validate(&json!({"schedule_cron": "0 * * * *"}), false).is_err());
+        assert!(validate(&json!({"payload_mapping": {"sha": "/after"}}), true).is_ok());
+        assert!(validate(&json!({"payload_mapping": {"sha": "/after"}}), false).is_err());
+        assert!(validate(&json!({"payload_mapping": {"sha": "after"}}), true).is_err());
+        assert!(validate(&json!({"file_size_min": "5MB"}), false).is_err());
+        assert!(validate(&json!({"tags": "a"}), false).is_err());
+        assert!(validate(&json!({"colour": "red"}), false).is_err());
+        assert!(validate(&json!([]), false).is_err());
+    }
+}
@@ -0,0 +1,728 @@
+//! Event dispatch, rule execution and schedules
+
+use super::conditions::{self, Evaluation, FileFacts};
+use super::{
+    ActionRunner, ActionStep, MAX_CHAIN_DEPTH, Origin, RULE_COLUMNS, Rule, Trigger, WorkflowEvent,
+};
+use crate::jobs::queue::JobQueue;
+use crate::jobs::types::{Job, JobResult, JobType};
+use crate::websocket::FileChangeEvent;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde_json::{Value, json};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// How long a job waits when its rule is at its concurrency limit
+const DEFER_DELAY: Duration = Duration::from_secs(10);
+/// How long changes announced with `expect_change` are attributed to the
+/// workflow that made them; the API and the watcher may both report one
+const EXPECTED_CHANGE_TTL: Duration = Duration::from_secs(30);
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Outcome of running a workflow job
+#[derive(Debug)]
+pub enum JobRun {
+    /// Done, successfully or not; the job must not be retried
+    Finished(JobResult),
+    /// The rule is at its concurrency limit, try again later
+    Deferred(Duration),
+    /// Temporary failure, retry with backoff
+    Failed(String),
+}
+
+#[derive(Clone)]
+pub struct WorkflowEngine {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    pool: SqlitePool,
+    data_dir: PathBuf,
+    runner: Arc<dyn ActionRunner>,
+    expected: Mutex<HashMap<String, (Origin, Instant)>>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ExecutionRow {
+    rule_id: String,
+    trigger_event: String,
+    file_path: Option<String>,
+    action_result: Option<String>,
+    status: String,
+    created_at: String,
+}
+
+impl WorkflowEngine {
+    pub fn new(
+        pool: SqlitePool,
+        data_dir: impl Into<PathBuf>,
+        runner: Arc<dyn ActionRunner>,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                pool,
+                data_dir: data_dir.into(),
+                runner,
+                expected: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Listen for file events and run scheduled rules in the background
+    pub fn spawn(&self, fs_tx: &broadcast::Sender<FileChangeEvent>) {
+        let mut rx = fs_tx.subscribe();
+        let engine = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = engine.recover_interrupted().await {
+                tracing::warn!("Failed to requeue interrupted workflow executions: {}", e);
+            }
+            loop {
+                match rx.recv().await {
+                    Ok(change) => {
+                        for event in engine.events_for_change(&change) {
+                            if let Err(e) = engine.dispatch(event).await {
+                                tracing::warn!("Failed to dispatch workflow event: {}", e);
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("Workflow engine missed {} file events", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let engine = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = engine.run_schedules(Utc::now()).await {
+                    tracing::warn!("Failed to run scheduled workflows: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Executions left running by a previous process are run again
+    async fn recover_interrupted(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE workflow_executions SET status = 'retrying' WHERE status = 'running'")
+            .execute(&self.inner.pool)
+            .await?;
+        sqlx::query(
+            "UPDATE jobs SET status = 'pending', attempts = MAX(attempts - 1, 0)
+             WHERE status = 'running' AND job_type = 'workflow_execution'",
+        )
+        .execute(&self.inner.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Attribute upcoming file events for `path` to a workflow action, so that
+    /// rules cannot trigger themselves and chains stay bounded
+    pub fn expect_change(&self, path: &str, origin: Origin) {
+        if let Ok(mut expected) = self.inner.expected.lock() {
+            expected.retain(|_, (_, at)| at.elapsed() < EXPECTED_CHANGE_TTL);
+            expected.insert(normalize(path), (origin, Instant::now()));
+        }
+    }
+
+    fn expected_origin(&self, path: &str) -> Option<Origin> {
+        let expected = self.inner.expected.lock().ok()?;
+        expected
+            .get(path)
+            .filter(|(_, at)| at.elapsed() < EXPECTED_CHANGE_TTL)
+            .map(|(origin, _)| origin.clone())
+    }
+
+    /// Workflow events for a file change broadcast on `fs_tx`
+    pub fn events_for_change(&self, change: &FileChangeEvent) -> Vec<WorkflowEvent> {
+        let FileChangeEvent::FileChange {
+            path,
+            kind,
+            user_id,
+            metadata,
+            ..
+        } = change
+        else {
+            return Vec::new();
+        };
+        let path = normalize(path);
+        let old_path = metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get("from"))
+            .and_then(Value::as_str);
+        let origin = self.expected_origin(&path);
+
+        Trigger::for_change_kind(kind)
+            .iter()
+            .map(|trigger| {
+                let mut event = WorkflowEvent::new(*trigger, Some(path.clone()));
+                event.user_id = user_id.clone();
+                if let Some(old_path) = old_path {
+                    event = event.with_old_path(&normalize(old_path));
+                }
+                if let Some(origin) = &origin {
+                    event = event.caused_by(origin.clone());
+                }
+                event
+            })
+            .collect()
+    }
+
+    /// Evaluate the active rules for the event's trigger and queue the
+    /// matching ones. Returns the ids of the queued executions.
+    pub async fn dispatch(&self, mut event: WorkflowEvent) -> Result<Vec<String>, sqlx::Error> {
+        event.path = event.path.as_deref().map(normalize);
+        event.old_path = event.old_path.as_deref().map(normalize);
+        if event
+            .origin
+            .as_ref()
+            .is_some_and(|origin| origin.depth >= MAX_CHAIN_DEPTH)
+        {
+            tracing::debug!(
+                "Workflow chain for {:?} stopped at depth {}",
+                event.path,
+                MAX_CHAIN_DEPTH
+            );
+            return Ok(Vec::new());
+        }
+
+        let rules: Vec<Rule> = sqlx::query_as(&format!(
+            "SELECT {} FROM workflow_rules WHERE trigger_type = ? AND is_active = 1
+             ORDER BY priority DESC, created_at ASC",
+            RULE_COLUMNS
+        ))
+        .bind(event.trigger.as_str())
+        .fetch_all(&self.inner.pool)
+        .await?;
+        if rules.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let facts = self.facts(event.path.as_deref()).await?;
+        let owner = self.owner(&event).await?;
+        let mut queued = Vec::new();
+        for rule in &rules {
+            if let Some(id) = self
+                .dispatch_to(rule, &event, &facts, owner.as_deref())
+                .await?
+            {
+                queued.push(id);
+            }
+        }
+        Ok(queued)
+    }
+
+    async fn dispatch_to(
+        &self,
+        rule: &Rule,
+        event: &WorkflowEvent,
+        facts: &FileFacts,
+        owner: Option<&str>,
+    ) -> Result<Option<String>, sqlx::Error> {
+        if event
+            .origin
+            .as_ref()
+            .is_some_and(|origin| origin.rule_ids.contains(&rule.id))
+        {
+            return Ok(None);
+        }
+        if event.path.is_some() && !self.applies_to(rule, owner).await? {
+            return Ok(None);
+        }
+        if !conditions::evaluate(&rule.trigger_config(), facts).met {
+            return Ok(None);
+        }
+        let condition = conditions::evaluate(&rule.condition_config(), facts);
+        self.record(rule, event, &condition).await
+    }
+
+    /// Rules act on their creator's files; rules of admins act on all files.
+    /// Events without a file, such as plain schedules, apply to every rule.
+    async fn applies_to(&self, rule: &Rule, owner: Option<&str>) -> Result<bool, sqlx::Error> {
+        if owner == Some(rule.created_by.as_str()) {
+            return Ok(true);
+        }
+        let is_admin: Option<bool> = sqlx::query_scalar("SELECT is_admin FROM users WHERE id = ?")
+            .bind(&rule.created_by)
+            .fetch_optional(&self.inner.pool)
+            .await?;
+        Ok(is_admin.unwrap_or(false))
+    }
+
+    async fn owner(&self, event: &WorkflowEvent) -> Result<Option<String>, sqlx::Error> {
+        let Some(path) = &event.path else {
+            return Ok(event.user_id.clone());
+        };
+        let owner: Option<String> = sqlx::query_scalar(
+            "SELECT owner_id FROM files WHERE path = ?1 OR path = '/' || ?1
+             ORDER BY is_deleted ASC LIMIT 1",
+        )
+        .bind(path)
+        .fetch_optional(&self.inner.pool)
+        .await?;
+        Ok(owner.or_else(|| event.user_id.clone()))
+    }
+
+    async fn facts(&self, path: Option<&str>) -> Result<FileFacts, sqlx::Error> {
+        let Some(path) = path else {
+            return Ok(FileFacts::default());
+        };
+        let path = normalize(path);
+        let metadata = tokio::fs::metadata(self.inner.data_dir.join(&path))
+            .await
+            .ok();
+        let tags: Vec<String> = sqlx::query_scalar(
+            "SELECT DISTINCT t.name FROM file_tags ft JOIN tags t ON t.id = ft.tag_id
+             WHERE ft.file_path = ?1 OR ft.file_id = ?1
+                OR ft.file_id IN (SELECT id FROM files WHERE path = ?1 OR path = '/' || ?1)",
+        )
+        .bind(&path)
+        .fetch_all(&self.inner.pool)
+        .await?;
+        Ok(FileFacts {
+            size: metadata.as_ref().filter(|m| m.is_file()).map(|m| m.len()),
+            is_dir: metadata.as_ref().is_some_and(|m| m.is_dir()),
+            path,
+            tags,
+        })
+    }
+
+    /// Log an execution and queue it, or log it as skipped
+    async fn record(
+        &self,
+        rule: &Rule,
+        event: &WorkflowEvent,
+        condition: &Evaluation,
+    ) -> Result<Option<String>, sqlx::Error> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let status = if condition.met { "queued" } else { "skipped" };
+        sqlx::query(
+            "INSERT INTO workflow_executions (
+                 id, rule_id, triggered_by, trigger_event, condition_met, condition_result,
+                 action_executed, status, file_path, completed_at
+             )
+             VALUES (?, ?, (SELECT id FROM users WHERE id = ?), ?, ?, ?, 0, ?, ?, ?)",
+        )
+        .bind(&id)
+        .bind(&rule.id)
+        .bind(&event.user_id)
+        .bind(event.to_json().to_string())
+        .bind(condition.met)
+        .bind(serde_json::to_string(condition).unwrap_or_default())
+        .bind(status)
+        .bind(&event.path)
+        .bind((!condition.met).then(|| Utc::now().to_rfc3339()))
+        .execute(&self.inner.pool)
+        .await?;
+        if !condition.met {
+            return Ok(None);
+        }
+
+        let job = Job::new(
+            JobType::WorkflowExecution {
+                execution_id: id.clone(),
+            },
+            Some(rule.created_by.clone()),
+        )
+        .map_err(|e| sqlx::Error::Protocol(e.to_string()))?
+        .with_max_attempts(rule.max_retries.clamp(0, 10) as i32 + 1);
+        let queue = JobQueue::new(Arc::new(self.inner.pool.clone()));
+        match queue.enqueue(job).await {
+            Ok(job) => {
+                sqlx::query("UPDATE workflow_executions SET job_id = ? WHERE id = ?")
+                    .bind(&job.id)
+                    .bind(&id)
+                    .execute(&self.inner.pool)
+                    .await?;
+                self.log(&id, "info", &format!("Queued as job {}", job.id))
+                    .await;
+                Ok(Some(id))
+            }
+            Err(e) => {
+                self.finish(&id, "failed", Some(&format!("Failed to queue: {}", e)), 0)
+                    .await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Evaluate the event of an inbound webhook call against the rule that
+    /// owns the endpoint. Returns the id of the queued execution, or `None`
+    /// if the rule is inactive or its filters reject the event.
+    pub async fn dispatch_webhook(
+        &self,
+        rule_id: &str,
+        mut event: WorkflowEvent,
+    ) -> Result<Option<String>, sqlx::Error> {
+        event.path = event.path.as_deref().map(normalize);
+        let rule: Option<Rule> = sqlx::query_as(&format!(
+            "SELECT {} FROM workflow_rules
+             WHERE id = ? AND trigger_type = 'webhook' AND is_active = 1",
+            RULE_COLUMNS
+        ))
+        .bind(rule_id)
+        .fetch_optional(&self.inner.pool)
+        .await?;
+        let Some(rule) = rule else {
+            return Ok(None);
+        };
+
+        let facts = self.facts(event.path.as_deref()).await?;
+        let owner = self.owner(&event).await?;
+        self.dispatch_to(&rule, &event, &facts, owner.as_deref())
+            .await
+    }
+
+    /// Queue a rule for `path` regardless of its trigger and conditions.
+    /// Returns `None` if the rule does not exist.
+    pub async fn execute_manually(
+        &self,
+        rule_id: &str,
+        path: &str,
+        user_id: &str,
+        context: Value,
+    ) -> Result<Option<String>, sqlx::Error> {
+        let rule: Option<Rule> = sqlx::query_as(&format!(
+            "SELECT {} FROM workflow_rules WHERE id = ?",
+            RULE_COLUMNS
+        ))
+        .bind(rule_id)
+        .fetch_optional(&self.inner.pool)
+        .await?;
+        let Some(rule) = rule else {
+            return Ok(None);
+        };
+        let event = WorkflowEvent::new(Trigger::Manual, Some(normalize(path)))
+            .by_user(user_id)
+            .with_context(context);
+        self.record(
+            &rule,
+            &event,
+            &Evaluation {
+                met: true,
+                checks: Vec::new(),
+            },
+        )
+        .await
+    }
+
+    /// Run the actions of a queued execution. `job` is the claimed job,
+    /// its attempt counter already includes this run.
+    pub async fn run_job(&self, job: &Job, execution_id: &str) -> JobRun {
+        match self.run_execution(job, execution_id).await {
+            Ok(run) => run,
+            Err(e) => JobRun::Failed(format!("Database error: {}", e)),
+        }
+    }
+
+    async fn run_execution(&self, job: &Job, execution_id: &str) -> Result<JobRun, sqlx::Error> {
+        let pool = &self.inner.pool;
+        let execution: Option<ExecutionRow> = sqlx::query_as(
+            "SELECT rule_id, trigger_event, file_path, action_result, status, created_at
+             FROM workflow_executions WHERE id = ?",
+        )
+        .bind(execution_id)
+        .fetch_optional(pool)
+        .await?;
+        let Some(execution) = execution else {
+            return Ok(JobRun::Finished(JobResult::failure(
+                "Workflow execution no longer exists",
+            )));
+        };
+        if matches!(
+            execution.status.as_str(),
+            "success" | "failed" | "skipped" | "cancelled"
+        ) {
+            return Ok(JobRun::Finished(JobResult::success(format!(
+                "Workflow execution already {}",
+                execution.status
+            ))));
+        }
+
+        let rule: Option<Rule> = sqlx::query_as(&format!(
+            "SELECT {} FROM workflow_rules WHERE id = ?",
+            RULE_COLUMNS
+        ))
+        .bind(&execution.rule_id)
+        .fetch_optional(pool)
+        .await?;
+        let event: Option<WorkflowEvent> = serde_json::from_str(&execution.trigger_event).ok();
+        let (Some(rule), Some(event)) = (rule, event) else {
+            self.finish(
+                execution_id,
+                "failed",
+                Some("Rule or trigger event is missing"),
+                0,
+            )
+            .await?;
+            return Ok(JobRun::Finished(JobResult::failure(
+                "Rule or trigger event is missing",
+            )));
+        };
+        if !rule.is_active && event.trigger != Trigger::Manual {
+            self.log(execution_id, "warn", "Rule was disabled before it ran")
+                .await;
+            self.finish(execution_id, "cancelled", Some("Rule is disabled"), 0)
+                .await?;
+            return Ok(JobRun::Finished(JobResult::success("Rule is disabled")));
+        }
+
+        // Claim a slot; the count and the update are one statement, so two
+        // workers cannot both take the last slot
+        let claimed = sqlx::query(
+            "UPDATE workflow_executions
+             SET status = 'running', attempts = attempts + 1, started_at = COALESCE(started_at, ?)
+             WHERE id = ? AND status IN ('queued', 'retrying')
+               AND (SELECT COUNT(*) FROM workflow_executions
+                    WHERE rule_id = ? AND status = 'running') < ?",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(execution_id)
+        .bind(&rule.id)
+        .bind(rule.max_concurrency.max(1))
+        .execute(pool)
+        .await?
+        .rows_affected();
+        if claimed == 0 {
+            return Ok(JobRun::Deferred(DEFER_DELAY));
+        }
+
+        let started = Instant::now();
+        let actions = match rule.actions() {
+            Ok(actions) => actions,
+            Err(e) => {
+                self.log(execution_id, "error", &e).await;
+                self.finish(execution_id, "failed", Some(&e), 0).await?;
+                return Ok(JobRun::Finished(JobResult::failure(e)));
+            }
+        };
+
+        // Actions that succeeded in an earlier attempt are not repeated
+        let mut results: Vec<Value> = execution
+            .action_result
+            .as_deref()
+            .and_then(|result| serde_json::from_str::<Value>(result).ok())
+            .and_then(|result| result.get("actions").and_then(Value::as_array).cloned())
+            .unwrap_or_default();
+        let mut path = results
+            .last()
+            .and_then(|result| result.get("path"))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .or(execution.file_path);
+        let origin = Origin::caused_by(event.origin.as_ref(), &rule.id);
+
+        for (index, action) in actions.iter().enumerate().skip(results.len()) {
+            self.log(
+                execution_id,
+                "info",
+                &format!("Running {} ({}/{})", action.kind, index + 1, actions.len()),
+            )
+            .await;
+            let step = ActionStep {
+                execution_id,
+                rule: &rule,
+                action,
+                path: path.as_deref(),
+                event: &event,
+                origin: origin.clone(),
+            };
+            match self.inner.runner.run(&step).await {
+                Ok(output) => {
+                    if output.path.is_some() {
+                        path = output.path;
+                    }
+                    results.push(json!({
+                        "type": action.kind,
+                        "status": "success",
+                        "output": output.detail,
+                        "path": path,
+                    }));
+                    sqlx::query(
+                        "UPDATE workflow_executions SET action_executed = 1, action_result = ?
+                         WHERE id = ?",
+                    )
+                    .bind(json!({ "actions": results }).to_string())
+                    .bind(execution_id)
+                    .execute(pool)
+                    .await?;
+                }
+                Err(e) => {
+                    let message = format!("{} failed: {}", action.kind, e);
+                    self.log(execution_id, "error", &message).await;
+                    let elapsed = started.elapsed().as_millis() as i64;
+                    if e.retryable && job.attempts < job.max_attempts {
+                        self.finish(execution_id, "retrying", Some(&message), elapsed)
+                            .await?;
+                        self.log(
+                            execution_id,
+                            "info",
+                            &format!(
+                                "Retrying (attempt {}/{})",
+                                job.attempts + 1,
+                                job.max_attempts
+                            ),
+                        )
+                        .await;
+                        return Ok(JobRun::Failed(message));
+                    }
+                    self.finish(execution_id, "failed", Some(&message), elapsed)
+                        .await?;
+                    return Ok(JobRun::Finished(JobResult::failure(message)));
+                }
+            }
+        }
+
+        self.finish(
+            execution_id,
+            "success",
+            None,
+            started.elapsed().as_millis() as i64,
+        )
+        .await?;
+        self.log(execution_id, "info", "Finished").await;
+        Ok(JobRun::Finished(JobResult::success_with_data(
+            format!("Workflow '{}' finished", rule.name),
+            json!({ "execution_id": execution_id, "queued_at": execution.created_at }),
+        )))
+    }
+
+    /// Set the status after a run; final statuses also record completion
+    async fn finish(
+        &self,
+        execution_id: &str,
+        status: &str,
+        error: Option<&str>,
+        elapsed_ms: i64,
+    ) -> Result<(), sqlx::Error> {
+        let completed_at = (status != "retrying").then(|| Utc::now().to_rfc3339());
+        sqlx::query(
+            "UPDATE workflow_executions
+             SET status = ?, error_message = ?, completed_at = ?,
+                 execution_time_ms = COALESCE(execution_time_ms, 0) + ?
+             WHERE id = ?",
+        )
+        .bind(status)
+        .bind(error)
+        .bind(completed_at)
+        .bind(elapsed_ms)
+        .bind(execution_id)
+        .execute(&self.inner.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn log(&self, execution_id: &str, level: &str, message: &str) {
+        let result = sqlx::query(
+            "INSERT INTO workflow_execution_logs (execution_id, level, message, created_at)
+             VALUES (?, ?, ?, ?)",
+        )
+        .bind(execution_id)
+        .bind(level)
+        .bind(message)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.inner.pool)
+        .await;
+        if let Err(e) = result {
+            tracing::warn!("Failed to write workflow log: {}", e);
+        }
+    }
+
+    /// Queue the scheduled rules that are due. Returns the number of
+    /// executions queued.
+    pub async fn run_schedules(&self, now: DateTime<Utc>) -> Result<usize, sqlx::Error> {
+        let rules: Vec<Rule> = sqlx::query_as(&format!(
+            "SELECT {} FROM workflow_rules WHERE trigger_type = 'scheduled' AND is_active = 1
+             ORDER BY priority DESC, created_at ASC",
+            RULE_COLUMNS
+        ))
+        .fetch_all(&self.inner.pool)
+        .await?;
+
+        let mut queued = 0;
+        for rule in rules {
+            let trigger_config = rule.trigger_config();
+            let Some(cron) = trigger_config.get("schedule_cron").and_then(Value::as_str) else {
+                continue;
+            };
+            let last = rule
+                .last_triggered_at
+                .as_deref()
+                .or(Some(rule.created_at.as_str()))
+                .and_then(parse_timestamp)
+                .unwrap_or(now);
+            if crate::cron::calculate_next_run(cron, last).is_none_or(|next| next > now) {
+                continue;
+            }
+            sqlx::query("UPDATE workflow_rules SET last_triggered_at = ? WHERE id = ?")
+                .bind(now.to_rfc3339())
+                .bind(&rule.id)
+                .execute(&self.inner.pool)
+                .await?;
+
+            let events = match trigger_config.get("path").and_then(Value::as_str) {
+                Some(folder) => self.folder_events(folder).await,
+                None => vec![WorkflowEvent::new(Trigger::Scheduled, None)],
+            };
+            for event in events {
+                let facts = self.facts(event.path.as_deref()).await?;
+                let owner = self.owner(&event).await?;
+                if self
+                    .dispatch_to(&rule, &event, &facts, owner.as_deref())
+                    .await?
+                    .is_some()
+                {
+                    queued += 1;
+                }
+            }
+        }
+        Ok(queued)
+    }
+
+    /// One scheduled event per file directly inside `folder`
+    async fn folder_events(&self, folder: &str) -> Vec<WorkflowEvent> {
+        let folder = normalize(folder);
+        let mut events = Vec::new();
+        let Ok(mut entries) = tokio::fs::read_dir(self.inner.data_dir.join(&folder)).await else {
+            tracing::warn!("Scheduled workflow folder '{}' is not readable", folder);
+            return events;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let is_file = entry.file_type().await.is_ok_and(|t| t.is_file());
+            if !is_file || name.starts_with('.') {
+                continue;
+            }
+            let path = Path::new(&folder).join(&name).to_string_lossy().to_string();
+            events.push(WorkflowEvent::new(
+                Trigger::Scheduled,
+                Some(normalize(&path)),
+            ));
+        }
+        events.sort_by(|a, b| a.path.cmp(&b.path));
+        events
+    }
+}
+
+fn normalize(path: &str) -> String {
+    path.trim_start_matches('/').to_string()
+}
+
+/// RFC 3339 or SQLite's `CURRENT_TIMESTAMP` format
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+                .ok()
+                .map(|t| t.and_utc())
+        })
+}
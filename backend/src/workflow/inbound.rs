@@ -0,0 +1,360 @@
+//! Inbound webhooks
+//!
+//! A rule with the `webhook` trigger can be given an endpoint, reachable
+//! without a session at `/api/hooks/workflows/{id}`. Callers sign every
+//! request with the endpoint's secret the way outbound deliveries are
+//! signed: `X-Webhook-Signature: sha256=<hex>` holds the HMAC-SHA256 of
+//! `{timestamp}.{body}`, with the Unix time in seconds sent as
+//! `X-Webhook-Timestamp`. Requests whose timestamp is more than
+//! [`MAX_CLOCK_SKEW_SECS`] off are rejected, and each signature is accepted
+//! once, so a captured request cannot be replayed.
+//!
+//! The JSON body is available to actions as `payload` in the event context.
+//! `trigger_config.payload_mapping` copies values out of it, for example
+//! `{"commit": "/head_commit/id", "file_path": "/artifact/path"}` (JSON
+//! pointers); a mapped `file_path` is the file the rule acts on.
+
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use sha2::Sha256;
+use sqlx::SqlitePool;
+
+use super::{Trigger, WorkflowEvent};
+use crate::secrets::{self, SecretError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+pub const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// Context key of the request body; cannot be used as a mapping name
+const PAYLOAD_KEY: &str = "payload";
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Endpoint {
+    pub id: String,
+    pub rule_id: String,
+    /// Sealed as stored, except in the endpoint returned by [`rotate`]
+    #[serde(skip)]
+    pub secret: String,
+    pub created_at: String,
+    pub last_received_at: Option<String>,
+}
+
+impl Endpoint {
+    /// Path of the endpoint below the server's address
+    pub fn path(&self) -> String {
+        format!("/api/hooks/workflows/{}", self.id)
+    }
+}
+
+/// Why an inbound call was not accepted
+#[derive(Debug)]
+pub enum Rejection {
+    /// No endpoint with this id, or its rule no longer has the webhook trigger
+    UnknownEndpoint,
+    /// Missing, stale or wrong signature
+    Unauthorized,
+    /// The signature was accepted before
+    Replayed,
+    /// The body is not JSON or maps to an invalid file path
+    InvalidPayload(String),
+    /// The endpoint's secret cannot be read
+    Secret(SecretError),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for Rejection {
+    fn from(e: sqlx::Error) -> Self {
+        Rejection::Database(e)
+    }
+}
+
+/// `sha256=<hex>` signature of a request sent at `timestamp`
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+pub fn verify_signature(secret: &str, timestamp: i64, body: &[u8], signature: &str) -> bool {
+    let signature = signature.trim();
+    let Ok(signature) = hex::decode(signature.strip_prefix("sha256=").unwrap_or(signature)) else {
+        return false;
+    };
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Check `payload_mapping` of a trigger config
+pub fn validate_mapping(mapping: &Value) -> Result<(), String> {
+    let Some(mapping) = mapping.as_object() else {
+        return Err("payload_mapping must be an object".to_string());
+    };
+    for (name, pointer) in mapping {
+        if name == PAYLOAD_KEY {
+            return Err(format!("\"{}\" cannot be mapped", PAYLOAD_KEY));
+        }
+        let valid = pointer
+            .as_str()
+            .is_some_and(|pointer| pointer.is_empty() || pointer.starts_with('/'));
+        if !valid {
+            return Err(format!(
+                "payload_mapping.{} must be a JSON pointer such as \"/repository/name\"",
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Event context for `payload`: the payload itself and the mapped values.
+/// Values the payload does not contain are left out.
+pub fn map_payload(mapping: Option<&Value>, payload: Value) -> Value {
+    let mut context = Map::new();
+    if let Some(mapping) = mapping.and_then(Value::as_object) {
+        for (name, pointer) in mapping {
+            if let Some(value) = pointer
+                .as_str()
+                .and_then(|pointer| payload.pointer(pointer))
+            {
+                context.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    context.insert(PAYLOAD_KEY.to_string(), payload);
+    Value::Object(context)
+}
+
+/// Create an endpoint for `rule_id` with a new secret, replacing the
+/// previous one and thereby invalidating its URL and secret. The returned
+/// endpoint holds the plaintext secret, which is not shown again.
+pub async fn rotate(pool: &SqlitePool, rule_id: &str) -> Result<Endpoint, sqlx::Error> {
+    let id = hex::encode(rand::random::<[u8; 16]>());
+    let secret = hex::encode(rand::random::<[u8; 32]>());
+    let sealed = secrets::seal(&secrets::WORKFLOW_WEBHOOK_SECRET, &id, &secret)
+        .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM workflow_webhook_endpoints WHERE rule_id = ?")
+        .bind(rule_id)
+        .execute(&mut *tx)
+        .await?;
+    let mut endpoint: Endpoint = sqlx::query_as(
+        "INSERT INTO workflow_webhook_endpoints (id, rule_id, secret, created_at)
+         VALUES (?, ?, ?, ?)
+         RETURNING id, rule_id, secret, created_at, last_received_at",
+    )
+    .bind(&id)
+    .bind(rule_id)
+    .bind(&sealed)
+    .bind(Utc::now().to_rfc3339())
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    endpoint.secret = secret;
+    Ok(endpoint)
+}
+
+pub async fn endpoint(pool: &SqlitePool, rule_id: &str) -> Result<Option<Endpoint>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, rule_id, secret, created_at, last_received_at
+         FROM workflow_webhook_endpoints WHERE rule_id = ?",
+    )
+    .bind(rule_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Remove the endpoint of `rule_id`; returns whether there was one
+pub async fn remove(pool: &SqlitePool, rule_id: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query(
+        "DELETE FROM workflow_webhook_deliveries WHERE endpoint_id IN
+             (SELECT id FROM workflow_webhook_endpoints WHERE rule_id = ?)",
+    )
+    .bind(rule_id)
+    .execute(pool)
+    .await?;
+    let result = sqlx::query("DELETE FROM workflow_webhook_endpoints WHERE rule_id = ?")
+        .bind(rule_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Authenticate a call to endpoint `endpoint_id` and turn it into a
+/// workflow event. Returns the rule to dispatch the event to.
+pub async fn receive(
+    pool: &SqlitePool,
+    endpoint_id: &str,
+    timestamp: Option<&str>,
+    signature: Option<&str>,
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> Result<(String, WorkflowEvent), Rejection> {
+    let found: Option<(String, String, String)> = sqlx::query_as(
+        "SELECT e.rule_id, e.secret, r.trigger_config
+         FROM workflow_webhook_endpoints e JOIN workflow_rules r ON r.id = e.rule_id
+         WHERE e.id = ? AND r.trigger_type = 'webhook'",
+    )
+    .bind(endpoint_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some((rule_id, secret, trigger_config)) = found else {
+        return Err(Rejection::UnknownEndpoint);
+    };
+
+    let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+        return Err(Rejection::Unauthorized);
+    };
+    let Ok(timestamp) = timestamp.trim().parse::<i64>() else {
+        return Err(Rejection::Unauthorized);
+    };
+    if (now.timestamp() - timestamp).abs() > MAX_CLOCK_SKEW_SECS {
+        return Err(Rejection::Unauthorized);
+    }
+    let secret = secrets::reveal(
+        pool,
+        &secrets::WORKFLOW_WEBHOOK_SECRET,
+        endpoint_id,
+        &secret,
+        "inbound webhook verification",
+    )
+    .await
+    .map_err(Rejection::Secret)?;
+    if !verify_signature(&secret, timestamp, body, signature) {
+        return Err(Rejection::Unauthorized);
+    }
+
+    let payload: Value = if body.iter().all(u8::is_ascii_whitespace) {
+        json!({})
+    } else {
+        serde_json::from_slice(body)
+            .map_err(|e| Rejection::InvalidPayload(format!("body is not JSON: {}", e)))?
+    };
+
+    // A signature stays valid for MAX_CLOCK_SKEW_SECS either side of its
+    // timestamp, so it has to be remembered for twice as long
+    let forget_before = now - Duration::seconds(2 * MAX_CLOCK_SKEW_SECS);
+    sqlx::query("DELETE FROM workflow_webhook_deliveries WHERE received_at < ?")
+        .bind(forget_before.to_rfc3339())
+        .execute(pool)
+        .await?;
+    let recorded = sqlx::query(
+        "INSERT OR IGNORE INTO workflow_webhook_deliveries (endpoint_id, signature, received_at)
+         VALUES (?, ?, ?)",
+    )
+    .bind(endpoint_id)
+    .bind(
+        signature
+            .trim()
+            .trim_start_matches("sha256=")
+            .to_lowercase(),
+    )
+    .bind(now.to_rfc3339())
+    .execute(pool)
+    .await?;
+    if recorded.rows_affected() == 0 {
+        return Err(Rejection::Replayed);
+    }
+    sqlx::query("UPDATE workflow_webhook_endpoints SET last_received_at = ? WHERE id = ?")
+        .bind(now.to_rfc3339())
+        .bind(endpoint_id)
+        .execute(pool)
+        .await?;
+
+    let trigger_config: Value = serde_json::from_str(&trigger_config).unwrap_or_default();
+    let context = map_payload(trigger_config.get("payload_mapping"), payload);
+    let path = match context.get("file_path") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(path)) => Some(
+            crate::security::validate_file_path(path)
+                .map_err(|_| Rejection::InvalidPayload(format!("invalid file_path {}", path)))?,
+        ),
+        Some(_) => {
+            return Err(Rejection::InvalidPayload(
+                "file_path must be a string".to_string(),
+            ));
+        }
+    };
+    Ok((
+        rule_id,
+        WorkflowEvent::new(Trigger::Webhook, path).with_context(context),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signatures() {
+        let signature = sign("secret", 1_700_000_000, b"{\"ok\":true}");
+        assert!(signature.starts_with("sha256="));
+        assert!(verify_signature(
+            "secret",
+            1_700_000_000,
+            b"{\"ok\":true}",
+            &signature
+        ));
+        // The bare hex digest is accepted as well
+        assert!(verify_signature(
+            "secret",
+            1_700_000_000,
+            b"{\"ok\":true}",
+            &signature["sha256=".len()..]
+        ));
+        assert!(!verify_signature(
+            "secret",
+            1_700_000_001,
+            b"{\"ok\":true}",
+            &signature
+        ));
+        assert!(!verify_signature(
+            "other",
+            1_700_000_000,
+            b"{\"ok\":true}",
+            &signature
+        ));
+        assert!(!verify_signature(
+            "secret",
+            1_700_000_000,
+            b"{\"ok\":false}",
+            &signature
+        ));
+        assert!(!verify_signature(
+            "secret",
+            1_700_000_000,
+            b"{}",
+            "sha256=zz"
+        ));
+    }
+
+    #[test]
+    fn test_payload_mapping() {
+        let mapping = json!({
+            "scanner": "/scan/engine",
+            "file_path": "/artifact/path",
+            "missing": "/nope",
+        });
+        assert!(validate_mapping(&mapping).is_ok());
+        assert!(validate_mapping(&json!({"payload": "/a"})).is_err());
+        assert!(validate_mapping(&json!({"commit": "head_commit.id"})).is_err());
+        assert!(validate_mapping(&json!(["/a"])).is_err());
+
+        let payload = json!({"scan": {"engine": "clamav"}, "artifact": {"path": "builds/app.zip"}});
+        let context = map_payload(Some(&mapping), payload.clone());
+        assert_eq!(context["scanner"], "clamav");
+        assert_eq!(context["file_path"], "builds/app.zip");
+        assert!(context.get("missing").is_none());
+        assert_eq!(context["payload"], payload);
+    }
+}
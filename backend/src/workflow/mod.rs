@@ -0,0 +1,400 @@
+//! Workflow engine
+//!
+//! Runs the automation rules stored in `workflow_rules`. File events from the
+//! `fs_tx` broadcast, service-layer hooks (tags, shares) and cron schedules
+//! are matched against each active rule's trigger; `trigger_config` and
+//! `condition_config` are evaluated (see [`conditions`]) and every match is
+//! logged in `workflow_executions` and queued as a job. Job workers then run
+//! the rule's actions through an [`ActionRunner`], which the application
+//! provides, with retries and a per-rule concurrency limit.
+//!
+//! A rule runs `action_type` with `action_config`; further actions can be
+//! chained with `action_config.then`, a list of `{"type": ..., ...config}`
+//! objects that run in order on the file produced by the previous action.
+//!
+//! Rules with the `webhook` trigger run when their signed endpoint is called
+//! (see [`inbound`]).
+
+pub mod conditions;
+mod engine;
+pub mod inbound;
+pub mod plugins;
+
+pub use engine::{JobRun, WorkflowEngine};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::sync::OnceLock;
+
+/// Events caused by workflow actions may trigger further rules up to this depth
+pub const MAX_CHAIN_DEPTH: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    FileUpload,
+    FileDelete,
+    FileMove,
+    FileRename,
+    FileShare,
+    FileTag,
+    FileVersion,
+    Scheduled,
+    Webhook,
+    Manual,
+}
+
+impl Trigger {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Trigger::FileUpload => "file_upload",
+            Trigger::FileDelete => "file_delete",
+            Trigger::FileMove => "file_move",
+            Trigger::FileRename => "file_rename",
+            Trigger::FileShare => "file_share",
+            Trigger::FileTag => "file_tag",
+            Trigger::FileVersion => "file_version",
+            Trigger::Scheduled => "scheduled",
+            Trigger::Webhook => "webhook",
+            Trigger::Manual => "manual",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        serde_json::from_value(Value::String(value.to_string())).ok()
+    }
+
+    /// Triggers fired by a `FileChangeEvent` of the given kind
+    pub fn for_change_kind(kind: &str) -> &'static [Trigger] {
+        match kind {
+            "create" | "copy" => &[Trigger::FileUpload],
+            // Overwriting a file stores a new version of it
+            "modify" => &[Trigger::FileUpload, Trigger::FileVersion],
+            "delete" => &[Trigger::FileDelete],
+            "move" => &[Trigger::FileMove],
+            "rename" => &[Trigger::FileRename],
+            _ => &[],
+        }
+    }
+}
+
+/// Workflow actions that caused an event
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin {
+    /// Rules whose actions led to the event; they are not triggered again
+    pub rule_ids: Vec<String>,
+    pub depth: u32,
+}
+
+impl Origin {
+    /// Origin of events caused by an action of `rule_id`
+    pub fn caused_by(previous: Option<&Origin>, rule_id: &str) -> Self {
+        let mut origin = previous.cloned().unwrap_or_default();
+        if !origin.rule_ids.iter().any(|id| id == rule_id) {
+            origin.rule_ids.push(rule_id.to_string());
+        }
+        origin.depth += 1;
+        origin
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkflowEvent {
+    #[serde(rename = "event_type")]
+    pub trigger: Trigger,
+    /// Path relative to the data directory, `None` for events without a file
+    #[serde(rename = "file_path")]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub old_path: Option<String>,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Trigger specific details, e.g. the tag name of `file_tag`
+    #[serde(default)]
+    pub context: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<Origin>,
+}
+
+impl WorkflowEvent {
+    pub fn new(trigger: Trigger, path: Option<String>) -> Self {
+        Self {
+            trigger,
+            path,
+            old_path: None,
+            user_id: None,
+            context: Value::Null,
+            origin: None,
+        }
+    }
+
+    pub fn by_user(mut self, user_id: &str) -> Self {
+        self.user_id = Some(user_id.to_string());
+        self
+    }
+
+    pub fn with_old_path(mut self, old_path: &str) -> Self {
+        self.old_path = Some(old_path.to_string());
+        self
+    }
+
+    pub fn with_context(mut self, context: Value) -> Self {
+        self.context = context;
+        self
+    }
+
+    pub fn caused_by(mut self, origin: Origin) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    /// JSON stored as the execution's `trigger_event`
+    pub fn to_json(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or_else(|_| json!({}));
+        value["timestamp"] = json!(chrono::Utc::now().to_rfc3339());
+        value
+    }
+}
+
+/// A stored rule as seen by the engine
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Rule {
+    pub id: String,
+    pub name: String,
+    pub trigger_type: String,
+    pub trigger_config: String,
+    pub condition_config: String,
+    pub action_type: String,
+    pub action_config: String,
+    pub is_active: bool,
+    pub priority: i32,
+    pub created_by: String,
+    pub max_concurrency: i64,
+    pub max_retries: i64,
+    pub last_triggered_at: Option<String>,
+    pub created_at: String,
+}
+
+/// Columns of `workflow_rules` read into [`Rule`]
+pub(crate) const RULE_COLUMNS: &str = "id, name, trigger_type, trigger_config, condition_config,
+     action_type, action_config, is_active, priority, created_by, max_concurrency,
+     max_retries, last_triggered_at, created_at";
+
+impl Rule {
+    pub fn trigger_config(&self) -> Value {
+        serde_json::from_str(&self.trigger_config).unwrap_or_else(|_| json!({}))
+    }
+
+    pub fn condition_config(&self) -> Value {
+        serde_json::from_str(&self.condition_config).unwrap_or_else(|_| json!({}))
+    }
+
+    /// The rule's action followed by the actions chained with `then`
+    pub fn actions(&self) -> Result<Vec<Action>, String> {
+        let config: Value = serde_json::from_str(&self.action_config)
+            .map_err(|e| format!("invalid action_config: {}", e))?;
+        parse_actions(&self.action_type, &config)
+    }
+}
+
+/// One step of a rule
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Action {
+    pub kind: String,
+    pub config: Value,
+}
+
+/// Action types that can be used in rules and `then` chains
+pub const ACTION_TYPES: &[&str] = &[
+    "convert_file",
+    "compress_file",
+    "send_notification",
+    "add_tag",
+    "move_file",
+    "copy_file",
+    "delete_file",
+    "send_webhook",
+    "send_email",
+    "run_script",
+];
+
+/// Split an action config into the primary action and its `then` chain
+pub fn parse_actions(action_type: &str, config: &Value) -> Result<Vec<Action>, String> {
+    let mut primary = config.clone();
+    let chained = match &mut primary {
+        Value::Object(object) => object.remove("then"),
+        Value::Null => None,
+        _ => return Err("action_config must be an object".to_string()),
+    };
+
+    let mut actions = vec![Action {
+        kind: action_type.to_string(),
+        config: primary,
+    }];
+    match chained {
+        None | Some(Value::Null) => {}
+        Some(Value::Array(steps)) => {
+            for step in steps {
+                let mut step = step;
+                let kind = step
+                    .as_object_mut()
+                    .and_then(|object| object.remove("type"))
+                    .and_then(|kind| kind.as_str().map(str::to_string))
+                    .ok_or("every chained action needs a \"type\"")?;
+                actions.push(Action { kind, config: step });
+            }
+        }
+        Some(_) => return Err("\"then\" must be a list of actions".to_string()),
+    }
+
+    for action in &actions {
+        if !ACTION_TYPES.contains(&action.kind.as_str()) {
+            return Err(format!("unknown action type '{}'", action.kind));
+        }
+        if action.kind == "run_script" && !action.config["plugin"].is_string() {
+            return Err("run_script actions need a \"plugin\"".to_string());
+        }
+    }
+    Ok(actions)
+}
+
+/// An action about to be run
+pub struct ActionStep<'a> {
+    pub execution_id: &'a str,
+    pub rule: &'a Rule,
+    pub action: &'a Action,
+    /// Current location of the file, updated by earlier actions that move it
+    pub path: Option<&'a str>,
+    pub event: &'a WorkflowEvent,
+    /// Origin to attach to events the action causes
+    pub origin: Origin,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ActionOutput {
+    /// Recorded in the execution's `action_result`
+    pub detail: Value,
+    /// New location of the file if the action moved it
+    pub path: Option<String>,
+}
+
+impl ActionOutput {
+    pub fn new(detail: Value) -> Self {
+        Self { detail, path: None }
+    }
+
+    pub fn moved_to(mut self, path: String) -> Self {
+        self.path = Some(path);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionError {
+    pub message: String,
+    /// Temporary failures are retried, configuration errors are not
+    pub retryable: bool,
+}
+
+impl ActionError {
+    pub fn retryable(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            retryable: true,
+        }
+    }
+
+    pub fn fatal(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            retryable: false,
+        }
+    }
+}
+
+impl std::fmt::Display for ActionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Executes actions on behalf of the engine
+#[async_trait::async_trait]
+pub trait ActionRunner: Send + Sync {
+    async fn run(&self, step: &ActionStep<'_>) -> Result<ActionOutput, ActionError>;
+}
+
+static ENGINE: OnceLock<WorkflowEngine> = OnceLock::new();
+
+/// Make `engine` available to job workers and service-layer hooks
+pub fn install(engine: WorkflowEngine) {
+    if ENGINE.set(engine).is_err() {
+        tracing::warn!("Workflow engine is already installed");
+    }
+}
+
+pub fn engine() -> Option<&'static WorkflowEngine> {
+    ENGINE.get()
+}
+
+/// Report an event from the service layer; ignored when no engine runs
+pub fn notify(event: WorkflowEvent) {
+    let Some(engine) = engine() else {
+        return;
+    };
+    let engine = engine.clone();
+    tokio::spawn(async move {
+        if let Err(e) = engine.dispatch(event).await {
+            tracing::warn!("Failed to dispatch workflow event: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_actions_with_chain() {
+        let actions = parse_actions(
+            "convert_file",
+            &json!({"target_format": "pdf", "then": [
+                {"type": "add_tag", "tag": "converted"},
+                {"type": "move_file", "target_path": "/Done"}
+            ]}),
+        )
+        .unwrap();
+        assert_eq!(actions.len(), 3);
+        assert_eq!(actions[0].config, json!({"target_format": "pdf"}));
+        assert_eq!(actions[1].kind, "add_tag");
+        assert_eq!(actions[1].config, json!({"tag": "converted"}));
+
+        assert!(parse_actions("add_tag", &json!({"then": [{"tag": "x"}]})).is_err());
+        assert!(parse_actions("add_tag", &json!({"then": [{"type": "format_disk"}]})).is_err());
+        assert!(parse_actions("add_tag", &json!([])).is_err());
+        assert!(parse_actions("run_script", &json!({"config": {}})).is_err());
+        assert!(parse_actions("run_script", &json!({"plugin": "classify"})).is_ok());
+    }
+
+    #[test]
+    fn test_trigger_names_round_trip() {
+        for trigger in [Trigger::FileUpload, Trigger::FileTag, Trigger::Scheduled] {
+            assert_eq!(Trigger::parse(trigger.as_str()), Some(trigger));
+        }
+        assert_eq!(Trigger::parse("file_explode"), None);
+        assert_eq!(
+            Trigger::for_change_kind("modify"),
+            &[Trigger::FileUpload, Trigger::FileVersion]
+        );
+        assert!(Trigger::for_change_kind("mkdir").is_empty());
+    }
+
+    #[test]
+    fn test_origin_tracks_rules_and_depth() {
+        let first = Origin::caused_by(None, "a");
+        let second = Origin::caused_by(Some(&first), "b");
+        let third = Origin::caused_by(Some(&second), "a");
+        assert_eq!(third.rule_ids, vec!["a", "b"]);
+        assert_eq!(third.depth, 3);
+    }
+}
@@ -0,0 +1,290 @@
+//! WebAssembly plugins for `run_script` actions
+//!
+//! Admins register WebAssembly modules; rules run them with a `run_script`
+//! action whose config names the plugin and passes it settings:
+//! `{"plugin": "classify-invoices", "config": {...}}`.
+//!
+//! Plugins run in an interpreter with a fuel (instruction), memory and time
+//! budget and reach the server only through these functions, imported from
+//! the `syncspace` module. Pointers and lengths are `i32` offsets into the
+//! plugin's exported `memory`; negative results are errors (-1 no file,
+//! -2 invalid argument, -3 limit reached).
+//!
+//! - `input_len() -> i32` and `input_read(ptr, len) -> i32` give the run's
+//!   input as JSON: `{"file_path", "event", "rule", "config"}`
+//! - `file_size() -> i64` and `file_read(offset: i64, ptr, len) -> i32`
+//!   read the file the rule runs on
+//! - `file_write(name_ptr, name_len, ptr, len) -> i32` appends to a derived
+//!   file created next to it
+//! - `rename(name_ptr, name_len) -> i32` gives the file a new name
+//! - `tag_add(ptr, len) -> i32` tags the file
+//! - `metadata_set(key_ptr, key_len, ptr, len) -> i32` sets a metadata
+//!   value, stored as a WebDAV dead property
+//! - `notify(title_ptr, title_len, ptr, len) -> i32` notifies the rule owner
+//! - `log(ptr, len)` adds a line to the execution log
+//!
+//! The exported `run() -> i32` returns 0 on success, 2 for temporary
+//! failures that are retried and anything else for permanent failures.
+//! Effects are collected during the run and applied by the caller only if it
+//! succeeds.
+
+mod runtime;
+
+pub use runtime::{run, validate};
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::time::Duration;
+
+pub const HOST_MODULE: &str = "syncspace";
+/// Namespace of the dead properties that hold plugin metadata
+pub const METADATA_NAMESPACE: &str = "urn:syncspace:metadata";
+
+/// Largest module that can be registered
+pub const MAX_MODULE_BYTES: usize = 16 * 1024 * 1024;
+/// Total size of the derived files of one run
+const OUTPUT_BYTES: usize = 64 * 1024 * 1024;
+
+const DEFAULT_FUEL: i64 = 500_000_000;
+const MAX_FUEL: i64 = 20_000_000_000;
+const DEFAULT_MEMORY_MB: i64 = 64;
+const MAX_MEMORY_MB: i64 = 1024;
+const DEFAULT_TIMEOUT_MS: i64 = 10_000;
+const MAX_TIMEOUT_MS: i64 = 300_000;
+
+/// Resource budget of one run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Instructions the plugin may execute, roughly
+    pub fuel: u64,
+    pub memory_bytes: usize,
+    pub timeout: Duration,
+    pub output_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: DEFAULT_FUEL as u64,
+            memory_bytes: DEFAULT_MEMORY_MB as usize * 1024 * 1024,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS as u64),
+            output_bytes: OUTPUT_BYTES,
+        }
+    }
+}
+
+/// What a successful run asks the server to do
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PluginOutput {
+    /// Derived files by name, in the order they were first written
+    pub files: Vec<(String, Vec<u8>)>,
+    pub rename: Option<String>,
+    pub tags: Vec<String>,
+    pub metadata: Vec<(String, String)>,
+    /// Title and message of each notification
+    pub notifications: Vec<(String, String)>,
+    pub logs: Vec<String>,
+    pub fuel_used: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PluginError {
+    #[error("invalid plugin: {0}")]
+    Invalid(String),
+    #[error("plugin exceeded its {0}")]
+    LimitExceeded(String),
+    #[error("plugin crashed: {0}")]
+    Trapped(String),
+    #[error("plugin failed with code {code}: {message}")]
+    Failed {
+        code: i32,
+        retryable: bool,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct PluginInfo {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub sha256: String,
+    pub size_bytes: i64,
+    pub fuel_limit: i64,
+    pub memory_limit_mb: i64,
+    pub timeout_ms: i64,
+    pub is_active: bool,
+    pub created_by: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl PluginInfo {
+    pub fn limits(&self) -> Limits {
+        Limits {
+            fuel: self.fuel_limit.clamp(1, MAX_FUEL) as u64,
+            memory_bytes: self.memory_limit_mb.clamp(1, MAX_MEMORY_MB) as usize * 1024 * 1024,
+            timeout: Duration::from_millis(self.timeout_ms.clamp(1, MAX_TIMEOUT_MS) as u64),
+            output_bytes: OUTPUT_BYTES,
+        }
+    }
+}
+
+/// Limits set at registration; missing values use the defaults
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LimitSettings {
+    pub fuel_limit: Option<i64>,
+    pub memory_limit_mb: Option<i64>,
+    pub timeout_ms: Option<i64>,
+}
+
+const INFO_COLUMNS: &str = "id, name, description, sha256, size_bytes, fuel_limit,
+     memory_limit_mb, timeout_ms, is_active, created_by, created_at, updated_at";
+
+/// Validate and store a module. Registering an existing name replaces the
+/// module and its limits.
+pub async fn register(
+    pool: &SqlitePool,
+    name: &str,
+    description: Option<&str>,
+    wasm: &[u8],
+    limits: &LimitSettings,
+    created_by: &str,
+) -> Result<PluginInfo, RegisterError> {
+    let valid_name = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_name {
+        return Err(RegisterError::Invalid(
+            "name must be 1-64 letters, digits, '-' or '_'".to_string(),
+        ));
+    }
+    if wasm.len() > MAX_MODULE_BYTES {
+        return Err(RegisterError::Invalid(format!(
+            "module is larger than {} bytes",
+            MAX_MODULE_BYTES
+        )));
+    }
+    validate(wasm).map_err(|e| RegisterError::Invalid(e.to_string()))?;
+
+    use sha2::{Digest, Sha256};
+    let sha256 = format!("{:x}", Sha256::digest(wasm));
+    let now = chrono::Utc::now().to_rfc3339();
+    let plugin = sqlx::query_as::<_, PluginInfo>(&format!(
+        "INSERT INTO workflow_plugins (id, name, description, module, sha256, size_bytes,
+             fuel_limit, memory_limit_mb, timeout_ms, is_active, created_by, created_at, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 1, ?, ?, ?)
+         ON CONFLICT(name) DO UPDATE SET
+             description = COALESCE(excluded.description, description),
+             module = excluded.module, sha256 = excluded.sha256,
+             size_bytes = excluded.size_bytes, fuel_limit = excluded.fuel_limit,
+             memory_limit_mb = excluded.memory_limit_mb, timeout_ms = excluded.timeout_ms,
+             updated_at = excluded.updated_at
+         RETURNING {}",
+        INFO_COLUMNS
+    ))
+    .bind(uuid::Uuid::new_v4().to_string())
+    .bind(name)
+    .bind(description)
+    .bind(wasm)
+    .bind(&sha256)
+    .bind(wasm.len() as i64)
+    .bind(limits.fuel_limit.unwrap_or(DEFAULT_FUEL).clamp(1, MAX_FUEL))
+    .bind(
+        limits
+            .memory_limit_mb
+            .unwrap_or(DEFAULT_MEMORY_MB)
+            .clamp(1, MAX_MEMORY_MB),
+    )
+    .bind(
+        limits
+            .timeout_ms
+            .unwrap_or(DEFAULT_TIMEOUT_MS)
+            .clamp(1, MAX_TIMEOUT_MS),
+    )
+    .bind(created_by)
+    .bind(&now)
+    .bind(&now)
+    .fetch_one(pool)
+    .await?;
+    Ok(plugin)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegisterError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+pub async fn list(pool: &SqlitePool) -> Result<Vec<PluginInfo>, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "SELECT {} FROM workflow_plugins ORDER BY name",
+        INFO_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await
+}
+
+/// A plugin and its module, by name or id
+pub async fn load(
+    pool: &SqlitePool,
+    name_or_id: &str,
+) -> Result<Option<(PluginInfo, Vec<u8>)>, sqlx::Error> {
+    let Some(info) = sqlx::query_as::<_, PluginInfo>(&format!(
+        "SELECT {} FROM workflow_plugins WHERE name = ?1 OR id = ?1",
+        INFO_COLUMNS
+    ))
+    .bind(name_or_id)
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let module: Vec<u8> = sqlx::query_scalar("SELECT module FROM workflow_plugins WHERE id = ?")
+        .bind(&info.id)
+        .fetch_one(pool)
+        .await?;
+    Ok(Some((info, module)))
+}
+
+/// Change a plugin's settings; `None` leaves a value unchanged
+pub async fn update(
+    pool: &SqlitePool,
+    id: &str,
+    description: Option<&str>,
+    is_active: Option<bool>,
+    limits: &LimitSettings,
+) -> Result<Option<PluginInfo>, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "UPDATE workflow_plugins SET
+             description = COALESCE(?, description),
+             is_active = COALESCE(?, is_active),
+             fuel_limit = COALESCE(?, fuel_limit),
+             memory_limit_mb = COALESCE(?, memory_limit_mb),
+             timeout_ms = COALESCE(?, timeout_ms),
+             updated_at = ?
+         WHERE id = ? RETURNING {}",
+        INFO_COLUMNS
+    ))
+    .bind(description)
+    .bind(is_active)
+    .bind(limits.fuel_limit.map(|fuel| fuel.clamp(1, MAX_FUEL)))
+    .bind(limits.memory_limit_mb.map(|mb| mb.clamp(1, MAX_MEMORY_MB)))
+    .bind(limits.timeout_ms.map(|ms| ms.clamp(1, MAX_TIMEOUT_MS)))
+    .bind(chrono::Utc::now().to_rfc3339())
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn delete(pool: &SqlitePool, id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM workflow_plugins WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
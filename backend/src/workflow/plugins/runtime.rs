@@ -0,0 +1,758 @@
+//! Plugin execution in the wasmi interpreter
+
+use super::{HOST_MODULE, Limits, PluginError, PluginOutput};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use wasmi::core::TrapCode;
+use wasmi::errors::ErrorKind;
+use wasmi::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+/// Host call results
+const OK: i32 = 0;
+/// No file, or it cannot be read
+const ERR_NO_FILE: i32 = -1;
+/// Bad pointer, name or value
+const ERR_INVALID: i32 = -2;
+/// An output limit was reached
+const ERR_LIMIT: i32 = -3;
+
+/// Trap message of host calls made after the deadline
+const TIME_LIMIT: &str = "time limit exceeded";
+
+/// `(module (func (export "spin") (loop (br 0))))`, to measure how fast the
+/// interpreter burns fuel
+const SPIN: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+    0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type: () -> ()
+    0x03, 0x02, 0x01, 0x00, // function 0 has type 0
+    0x07, 0x08, 0x01, 0x04, b's', b'p', b'i', b'n', 0x00, 0x00, // export "spin"
+    0x0a, 0x09, 0x01, 0x07, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b, // loop br 0
+];
+/// How long the measurement spins at least
+const CALIBRATION_TIME: Duration = Duration::from_millis(20);
+
+const MAX_FILES: usize = 16;
+const MAX_TAGS: usize = 64;
+const MAX_METADATA: usize = 64;
+const MAX_NOTIFICATIONS: usize = 8;
+const MAX_LOG_LINES: usize = 200;
+const MAX_TEXT: usize = 4096;
+
+/// Imports a plugin may use
+const HOST_FUNCTIONS: &[&str] = &[
+    "input_len",
+    "input_read",
+    "file_size",
+    "file_read",
+    "file_write",
+    "rename",
+    "tag_add",
+    "metadata_set",
+    "notify",
+    "log",
+];
+
+struct Host {
+    limits: StoreLimits,
+    deadline: Instant,
+    output_bytes: usize,
+    input: Vec<u8>,
+    file: Option<File>,
+    output: PluginOutput,
+}
+
+fn engine() -> Engine {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    Engine::new(&config)
+}
+
+/// Fuel a busy loop burns per millisecond on this machine, measured on first
+/// use; `None` if the measurement failed
+fn fuel_per_ms() -> Option<u64> {
+    static RATE: OnceLock<Option<u64>> = OnceLock::new();
+    *RATE.get_or_init(|| {
+        let engine = engine();
+        let module = Module::new(&engine, SPIN).ok()?;
+        let mut store = Store::new(&engine, ());
+        let spin = Linker::<()>::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.ensure_no_start(&mut store).map_err(Into::into))
+            .and_then(|instance| instance.get_typed_func::<(), ()>(&store, "spin"))
+            .ok()?;
+        let mut fuel: u64 = 1_000_000;
+        loop {
+            store.set_fuel(fuel).ok()?;
+            let started = Instant::now();
+            // Spins until the fuel runs out
+            let _ = spin.call(&mut store, ());
+            let elapsed = started.elapsed();
+            if elapsed >= CALIBRATION_TIME {
+                let rate = fuel as u128 * 1000 / elapsed.as_micros();
+                return u64::try_from(rate).ok().filter(|rate| *rate > 0);
+            }
+            fuel = fuel.checked_mul(4)?;
+        }
+    })
+}
+
+/// The fuel a run gets: the fuel limit, or less if the interpreter would
+/// burn through it only after the time limit. This stops plugins that never
+/// call the host near their deadline.
+fn fuel_for(limits: &Limits) -> u64 {
+    let Some(rate) = fuel_per_ms() else {
+        return limits.fuel;
+    };
+    let in_time = u64::try_from(limits.timeout.as_millis())
+        .unwrap_or(u64::MAX)
+        .saturating_mul(rate)
+        .max(1);
+    limits.fuel.min(in_time)
+}
+
+/// Check that `wasm` is a module the runtime can run
+pub fn validate(wasm: &[u8]) -> Result<(), PluginError> {
+    let module = Module::new(&engine(), wasm).map_err(|e| PluginError::Invalid(e.to_string()))?;
+    check_interface(&module)
+}
+
+fn check_interface(module: &Module) -> Result<(), PluginError> {
+    for import in module.imports() {
+        if import.module() != HOST_MODULE || !HOST_FUNCTIONS.contains(&import.name()) {
+            return Err(PluginError::Invalid(format!(
+                "unknown import {}::{}",
+                import.module(),
+                import.name()
+            )));
+        }
+    }
+    let exports: Vec<&str> = module.exports().map(|export| export.name()).collect();
+    for required in ["run", "memory"] {
+        if !exports.contains(&required) {
+            return Err(PluginError::Invalid(format!(
+                "missing export '{}'",
+                required
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Run a plugin to completion. Blocks; call it from a blocking task.
+pub fn run(
+    wasm: &[u8],
+    limits: &Limits,
+    input: &Value,
+    source: Option<&Path>,
+) -> Result<PluginOutput, PluginError> {
+    let engine = engine();
+    let module = Module::new(&engine, wasm).map_err(|e| PluginError::Invalid(e.to_string()))?;
+    check_interface(&module)?;
+
+    let host = Host {
+        limits: StoreLimitsBuilder::new()
+            .memory_size(limits.memory_bytes)
+            .memories(1)
+            .instances(1)
+            .tables(1)
+            .table_elements(10_000)
+            .build(),
+        deadline: Instant::now() + limits.timeout,
+        output_bytes: limits.output_bytes,
+        input: serde_json::to_vec(input).unwrap_or_default(),
+        file: source.and_then(|path| File::open(path).ok()),
+        output: PluginOutput::default(),
+    };
+    let fuel = fuel_for(limits);
+    let mut store = Store::new(&engine, host);
+    store.limiter(|host| &mut host.limits);
+    store
+        .set_fuel(fuel)
+        .map_err(|e| PluginError::Trapped(e.to_string()))?;
+
+    let linker = linker(&engine)?;
+    let result = linker
+        .instantiate(&mut store, &module)
+        .and_then(|pre| pre.ensure_no_start(&mut store).map_err(Into::into))
+        .and_then(|instance| {
+            instance
+                .get_typed_func::<(), i32>(&store, "run")
+                .and_then(|run| run.call(&mut store, ()))
+        });
+    let fuel_used = fuel - store.get_fuel().unwrap_or(0);
+    let mut output = std::mem::take(&mut store.data_mut().output);
+    output.fuel_used = fuel_used;
+
+    let code = result.map_err(|e| classify(e, limits, fuel < limits.fuel))?;
+    match code {
+        0 => Ok(output),
+        code => Err(PluginError::Failed {
+            code,
+            retryable: code == 2,
+            message: output.logs.last().cloned().unwrap_or_default(),
+        }),
+    }
+}
+
+/// `time_bound` is set when the fuel was cut down to the time limit
+fn classify(error: wasmi::Error, limits: &Limits, time_bound: bool) -> PluginError {
+    match error.kind() {
+        ErrorKind::TrapCode(TrapCode::OutOfFuel) if time_bound => {
+            PluginError::LimitExceeded(format!("time limit of {:?}", limits.timeout))
+        }
+        ErrorKind::TrapCode(TrapCode::OutOfFuel) => {
+            PluginError::LimitExceeded(format!("fuel limit of {}", limits.fuel))
+        }
+        // Memory errors come from the limiter when instantiating a module
+        // whose initial memory is larger than allowed
+        ErrorKind::TrapCode(TrapCode::GrowthOperationLimited) | ErrorKind::Memory(_) => {
+            PluginError::LimitExceeded(format!("memory limit of {} bytes", limits.memory_bytes))
+        }
+        ErrorKind::Message(message) if message.contains(TIME_LIMIT) => {
+            PluginError::LimitExceeded(format!("time limit of {:?}", limits.timeout))
+        }
+        _ => PluginError::Trapped(error.to_string()),
+    }
+}
+
+fn memory(caller: &Caller<'_, Host>) -> Option<Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory)
+}
+
+/// Copy `len` bytes at `ptr` out of the plugin's memory
+fn read_bytes(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let memory = memory(caller)?;
+    let start = usize::try_from(ptr).ok()?;
+    let len = usize::try_from(len).ok()?;
+    memory
+        .data(caller)
+        .get(start..start.checked_add(len)?)
+        .map(<[u8]>::to_vec)
+}
+
+fn read_text(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Option<String> {
+    if usize::try_from(len).ok()? > MAX_TEXT {
+        return None;
+    }
+    String::from_utf8(read_bytes(caller, ptr, len)?).ok()
+}
+
+/// Host calls end the run once the time limit has passed; fuel ends it
+/// between host calls (see [`fuel_for`])
+fn check_deadline(caller: &Caller<'_, Host>) -> Result<(), wasmi::Error> {
+    if Instant::now() > caller.data().deadline {
+        return Err(wasmi::Error::new(TIME_LIMIT));
+    }
+    Ok(())
+}
+
+/// A plain file name: no folders, no hidden files
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 255
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\', ':', '\0'])
+}
+
+fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= 128
+        && tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | ' '))
+}
+
+/// Metadata keys become XML element names in PROPFIND responses
+fn valid_key(key: &str) -> bool {
+    key.len() <= 128
+        && key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn linker(engine: &Engine) -> Result<Linker<Host>, PluginError> {
+    let mut linker = Linker::new(engine);
+    let wrap = |e: wasmi::errors::LinkerError| PluginError::Invalid(e.to_string());
+
+    linker
+        .func_wrap(HOST_MODULE, "input_len", |caller: Caller<'_, Host>| {
+            caller.data().input.len() as i32
+        })
+        .map_err(wrap)?;
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "input_read",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<i32, wasmi::Error> {
+                check_deadline(&caller)?;
+                let Some(memory) = memory(&caller) else {
+                    return Ok(ERR_INVALID);
+                };
+                let (data, host) = memory.data_and_store_mut(&mut caller);
+                let count = host.input.len().min(len.max(0) as usize);
+                let Some(target) = usize::try_from(ptr)
+                    .ok()
+                    .and_then(|start| data.get_mut(start..start.checked_add(count)?))
+                else {
+                    return Ok(ERR_INVALID);
+                };
+                target.copy_from_slice(&host.input[..count]);
+                Ok(count as i32)
+            },
+        )
+        .map_err(wrap)?;
+    linker
+        .func_wrap(HOST_MODULE, "file_size", |caller: Caller<'_, Host>| {
+            caller
+                .data()
+                .file
+                .as_ref()
+                .and_then(|file| file.metadata().ok())
+                .map_or(ERR_NO_FILE as i64, |metadata| metadata.len() as i64)
+        })
+        .map_err(wrap)?;
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "file_read",
+            |mut caller: Caller<'_, Host>,
+             offset: i64,
+             ptr: i32,
+             len: i32|
+             -> Result<i32, wasmi::Error> {
+                check_deadline(&caller)?;
+                let Some(memory) = memory(&caller) else {
+                    return Ok(ERR_INVALID);
+                };
+                let (data, host) = memory.data_and_store_mut(&mut caller);
+                let Some(file) = host.file.as_mut() else {
+                    return Ok(ERR_NO_FILE);
+                };
+                let (Ok(offset), Ok(start), Ok(len)) = (
+                    u64::try_from(offset),
+                    usize::try_from(ptr),
+                    usize::try_from(len),
+                ) else {
+                    return Ok(ERR_INVALID);
+                };
+                let Some(target) = start
+                    .checked_add(len)
+                    .and_then(|end| data.get_mut(start..end))
+                else {
+                    return Ok(ERR_INVALID);
+                };
+                if file.seek(SeekFrom::Start(offset)).is_err() {
+                    return Ok(ERR_NO_FILE);
+                }
+                Ok(file.read(target).map_or(ERR_NO_FILE, |read| read as i32))
+            },
+        )
+        .map_err(wrap)?;
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "file_write",
+            |mut caller: Caller<'_, Host>,
+             name_ptr: i32,
+             name_len: i32,
+             ptr: i32,
+             len: i32|
+             -> Result<i32, wasmi::Error> {
+                check_deadline(&caller)?;
+                let Some(name) = read_text(&caller, name_ptr, name_len).filter(|n| valid_name(n))
+                else {
+                    return Ok(ERR_INVALID);
+                };
+                let Some(bytes) = read_bytes(&caller, ptr, len) else {
+                    return Ok(ERR_INVALID);
+                };
+                let host = caller.data_mut();
+                let size = bytes.len();
+                if size > host.output_bytes {
+                    return Ok(ERR_LIMIT);
+                }
+                let files = &mut host.output.files;
+                match files.iter().position(|(existing, _)| *existing == name) {
+                    Some(index) => files[index].1.extend_from_slice(&bytes),
+                    None if files.len() < MAX_FILES => files.push((name, bytes)),
+                    None => return Ok(ERR_LIMIT),
+                }
+                host.output_bytes -= size;
+                Ok(OK)
+            },
+        )
+        .map_err(wrap)?;
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "rename",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> i32 {
+                match read_text(&caller, ptr, len).filter(|name| valid_name(name)) {
+                    Some(name) if caller.data().file.is_some() => {
+                        caller.data_mut().output.rename = Some(name);
+                        OK
+                    }
+                    Some(_) => ERR_NO_FILE,
+                    None => ERR_INVALID,
+                }
+            },
+        )
+        .map_err(wrap)?;
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "tag_add",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> i32 {
+                let Some(tag) = read_text(&caller, ptr, len).filter(|tag| valid_tag(tag.trim()))
+                else {
+                    return ERR_INVALID;
+                };
+                let tags = &mut caller.data_mut().output.tags;
+                let tag = tag.trim().to_string();
+                if !tags.contains(&tag) {
+                    if tags.len() >= MAX_TAGS {
+                        return ERR_LIMIT;
+                    }
+                    tags.push(tag);
+                }
+                OK
+            },
+        )
+        .map_err(wrap)?;
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "metadata_set",
+            |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32, ptr: i32, len: i32| -> i32 {
+                let key = read_text(&caller, key_ptr, key_len).filter(|key| valid_key(key));
+                let value = read_text(&caller, ptr, len);
+                let (Some(key), Some(value)) = (key, value) else {
+                    return ERR_INVALID;
+                };
+                let metadata = &mut caller.data_mut().output.metadata;
+                match metadata.iter().position(|(existing, _)| *existing == key) {
+                    Some(index) => metadata[index].1 = value,
+                    None if metadata.len() < MAX_METADATA => metadata.push((key, value)),
+                    None => return ERR_LIMIT,
+                }
+                OK
+            },
+        )
+        .map_err(wrap)?;
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "notify",
+            |mut caller: Caller<'_, Host>,
+             title_ptr: i32,
+             title_len: i32,
+             ptr: i32,
+             len: i32|
+             -> i32 {
+                let title = read_text(&caller, title_ptr, title_len);
+                let message = read_text(&caller, ptr, len);
+                let (Some(title), Some(message)) = (title, message) else {
+                    return ERR_INVALID;
+                };
+                let notifications = &mut caller.data_mut().output.notifications;
+                if notifications.len() >= MAX_NOTIFICATIONS {
+                    return ERR_LIMIT;
+                }
+                notifications.push((title, message));
+                OK
+            },
+        )
+        .map_err(wrap)?;
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "log",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                let line = read_bytes(&caller, ptr, len.clamp(0, MAX_TEXT as i32))
+                    .map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+                let logs = &mut caller.data_mut().output.logs;
+                if let Some(line) = line
+                    && logs.len() < MAX_LOG_LINES
+                {
+                    logs.push(line);
+                }
+            },
+        )
+        .map_err(wrap)?;
+    Ok(linker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Write;
+
+    /// Runs `body` as the plugin's `run` function with every host function imported
+    fn plugin(data: &str, body: &str) -> Vec<u8> {
+        wat::parse_str(format!(
+            r#"(module
+                (import "syncspace" "input_len" (func $input_len (result i32)))
+                (import "syncspace" "input_read" (func $input_read (param i32 i32) (result i32)))
+                (import "syncspace" "file_size" (func $file_size (result i64)))
+                (import "syncspace" "file_read" (func $file_read (param i64 i32 i32) (result i32)))
+                (import "syncspace" "file_write" (func $file_write (param i32 i32 i32 i32) (result i32)))
+                (import "syncspace" "rename" (func $rename (param i32 i32) (result i32)))
+                (import "syncspace" "tag_add" (func $tag_add (param i32 i32) (result i32)))
+                (import "syncspace" "metadata_set" (func $metadata_set (param i32 i32 i32 i32) (result i32)))
+                (import "syncspace" "notify" (func $notify (param i32 i32 i32 i32) (result i32)))
+                (import "syncspace" "log" (func $log (param i32 i32)))
+                (memory (export "memory") 1)
+                {}
+                (func (export "run") (result i32) {}))"#,
+            data, body
+        ))
+        .unwrap()
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            fuel: 1_000_000,
+            memory_bytes: 4 * 65536,
+            timeout: Duration::from_secs(5),
+            output_bytes: 1024,
+        }
+    }
+
+    #[test]
+    fn test_effects_are_collected() {
+        let wasm = plugin(
+            r#"(data (i32.const 0) "summary.txt")
+               (data (i32.const 16) "hello")
+               (data (i32.const 32) "invoice.pdf")
+               (data (i32.const 48) "invoice")
+               (data (i32.const 64) "category")
+               (data (i32.const 80) "a&b")
+               (data (i32.const 96) "Done")
+               (data (i32.const 112) "../x")
+               (data (i32.const 128) "bad key")"#,
+            r#"(if (call $file_write (i32.const 0) (i32.const 11) (i32.const 16) (i32.const 5))
+                   (then (return (i32.const 10))))
+               (if (call $file_write (i32.const 0) (i32.const 11) (i32.const 16) (i32.const 5))
+                   (then (return (i32.const 11))))
+               (if (i32.ne (call $file_write (i32.const 112) (i32.const 4) (i32.const 16) (i32.const 5))
+                           (i32.const -2))
+                   (then (return (i32.const 12))))
+               (if (call $rename (i32.const 32) (i32.const 11))
+                   (then (return (i32.const 13))))
+               (drop (call $tag_add (i32.const 48) (i32.const 7)))
+               (drop (call $tag_add (i32.const 48) (i32.const 7)))
+               (if (call $metadata_set (i32.const 64) (i32.const 8) (i32.const 80) (i32.const 3))
+                   (then (return (i32.const 14))))
+               (if (i32.ne (call $metadata_set (i32.const 128) (i32.const 7) (i32.const 80) (i32.const 3))
+                           (i32.const -2))
+                   (then (return (i32.const 15))))
+               (if (call $notify (i32.const 96) (i32.const 4) (i32.const 16) (i32.const 5))
+                   (then (return (i32.const 16))))
+               (call $log (i32.const 16) (i32.const 5))
+               (i32.const 0)"#,
+        );
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        source.write_all(b"%PDF").unwrap();
+
+        let output = run(&wasm, &limits(), &json!({}), Some(source.path())).unwrap();
+        assert_eq!(
+            output.files,
+            vec![("summary.txt".to_string(), b"hellohello".to_vec())]
+        );
+        assert_eq!(output.rename.as_deref(), Some("invoice.pdf"));
+        assert_eq!(output.tags, vec!["invoice"]);
+        assert_eq!(
+            output.metadata,
+            vec![("category".to_string(), "a&b".to_string())]
+        );
+        assert_eq!(
+            output.notifications,
+            vec![("Done".to_string(), "hello".to_string())]
+        );
+        assert_eq!(output.logs, vec!["hello"]);
+        assert!(output.fuel_used > 0);
+    }
+
+    #[test]
+    fn test_reads_input_and_file() {
+        // Copies the input to input.json and the file's first 4 bytes to head.bin
+        let wasm = plugin(
+            r#"(data (i32.const 0) "input.json")
+               (data (i32.const 16) "head.bin")"#,
+            r#"(local $len i32)
+               (local.set $len (call $input_len))
+               (drop (call $input_read (i32.const 1024) (local.get $len)))
+               (drop (call $file_write (i32.const 0) (i32.const 10) (i32.const 1024) (local.get $len)))
+               (if (i64.ne (call $file_size) (i64.const 6)) (then (return (i32.const 10))))
+               (drop (call $file_read (i64.const 2) (i32.const 2048) (i32.const 4)))
+               (drop (call $file_write (i32.const 16) (i32.const 8) (i32.const 2048) (i32.const 4)))
+               (i32.const 0)"#,
+        );
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        source.write_all(b"abcdef").unwrap();
+        let input = json!({"file_path": "docs/a.txt", "config": {"mode": "fast"}});
+
+        let output = run(&wasm, &limits(), &input, Some(source.path())).unwrap();
+        let copied: Value = serde_json::from_slice(&output.files[0].1).unwrap();
+        assert_eq!(copied, input);
+        assert_eq!(output.files[1], ("head.bin".to_string(), b"cdef".to_vec()));
+
+        // Without a file there is nothing to read or rename
+        let wasm = plugin(
+            r#"(data (i32.const 0) "x")"#,
+            r#"(if (i64.ne (call $file_size) (i64.const -1)) (then (return (i32.const 10))))
+               (if (i32.ne (call $rename (i32.const 0) (i32.const 1)) (i32.const -1))
+                   (then (return (i32.const 11))))
+               (i32.const 0)"#,
+        );
+        assert!(run(&wasm, &limits(), &json!({}), None).is_ok());
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let wasm = plugin(
+            r#"(data (i32.const 0) "big.bin")"#,
+            r#"(call $file_write (i32.const 0) (i32.const 7) (i32.const 0) (i32.const 2048))"#,
+        );
+        let error = run(&wasm, &limits(), &json!({}), None).unwrap_err();
+        assert!(
+            matches!(error, PluginError::Failed { code: -3, .. }),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_failure_codes() {
+        let wasm = plugin(
+            r#"(data (i32.const 0) "service unavailable")"#,
+            r#"(call $log (i32.const 0) (i32.const 19))
+               (i32.const 2)"#,
+        );
+        assert_eq!(
+            run(&wasm, &limits(), &json!({}), None).unwrap_err(),
+            PluginError::Failed {
+                code: 2,
+                retryable: true,
+                message: "service unavailable".to_string()
+            }
+        );
+
+        let wasm = plugin("", "(i32.const 1)");
+        assert!(matches!(
+            run(&wasm, &limits(), &json!({}), None),
+            Err(PluginError::Failed {
+                code: 1,
+                retryable: false,
+                ..
+            })
+        ));
+
+        let wasm = plugin("", "(unreachable)");
+        assert!(matches!(
+            run(&wasm, &limits(), &json!({}), None),
+            Err(PluginError::Trapped(_))
+        ));
+    }
+
+    #[test]
+    fn test_fuel_limit() {
+        let wasm = plugin("", "(loop $spin (br $spin)) (i32.const 0)");
+        assert!(matches!(
+            run(&wasm, &limits(), &json!({}), None),
+            Err(PluginError::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let wasm = plugin(
+            "",
+            "(loop $spin (drop (call $input_read (i32.const 0) (i32.const 0))) (br $spin)) (i32.const 0)",
+        );
+        let limits = Limits {
+            fuel: u64::MAX,
+            timeout: Duration::from_millis(50),
+            ..limits()
+        };
+        assert!(matches!(
+            run(&wasm, &limits, &json!({}), None),
+            Err(PluginError::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_time_limit_without_host_calls() {
+        let wasm = plugin("", "(loop $spin (br $spin)) (i32.const 0)");
+        let limits = Limits {
+            fuel: 20_000_000_000,
+            timeout: Duration::from_millis(200),
+            ..limits()
+        };
+        let started = Instant::now();
+        let error = run(&wasm, &limits, &json!({}), None).unwrap_err();
+        let elapsed = started.elapsed();
+        assert_eq!(
+            error,
+            PluginError::LimitExceeded("time limit of 200ms".to_string())
+        );
+        // Loops cost a little more than the measurement, and tests run in parallel
+        assert!(elapsed < Duration::from_secs(2), "ran for {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_spin_module() {
+        let spin = wat::parse_str(r#"(module (func (export "spin") (loop (br 0))))"#).unwrap();
+        assert_eq!(spin, SPIN);
+        assert!(fuel_per_ms().is_some());
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        // Growing past the limit fails like running out of memory
+        let wasm = plugin("", "(memory.grow (i32.const 8))");
+        assert!(matches!(
+            run(&wasm, &limits(), &json!({}), None),
+            Err(PluginError::Failed { code: -1, .. })
+        ));
+
+        let wasm = wat::parse_str(
+            r#"(module (memory (export "memory") 8) (func (export "run") (result i32) (i32.const 0)))"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            run(&wasm, &limits(), &json!({}), None),
+            Err(PluginError::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_interface_is_checked() {
+        let wasi = wat::parse_str(
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "run") (result i32) (i32.const 0)))"#,
+        )
+        .unwrap();
+        assert!(matches!(validate(&wasi), Err(PluginError::Invalid(_))));
+
+        let no_memory =
+            wat::parse_str(r#"(module (func (export "run") (result i32) (i32.const 0)))"#).unwrap();
+        assert!(matches!(validate(&no_memory), Err(PluginError::Invalid(_))));
+
+        assert!(matches!(
+            validate(b"not wasm"),
+            Err(PluginError::Invalid(_))
+        ));
+        assert!(validate(&plugin("", "(i32.const 0)")).is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+//! Backup chain tests
+//!
+//! Incremental and differential backups pick the backup they are taken
+//! against, chains are closed after `full_every` links and deleting a link
+//! removes its archive and rows.
+
+mod common;
+
+use std::str::FromStr;
+
+use sqlx::SqlitePool;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use syncbackend::backup::chain;
+use tempfile::TempDir;
+
+async fn setup() -> SqlitePool {
+    // Like the server, without foreign key enforcement
+    let options = SqliteConnectOptions::from_str("sqlite::memory:")
+        .unwrap()
+        .foreign_keys(false);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+        .unwrap();
+    for sql in [
+        include_str!("../migrations/003_add_backups.sql"),
+        include_str!("../migrations/016_add_backup_scheduling.sql"),
+        include_str!("../migrations/043_enhanced_backup_system.sql"),
+        include_str!("../migrations/044_backup_system.sql"),
+        include_str!("../migrations/066_secrets_vault.sql"),
+        include_str!("../migrations/067_backup_chains.sql"),
+        include_str!("../migrations/068_backup_uploads.sql"),
+        include_str!("../migrations/069_backup_file_entries.sql"),
+    ] {
+        common::run_migration(&pool, sql).await;
+    }
+    pool
+}
+
+/// A backup of `schedule_id` started on `day` of January
+async fn start_backup(
+    pool: &SqlitePool,
+    id: &str,
+    backup_type: &str,
+    schedule_id: Option<&str>,
+    day: u32,
+) {
+    sqlx::query(
+        "INSERT INTO backups (id, backup_type, storage_path, created_by, created_at, schedule_id)
+         VALUES (?, ?, ?, 'admin', ?, ?)",
+    )
+    .bind(id)
+    .bind(backup_type)
+    .bind(format!("./data/backups/{}.tar.zst", id))
+    .bind(format!("2026-01-{:02} 02:00:00", day))
+    .bind(schedule_id)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+/// Complete a backup the way the backup job does, linked to its parent
+async fn complete(pool: &SqlitePool, backup_id: &str) {
+    let parent = chain::parent_for(pool, backup_id).await.unwrap();
+    sqlx::query(
+        "UPDATE backups SET status = 'completed', parent_backup_id = ?, base_backup_id = ?
+         WHERE id = ?",
+    )
+    .bind(parent.as_ref().map(|link| link.id.clone()))
+    .bind(parent.as_ref().map(|link| link.base_id().to_string()))
+    .bind(backup_id)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+async fn parent(pool: &SqlitePool, backup_id: &str) -> Option<String> {
+    chain::parent_for(pool, backup_id)
+        .await
+        .unwrap()
+        .map(|link| link.id)
+}
+
+#[tokio::test]
+async fn test_parents_and_full_every() {
+    let pool = setup().await;
+    sqlx::query("INSERT INTO backup_schedules (id, name, full_every) VALUES ('s1', 'Nightly', 2)")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // Nothing to chain onto yet
+    start_backup(&pool, "i0", "incremental", Some("s1"), 1).await;
+    assert_eq!(parent(&pool, "i0").await, None);
+    sqlx::query("DELETE FROM backups WHERE id = 'i0'")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    start_backup(&pool, "f1", "full", Some("s1"), 1).await;
+    assert_eq!(parent(&pool, "f1").await, None);
+    complete(&pool, "f1").await;
+    start_backup(&pool, "i1", "incremental", Some("s1"), 2).await;
+    assert_eq!(parent(&pool, "i1").await.as_deref(), Some("f1"));
+    complete(&pool, "i1").await;
+
+    // Incremental backups follow the latest link, differential ones the full
+    // backup of the chain; failed backups and other schedules do not count
+    start_backup(&pool, "x1", "full", None, 3).await;
+    sqlx::query("UPDATE backups SET status = 'failed' WHERE id = 'x1'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    start_backup(&pool, "i2", "incremental", Some("s1"), 3).await;
+    start_backup(&pool, "d2", "differential", Some("s1"), 3).await;
+    let next = chain::parent_for(&pool, "i2").await.unwrap().unwrap();
+    assert_eq!((next.id.as_str(), next.base_id()), ("i1", "f1"));
+    assert_eq!(parent(&pool, "d2").await.as_deref(), Some("f1"));
+    sqlx::query("DELETE FROM backups WHERE id = 'd2'")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // Two links after f1 close the chain
+    complete(&pool, "i2").await;
+    start_backup(&pool, "i3", "incremental", Some("s1"), 4).await;
+    assert_eq!(parent(&pool, "i3").await, None);
+
+    // Manual backups chain among themselves
+    start_backup(&pool, "m1", "full", None, 5).await;
+    complete(&pool, "m1").await;
+    start_backup(&pool, "m2", "incremental", None, 6).await;
+    assert_eq!(parent(&pool, "m2").await.as_deref(), Some("m1"));
+
+    assert_eq!(chain::children(&pool, "f1").await.unwrap(), ["i1"]);
+    assert_eq!(chain::links(&pool, "i2").await.unwrap(), ["i2", "i1", "f1"]);
+    assert_eq!(chain::links(&pool, "f1").await.unwrap(), ["f1"]);
+}
+
+#[tokio::test]
+async fn test_delete_removes_archive_and_rows() {
+    let pool = setup().await;
+    let dir = TempDir::new().unwrap();
+    let archive = dir.path().join("f1.tar.zst");
+    std::fs::write(&archive, b"archive").unwrap();
+    let manifest = syncbackend::backup::manifest_path(&archive);
+    std::fs::write(&manifest, b"{}").unwrap();
+
+    start_backup(&pool, "f1", "full", None, 1).await;
+    complete(&pool, "f1").await;
+    sqlx::query("UPDATE backups SET storage_path = ? WHERE id = 'f1'")
+        .bind(archive.to_string_lossy().as_ref())
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO backup_files (backup_id, file_path, file_size) VALUES ('f1', 'a', 1)")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    chain::delete(&pool, "f1").await.unwrap();
+    assert!(!archive.exists());
+    assert!(!manifest.exists());
+    for table in ["backups", "backup_files"] {
+        let (count,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {}", table))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 0, "{} still has rows", table);
+    }
+
+    // Deleting what is already gone is not an error
+    chain::delete(&pool, "f1").await.unwrap();
+}
@@ -0,0 +1,564 @@
+//! Backup destination integration tests
+//!
+//! Uploads to a local destination and to an in-process mock of the S3
+//! multipart API are always tested, including resuming after a failed part
+//! and rejecting copies that do not match. The same round trip runs against
+//! a real MinIO and SFTP server when they are configured:
+//!
+//! ```bash
+//! docker run -p 9000:9000 minio/minio server /data
+//! SYNCSPACE_TEST_S3_ENDPOINT=http://127.0.0.1:9000 SYNCSPACE_TEST_S3_BUCKET=syncspace-test \
+//! SYNCSPACE_TEST_S3_ACCESS_KEY=minioadmin SYNCSPACE_TEST_S3_SECRET_KEY=minioadmin \
+//!     cargo test --test backup_destination_tests
+//!
+//! docker run -p 2222:22 atmoz/sftp syncspace:secret:::upload
+//! ssh-keyscan -p 2222 127.0.0.1 2>/dev/null | ssh-keygen -lf - # host key fingerprint
+//! SYNCSPACE_TEST_SFTP_HOST=127.0.0.1 SYNCSPACE_TEST_SFTP_PORT=2222 \
+//! SYNCSPACE_TEST_SFTP_USER=syncspace SYNCSPACE_TEST_SFTP_PASSWORD=secret \
+//! SYNCSPACE_TEST_SFTP_PATH=upload/backups SYNCSPACE_TEST_SFTP_HOST_KEY=SHA256:... \
+//!     cargo test --test backup_destination_tests
+//! ```
+
+mod common;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::Engine as _;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use syncbackend::backup::{chain, destination};
+use syncbackend::secrets;
+use tempfile::TempDir;
+
+async fn setup() -> SqlitePool {
+    // Like the server, without foreign key enforcement
+    let options = SqliteConnectOptions::from_str("sqlite::memory:")
+        .unwrap()
+        .foreign_keys(false);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+        .unwrap();
+    for sql in [
+        include_str!("../migrations/003_add_backups.sql"),
+        include_str!("../migrations/016_add_backup_scheduling.sql"),
+        include_str!("../migrations/043_enhanced_backup_system.sql"),
+        include_str!("../migrations/044_backup_system.sql"),
+        include_str!("../migrations/066_secrets_vault.sql"),
+        include_str!("../migrations/067_backup_chains.sql"),
+        include_str!("../migrations/068_backup_uploads.sql"),
+        include_str!("../migrations/069_backup_file_entries.sql"),
+    ] {
+        common::run_migration(&pool, sql).await;
+    }
+    // Installed once for all tests of this binary
+    if secrets::vault().is_none() {
+        secrets::install(secrets::Vault::in_memory());
+    }
+    pool
+}
+
+/// Deterministic content that does not compress away
+fn content(size: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_u32;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// Store a destination the way the API does, credentials sealed
+async fn add_destination(
+    pool: &SqlitePool,
+    destination_type: &str,
+    mut config: serde_json::Value,
+) -> String {
+    let id = uuid::Uuid::new_v4().to_string();
+    let sealed = destination::split_secrets(&mut config).map(|secrets| {
+        secrets::seal(
+            &secrets::BACKUP_DESTINATION_SECRETS,
+            &id,
+            &secrets.to_string(),
+        )
+        .unwrap()
+    });
+    sqlx::query(
+        "INSERT INTO backup_destinations (id, name, destination_type, config, secrets, created_by)
+         VALUES (?, ?, ?, ?, ?, 'admin')",
+    )
+    .bind(&id)
+    .bind(format!("{} test", destination_type))
+    .bind(destination_type)
+    .bind(config.to_string())
+    .bind(sealed)
+    .execute(pool)
+    .await
+    .unwrap();
+    id
+}
+
+/// A completed backup whose archive holds `data`
+async fn add_backup(
+    pool: &SqlitePool,
+    dir: &Path,
+    destination_id: &str,
+    data: &[u8],
+) -> (String, PathBuf) {
+    let id = uuid::Uuid::new_v4().to_string();
+    let archive = dir.join(format!("{}.tar.zst", id));
+    std::fs::write(&archive, data).unwrap();
+    sqlx::query(
+        "INSERT INTO backups (id, backup_type, size_bytes, storage_path, created_by, created_at,
+                              status, checksum, destination_id)
+         VALUES (?, 'full', ?, ?, 'admin', datetime('now'), 'completed', ?, ?)",
+    )
+    .bind(&id)
+    .bind(data.len() as i64)
+    .bind(archive.to_string_lossy().as_ref())
+    .bind(hex::encode(Sha256::digest(data)))
+    .bind(destination_id)
+    .execute(pool)
+    .await
+    .unwrap();
+    (id, archive)
+}
+
+async fn upload_state(pool: &SqlitePool, backup_id: &str) -> (Option<String>, Option<String>) {
+    sqlx::query_as("SELECT upload_status, remote_path FROM backups WHERE id = ?")
+        .bind(backup_id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+async fn pending_upload(pool: &SqlitePool, backup_id: &str) -> Option<String> {
+    sqlx::query_scalar("SELECT upload_id FROM backup_uploads WHERE backup_id = ?")
+        .bind(backup_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+        .flatten()
+}
+
+/// Upload, drop the local archive, fetch it back and delete the backup
+async fn round_trip(pool: &SqlitePool, destination_type: &str, config: serde_json::Value) {
+    let dir = TempDir::new().unwrap();
+    let destination_id = add_destination(pool, destination_type, config).await;
+    destination::open(pool, &destination_id, "test")
+        .await
+        .unwrap()
+        .probe()
+        .await
+        .unwrap();
+
+    let data = content(6 * 1024 * 1024 + 3);
+    let (backup_id, archive) = add_backup(pool, dir.path(), &destination_id, &data).await;
+    let uploaded = destination::upload(pool, &backup_id).await.unwrap();
+    assert_eq!(
+        upload_state(pool, &backup_id).await,
+        (Some("uploaded".to_string()), Some(uploaded.location))
+    );
+
+    std::fs::remove_file(&archive).unwrap();
+    assert_eq!(destination::fetch_chain(pool, &backup_id).await.unwrap(), 1);
+    assert_eq!(std::fs::read(&archive).unwrap(), data);
+
+    chain::delete(pool, &backup_id).await.unwrap();
+    let destination = destination::open(pool, &destination_id, "test")
+        .await
+        .unwrap();
+    let name = archive.file_name().unwrap().to_str().unwrap();
+    assert!(
+        destination
+            .download(name, &dir.path().join("gone"))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_local_destination_resumes_verifies_and_prunes() {
+    let pool = setup().await;
+    let backups = TempDir::new().unwrap();
+    let mount = TempDir::new().unwrap();
+    let destination_id = add_destination(
+        &pool,
+        "local",
+        serde_json::json!({ "path": mount.path(), "keep_local": false }),
+    )
+    .await;
+
+    // A copy interrupted halfway is continued
+    let data = content(300_000);
+    let (backup_id, archive) = add_backup(&pool, backups.path(), &destination_id, &data).await;
+    let name = archive.file_name().unwrap().to_str().unwrap().to_string();
+    std::fs::write(
+        mount.path().join(format!("{}.partial", name)),
+        &data[..100_000],
+    )
+    .unwrap();
+    let uploaded = destination::upload(&pool, &backup_id).await.unwrap();
+    assert!(uploaded.removed_local);
+    assert!(!archive.exists());
+    assert_eq!(std::fs::read(mount.path().join(&name)).unwrap(), data);
+    assert!(!mount.path().join(format!("{}.partial", name)).exists());
+    assert_eq!(
+        upload_state(&pool, &backup_id).await.0.as_deref(),
+        Some("uploaded")
+    );
+
+    // A damaged partial copy fails the check and the retry starts over
+    let (second, second_archive) = add_backup(&pool, backups.path(), &destination_id, &data).await;
+    let second_name = second_archive
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    std::fs::write(
+        mount.path().join(format!("{}.partial", second_name)),
+        vec![0u8; 50_000],
+    )
+    .unwrap();
+    let error = destination::upload(&pool, &second).await.unwrap_err();
+    assert!(error.to_string().contains("checksum"), "{:#}", error);
+    assert_eq!(
+        upload_state(&pool, &second).await.0.as_deref(),
+        Some("failed")
+    );
+    assert!(second_archive.exists());
+    destination::upload(&pool, &second).await.unwrap();
+    assert_eq!(
+        std::fs::read(mount.path().join(&second_name)).unwrap(),
+        data
+    );
+
+    // Archives only kept at the destination are fetched and checked
+    assert_eq!(
+        destination::fetch_chain(&pool, &backup_id).await.unwrap(),
+        1
+    );
+    assert_eq!(std::fs::read(&archive).unwrap(), data);
+    std::fs::remove_file(&archive).unwrap();
+    std::fs::write(mount.path().join(&name), b"tampered").unwrap();
+    assert!(destination::fetch_chain(&pool, &backup_id).await.is_err());
+    assert!(!archive.exists());
+
+    // Deleting a backup deletes its uploaded copy
+    chain::delete(&pool, &second).await.unwrap();
+    assert!(!mount.path().join(&second_name).exists());
+}
+
+#[tokio::test]
+async fn test_destination_credentials_are_sealed() {
+    let pool = setup().await;
+    let destination_id = add_destination(
+        &pool,
+        "sftp",
+        serde_json::json!({
+            "host": "nas.local",
+            "username": "backup",
+            "password": "hunter2",
+            "path": "/srv/backups"
+        }),
+    )
+    .await;
+    let (config, sealed): (String, String) =
+        sqlx::query_as("SELECT config, secrets FROM backup_destinations WHERE id = ?")
+            .bind(&destination_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert!(!config.contains("hunter2"));
+    assert!(secrets::is_sealed(&sealed));
+
+    let destination = destination::open(&pool, &destination_id, "test")
+        .await
+        .unwrap();
+    assert_eq!(destination.kind(), "sftp");
+    assert_eq!(
+        destination.location("b.tar.zst"),
+        "sftp://backup@nas.local:22/srv/backups/b.tar.zst"
+    );
+
+    // Without its password the destination cannot be built
+    sqlx::query("UPDATE backup_destinations SET secrets = NULL WHERE id = ?")
+        .bind(&destination_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert!(
+        destination::open(&pool, &destination_id, "test")
+            .await
+            .is_err()
+    );
+}
+
+/// The parts of the S3 API multipart uploads use, keeping everything in
+/// memory
+#[derive(Default)]
+struct MockS3 {
+    uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+    objects: HashMap<String, Vec<u8>>,
+    /// Parts received, in order
+    received: Vec<u32>,
+    /// Answer the next upload of this part with an error
+    fail_part: Option<u32>,
+    /// Drop a byte when assembling the next object
+    damage: bool,
+}
+
+type Mock = Arc<Mutex<MockS3>>;
+
+fn md5_hex(data: &[u8]) -> String {
+    format!("{:x}", md5::compute(data))
+}
+
+fn xml(body: String) -> Response {
+    ([("content-type", "application/xml")], body).into_response()
+}
+
+async fn mock_s3(
+    State(mock): State<Mock>,
+    method: Method,
+    axum::extract::Path((_bucket, key)): axum::extract::Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let mut guard = mock.lock().unwrap();
+    let mock = &mut *guard;
+    let upload_id = query.get("uploadId").cloned();
+    match (method, upload_id) {
+        (Method::POST, None) if query.contains_key("uploads") => {
+            let id = uuid::Uuid::new_v4().to_string();
+            mock.uploads.insert(id.clone(), BTreeMap::new());
+            xml(format!(
+                "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                id
+            ))
+        }
+        (Method::PUT, Some(id)) => {
+            let number: u32 = query["partNumber"].parse().unwrap();
+            let digest = base64::engine::general_purpose::STANDARD.encode(md5::compute(&body).0);
+            if headers.get("content-md5").and_then(|v| v.to_str().ok()) != Some(digest.as_str()) {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "<Error><Code>BadDigest</Code></Error>",
+                )
+                    .into_response();
+            }
+            if mock.fail_part == Some(number) {
+                mock.fail_part = None;
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            let Some(parts) = mock.uploads.get_mut(&id) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            parts.insert(number, body.to_vec());
+            mock.received.push(number);
+            ([("etag", format!("\"{}\"", md5_hex(&body)))], "").into_response()
+        }
+        (Method::GET, Some(id)) => {
+            let Some(parts) = mock.uploads.get(&id) else {
+                return (
+                    StatusCode::NOT_FOUND,
+                    "<Error><Code>NoSuchUpload</Code></Error>",
+                )
+                    .into_response();
+            };
+            let listed: String = parts
+                .iter()
+                .map(|(number, data)| {
+                    format!(
+                        "<Part><PartNumber>{}</PartNumber><ETag>&quot;{}&quot;</ETag><Size>{}</Size></Part>",
+                        number,
+                        md5_hex(data),
+                        data.len()
+                    )
+                })
+                .collect();
+            xml(format!(
+                "<ListPartsResult><IsTruncated>false</IsTruncated>{}</ListPartsResult>",
+                listed
+            ))
+        }
+        (Method::POST, Some(id)) => {
+            let Some(parts) = mock.uploads.remove(&id) else {
+                return (
+                    StatusCode::NOT_FOUND,
+                    "<Error><Code>NoSuchUpload</Code></Error>",
+                )
+                    .into_response();
+            };
+            let mut data: Vec<u8> = parts.values().flatten().copied().collect();
+            if std::mem::take(&mut mock.damage) {
+                data.pop();
+            }
+            mock.objects.insert(key, data);
+            xml("<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".to_string())
+        }
+        (Method::DELETE, Some(id)) => {
+            mock.uploads.remove(&id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        (Method::PUT, None) => {
+            mock.objects.insert(key, body.to_vec());
+            StatusCode::OK.into_response()
+        }
+        (Method::GET | Method::HEAD, None) => match mock.objects.get(&key) {
+            // The ETag of an assembled object is that of its parts
+            Some(data) => {
+                let parts = data.len().div_ceil(5 * 1024 * 1024).max(1);
+                let digests: Vec<u8> = data
+                    .chunks(5 * 1024 * 1024)
+                    .flat_map(|part| md5::compute(part).0)
+                    .collect();
+                let etag = format!("\"{}-{}\"", md5_hex(&digests), parts);
+                ([("etag", etag)], data.clone()).into_response()
+            }
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        (Method::DELETE, None) => {
+            mock.objects.remove(&key);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+async fn start_mock_s3() -> (Mock, String) {
+    let mock = Mock::default();
+    let app = Router::new()
+        .route("/{bucket}/{*key}", axum::routing::any(mock_s3))
+        .layer(axum::extract::DefaultBodyLimit::disable())
+        .with_state(mock.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (mock, endpoint)
+}
+
+#[tokio::test]
+async fn test_s3_destination_resumes_multipart_upload() {
+    let pool = setup().await;
+    let (mock, endpoint) = start_mock_s3().await;
+    let backups = TempDir::new().unwrap();
+    let destination_id = add_destination(
+        &pool,
+        "s3",
+        serde_json::json!({
+            "endpoint": endpoint,
+            "bucket": "backups",
+            "access_key_id": "test",
+            "secret_access_key": "test-secret",
+            "prefix": "nightly",
+            "part_size_mb": 5
+        }),
+    )
+    .await;
+
+    // Three parts, the second of which fails the first time
+    let data = content(11 * 1024 * 1024);
+    let (backup_id, archive) = add_backup(&pool, backups.path(), &destination_id, &data).await;
+    let key = format!("nightly/{}", archive.file_name().unwrap().to_str().unwrap());
+    mock.lock().unwrap().fail_part = Some(2);
+    assert!(destination::upload(&pool, &backup_id).await.is_err());
+    assert_eq!(
+        upload_state(&pool, &backup_id).await.0.as_deref(),
+        Some("failed")
+    );
+    let upload_id = pending_upload(&pool, &backup_id).await.unwrap();
+    assert!(mock.lock().unwrap().uploads.contains_key(&upload_id));
+
+    // The retry continues the same upload without sending part 1 again
+    let uploaded = destination::upload(&pool, &backup_id).await.unwrap();
+    assert!(!uploaded.removed_local);
+    assert_eq!(uploaded.location, format!("s3://backups/{}", key));
+    assert_eq!(mock.lock().unwrap().received, vec![1, 2, 3]);
+    assert_eq!(mock.lock().unwrap().objects[&key], data);
+    assert_eq!(pending_upload(&pool, &backup_id).await, None);
+
+    // An object that does not match what was sent is removed again
+    let (damaged, damaged_archive) =
+        add_backup(&pool, backups.path(), &destination_id, &data).await;
+    let damaged_key = format!(
+        "nightly/{}",
+        damaged_archive.file_name().unwrap().to_str().unwrap()
+    );
+    mock.lock().unwrap().damage = true;
+    let error = destination::upload(&pool, &damaged).await.unwrap_err();
+    assert!(error.to_string().contains("does not match"), "{:#}", error);
+    assert!(!mock.lock().unwrap().objects.contains_key(&damaged_key));
+
+    // An unfinished upload is aborted when its backup is deleted
+    mock.lock().unwrap().fail_part = Some(3);
+    assert!(destination::upload(&pool, &damaged).await.is_err());
+    assert_eq!(mock.lock().unwrap().uploads.len(), 1);
+    chain::delete(&pool, &damaged).await.unwrap();
+    assert!(mock.lock().unwrap().uploads.is_empty());
+
+    std::fs::remove_file(&archive).unwrap();
+    assert_eq!(
+        destination::fetch_chain(&pool, &backup_id).await.unwrap(),
+        1
+    );
+    assert_eq!(std::fs::read(&archive).unwrap(), data);
+    chain::delete(&pool, &backup_id).await.unwrap();
+    assert!(mock.lock().unwrap().objects.is_empty());
+}
+
+#[tokio::test]
+async fn test_minio_destination_round_trip() {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let config = (|| {
+        Some(serde_json::json!({
+            "endpoint": var("SYNCSPACE_TEST_S3_ENDPOINT")?,
+            "bucket": var("SYNCSPACE_TEST_S3_BUCKET")?,
+            "access_key_id": var("SYNCSPACE_TEST_S3_ACCESS_KEY")?,
+            "secret_access_key": var("SYNCSPACE_TEST_S3_SECRET_KEY")?,
+            "region": var("SYNCSPACE_TEST_S3_REGION").unwrap_or_else(|| "us-east-1".to_string()),
+            "prefix": "syncspace-tests/backups",
+            "part_size_mb": 5
+        }))
+    })();
+    let Some(config) = config else {
+        eprintln!("Skipping MinIO round trip: SYNCSPACE_TEST_S3_* is not configured");
+        return;
+    };
+    round_trip(&setup().await, "minio", config).await;
+}
+
+#[tokio::test]
+async fn test_sftp_destination_round_trip() {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let config = (|| {
+        Some(serde_json::json!({
+            "host": var("SYNCSPACE_TEST_SFTP_HOST")?,
+            "port": var("SYNCSPACE_TEST_SFTP_PORT").unwrap_or_else(|| "22".to_string()),
+            "username": var("SYNCSPACE_TEST_SFTP_USER")?,
+            "password": var("SYNCSPACE_TEST_SFTP_PASSWORD")?,
+            "path": var("SYNCSPACE_TEST_SFTP_PATH").unwrap_or_else(|| "backups".to_string()),
+            "host_key_sha256": var("SYNCSPACE_TEST_SFTP_HOST_KEY")?
+        }))
+    })();
+    let Some(config) = config else {
+        eprintln!("Skipping SFTP round trip: SYNCSPACE_TEST_SFTP_* is not configured");
+        return;
+    };
+    round_trip(&setup().await, "sftp", config).await;
+}
@@ -0,0 +1,518 @@
+//! Backup integration tests
+//!
+//! Backups are taken of a temporary data directory with a live WAL database,
+//! then restored into a wiped, an existing and a partial target. A chain of
+//! incremental backups is restored from its middle link.
+
+mod common;
+
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use std::path::Path;
+use syncbackend::backup::archive::ArchiveWriter;
+use syncbackend::backup::{
+    self, BackupOptions, Change, DatabaseRestore, EntryKind, Manifest, ManifestEntry,
+    RestoreOptions,
+};
+use tempfile::TempDir;
+
+async fn open_db(data: &Path) -> SqlitePool {
+    let options = SqliteConnectOptions::new()
+        .filename(data.join("syncspace.db"))
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal);
+    SqlitePoolOptions::new()
+        .max_connections(2)
+        .connect_with(options)
+        .await
+        .unwrap()
+}
+
+/// A data directory with user files, version history, rebuildable content
+/// and a database holding `rows` files
+async fn seed(data: &Path, rows: usize) -> SqlitePool {
+    let photo: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    for (path, content) in [
+        ("Documents/report.txt", b"quarterly numbers".to_vec()),
+        ("Photos/holiday.raw", photo),
+        ("versions/file-1/1", b"first draft".to_vec()),
+        (".blocks/ab/abcdef", b"block".to_vec()),
+        ("search_index/meta.json", b"{}".to_vec()),
+        ("backups/older.tar.zst", b"not me".to_vec()),
+    ] {
+        let path = data.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    let pool = open_db(data).await;
+    sqlx::query("CREATE TABLE files (id INTEGER PRIMARY KEY, name TEXT NOT NULL)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    for i in 0..rows {
+        sqlx::query("INSERT INTO files (name) VALUES (?)")
+            .bind(format!("file-{}", i))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    pool
+}
+
+async fn file_names(data: &Path) -> Vec<String> {
+    let pool = open_db(data).await;
+    let names = sqlx::query_scalar("SELECT name FROM files ORDER BY id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+    names
+}
+
+fn staging_dirs(data: &Path) -> usize {
+    std::fs::read_dir(data)
+        .unwrap()
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_string_lossy().starts_with(".restore-")
+        })
+        .count()
+}
+
+#[tokio::test]
+async fn test_backup_wipe_restore() {
+    let dir = TempDir::new().unwrap();
+    let data = dir.path().join("data");
+    let pool = seed(&data, 3).await;
+
+    // Not committed when the snapshot is taken, so not part of it
+    let mut pending = pool.begin().await.unwrap();
+    sqlx::query("INSERT INTO files (name) VALUES ('pending')")
+        .execute(&mut *pending)
+        .await
+        .unwrap();
+
+    let archive = dir.path().join("out/backup.tar.zst");
+    let written = backup::create(&pool, &data, &archive, "b1", BackupOptions::default(), None)
+        .await
+        .unwrap();
+    pending.rollback().await.unwrap();
+    pool.close().await;
+
+    let manifest = &written.manifest;
+    let paths: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        [
+            "syncspace.db",
+            "data/.blocks/ab/abcdef",
+            "data/Documents/report.txt",
+            "data/Photos/holiday.raw",
+            "data/versions/file-1/1"
+        ]
+    );
+    assert_eq!(manifest.file_count(), 4);
+    assert_eq!(manifest.entries[4].kind, EntryKind::Version);
+    assert_eq!(
+        manifest.entries[2].sha256,
+        hex::encode(Sha256::digest(b"quarterly numbers"))
+    );
+    let bytes = std::fs::read(&archive).unwrap();
+    assert_eq!(written.size, bytes.len() as u64);
+    assert_eq!(written.sha256, hex::encode(Sha256::digest(&bytes)));
+    // Compressed, and nothing but the manifest copy left next to the archive
+    assert!(written.size < manifest.total_size());
+    assert_eq!(
+        std::fs::read_dir(dir.path().join("out")).unwrap().count(),
+        2
+    );
+    assert!(dir.path().join("out/backup.manifest.json").exists());
+
+    let verified = backup::verify(&archive).await.unwrap();
+    assert_eq!(verified.len(), 1);
+    assert_eq!(verified[0].sha256, written.sha256);
+    assert_eq!(verified[0].manifest, *manifest);
+
+    let original_photo = std::fs::read(data.join("Photos/holiday.raw")).unwrap();
+    std::fs::remove_dir_all(&data).unwrap();
+
+    let report = backup::restore(&archive, &data, RestoreOptions::full())
+        .await
+        .unwrap();
+    assert_eq!(report.backup_id, "b1");
+    assert_eq!(report.database, DatabaseRestore::Restored);
+    assert_eq!(report.files_restored, 4);
+
+    assert_eq!(
+        std::fs::read(data.join("Documents/report.txt")).unwrap(),
+        b"quarterly numbers"
+    );
+    assert_eq!(
+        std::fs::read(data.join("Photos/holiday.raw")).unwrap(),
+        original_photo
+    );
+    assert_eq!(
+        std::fs::read(data.join("versions/file-1/1")).unwrap(),
+        b"first draft"
+    );
+    assert!(!data.join("search_index").exists());
+    assert!(!data.join("backups").exists());
+    assert_eq!(staging_dirs(&data), 0);
+    assert_eq!(file_names(&data).await, ["file-0", "file-1", "file-2"]);
+}
+
+#[tokio::test]
+async fn test_restore_into_existing_and_damaged_archives() {
+    let dir = TempDir::new().unwrap();
+    let data = dir.path().join("data");
+    let pool = seed(&data, 2).await;
+    let archive = dir.path().join("backup.tar.zst");
+    backup::create(&pool, &data, &archive, "b2", BackupOptions::default(), None)
+        .await
+        .unwrap();
+
+    // Changes after the backup
+    sqlx::query("INSERT INTO files (name) VALUES ('later')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+    std::fs::write(data.join("Documents/report.txt"), b"edited").unwrap();
+    std::fs::write(data.join("Documents/new.txt"), b"new").unwrap();
+
+    // An archive whose content does not match its manifest changes nothing
+    let forged = dir.path().join("forged.tar.zst");
+    let mut writer = ArchiveWriter::new(std::fs::File::create(&forged).unwrap()).unwrap();
+    writer
+        .append_bytes("data/Documents/report.txt", b"forged")
+        .unwrap();
+    let manifest = Manifest {
+        format: backup::FORMAT_VERSION,
+        backup_id: "forged".to_string(),
+        parent: None,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        entries: vec![ManifestEntry {
+            path: "data/Documents/report.txt".to_string(),
+            kind: EntryKind::File,
+            size: 6,
+            sha256: hex::encode(Sha256::digest(b"honest")),
+            mtime: 0,
+            stored_in: None,
+            stored_as: None,
+        }],
+    };
+    writer
+        .append_bytes("manifest.json", &serde_json::to_vec(&manifest).unwrap())
+        .unwrap();
+    writer.finish().unwrap();
+    assert!(backup::verify(&forged).await.is_err());
+    assert!(
+        backup::restore(&forged, &data, RestoreOptions::full())
+            .await
+            .is_err()
+    );
+    assert_eq!(
+        std::fs::read(data.join("Documents/report.txt")).unwrap(),
+        b"edited"
+    );
+    assert_eq!(staging_dirs(&data), 0);
+
+    // Selected files below another directory, leaving everything else alone
+    let options = RestoreOptions {
+        files: true,
+        paths: Some(vec!["Documents".to_string()]),
+        into: Some("restored".to_string()),
+        ..Default::default()
+    };
+    let report = backup::restore(&archive, &data, options).await.unwrap();
+    assert_eq!(report.files_restored, 1);
+    assert_eq!(report.database, DatabaseRestore::Skipped);
+    assert_eq!(
+        std::fs::read(data.join("restored/Documents/report.txt")).unwrap(),
+        b"quarterly numbers"
+    );
+    assert!(!data.join("restored/Photos").exists());
+    assert!(
+        backup::restore(
+            &archive,
+            &data,
+            RestoreOptions {
+                into: Some("../outside".to_string()),
+                ..RestoreOptions::full()
+            }
+        )
+        .await
+        .is_err()
+    );
+
+    // The database in use is staged and swapped in before the next start
+    let report = backup::restore(&archive, &data, RestoreOptions::full())
+        .await
+        .unwrap();
+    assert_eq!(report.database, DatabaseRestore::Staged);
+    assert_eq!(
+        std::fs::read(data.join("Documents/report.txt")).unwrap(),
+        b"quarterly numbers"
+    );
+    // Files created after the backup are kept
+    assert!(data.join("Documents/new.txt").exists());
+    assert_eq!(file_names(&data).await, ["file-0", "file-1", "later"]);
+
+    assert!(backup::apply_staged_database(&data).unwrap());
+    assert!(!backup::apply_staged_database(&data).unwrap());
+    assert_eq!(file_names(&data).await, ["file-0", "file-1"]);
+    assert!(data.join("syncspace.db.pre-restore").exists());
+}
+
+#[tokio::test]
+async fn test_incremental_chain_restore_and_damage() {
+    let dir = TempDir::new().unwrap();
+    let data = dir.path().join("data");
+    let pool = seed(&data, 1).await;
+    let archives = dir.path().join("chain");
+    let archive = |id: &str| archives.join(format!("{}.tar.zst", id));
+
+    let full = backup::create(
+        &pool,
+        &data,
+        &archive("c1"),
+        "c1",
+        BackupOptions::default(),
+        None,
+    )
+    .await
+    .unwrap();
+
+    // Edited, added, renamed and deleted files, and a new database row
+    std::fs::write(
+        data.join("Documents/report.txt"),
+        b"quarterly numbers, revised",
+    )
+    .unwrap();
+    std::fs::write(data.join("Documents/new.txt"), b"new").unwrap();
+    std::fs::create_dir(data.join("Photos/2026")).unwrap();
+    std::fs::rename(
+        data.join("Photos/holiday.raw"),
+        data.join("Photos/2026/holiday.raw"),
+    )
+    .unwrap();
+    std::fs::remove_file(data.join(".blocks/ab/abcdef")).unwrap();
+    sqlx::query("INSERT INTO files (name) VALUES ('second')")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let second = backup::create(
+        &pool,
+        &data,
+        &archive("c2"),
+        "c2",
+        BackupOptions::default(),
+        Some(&full.manifest),
+    )
+    .await
+    .unwrap();
+    let manifest = &second.manifest;
+    assert_eq!(manifest.parent.as_deref(), Some("c1"));
+    let stored: Vec<&str> = manifest
+        .entries
+        .iter()
+        .filter(|entry| entry.is_stored())
+        .map(|entry| entry.path.as_str())
+        .collect();
+    assert_eq!(
+        stored,
+        [
+            "syncspace.db",
+            "data/Documents/new.txt",
+            "data/Documents/report.txt"
+        ]
+    );
+    let entry = |manifest: &Manifest, path: &str| {
+        manifest
+            .entries
+            .iter()
+            .find(|entry| entry.path == path)
+            .cloned()
+            .unwrap()
+    };
+    let moved = entry(manifest, "data/Photos/2026/holiday.raw");
+    assert_eq!(moved.stored_in.as_deref(), Some("c1"));
+    assert_eq!(moved.stored_as.as_deref(), Some("data/Photos/holiday.raw"));
+    assert!(manifest.stored_size() * 4 < full.manifest.stored_size());
+
+    let changes: Vec<(&str, Change)> = backup::changes(Some(&full.manifest), manifest)
+        .into_iter()
+        .filter(|(entry, _)| entry.kind != EntryKind::Database)
+        .map(|(entry, change)| (entry.path.as_str(), change))
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            ("data/Documents/new.txt", Change::Added),
+            ("data/Documents/report.txt", Change::Modified),
+            ("data/Photos/2026/holiday.raw", Change::Added),
+            ("data/versions/file-1/1", Change::Unchanged),
+            ("data/.blocks/ab/abcdef", Change::Deleted),
+            ("data/Photos/holiday.raw", Change::Deleted),
+        ]
+    );
+
+    // A third link refers straight to the backup holding the content
+    std::fs::write(data.join("Documents/third.txt"), b"third").unwrap();
+    let third = backup::create(
+        &pool,
+        &data,
+        &archive("c3"),
+        "c3",
+        BackupOptions::default(),
+        Some(manifest),
+    )
+    .await
+    .unwrap();
+    pool.close().await;
+    let report = entry(&third.manifest, "data/Documents/report.txt");
+    assert_eq!(report.stored_in.as_deref(), Some("c2"));
+    let moved = entry(&third.manifest, "data/Photos/2026/holiday.raw");
+    assert_eq!(moved.stored_in.as_deref(), Some("c1"));
+    assert_eq!(
+        backup::read_manifest_of(&archive("c3")).await.unwrap(),
+        third.manifest
+    );
+
+    let links = backup::verify(&archive("c3")).await.unwrap();
+    let ids: Vec<&str> = links
+        .iter()
+        .map(|link| link.manifest.backup_id.as_str())
+        .collect();
+    assert_eq!(ids, ["c3", "c2", "c1"]);
+    assert_eq!(links[2].sha256, full.sha256);
+
+    // The middle link restores the state at its time into an empty directory
+    let restored = dir.path().join("restored");
+    let report = backup::restore(&archive("c2"), &restored, RestoreOptions::full())
+        .await
+        .unwrap();
+    assert_eq!(report.backup_id, "c2");
+    assert_eq!(report.database, DatabaseRestore::Restored);
+    assert_eq!(report.files_restored, 4);
+    assert_eq!(
+        std::fs::read(restored.join("Documents/report.txt")).unwrap(),
+        b"quarterly numbers, revised"
+    );
+    assert_eq!(
+        std::fs::read(restored.join("Photos/2026/holiday.raw")).unwrap(),
+        std::fs::read(data.join("Photos/2026/holiday.raw")).unwrap()
+    );
+    assert_eq!(
+        std::fs::read(restored.join("versions/file-1/1")).unwrap(),
+        b"first draft"
+    );
+    assert!(!restored.join("Photos/holiday.raw").exists());
+    assert!(!restored.join(".blocks").exists());
+    assert!(!restored.join("Documents/third.txt").exists());
+    assert_eq!(file_names(&restored).await, ["file-0", "second"]);
+
+    // Damage to the full backup breaks every link relying on it
+    let mut bytes = std::fs::read(archive("c1")).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    std::fs::write(archive("c1"), &bytes).unwrap();
+    assert!(backup::verify(&archive("c3")).await.is_err());
+    let damaged = dir.path().join("damaged");
+    assert!(
+        backup::restore(&archive("c2"), &damaged, RestoreOptions::full())
+            .await
+            .is_err()
+    );
+    assert_eq!(std::fs::read_dir(&damaged).unwrap().count(), 0);
+
+    std::fs::remove_file(archive("c1")).unwrap();
+    let error = backup::verify(&archive("c2")).await.unwrap_err();
+    assert!(format!("{:#}", error).contains("c1.tar.zst"));
+}
+
+#[tokio::test]
+async fn test_backup_files_belong_to_backups() {
+    // Foreign keys enforced, unlike the server
+    let options = SqliteConnectOptions::new()
+        .in_memory(true)
+        .foreign_keys(true);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+        .unwrap();
+    common::run_migration(
+        &pool,
+        "CREATE TABLE users (id TEXT PRIMARY KEY);
+         INSERT INTO users (id) VALUES ('admin');",
+    )
+    .await;
+    for sql in [
+        include_str!("../migrations/003_add_backups.sql"),
+        include_str!("../migrations/044_backup_system.sql"),
+    ] {
+        common::run_migration(&pool, sql).await;
+    }
+    for sql in [
+        "INSERT INTO backups (id, backup_type, size_bytes, storage_path, created_by, created_at)
+         VALUES ('b1', 'full', 0, 'b1.tar.zst', 'admin', datetime('now'))",
+        "INSERT INTO backup_jobs (id, job_type, created_by) VALUES ('j1', 'full', 'admin')",
+        // Written before migration 069, with foreign keys off
+        "PRAGMA foreign_keys = OFF",
+        "INSERT INTO backup_files (id, backup_job_id, file_path, file_size)
+         VALUES ('f1', 'b1', 'a.txt', 1), ('f2', 'j1', 'b.txt', 2)",
+        "PRAGMA foreign_keys = ON",
+    ] {
+        sqlx::query(sql).execute(&pool).await.unwrap();
+    }
+
+    common::run_migration(
+        &pool,
+        include_str!("../migrations/069_backup_file_entries.sql"),
+    )
+    .await;
+
+    let rows: Vec<(String, Option<String>, Option<String>)> =
+        sqlx::query_as("SELECT id, backup_id, backup_job_id FROM backup_files ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            ("f1".to_string(), Some("b1".to_string()), None),
+            ("f2".to_string(), None, Some("j1".to_string())),
+        ]
+    );
+
+    // Entries of a backup need the backup and go with it
+    sqlx::query(
+        "INSERT INTO backup_files (id, backup_id, file_path, file_size)
+         VALUES ('f3', 'b1', 'c.txt', 3)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    assert!(
+        sqlx::query(
+            "INSERT INTO backup_files (id, backup_id, file_path, file_size)
+             VALUES ('f4', 'missing', 'd.txt', 4)",
+        )
+        .execute(&pool)
+        .await
+        .is_err()
+    );
+    sqlx::query("DELETE FROM backups WHERE id = 'b1'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let left: Vec<String> = sqlx::query_scalar("SELECT id FROM backup_files")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(left, vec!["f2".to_string()]);
+}
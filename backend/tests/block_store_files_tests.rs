@@ -0,0 +1,134 @@
+//! Block store tests for live files
+//!
+//! Uploaded and copied files share blocks, replacing or purging a file
+//! releases them, and a missing working copy is restored from the store.
+//! The blocks live in ./data, so this binary holds a single test.
+
+mod common;
+
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use syncbackend::block_store;
+use tempfile::TempDir;
+
+async fn setup() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::query(
+        "CREATE TABLE files (id TEXT PRIMARY KEY, path TEXT NOT NULL, checksum_sha256 TEXT,
+             is_deleted BOOLEAN NOT NULL DEFAULT 0)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    common::run_migration(&pool, include_str!("../migrations/052_block_store.sql")).await;
+    common::run_migration(&pool, include_str!("../migrations/070_file_block_refs.sql")).await;
+    pool
+}
+
+/// Deterministic content that chunks into several blocks
+fn content(size: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// Write a working copy and register it with the checksum the server records
+async fn write_file(pool: &SqlitePool, id: &str, path: &str, data: &[u8]) -> String {
+    let local = Path::new("./data").join(path);
+    tokio::fs::create_dir_all(local.parent().unwrap())
+        .await
+        .unwrap();
+    tokio::fs::write(&local, data).await.unwrap();
+    let checksum = format!("{:x}", Sha256::digest(data));
+    sqlx::query(
+        "INSERT INTO files (id, path, checksum_sha256) VALUES (?, ?, ?)
+         ON CONFLICT(id) DO UPDATE SET checksum_sha256 = excluded.checksum_sha256",
+    )
+    .bind(id)
+    .bind(path)
+    .bind(&checksum)
+    .execute(pool)
+    .await
+    .unwrap();
+    checksum
+}
+
+async fn ref_count(pool: &SqlitePool, manifest_id: &str) -> Option<i64> {
+    sqlx::query_scalar("SELECT ref_count FROM block_manifests WHERE id = ?")
+        .bind(manifest_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_live_files_share_blocks() {
+    let dir = TempDir::new().unwrap();
+    std::env::set_current_dir(dir.path()).unwrap();
+    let pool = setup().await;
+    let data = content(3 * 1024 * 1024, 0x2545_f491);
+
+    // An upload is stored, a copy of it only takes another reference
+    let checksum = write_file(&pool, "f1", "docs/a.bin", &data).await;
+    let upload =
+        block_store::store_live_file(&pool, "f1", Path::new("./data/docs/a.bin"), &checksum)
+            .await
+            .unwrap();
+    assert_eq!(upload.new_bytes, data.len() as i64);
+    write_file(&pool, "f2", "docs/copy.bin", &data).await;
+    let copy =
+        block_store::store_live_file(&pool, "f2", Path::new("./data/docs/copy.bin"), &checksum)
+            .await
+            .unwrap();
+    assert_eq!((copy.id.as_str(), copy.new_bytes), (upload.id.as_str(), 0));
+    assert_eq!(ref_count(&pool, &upload.id).await, Some(2));
+
+    let stats = block_store::stats(&pool).await.unwrap();
+    assert_eq!(stats.logical_bytes, 2 * data.len() as i64);
+    assert_eq!(stats.physical_bytes, data.len() as i64);
+    assert_eq!(stats.savings_bytes, data.len() as i64);
+
+    // A missing working copy comes back from the store
+    tokio::fs::remove_file("./data/docs/a.bin").await.unwrap();
+    let restored = Path::new("./data/docs/a.bin");
+    assert!(
+        block_store::restore_live_file(&pool, "docs/a.bin", restored)
+            .await
+            .unwrap()
+    );
+    assert!(tokio::fs::read(restored).await.unwrap() == data);
+    assert!(
+        !block_store::restore_live_file(&pool, "docs/none.bin", restored)
+            .await
+            .unwrap()
+    );
+
+    // Replacing the upload moves its reference to the new content, and
+    // purging the copy releases the rest
+    let mut edited = data.clone();
+    edited.extend_from_slice(b"appended");
+    let checksum = write_file(&pool, "f1", "docs/a.bin", &edited).await;
+    let replaced =
+        block_store::store_live_file(&pool, "f1", Path::new("./data/docs/a.bin"), &checksum)
+            .await
+            .unwrap();
+    assert!(replaced.new_bytes < edited.len() as i64);
+    assert_eq!(ref_count(&pool, &upload.id).await, Some(1));
+    block_store::release_live_file(&pool, "f2").await.unwrap();
+    assert_eq!(ref_count(&pool, &upload.id).await, None);
+    assert_eq!(ref_count(&pool, &replaced.id).await, Some(1));
+    block_store::release_live_file(&pool, "f2").await.unwrap();
+}
@@ -0,0 +1,80 @@
+//! Block store tests
+//!
+//! Stores content in the block store and reads it back as a stream, including
+//! the errors a damaged store produces while streaming.
+
+mod common;
+
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use syncbackend::block_store;
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+
+async fn setup() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    common::run_migration(&pool, include_str!("../migrations/052_block_store.sql")).await;
+    pool
+}
+
+/// Deterministic content that chunks into several blocks
+fn content(size: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_u32;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_manifest_streams_back_and_detects_damage() {
+    let dir = TempDir::new().unwrap();
+    std::env::set_current_dir(dir.path()).unwrap();
+    let pool = setup().await;
+
+    let data = content(6 * 1024 * 1024);
+    let manifest = block_store::store_reader(&pool, data.as_slice())
+        .await
+        .unwrap();
+    assert_eq!(manifest.total_size, data.len() as i64);
+
+    let mut reader = block_store::open_manifest(&pool, &manifest.id)
+        .await
+        .unwrap();
+    let mut read = Vec::new();
+    reader.read_to_end(&mut read).await.unwrap();
+    assert!(read == data);
+
+    assert!(block_store::open_manifest(&pool, "missing").await.is_err());
+
+    // A block that changed on disk fails the stream when it is reached
+    let hashes: Vec<String> = sqlx::query_scalar(
+        "SELECT block_hash FROM block_manifest_entries WHERE manifest_id = ? ORDER BY seq",
+    )
+    .bind(&manifest.id)
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert!(hashes.len() > 1);
+    let last = hashes.last().unwrap();
+    let path = std::path::Path::new(block_store::BLOCK_STORE_DIR)
+        .join(&last[..2])
+        .join(last);
+    tokio::fs::write(&path, b"damaged").await.unwrap();
+
+    let mut reader = block_store::open_manifest(&pool, &manifest.id)
+        .await
+        .unwrap();
+    let mut read = Vec::new();
+    let error = reader.read_to_end(&mut read).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(read.len() < data.len());
+}
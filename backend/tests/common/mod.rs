@@ -0,0 +1,70 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+
+/// Split a migration into statements the way the server runs it: `--`
+/// comments are dropped and every `;` ends a statement
+pub fn migration_statements(sql: &str) -> Vec<String> {
+    let cleaned: Vec<&str> = sql
+        .lines()
+        .map(|line| line.split("--").next().unwrap_or_default())
+        .collect();
+    cleaned
+        .join("\n")
+        .split(';')
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Run every statement of a migration
+pub async fn run_migration(pool: &SqlitePool, sql: &str) {
+    run_migration_skipping(pool, sql, &[]).await;
+}
+
+/// Run a migration without the statements that mention any of `tables`,
+/// for migrations that copy rows from legacy tables the test does not have
+pub async fn run_migration_skipping(pool: &SqlitePool, sql: &str, tables: &[&str]) {
+    for statement in migration_statements(sql) {
+        if tables.iter().any(|table| statement.contains(table)) {
+            continue;
+        }
+        if let Err(e) = sqlx::query(&statement).execute(pool).await {
+            panic!("migration statement failed: {}\n{}", e, statement);
+        }
+    }
+}
+
+/// An in-memory database with the tables `search::access` reads to decide who
+/// may see a path: owners, shares, per-user and group permissions
+pub async fn access_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    for statement in [
+        "CREATE TABLE files (id TEXT PRIMARY KEY, path TEXT NOT NULL, owner_id TEXT NOT NULL,
+             is_deleted BOOLEAN NOT NULL DEFAULT 0)",
+        "CREATE TABLE folders (id TEXT PRIMARY KEY, path TEXT NOT NULL, owner_id TEXT NOT NULL,
+             is_deleted BOOLEAN NOT NULL DEFAULT 0)",
+        "CREATE TABLE shared_links (id TEXT PRIMARY KEY, item_id TEXT NOT NULL,
+             created_by TEXT NOT NULL, expires_at TEXT)",
+        "CREATE TABLE share_users (id TEXT PRIMARY KEY, share_id TEXT NOT NULL,
+             user_id TEXT NOT NULL)",
+        "CREATE TABLE file_permissions (id TEXT PRIMARY KEY, item_type TEXT NOT NULL,
+             item_id TEXT NOT NULL, user_id TEXT NOT NULL, can_read BOOLEAN NOT NULL DEFAULT 1,
+             expires_at TEXT)",
+        "CREATE TABLE group_permissions (id TEXT PRIMARY KEY, group_id TEXT NOT NULL,
+             resource_type TEXT NOT NULL, resource_id TEXT NOT NULL, expires_at TEXT)",
+        "CREATE TABLE user_group_members (id TEXT PRIMARY KEY, group_id TEXT NOT NULL,
+             user_id TEXT NOT NULL)",
+    ] {
+        sqlx::query(statement).execute(&pool).await.unwrap();
+    }
+    pool
+}
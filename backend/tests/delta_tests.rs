@@ -0,0 +1,201 @@
+//! Delta encoding integration tests
+//!
+//! Encode modified content against the signature of a base file, apply the
+//! delta to the base and compare the result with the modified content.
+
+use sha2::{Digest, Sha256};
+use syncbackend::delta::{self, DeltaError};
+use tempfile::TempDir;
+
+/// Deterministic pseudo-random content so misplaced blocks are detected
+fn test_content(len: usize, seed: u32) -> Vec<u8> {
+    let mut state: u32 = 0x9e37_79b9 ^ seed;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// Round trip `modified` against `base`; returns the rebuilt file's stats
+async fn round_trip(base: &[u8], modified: &[u8], block_size: u32) -> delta::DeltaStats {
+    let dir = TempDir::new().unwrap();
+    let base_path = dir.path().join("base.bin");
+    tokio::fs::write(&base_path, base).await.unwrap();
+
+    let signature = delta::signature(&base_path, block_size).await.unwrap();
+    assert_eq!(signature.file_size, base.len() as u64);
+    assert_eq!(signature.checksum, format!("{:x}", Sha256::digest(base)));
+
+    let encoded = delta::encode(&signature, modified).await;
+    let mut output = Vec::new();
+    let rebuilt = delta::apply(&base_path, encoded.as_slice(), &mut output, u64::MAX)
+        .await
+        .unwrap();
+
+    assert!(output == modified, "rebuilt content differs");
+    assert_eq!(rebuilt.size, modified.len() as u64);
+    assert_eq!(rebuilt.checksum, format!("{:x}", Sha256::digest(modified)));
+    assert_eq!(rebuilt.copied_bytes + rebuilt.literal_bytes, rebuilt.size);
+    rebuilt
+}
+
+#[tokio::test]
+async fn test_edit_in_the_middle_sends_only_the_change() {
+    let base = test_content(1024 * 1024, 1);
+    let mut modified = base.clone();
+    modified.splice(500_000..500_100, test_content(300, 2));
+
+    let rebuilt = round_trip(&base, &modified, 4096).await;
+    assert!(
+        rebuilt.literal_bytes < 3 * 4096,
+        "sent {} literal bytes",
+        rebuilt.literal_bytes
+    );
+}
+
+#[tokio::test]
+async fn test_shifted_content_is_found_at_any_offset() {
+    let base = test_content(256 * 1024, 3);
+    let mut modified = b"prepended header".to_vec();
+    modified.extend_from_slice(&base);
+
+    let rebuilt = round_trip(&base, &modified, 2048).await;
+    assert_eq!(rebuilt.literal_bytes, 16);
+}
+
+#[tokio::test]
+async fn test_short_last_block_is_reused() {
+    let base = test_content(10_000, 4);
+    let mut modified = test_content(100, 5);
+    modified.extend_from_slice(&base);
+
+    let rebuilt = round_trip(&base, &modified, 1024).await;
+    assert_eq!(rebuilt.copied_bytes, base.len() as u64);
+}
+
+#[tokio::test]
+async fn test_edge_cases_round_trip() {
+    let base = test_content(50_000, 6);
+    // Truncated, appended, reordered, unrelated and empty content
+    round_trip(&base, &base[..20_000], 1024).await;
+    round_trip(
+        &base,
+        &[base.as_slice(), &test_content(5000, 7)].concat(),
+        1024,
+    )
+    .await;
+    round_trip(&base, &[&base[25_000..], &base[..25_000]].concat(), 1024).await;
+    round_trip(&base, &test_content(30_000, 8), 1024).await;
+    round_trip(&base, &[], 1024).await;
+    round_trip(&[], &base, 1024).await;
+}
+
+#[tokio::test]
+async fn test_apply_enforces_size_limit() {
+    let dir = TempDir::new().unwrap();
+    let base_path = dir.path().join("base.bin");
+    tokio::fs::write(&base_path, test_content(8192, 9))
+        .await
+        .unwrap();
+    let signature = delta::signature(&base_path, 1024).await.unwrap();
+
+    // Eight copies of the whole file expand to 64 KiB
+    let mut expanded = test_content(8192, 9);
+    for _ in 0..3 {
+        expanded.extend_from_within(..);
+    }
+    let encoded = delta::encode(&signature, &expanded).await;
+    assert!(encoded.len() < 1024);
+
+    let result = delta::apply(
+        &base_path,
+        encoded.as_slice(),
+        &mut tokio::io::sink(),
+        32 * 1024,
+    )
+    .await;
+    assert!(matches!(result, Err(DeltaError::TooLarge(_))));
+}
+
+#[tokio::test]
+async fn test_apply_rejects_malformed_deltas() {
+    let dir = TempDir::new().unwrap();
+    let base_path = dir.path().join("base.bin");
+    tokio::fs::write(&base_path, test_content(4000, 10))
+        .await
+        .unwrap();
+
+    let header = [delta::MAGIC.as_slice(), &1024u32.to_be_bytes()].concat();
+    let copy = |first: u64, count: u32| {
+        [
+            &[0x01][..],
+            &first.to_be_bytes(),
+            &count.to_be_bytes(),
+            &[0x00],
+        ]
+        .concat()
+    };
+    let cases: Vec<Vec<u8>> = vec![
+        b"XXXX".to_vec(),
+        [delta::MAGIC.as_slice(), &16u32.to_be_bytes(), &[0x00]].concat(),
+        [header.as_slice(), &copy(4, 1)].concat(),
+        [header.as_slice(), &copy(3, 2)].concat(),
+        [header.as_slice(), &copy(u64::MAX, 1)].concat(),
+        [header.as_slice(), &[0x02], &100u32.to_be_bytes(), b"short"].concat(),
+        [header.as_slice(), &[0x07]].concat(),
+        [header.as_slice(), &[0x00, 0x00]].concat(),
+        header.clone(),
+    ];
+
+    for (i, case) in cases.iter().enumerate() {
+        let result = delta::apply(
+            &base_path,
+            case.as_slice(),
+            &mut tokio::io::sink(),
+            u64::MAX,
+        )
+        .await;
+        assert!(
+            matches!(result, Err(DeltaError::Invalid(_))),
+            "case {} was accepted: {:?}",
+            i,
+            result
+        );
+    }
+
+    // The last block may be referenced even though the range ends past EOF
+    let valid = [header.as_slice(), &copy(0, 4)].concat();
+    let rebuilt = delta::apply(
+        &base_path,
+        valid.as_slice(),
+        &mut tokio::io::sink(),
+        u64::MAX,
+    )
+    .await
+    .unwrap();
+    assert_eq!(rebuilt.size, 4000);
+}
+
+#[tokio::test]
+async fn test_signature_builder_accepts_any_chunking() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("content.bin");
+    let content = test_content(20_000, 11);
+    tokio::fs::write(&path, &content).await.unwrap();
+    let expected = delta::signature(&path, 1024).await.unwrap();
+
+    for chunk_size in [1, 100, 1024, 5000, 20_000] {
+        let mut builder = delta::SignatureBuilder::new(1024).unwrap();
+        for chunk in content.chunks(chunk_size) {
+            builder.update(chunk);
+        }
+        let built = builder.finish();
+        assert_eq!(built.blocks, expected.blocks, "chunks of {}", chunk_size);
+        assert_eq!(built.checksum, expected.checksum);
+        assert_eq!(built.file_size, expected.file_size);
+    }
+}
@@ -0,0 +1,76 @@
+//! File encryption tests
+//!
+//! Encrypting a file deletes its plaintext versions and search index entry.
+//! The database, versions and index live in ./data, so this binary holds a
+//! single test.
+
+use std::path::Path;
+
+use syncbackend::encryption::{SecretKey, encrypt_file};
+use syncbackend::search::SearchIndex;
+use syncbackend::services::version_storage_service;
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn test_encrypting_forgets_the_plaintext() {
+    let dir = TempDir::new().unwrap();
+    std::env::set_current_dir(dir.path()).unwrap();
+    let pool = syncbackend::database::init_db().await.unwrap();
+    let search_index = SearchIndex::new().unwrap();
+    tokio::fs::create_dir_all("./data/docs").await.unwrap();
+    let live = Path::new("./data/docs/plan.txt");
+
+    // Two versions and an index entry of the plaintext
+    tokio::fs::write(live, "draft plan").await.unwrap();
+    version_storage_service::create_version(&pool, "f1", live, "alice", None)
+        .await
+        .unwrap();
+    tokio::fs::write(live, "the final plan").await.unwrap();
+    let version = version_storage_service::create_version(&pool, "f1", live, "alice", None)
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO files
+             (id, name, path, owner_id, size_bytes, storage_path, created_at, updated_at)
+         VALUES ('f1', 'plan.txt', 'docs/plan.txt', 'alice', 14, 'docs/plan.txt', '', '')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    search_index
+        .index_file(
+            "f1",
+            "plan.txt",
+            "docs/plan.txt",
+            Some("the final plan".to_string()),
+            chrono::Utc::now(),
+            14,
+            &[],
+        )
+        .await
+        .unwrap();
+    search_index.force_commit().await.unwrap();
+
+    let key_encryption_key = SecretKey::generate();
+    encrypt_file(
+        &pool,
+        &search_index,
+        "f1",
+        "k1",
+        "alice",
+        &key_encryption_key,
+    )
+    .await
+    .unwrap();
+
+    let (versions,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM file_versions WHERE file_id = 'f1'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(versions, 0);
+    assert!(!Path::new(&version.storage_path).exists());
+    search_index.force_commit().await.unwrap();
+    assert_eq!(search_index.stats().unwrap().num_documents, 0);
+    assert_ne!(tokio::fs::read(live).await.unwrap(), b"the final plan");
+}
@@ -0,0 +1,249 @@
+//! Outgoing mail tests
+//!
+//! Queues messages in an in-memory outbox and delivers them to a directory
+//! of `.eml` files or to a scripted SMTP server on localhost.
+
+mod common;
+
+use lettre::message::Mailbox;
+use serde_json::json;
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use syncbackend::mail::{self, Kind, Mailer, Security, SmtpSettings, Transport};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+async fn setup() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::query("CREATE TABLE users (id TEXT PRIMARY KEY, username TEXT NOT NULL, email TEXT)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    common::run_migration(&pool, include_str!("../migrations/062_email_outbox.sql")).await;
+    sqlx::query(
+        "INSERT INTO users (id, username, email) VALUES
+             ('u1', 'ann', 'ann@example.com'), ('u2', 'bob', NULL)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    pool
+}
+
+fn sender() -> Mailbox {
+    "SyncSpace <noreply@files.example.com>".parse().unwrap()
+}
+
+async fn status_of(pool: &SqlitePool, id: &str) -> (String, i64, Option<String>) {
+    sqlx::query_as("SELECT status, attempts, last_error FROM email_logs WHERE id = ?")
+        .bind(id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+/// SMTP server on localhost that answers RCPT TO with `rcpt_reply`
+async fn smtp_server(rcpt_reply: &'static str) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                write.write_all(b"220 test ESMTP\r\n").await.unwrap();
+                let mut in_data = false;
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply = if in_data {
+                        if line != "." {
+                            continue;
+                        }
+                        in_data = false;
+                        "250 2.0.0 queued as 42"
+                    } else {
+                        let command = line.to_ascii_uppercase();
+                        if command.starts_with("EHLO") {
+                            "250 test"
+                        } else if command.starts_with("RCPT") {
+                            rcpt_reply
+                        } else if command.starts_with("DATA") {
+                            in_data = true;
+                            "354 go ahead"
+                        } else if command.starts_with("QUIT") {
+                            write.write_all(b"221 bye\r\n").await.ok();
+                            break;
+                        } else {
+                            "250 ok"
+                        }
+                    };
+                    if write
+                        .write_all(format!("{}\r\n", reply).as_bytes())
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    port
+}
+
+fn smtp_mailer(pool: &SqlitePool, port: u16) -> Mailer {
+    let settings = SmtpSettings {
+        host: "127.0.0.1".to_string(),
+        port: Some(port),
+        security: Security::None,
+        username: None,
+        password: None,
+    };
+    Mailer::new(pool.clone(), Transport::smtp(&settings).unwrap(), sender())
+}
+
+#[tokio::test]
+async fn test_outbox_directory_transport() {
+    let pool = setup().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mailer = Mailer::new(
+        pool.clone(),
+        Transport::Outbox(dir.path().to_path_buf()),
+        sender(),
+    );
+
+    let id = mail::queue_for_user(
+        &pool,
+        "u1",
+        Kind::ShareInvite,
+        &json!({
+            "sharer": "Carol",
+            "file_name": "Budget <2025>.xlsx",
+            "permission": "write",
+            "link": "https://files.example.com/#/shared",
+        }),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    // Users without an address get no mail
+    assert_eq!(
+        mail::queue_for_user(&pool, "u2", Kind::QuotaAlert, &json!({}))
+            .await
+            .unwrap(),
+        None
+    );
+
+    assert_eq!(mailer.deliver_due().await.unwrap(), 1);
+    let (status, attempts, error) = status_of(&pool, &id).await;
+    assert_eq!((status.as_str(), attempts, error), ("sent", 1, None));
+    // Sent messages are not delivered twice
+    assert_eq!(mailer.deliver(&id).await.unwrap(), None);
+
+    let eml = std::fs::read_to_string(dir.path().join(format!("{}.eml", id))).unwrap();
+    assert!(eml.contains("To: ann@example.com"));
+    assert!(eml.contains("From: SyncSpace <noreply@files.example.com>"));
+    assert!(eml.contains(&format!("Message-ID: <{}@files.example.com>", id)));
+    assert!(eml.contains("multipart/alternative"));
+    assert!(eml.contains("Content-Type: text/plain"));
+    assert!(eml.contains("Content-Type: text/html"));
+    assert!(eml.contains("Budget &lt;2025&gt;.xlsx"));
+
+    let (user_id, template, sent_at): (Option<String>, String, Option<String>) =
+        sqlx::query_as("SELECT user_id, template, sent_at FROM email_logs WHERE id = ?")
+            .bind(&id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(user_id.as_deref(), Some("u1"));
+    assert_eq!(template, "share_invite");
+    assert!(sent_at.is_some());
+}
+
+#[tokio::test]
+async fn test_smtp_delivery() {
+    let pool = setup().await;
+    let mailer = smtp_mailer(&pool, smtp_server("250 2.1.5 ok").await);
+
+    let id = mail::queue(
+        &pool,
+        "dave@example.com",
+        None,
+        Kind::Notification,
+        &json!({"subject": "Report ready", "body": "See /Reports/q3.pdf"}),
+    )
+    .await
+    .unwrap();
+    assert_eq!(mailer.deliver(&id).await.unwrap(), Some("sent"));
+
+    let response: Option<String> =
+        sqlx::query_scalar("SELECT smtp_response FROM email_logs WHERE id = ?")
+            .bind(&id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert!(response.unwrap().contains("queued as 42"));
+}
+
+#[tokio::test]
+async fn test_smtp_bounce_and_retry() {
+    let pool = setup().await;
+
+    // Permanent rejections bounce without retries
+    let rejecting = smtp_mailer(&pool, smtp_server("550 5.1.1 no such user").await);
+    let bounced = mail::queue(
+        &pool,
+        "nobody@example.com",
+        None,
+        Kind::Notification,
+        &json!({}),
+    )
+    .await
+    .unwrap();
+    assert_eq!(rejecting.deliver(&bounced).await.unwrap(), Some("bounced"));
+    let (_, attempts, error) = status_of(&pool, &bounced).await;
+    assert_eq!(attempts, 1);
+    assert!(error.unwrap().contains("no such user"));
+
+    // Temporary failures are retried later
+    let busy = smtp_mailer(&pool, smtp_server("451 4.3.0 try again later").await);
+    let deferred = mail::queue(
+        &pool,
+        "eve@example.com",
+        None,
+        Kind::Notification,
+        &json!({}),
+    )
+    .await
+    .unwrap();
+    assert_eq!(busy.deliver(&deferred).await.unwrap(), Some("retrying"));
+    assert_eq!(busy.deliver(&deferred).await.unwrap(), None, "not due yet");
+
+    // ... until the attempts run out
+    sqlx::query(
+        "UPDATE email_logs SET attempts = ?, next_attempt_at = datetime('now') WHERE id = ?",
+    )
+    .bind(mail::MAX_ATTEMPTS - 1)
+    .bind(&deferred)
+    .execute(&pool)
+    .await
+    .unwrap();
+    assert_eq!(busy.deliver_due().await.unwrap(), 1);
+    let (status, attempts, _) = status_of(&pool, &deferred).await;
+    assert_eq!((status.as_str(), attempts), ("failed", mail::MAX_ATTEMPTS));
+
+    // Unparseable addresses fail at once
+    let invalid = mail::queue(
+        &pool,
+        "not an address",
+        None,
+        Kind::Notification,
+        &json!({}),
+    )
+    .await
+    .unwrap();
+    assert_eq!(busy.deliver(&invalid).await.unwrap(), Some("failed"));
+}
@@ -0,0 +1,36 @@
+//! Migration tests
+//!
+//! Runs every migration on a fresh database, the way the server does on its
+//! first start. The database lives in ./data, so this binary holds a single
+//! test.
+
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn test_fresh_database_runs_every_migration() {
+    let dir = TempDir::new().unwrap();
+    std::env::set_current_dir(dir.path()).unwrap();
+
+    let pool = syncbackend::database::init_db().await.unwrap();
+
+    // Tables rebuilt by 058 and 069 are in place under their own names
+    for table in ["workflow_executions", "backup_files"] {
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+                .bind(table)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(count, 1, "{} is missing", table);
+    }
+    let (leftovers,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM sqlite_master WHERE name LIKE '%\\_new' ESCAPE '\\'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(leftovers, 0);
+
+    // Starting again skips what already ran
+    pool.close().await;
+    syncbackend::database::init_db().await.unwrap();
+}
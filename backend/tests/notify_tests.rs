@@ -0,0 +1,301 @@
+//! Notification dispatch tests
+//!
+//! Dispatches notifications against an in-memory database and checks the
+//! inbox, the broadcast channel, the mail outbox, queued webhook jobs and
+//! the digest queue.
+
+mod common;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde_json::json;
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use syncbackend::notify::preferences::{self, EventPreference, Settings};
+use syncbackend::notify::{Dispatcher, EventType, Notification};
+use syncbackend::websocket::FileChangeEvent;
+use tokio::sync::broadcast;
+
+async fn setup() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    common::run_migration(
+        &pool,
+        "CREATE TABLE users (id TEXT PRIMARY KEY, username TEXT NOT NULL, email TEXT);
+         CREATE TABLE user_preferences (
+             user_id TEXT PRIMARY KEY,
+             notification_email BOOLEAN NOT NULL DEFAULT 1,
+             notification_browser BOOLEAN NOT NULL DEFAULT 1
+         );
+         CREATE TABLE notifications (
+             id TEXT PRIMARY KEY, user_id TEXT NOT NULL, type TEXT NOT NULL,
+             title TEXT NOT NULL, message TEXT NOT NULL, action_url TEXT,
+             is_read BOOLEAN NOT NULL DEFAULT 0, priority TEXT NOT NULL DEFAULT 'normal',
+             related_user_id TEXT, created_at TEXT NOT NULL
+         );
+         CREATE TABLE webhooks (
+             id TEXT PRIMARY KEY, user_id TEXT NOT NULL, events TEXT NOT NULL,
+             is_active INTEGER NOT NULL DEFAULT 1
+         );
+         INSERT INTO users (id, username, email) VALUES
+             ('u1', 'ann', 'ann@example.com'), ('u2', 'bob', 'bob@example.com');",
+    )
+    .await;
+    // The jobs migration copies rows from a legacy table that does not exist here
+    common::run_migration_skipping(
+        &pool,
+        include_str!("../migrations/028_update_jobs_system.sql"),
+        &["background_jobs"],
+    )
+    .await;
+    common::run_migration(&pool, include_str!("../migrations/062_email_outbox.sql")).await;
+    common::run_migration(
+        &pool,
+        include_str!("../migrations/063_notification_dispatch.sql"),
+    )
+    .await;
+    pool
+}
+
+fn at(value: &str) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").unwrap())
+}
+
+fn share(user_id: &str) -> Notification {
+    Notification::new(
+        user_id,
+        EventType::ShareReceived,
+        "bob shared Plan.pdf with you",
+        "bob shared \"Plan.pdf\" with you (read access)",
+    )
+    .with_action_url("/#/shared")
+    .from_user("u2")
+    .with_context(json!({"sharer": "bob", "file_name": "Plan.pdf", "permission": "read"}))
+}
+
+fn mention(user_id: &str, n: usize) -> Notification {
+    Notification::new(
+        user_id,
+        EventType::CommentMention,
+        &format!("bob mentioned you on draft-{}.md", n),
+        "@ann can you check this?",
+    )
+}
+
+async fn count(pool: &SqlitePool, sql: &str) -> i64 {
+    sqlx::query_scalar(sql).fetch_one(pool).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_dispatch_follows_preferences() {
+    let pool = setup().await;
+    let (events, mut sockets) = broadcast::channel(16);
+    let dispatcher = Dispatcher::new(pool.clone(), Some(events));
+    sqlx::query(
+        "INSERT INTO webhooks (id, user_id, events) VALUES
+             ('w1', 'u1', '[\"notification.share_received\"]'),
+             ('w2', 'u1', '[\"file.uploaded\"]'),
+             ('w3', 'u2', '[\"notification.share_received\"]')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    // Every channel is on by default
+    let now = at("2026-03-02 12:00");
+    let delivery = dispatcher.dispatch_at(&share("u1"), now).await.unwrap();
+    let id = delivery.in_app.clone().unwrap();
+    assert!(delivery.websocket);
+    assert_eq!(delivery.email_due, None);
+    assert_eq!(delivery.webhooks, 1);
+
+    let (kind, action_url, related): (String, Option<String>, Option<String>) =
+        sqlx::query_as("SELECT type, action_url, related_user_id FROM notifications WHERE id = ?")
+            .bind(&id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(kind, "share_received");
+    assert_eq!(action_url.as_deref(), Some("/#/shared"));
+    assert_eq!(related.as_deref(), Some("u2"));
+
+    match sockets.recv().await.unwrap() {
+        FileChangeEvent::Notification {
+            user_id,
+            id: event_id,
+            notification_type,
+            ..
+        } => {
+            assert_eq!(user_id, "u1");
+            assert_eq!(event_id, id);
+            assert_eq!(notification_type, "share_received");
+        }
+        other => panic!("unexpected event {:?}", other),
+    }
+
+    let (template, subject): (String, String) =
+        sqlx::query_as("SELECT template, subject FROM email_logs WHERE id = ?")
+            .bind(delivery.email.as_deref().unwrap())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(template, "share_invite");
+    assert_eq!(subject, "bob shared \"Plan.pdf\" with you");
+
+    // Webhook deliveries have no owner, so their failures notify no one
+    let (job_type, payload, created_by): (String, String, Option<String>) =
+        sqlx::query_as("SELECT job_type, payload, created_by FROM jobs")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(job_type, "webhook_delivery");
+    assert!(payload.contains("\"webhook_id\":\"w1\""));
+    assert!(payload.contains("notification.share_received"));
+    assert_eq!(created_by, None);
+
+    // Turning channels off for the event type
+    let quiet = EventPreference {
+        event_type: "share_received".to_string(),
+        in_app: false,
+        websocket: false,
+        email: false,
+        webhook: true,
+        email_digest: "off".to_string(),
+    };
+    preferences::save_event_preference(&pool, "u1", &quiet)
+        .await
+        .unwrap();
+    let delivery = dispatcher.dispatch_at(&share("u1"), now).await.unwrap();
+    assert_eq!(delivery.in_app, None);
+    assert!(!delivery.websocket);
+    assert_eq!(delivery.email, None);
+    assert_eq!(delivery.webhooks, 1);
+
+    // Without a row for the event type the general switches apply
+    sqlx::query(
+        "INSERT INTO user_preferences (user_id, notification_email, notification_browser)
+         VALUES ('u1', 0, 1)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let delivery = dispatcher
+        .dispatch_at(&mention("u1", 1), now)
+        .await
+        .unwrap();
+    assert!(delivery.in_app.is_some());
+    assert!(delivery.websocket);
+    assert_eq!((delivery.email, delivery.email_due), (None, None));
+    assert_eq!(count(&pool, "SELECT COUNT(*) FROM email_logs").await, 1);
+}
+
+#[tokio::test]
+async fn test_quiet_hours_and_digests() {
+    let pool = setup().await;
+    let (events, _sockets) = broadcast::channel(16);
+    let dispatcher = Dispatcher::new(pool.clone(), Some(events));
+    let night = Settings {
+        quiet_hours_start: Some("22:00".to_string()),
+        quiet_hours_end: Some("07:00".to_string()),
+        ..Settings::default()
+    };
+    preferences::save_settings(&pool, "u1", &night)
+        .await
+        .unwrap();
+
+    // During quiet hours mail waits for the morning and sockets stay silent,
+    // but the inbox is kept up to date
+    let delivery = dispatcher
+        .dispatch_at(&share("u1"), at("2026-03-01 23:00"))
+        .await
+        .unwrap();
+    assert!(delivery.in_app.is_some());
+    assert!(!delivery.websocket);
+    assert_eq!(delivery.email, None);
+    assert_eq!(delivery.email_due, Some(at("2026-03-02 07:00")));
+
+    // A lone held-back item keeps its own template
+    assert_eq!(
+        dispatcher
+            .flush_digests_at(at("2026-03-02 06:59"))
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        dispatcher
+            .flush_digests_at(at("2026-03-02 07:00"))
+            .await
+            .unwrap(),
+        1
+    );
+    let template: String = sqlx::query_scalar("SELECT template FROM email_logs")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(template, "share_invite");
+
+    // Hourly digests batch mentions into one message
+    let hourly = EventPreference {
+        event_type: "comment_mention".to_string(),
+        in_app: true,
+        websocket: true,
+        email: true,
+        webhook: true,
+        email_digest: "hourly".to_string(),
+    };
+    preferences::save_event_preference(&pool, "u1", &hourly)
+        .await
+        .unwrap();
+    for n in 1..=3 {
+        let delivery = dispatcher
+            .dispatch_at(&mention("u1", n), at("2026-03-02 10:05"))
+            .await
+            .unwrap();
+        assert_eq!(delivery.email_due, Some(at("2026-03-02 11:00")));
+    }
+    // Other users are not affected
+    let delivery = dispatcher
+        .dispatch_at(&mention("u2", 4), at("2026-03-02 10:05"))
+        .await
+        .unwrap();
+    assert!(delivery.email.is_some());
+
+    assert_eq!(
+        dispatcher
+            .flush_digests_at(at("2026-03-02 10:30"))
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        dispatcher
+            .flush_digests_at(at("2026-03-02 11:00"))
+            .await
+            .unwrap(),
+        1
+    );
+    let (subject, text): (String, String) = sqlx::query_as(
+        "SELECT subject, text_body FROM email_logs WHERE template = 'digest' AND user_id = 'u1'",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(subject, "3 new notifications on SyncSpace");
+    assert!(text.contains("- bob mentioned you on draft-1.md: @ann can you check this?"));
+    assert!(text.contains("draft-3.md"));
+    assert_eq!(
+        count(&pool, "SELECT COUNT(*) FROM notification_digest_items").await,
+        0
+    );
+    assert_eq!(
+        count(
+            &pool,
+            "SELECT COUNT(*) FROM notifications WHERE user_id = 'u1'"
+        )
+        .await,
+        4
+    );
+}
@@ -0,0 +1,470 @@
+//! RBAC enforcement tests
+//!
+//! Runs every system role from the RBAC migration against representative
+//! endpoints of each route policy and checks who gets through, then the
+//! restrictions of personal access tokens on top.
+
+mod common;
+
+use axum::{
+    Router,
+    body::{Body, to_bytes},
+    extract::Request,
+    http::StatusCode,
+    middleware::{self, Next},
+    response::Response,
+    routing::{any, get, post},
+};
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use syncbackend::auth::{User, UserInfo};
+use syncbackend::rbac::{self, Guard, Policy, TokenGrant};
+use tower::ServiceExt;
+
+async fn setup() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    for statement in [
+        "CREATE TABLE users (id TEXT PRIMARY KEY, username TEXT NOT NULL,
+             is_admin INTEGER NOT NULL DEFAULT 0, created_at TEXT NOT NULL)",
+        "CREATE TABLE audit_logs (id TEXT PRIMARY KEY, user_id TEXT NOT NULL, username TEXT,
+             action TEXT NOT NULL, action_category TEXT, resource_type TEXT NOT NULL,
+             resource_id TEXT NOT NULL, request_method TEXT, request_path TEXT,
+             response_status INTEGER, metadata TEXT, severity TEXT NOT NULL,
+             is_compliance_relevant BOOLEAN DEFAULT 0, created_at TEXT NOT NULL)",
+    ] {
+        sqlx::query(statement).execute(&pool).await.unwrap();
+    }
+
+    // The real roles, created before any user exists
+    common::run_migration(&pool, include_str!("../migrations/039_rbac_system.sql")).await;
+
+    for (id, role, is_admin) in [
+        ("super", Some("role-super-admin"), 0),
+        ("admin", Some("role-admin"), 0),
+        ("moderator", Some("role-moderator"), 0),
+        ("user", Some("role-user"), 0),
+        ("viewer", Some("role-viewer"), 0),
+        ("guest", Some("role-guest"), 0),
+        ("newcomer", None, 0),
+        ("flagged", Some("role-guest"), 1),
+    ] {
+        sqlx::query("INSERT INTO users (id, username, is_admin, created_at) VALUES (?, ?, ?, datetime('now'))")
+            .bind(id)
+            .bind(id)
+            .bind(is_admin)
+            .execute(&pool)
+            .await
+            .unwrap();
+        if let Some(role) = role {
+            sqlx::query("INSERT INTO user_roles (user_id, role_id, granted_at) VALUES (?, ?, datetime('now'))")
+                .bind(id)
+                .bind(role)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+    }
+    // An administrator whose role has run out is a standard user again
+    sqlx::query(
+        "INSERT INTO users (id, username, created_at) VALUES ('lapsed', 'lapsed', datetime('now'))",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO user_roles (user_id, role_id, granted_at, expires_at)
+         VALUES ('lapsed', 'role-admin', datetime('now'), '2020-01-01T00:00:00+00:00')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    pool
+}
+
+/// Stand-in for the auth middleware: the user named in `x-user`, with a
+/// token of the scopes and path prefixes in `x-token` (`scopes;prefixes`)
+async fn sign_in(mut req: Request, next: Next) -> Response {
+    if let Some(token) = req.headers().get("x-token").and_then(|v| v.to_str().ok()) {
+        let (scopes, prefixes) = token.split_once(';').unwrap_or((token, ""));
+        let grant = TokenGrant::new("t1", scopes, Some(prefixes), None);
+        req.extensions_mut().insert(grant);
+    }
+    if let Some(id) = req.headers().get("x-user").and_then(|v| v.to_str().ok()) {
+        let id = id.to_string();
+        req.extensions_mut().insert(User(UserInfo {
+            id: id.clone(),
+            username: id,
+            totp_enabled: false,
+            role: None,
+            is_admin: false,
+        }));
+    }
+    next.run(req).await
+}
+
+fn app(pool: &SqlitePool) -> Router {
+    let guarded = |router: Router, policy: &'static Policy| {
+        router.route_layer(middleware::from_fn_with_state(
+            Guard::new(pool.clone(), policy),
+            rbac::enforce,
+        ))
+    };
+    let ok = || async { "ok" };
+    let api = Router::new()
+        .merge(guarded(
+            Router::new()
+                .route("/file/{*path}", get(ok))
+                .route("/upload/{*path}", post(ok))
+                .route("/files/{*path}", get(ok).delete(ok)),
+            &rbac::FILES,
+        ))
+        .merge(guarded(
+            Router::new()
+                .route("/webdav", any(ok))
+                .route("/webdav/{*path}", any(ok)),
+            &rbac::WEBDAV,
+        ))
+        .merge(guarded(
+            Router::new()
+                .route("/shares", post(ok))
+                .route("/shared-with-me", get(ok)),
+            &rbac::SHARES,
+        ))
+        .merge(guarded(
+            Router::new().route("/admin/users", get(ok)),
+            &rbac::USERS,
+        ))
+        .merge(guarded(
+            Router::new()
+                .route("/roles", post(ok))
+                .route("/users/{user_id}/roles", post(ok)),
+            &rbac::ROLES,
+        ))
+        .merge(guarded(
+            Router::new()
+                .route("/backups", get(ok))
+                .route("/backups/{backup_id}/restore", post(ok)),
+            &rbac::BACKUPS,
+        ))
+        .merge(guarded(
+            Router::new().route("/audit/logs", get(ok)),
+            &rbac::AUDIT,
+        ))
+        .merge(guarded(
+            Router::new().route("/performance/metrics", get(ok)),
+            &rbac::SETTINGS,
+        ))
+        .merge(guarded(
+            Router::new().route("/ldap/configs", get(ok)),
+            &rbac::SYSTEM,
+        ))
+        .merge(guarded(
+            Router::new().route("/groups", get(ok)),
+            &rbac::GROUPS,
+        ))
+        .merge(guarded(
+            Router::new().route("/notifications", get(ok)),
+            &rbac::NOTIFICATIONS,
+        ))
+        .merge(guarded(
+            Router::new().route("/api-tokens", post(ok)),
+            &rbac::ACCOUNT,
+        ))
+        .layer(middleware::from_fn(sign_in));
+    Router::new().nest("/api", api)
+}
+
+async fn call(app: &Router, user: Option<&str>, method: &str, uri: &str) -> Response {
+    call_with_token(app, user, None, method, uri).await
+}
+
+async fn call_with_token(
+    app: &Router,
+    user: Option<&str>,
+    token: Option<&str>,
+    method: &str,
+    uri: &str,
+) -> Response {
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(user) = user {
+        request = request.header("x-user", user);
+    }
+    if let Some(token) = token {
+        request = request.header("x-token", token);
+    }
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+const ENDPOINTS: &[(&str, &str)] = &[
+    ("GET", "/api/files/docs"),
+    ("GET", "/api/file/docs/a.txt"),
+    ("POST", "/api/upload/docs/a.txt"),
+    ("DELETE", "/api/files/docs/a.txt"),
+    ("PROPFIND", "/api/webdav"),
+    ("PROPFIND", "/api/webdav/docs"),
+    ("GET", "/api/webdav/docs/a.txt"),
+    ("PUT", "/api/webdav/docs/a.txt"),
+    ("MKCOL", "/api/webdav/docs/new"),
+    ("MOVE", "/api/webdav/docs/a.txt"),
+    ("DELETE", "/api/webdav/docs/a.txt"),
+    ("POST", "/api/shares"),
+    ("GET", "/api/shared-with-me"),
+    ("GET", "/api/admin/users"),
+    ("POST", "/api/roles"),
+    ("POST", "/api/users/someone/roles"),
+    ("GET", "/api/backups"),
+    ("POST", "/api/backups/b1/restore"),
+    ("GET", "/api/audit/logs"),
+    ("GET", "/api/performance/metrics"),
+    ("GET", "/api/ldap/configs"),
+    ("GET", "/api/groups"),
+];
+
+/// One column per endpoint above: `+` allowed, `-` forbidden
+const MATRIX: &[(&str, &str)] = &[
+    ("super", "++++++++++++++++++++++"),
+    ("admin", "++++++++++++++-++-++-+"),
+    ("moderator", "++++++++++++++----+--+"),
+    ("user", "+++++++++++++--------+"),
+    ("viewer", "++--+++-----+--------+"),
+    ("guest", "+---+++-----+--------+"),
+    ("newcomer", "+++++++++++++--------+"),
+    ("lapsed", "+++++++++++++--------+"),
+    ("flagged", "++++++++++++++++++++++"),
+];
+
+#[tokio::test]
+async fn test_role_endpoint_matrix() {
+    let pool = setup().await;
+    let app = app(&pool);
+
+    let mut mismatches = Vec::new();
+    for (user, row) in MATRIX {
+        for ((method, uri), expected) in ENDPOINTS.iter().zip(row.chars()) {
+            let status = call(&app, Some(user), method, uri).await.status();
+            let expected = if expected == '+' {
+                StatusCode::OK
+            } else {
+                StatusCode::FORBIDDEN
+            };
+            if status != expected {
+                mismatches.push(format!(
+                    "{} {} {}: {} (expected {})",
+                    user, method, uri, status, expected
+                ));
+            }
+        }
+    }
+    assert!(mismatches.is_empty(), "{:#?}", mismatches);
+}
+
+#[tokio::test]
+async fn test_denials_are_audited() {
+    let pool = setup().await;
+    let app = app(&pool);
+
+    let response = call(&app, Some("guest"), "GET", "/api/file/docs/a.txt").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["permission"], "file.download");
+
+    let (method, path, metadata): (String, String, String) = sqlx::query_as(
+        "SELECT request_method, request_path, metadata FROM audit_logs
+         WHERE user_id = 'guest' AND action = 'permission_denied'",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(method, "GET");
+    assert_eq!(path, "/api/file/docs/a.txt");
+    assert!(metadata.contains("file.download"));
+
+    // Allowed requests and routes without a permission leave no trace
+    call(&app, Some("guest"), "GET", "/api/files/docs").await;
+    call(&app, Some("guest"), "GET", "/api/groups").await;
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM audit_logs")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 1);
+}
+
+#[tokio::test]
+async fn test_missing_user_is_unauthorized() {
+    let pool = setup().await;
+    let app = app(&pool);
+
+    let response = call(&app, None, "GET", "/api/files/docs").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    // Unknown routes stay 404 rather than being refused
+    let response = call(&app, Some("guest"), "GET", "/api/nowhere").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_effective_permissions() {
+    let pool = setup().await;
+
+    let newcomer = rbac::permissions(&pool, "newcomer").await.unwrap();
+    let user = rbac::permissions(&pool, "user").await.unwrap();
+    assert_eq!(newcomer, user);
+    assert!(!user.contains("system.admin"));
+
+    let flagged = rbac::permissions(&pool, "flagged").await.unwrap();
+    assert!(flagged.contains("system.admin"));
+    assert!(rbac::has_permission(&pool, "flagged", "backup.restore").await);
+    assert!(rbac::has_permission(&pool, "super", "role.assign").await);
+    assert!(!rbac::has_permission(&pool, "admin", "role.create").await);
+}
+
+#[tokio::test]
+async fn test_token_scopes() {
+    let pool = setup().await;
+    let app = app(&pool);
+    let status =
+        |token: &'static str, user: &'static str, method: &'static str, uri: &'static str| {
+            let app = app.clone();
+            async move {
+                call_with_token(&app, Some(user), Some(token), method, uri)
+                    .await
+                    .status()
+            }
+        };
+
+    // A read-only token reads but cannot change anything
+    assert_eq!(
+        status("files:read", "user", "GET", "/api/files/docs").await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        status("files:read", "user", "DELETE", "/api/files/docs/a.txt").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        status("files:read", "user", "POST", "/api/shares").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        status("shares:manage", "user", "POST", "/api/shares").await,
+        StatusCode::OK
+    );
+    // Routes without a permission need their policy's scope, or admin
+    assert_eq!(
+        status("files:read", "user", "GET", "/api/groups").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        status("notifications", "user", "GET", "/api/notifications").await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        status("files:write", "user", "POST", "/api/api-tokens").await,
+        StatusCode::FORBIDDEN
+    );
+    // Legacy scope names still work
+    assert_eq!(
+        status("read,upload", "user", "POST", "/api/upload/a.txt").await,
+        StatusCode::OK
+    );
+    // The admin scope covers everything the user may do, but no more
+    assert_eq!(
+        status("admin", "admin", "GET", "/api/backups").await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        status("admin", "admin", "POST", "/api/api-tokens").await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        status("admin", "viewer", "POST", "/api/upload/a.txt").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        status("files:read", "admin", "GET", "/api/backups").await,
+        StatusCode::FORBIDDEN
+    );
+
+    let response = call_with_token(
+        &app,
+        Some("user"),
+        Some("files:read"),
+        "DELETE",
+        "/api/files/a",
+    )
+    .await;
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["scope"], "files:write");
+    let metadata: String = sqlx::query_scalar(
+        "SELECT metadata FROM audit_logs WHERE user_id = 'user' AND request_path = '/api/files/a'",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert!(metadata.contains("scope files:write"));
+}
+
+#[tokio::test]
+async fn test_token_path_prefixes() {
+    let pool = setup().await;
+    let app = app(&pool);
+    let token = "files:read,files:write,shares:manage;builds,releases/app";
+    let status = |method: &'static str, uri: &'static str| {
+        let app = app.clone();
+        async move {
+            call_with_token(&app, Some("user"), Some(token), method, uri)
+                .await
+                .status()
+        }
+    };
+
+    assert_eq!(status("GET", "/api/files/builds").await, StatusCode::OK);
+    assert_eq!(
+        status("POST", "/api/upload/builds/42/log.txt").await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        status("GET", "/api/file/releases/app/1.0.zip").await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        status("GET", "/api/file/releases/other.zip").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        status("DELETE", "/api/files/builds-old").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        status("GET", "/api/file/builds/../secrets.txt").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        status("GET", "/api/file/builds/%2e%2e/secrets.txt").await,
+        StatusCode::FORBIDDEN
+    );
+    // File routes that do not name a path cannot be limited, so they are refused
+    assert_eq!(
+        status("GET", "/api/shared-with-me").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(status("POST", "/api/shares").await, StatusCode::FORBIDDEN);
+    // Other scopes are not about files
+    let response = call_with_token(
+        &app,
+        Some("user"),
+        Some("admin;builds"),
+        "GET",
+        "/api/groups",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
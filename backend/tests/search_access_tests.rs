@@ -0,0 +1,90 @@
+//! Search access control tests
+//!
+//! Checks which principals are stored with indexed documents for owners,
+//! shares, per-user permissions and group permissions.
+
+mod common;
+
+use sqlx::SqlitePool;
+use syncbackend::search::Viewer;
+use syncbackend::search::access::{grants, group, user};
+
+async fn setup() -> SqlitePool {
+    let pool = common::access_pool().await;
+    for statement in [
+        // alice owns Projects and Other; bob uploaded a file into Projects
+        "INSERT INTO folders (id, path, owner_id) VALUES
+             ('projects', '/Projects', 'alice'), ('sub', '/Projects/sub', 'alice'),
+             ('other', 'Other', 'alice')",
+        "INSERT INTO files (id, path, owner_id) VALUES
+             ('plan', 'Projects/plan.md', 'alice'), ('deep', 'Projects/sub/deep.txt', 'bob'),
+             ('notes', 'Other/notes.txt', 'alice'), ('old', 'Other/old.txt', 'alice')",
+        // Projects is shared with carol, Other with dave until last year
+        "INSERT INTO shared_links (id, item_id, created_by, expires_at) VALUES
+             ('share-1', 'Projects', 'alice', NULL),
+             ('share-2', '/Other', 'alice', '2020-01-01T00:00:00+00:00')",
+        "INSERT INTO share_users (id, share_id, user_id) VALUES
+             ('su-1', 'share-1', 'carol'), ('su-2', 'share-2', 'dave')",
+        "INSERT INTO file_permissions (id, item_type, item_id, user_id, can_read) VALUES
+             ('fp-1', 'file', 'notes', 'erin', 1), ('fp-2', 'file', 'notes', 'frank', 0)",
+        "INSERT INTO group_permissions (id, group_id, resource_type, resource_id) VALUES
+             ('gp-1', 'design', 'folder', 'sub')",
+        "INSERT INTO user_group_members (id, group_id, user_id) VALUES
+             ('m-1', 'design', 'erin'), ('m-2', 'ops', 'erin')",
+    ] {
+        sqlx::query(statement).execute(&pool).await.unwrap();
+    }
+    pool
+}
+
+#[tokio::test]
+async fn test_grants_cover_owners_shares_and_permissions() {
+    let pool = setup().await;
+
+    assert_eq!(
+        grants(&pool, "Projects/plan.md").await.unwrap(),
+        vec![user("alice"), user("carol")]
+    );
+    // Folder owners, shares and group grants apply to everything below
+    assert_eq!(
+        grants(&pool, "/Projects/sub/deep.txt").await.unwrap(),
+        vec![group("design"), user("alice"), user("bob"), user("carol")]
+    );
+    assert_eq!(
+        grants(&pool, "Projects/sub").await.unwrap(),
+        vec![group("design"), user("alice"), user("carol")]
+    );
+    // Expired shares and permissions without read access grant nothing
+    assert_eq!(
+        grants(&pool, "Other/notes.txt").await.unwrap(),
+        vec![user("alice"), user("erin")]
+    );
+    assert_eq!(
+        grants(&pool, "Other/old.txt").await.unwrap(),
+        vec![user("alice")]
+    );
+    assert!(
+        grants(&pool, "Projects-old/plan.md")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    assert!(grants(&pool, "").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_viewer_includes_groups() {
+    let pool = setup().await;
+
+    let erin = Viewer::load(&pool, "erin").await.unwrap();
+    assert_eq!(erin.principals.len(), 3);
+    assert!(erin.principals.contains(&group("ops")));
+
+    let deep = grants(&pool, "Projects/sub/deep.txt").await.unwrap();
+    assert!(erin.can_read(&deep));
+    assert!(!erin.can_read(&grants(&pool, "Projects/plan.md").await.unwrap()));
+
+    let dave = Viewer::load(&pool, "dave").await.unwrap();
+    assert_eq!(dave.principals, vec![user("dave")]);
+    assert!(!dave.can_read(&grants(&pool, "Other/old.txt").await.unwrap()));
+}
@@ -0,0 +1,142 @@
+//! Storage backend integration tests
+//!
+//! The local backend is always tested against a temporary directory. The
+//! S3 round trip runs against a real S3-compatible service (e.g. MinIO) when
+//! `SYNCSPACE_TEST_S3_ENDPOINT`, `SYNCSPACE_TEST_S3_BUCKET`,
+//! `SYNCSPACE_TEST_S3_ACCESS_KEY` and `SYNCSPACE_TEST_S3_SECRET_KEY` are set:
+//!
+//! ```bash
+//! docker run -p 9000:9000 minio/minio server /data
+//! SYNCSPACE_TEST_S3_ENDPOINT=http://127.0.0.1:9000 SYNCSPACE_TEST_S3_BUCKET=syncspace-test \
+//! SYNCSPACE_TEST_S3_ACCESS_KEY=minioadmin SYNCSPACE_TEST_S3_SECRET_KEY=minioadmin \
+//!     cargo test --test storage_backend_tests
+//! ```
+
+use bytes::Bytes;
+use syncbackend::storage::{LocalBackend, S3Backend, StorageBackend, probe};
+use tempfile::TempDir;
+
+/// Deterministic pseudo-random content so corrupted parts are detected
+fn test_content(len: usize) -> Vec<u8> {
+    let mut state: u32 = 0x9e37_79b9;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// Put, head, get and delete through any backend
+async fn round_trip(backend: &dyn StorageBackend, dir: &TempDir, size: usize) {
+    let content = test_content(size);
+    let source = dir.path().join(format!("source-{}.bin", size));
+    tokio::fs::write(&source, &content).await.unwrap();
+
+    let key = format!("tests/{}/object-{}.bin", uuid::Uuid::new_v4(), size);
+    let stored = backend.put_file(&key, &source).await.unwrap();
+    assert_eq!(stored, size as u64);
+
+    let meta = backend
+        .head(&key)
+        .await
+        .unwrap()
+        .expect("object should exist");
+    assert_eq!(meta.size, size as u64);
+
+    let dest = dir
+        .path()
+        .join("restored")
+        .join(format!("object-{}.bin", size));
+    let fetched = backend.get_to_file(&key, &dest).await.unwrap();
+    assert_eq!(fetched, size as u64);
+    assert_eq!(tokio::fs::read(&dest).await.unwrap(), content);
+
+    backend.delete(&key).await.unwrap();
+    assert!(backend.head(&key).await.unwrap().is_none());
+    // Deleting twice is not an error
+    backend.delete(&key).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_local_backend_round_trip() {
+    let root = TempDir::new().unwrap();
+    let work = TempDir::new().unwrap();
+    let backend = LocalBackend::new("test-local", root.path());
+    assert!(!backend.is_working_tree());
+
+    round_trip(&backend, &work, 0).await;
+    round_trip(&backend, &work, 300 * 1024).await;
+
+    backend
+        .put_bytes("small/hello.txt", Bytes::from_static(b"hello"))
+        .await
+        .unwrap();
+    assert_eq!(
+        backend.get_bytes("small/hello.txt").await.unwrap(),
+        Bytes::from_static(b"hello")
+    );
+}
+
+#[tokio::test]
+async fn test_local_backend_rejects_unsafe_keys() {
+    let root = TempDir::new().unwrap();
+    let backend = LocalBackend::new("test-local", root.path());
+
+    for key in ["../escape.txt", "/etc/passwd", "a//b", ""] {
+        assert!(
+            backend
+                .put_bytes(key, Bytes::from_static(b"x"))
+                .await
+                .is_err(),
+            "key {:?} should be rejected",
+            key
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_probe_reports_all_checks() {
+    let root = TempDir::new().unwrap();
+    let backend = LocalBackend::new("test-local", root.path());
+
+    let results = probe(&backend).await;
+    let checks: Vec<_> = results.iter().map(|r| r.check_type).collect();
+    assert_eq!(checks, vec!["write", "read", "delete"]);
+    assert!(results.iter().all(|r| r.passed));
+}
+
+fn s3_test_config() -> Option<serde_json::Value> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    Some(serde_json::json!({
+        "endpoint": var("SYNCSPACE_TEST_S3_ENDPOINT")?,
+        "bucket": var("SYNCSPACE_TEST_S3_BUCKET")?,
+        "access_key_id": var("SYNCSPACE_TEST_S3_ACCESS_KEY")?,
+        "secret_access_key": var("SYNCSPACE_TEST_S3_SECRET_KEY")?,
+        "region": var("SYNCSPACE_TEST_S3_REGION").unwrap_or_else(|| "us-east-1".to_string()),
+        "prefix": "syncspace-tests",
+    }))
+}
+
+#[tokio::test]
+async fn test_s3_backend_round_trip() {
+    let Some(config) = s3_test_config() else {
+        eprintln!("Skipping S3 round trip: SYNCSPACE_TEST_S3_* is not configured");
+        return;
+    };
+    let backend = S3Backend::from_config("test-s3", "minio", &config).unwrap();
+    let work = TempDir::new().unwrap();
+
+    // Single PUT and multipart upload (above the 16 MiB threshold)
+    round_trip(&backend, &work, 1024).await;
+    round_trip(&backend, &work, 20 * 1024 * 1024 + 17).await;
+
+    let results = probe(&backend).await;
+    assert!(results.iter().all(|r| r.passed), "{:?}", results);
+
+    let missing = format!("tests/{}/missing", uuid::Uuid::new_v4());
+    assert!(backend.head(&missing).await.unwrap().is_none());
+    assert!(backend.get_bytes(&missing).await.is_err());
+}
@@ -0,0 +1,81 @@
+//! Delta sync access tests
+//!
+//! Checks which change journal entries a user gets back: their own changes,
+//! changes to paths they can read (also by their former path) and all of
+//! them for admins.
+
+mod common;
+
+use sqlx::SqlitePool;
+use syncbackend::auth::UserInfo;
+use syncbackend::services::change_journal::{self, Change};
+
+async fn setup() -> SqlitePool {
+    let pool = common::access_pool().await;
+    common::run_migration(&pool, include_str!("../migrations/055_change_journal.sql")).await;
+    for statement in [
+        // alice owns Projects and Private; Projects is shared with bob
+        "INSERT INTO folders (id, path, owner_id) VALUES
+             ('projects', '/Projects', 'alice'), ('private', '/Private', 'alice')",
+        "INSERT INTO shared_links (id, item_id, created_by) VALUES ('share-1', 'Projects', 'alice')",
+        "INSERT INTO share_users (id, share_id, user_id) VALUES ('su-1', 'share-1', 'bob')",
+    ] {
+        sqlx::query(statement).execute(&pool).await.unwrap();
+    }
+    pool
+}
+
+fn user(id: &str, is_admin: bool) -> UserInfo {
+    UserInfo {
+        id: id.to_string(),
+        username: id.to_string(),
+        totp_enabled: false,
+        role: None,
+        is_admin,
+    }
+}
+
+async fn visible_paths(pool: &SqlitePool, user: &UserInfo) -> Vec<String> {
+    let page = change_journal::changes_since(pool, 0, None, 100)
+        .await
+        .unwrap()
+        .unwrap();
+    change_journal::visible_to(pool, user, page.changes)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|change| change.path)
+        .collect()
+}
+
+#[tokio::test]
+async fn test_changes_are_limited_to_readable_paths() {
+    let pool = setup().await;
+    for change in [
+        Change::new("create", "Private/salary.xlsx").by_user("alice"),
+        Change::new("create", "Projects/plan.md").by_user("alice"),
+        Change::new("rename", "Private/plan-old.md")
+            .with_old_path("Projects/plan.md")
+            .by_user("alice"),
+        Change::new("delete", "Private/diary.txt").by_user("alice"),
+        Change::new("create", "Projects/notes.txt").by_user("bob"),
+        Change::new("create", "Private/drop.txt").by_user("bob"),
+    ] {
+        change_journal::record(&pool, change).await;
+    }
+
+    // bob sees the shared folder, what left it and what he did himself, but
+    // none of alice's private changes
+    assert_eq!(
+        visible_paths(&pool, &user("bob", false)).await,
+        [
+            "Projects/plan.md",
+            "Private/plan-old.md",
+            "Projects/notes.txt",
+            "Private/drop.txt"
+        ]
+    );
+    assert_eq!(visible_paths(&pool, &user("alice", false)).await.len(), 6);
+    assert!(visible_paths(&pool, &user("carol", false)).await.is_empty());
+    assert_eq!(visible_paths(&pool, &user("root", true)).await.len(), 6);
+}
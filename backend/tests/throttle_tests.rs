@@ -0,0 +1,473 @@
+//! Rate limit and bandwidth middleware tests
+//!
+//! Runs the rate limiting migrations in memory and sends requests through
+//! the live limits: buckets and headers, rule precedence, per-address and
+//! per-token counters, bandwidth quotas and shaping.
+
+mod common;
+
+use axum::{
+    Router,
+    body::{Body, Bytes, to_bytes},
+    extract::{ConnectInfo, Request},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post},
+};
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use syncbackend::auth::{User, UserInfo};
+use syncbackend::rbac::TokenGrant;
+use syncbackend::throttle::{self, Throttle, bandwidth};
+use tower::ServiceExt;
+
+async fn setup() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::query(
+        "CREATE TABLE users (id TEXT PRIMARY KEY, username TEXT NOT NULL,
+             is_admin INTEGER NOT NULL DEFAULT 0, created_at TEXT NOT NULL)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    common::run_migration(&pool, include_str!("../migrations/039_rbac_system.sql")).await;
+    common::run_migration(
+        &pool,
+        include_str!("../migrations/047_rate_limiting_quotas.sql"),
+    )
+    .await;
+    common::run_migration(
+        &pool,
+        include_str!("../migrations/061_live_rate_limits.sql"),
+    )
+    .await;
+    for user in ["alice", "bob", "dave", "erin"] {
+        sqlx::query("INSERT INTO users (id, username, created_at) VALUES (?, ?, datetime('now'))")
+            .bind(user)
+            .bind(user)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    // Start without the default role limits
+    sqlx::query("DELETE FROM api_rate_limits")
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool
+}
+
+/// Add a rule allowing `burst` requests at once and one more per second
+async fn add_rule(
+    pool: &SqlitePool,
+    id: &str,
+    columns: &[(&str, &str)],
+    pattern: &str,
+    burst: i64,
+) {
+    let names: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+    let sql =
+        format!(
+        "INSERT INTO api_rate_limits (id, endpoint_pattern, requests_per_minute, requests_per_hour,
+             requests_per_day, burst_limit{}) VALUES (?, ?, 60, 0, 0, ?{})",
+        names.iter().map(|name| format!(", {}", name)).collect::<String>(),
+        ", ?".repeat(names.len())
+    );
+    let mut query = sqlx::query(&sql).bind(id).bind(pattern).bind(burst);
+    for (_, value) in columns {
+        query = query.bind(*value);
+    }
+    query.execute(pool).await.unwrap();
+}
+
+/// Stand-in for the auth middleware: the user named in `x-user` and the
+/// token named in `x-token`
+async fn sign_in(mut req: Request, next: Next) -> Response {
+    if let Some(token) = req.headers().get("x-token").and_then(|v| v.to_str().ok()) {
+        let grant = TokenGrant::new(token, "admin", None, None);
+        req.extensions_mut().insert(grant);
+    }
+    if let Some(id) = req.headers().get("x-user").and_then(|v| v.to_str().ok()) {
+        let id = id.to_string();
+        req.extensions_mut().insert(User(UserInfo {
+            id: id.clone(),
+            username: id,
+            totp_enabled: false,
+            role: None,
+            is_admin: false,
+        }));
+    }
+    next.run(req).await
+}
+
+fn app(pool: &SqlitePool) -> Router {
+    let throttle = Throttle::new(pool.clone());
+    let ok = || async { "ok" };
+    let transfers = Router::new()
+        .route("/download", get(|| async { "0123456789" }))
+        .route("/big", get(|| async { vec![b'x'; 300] }))
+        .route(
+            "/upload",
+            post(|body: Bytes| async move { body.len().to_string() }),
+        )
+        .layer(middleware::from_fn_with_state(
+            throttle.clone(),
+            bandwidth::meter,
+        ));
+    let api = Router::new()
+        .route("/files", get(ok))
+        .route("/search", get(ok))
+        .route("/search/suggest", get(ok))
+        .merge(transfers)
+        .layer(middleware::from_fn_with_state(throttle, throttle::limit))
+        .layer(middleware::from_fn(sign_in));
+    Router::new().nest("/api", api)
+}
+
+struct Call<'a> {
+    user: Option<&'a str>,
+    token: Option<&'a str>,
+    ip: Option<[u8; 4]>,
+    method: &'a str,
+    uri: &'a str,
+    body: &'a str,
+}
+
+impl Default for Call<'_> {
+    fn default() -> Self {
+        Self {
+            user: None,
+            token: None,
+            ip: None,
+            method: "GET",
+            uri: "/api/files",
+            body: "",
+        }
+    }
+}
+
+async fn send(app: &Router, call: Call<'_>) -> Response {
+    let mut request = Request::builder().method(call.method).uri(call.uri);
+    if let Some(user) = call.user {
+        request = request.header("x-user", user);
+    }
+    if let Some(token) = call.token {
+        request = request.header("x-token", token);
+    }
+    if let Some(ip) = call.ip {
+        request = request.extension(ConnectInfo(SocketAddr::from((ip, 40000))));
+    }
+    if !call.body.is_empty() {
+        request = request.header(header::CONTENT_LENGTH, call.body.len());
+    }
+    app.clone()
+        .oneshot(request.body(Body::from(call.body.to_string())).unwrap())
+        .await
+        .unwrap()
+}
+
+async fn get_as(app: &Router, user: &str, uri: &str) -> StatusCode {
+    let call = Call {
+        user: Some(user),
+        uri,
+        ..Call::default()
+    };
+    send(app, call).await.status()
+}
+
+fn header_of<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+    response.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+#[tokio::test]
+async fn test_bucket_headers_and_refusal() {
+    let pool = setup().await;
+    add_rule(&pool, "r1", &[("user_id", "alice")], "*", 2).await;
+    let app = app(&pool);
+    let alice = || Call {
+        user: Some("alice"),
+        ..Call::default()
+    };
+
+    for remaining in ["1", "0"] {
+        let response = send(&app, alice()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_of(&response, "ratelimit-limit"), Some("2"));
+        assert_eq!(header_of(&response, "ratelimit-remaining"), Some(remaining));
+        assert_eq!(
+            header_of(&response, "ratelimit-policy"),
+            Some("60;w=60;burst=2")
+        );
+    }
+    let response = send(&app, alice()).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header_of(&response, "retry-after"), Some("1"));
+    assert_eq!(header_of(&response, "ratelimit-remaining"), Some("0"));
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"], "rate limit exceeded");
+
+    // Repeated refusals are logged and alerted once
+    send(&app, alice()).await;
+    let logged: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM api_request_log WHERE user_id = 'alice' AND is_rate_limited = 1",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(logged, 1);
+    let alerts: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM quota_alerts WHERE user_id = 'alice' AND alert_type = 'rate_limit_exceeded'",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(alerts, 1);
+
+    // Users without a rule are not limited
+    let response = send(
+        &app,
+        Call {
+            user: Some("bob"),
+            ..Call::default()
+        },
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("ratelimit-limit").is_none());
+}
+
+#[tokio::test]
+async fn test_rule_precedence_and_route_groups() {
+    let pool = setup().await;
+    // Everyone has the default "user" role
+    add_rule(&pool, "role", &[("role_name", "user")], "*", 1).await;
+    add_rule(&pool, "alice", &[("user_id", "alice")], "*", 3).await;
+    add_rule(&pool, "dave", &[("user_id", "dave")], "*", 100).await;
+    add_rule(&pool, "search", &[], "/search*", 2).await;
+    let app = app(&pool);
+
+    assert_eq!(get_as(&app, "bob", "/api/files").await, StatusCode::OK);
+    assert_eq!(
+        get_as(&app, "bob", "/api/files").await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    // A rule for the user replaces the role's rule
+    for _ in 0..3 {
+        assert_eq!(get_as(&app, "alice", "/api/files").await, StatusCode::OK);
+    }
+    assert_eq!(
+        get_as(&app, "alice", "/api/files").await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    // The search routes share one bucket on top of the user's own
+    assert_eq!(get_as(&app, "dave", "/api/search").await, StatusCode::OK);
+    assert_eq!(
+        get_as(&app, "dave", "/api/search/suggest").await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        get_as(&app, "dave", "/api/search").await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(get_as(&app, "dave", "/api/files").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_limits_per_address_and_token() {
+    let pool = setup().await;
+    add_rule(
+        &pool,
+        "lan",
+        &[("ip_address", "10.0.0.0/8"), ("limit_by", "ip")],
+        "*",
+        1,
+    )
+    .await;
+    add_rule(
+        &pool,
+        "ci",
+        &[("user_id", "erin"), ("limit_by", "token")],
+        "*",
+        1,
+    )
+    .await;
+    add_rule(&pool, "t3", &[("token_id", "t3")], "*", 3).await;
+    let app = app(&pool);
+    let from = |ip: [u8; 4]| Call {
+        ip: Some(ip),
+        ..Call::default()
+    };
+
+    assert_eq!(
+        send(&app, from([10, 1, 1, 1])).await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        send(&app, from([10, 1, 1, 1])).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(
+        send(&app, from([10, 2, 2, 2])).await.status(),
+        StatusCode::OK
+    );
+    for _ in 0..2 {
+        assert_eq!(
+            send(&app, from([192, 168, 0, 1])).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    let with_token = |token: &'static str| Call {
+        user: Some("erin"),
+        token: Some(token),
+        ..Call::default()
+    };
+    assert_eq!(send(&app, with_token("t1")).await.status(), StatusCode::OK);
+    assert_eq!(
+        send(&app, with_token("t1")).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(send(&app, with_token("t2")).await.status(), StatusCode::OK);
+    // A rule for the token itself takes precedence
+    for _ in 0..3 {
+        assert_eq!(send(&app, with_token("t3")).await.status(), StatusCode::OK);
+    }
+    assert_eq!(
+        send(&app, with_token("t3")).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+}
+
+/// Bytes recorded for the user once the counted bodies are done
+async fn usage_of(pool: &SqlitePool, user: &str, expected: (i64, i64)) -> (i64, i64) {
+    let mut usage = (0, 0);
+    for _ in 0..50 {
+        usage = sqlx::query_as(
+            "SELECT COALESCE(SUM(upload_bytes), 0), COALESCE(SUM(download_bytes), 0)
+             FROM bandwidth_usage WHERE user_id = ?",
+        )
+        .bind(user)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        if usage == expected {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    usage
+}
+
+async fn alerts_of(pool: &SqlitePool, user: &str) -> Vec<String> {
+    sqlx::query_scalar("SELECT alert_type FROM quota_alerts WHERE user_id = ? ORDER BY alert_type")
+        .bind(user)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_bandwidth_quota() {
+    let pool = setup().await;
+    sqlx::query(
+        "INSERT INTO user_bandwidth_quotas (user_id, daily_download_limit_bytes, daily_upload_limit_bytes)
+         VALUES ('alice', 15, 5)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let app = app(&pool);
+    let download = || Call {
+        user: Some("alice"),
+        uri: "/api/download",
+        ..Call::default()
+    };
+
+    let response = send(&app, download()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(usage_of(&pool, "alice", (0, 10)).await, (0, 10));
+
+    let response = send(&app, download()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(usage_of(&pool, "alice", (0, 20)).await, (0, 20));
+
+    let response = send(&app, download()).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["direction"], "download");
+
+    // Uploads known to go over the limit are refused up front
+    let upload = |body| Call {
+        user: Some("alice"),
+        method: "POST",
+        uri: "/api/upload",
+        body,
+        ..Call::default()
+    };
+    let response = send(&app, upload("12345678")).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let response = send(&app, upload("1234")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(usage_of(&pool, "alice", (4, 20)).await, (4, 20));
+    for _ in 0..50 {
+        if alerts_of(&pool, "alice").await.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        alerts_of(&pool, "alice").await,
+        vec!["bandwidth_exceeded", "bandwidth_warning"]
+    );
+
+    // Other users are only counted
+    let response = send(
+        &app,
+        Call {
+            user: Some("bob"),
+            uri: "/api/download",
+            ..Call::default()
+        },
+    )
+    .await;
+    to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(usage_of(&pool, "bob", (0, 10)).await, (0, 10));
+}
+
+#[tokio::test]
+async fn test_bandwidth_shaping() {
+    let pool = setup().await;
+    sqlx::query(
+        "INSERT INTO user_bandwidth_quotas (user_id, download_bytes_per_second) VALUES ('alice', 1000)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let app = app(&pool);
+
+    let started = Instant::now();
+    let response = send(
+        &app,
+        Call {
+            user: Some("alice"),
+            uri: "/api/big",
+            ..Call::default()
+        },
+    )
+    .await;
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body.len(), 300);
+    assert!(started.elapsed() >= Duration::from_millis(250));
+}
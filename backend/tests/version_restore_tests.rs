@@ -0,0 +1,122 @@
+//! Version restore tests
+//!
+//! Restores zstd-compressed and delta versions over the live file, the way
+//! `POST /api/file-versions/restore/{version_num}` does, and checks that the
+//! file gets the original bytes back and the replaced content is kept.
+
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use std::path::Path;
+use syncbackend::services::version_storage_service;
+use tempfile::TempDir;
+
+async fn version_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::query(
+        "CREATE TABLE file_versions (
+            id TEXT PRIMARY KEY NOT NULL,
+            file_id TEXT NOT NULL,
+            version_number INTEGER NOT NULL,
+            size_bytes INTEGER NOT NULL,
+            checksum_sha256 TEXT NOT NULL,
+            storage_path TEXT NOT NULL,
+            created_by TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            original_size INTEGER NOT NULL DEFAULT 0,
+            compressed_size INTEGER NOT NULL DEFAULT 0,
+            is_compressed INTEGER NOT NULL DEFAULT 0,
+            is_differential INTEGER NOT NULL DEFAULT 0,
+            base_version_id TEXT,
+            checksum TEXT NOT NULL DEFAULT '',
+            comment TEXT,
+            UNIQUE(file_id, version_number)
+        )",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    pool
+}
+
+/// Text that compresses well, with `edit` spliced in at line 1000
+fn document(edit: &str) -> Vec<u8> {
+    let mut text = String::new();
+    for line in 0..4000 {
+        if line == 1000 {
+            text.push_str(edit);
+        }
+        text.push_str(&format!("line {} of the quarterly report\n", line));
+    }
+    text.into_bytes()
+}
+
+#[tokio::test]
+async fn test_restore_compressed_and_delta_versions_over_live_file() {
+    let dir = TempDir::new().unwrap();
+    std::env::set_current_dir(dir.path()).unwrap();
+    tokio::fs::create_dir_all("./data/docs").await.unwrap();
+    let pool = version_pool().await;
+    let live = Path::new("./data/docs/report.txt");
+
+    let original = document("");
+    tokio::fs::write(live, &original).await.unwrap();
+    let v1 = version_storage_service::create_version(&pool, "file-1", live, "user-1", None)
+        .await
+        .unwrap();
+    assert!(v1.is_compressed && !v1.is_differential);
+    assert!(v1.compressed_size < v1.original_size);
+
+    let edited = document("an edited paragraph\n");
+    tokio::fs::write(live, &edited).await.unwrap();
+    let v2 = version_storage_service::create_version(&pool, "file-1", live, "user-1", None)
+        .await
+        .unwrap();
+    assert!(v2.is_differential);
+    assert_eq!(v2.base_version_id.as_deref(), Some(v1.id.as_str()));
+
+    let current = document("the latest draft\n");
+    tokio::fs::write(live, &current).await.unwrap();
+
+    // The delta version comes back decoded, the current draft is kept
+    let (restored, replaced) =
+        version_storage_service::restore_version_over(&pool, &v2.id, live, "user-1")
+            .await
+            .unwrap();
+    assert_eq!(restored.id, v2.id);
+    assert_eq!(tokio::fs::read(live).await.unwrap(), edited);
+    let replaced = replaced.unwrap();
+    assert_eq!(replaced.version_number, 3);
+    assert_eq!(replaced.comment.as_deref(), Some("Before restoring version 2"));
+    let mut kept = Vec::new();
+    version_storage_service::write_version_to(&pool, &replaced.id, &mut kept)
+        .await
+        .unwrap();
+    assert_eq!(kept, current);
+
+    // The compressed full copy comes back decompressed
+    let (restored, _) = version_storage_service::restore_version_over(&pool, &v1.id, live, "user-1")
+        .await
+        .unwrap();
+    assert_eq!(restored.checksum, v1.checksum);
+    assert_eq!(tokio::fs::read(live).await.unwrap(), original);
+
+    // Nothing but the live file is left next to it
+    let mut entries = tokio::fs::read_dir("./data/docs").await.unwrap();
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    assert_eq!(names, vec!["report.txt".to_string()]);
+
+    // A restore into a missing directory fails without touching anything
+    let missing = Path::new("./data/gone/report.txt");
+    assert!(
+        version_storage_service::restore_version_over(&pool, &v1.id, missing, "user-1")
+            .await
+            .is_err()
+    );
+}
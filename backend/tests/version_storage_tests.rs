@@ -0,0 +1,253 @@
+//! Version storage integration tests
+//!
+//! Versions a file several times larger than a memory cap and checks that
+//! creating, restoring, diffing and deleting versions stays below it. Heap
+//! use is measured with a counting global allocator, so this binary holds a
+//! single test.
+
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use syncbackend::services::version_storage_service::{self, DiffType};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+struct CountingAllocator;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let now = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Heap allowed on top of what was allocated before an operation. zstd
+/// allocates its (level-bounded) state through malloc, outside this count.
+const MEMORY_CAP: usize = 16 * 1024 * 1024;
+const FILE_SIZE: usize = 48 * 1024 * 1024;
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Run `operation` and fail if it allocates more than `MEMORY_CAP` at once
+async fn within_cap<T>(what: &str, operation: impl Future<Output = T>) -> T {
+    let baseline = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    let result = operation.await;
+    let peak = PEAK.load(Ordering::Relaxed).saturating_sub(baseline);
+    assert!(
+        peak < MEMORY_CAP,
+        "{} used {} bytes of heap, cap is {}",
+        what,
+        peak,
+        MEMORY_CAP
+    );
+    result
+}
+
+/// Write `len` bytes of deterministic, incompressible content in chunks
+async fn write_test_file(path: &Path, len: usize) {
+    let mut file = tokio::fs::File::create(path).await.unwrap();
+    let mut state: u32 = 0x9e37_79b9;
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut written = 0;
+    while written < len {
+        let take = CHUNK_SIZE.min(len - written);
+        for byte in &mut chunk[..take] {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            *byte = state as u8;
+        }
+        file.write_all(&chunk[..take]).await.unwrap();
+        written += take;
+    }
+    file.flush().await.unwrap();
+}
+
+async fn sha256_file(path: &Path) -> String {
+    let mut file = tokio::fs::File::open(path).await.unwrap();
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk).await.unwrap();
+        if read == 0 {
+            break;
+        }
+        hasher.update(&chunk[..read]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+async fn version_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::query(
+        "CREATE TABLE file_versions (
+            id TEXT PRIMARY KEY NOT NULL,
+            file_id TEXT NOT NULL,
+            version_number INTEGER NOT NULL,
+            size_bytes INTEGER NOT NULL,
+            checksum_sha256 TEXT NOT NULL,
+            storage_path TEXT NOT NULL,
+            created_by TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            original_size INTEGER NOT NULL DEFAULT 0,
+            compressed_size INTEGER NOT NULL DEFAULT 0,
+            is_compressed INTEGER NOT NULL DEFAULT 0,
+            is_differential INTEGER NOT NULL DEFAULT 0,
+            base_version_id TEXT,
+            checksum TEXT NOT NULL DEFAULT '',
+            comment TEXT,
+            UNIQUE(file_id, version_number)
+        )",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    pool
+}
+
+#[tokio::test]
+async fn test_versions_larger_than_memory_cap_are_streamed() {
+    let dir = TempDir::new().unwrap();
+    std::env::set_current_dir(dir.path()).unwrap();
+    tokio::fs::create_dir_all("./data/files").await.unwrap();
+    let pool = version_pool().await;
+
+    let path = Path::new("./data/files/video.bin");
+    write_test_file(path, FILE_SIZE).await;
+    let original_checksum = sha256_file(path).await;
+
+    let v1 = within_cap(
+        "creating the first version",
+        version_storage_service::create_version(&pool, "file-1", path, "user-1", None),
+    )
+    .await
+    .unwrap();
+    assert_eq!(v1.original_size, FILE_SIZE as i64);
+    assert_eq!(v1.checksum, original_checksum);
+    assert!(v1.is_compressed && !v1.is_differential);
+
+    // Overwrite 100 KiB in the middle and append a little
+    {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .await
+            .unwrap();
+        file.seek(std::io::SeekFrom::Start(FILE_SIZE as u64 / 2))
+            .await
+            .unwrap();
+        file.write_all(&[0xab; 100 * 1024]).await.unwrap();
+        file.seek(std::io::SeekFrom::End(0)).await.unwrap();
+        file.write_all(b"appended trailer").await.unwrap();
+        file.flush().await.unwrap();
+    }
+    let modified_checksum = sha256_file(path).await;
+
+    let v2 = within_cap(
+        "creating a delta version",
+        version_storage_service::create_version(&pool, "file-1", path, "user-1", Some("edit")),
+    )
+    .await
+    .unwrap();
+    assert!(v2.is_differential);
+    assert_eq!(v2.base_version_id.as_deref(), Some(v1.id.as_str()));
+    assert_eq!(v2.checksum, modified_checksum);
+    assert!(
+        v2.compressed_size < 1024 * 1024,
+        "delta took {} bytes",
+        v2.compressed_size
+    );
+
+    // Restoring the delta materializes its base on disk, not in memory
+    let restored = dir.path().join("restored.bin");
+    within_cap(
+        "restoring the delta version",
+        version_storage_service::restore_version_to_file(&pool, &v2.id, &restored),
+    )
+    .await
+    .unwrap();
+    assert_eq!(sha256_file(&restored).await, modified_checksum);
+
+    // Streaming the first version
+    let (metadata, mut stream) = version_storage_service::open_version(&pool, &v1.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(metadata.id, v1.id);
+    let streamed = within_cap("streaming the first version", async {
+        let mut hasher = Sha256::new();
+        let mut chunk = vec![0u8; 64 * 1024];
+        let mut total = 0;
+        loop {
+            let read = stream.read(&mut chunk).await.unwrap();
+            if read == 0 {
+                break;
+            }
+            hasher.update(&chunk[..read]);
+            total += read;
+        }
+        (total, format!("{:x}", hasher.finalize()))
+    })
+    .await;
+    assert_eq!(streamed, (FILE_SIZE, original_checksum));
+
+    // Large versions are compared by checksum without loading them
+    let diff = within_cap(
+        "diffing the versions",
+        version_storage_service::get_version_diff(&pool, &v1.id, &v2.id),
+    )
+    .await
+    .unwrap();
+    assert!(matches!(diff.diff_type, DiffType::Binary));
+    assert!(diff.binary_diff.unwrap().checksum_changed);
+    assert_eq!(diff.size_change, 16);
+
+    // Deleting the base turns the delta into a full copy
+    let deleted = within_cap(
+        "deleting the base version",
+        version_storage_service::delete_version(&pool, &v1.id),
+    )
+    .await
+    .unwrap();
+    assert!(deleted);
+    assert!(!Path::new(&v1.storage_path).exists());
+
+    let detached: (i32, Option<String>) =
+        sqlx::query_as("SELECT is_differential, base_version_id FROM file_versions WHERE id = ?")
+            .bind(&v2.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(detached, (0, None));
+
+    tokio::fs::remove_file(&restored).await.unwrap();
+    within_cap(
+        "restoring the detached version",
+        version_storage_service::restore_version_to_file(&pool, &v2.id, &restored),
+    )
+    .await
+    .unwrap();
+    assert_eq!(sha256_file(&restored).await, modified_checksum);
+}
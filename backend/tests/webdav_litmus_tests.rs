@@ -0,0 +1,1486 @@
+//! WebDAV conformance tests
+//!
+//! Modeled on the litmus suites (basic, copymove, props, locks): each test
+//! walks through the same sequence of requests litmus sends and checks the
+//! status codes and response bodies RFC 4918 requires.
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{HeaderMap, Request, StatusCode};
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use std::sync::{Arc, Mutex};
+use syncbackend::webdav::{DavChange, DavContent, DavHooks, DavServer, DavUser};
+use tempfile::TempDir;
+
+const PREFIX: &str = "/dav";
+/// Marks content sealed by `RecordingHooks`, which stands in for
+/// encryption: only the owner reads it, and writes over it stay sealed
+const SEALED: &str = "sealed:";
+
+#[derive(Default)]
+struct RecordingHooks {
+    changes: Mutex<Vec<DavChange>>,
+    quota: Option<u64>,
+    seal: bool,
+    /// Set by `Dav::with_hooks`
+    root: std::path::PathBuf,
+}
+
+impl RecordingHooks {
+    /// Unsealed content of a sealed file
+    fn sealed(&self, path: &str) -> Option<String> {
+        if !self.seal {
+            return None;
+        }
+        let content = std::fs::read_to_string(self.root.join(path)).ok()?;
+        content.strip_prefix(SEALED).map(str::to_string)
+    }
+}
+
+#[async_trait::async_trait]
+impl DavHooks for RecordingHooks {
+    async fn available_bytes(&self, _user: &DavUser) -> Option<u64> {
+        self.quota
+    }
+
+    async fn changed(&self, _user: &DavUser, change: DavChange) {
+        self.changes.lock().unwrap().push(change);
+    }
+
+    fn is_hidden(&self, path: &str) -> bool {
+        path == "internal"
+    }
+
+    async fn open(
+        &self,
+        user: &DavUser,
+        path: &str,
+        _stored_len: u64,
+    ) -> Result<Option<DavContent>, StatusCode> {
+        let Some(content) = self.sealed(path) else {
+            return Ok(None);
+        };
+        if user.id != "user-1" {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(Some(DavContent {
+            len: content.len() as u64,
+            body: Body::from(content),
+        }))
+    }
+
+    async fn content_length(&self, path: &str, stored_len: u64) -> u64 {
+        self.sealed(path)
+            .map_or(stored_len, |content| content.len() as u64)
+    }
+
+    async fn replace(
+        &self,
+        user: &DavUser,
+        path: &str,
+        upload: &std::path::Path,
+    ) -> Result<bool, StatusCode> {
+        if self.sealed(path).is_none() {
+            return Ok(false);
+        }
+        if user.id != "user-1" {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let content = std::fs::read_to_string(upload).unwrap();
+        let target = self.root.join(path);
+        std::fs::write(target, format!("{}{}", SEALED, content)).unwrap();
+        Ok(true)
+    }
+}
+
+struct Reply {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: String,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    fn xml(&self) -> roxmltree::Document<'_> {
+        roxmltree::Document::parse(&self.body)
+            .unwrap_or_else(|e| panic!("invalid XML ({}): {}", e, self.body))
+    }
+
+    /// Text of the first `DAV:<name>` element
+    fn dav_text(&self, name: &str) -> Option<String> {
+        let document = self.xml();
+        document
+            .descendants()
+            .find(|n| n.has_tag_name(("DAV:", name)))
+            .map(|n| n.text().unwrap_or_default().to_string())
+    }
+
+    /// `(href, status)` of every propstat in a multistatus body, with the
+    /// names of the properties in it
+    fn propstats(&self) -> Vec<(String, u16, Vec<String>)> {
+        let document = self.xml();
+        let mut result = Vec::new();
+        for response in document
+            .descendants()
+            .filter(|n| n.has_tag_name(("DAV:", "response")))
+        {
+            let href = response
+                .children()
+                .find(|n| n.has_tag_name(("DAV:", "href")))
+                .and_then(|n| n.text())
+                .unwrap_or_default()
+                .to_string();
+            for propstat in response
+                .children()
+                .filter(|n| n.has_tag_name(("DAV:", "propstat")))
+            {
+                let status = propstat
+                    .children()
+                    .find(|n| n.has_tag_name(("DAV:", "status")))
+                    .and_then(|n| n.text())
+                    .and_then(|s| s.split(' ').nth(1))
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_default();
+                let names = propstat
+                    .children()
+                    .find(|n| n.has_tag_name(("DAV:", "prop")))
+                    .map(|prop| {
+                        prop.children()
+                            .filter(|n| n.is_element())
+                            .map(|n| n.tag_name().name().to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                result.push((href.clone(), status, names));
+            }
+        }
+        result
+    }
+
+    fn hrefs(&self) -> Vec<String> {
+        let mut hrefs: Vec<String> = self
+            .propstats()
+            .into_iter()
+            .map(|(href, _, _)| href)
+            .collect();
+        hrefs.dedup();
+        hrefs
+    }
+}
+
+struct Dav {
+    _dir: TempDir,
+    root: std::path::PathBuf,
+    pool: SqlitePool,
+    server: DavServer,
+    hooks: Arc<RecordingHooks>,
+    owner: DavUser,
+    other: DavUser,
+}
+
+async fn dav_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::query(
+        "CREATE TABLE collaborative_locks (
+            id TEXT PRIMARY KEY,
+            file_id TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            locked_by TEXT NOT NULL,
+            locked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            expires_at TIMESTAMP NOT NULL,
+            lock_type TEXT NOT NULL DEFAULT 'exclusive',
+            last_heartbeat TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    common::run_migration(&pool, include_str!("../migrations/057_webdav.sql")).await;
+    pool
+}
+
+impl Dav {
+    async fn new() -> Self {
+        Self::with_hooks(RecordingHooks::default()).await
+    }
+
+    async fn with_hooks(mut hooks: RecordingHooks) -> Self {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("data");
+        std::fs::create_dir_all(root.join("internal")).unwrap();
+        let pool = dav_pool().await;
+        hooks.root = root.clone();
+        let hooks = Arc::new(hooks);
+        let server = DavServer::new(pool.clone(), &root, PREFIX).with_hooks(hooks.clone());
+        Self {
+            _dir: dir,
+            root,
+            pool,
+            server,
+            hooks,
+            owner: DavUser {
+                id: "user-1".to_string(),
+                username: "alice".to_string(),
+            },
+            other: DavUser {
+                id: "user-2".to_string(),
+                username: "bob".to_string(),
+            },
+        }
+    }
+
+    async fn send(
+        &self,
+        user: &DavUser,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Reply {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", PREFIX, path));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = self
+            .server
+            .handle(user, request.body(Body::from(body.to_string())).unwrap())
+            .await;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        Reply {
+            status,
+            headers,
+            body: String::from_utf8(bytes.to_vec()).unwrap(),
+        }
+    }
+
+    async fn req(&self, method: &str, path: &str, headers: &[(&str, &str)], body: &str) -> Reply {
+        self.send(&self.owner, method, path, headers, body).await
+    }
+
+    async fn status(&self, method: &str, path: &str, headers: &[(&str, &str)], body: &str) -> u16 {
+        self.req(method, path, headers, body).await.status.as_u16()
+    }
+
+    async fn put(&self, path: &str, body: &str) -> u16 {
+        self.status("PUT", path, &[], body).await
+    }
+
+    async fn propfind(&self, path: &str, depth: &str, body: &str) -> Reply {
+        let reply = self.req("PROPFIND", path, &[("Depth", depth)], body).await;
+        assert_eq!(reply.status, StatusCode::MULTI_STATUS, "{}", reply.body);
+        reply
+    }
+
+    async fn lock(&self, user: &DavUser, path: &str, scope: &str, depth: &str) -> Reply {
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+            <D:lockinfo xmlns:D="DAV:">
+              <D:lockscope><D:{}/></D:lockscope>
+              <D:locktype><D:write/></D:locktype>
+              <D:owner><D:href>litmus test suite</D:href></D:owner>
+            </D:lockinfo>"#,
+            scope
+        );
+        self.send(
+            user,
+            "LOCK",
+            path,
+            &[("Depth", depth), ("Timeout", "Second-3600")],
+            &body,
+        )
+        .await
+    }
+
+    fn changes(&self) -> Vec<DavChange> {
+        std::mem::take(&mut *self.hooks.changes.lock().unwrap())
+    }
+}
+
+fn lock_token(reply: &Reply) -> String {
+    let header = reply.header("lock-token").expect("Lock-Token header");
+    header
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_string()
+}
+
+const PROPPATCH_SET: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<propertyupdate xmlns="DAV:" xmlns:z="http://example.com/neon/litmus/">
+  <set><prop>
+    <z:prop0>value0</z:prop0>
+    <z:prop1>value1</z:prop1>
+    <z:prop2>value2</z:prop2>
+  </prop></set>
+</propertyupdate>"#;
+
+const PROPFIND_LITMUS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<propfind xmlns="DAV:" xmlns:z="http://example.com/neon/litmus/">
+  <prop><z:prop0/><z:prop1/><z:prop2/></prop>
+</propfind>"#;
+
+#[tokio::test]
+async fn test_basic() {
+    let dav = Dav::new().await;
+
+    // options
+    let reply = dav.req("OPTIONS", "/", &[], "").await;
+    assert_eq!(reply.status, StatusCode::OK);
+    let dav_header = reply.header("dav").unwrap();
+    assert!(dav_header.contains('1') && dav_header.contains('2'));
+
+    // put_get, put_get_utf8_segment
+    assert_eq!(dav.status("MKCOL", "/litmus/", &[], "").await, 201);
+    assert_eq!(dav.put("/litmus/res", "This is\na test file.\n").await, 201);
+    let reply = dav.req("GET", "/litmus/res", &[], "").await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.body, "This is\na test file.\n");
+    let etag = reply.header("etag").unwrap().to_string();
+    assert!(etag.starts_with('"'), "strong ETag expected: {}", etag);
+
+    assert_eq!(dav.put("/litmus/res-%e2%82%ac", "euro").await, 201);
+    assert_eq!(
+        std::fs::read_to_string(dav.root.join("litmus/res-€")).unwrap(),
+        "euro"
+    );
+    let reply = dav.req("GET", "/litmus/res-%E2%82%AC", &[], "").await;
+    assert_eq!(reply.body, "euro");
+
+    // Overwriting returns 204 and a new ETag
+    assert_eq!(dav.put("/litmus/res", "changed").await, 204);
+    let reply = dav.req("HEAD", "/litmus/res", &[], "").await;
+    assert_eq!(reply.header("content-length"), Some("7"));
+    assert_ne!(reply.header("etag").unwrap(), etag);
+    let etag = reply.header("etag").unwrap().to_string();
+    assert_eq!(
+        dav.status("GET", "/litmus/res", &[("If-None-Match", &etag)], "")
+            .await,
+        304
+    );
+    assert_eq!(
+        dav.status("GET", "/litmus/res", &[("If-Match", "\"other\"")], "")
+            .await,
+        412
+    );
+
+    // mkcol_over_plain, mkcol_again, mkcol_no_parent, mkcol_with_body
+    assert_eq!(dav.status("MKCOL", "/litmus/res", &[], "").await, 405);
+    assert_eq!(dav.status("MKCOL", "/litmus/coll/", &[], "").await, 201);
+    assert_eq!(dav.status("MKCOL", "/litmus/coll/", &[], "").await, 405);
+    assert_eq!(
+        dav.status("MKCOL", "/litmus/noparent/foo/", &[], "").await,
+        409
+    );
+    assert_eq!(
+        dav.status(
+            "MKCOL",
+            "/litmus/mkcolbody",
+            &[("Content-Type", "xzy-foo/bar-512")],
+            "afafafaf"
+        )
+        .await,
+        415
+    );
+
+    // PUT without a parent, onto a collection, partial
+    assert_eq!(dav.put("/litmus/noparent/res", "x").await, 409);
+    assert_eq!(dav.put("/litmus/coll", "x").await, 405);
+    assert_eq!(
+        dav.status(
+            "PUT",
+            "/litmus/res",
+            &[("Content-Range", "bytes 0-1/2")],
+            "ab"
+        )
+        .await,
+        400
+    );
+
+    // delete, delete_null, delete_fragment, delete_coll
+    assert_eq!(dav.status("DELETE", "/litmus/res", &[], "").await, 204);
+    assert_eq!(dav.status("GET", "/litmus/res", &[], "").await, 404);
+    assert_eq!(dav.status("DELETE", "/litmus/404me", &[], "").await, 404);
+    assert_eq!(dav.put("/litmus/coll/member", "m").await, 201);
+    assert_eq!(dav.status("DELETE", "/litmus/coll/", &[], "").await, 204);
+    assert!(!dav.root.join("litmus/coll").exists());
+    assert_eq!(dav.status("DELETE", "/", &[], "").await, 403);
+
+    // Escaping the root and server-managed paths
+    assert_eq!(
+        dav.status("GET", "/litmus/../../etc/passwd", &[], "").await,
+        400
+    );
+    assert_eq!(
+        dav.status("GET", "/litmus/%2e%2e/%2e%2e/x", &[], "").await,
+        400
+    );
+    assert_eq!(dav.status("PROPFIND", "/internal/", &[], "").await, 404);
+    assert_eq!(dav.put("/temp_uploads/x", "x").await, 404);
+    assert_eq!(dav.status("BREW", "/litmus/", &[], "").await, 405);
+
+    assert_eq!(
+        dav.changes(),
+        vec![
+            DavChange::Changed("litmus".to_string()),
+            DavChange::Changed("litmus/res".to_string()),
+            DavChange::Changed("litmus/res-€".to_string()),
+            DavChange::Changed("litmus/res".to_string()),
+            DavChange::Changed("litmus/coll".to_string()),
+            DavChange::Deleted("litmus/res".to_string()),
+            DavChange::Changed("litmus/coll/member".to_string()),
+            DavChange::Deleted("litmus/coll".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_copymove() {
+    let dav = Dav::new().await;
+    dav.status("MKCOL", "/litmus/", &[], "").await;
+    assert_eq!(dav.put("/litmus/copysrc", "source").await, 201);
+
+    // copy_simple, copy_overwrite, copy_nodestcoll
+    let dest = |path: &str| format!("http://localhost{}{}", PREFIX, path);
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/copysrc",
+            &[("Destination", &dest("/litmus/copydest"))],
+            ""
+        )
+        .await,
+        201
+    );
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/copysrc",
+            &[
+                ("Destination", &dest("/litmus/copydest")),
+                ("Overwrite", "F")
+            ],
+            ""
+        )
+        .await,
+        412
+    );
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/copysrc",
+            &[
+                ("Destination", &dest("/litmus/copydest")),
+                ("Overwrite", "T")
+            ],
+            ""
+        )
+        .await,
+        204
+    );
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/copysrc",
+            &[("Destination", &dest("/litmus/nonesuch/foo"))],
+            ""
+        )
+        .await,
+        409
+    );
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/copysrc",
+            &[("Destination", &dest("/litmus/copysrc"))],
+            ""
+        )
+        .await,
+        403
+    );
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/copysrc",
+            &[("Destination", "http://other/x")],
+            ""
+        )
+        .await,
+        502
+    );
+    assert_eq!(dav.status("COPY", "/litmus/copysrc", &[], "").await, 400);
+
+    // copy_coll, copy_shallow
+    dav.status("MKCOL", "/litmus/ccsrc/", &[], "").await;
+    dav.status("MKCOL", "/litmus/ccsrc/subcoll/", &[], "").await;
+    for i in 0..5 {
+        dav.put(&format!("/litmus/ccsrc/foo.{}", i), "x").await;
+    }
+    dav.put("/litmus/ccsrc/subcoll/deep", "deep").await;
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/ccsrc/",
+            &[("Destination", &dest("/litmus/ccdest/"))],
+            ""
+        )
+        .await,
+        201
+    );
+    assert_eq!(
+        std::fs::read_to_string(dav.root.join("litmus/ccdest/subcoll/deep")).unwrap(),
+        "deep"
+    );
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/ccsrc/",
+            &[
+                ("Destination", &dest("/litmus/ccdest/")),
+                ("Overwrite", "F")
+            ],
+            ""
+        )
+        .await,
+        412
+    );
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/ccsrc/",
+            &[("Destination", &dest("/litmus/ccshallow/")), ("Depth", "0")],
+            ""
+        )
+        .await,
+        201
+    );
+    assert!(dav.root.join("litmus/ccshallow").is_dir());
+    assert_eq!(
+        std::fs::read_dir(dav.root.join("litmus/ccshallow"))
+            .unwrap()
+            .count(),
+        0
+    );
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/ccsrc/",
+            &[("Destination", &dest("/litmus/ccsrc/into/"))],
+            ""
+        )
+        .await,
+        403
+    );
+
+    // move, move_coll
+    dav.changes();
+    assert_eq!(
+        dav.status(
+            "MOVE",
+            "/litmus/copysrc",
+            &[("Destination", &dest("/litmus/movedest"))],
+            ""
+        )
+        .await,
+        201
+    );
+    assert_eq!(dav.status("GET", "/litmus/copysrc", &[], "").await, 404);
+    assert_eq!(
+        dav.status(
+            "MOVE",
+            "/litmus/copydest",
+            &[
+                ("Destination", &dest("/litmus/movedest")),
+                ("Overwrite", "F")
+            ],
+            ""
+        )
+        .await,
+        412
+    );
+    assert_eq!(
+        dav.status(
+            "MOVE",
+            "/litmus/copydest",
+            &[("Destination", &dest("/litmus/movedest"))],
+            ""
+        )
+        .await,
+        204
+    );
+    assert_eq!(
+        dav.status(
+            "MOVE",
+            "/litmus/ccsrc/",
+            &[
+                ("Destination", &format!("{}/litmus/mvdest/", PREFIX)),
+                ("Depth", "0")
+            ],
+            ""
+        )
+        .await,
+        400
+    );
+    assert_eq!(
+        dav.status(
+            "MOVE",
+            "/litmus/ccsrc/",
+            &[("Destination", &format!("{}/litmus/mvdest/", PREFIX))],
+            ""
+        )
+        .await,
+        201
+    );
+    assert_eq!(
+        std::fs::read_to_string(dav.root.join("litmus/mvdest/subcoll/deep")).unwrap(),
+        "deep"
+    );
+    assert_eq!(
+        dav.changes(),
+        vec![
+            DavChange::Moved {
+                from: "litmus/copysrc".to_string(),
+                to: "litmus/movedest".to_string()
+            },
+            DavChange::Deleted("litmus/movedest".to_string()),
+            DavChange::Moved {
+                from: "litmus/copydest".to_string(),
+                to: "litmus/movedest".to_string()
+            },
+            DavChange::Moved {
+                from: "litmus/ccsrc".to_string(),
+                to: "litmus/mvdest".to_string()
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_props() {
+    let dav = Dav::new().await;
+    dav.status("MKCOL", "/litmus/", &[], "").await;
+
+    // propfind_invalid, propfind_invalid2, propfind_d0
+    assert_eq!(
+        dav.status("PROPFIND", "/litmus/", &[("Depth", "0")], "<foo>")
+            .await,
+        400
+    );
+    assert_eq!(
+        dav.status(
+            "PROPFIND",
+            "/litmus/",
+            &[("Depth", "0")],
+            r#"<propfind xmlns="DAV:"><prop><bar:foo xmlns:bar=""/></prop></propfind>"#
+        )
+        .await,
+        400
+    );
+    let reply = dav.propfind("/litmus/", "0", "").await;
+    assert_eq!(reply.hrefs(), vec!["/dav/litmus/"]);
+    let (_, status, names) = &reply.propstats()[0];
+    assert_eq!(*status, 200);
+    for name in [
+        "resourcetype",
+        "getetag",
+        "getlastmodified",
+        "supportedlock",
+        "lockdiscovery",
+    ] {
+        assert!(names.iter().any(|n| n == name), "allprop misses {}", name);
+    }
+    assert!(!names.iter().any(|n| n == "getcontentlength"));
+    assert!(reply.body.contains("<D:collection/>"));
+
+    // propinit, propset, propget
+    dav.put("/litmus/prop", "props").await;
+    let reply = dav
+        .req("PROPPATCH", "/litmus/prop", &[], PROPPATCH_SET)
+        .await;
+    assert_eq!(reply.status, StatusCode::MULTI_STATUS);
+    assert_eq!(reply.propstats()[0].1, 200);
+    let reply = dav.propfind("/litmus/prop", "0", PROPFIND_LITMUS).await;
+    assert_eq!(
+        reply.propstats(),
+        vec![(
+            "/dav/litmus/prop".to_string(),
+            200,
+            vec![
+                "prop0".to_string(),
+                "prop1".to_string(),
+                "prop2".to_string()
+            ]
+        )]
+    );
+    assert_eq!(reply.body.matches("value1").count(), 1);
+
+    // propmove, propcopy: dead properties follow the resource
+    let dest = format!("{}/litmus/prop2", PREFIX);
+    assert_eq!(
+        dav.status("MOVE", "/litmus/prop", &[("Destination", &dest)], "")
+            .await,
+        201
+    );
+    let reply = dav.propfind("/litmus/prop2", "0", PROPFIND_LITMUS).await;
+    assert_eq!(reply.propstats()[0].1, 200);
+    let dest = format!("{}/litmus/prop", PREFIX);
+    assert_eq!(
+        dav.status("COPY", "/litmus/prop2", &[("Destination", &dest)], "")
+            .await,
+        201
+    );
+    let reply = dav.propfind("/litmus/prop", "0", PROPFIND_LITMUS).await;
+    assert_eq!(reply.propstats()[0].1, 200);
+
+    // propdeletes, propreplace
+    let reply = dav
+        .req(
+            "PROPPATCH",
+            "/litmus/prop",
+            &[],
+            r#"<propertyupdate xmlns="DAV:" xmlns:z="http://example.com/neon/litmus/">
+               <remove><prop><z:prop0/></prop></remove>
+               <set><prop><z:prop1>replaced</z:prop1></prop></set>
+               </propertyupdate>"#,
+        )
+        .await;
+    assert_eq!(reply.status, StatusCode::MULTI_STATUS);
+    let reply = dav.propfind("/litmus/prop", "0", PROPFIND_LITMUS).await;
+    let propstats = reply.propstats();
+    assert!(propstats.contains(&(
+        "/dav/litmus/prop".to_string(),
+        404,
+        vec!["prop0".to_string()]
+    )));
+    assert!(reply.body.contains("replaced") && !reply.body.contains("value1"));
+
+    // propnullns, prophighunicode, propvalnspace, propwformed
+    let reply = dav
+        .req(
+            "PROPPATCH",
+            "/litmus/prop",
+            &[],
+            r#"<propertyupdate xmlns="DAV:"><set><prop>
+                 <nonamespace xmlns="">randomvalue</nonamespace>
+                 <z:high xmlns:z="http://example.com/neon/litmus/">&#x10000;&#x10FFFF; é</z:high>
+                 <z:valnspace xmlns:z="http://example.com/neon/litmus/"><foo xmlns="http://bar">baz</foo></z:valnspace>
+                 <z:wformed xmlns:z="http://example.com/neon/litmus/"><a:x xmlns:a="http://a"><a:y/>text</a:x></z:wformed>
+               </prop></set></propertyupdate>"#,
+        )
+        .await;
+    assert_eq!(reply.propstats()[0].1, 200);
+    let reply = dav
+        .propfind(
+            "/litmus/prop",
+            "0",
+            r#"<propfind xmlns="DAV:"><prop>
+                 <nonamespace xmlns=""/>
+                 <z:high xmlns:z="http://example.com/neon/litmus/"/>
+                 <z:valnspace xmlns:z="http://example.com/neon/litmus/"/>
+                 <z:wformed xmlns:z="http://example.com/neon/litmus/"/>
+               </prop></propfind>"#,
+        )
+        .await;
+    let document = reply.xml();
+    let prop = |ns: &str, name: &str| {
+        document
+            .descendants()
+            .find(|n| n.has_tag_name((ns, name)))
+            .unwrap_or_else(|| panic!("missing {} in {}", name, reply.body))
+    };
+    assert_eq!(prop("", "nonamespace").text(), Some("randomvalue"));
+    assert_eq!(
+        prop("http://example.com/neon/litmus/", "high").text(),
+        Some("\u{10000}\u{10FFFF} é")
+    );
+    let foo = prop("http://bar", "foo");
+    assert_eq!(foo.text(), Some("baz"));
+    let x = prop("http://a", "x");
+    assert!(x.children().any(|n| n.has_tag_name(("http://a", "y"))));
+
+    // propremoveset, propsetremove: updates apply in document order
+    dav.req(
+        "PROPPATCH",
+        "/litmus/prop",
+        &[],
+        r#"<propertyupdate xmlns="DAV:" xmlns:z="urn:z">
+           <set><prop><z:order>first</z:order></prop></set>
+           <remove><prop><z:order/></prop></remove>
+           <set><prop><z:order>last</z:order></prop></set>
+           </propertyupdate>"#,
+    )
+    .await;
+    let reply = dav
+        .propfind(
+            "/litmus/prop",
+            "0",
+            r#"<propfind xmlns="DAV:" xmlns:z="urn:z"><prop><z:order/></prop></propfind>"#,
+        )
+        .await;
+    assert!(reply.body.contains(">last<"));
+
+    // Protected live properties fail the whole PROPPATCH
+    let reply = dav
+        .req(
+            "PROPPATCH",
+            "/litmus/prop",
+            &[],
+            r#"<propertyupdate xmlns="DAV:" xmlns:z="urn:z"><set><prop>
+               <getetag>"forged"</getetag><z:unapplied>x</z:unapplied>
+               </prop></set></propertyupdate>"#,
+        )
+        .await;
+    let mut statuses: Vec<(u16, Vec<String>)> = reply
+        .propstats()
+        .into_iter()
+        .map(|(_, status, names)| (status, names))
+        .collect();
+    statuses.sort();
+    assert_eq!(
+        statuses,
+        vec![
+            (403, vec!["getetag".to_string()]),
+            (424, vec!["unapplied".to_string()]),
+        ]
+    );
+    let reply = dav
+        .propfind(
+            "/litmus/prop",
+            "0",
+            r#"<propfind xmlns="DAV:" xmlns:z="urn:z"><prop><z:unapplied/></prop></propfind>"#,
+        )
+        .await;
+    assert_eq!(reply.propstats()[0].1, 404);
+
+    // propname lists dead properties without values
+    let reply = dav
+        .propfind(
+            "/litmus/prop",
+            "0",
+            r#"<propfind xmlns="DAV:"><propname/></propfind>"#,
+        )
+        .await;
+    assert!(reply.body.contains("order") && !reply.body.contains(">last<"));
+
+    // Deleting a resource deletes its properties
+    dav.status("DELETE", "/litmus/prop", &[], "").await;
+    dav.put("/litmus/prop", "again").await;
+    let reply = dav.propfind("/litmus/prop", "0", PROPFIND_LITMUS).await;
+    assert_eq!(reply.propstats()[0].1, 404);
+    let stored: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM webdav_properties WHERE path = 'litmus/prop'")
+            .fetch_one(&dav.pool)
+            .await
+            .unwrap();
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn test_propfind_depth() {
+    let dav = Dav::new().await;
+    dav.status("MKCOL", "/a/", &[], "").await;
+    dav.status("MKCOL", "/a/b/", &[], "").await;
+    dav.status("MKCOL", "/a/b/c/", &[], "").await;
+    dav.put("/a/one.txt", "1").await;
+    dav.put("/a/b/two%20words.txt", "22").await;
+    dav.put("/a/b/c/three.txt", "333").await;
+
+    let mut depth_one = dav.propfind("/a/", "1", "").await.hrefs();
+    depth_one.sort();
+    assert_eq!(depth_one, vec!["/dav/a/", "/dav/a/b/", "/dav/a/one.txt"]);
+
+    let mut all = dav.propfind("/", "infinity", "").await.hrefs();
+    all.sort();
+    assert_eq!(
+        all,
+        vec![
+            "/dav/",
+            "/dav/a/",
+            "/dav/a/b/",
+            "/dav/a/b/c/",
+            "/dav/a/b/c/three.txt",
+            "/dav/a/b/two%20words.txt",
+            "/dav/a/one.txt",
+        ]
+    );
+
+    // No Depth header means infinity
+    assert_eq!(dav.req("PROPFIND", "/a/b/", &[], "").await.hrefs().len(), 4);
+    assert_eq!(
+        dav.status("PROPFIND", "/a/", &[("Depth", "2")], "").await,
+        400
+    );
+    assert_eq!(
+        dav.status("PROPFIND", "/missing/", &[("Depth", "0")], "")
+            .await,
+        404
+    );
+
+    let reply = dav
+        .propfind(
+            "/a/b/c/three.txt",
+            "0",
+            r#"<propfind xmlns="DAV:"><prop><getcontentlength/><getcontenttype/><foo xmlns="urn:x"/></prop></propfind>"#,
+        )
+        .await;
+    assert_eq!(reply.dav_text("getcontentlength").as_deref(), Some("3"));
+    assert_eq!(
+        reply.dav_text("getcontenttype").as_deref(),
+        Some("text/plain")
+    );
+    assert_eq!(
+        reply.propstats()[1],
+        (
+            "/dav/a/b/c/three.txt".to_string(),
+            404,
+            vec!["foo".to_string()]
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_locks() {
+    let dav = Dav::new().await;
+    dav.status("MKCOL", "/litmus/", &[], "").await;
+    dav.put("/litmus/lockme", "lock me").await;
+
+    // lock_excl, discover
+    let reply = dav
+        .lock(&dav.owner, "/litmus/lockme", "exclusive", "0")
+        .await;
+    assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+    let token = lock_token(&reply);
+    assert!(token.starts_with("urn:uuid:"));
+    assert_eq!(reply.dav_text("depth").as_deref(), Some("0"));
+    let reply = dav
+        .propfind(
+            "/litmus/lockme",
+            "0",
+            r#"<propfind xmlns="DAV:"><prop><lockdiscovery/></prop></propfind>"#,
+        )
+        .await;
+    assert!(reply.body.contains(&token));
+    assert!(reply.body.contains("litmus test suite"));
+
+    // refresh
+    let if_token = format!("(<{}>)", token);
+    let reply = dav
+        .req(
+            "LOCK",
+            "/litmus/lockme",
+            &[("If", &if_token), ("Timeout", "Second-120")],
+            "",
+        )
+        .await;
+    assert_eq!(reply.status, StatusCode::OK);
+    let timeout = reply.dav_text("timeout").unwrap();
+    let seconds: u64 = timeout.trim_start_matches("Second-").parse().unwrap();
+    assert!((110..=120).contains(&seconds), "{}", timeout);
+
+    // notowner_modify, notowner_lock
+    let other = &dav.other;
+    assert_eq!(
+        dav.send(other, "PUT", "/litmus/lockme", &[], "x")
+            .await
+            .status,
+        StatusCode::LOCKED
+    );
+    assert_eq!(
+        dav.send(other, "DELETE", "/litmus/lockme", &[], "")
+            .await
+            .status,
+        StatusCode::LOCKED
+    );
+    assert_eq!(
+        dav.send(other, "PUT", "/litmus/lockme", &[("If", &if_token)], "x")
+            .await
+            .status,
+        StatusCode::LOCKED
+    );
+    let reply = dav
+        .send(
+            other,
+            "MOVE",
+            "/litmus/lockme",
+            &[("Destination", "/dav/litmus/moved")],
+            "",
+        )
+        .await;
+    assert_eq!(reply.status, StatusCode::LOCKED);
+    assert!(reply.body.contains("lock-token-submitted"));
+    assert_eq!(
+        dav.lock(other, "/litmus/lockme", "exclusive", "0")
+            .await
+            .status,
+        StatusCode::LOCKED
+    );
+    assert_eq!(
+        dav.lock(other, "/litmus/lockme", "shared", "0")
+            .await
+            .status,
+        StatusCode::LOCKED
+    );
+    // Even the owner has to submit the token
+    assert_eq!(dav.put("/litmus/lockme", "x").await, 423);
+
+    // owner_modify, copy (the copy is not locked)
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockme", &[("If", &if_token)], "owner")
+            .await,
+        204
+    );
+    assert_eq!(
+        dav.status(
+            "COPY",
+            "/litmus/lockme",
+            &[("Destination", "/dav/litmus/lockcopy")],
+            ""
+        )
+        .await,
+        201
+    );
+    assert_eq!(dav.put("/litmus/lockcopy", "free").await, 204);
+
+    // cond_put, fail_cond_put, cond_put_with_not, cond_put_corrupt_token
+    let etag = dav
+        .req("HEAD", "/litmus/lockme", &[], "")
+        .await
+        .header("etag")
+        .unwrap()
+        .to_string();
+    let cond = format!("(<{}> [{}])", token, etag);
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockme", &[("If", &cond)], "c")
+            .await,
+        204
+    );
+    let fail = format!("(<{}> [\"wrong\"])", token);
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockme", &[("If", &fail)], "c")
+            .await,
+        412
+    );
+    let with_not = format!("(<{}>) (Not <DAV:no-lock>)", token);
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockme", &[("If", &with_not)], "c")
+            .await,
+        204
+    );
+    let corrupt = format!("(<{}x>)", token);
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockme", &[("If", &corrupt)], "c")
+            .await,
+        412
+    );
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockme", &[("If", "(<garbage")], "c")
+            .await,
+        400
+    );
+
+    // complex_cond_put, fail_complex_cond_put (tagged lists)
+    let etag = dav
+        .req("HEAD", "/litmus/lockme", &[], "")
+        .await
+        .header("etag")
+        .unwrap()
+        .to_string();
+    let tagged = format!(
+        "<http://localhost/dav/litmus/lockme> (<{}> [{}]) (Not <DAV:no-lock> [{}])",
+        token, etag, etag
+    );
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockme", &[("If", &tagged)], "t")
+            .await,
+        204
+    );
+    let failing = format!(
+        "<http://localhost/dav/litmus/lockme> ([{}] Not <{}>)",
+        etag, token
+    );
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockme", &[("If", &failing)], "t")
+            .await,
+        412
+    );
+
+    // unlock: wrong token, other user, owner
+    assert_eq!(
+        dav.status(
+            "UNLOCK",
+            "/litmus/lockme",
+            &[(
+                "Lock-Token",
+                "<urn:uuid:00000000-0000-0000-0000-000000000000>"
+            )],
+            ""
+        )
+        .await,
+        409
+    );
+    let lock_header = format!("<{}>", token);
+    assert_eq!(
+        dav.send(
+            other,
+            "UNLOCK",
+            "/litmus/lockme",
+            &[("Lock-Token", &lock_header)],
+            ""
+        )
+        .await
+        .status,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        dav.status(
+            "UNLOCK",
+            "/litmus/lockme",
+            &[("Lock-Token", &lock_header)],
+            ""
+        )
+        .await,
+        204
+    );
+
+    // fail_cond_put_unlocked
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockme", &[("If", &if_token)], "c")
+            .await,
+        412
+    );
+    assert_eq!(dav.put("/litmus/lockme", "unlocked").await, 204);
+
+    // lock_shared, double_sharedlock
+    let first = dav.lock(&dav.owner, "/litmus/lockme", "shared", "0").await;
+    assert_eq!(first.status, StatusCode::OK);
+    let second = dav.lock(other, "/litmus/lockme", "shared", "0").await;
+    assert_eq!(second.status, StatusCode::OK);
+    assert_eq!(
+        dav.lock(other, "/litmus/lockme", "exclusive", "0")
+            .await
+            .status,
+        StatusCode::LOCKED
+    );
+    let shared_if = format!("(<{}>)", lock_token(&first));
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockme", &[("If", &shared_if)], "s")
+            .await,
+        204
+    );
+    assert_eq!(dav.put("/litmus/lockme", "s").await, 423);
+    for (user, reply) in [(&dav.owner, &first), (other, &second)] {
+        let header = format!("<{}>", lock_token(reply));
+        assert_eq!(
+            dav.send(
+                user,
+                "UNLOCK",
+                "/litmus/lockme",
+                &[("Lock-Token", &header)],
+                ""
+            )
+            .await
+            .status,
+            StatusCode::NO_CONTENT
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_collection_locks() {
+    let dav = Dav::new().await;
+    dav.status("MKCOL", "/litmus/", &[], "").await;
+    dav.status("MKCOL", "/litmus/lockcoll/", &[], "").await;
+    dav.put("/litmus/lockcoll/lockme.txt", "x").await;
+
+    // lock_collection
+    let reply = dav
+        .lock(&dav.owner, "/litmus/lockcoll/", "exclusive", "infinity")
+        .await;
+    assert_eq!(reply.status, StatusCode::OK);
+    let token = lock_token(&reply);
+    assert_eq!(reply.dav_text("depth").as_deref(), Some("infinity"));
+    let if_token = format!("(<{}>)", token);
+
+    // owner_modify, notowner_modify: members are covered by the lock
+    assert_eq!(dav.put("/litmus/lockcoll/lockme.txt", "y").await, 423);
+    assert_eq!(dav.put("/litmus/lockcoll/new.txt", "y").await, 423);
+    assert_eq!(
+        dav.status(
+            "PUT",
+            "/litmus/lockcoll/lockme.txt",
+            &[("If", &if_token)],
+            "y"
+        )
+        .await,
+        204
+    );
+    assert_eq!(
+        dav.status("PUT", "/litmus/lockcoll/new.txt", &[("If", &if_token)], "y")
+            .await,
+        201
+    );
+    assert_eq!(
+        dav.lock(&dav.other, "/litmus/lockcoll/lockme.txt", "shared", "0")
+            .await
+            .status,
+        StatusCode::LOCKED
+    );
+    // Locking the parent with depth infinity conflicts with the lock below
+    assert_eq!(
+        dav.lock(&dav.other, "/litmus/", "exclusive", "infinity")
+            .await
+            .status,
+        StatusCode::LOCKED
+    );
+    // Deleting the parent needs the token of the lock below it
+    assert_eq!(dav.status("DELETE", "/litmus/", &[], "").await, 423);
+
+    // indirect_refresh through a member
+    let reply = dav
+        .req(
+            "LOCK",
+            "/litmus/lockcoll/lockme.txt",
+            &[("If", &if_token)],
+            "",
+        )
+        .await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert!(reply.body.contains("/dav/litmus/lockcoll/"));
+
+    // The lockroot is reported for members
+    let reply = dav
+        .propfind(
+            "/litmus/lockcoll/lockme.txt",
+            "0",
+            r#"<propfind xmlns="DAV:"><prop><lockdiscovery/></prop></propfind>"#,
+        )
+        .await;
+    assert!(reply.body.contains(&token));
+
+    // Unlocking through a member
+    let header = format!("<{}>", token);
+    assert_eq!(
+        dav.status(
+            "UNLOCK",
+            "/litmus/lockcoll/lockme.txt",
+            &[("Lock-Token", &header)],
+            ""
+        )
+        .await,
+        204
+    );
+
+    // Moving a locked collection with the token drops the lock
+    let reply = dav
+        .lock(&dav.owner, "/litmus/lockcoll/", "exclusive", "infinity")
+        .await;
+    let if_token = format!("(<{}>)", lock_token(&reply));
+    assert_eq!(
+        dav.status(
+            "MOVE",
+            "/litmus/lockcoll/",
+            &[("Destination", "/dav/litmus/moved/"), ("If", &if_token)],
+            ""
+        )
+        .await,
+        201
+    );
+    assert_eq!(dav.put("/litmus/moved/lockme.txt", "free").await, 204);
+    let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM collaborative_locks")
+        .fetch_one(&dav.pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining, 0);
+}
+
+#[tokio::test]
+async fn test_unmapped_lock_and_persistence() {
+    let dav = Dav::new().await;
+    dav.status("MKCOL", "/litmus/", &[], "").await;
+
+    // unmapped_lock creates an empty resource
+    let reply = dav
+        .lock(&dav.owner, "/litmus/unmapped_url", "exclusive", "0")
+        .await;
+    assert_eq!(reply.status, StatusCode::CREATED);
+    assert_eq!(
+        std::fs::read(dav.root.join("litmus/unmapped_url")).unwrap(),
+        b""
+    );
+    let token = lock_token(&reply);
+    assert_eq!(
+        dav.lock(&dav.owner, "/litmus/missing/url", "exclusive", "0")
+            .await
+            .status,
+        StatusCode::CONFLICT
+    );
+
+    // Locks survive a server restart
+    let restarted = DavServer::new(dav.pool.clone(), &dav.root, PREFIX);
+    let response = restarted
+        .handle(
+            &dav.other,
+            Request::builder()
+                .method("PUT")
+                .uri("/dav/litmus/unmapped_url")
+                .body(Body::from("x"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::LOCKED);
+
+    // Expired locks no longer apply
+    sqlx::query("UPDATE collaborative_locks SET expires_at = '2000-01-01 00:00:00'")
+        .execute(&dav.pool)
+        .await
+        .unwrap();
+    assert_eq!(dav.put("/litmus/unmapped_url", "x").await, 204);
+    let header = format!("<{}>", token);
+    assert_eq!(
+        dav.status(
+            "UNLOCK",
+            "/litmus/unmapped_url",
+            &[("Lock-Token", &header)],
+            ""
+        )
+        .await,
+        409
+    );
+}
+
+#[tokio::test]
+async fn test_put_respects_quota() {
+    let dav = Dav::with_hooks(RecordingHooks {
+        quota: Some(10),
+        ..Default::default()
+    })
+    .await;
+
+    assert_eq!(dav.put("/small.txt", "0123456789").await, 201);
+    assert_eq!(dav.put("/large.txt", "0123456789A").await, 507);
+    assert!(!dav.root.join("large.txt").exists());
+    // Replacing a file may use the space it frees
+    assert_eq!(dav.put("/small.txt", "0123456789abcdefghij").await, 204);
+    // Failed uploads leave no temp files behind
+    assert_eq!(
+        std::fs::read_dir(dav.root.join("temp_uploads"))
+            .unwrap()
+            .count(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_etag_is_the_download_etag() {
+    let dav = Dav::new().await;
+    sqlx::query(
+        "CREATE TABLE files (
+            id TEXT PRIMARY KEY,
+            path TEXT NOT NULL,
+            size_bytes INTEGER NOT NULL,
+            checksum_sha256 TEXT,
+            is_deleted INTEGER NOT NULL DEFAULT 0,
+            updated_at TEXT NOT NULL DEFAULT ''
+        )",
+    )
+    .execute(&dav.pool)
+    .await
+    .unwrap();
+
+    assert_eq!(dav.put("/report.txt", "quarterly").await, 201);
+    let checksum = "c0ffee".repeat(10) + "abcd";
+    sqlx::query("INSERT INTO files (id, path, size_bytes, checksum_sha256) VALUES ('f1', 'report.txt', 9, ?)")
+        .bind(&checksum)
+        .execute(&dav.pool)
+        .await
+        .unwrap();
+
+    let expected = syncbackend::etag::file_etag(Some(&checksum), 9, None);
+    let reply = dav.req("GET", "/report.txt", &[], "").await;
+    assert_eq!(reply.header("etag"), Some(expected.as_str()));
+    let reply = dav.propfind("/report.txt", "0", "").await;
+    assert_eq!(
+        reply.dav_text("getetag").as_deref(),
+        Some(expected.as_str())
+    );
+    assert_eq!(
+        dav.status("GET", "/report.txt", &[("If-None-Match", &expected)], "")
+            .await,
+        304
+    );
+
+    // Content the database has not caught up with falls back to size and mtime
+    std::fs::write(dav.root.join("report.txt"), "rewritten externally").unwrap();
+    let reply = dav.req("GET", "/report.txt", &[], "").await;
+    assert_ne!(reply.header("etag"), Some(expected.as_str()));
+    assert!(reply.header("etag").unwrap().starts_with('"'));
+}
+
+#[tokio::test]
+async fn test_content_served_through_hooks() {
+    let dav = Dav::with_hooks(RecordingHooks {
+        seal: true,
+        ..Default::default()
+    })
+    .await;
+    std::fs::write(dav.root.join("secret.txt"), "sealed:attack at dawn").unwrap();
+
+    // Reads see the content the hooks serve, with its length
+    let reply = dav.req("GET", "/secret.txt", &[], "").await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.body, "attack at dawn");
+    assert_eq!(reply.header("content-length"), Some("14"));
+    let reply = dav.req("HEAD", "/secret.txt", &[], "").await;
+    assert_eq!(reply.header("content-length"), Some("14"));
+    assert_eq!(reply.body, "");
+    let reply = dav.propfind("/secret.txt", "0", "").await;
+    assert_eq!(reply.dav_text("getcontentlength").as_deref(), Some("14"));
+    let reply = dav.send(&dav.other, "GET", "/secret.txt", &[], "").await;
+    assert_eq!(reply.status, StatusCode::FORBIDDEN);
+
+    // Writes over it are stored by the hooks, and refused ones change nothing
+    assert_eq!(dav.put("/secret.txt", "attack at noon").await, 204);
+    assert_eq!(
+        std::fs::read_to_string(dav.root.join("secret.txt")).unwrap(),
+        "sealed:attack at noon"
+    );
+    let reply = dav
+        .send(&dav.other, "PUT", "/secret.txt", &[], "retreat")
+        .await;
+    assert_eq!(reply.status, StatusCode::FORBIDDEN);
+    assert_eq!(
+        std::fs::read_to_string(dav.root.join("secret.txt")).unwrap(),
+        "sealed:attack at noon"
+    );
+    assert_eq!(
+        std::fs::read_dir(dav.root.join("temp_uploads"))
+            .unwrap()
+            .count(),
+        0
+    );
+
+    // Copies hold the content as read
+    let destination = format!("http://localhost{}/copy.txt", PREFIX);
+    assert_eq!(
+        dav.status("COPY", "/secret.txt", &[("Destination", &destination)], "")
+            .await,
+        201
+    );
+    assert_eq!(
+        std::fs::read_to_string(dav.root.join("copy.txt")).unwrap(),
+        "attack at noon"
+    );
+}
@@ -0,0 +1,276 @@
+//! WebSocket event stream tests
+//!
+//! Checks which events a session lets through for owners, shared users,
+//! admins and path-limited API tokens, how subscriptions narrow them, and
+//! what a reconnecting client gets when it resumes.
+
+mod common;
+
+use sqlx::SqlitePool;
+use syncbackend::auth::UserInfo;
+use syncbackend::rbac::tokens::TokenGrant;
+use syncbackend::websocket::session::frame;
+use syncbackend::websocket::{ClientMessage, FileChangeEvent, Hub, ServerMessage, Session};
+
+async fn setup() -> SqlitePool {
+    let pool = common::access_pool().await;
+    for statement in [
+        "CREATE TABLE conversion_jobs (id TEXT PRIMARY KEY, user_id TEXT NOT NULL)",
+        // alice owns Projects and Private; Projects is shared with carol
+        "INSERT INTO folders (id, path, owner_id) VALUES
+             ('projects', '/Projects', 'alice'), ('private', '/Private', 'alice')",
+        "INSERT INTO shared_links (id, item_id, created_by) VALUES ('share-1', 'Projects', 'alice')",
+        "INSERT INTO share_users (id, share_id, user_id) VALUES ('su-1', 'share-1', 'carol')",
+        "INSERT INTO conversion_jobs (id, user_id) VALUES ('job-a', 'alice'), ('job-c', 'carol')",
+    ] {
+        sqlx::query(statement).execute(&pool).await.unwrap();
+    }
+    pool
+}
+
+fn user(id: &str, is_admin: bool) -> UserInfo {
+    UserInfo {
+        id: id.to_string(),
+        username: id.to_string(),
+        totp_enabled: false,
+        role: None,
+        is_admin,
+    }
+}
+
+fn change(path: &str) -> FileChangeEvent {
+    FileChangeEvent::new(path.to_string(), "create".to_string())
+}
+
+fn progress(job_id: &str) -> FileChangeEvent {
+    FileChangeEvent::conversion_progress(job_id.to_string(), "processing".to_string(), None)
+}
+
+fn notification(user_id: &str) -> FileChangeEvent {
+    FileChangeEvent::Notification {
+        user_id: user_id.to_string(),
+        id: "n1".to_string(),
+        notification_type: "share_received".to_string(),
+        title: "Shared".to_string(),
+        message: "A file was shared with you".to_string(),
+        action_url: None,
+        timestamp: "2026-03-01T10:00:00Z".to_string(),
+    }
+}
+
+async fn subscribe(session: &mut Session, hub: &Hub, path: Option<&str>, job_id: Option<&str>) {
+    let message = ClientMessage::Subscribe {
+        path: path.map(String::from),
+        job_id: job_id.map(String::from),
+    };
+    let (reply, _) = session.handle(message, hub).await;
+    assert!(matches!(reply, ServerMessage::Subscriptions { .. }));
+}
+
+#[tokio::test]
+async fn test_events_follow_permissions() {
+    let pool = setup().await;
+
+    let mut alice = Session::new(pool.clone(), &user("alice", false));
+    let mut carol = Session::new(pool.clone(), &user("carol", false));
+    let mut dave = Session::new(pool.clone(), &user("dave", false));
+    let mut admin = Session::new(pool.clone(), &user("root", true));
+
+    let shared = change("Projects/plan.md");
+    let private = change("/Private/diary.txt");
+    assert!(alice.wants(&shared).await);
+    assert!(alice.wants(&private).await);
+    assert!(carol.wants(&shared).await);
+    assert!(!carol.wants(&private).await);
+    assert!(!dave.wants(&shared).await);
+    assert!(admin.wants(&private).await);
+    // Users always see their own changes
+    let own = change("Elsewhere/new.txt").with_user("dave".to_string());
+    assert!(dave.wants(&own).await);
+    assert!(!carol.wants(&own).await);
+
+    // Conversions reach the job's owner, notifications their addressee
+    assert!(alice.wants(&progress("job-a")).await);
+    assert!(!carol.wants(&progress("job-a")).await);
+    assert!(admin.wants(&progress("job-c")).await);
+    assert!(!alice.wants(&progress("job-unknown")).await);
+    assert!(carol.wants(&notification("carol")).await);
+    assert!(!alice.wants(&notification("carol")).await);
+    assert!(!admin.wants(&notification("carol")).await);
+
+    // API tokens are limited to their scopes and folders
+    let grant = TokenGrant::new("t1", "files:read", Some("Projects"), None);
+    let mut limited = Session::new(pool.clone(), &user("alice", false)).with_grant(grant);
+    assert!(limited.wants(&shared).await);
+    assert!(!limited.wants(&private).await);
+    let grant = TokenGrant::new("t2", "shares:manage", None, None);
+    let mut unscoped = Session::new(pool.clone(), &user("alice", false)).with_grant(grant);
+    assert!(!unscoped.wants(&shared).await);
+    assert!(unscoped.wants(&notification("alice")).await);
+}
+
+#[tokio::test]
+async fn test_subscriptions_narrow_the_stream() {
+    let pool = setup().await;
+    let hub = Hub::new(16);
+    let mut alice = Session::new(pool.clone(), &user("alice", false));
+
+    subscribe(&mut alice, &hub, Some("/Projects/"), None).await;
+    assert!(alice.wants(&change("Projects/plan.md")).await);
+    assert!(alice.wants(&change("Projects")).await);
+    assert!(!alice.wants(&change("Projects-old/plan.md")).await);
+    assert!(!alice.wants(&change("Private/diary.txt")).await);
+    // Job subscriptions are separate from folders
+    assert!(alice.wants(&progress("job-a")).await);
+    subscribe(&mut alice, &hub, None, Some("job-other")).await;
+    assert!(!alice.wants(&progress("job-a")).await);
+    // ... and never reach jobs of others
+    subscribe(&mut alice, &hub, None, Some("job-c")).await;
+    assert!(!alice.wants(&progress("job-c")).await);
+
+    let (reply, _) = alice
+        .handle(
+            ClientMessage::Unsubscribe {
+                path: Some("Projects".to_string()),
+                job_id: Some("job-other".to_string()),
+            },
+            &hub,
+        )
+        .await;
+    assert_eq!(
+        reply,
+        ServerMessage::Subscriptions {
+            folders: vec![],
+            jobs: vec!["job-c".to_string()],
+        }
+    );
+    assert!(alice.wants(&change("Private/diary.txt")).await);
+
+    let (reply, _) = alice
+        .handle(
+            ClientMessage::Subscribe {
+                path: Some("Projects/../Private".to_string()),
+                job_id: None,
+            },
+            &hub,
+        )
+        .await;
+    assert!(matches!(reply, ServerMessage::Error { .. }));
+    let (reply, _) = alice.handle(ClientMessage::Ping, &hub).await;
+    assert_eq!(reply, ServerMessage::Pong);
+}
+
+#[tokio::test]
+async fn test_resume_replays_missed_events() {
+    let pool = setup().await;
+    let hub = Hub::new(4);
+
+    // carol saw event 1, then lost the connection
+    hub.publish(change("Projects/a.md"));
+    hub.publish(change("Private/b.md"));
+    hub.publish(change("Projects/c.md"));
+
+    let mut carol = Session::new(pool.clone(), &user("carol", false));
+    let (live_from, mut live) = carol.attach(&hub);
+    assert_eq!(live_from, 3);
+    assert_eq!(
+        carol.welcome(&hub),
+        ServerMessage::Welcome {
+            stream: hub.stream().to_string(),
+            seq: 3,
+            user_id: "carol".to_string(),
+        }
+    );
+    // Sent live while the client re-subscribes
+    hub.publish(change("Projects/d.md"));
+    assert_eq!(live.recv().await.unwrap().seq, 4);
+
+    let resume = ClientMessage::Resume {
+        stream: hub.stream().to_string(),
+        after: 1,
+    };
+    let (reply, missed) = carol.handle(resume.clone(), &hub).await;
+    assert_eq!(reply, ServerMessage::Resumed { replayed: 1 });
+    assert_eq!(missed.len(), 1);
+    assert_eq!(missed[0].seq, 3);
+    let frame: serde_json::Value = serde_json::from_str(&frame(&missed[0]).unwrap()).unwrap();
+    assert_eq!(frame["seq"], 3);
+    assert_eq!(frame["event_type"], "file_change");
+    assert_eq!(frame["path"], "Projects/c.md");
+
+    // Only once per connection
+    let (reply, _) = carol.handle(resume, &hub).await;
+    assert!(matches!(reply, ServerMessage::Error { .. }));
+
+    // Events no longer kept, or from before a restart, need a resync
+    for n in 0..4 {
+        hub.publish(change(&format!("Projects/e{}.md", n)));
+    }
+    let mut again = Session::new(pool.clone(), &user("carol", false));
+    again.attach(&hub);
+    let (reply, missed) = again
+        .handle(
+            ClientMessage::Resume {
+                stream: hub.stream().to_string(),
+                after: 2,
+            },
+            &hub,
+        )
+        .await;
+    assert!(matches!(
+        reply,
+        ServerMessage::ResyncRequired { seq: 8, .. }
+    ));
+    assert!(missed.is_empty());
+
+    let mut restarted = Session::new(pool.clone(), &user("carol", false));
+    restarted.attach(&hub);
+    let (reply, _) = restarted
+        .handle(
+            ClientMessage::Resume {
+                stream: "previous-run".to_string(),
+                after: 8,
+            },
+            &hub,
+        )
+        .await;
+    assert!(matches!(reply, ServerMessage::ResyncRequired { .. }));
+}
+
+#[test]
+fn test_client_messages() {
+    let parse = |text: &str| serde_json::from_str::<ClientMessage>(text).unwrap();
+    assert_eq!(
+        parse(r#"{"type": "subscribe", "path": "/Projects"}"#),
+        ClientMessage::Subscribe {
+            path: Some("/Projects".to_string()),
+            job_id: None,
+        }
+    );
+    // Older clients subscribe to channels, which changes nothing
+    assert_eq!(
+        parse(r#"{"type": "subscribe", "channel": "notifications"}"#),
+        ClientMessage::Subscribe {
+            path: None,
+            job_id: None,
+        }
+    );
+    assert_eq!(
+        parse(r#"{"type": "resume", "stream": "s", "after": 12}"#),
+        ClientMessage::Resume {
+            stream: "s".to_string(),
+            after: 12,
+        }
+    );
+    assert!(serde_json::from_str::<ClientMessage>(r#"{"type": "shutdown"}"#).is_err());
+
+    let reply = serde_json::to_value(ServerMessage::ResyncRequired {
+        stream: "s".to_string(),
+        seq: 4,
+    })
+    .unwrap();
+    assert_eq!(
+        reply,
+        serde_json::json!({"type": "resync_required", "stream": "s", "seq": 4})
+    );
+}
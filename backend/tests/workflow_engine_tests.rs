@@ -0,0 +1,801 @@
+//! Workflow engine integration tests
+//!
+//! Rules are dispatched against files in a temporary data directory and their
+//! jobs are run through the job queue with a runner that records the actions
+//! instead of performing them.
+
+mod common;
+
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use serde_json::{Value, json};
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use std::sync::{Arc, Mutex};
+use syncbackend::jobs::queue::JobQueue;
+use syncbackend::secrets;
+use syncbackend::websocket::FileChangeEvent;
+use syncbackend::workflow::inbound::{self, Rejection};
+use syncbackend::workflow::{
+    ActionError, ActionOutput, ActionRunner, ActionStep, JobRun, Origin, Trigger, WorkflowEngine,
+    WorkflowEvent,
+};
+use tempfile::TempDir;
+
+const OWNER: &str = "user-1";
+const OTHER: &str = "user-2";
+const ADMIN: &str = "admin";
+
+/// Records every action; `fail` maps action types to the errors they return
+#[derive(Default)]
+struct RecordingRunner {
+    calls: Mutex<Vec<(String, Option<String>)>>,
+    fail: Mutex<Vec<(String, ActionError)>>,
+}
+
+impl RecordingRunner {
+    /// Make the next run of `kind` fail with `error`
+    fn fail_once(&self, kind: &str, error: ActionError) {
+        self.fail.lock().unwrap().push((kind.to_string(), error));
+    }
+
+    fn calls(&self) -> Vec<(String, Option<String>)> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl ActionRunner for RecordingRunner {
+    async fn run(&self, step: &ActionStep<'_>) -> Result<ActionOutput, ActionError> {
+        let kind = step.action.kind.clone();
+        self.calls
+            .lock()
+            .unwrap()
+            .push((kind.clone(), step.path.map(str::to_string)));
+
+        let mut fail = self.fail.lock().unwrap();
+        if let Some(index) = fail.iter().position(|(k, _)| *k == kind) {
+            return Err(fail.remove(index).1);
+        }
+        drop(fail);
+
+        match (kind.as_str(), step.path) {
+            ("move_file", Some(path)) => {
+                let folder = step.action.config["target_path"]
+                    .as_str()
+                    .unwrap()
+                    .trim_matches('/');
+                let name = path.rsplit('/').next().unwrap();
+                let target = format!("{}/{}", folder, name);
+                Ok(ActionOutput::new(json!({ "moved_to": target })).moved_to(target))
+            }
+            _ => Ok(ActionOutput::new(json!({ "ok": true }))),
+        }
+    }
+}
+
+struct Fixture {
+    dir: TempDir,
+    pool: SqlitePool,
+    queue: JobQueue,
+    runner: Arc<RecordingRunner>,
+    engine: WorkflowEngine,
+}
+
+impl Fixture {
+    async fn new() -> Self {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for statement in [
+            "CREATE TABLE users (id TEXT PRIMARY KEY, is_admin BOOLEAN NOT NULL DEFAULT 0,
+                 created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)",
+            "CREATE TABLE files (id TEXT PRIMARY KEY, path TEXT NOT NULL, owner_id TEXT NOT NULL,
+                 is_deleted BOOLEAN NOT NULL DEFAULT 0)",
+            "CREATE TABLE tags (id TEXT PRIMARY KEY, name TEXT NOT NULL, owner_id TEXT NOT NULL)",
+            "CREATE TABLE file_tags (id TEXT, file_id TEXT NOT NULL, tag_id TEXT NOT NULL,
+                 file_path TEXT)",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        // The jobs migration copies rows from a legacy table that does not exist here
+        common::run_migration_skipping(
+            &pool,
+            include_str!("../migrations/028_update_jobs_system.sql"),
+            &["background_jobs"],
+        )
+        .await;
+        common::run_migration(
+            &pool,
+            include_str!("../migrations/040_workflow_automation.sql"),
+        )
+        .await;
+        common::run_migration(&pool, include_str!("../migrations/058_workflow_engine.sql")).await;
+        common::run_migration(
+            &pool,
+            include_str!("../migrations/059_workflow_plugins.sql"),
+        )
+        .await;
+        common::run_migration(
+            &pool,
+            include_str!("../migrations/064_workflow_webhooks.sql"),
+        )
+        .await;
+        common::run_migration(&pool, include_str!("../migrations/066_secrets_vault.sql")).await;
+        // Installed once for all tests of this binary
+        if secrets::vault().is_none() {
+            secrets::install(secrets::Vault::in_memory());
+        }
+        for (id, is_admin) in [(OWNER, false), (OTHER, false), (ADMIN, true)] {
+            sqlx::query("INSERT INTO users (id, is_admin) VALUES (?, ?)")
+                .bind(id)
+                .bind(is_admin)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let dir = TempDir::new().unwrap();
+        let runner = Arc::new(RecordingRunner::default());
+        let engine = WorkflowEngine::new(pool.clone(), dir.path(), runner.clone());
+        Self {
+            queue: JobQueue::new(Arc::new(pool.clone())),
+            dir,
+            pool,
+            runner,
+            engine,
+        }
+    }
+
+    /// Create a file of `size` bytes owned by `owner`
+    async fn file(&self, path: &str, size: usize, owner: &str) {
+        let full = self.dir.path().join(path);
+        std::fs::create_dir_all(full.parent().unwrap()).unwrap();
+        std::fs::write(&full, vec![b'x'; size]).unwrap();
+        sqlx::query("INSERT INTO files (id, path, owner_id) VALUES (?, ?, ?)")
+            .bind(uuid::Uuid::new_v4().to_string())
+            .bind(format!("/{}", path))
+            .bind(owner)
+            .execute(&self.pool)
+            .await
+            .unwrap();
+    }
+
+    async fn rule(&self, created_by: &str, trigger: &str, fields: Value) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        let text = |key: &str, default: Value| fields.get(key).unwrap_or(&default).to_string();
+        sqlx::query(
+            "INSERT INTO workflow_rules (id, name, display_name, trigger_type, trigger_config,
+                 condition_config, action_type, action_config, created_by, max_concurrency,
+                 max_retries, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&id)
+        .bind(&id)
+        .bind("Test rule")
+        .bind(trigger)
+        .bind(text("trigger", json!({})))
+        .bind(text("condition", json!({})))
+        .bind(fields["action"].as_str().unwrap())
+        .bind(text("config", json!({})))
+        .bind(created_by)
+        .bind(
+            fields
+                .get("max_concurrency")
+                .and_then(Value::as_i64)
+                .unwrap_or(1),
+        )
+        .bind(
+            fields
+                .get("max_retries")
+                .and_then(Value::as_i64)
+                .unwrap_or(3),
+        )
+        .bind(
+            fields
+                .get("created_at")
+                .and_then(Value::as_str)
+                .map_or_else(|| Utc::now().to_rfc3339(), str::to_string),
+        )
+        .execute(&self.pool)
+        .await
+        .unwrap();
+        id
+    }
+
+    async fn upload(&self, path: &str) -> Vec<String> {
+        self.engine
+            .dispatch(
+                WorkflowEvent::new(Trigger::FileUpload, Some(path.to_string())).by_user(OWNER),
+            )
+            .await
+            .unwrap()
+    }
+
+    /// Claim the next job and run it like a job worker
+    async fn run_next(&self) -> JobRun {
+        let job = self
+            .queue
+            .claim_next()
+            .await
+            .unwrap()
+            .expect("no job queued");
+        let execution_id = match job.parse_type().unwrap() {
+            syncbackend::JobType::WorkflowExecution { execution_id } => execution_id,
+            other => panic!("unexpected job {:?}", other),
+        };
+        let run = self.engine.run_job(&job, &execution_id).await;
+        match &run {
+            JobRun::Finished(result) => {
+                self.queue
+                    .mark_success(&job.id, &result.message)
+                    .await
+                    .unwrap();
+            }
+            JobRun::Deferred(delay) => self.queue.defer(&job.id, *delay).await.unwrap(),
+            JobRun::Failed(error) => {
+                self.queue.mark_failed(&job.id, error).await.unwrap();
+                // Skip the backoff
+                sqlx::query("UPDATE jobs SET scheduled_for = NULL WHERE id = ?")
+                    .bind(&job.id)
+                    .execute(&self.pool)
+                    .await
+                    .unwrap();
+            }
+        }
+        run
+    }
+
+    async fn execution(&self, id: &str) -> (String, Option<String>, i64) {
+        sqlx::query_as(
+            "SELECT status, action_result, attempts FROM workflow_executions WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await
+        .unwrap()
+    }
+
+    async fn statuses(&self, rule_id: &str) -> Vec<(Option<String>, String)> {
+        sqlx::query_as(
+            "SELECT file_path, status FROM workflow_executions WHERE rule_id = ? ORDER BY file_path",
+        )
+        .bind(rule_id)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+    }
+}
+
+#[tokio::test]
+async fn test_upload_runs_matching_rule_chain() {
+    let fx = Fixture::new().await;
+    fx.file("Intake/scan.pdf", 100, OWNER).await;
+    fx.file("Intake/tiny.pdf", 1, OWNER).await;
+    fx.file("Other/scan.pdf", 100, OWNER).await;
+    let rule = fx
+        .rule(
+            OWNER,
+            "file_upload",
+            json!({
+                "trigger": {"path_prefix": "/Intake", "file_types": ["pdf"]},
+                "condition": {"file_size_min": 10},
+                "action": "add_tag",
+                "config": {"tag": "inbox", "then": [
+                    {"type": "move_file", "target_path": "/Archive"},
+                    {"type": "send_notification"}
+                ]}
+            }),
+        )
+        .await;
+
+    let queued = fx.upload("/Intake/scan.pdf").await;
+    assert_eq!(queued.len(), 1);
+    assert!(fx.upload("Intake/tiny.pdf").await.is_empty());
+    assert!(fx.upload("Other/scan.pdf").await.is_empty());
+    assert_eq!(
+        fx.statuses(&rule).await,
+        vec![
+            (Some("Intake/scan.pdf".to_string()), "queued".to_string()),
+            (Some("Intake/tiny.pdf".to_string()), "skipped".to_string()),
+        ]
+    );
+
+    assert!(matches!(fx.run_next().await, JobRun::Finished(result) if result.success));
+    assert_eq!(
+        fx.runner.calls(),
+        vec![
+            ("add_tag".to_string(), Some("Intake/scan.pdf".to_string())),
+            ("move_file".to_string(), Some("Intake/scan.pdf".to_string())),
+            (
+                "send_notification".to_string(),
+                Some("Archive/scan.pdf".to_string())
+            ),
+        ]
+    );
+    let (status, result, attempts) = fx.execution(&queued[0]).await;
+    assert_eq!(status, "success");
+    assert_eq!(attempts, 1);
+    let result: Value = serde_json::from_str(&result.unwrap()).unwrap();
+    assert_eq!(result["actions"].as_array().unwrap().len(), 3);
+
+    let logs: Vec<String> = sqlx::query_scalar(
+        "SELECT message FROM workflow_execution_logs WHERE execution_id = ? ORDER BY id",
+    )
+    .bind(&queued[0])
+    .fetch_all(&fx.pool)
+    .await
+    .unwrap();
+    assert!(logs.iter().any(|line| line == "Running move_file (2/3)"));
+    assert_eq!(logs.last().unwrap(), "Finished");
+}
+
+#[tokio::test]
+async fn test_rules_only_act_on_their_owners_files() {
+    let fx = Fixture::new().await;
+    fx.file("mine.txt", 10, OWNER).await;
+    let other = fx
+        .rule(OTHER, "file_upload", json!({"action": "add_tag"}))
+        .await;
+    let admin = fx
+        .rule(ADMIN, "file_upload", json!({"action": "add_tag"}))
+        .await;
+
+    assert_eq!(fx.upload("mine.txt").await.len(), 1);
+    assert!(fx.statuses(&other).await.is_empty());
+    assert_eq!(fx.statuses(&admin).await.len(), 1);
+}
+
+#[tokio::test]
+async fn test_workflow_changes_do_not_retrigger_their_rule() {
+    let fx = Fixture::new().await;
+    fx.file("a.txt", 10, OWNER).await;
+    let rule = fx
+        .rule(OWNER, "file_move", json!({"action": "add_tag"}))
+        .await;
+
+    // A change announced by the rule's own action carries it as origin
+    fx.engine
+        .expect_change("/a.txt", Origin::caused_by(None, &rule));
+    let events = fx.engine.events_for_change(&FileChangeEvent::new(
+        "a.txt".to_string(),
+        "move".to_string(),
+    ));
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0].origin.as_ref().unwrap().rule_ids,
+        vec![rule.clone()]
+    );
+    assert!(
+        fx.engine
+            .dispatch(events[0].clone())
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // Chains of other rules stop at the maximum depth
+    let deep = Origin {
+        rule_ids: vec!["x".to_string()],
+        depth: syncbackend::workflow::MAX_CHAIN_DEPTH,
+    };
+    let event = WorkflowEvent::new(Trigger::FileMove, Some("a.txt".to_string())).caused_by(deep);
+    assert!(fx.engine.dispatch(event).await.unwrap().is_empty());
+
+    let event = WorkflowEvent::new(Trigger::FileMove, Some("a.txt".to_string()))
+        .caused_by(Origin::caused_by(None, "x"));
+    assert_eq!(fx.engine.dispatch(event).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_concurrency_limit_defers_without_using_attempts() {
+    let fx = Fixture::new().await;
+    fx.file("a.txt", 10, OWNER).await;
+    fx.file("b.txt", 10, OWNER).await;
+    let rule = fx
+        .rule(
+            OWNER,
+            "file_upload",
+            json!({"action": "add_tag", "max_concurrency": 1}),
+        )
+        .await;
+    let first = fx.upload("a.txt").await.remove(0);
+    let second = fx.upload("b.txt").await.remove(0);
+
+    // Another worker is still running the first execution
+    sqlx::query("UPDATE workflow_executions SET status = 'running' WHERE id = ?")
+        .bind(&first)
+        .execute(&fx.pool)
+        .await
+        .unwrap();
+    let job_id: String = sqlx::query_scalar("SELECT job_id FROM workflow_executions WHERE id = ?")
+        .bind(&second)
+        .fetch_one(&fx.pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE jobs SET status = 'running' WHERE id != ?")
+        .bind(&job_id)
+        .execute(&fx.pool)
+        .await
+        .unwrap();
+
+    assert!(matches!(fx.run_next().await, JobRun::Deferred(_)));
+    assert!(fx.runner.calls().is_empty());
+    let job = fx.queue.get_job(&job_id).await.unwrap().unwrap();
+    assert_eq!((job.status.as_str(), job.attempts), ("pending", 0));
+    assert_eq!(fx.execution(&second).await.0, "queued");
+
+    sqlx::query("UPDATE workflow_executions SET status = 'success' WHERE id = ?")
+        .bind(&first)
+        .execute(&fx.pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE jobs SET scheduled_for = NULL WHERE id = ?")
+        .bind(&job_id)
+        .execute(&fx.pool)
+        .await
+        .unwrap();
+    assert!(matches!(fx.run_next().await, JobRun::Finished(_)));
+    assert_eq!(fx.execution(&second).await.0, "success");
+    assert_eq!(fx.statuses(&rule).await.len(), 2);
+}
+
+#[tokio::test]
+async fn test_retry_resumes_after_the_last_successful_action() {
+    let fx = Fixture::new().await;
+    fx.file("a.txt", 10, OWNER).await;
+    fx.rule(
+        OWNER,
+        "file_upload",
+        json!({
+            "action": "add_tag",
+            "config": {"tag": "x", "then": [{"type": "send_webhook", "url": "http://hook"}]},
+            "max_retries": 2
+        }),
+    )
+    .await;
+    let id = fx.upload("a.txt").await.remove(0);
+    fx.runner
+        .fail_once("send_webhook", ActionError::retryable("connection refused"));
+
+    assert!(matches!(fx.run_next().await, JobRun::Failed(_)));
+    let (status, _, attempts) = fx.execution(&id).await;
+    assert_eq!((status.as_str(), attempts), ("retrying", 1));
+
+    assert!(matches!(fx.run_next().await, JobRun::Finished(result) if result.success));
+    let kinds: Vec<String> = fx
+        .runner
+        .calls()
+        .into_iter()
+        .map(|(kind, _)| kind)
+        .collect();
+    assert_eq!(kinds, ["add_tag", "send_webhook", "send_webhook"]);
+    let (status, _, attempts) = fx.execution(&id).await;
+    assert_eq!((status.as_str(), attempts), ("success", 2));
+}
+
+#[tokio::test]
+async fn test_fatal_errors_and_exhausted_retries_fail_the_execution() {
+    let fx = Fixture::new().await;
+    fx.file("a.txt", 10, OWNER).await;
+    fx.file("b.txt", 10, OWNER).await;
+    fx.rule(
+        OWNER,
+        "file_upload",
+        json!({"action": "send_webhook", "max_retries": 0}),
+    )
+    .await;
+
+    let fatal = fx.upload("a.txt").await.remove(0);
+    fx.runner
+        .fail_once("send_webhook", ActionError::fatal("Webhook returned 404"));
+    assert!(matches!(fx.run_next().await, JobRun::Finished(result) if !result.success));
+    assert_eq!(fx.execution(&fatal).await.0, "failed");
+
+    let exhausted = fx.upload("b.txt").await.remove(0);
+    fx.runner
+        .fail_once("send_webhook", ActionError::retryable("timeout"));
+    assert!(matches!(fx.run_next().await, JobRun::Finished(result) if !result.success));
+    assert_eq!(fx.execution(&exhausted).await.0, "failed");
+    let error: String =
+        sqlx::query_scalar("SELECT error_message FROM workflow_executions WHERE id = ?")
+            .bind(&exhausted)
+            .fetch_one(&fx.pool)
+            .await
+            .unwrap();
+    assert_eq!(error, "send_webhook failed: timeout");
+}
+
+#[tokio::test]
+async fn test_disabled_rule_cancels_queued_executions() {
+    let fx = Fixture::new().await;
+    fx.file("a.txt", 10, OWNER).await;
+    let rule = fx
+        .rule(OWNER, "file_upload", json!({"action": "add_tag"}))
+        .await;
+    let id = fx.upload("a.txt").await.remove(0);
+    sqlx::query("UPDATE workflow_rules SET is_active = 0 WHERE id = ?")
+        .bind(&rule)
+        .execute(&fx.pool)
+        .await
+        .unwrap();
+
+    assert!(matches!(fx.run_next().await, JobRun::Finished(_)));
+    assert_eq!(fx.execution(&id).await.0, "cancelled");
+    assert!(fx.runner.calls().is_empty());
+
+    // Manual runs ignore the active flag
+    let manual = fx
+        .engine
+        .execute_manually(&rule, "/a.txt", OWNER, json!({}))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(fx.run_next().await, JobRun::Finished(_)));
+    assert_eq!(fx.execution(&manual).await.0, "success");
+}
+
+#[tokio::test]
+async fn test_schedule_processes_folder_once_per_interval() {
+    let fx = Fixture::new().await;
+    std::fs::create_dir_all(fx.dir.path().join("Reports/old")).unwrap();
+    for name in ["a.csv", "b.csv", ".hidden"] {
+        std::fs::write(fx.dir.path().join("Reports").join(name), b"1").unwrap();
+    }
+    let created_at = (Utc::now() - Duration::hours(2)).to_rfc3339();
+    let folder = fx
+        .rule(
+            ADMIN,
+            "scheduled",
+            json!({
+                "trigger": {"schedule_cron": "0 * * * *", "path": "/Reports"},
+                "action": "compress_file",
+                "created_at": created_at
+            }),
+        )
+        .await;
+    let plain = fx
+        .rule(
+            OWNER,
+            "scheduled",
+            json!({
+                "trigger": {"schedule_cron": "0 * * * *"},
+                "action": "send_notification",
+                "created_at": created_at
+            }),
+        )
+        .await;
+
+    let now = Utc::now();
+    assert_eq!(fx.engine.run_schedules(now).await.unwrap(), 3);
+    assert_eq!(
+        fx.statuses(&folder).await,
+        vec![
+            (Some("Reports/a.csv".to_string()), "queued".to_string()),
+            (Some("Reports/b.csv".to_string()), "queued".to_string()),
+        ]
+    );
+    assert_eq!(
+        fx.statuses(&plain).await,
+        vec![(None, "queued".to_string())]
+    );
+
+    // Not due again until the next full hour
+    assert_eq!(fx.engine.run_schedules(now).await.unwrap(), 0);
+    let later = now + Duration::hours(1);
+    assert_eq!(fx.engine.run_schedules(later).await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn test_plugin_registry() {
+    use syncbackend::workflow::plugins::{self, LimitSettings, RegisterError};
+
+    let fx = Fixture::new().await;
+    let module = |result: i32| {
+        wat::parse_str(format!(
+            r#"(module (memory (export "memory") 1) (func (export "run") (result i32) (i32.const {})))"#,
+            result
+        ))
+        .unwrap()
+    };
+    let defaults = LimitSettings::default();
+
+    let shell = wat::parse_str(
+        r#"(module (import "env" "system" (func (param i32) (result i32)))
+                   (memory (export "memory") 1) (func (export "run") (result i32) (i32.const 0)))"#,
+    )
+    .unwrap();
+    for (name, wasm) in [("shell", shell), ("../escape", module(0))] {
+        let result = plugins::register(&fx.pool, name, None, &wasm, &defaults, ADMIN).await;
+        assert!(matches!(result, Err(RegisterError::Invalid(_))), "{}", name);
+    }
+
+    let limits = LimitSettings {
+        fuel_limit: Some(1_000),
+        memory_limit_mb: Some(100_000),
+        timeout_ms: None,
+    };
+    let first = plugins::register(
+        &fx.pool,
+        "classify",
+        Some("Sorts invoices"),
+        &module(0),
+        &limits,
+        ADMIN,
+    )
+    .await
+    .unwrap();
+    assert_eq!(first.fuel_limit, 1_000);
+    assert_eq!(first.memory_limit_mb, 1024);
+    assert!(first.is_active);
+
+    // Registering the name again replaces the module but keeps the plugin
+    let second = plugins::register(&fx.pool, "classify", None, &module(1), &defaults, ADMIN)
+        .await
+        .unwrap();
+    assert_eq!(second.id, first.id);
+    assert_ne!(second.sha256, first.sha256);
+    assert_eq!(second.description.as_deref(), Some("Sorts invoices"));
+
+    let (info, wasm) = plugins::load(&fx.pool, "classify").await.unwrap().unwrap();
+    assert_eq!(info.id, first.id);
+    assert_eq!(wasm, module(1));
+    assert_eq!(plugins::list(&fx.pool).await.unwrap().len(), 1);
+
+    let disabled = plugins::update(&fx.pool, &first.id, None, Some(false), &defaults)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!disabled.is_active);
+    assert_eq!(disabled.description.as_deref(), Some("Sorts invoices"));
+
+    assert!(plugins::delete(&fx.pool, &first.id).await.unwrap());
+    assert!(!plugins::delete(&fx.pool, &first.id).await.unwrap());
+    assert!(plugins::load(&fx.pool, "classify").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_signed_webhook_queues_its_rule() {
+    let fx = Fixture::new().await;
+    fx.file("Builds/app.zip", 100, OWNER).await;
+    let rule = fx
+        .rule(
+            OWNER,
+            "webhook",
+            json!({
+                "trigger": {
+                    "path_prefix": "/Builds",
+                    "payload_mapping": {"file_path": "/artifact", "verdict": "/scan/result"}
+                },
+                "action": "add_tag",
+                "config": {"tag": "scanned"}
+            }),
+        )
+        .await;
+    let other = fx
+        .rule(OWNER, "webhook", json!({"action": "send_notification"}))
+        .await;
+    let endpoint = inbound::rotate(&fx.pool, &rule).await.unwrap();
+    assert_eq!(
+        inbound::endpoint(&fx.pool, &rule)
+            .await
+            .unwrap()
+            .unwrap()
+            .id,
+        endpoint.id
+    );
+    // Only the returned endpoint holds the plaintext secret
+    let (stored,): (String,) =
+        sqlx::query_as("SELECT secret FROM workflow_webhook_endpoints WHERE id = ?")
+            .bind(&endpoint.id)
+            .fetch_one(&fx.pool)
+            .await
+            .unwrap();
+    assert!(secrets::is_sealed(&stored));
+    assert!(!stored.contains(&endpoint.secret));
+
+    let now = Utc::now();
+    let body = br#"{"artifact": "Builds/app.zip", "scan": {"result": "clean"}}"#;
+    let signature = inbound::sign(&endpoint.secret, now.timestamp(), body);
+    let receive = |timestamp: i64, signature: String, body: &'static [u8]| {
+        let pool = fx.pool.clone();
+        let id = endpoint.id.clone();
+        async move {
+            inbound::receive(
+                &pool,
+                &id,
+                Some(&timestamp.to_string()),
+                Some(&signature),
+                body,
+                now,
+            )
+            .await
+        }
+    };
+
+    let (rule_id, event) = receive(now.timestamp(), signature.clone(), body)
+        .await
+        .unwrap();
+    assert_eq!(rule_id, rule);
+    let (reads,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM secret_reads WHERE row_id = ?")
+        .bind(&endpoint.id)
+        .fetch_one(&fx.pool)
+        .await
+        .unwrap();
+    assert_eq!(reads, 1);
+    assert_eq!(event.trigger, Trigger::Webhook);
+    assert_eq!(event.path.as_deref(), Some("Builds/app.zip"));
+    assert_eq!(event.context["verdict"], "clean");
+    assert_eq!(event.context["payload"]["scan"]["result"], "clean");
+
+    // Only the endpoint's own rule runs
+    let queued = fx.engine.dispatch_webhook(&rule_id, event).await.unwrap();
+    assert!(queued.is_some());
+    assert!(fx.statuses(&other).await.is_empty());
+    assert!(matches!(fx.run_next().await, JobRun::Finished(_)));
+    assert_eq!(
+        fx.runner.calls(),
+        vec![("add_tag".to_string(), Some("Builds/app.zip".to_string()))]
+    );
+
+    // The same request again, a stale one and a forged one
+    assert!(matches!(
+        receive(now.timestamp(), signature, body).await,
+        Err(Rejection::Replayed)
+    ));
+    let stale = now.timestamp() - inbound::MAX_CLOCK_SKEW_SECS - 1;
+    assert!(matches!(
+        receive(stale, inbound::sign(&endpoint.secret, stale, body), body).await,
+        Err(Rejection::Unauthorized)
+    ));
+    assert!(matches!(
+        receive(
+            now.timestamp(),
+            inbound::sign("guess", now.timestamp(), body),
+            body
+        )
+        .await,
+        Err(Rejection::Unauthorized)
+    ));
+
+    // Trigger filters still apply, and paths must stay in the data directory
+    let elsewhere: &'static [u8] = br#"{"artifact": "Other/app.zip"}"#;
+    let (_, event) = receive(
+        now.timestamp(),
+        inbound::sign(&endpoint.secret, now.timestamp(), elsewhere),
+        elsewhere,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        fx.engine.dispatch_webhook(&rule, event).await.unwrap(),
+        None
+    );
+    let escape: &'static [u8] = br#"{"artifact": "../etc/passwd"}"#;
+    assert!(matches!(
+        receive(
+            now.timestamp(),
+            inbound::sign(&endpoint.secret, now.timestamp(), escape),
+            escape
+        )
+        .await,
+        Err(Rejection::InvalidPayload(_))
+    ));
+
+    // A new secret invalidates the old endpoint
+    let rotated = inbound::rotate(&fx.pool, &rule).await.unwrap();
+    assert_ne!(rotated.secret, endpoint.secret);
+    assert!(matches!(
+        receive(
+            now.timestamp(),
+            inbound::sign(&endpoint.secret, now.timestamp(), b"{}"),
+            b"{}"
+        )
+        .await,
+        Err(Rejection::UnknownEndpoint)
+    ));
+    assert!(inbound::remove(&fx.pool, &rule).await.unwrap());
+    assert!(inbound::endpoint(&fx.pool, &rule).await.unwrap().is_none());
+}
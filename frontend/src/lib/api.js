@@ -1619,9 +1619,11 @@ export const rbac = {
 // WebSocket base URL (ws:// or wss://)
 const WS_BASE = API_HOST.replace(/^http/, 'ws') + '/api/ws';
 
+// Browsers cannot set headers on WebSockets, so the token goes in the URL
 export function createWebSocket() {
-  const ws = new WebSocket(WS_BASE);
-  return ws;
+  const token = getToken();
+  const url = token ? `${WS_BASE}?token=${encodeURIComponent(token)}` : WS_BASE;
+  return new WebSocket(url);
 }
 
 const performance = {
@@ -4238,6 +4240,33 @@ export const encryption = {
     return handleResponse(response);
   },
 
+  /**
+   * Unlock an encryption key for this session, so encrypted files can be
+   * downloaded and encrypted or decrypted without the password
+   * @param {string} keyId - Key ID to unlock
+   * @param {string} password - Key password
+   */
+  async unlockKey(keyId, password) {
+    const response = await fetch(`${API_BASE}/encryption/keys/${keyId}/unlock`, {
+      method: "POST",
+      headers: getHeaders(),
+      body: JSON.stringify({ password }),
+    });
+    return handleResponse(response);
+  },
+
+  /**
+   * Lock an unlocked encryption key again
+   * @param {string} keyId - Key ID to lock
+   */
+  async lockKey(keyId) {
+    const response = await fetch(`${API_BASE}/encryption/keys/${keyId}/lock`, {
+      method: "POST",
+      headers: getHeaders(),
+    });
+    return handleResponse(response);
+  },
+
   // --- File Operations ---
 
   /**
@@ -5450,6 +5479,42 @@ const ldap = {
   },
 };
 
+// ==================== SECRETS VAULT ====================
+const secrets = {
+  /**
+   * Master keys and how many stored credentials each one seals
+   */
+  async status() {
+    const response = await fetch(`${API_BASE}/secrets/status`, {
+      headers: getHeaders(),
+    });
+    return handleResponse(response);
+  },
+
+  /**
+   * Activate a new master key and rewrap all stored credentials
+   */
+  async rotate() {
+    const response = await fetch(`${API_BASE}/secrets/rotate`, {
+      method: "POST",
+      headers: getHeaders(),
+    });
+    return handleResponse(response);
+  },
+
+  /**
+   * Audit trail of credential reads, optionally for one secret column
+   */
+  async reads(secret = null, limit = 100) {
+    const params = new URLSearchParams({ limit: String(limit) });
+    if (secret) params.set("secret", secret);
+    const response = await fetch(`${API_BASE}/secrets/reads?${params}`, {
+      headers: getHeaders(),
+    });
+    return handleResponse(response);
+  },
+};
+
 // ==================== ARCHIVES ====================
 const archives = {
   /**
@@ -5878,7 +5943,7 @@ const conversion = {
 };
 
 // Add late-defined exports to api object
-Object.assign(api, { system, themes, encryption, quota, groups, guests, rateLimiting, admin, ftp, email, oauth, ldap, archives, compression, thumbnails, preview, virusScan, conversion });
+Object.assign(api, { system, themes, encryption, quota, groups, guests, rateLimiting, admin, ftp, email, oauth, ldap, secrets, archives, compression, thumbnails, preview, virusScan, conversion });
 
 // Export individual modules for direct import
 export { thumbnails, preview, virusScan, conversion };
@@ -55,7 +55,9 @@ function createActivityStore() {
     if (websocket) return;
     
     try {
-      websocket = new WebSocket(WS_BASE);
+      const token = getToken();
+      if (!token) return;
+      websocket = new WebSocket(`${WS_BASE}?token=${encodeURIComponent(token)}`);
       
       websocket.onopen = () => {
         console.log('[Activity] WebSocket connected for real-time updates');
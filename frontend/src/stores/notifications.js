@@ -151,24 +151,31 @@ const createNotificationsStore = () => {
           console.log('[Notifications] WebSocket connected');
           reconnectAttempts = 0;
           
-          // Request notification updates
-          ws.send(JSON.stringify({ type: 'subscribe', channel: 'notifications' }));
+          // Notifications addressed to the user are sent without subscribing
         };
         
         ws.onmessage = (event) => {
           try {
             const message = JSON.parse(event.data);
             
-            if (message.type === 'notification') {
+            if (message.event_type === 'notification') {
               // New notification received
-              update(notifications => [message.data, ...notifications]);
+              const notification = {
+                id: message.id,
+                type: message.notification_type,
+                title: message.title,
+                message: message.message,
+                action_url: message.action_url,
+                read: false,
+              };
+              update(notifications => [notification, ...notifications]);
               
               // Show browser notification if enabled
               if ('Notification' in window && Notification.permission === 'granted') {
-                new Notification(message.data.title || 'SyncSpace', {
-                  body: message.data.message,
+                new Notification(notification.title || 'SyncSpace', {
+                  body: notification.message,
                   icon: '/favicon.ico',
-                  tag: message.data.id
+                  tag: notification.id
                 });
               }
             } else if (message.type === 'notification_update') {
@@ -35,6 +35,9 @@ class WebSocketManager {
     this.missedHeartbeats = 0;
     this.maxMissedHeartbeats = 3;
     this.manualDisconnect = false;
+    // Position in the server's event stream, for resuming after a reconnect
+    this.stream = null;
+    this.lastSeq = null;
     
     // Don't auto-connect! Let components decide when to connect.
     // Call websocketManager.connect() explicitly when needed.
@@ -88,6 +91,13 @@ class WebSocketManager {
           
           console.log('📨 WebSocket message received:', data);
           
+          if (this.handleStreamMessage(data)) {
+            return;
+          }
+          if (typeof data.seq === 'number') {
+            this.lastSeq = data.seq;
+          }
+          
           // Add to events store (keep last 50 events)
           wsEvents.update(events => [...events.slice(-49), data]);
           
@@ -232,6 +242,38 @@ class WebSocketManager {
     this.missedHeartbeats = 0;
   }
 
+  // Stream control messages; returns true when the message was one
+  handleStreamMessage(data) {
+    switch (data.type) {
+      case 'welcome':
+        // Ask for the events missed while disconnected
+        if (this.stream && this.lastSeq !== null) {
+          this.send({ type: 'resume', stream: this.stream, after: this.lastSeq });
+        } else {
+          this.lastSeq = data.seq;
+        }
+        this.stream = data.stream;
+        return true;
+      case 'resync_required':
+        // Too much was missed; listeners should reload their data
+        this.stream = data.stream;
+        this.lastSeq = data.seq;
+        this.emit('resync', data);
+        return true;
+      case 'resumed':
+      case 'pong':
+      case 'subscriptions':
+      case 'status':
+        this.emit(data.type, data);
+        return true;
+      case 'error':
+        console.warn('⚠️ WebSocket error from server:', data.message);
+        return true;
+      default:
+        return false;
+    }
+  }
+
   handleFileEvent(event) {
     console.log('📁 File system event:', event);
     